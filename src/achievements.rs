@@ -0,0 +1,248 @@
+//! Achievements (like RetroAchievements) and training overlays: conditions on the memory, evaluated once per frame,
+//! that unlock an achievement when they are all true in the same frame.
+//!
+//! The file has an achievement per line, `#` starts a comment:
+//! ```text
+//! # id | title | conditions
+//! 1 | World 1-2 | [$075F] == 0 && [$075C] == 1
+//! 2 | One more life | [$075A] > prev[$075A]
+//! 3 | Star power for 5 seconds | [$079F] != 0 (300)
+//! ```
+//! A condition compares two operands with `==`, `!=`, `<`, `<=`, `>` or `>=`. An operand is a number (decimal, or hex
+//! with `$`), `[$addr]` (the byte in memory) or `prev[$addr]` (the byte in the previous frame). `(n)` after a condition
+//! is a hit count: the condition counts as true after it was true in `n` frames, not necessarily in a row.
+
+use std::collections::HashMap;
+use std::fs;
+
+use crate::nes::NES;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operand {
+	Value(u8),
+	Memory(u16),
+	Previous(u16),	// The memory in the previous frame
+}
+
+impl Operand {
+	fn parse(source: &str) -> Option<Self> {
+		let source = source.trim();
+		let address = |s: &str| u16::from_str_radix(s.strip_prefix("[$")?.strip_suffix(']')?, 16).ok();
+		if let Some(addr) = source.strip_prefix("prev").and_then(address) {
+			return Some(Operand::Previous(addr));
+		}
+		if let Some(addr) = address(source) {
+			return Some(Operand::Memory(addr));
+		}
+		match source.strip_prefix('$') {
+			Some(hex) => u8::from_str_radix(hex, 16).ok().map(Operand::Value),
+			None => source.parse().ok().map(Operand::Value),
+		}
+	}
+
+	fn address(self) -> Option<u16> {
+		match self {
+			Operand::Value(_) => None,
+			Operand::Memory(addr) | Operand::Previous(addr) => Some(addr),
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Compare {
+	Equal,
+	NotEqual,
+	Less,
+	LessOrEqual,
+	Greater,
+	GreaterOrEqual,
+}
+
+impl Compare {
+	/// Longest first, so `<=` isn't read as `<`.
+	const OPERATORS: [(&'static str, Compare); 6] = [
+		("==", Compare::Equal), ("!=", Compare::NotEqual), ("<=", Compare::LessOrEqual),
+		(">=", Compare::GreaterOrEqual), ("<", Compare::Less), (">", Compare::Greater),
+	];
+
+	fn test(self, left: u8, right: u8) -> bool {
+		match self {
+			Compare::Equal => left == right,
+			Compare::NotEqual => left != right,
+			Compare::Less => left < right,
+			Compare::LessOrEqual => left <= right,
+			Compare::Greater => left > right,
+			Compare::GreaterOrEqual => left >= right,
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Condition {
+	left: Operand,
+	compare: Compare,
+	right: Operand,
+	hits: u32,		// Frames it must be true in, 0 for no hit count
+	count: u32,		// Frames it was true in
+}
+
+impl Condition {
+	fn parse(source: &str) -> Result<Self, String> {
+		let source = source.trim();
+		let (source, hits) = match source.strip_suffix(')').and_then(|s| s.rsplit_once('(')) {
+			Some((condition, hits)) => (condition, hits.trim().parse().map_err(|_| format!("Invalid hit count: {}", hits))?),
+			None => (source, 0),
+		};
+		let (left, compare, right) = Compare::OPERATORS.iter()
+			.find_map(|&(operator, compare)| source.split_once(operator).map(|(left, right)| (left, compare, right)))
+			.ok_or(format!("No comparison in {:?}", source))?;
+		let operand = |s: &str| Operand::parse(s).ok_or(format!("Invalid operand: {:?}", s.trim()));
+		Ok(Condition { left: operand(left)?, compare, right: operand(right)?, hits, count: 0 })
+	}
+
+	/// Evaluate in a frame, counting the hits.
+	fn evaluate(&mut self, memory: &HashMap<u16, u8>, previous: &HashMap<u16, u8>) -> bool {
+		let value = |operand: Operand| match operand {
+			Operand::Value(value) => value,
+			Operand::Memory(addr) => memory[&addr],
+			Operand::Previous(addr) => previous.get(&addr).copied().unwrap_or(memory[&addr]),
+		};
+		let true_now = self.compare.test(value(self.left), value(self.right));
+		if self.hits == 0 {
+			return true_now;
+		}
+		if true_now && self.count < self.hits {
+			self.count += 1;
+		}
+		self.count >= self.hits
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Achievement {
+	pub id: u32,
+	pub title: String,
+	conditions: Vec<Condition>,
+	unlocked: Option<u64>,	// The frame it was unlocked in
+}
+
+impl Achievement {
+	pub fn unlocked(&self) -> Option<u64> {
+		self.unlocked
+	}
+}
+
+/// An achievement that was unlocked.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Unlock {
+	pub id: u32,
+	pub title: String,
+	pub frame: u64,
+}
+
+/// The achievements of a game, and the memory they read.
+#[derive(Debug, Default)]
+pub struct Achievements {
+	list: Vec<Achievement>,
+	previous: HashMap<u16, u8>,	// The memory the conditions read, in the previous frame
+}
+
+impl Achievements {
+	pub fn parse(text: &str) -> Result<Self, String> {
+		let mut list: Vec<Achievement> = vec![];
+		for (i, line) in text.lines().enumerate() {
+			let line = line.split('#').next().unwrap().trim();
+			if line.is_empty() {
+				continue;
+			}
+			let error = |e: String| format!("Line {}: {}", i + 1, e);
+			let [id, title, conditions] = line.splitn(3, '|').collect::<Vec<_>>()[..] else {
+				return Err(error("expected `id | title | conditions`".to_string()));
+			};
+			let id = id.trim().parse().map_err(|_| error(format!("Invalid id: {}", id.trim())))?;
+			if list.iter().any(|achievement| achievement.id == id) {
+				return Err(error(format!("Id {} is used twice", id)));
+			}
+			let conditions = conditions.split("&&").map(Condition::parse).collect::<Result<Vec<_>, _>>().map_err(error)?;
+			list.push(Achievement { id, title: title.trim().to_string(), conditions, unlocked: None });
+		}
+		Ok(Achievements { list, previous: HashMap::new() })
+	}
+
+	pub fn load(path: &str) -> Result<Self, String> {
+		Achievements::parse(&fs::read_to_string(path).map_err(|e| format!("Can't read {}: {}", path, e))?)
+	}
+
+	pub fn list(&self) -> &[Achievement] {
+		&self.list
+	}
+
+	/// Call once per frame: evaluates the conditions of the locked achievements, and returns the ones they unlock.
+	pub fn frame(&mut self, nes: &mut NES) -> Vec<Unlock> {
+		let mut memory = HashMap::new();
+		let addresses = self.list.iter().flat_map(|achievement| &achievement.conditions).flat_map(|condition| [condition.left, condition.right]);
+		for addr in addresses.filter_map(Operand::address) {
+			memory.entry(addr).or_insert_with(|| nes.peek(addr));
+		}
+
+		let mut unlocks = vec![];
+		for achievement in self.list.iter_mut().filter(|achievement| achievement.unlocked.is_none()) {
+			// Every condition is evaluated, so the hit counts go on while the others are false
+			let results: Vec<bool> = achievement.conditions.iter_mut().map(|condition| condition.evaluate(&memory, &self.previous)).collect();
+			if results.iter().all(|&result| result) {
+				achievement.unlocked = Some(nes.frame());
+				unlocks.push(Unlock { id: achievement.id, title: achievement.title.clone(), frame: nes.frame() });
+			}
+		}
+		self.previous = memory;
+		unlocks
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Achievements, Compare, Condition, Operand, Unlock};
+	use crate::{nes::NES, program_loader::*};
+
+	#[test]
+	fn test_parse() {
+		let condition = Condition::parse(" [$075A] >= prev[$075A] (60) ").unwrap();
+		assert_eq!((condition.left, condition.compare, condition.right, condition.hits), (Operand::Memory(0x075A), Compare::GreaterOrEqual, Operand::Previous(0x075A), 60));
+		let condition = Condition::parse("[$10]<$1F").unwrap();
+		assert_eq!((condition.left, condition.compare, condition.right, condition.hits), (Operand::Memory(0x10), Compare::Less, Operand::Value(0x1F), 0));
+
+		let achievements = Achievements::parse("# Comment\n1 | First | [$00] == 1\n\n2 | Second | 3 != [$01] && [$02] > 2 # Comment\n").unwrap();
+		assert_eq!(achievements.list().iter().map(|achievement| (achievement.id, achievement.title.as_str())).collect::<Vec<_>>(), [(1, "First"), (2, "Second")]);
+		assert!(Achievements::parse("1 | No conditions").unwrap_err().starts_with("Line 1"));
+		assert!(Achievements::parse("1 | A | [$00] = 1").unwrap_err().contains("No comparison"));
+		assert!(Achievements::parse("1 | A | [$00] == x").unwrap_err().contains("Invalid operand"));
+		assert!(Achievements::parse("1 | A | [$00] == 1\n1 | B | [$00] == 2").unwrap_err().contains("twice"));
+	}
+
+	#[test]
+	fn test_achievements() {
+		// The NMI counter increments $00 each frame
+		let mut rom_memory: [u8; 1024*32] = [0;1024*32];
+		load_program_nmi_counter(&mut rom_memory);
+		set_reset_vector(&mut rom_memory, 0x8000);
+		let mut nes = NES::new_custom_prg_rom(rom_memory);
+		let mut achievements = Achievements::parse("\
+			1 | Five frames | [$00] == 5\n\
+			2 | Counting | [$00] > prev[$00] (3)\n\
+			3 | Never | [$00] == 5 && [$00] == 6").unwrap();
+
+		let mut unlocks = vec![];
+		for _ in 0..10 {
+			nes.run_frame();
+			for unlock in achievements.frame(&mut nes) {
+				unlocks.push((unlock, nes.peek(0x00)));
+			}
+		}
+		// The first frame has no previous frame, the hits count from the second one: 3 increments after it
+		assert_eq!(unlocks.iter().map(|(unlock, _)| unlock.id).collect::<Vec<_>>(), [2, 1]);
+		let (counting, five) = (&unlocks[0], &unlocks[1]);
+		assert_eq!((counting.1, five.1), (4, 5));
+		assert_eq!(five.0, Unlock { id: 1, title: "Five frames".to_string(), frame: achievements.list()[0].unlocked().unwrap() });
+		assert_eq!(achievements.list()[2].unlocked(), None);
+	}
+}
@@ -0,0 +1,552 @@
+use crate::savestate::{Serialize, Serializer};
+use crate::state_dump::ApuState;
+
+use super::{dmc::{DMC, DMCStatus}, expansion::ExpansionVolumes, noise::Noise, notes::NoteLog, pulse::Pulse, resampler::{Resampler, ResamplerQuality}, scope::{Channel, ChannelState, Scope}, triangle::Triangle};
+
+/// Output sample rate of the mixer (Hz).
+pub const SAMPLE_RATE: u64 = 44_100;
+
+/// An output level of the mixer, in fixed point: `LEVEL_ONE` is 1.0. The core only does integer math, so the samples
+/// and the save states are the same on every platform and build (the replays and the scenarios check them by hash).
+pub type Level = i32;
+pub const LEVEL_ONE: Level = 1 << 16;
+/// A step of the pulse channel volume in the mixer (0.00752), the unit of the expansion audio chips.
+pub const PULSE_STEP: Level = 493;
+
+/// When nobody takes the samples, keep only the last 2 seconds.
+const MAX_BUFFERED_SAMPLES: usize = SAMPLE_RATE as usize * 2;
+
+/// Length of the frame counter sequence in CPU cycles. The 4-step sequence raises the frame IRQ at its last step.
+const FOUR_STEP_CYCLES: u64 = 29830;
+const FIVE_STEP_CYCLES: u64 = 37282;
+/// The steps of the sequences: the CPU cycles of the quarter frame clocks, and if it is also a half frame clock.
+const FOUR_STEPS: [(u64, bool); 4] = [(7457, false), (14913, true), (22371, false), (29829, true)];
+const FIVE_STEPS: [(u64, bool); 4] = [(7457, false), (14913, true), (22371, false), (37281, true)];
+
+/// Audio Processing Unit. Read here: https://www.nesdev.org/wiki/APU
+///
+/// The pulse, triangle and noise channels only have their length counters and the pulse sweeps so far, so they are
+/// silent. The DMC plays its samples, the CPU reads them for it (`dmc_fetch_address`). The mixer is already here, so
+/// cartridges with expansion audio (VRC6, FDS...) can be heard: the output of their chips, each at its volume
+/// (`expansion_volumes`), is added to the APU output.
+#[derive(Clone)]
+pub struct APU {
+	cycles: u64,
+	samples: Vec<i16>,
+	samples_generated: u64,
+	resampler: Resampler,
+	audio: bool,	// Make the samples, see `set_audio_enabled`
+	scope: Scope,
+	notes: Option<NoteLog>,
+
+	pulse1: Pulse,
+	pulse2: Pulse,
+	triangle: Triangle,
+	noise: Noise,
+	dmc: DMC,
+	expansion_volumes: ExpansionVolumes,
+
+	// Frame counter ($4017)
+	frame_counter_cycles: u64,	// CPU cycles since the sequence started
+	five_step_mode: bool,
+	frame_irq_inhibit: bool,
+	frame_irq: bool,
+	frame_counter_delay: u8,	// CPU cycles until the $4017 write restarts the sequence, 0 when none
+	frame_counter_mode: bool,	// The 5-step mode of that write
+}
+
+impl Default for APU {
+	fn default() -> Self {
+		APU::new()
+	}
+}
+
+impl APU {
+	pub fn new() -> Self {
+		APU {
+			cycles: 0,
+			samples: Vec::new(),
+			samples_generated: 0,
+			resampler: Resampler::new(ResamplerQuality::Linear, SAMPLE_RATE),
+			audio: true,
+			scope: Scope::default(),
+			notes: None,
+			pulse1: Pulse::new(true),
+			pulse2: Pulse::new(false),
+			triangle: Triangle::default(),
+			noise: Noise::default(),
+			dmc: DMC::new(),
+			expansion_volumes: ExpansionVolumes::default(),
+			frame_counter_cycles: 0,
+			five_step_mode: false,
+			frame_irq_inhibit: false,
+			frame_irq: false,
+			frame_counter_delay: 0,
+			frame_counter_mode: false,
+		}
+	}
+
+	/// Advance the APU by a single CPU cycle. `expansion` is the cartridge audio output mixed with the
+	/// `expansion_volumes`, in the same units as `mix`.
+	pub fn clock(&mut self, expansion: Level) {
+		self.cycles += 1;
+		self.clock_frame_counter();
+		self.pulse1.length.end_cycle();
+		self.pulse2.length.end_cycle();
+		self.triangle.length.end_cycle();
+		self.noise.length.end_cycle();
+		self.dmc.clock();
+		if !self.audio {
+			return;
+		}
+		let [pulse1, pulse2, triangle, noise, dmc] = self.channel_levels();
+		if let Some(sample) = self.resampler.clock(Self::mix(pulse1, pulse2, triangle, noise, dmc) + expansion) {
+			self.scope.push([pulse1, pulse2, triangle, noise, dmc]);
+			if self.samples.len() == MAX_BUFFERED_SAMPLES {
+				self.samples.drain(..MAX_BUFFERED_SAMPLES / 2);
+			}
+			self.samples.push(to_sample(sample));
+			self.samples_generated += 1;
+		}
+	}
+
+	fn clock_frame_counter(&mut self) {
+		if self.frame_counter_delay > 0 {
+			self.frame_counter_delay -= 1;
+			if self.frame_counter_delay == 0 {
+				self.five_step_mode = self.frame_counter_mode;
+				self.frame_counter_cycles = 0;
+				// The 5-step mode clocks the envelopes, length counters and sweeps right away
+				if self.five_step_mode {
+					self.clock_frame(true);
+				}
+				return;
+			}
+		}
+		self.frame_counter_cycles += 1;
+		let steps = if self.five_step_mode { FIVE_STEPS } else { FOUR_STEPS };
+		if let Some(&(_, half_frame)) = steps.iter().find(|(cycles, _)| *cycles == self.frame_counter_cycles) {
+			self.clock_frame(half_frame);
+		}
+		let sequence_cycles = if self.five_step_mode { FIVE_STEP_CYCLES } else { FOUR_STEP_CYCLES };
+		if self.frame_counter_cycles == sequence_cycles {
+			self.frame_counter_cycles = 0;
+			if !self.five_step_mode && !self.frame_irq_inhibit {
+				self.frame_irq = true;
+			}
+		}
+	}
+
+	/// A quarter frame clock (envelopes and triangle linear counter, not emulated yet), and a half frame clock (length
+	/// counters and sweeps).
+	fn clock_frame(&mut self, half_frame: bool) {
+		if half_frame {
+			self.pulse1.clock_half_frame();
+			self.pulse2.clock_half_frame();
+			self.triangle.length.clock();
+			self.noise.length.clock();
+			let active = Channel::ALL.map(|channel| self.channel_state(channel).enabled);
+			if let Some(notes) = &mut self.notes {
+				notes.update(self.cycles, active);
+			}
+		}
+	}
+
+	/// Read $4015 (status). Bits 0-3 are set while the length counter of the pulse 1, pulse 2, triangle and noise
+	/// channels isn't 0, bit 4 while the DMC sample is playing. Bit 6 is the frame IRQ, bit 7 is the DMC IRQ. Reading
+	/// acknowledges the frame IRQ.
+	pub fn read_status(&mut self, peek: bool) -> u8 {
+		let lengths = [&self.pulse1.length, &self.pulse2.length, &self.triangle.length, &self.noise.length];
+		let channels = lengths.iter().enumerate().fold(0, |status, (i, length)| status | ((length.active() as u8) << i));
+		let status = ((self.dmc.irq() as u8) << 7) | ((self.frame_irq as u8) << 6) | ((self.dmc.active() as u8) << 4) | channels;
+		if !peek {
+			self.frame_irq = false;
+		}
+		status
+	}
+
+	/// Write a channel register ($4000-$4013), $4015 (channel enable, acknowledges the DMC IRQ) or $4017 (frame counter
+	/// mode and IRQ inhibit).
+	pub fn write_register(&mut self, addr: u16, value: u8) {
+		if let Some(notes) = &mut self.notes {
+			notes.write(self.cycles, addr, value);
+		}
+		match addr {
+			0x4000..=0x4003 => self.pulse1.write_register(addr & 3, value),
+			0x4004..=0x4007 => self.pulse2.write_register(addr & 3, value),
+			0x4008..=0x400B => self.triangle.write_register(addr & 3, value),
+			0x400C..=0x400F => self.noise.write_register(addr & 3, value),
+			0x4010..=0x4013 => self.dmc.write_register(addr & 3, value),
+			0x4015 => {
+				self.pulse1.length.set_enabled(value & 0x01 != 0);
+				self.pulse2.length.set_enabled(value & 0x02 != 0);
+				self.triangle.length.set_enabled(value & 0x04 != 0);
+				self.noise.length.set_enabled(value & 0x08 != 0);
+				self.dmc.set_enabled(value & 0x10 != 0);
+			}
+			0x4017 => {
+				self.frame_irq_inhibit = value & 0x40 != 0;
+				if self.frame_irq_inhibit {
+					self.frame_irq = false;
+				}
+				// The sequence restarts 3 CPU cycles after a write on an APU cycle (every other CPU cycle), 4 after a
+				// write between them. Until then the old sequence goes on.
+				self.frame_counter_mode = value & 0x80 != 0;
+				self.frame_counter_delay = if self.cycles % 2 == 1 { 4 } else { 3 };
+			}
+			_ => (),
+		}
+	}
+
+	/// The reset button silences the APU ($4015 = 0) and restarts the frame counter in the same mode.
+	pub fn reset(&mut self) {
+		self.write_register(0x4015, 0);
+		self.frame_counter_cycles = 0;
+		self.frame_counter_delay = 0;
+	}
+
+	/// Is the frame counter asserting IRQ.
+	pub fn dump_state(&self) -> ApuState {
+		ApuState {
+			cycles: self.cycles,
+			frame_counter_cycles: self.frame_counter_cycles,
+			five_step_mode: self.five_step_mode,
+			frame_irq_inhibit: self.frame_irq_inhibit,
+			frame_irq: self.frame_irq,
+			dmc_irq: self.dmc.irq(),
+		}
+	}
+
+	pub fn frame_irq(&self) -> bool {
+		self.frame_irq
+	}
+
+	/// Is the DMC asserting IRQ.
+	pub fn dmc_irq(&self) -> bool {
+		self.dmc.irq()
+	}
+
+	/// The address the DMC needs the next sample byte from, if it needs one. The CPU reads it through the mapper (the
+	/// sample can be in any bank) and gives it with `dmc_fill`.
+	pub fn dmc_fetch_address(&self) -> Option<u16> {
+		self.dmc.fetch_address()
+	}
+
+	pub fn dmc_fill(&mut self, byte: u8) {
+		self.dmc.fill(byte);
+	}
+
+	/// The sample the DMC is playing, for audio debugging.
+	pub fn dmc_status(&self) -> DMCStatus {
+		self.dmc.status()
+	}
+
+	/// The output levels of the channels, in the order of `Channel`. Only the DMC has an output so far.
+	fn channel_levels(&self) -> [u8; 5] {
+		[0, 0, 0, 0, self.dmc.output()]
+	}
+
+	/// What a channel plays now, for the visualizers and the debugger.
+	pub fn channel_state(&self, channel: Channel) -> ChannelState {
+		let level = self.channel_levels()[channel as usize];
+		let (length, period, muted) = match channel {
+			Channel::Pulse1 => (&self.pulse1.length, Some(self.pulse1.timer_period()), self.pulse1.muted()),
+			Channel::Pulse2 => (&self.pulse2.length, Some(self.pulse2.timer_period()), self.pulse2.muted()),
+			Channel::Triangle => (&self.triangle.length, None, false),
+			Channel::Noise => (&self.noise.length, None, false),
+			Channel::DMC => {
+				let status = self.dmc.status();
+				return ChannelState { channel, enabled: self.dmc.active(), length: status.bytes_remaining, period: Some(self.dmc.rate()), level, muted: false };
+			}
+		};
+		ChannelState { channel, enabled: length.active(), length: length.counter() as u16, period, level, muted }
+	}
+
+	/// Log the notes the channels play from now on, see `NoteLog`.
+	pub fn start_note_log(&mut self) {
+		self.notes = Some(NoteLog::new());
+	}
+
+	pub fn note_log(&self) -> Option<&NoteLog> {
+		self.notes.as_ref()
+	}
+
+	/// The recent output levels of the channels, see `Scope::waveform`.
+	pub fn scope(&self) -> &Scope {
+		&self.scope
+	}
+
+	/// Mix the channel outputs (pulse: 0-15, triangle: 0-15, noise: 0-15, DMC: 0-127) to 0-`LEVEL_ONE`.
+	/// Linear approximation of the nonlinear DAC, read here: https://www.nesdev.org/wiki/APU_Mixer
+	pub fn mix(pulse1: u8, pulse2: u8, triangle: u8, noise: u8, dmc: u8) -> Level {
+		// 0.00851, 0.00494 and 0.00335 of LEVEL_ONE
+		let pulse_out = PULSE_STEP * (pulse1 + pulse2) as Level;
+		let tnd_out = 558 * triangle as Level + 324 * noise as Level + 220 * dmc as Level;
+		pulse_out + tnd_out
+	}
+
+	/// The volumes of the cartridge audio chips. They are settings, not saved in save states.
+	pub fn expansion_volumes(&self) -> &ExpansionVolumes {
+		&self.expansion_volumes
+	}
+
+	pub fn set_expansion_volumes(&mut self, volumes: ExpansionVolumes) {
+		self.expansion_volumes = volumes;
+	}
+
+	/// How the samples are made from the APU output, linear by default. Changing it restarts the resampling.
+	pub fn set_resampler_quality(&mut self, quality: ResamplerQuality) {
+		self.resampler = Resampler::new(quality, self.resampler.rate());
+	}
+
+	/// The samples made per second of emulated time, `SAMPLE_RATE` by default. The frontend changes it slightly to keep
+	/// its audio buffer filled (see `audio::RateControl`), the audio device still plays them at `SAMPLE_RATE`.
+	pub fn set_sample_rate(&mut self, rate: u64) {
+		self.resampler.set_rate(rate);
+	}
+
+	pub fn sample_rate(&self) -> u64 {
+		self.resampler.rate()
+	}
+
+	pub fn resampler_quality(&self) -> ResamplerQuality {
+		self.resampler.quality()
+	}
+
+	/// Without audio no samples are made (nor the scope levels), which is faster for bots and tests that don't listen.
+	/// The channels still run, the games see no difference.
+	pub fn set_audio_enabled(&mut self, enabled: bool) {
+		self.audio = enabled;
+	}
+
+	pub fn audio_enabled(&self) -> bool {
+		self.audio
+	}
+
+	/// The samples since the last call, at `sample_rate`.
+	pub fn take_samples(&mut self) -> Vec<i16> {
+		std::mem::take(&mut self.samples)
+	}
+
+	/// Amount of samples since power on.
+	pub fn samples_generated(&self) -> u64 {
+		self.samples_generated
+	}
+}
+
+/// A level as a 16 bit sample, `LEVEL_ONE` is full scale.
+fn to_sample(level: Level) -> i16 {
+	(level >> 1).clamp(i16::MIN as Level, i16::MAX as Level) as i16
+}
+
+/// The samples that were not taken yet are not saved.
+impl Serialize for APU {
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.cycles);
+		s.value(&mut self.samples_generated);
+		s.value(&mut self.frame_counter_cycles);
+		s.value(&mut self.five_step_mode);
+		s.value(&mut self.frame_irq_inhibit);
+		s.value(&mut self.frame_irq);
+		s.value(&mut self.pulse1);
+		s.value(&mut self.pulse2);
+		s.value(&mut self.triangle);
+		s.value(&mut self.noise);
+		s.value(&mut self.frame_counter_delay);
+		s.value(&mut self.frame_counter_mode);
+		s.value(&mut self.dmc);
+		s.value(&mut self.resampler);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::cpu::cpu::CPU_FREQUENCY;
+	use super::{APU, LEVEL_ONE, SAMPLE_RATE, FOUR_STEP_CYCLES, FIVE_STEP_CYCLES};
+	use crate::apu::length_counter::LengthCounter;
+	use crate::apu::scope::{Channel, SCOPE_LENGTH};
+
+	/// The registers of the channels with a length counter: the first register and its halt bit.
+	const CHANNELS: [(u16, u8); 4] = [(0x4000, 0x20), (0x4004, 0x20), (0x4008, 0x80), (0x400C, 0x20)];
+
+	fn length(apu: &APU, channel: usize) -> &LengthCounter {
+		[&apu.pulse1.length, &apu.pulse2.length, &apu.triangle.length, &apu.noise.length][channel]
+	}
+
+	/// Clock until the next clock is the half frame clock at cycle 14913 of the 4-step sequence.
+	fn clock_until_half_frame(apu: &mut APU) {
+		while apu.frame_counter_cycles != 14912 {
+			apu.clock(0);
+		}
+	}
+
+	#[test]
+	fn test_sample_rate_and_expansion_mixing() {
+		let mut apu = APU::new();
+		for _ in 0..CPU_FREQUENCY {
+			apu.clock(LEVEL_ONE / 4);
+		}
+		assert_eq!(apu.samples_generated(), SAMPLE_RATE);
+
+		let samples = apu.take_samples();
+		assert_eq!(samples.len() as u64, SAMPLE_RATE);
+		assert!(samples.iter().all(|&sample| sample == 8192));
+		assert!(apu.take_samples().is_empty());
+
+		// Both pulse channels at full volume
+		assert_eq!(APU::mix(15, 15, 0, 0, 0), 14790);
+		assert_eq!(APU::mix(15, 15, 15, 15, 127), 14790 + 8370 + 4860 + 27940);
+	}
+
+	#[test]
+	fn test_channel_state() {
+		let mut apu = APU::new();
+		apu.write_register(0x4015, 0x01);
+		apu.write_register(0x4002, 253);
+		apu.write_register(0x4003, 0x08);	// Length index 1: 254
+		apu.clock(0);
+		let pulse = apu.channel_state(Channel::Pulse1);
+		assert_eq!((pulse.enabled, pulse.length, pulse.period, pulse.muted), (true, 254, Some(253), false));
+		assert_eq!(pulse.frequency().unwrap().round(), 440.0);
+		assert!(!apu.channel_state(Channel::Pulse2).enabled);
+
+		// The DMC output level is in the scope, the newest last
+		apu.write_register(0x4011, 0x40);
+		for _ in 0..CPU_FREQUENCY / 60 {
+			apu.clock(0);
+		}
+		assert_eq!(apu.channel_state(Channel::DMC).level, 0x40);
+		let waveform = apu.scope().waveform(Channel::DMC);
+		assert_eq!((waveform.len(), waveform[SCOPE_LENGTH - 1]), (SCOPE_LENGTH, 0x40));
+	}
+
+	#[test]
+	fn test_frame_irq() {
+		let mut apu = APU::new();
+		for _ in 0..FOUR_STEP_CYCLES - 1 {
+			apu.clock(0);
+		}
+		assert!(!apu.frame_irq());
+		apu.clock(0);
+		assert!(apu.frame_irq());
+
+		// Peeking doesn't acknowledge, reading does
+		assert_eq!(apu.read_status(true), 0x40);
+		assert_eq!(apu.read_status(false), 0x40);
+		assert!(!apu.frame_irq());
+
+		// No IRQ when inhibited, or in 5-step mode
+		for value in [0x40, 0x80] {
+			apu.write_register(0x4017, value);
+			for _ in 0..FIVE_STEP_CYCLES * 2 {
+				apu.clock(0);
+			}
+			assert!(!apu.frame_irq());
+		}
+	}
+
+	#[test]
+	fn test_length_counters() {
+		for (channel, (register, halt)) in CHANNELS.into_iter().enumerate() {
+			let mut apu = APU::new();
+			// Disabled channels ignore the load
+			apu.write_register(register + 3, 0b0000_1000);
+			apu.clock(0);
+			assert_eq!(length(&apu, channel).counter(), 0);
+
+			apu.write_register(0x4015, 0x0F);
+			apu.write_register(register + 3, 0b0000_1000); // Index 1: 254
+			apu.clock(0);
+			assert_eq!(length(&apu, channel).counter(), 254, "channel {}", channel);
+			assert_eq!(apu.read_status(false), 1 << channel);
+			apu.write_register(register + 3, 0b1111_1000); // Index 31: 30
+			apu.clock(0);
+			assert_eq!(length(&apu, channel).counter(), 30);
+
+			// 2 half frames in the 4-step sequence
+			for _ in 0..FOUR_STEP_CYCLES {
+				apu.clock(0);
+			}
+			assert_eq!(length(&apu, channel).counter(), 28);
+			// The 5-step mode clocks when the sequence restarts
+			apu.write_register(0x4017, 0x80);
+			for _ in 0..4 {
+				apu.clock(0);
+			}
+			assert_eq!(length(&apu, channel).counter(), 27);
+			apu.write_register(0x4017, 0x00);
+
+			// Halted, the counter stays
+			apu.write_register(register, halt);
+			for _ in 0..FOUR_STEP_CYCLES {
+				apu.clock(0);
+			}
+			assert_eq!(length(&apu, channel).counter(), 27);
+			apu.write_register(register, 0);
+
+			// Clearing the halt flag on the half frame clock applies after it
+			apu.write_register(register, halt);
+			clock_until_half_frame(&mut apu);
+			apu.write_register(register, 0);
+			apu.clock(0);
+			assert_eq!(length(&apu, channel).counter(), 27);
+			// Setting it too: the clock still decrements (after the half frame clock at the end of the sequence)
+			clock_until_half_frame(&mut apu);
+			apu.write_register(register, halt);
+			apu.clock(0);
+			assert_eq!(length(&apu, channel).counter(), 25);
+			apu.write_register(register, 0);
+
+			// A reload on the half frame clock is ignored when the clock decremented the counter
+			clock_until_half_frame(&mut apu);
+			apu.write_register(register + 3, 0b0000_1000);
+			apu.clock(0);
+			assert_eq!(length(&apu, channel).counter(), 23);
+
+			// Disabling clears the counter, and a reload on the half frame clock of a counter at 0 works
+			apu.write_register(0x4015, 0x00);
+			assert_eq!(apu.read_status(false) & 0x0F, 0);
+			apu.write_register(0x4015, 0x0F);
+			clock_until_half_frame(&mut apu);
+			apu.write_register(register + 3, 0b0000_1000);
+			apu.clock(0);
+			assert_eq!(length(&apu, channel).counter(), 254);
+		}
+	}
+
+	#[test]
+	fn test_frame_counter_write_delay() {
+		// Like blargg's apu_test 4017_timing: the restart is 3 or 4 CPU cycles after the write, by the cycle parity
+		for (parity, delay) in [(0, 3), (1, 4)] {
+			let mut apu = APU::new();
+			apu.write_register(0x4015, 0x01);
+			apu.write_register(0x4003, 0b0000_1000);
+			while apu.cycles % 2 != parity || apu.frame_counter_cycles < 100 {
+				apu.clock(0);
+			}
+			apu.write_register(0x4017, 0x80);
+			for _ in 0..delay - 1 {
+				apu.clock(0);
+			}
+			assert!(apu.frame_counter_cycles > 100);
+			assert_eq!(apu.pulse1.length.counter(), 254);
+			// The 5-step mode clocks the half frame when the sequence restarts
+			apu.clock(0);
+			assert_eq!(apu.frame_counter_cycles, 0);
+			assert_eq!(apu.pulse1.length.counter(), 253);
+			assert!(apu.five_step_mode);
+		}
+
+		// The old sequence goes on until the restart, the IRQ inhibit is immediate
+		let mut apu = APU::new();
+		for _ in 0..FOUR_STEP_CYCLES - 2 {
+			apu.clock(0);
+		}
+		apu.write_register(0x4017, 0x00);
+		apu.clock(0);
+		apu.clock(0);
+		assert!(apu.frame_irq());
+		apu.write_register(0x4017, 0x40);
+		assert!(!apu.frame_irq());
+	}
+}
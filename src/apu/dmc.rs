@@ -0,0 +1,249 @@
+use std::fmt;
+
+use crate::savestate::{Serialize, Serializer};
+
+/// CPU cycles between output bits, indexed by the rate of $4010 (NTSC).
+const RATES: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+
+/// Delta modulation channel ($4010-$4013): plays 1 bit delta samples from PRG ROM. Read here:
+/// https://www.nesdev.org/wiki/APU_DMC
+///
+/// The memory reader doesn't read the memory itself, the CPU reads the byte `fetch_address` asks for through the mapper
+/// and gives it with `fill`, so the samples can be in any bank. The cycles the CPU is stalled by the fetch are not
+/// emulated.
+#[derive(Clone, Copy, Default)]
+pub struct DMC {
+	irq_enabled: bool,
+	irq: bool,
+	looping: bool,
+	rate: u16,				// CPU cycles between output bits
+	timer: u16,
+	output: u8,				// 7 bits
+	sample_address: u16,	// $4012: $C000-$FFC0
+	sample_length: u16,		// $4013: 1-4081 bytes
+
+	// Memory reader
+	address: u16,			// The next byte to fetch
+	bytes_remaining: u16,
+	sample_buffer: Option<u8>,
+
+	// Output unit
+	shift_register: u8,
+	bits_remaining: u8,
+	silence: bool,
+}
+
+/// What the DMC is playing, for the debugger.
+pub struct DMCStatus {
+	pub sample_address: u16,
+	pub sample_length: u16,
+	pub address: u16,
+	pub bytes_remaining: u16,
+	pub output: u8,
+	pub looping: bool,
+	pub irq: bool,
+}
+
+impl fmt::Display for DMCStatus {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "sample ${:04X} ({} bytes{}), playing ${:04X} ({} bytes remaining), output {}, IRQ {}",
+			self.sample_address, self.sample_length, if self.looping { ", looping" } else { "" },
+			self.address, self.bytes_remaining, self.output, if self.irq { "asserted" } else { "clear" })
+	}
+}
+
+impl DMC {
+	pub fn new() -> Self {
+		DMC {
+			rate: RATES[0],
+			sample_address: 0xC000,
+			sample_length: 1,
+			bits_remaining: 8,
+			silence: true,
+			..Default::default()
+		}
+	}
+
+	/// Write register 0-3 of the channel.
+	pub fn write_register(&mut self, reg: u16, value: u8) {
+		match reg {
+			0 => {
+				self.irq_enabled = value & 0x80 != 0;
+				if !self.irq_enabled {
+					self.irq = false;
+				}
+				self.looping = value & 0x40 != 0;
+				self.rate = RATES[value as usize & 0x0F];
+			}
+			1 => self.output = value & 0x7F,
+			2 => self.sample_address = 0xC000 + value as u16 * 64,
+			3 => self.sample_length = value as u16 * 16 + 1,
+			_ => (),
+		}
+	}
+
+	/// $4015 write: disabling stops the sample, enabling starts it when it isn't playing. Acknowledges the IRQ.
+	pub fn set_enabled(&mut self, enabled: bool) {
+		self.irq = false;
+		if !enabled {
+			self.bytes_remaining = 0;
+		} else if self.bytes_remaining == 0 {
+			self.restart();
+		}
+	}
+
+	fn restart(&mut self) {
+		self.address = self.sample_address;
+		self.bytes_remaining = self.sample_length;
+	}
+
+	/// A CPU cycle.
+	pub fn clock(&mut self) {
+		if self.timer > 0 {
+			self.timer -= 1;
+			return;
+		}
+		self.timer = self.rate - 1;
+
+		if !self.silence {
+			// Bit 1 adds 2 to the output, bit 0 subtracts 2, unless it goes out of 0-127
+			if self.shift_register & 1 != 0 {
+				if self.output <= 125 {
+					self.output += 2;
+				}
+			} else if self.output >= 2 {
+				self.output -= 2;
+			}
+		}
+		self.shift_register >>= 1;
+		self.bits_remaining -= 1;
+		if self.bits_remaining == 0 {
+			self.bits_remaining = 8;
+			match self.sample_buffer.take() {
+				Some(byte) => {
+					self.shift_register = byte;
+					self.silence = false;
+				}
+				None => self.silence = true,
+			}
+		}
+	}
+
+	/// The address the memory reader needs a byte from, when the sample buffer is empty and the sample isn't over.
+	pub fn fetch_address(&self) -> Option<u16> {
+		(self.sample_buffer.is_none() && self.bytes_remaining > 0).then_some(self.address)
+	}
+
+	/// The byte at `fetch_address`. The address wraps from $FFFF to $8000.
+	pub fn fill(&mut self, byte: u8) {
+		self.sample_buffer = Some(byte);
+		self.address = if self.address == 0xFFFF { 0x8000 } else { self.address + 1 };
+		self.bytes_remaining -= 1;
+		if self.bytes_remaining == 0 {
+			if self.looping {
+				self.restart();
+			} else if self.irq_enabled {
+				self.irq = true;
+			}
+		}
+	}
+
+	/// $4015 bit 4: the sample is playing.
+	pub fn active(&self) -> bool {
+		self.bytes_remaining > 0
+	}
+
+	pub fn irq(&self) -> bool {
+		self.irq
+	}
+
+	/// CPU cycles between output bits.
+	pub fn rate(&self) -> u16 {
+		self.rate
+	}
+
+	/// The output level, 0-127.
+	pub fn output(&self) -> u8 {
+		self.output
+	}
+
+	pub fn status(&self) -> DMCStatus {
+		DMCStatus {
+			sample_address: self.sample_address,
+			sample_length: self.sample_length,
+			address: self.address,
+			bytes_remaining: self.bytes_remaining,
+			output: self.output,
+			looping: self.looping,
+			irq: self.irq,
+		}
+	}
+}
+
+impl Serialize for DMC {
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.irq_enabled);
+		s.value(&mut self.irq);
+		s.value(&mut self.looping);
+		s.value(&mut self.rate);
+		s.value(&mut self.timer);
+		s.value(&mut self.output);
+		s.value(&mut self.sample_address);
+		s.value(&mut self.sample_length);
+		s.value(&mut self.address);
+		s.value(&mut self.bytes_remaining);
+		let mut buffer = self.sample_buffer.map_or(0, |byte| 0x100 | byte as u16);
+		s.value(&mut buffer);
+		self.sample_buffer = (buffer & 0x100 != 0).then_some(buffer as u8);
+		s.value(&mut self.shift_register);
+		s.value(&mut self.bits_remaining);
+		s.value(&mut self.silence);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::DMC;
+
+	/// Play the sample, feeding the reader from `memory` (at $8000-$FFFF), and return the addresses read.
+	fn play(dmc: &mut DMC, memory: &[u8], cycles: usize) -> Vec<u16> {
+		let mut addresses = vec![];
+		for _ in 0..cycles {
+			dmc.clock();
+			if let Some(addr) = dmc.fetch_address() {
+				addresses.push(addr);
+				dmc.fill(memory[addr as usize - 0x8000]);
+			}
+		}
+		addresses
+	}
+
+	#[test]
+	fn test_sample_wraps_to_8000() {
+		let mut memory = vec![0; 0x8000];
+		memory[0x7FFF] = 0xFF;
+		memory[0] = 0xFF;
+		let mut dmc = DMC::new();
+		dmc.write_register(0, 0x8F);	// IRQ, fastest rate
+		dmc.write_register(2, 0xFF);	// $FFC0
+		dmc.write_register(3, 0x04);	// 65 bytes
+		dmc.set_enabled(true);
+		let addresses = play(&mut dmc, &memory, 54 * 8 * 70);
+		assert_eq!(addresses.len(), 65);
+		assert_eq!(addresses[63..], [0xFFFF, 0x8000]);
+		assert!(!dmc.active());
+		assert!(dmc.irq());
+		// The bytes at $FFFF and $8000 are all 1 bits: the output goes up 2 per bit
+		assert_eq!(dmc.output(), 32);
+
+		// Looping restarts without IRQ
+		dmc.write_register(0, 0x4F);
+		dmc.set_enabled(true);
+		assert!(!dmc.irq());
+		let addresses = play(&mut dmc, &memory, 54 * 8 * 70);
+		assert_eq!(addresses[65..67], [0xFFC0, 0xFFC1]);
+		assert!(dmc.active());
+		assert_eq!(dmc.status().to_string(), format!("sample $FFC0 (65 bytes, looping), playing ${:04X} ({} bytes remaining), output {}, IRQ clear",
+			dmc.status().address, dmc.status().bytes_remaining, dmc.output()));
+	}
+}
@@ -0,0 +1,87 @@
+use crate::config::Config;
+
+use super::apu::Level;
+
+/// The volumes are in fixed point, this is 1.0.
+const VOLUME_ONE: i64 = 256;
+
+/// The audio chips of cartridges, mixed with the APU through the cartridge connector. Read here:
+/// https://www.nesdev.org/wiki/Expansion_audio
+///
+/// A mapper lists its chips with `Mapper::audio_sources` and gives the output of each with `Mapper::audio_output`. Only
+/// the VRC6, the FDS and the Sunsoft 5B are emulated so far.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExpansionAudio {
+	Vrc6,
+	Vrc7,		// FM synthesis (Lagrange Point)
+	Fds,
+	Mmc5,
+	Namco163,
+	Sunsoft5B,
+}
+
+impl ExpansionAudio {
+	pub const ALL: [ExpansionAudio; 6] = [ExpansionAudio::Vrc6, ExpansionAudio::Vrc7, ExpansionAudio::Fds, ExpansionAudio::Mmc5, ExpansionAudio::Namco163, ExpansionAudio::Sunsoft5B];
+
+	/// The name in the settings file (`audio.<name>.volume`).
+	pub fn name(self) -> &'static str {
+		match self {
+			ExpansionAudio::Vrc6 => "vrc6",
+			ExpansionAudio::Vrc7 => "vrc7",
+			ExpansionAudio::Fds => "fds",
+			ExpansionAudio::Mmc5 => "mmc5",
+			ExpansionAudio::Namco163 => "n163",
+			ExpansionAudio::Sunsoft5B => "5b",
+		}
+	}
+}
+
+/// The volume of each expansion audio chip. 1.0 is the level of the hardware relative to the APU (the level differs
+/// between Famicom models, so games may sound better a bit louder or quieter), 0.0 mutes the chip. They are kept in
+/// fixed point (1/256 steps), the mixing is integer only.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExpansionVolumes([i64; ExpansionAudio::ALL.len()]);
+
+impl Default for ExpansionVolumes {
+	fn default() -> Self {
+		ExpansionVolumes([VOLUME_ONE; ExpansionAudio::ALL.len()])
+	}
+}
+
+impl ExpansionVolumes {
+	/// The `audio.<chip>.volume` settings.
+	pub fn from_config(config: &Config) -> Self {
+		let mut volumes = ExpansionVolumes::default();
+		for source in ExpansionAudio::ALL {
+			volumes.set(source, config.get(&format!("audio.{}.volume", source.name()), 1.0));
+		}
+		volumes
+	}
+
+	pub fn get(&self, source: ExpansionAudio) -> f32 {
+		self.0[source as usize] as f32 / VOLUME_ONE as f32
+	}
+
+	/// Negative volumes are 0.
+	pub fn set(&mut self, source: ExpansionAudio, volume: f32) {
+		self.0[source as usize] = (volume.max(0.0) * VOLUME_ONE as f32).round() as i64;
+	}
+
+	/// The output level of a chip at its volume.
+	pub fn apply(&self, source: ExpansionAudio, level: Level) -> Level {
+		(level as i64 * self.0[source as usize] / VOLUME_ONE) as Level
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::config::Config;
+	use super::{ExpansionAudio, ExpansionVolumes};
+
+	#[test]
+	fn test_volumes_from_config() {
+		let volumes = ExpansionVolumes::from_config(&Config::parse("audio.fds.volume = 0.5\naudio.n163.volume = -1\naudio.vrc6.volume = loud"));
+		let expected = [1.0, 1.0, 0.5, 1.0, 0.0, 1.0];
+		assert_eq!(ExpansionAudio::ALL.map(|source| volumes.get(source)), expected);
+	}
+}
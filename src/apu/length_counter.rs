@@ -0,0 +1,84 @@
+use crate::savestate::{Serialize, Serializer};
+
+/// Length counter values, indexed by bits 3-7 of the last register of a channel.
+/// Read here: https://www.nesdev.org/wiki/APU_Length_Counter
+pub const LENGTH_TABLE: [u8; 32] = [
+	10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+	12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+/// Silences a channel after a number of half frames, unless halted. Pulse, triangle and noise have one.
+///
+/// Writes take effect at the end of the CPU cycle, after the frame counter: a reload written on the cycle of a half
+/// frame clock is ignored when the clock decremented the counter, and a halt flag written on that cycle applies after
+/// the clock.
+#[derive(Clone, Copy, Default)]
+pub struct LengthCounter {
+	counter: u8,
+	enabled: bool,			// $4015 bit of the channel
+	halt: bool,
+	new_halt: bool,			// Written this cycle
+	reload: u8,				// Written this cycle, 0 when none
+	previous_counter: u8,	// The counter when the reload was written
+}
+
+impl LengthCounter {
+	/// $4015 write. Disabling the channel clears the counter.
+	pub fn set_enabled(&mut self, enabled: bool) {
+		self.enabled = enabled;
+		if !enabled {
+			self.counter = 0;
+			self.reload = 0;
+		}
+	}
+
+	pub fn set_halt(&mut self, halt: bool) {
+		self.new_halt = halt;
+	}
+
+	/// Load the counter from `LENGTH_TABLE`. Ignored while the channel is disabled.
+	pub fn load(&mut self, index: u8) {
+		if self.enabled {
+			self.reload = LENGTH_TABLE[index as usize & 0x1F];
+			self.previous_counter = self.counter;
+		}
+	}
+
+	/// Half frame clock of the frame counter.
+	pub fn clock(&mut self) {
+		if self.counter > 0 && !self.halt {
+			self.counter -= 1;
+		}
+	}
+
+	/// Apply the writes of the CPU cycle.
+	pub fn end_cycle(&mut self) {
+		if self.reload != 0 {
+			if self.counter == self.previous_counter {
+				self.counter = self.reload;
+			}
+			self.reload = 0;
+		}
+		self.halt = self.new_halt;
+	}
+
+	/// The channel plays while the counter isn't 0 ($4015 read).
+	pub fn active(&self) -> bool {
+		self.counter > 0
+	}
+
+	pub fn counter(&self) -> u8 {
+		self.counter
+	}
+}
+
+impl Serialize for LengthCounter {
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.counter);
+		s.value(&mut self.enabled);
+		s.value(&mut self.halt);
+		s.value(&mut self.new_halt);
+		s.value(&mut self.reload);
+		s.value(&mut self.previous_counter);
+	}
+}
@@ -0,0 +1,11 @@
+pub mod apu;
+pub mod dmc;
+pub mod expansion;
+pub mod length_counter;
+pub mod noise;
+pub mod notes;
+pub mod pulse;
+pub mod resampler;
+pub mod scope;
+pub mod sink;
+pub mod triangle;
\ No newline at end of file
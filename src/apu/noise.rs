@@ -0,0 +1,26 @@
+use crate::savestate::{Serialize, Serializer};
+
+use super::length_counter::LengthCounter;
+
+/// Noise channel ($400C-$400F). Read here: https://www.nesdev.org/wiki/APU_Noise
+#[derive(Clone, Copy, Default)]
+pub struct Noise {
+	pub length: LengthCounter,
+}
+
+impl Noise {
+	/// Write register 0-3 of the channel ($400D is unused).
+	pub fn write_register(&mut self, reg: u16, value: u8) {
+		match reg {
+			0 => self.length.set_halt(value & 0x20 != 0),
+			3 => self.length.load(value >> 3),
+			_ => (),
+		}
+	}
+}
+
+impl Serialize for Noise {
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.length);
+	}
+}
@@ -0,0 +1,313 @@
+use std::fs;
+
+use crate::cpu::cpu::CPU_FREQUENCY;
+use super::scope::Channel;
+
+/// The periods of the noise channel (NTSC), by the index written to $400E.
+const NOISE_PERIODS: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+
+/// MIDI time: ticks per quarter note. At the default tempo (120 BPM, a quarter note is 500000µs) 960 ticks are a second.
+const TICKS_PER_QUARTER: u16 = 480;
+const TICKS_PER_SECOND: u64 = 960;
+/// The MIDI channel of the drums (channel 10).
+const PERCUSSION: u8 = 9;
+/// General MIDI program of the pulse channels: Lead 1 (square).
+const SQUARE_LEAD: u8 = 80;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoteKind {
+	On,
+	Period,	// The period changed while the note plays: a vibrato, a slide or the next note without a new trigger
+	Off,
+}
+
+impl NoteKind {
+	fn name(self) -> &'static str {
+		match self {
+			NoteKind::On => "on",
+			NoteKind::Period => "period",
+			NoteKind::Off => "off",
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NoteEvent {
+	pub cycle: u64,			// CPU cycles since power on
+	pub channel: Channel,
+	pub kind: NoteKind,
+	pub period: u16,		// The timer period, 0 for the DMC
+	pub volume: u8,			// 0-15, 15 when the envelope decides it
+}
+
+impl NoteEvent {
+	/// The nearest MIDI note, 69 is A4 (440 Hz). The noise and the DMC are drums: a closed hi-hat for the short noise
+	/// periods, a snare for the long ones, a bass drum for the DMC samples.
+	pub fn midi_note(&self) -> u8 {
+		match self.channel {
+			Channel::Noise if self.period <= 160 => 42,
+			Channel::Noise => 38,
+			Channel::DMC => 36,
+			_ => (69.0 + 12.0 * (self.channel.frequency(self.period) / 440.0).log2()).round().clamp(0.0, 127.0) as u8,
+		}
+	}
+}
+
+/// Observes the writes to the APU registers and logs the notes of the channels, to get the melodies of a game as a MIDI
+/// or CSV file.
+///
+/// A note starts when the length counter of the channel is loaded ($4003, $4007, $400B, $400F) or $4015 starts a DMC
+/// sample. The writes to the timer ($4002, $4006, $400A, $400E) change its period. It ends when $4015 disables the
+/// channel, the volume is set to 0 (the triangle: the linear counter), or the length counter runs out. The period
+/// changes of the pulse sweeps aren't writes, they are not logged.
+#[derive(Clone)]
+pub struct NoteLog {
+	periods: [u16; 5],
+	volumes: [u8; 5],
+	enabled: [bool; 5],	// $4015
+	playing: [bool; 5],
+	events: Vec<NoteEvent>,
+}
+
+impl Default for NoteLog {
+	fn default() -> Self {
+		NoteLog::new()
+	}
+}
+
+impl NoteLog {
+	pub fn new() -> Self {
+		NoteLog { periods: [0; 5], volumes: [15; 5], enabled: [false; 5], playing: [false; 5], events: vec![] }
+	}
+
+	/// A write to $4000-$4013, $4015 or $4017.
+	pub fn write(&mut self, cycle: u64, addr: u16, value: u8) {
+		match addr {
+			0x4015 => {
+				for channel in Channel::ALL {
+					self.enabled[channel as usize] = value & (1 << channel as u8) != 0;
+					if !self.enabled[channel as usize] {
+						self.end(cycle, channel);
+					} else if channel == Channel::DMC && !self.playing[channel as usize] {
+						self.start(cycle, channel);
+					}
+				}
+			}
+			0x4000..=0x400F => {
+				let channel = Channel::ALL[(addr - 0x4000) as usize / 4];
+				let index = channel as usize;
+				match (channel, addr & 3) {
+					(Channel::Triangle, 0) => self.volumes[index] = if value & 0x7F == 0 { 0 } else { 15 },
+					// Constant volume, or the envelope
+					(_, 0) => self.volumes[index] = if value & 0x10 != 0 { value & 0x0F } else { 15 },
+					(Channel::Noise, 2) => self.set_period(cycle, channel, NOISE_PERIODS[value as usize & 0x0F]),
+					(Channel::Noise, 3) => self.start(cycle, channel),
+					(_, 2) => self.set_period(cycle, channel, (self.periods[index] & 0x700) | value as u16),
+					(_, 3) => {
+						self.periods[index] = (self.periods[index] & 0xFF) | ((value as u16 & 0b111) << 8);
+						self.start(cycle, channel);
+					}
+					_ => {}
+				}
+				if addr & 3 == 0 && self.volumes[index] == 0 {
+					self.end(cycle, channel);
+				}
+			}
+			_ => {}
+		}
+	}
+
+	/// Each half frame: the channels whose length counter ran out (the DMC: the sample ended) end their notes. `active`
+	/// is in the order of `Channel`.
+	pub fn update(&mut self, cycle: u64, active: [bool; 5]) {
+		for channel in Channel::ALL {
+			if !active[channel as usize] {
+				self.end(cycle, channel);
+			}
+		}
+	}
+
+	fn start(&mut self, cycle: u64, channel: Channel) {
+		let index = channel as usize;
+		if self.enabled[index] && self.volumes[index] > 0 {
+			self.playing[index] = true;
+			self.push(cycle, channel, NoteKind::On);
+		}
+	}
+
+	fn set_period(&mut self, cycle: u64, channel: Channel, period: u16) {
+		let index = channel as usize;
+		let changed = self.periods[index] != period;
+		self.periods[index] = period;
+		if changed && self.playing[index] {
+			self.push(cycle, channel, NoteKind::Period);
+		}
+	}
+
+	fn end(&mut self, cycle: u64, channel: Channel) {
+		if self.playing[channel as usize] {
+			self.playing[channel as usize] = false;
+			self.push(cycle, channel, NoteKind::Off);
+		}
+	}
+
+	fn push(&mut self, cycle: u64, channel: Channel, kind: NoteKind) {
+		let period = if channel == Channel::DMC { 0 } else { self.periods[channel as usize] };
+		self.events.push(NoteEvent { cycle, channel, kind, period, volume: self.volumes[channel as usize] });
+	}
+
+	pub fn events(&self) -> &[NoteEvent] {
+		&self.events
+	}
+
+	/// A line for each event: the time, the channel, on/period/off, the period, its frequency and MIDI note, the volume.
+	pub fn to_csv(&self) -> String {
+		let mut csv = String::from("cycle,seconds,channel,event,period,frequency,note,volume\n");
+		for event in &self.events {
+			let frequency = if event.channel == Channel::DMC { String::new() } else { format!("{:.2}", event.channel.frequency(event.period)) };
+			csv += &format!("{},{:.6},{},{},{},{},{},{}\n", event.cycle, event.cycle as f64 / CPU_FREQUENCY as f64,
+				event.channel.name(), event.kind.name(), event.period, frequency, event.midi_note(), event.volume);
+		}
+		csv
+	}
+
+	/// A standard MIDI file (format 1) with a track for each channel. The pulse channels play a square lead, the
+	/// triangle the default piano, the noise and the DMC the drums. The volume is the velocity.
+	pub fn to_midi(&self) -> Vec<u8> {
+		let mut midi = b"MThd".to_vec();
+		midi.extend(6u32.to_be_bytes());
+		midi.extend(1u16.to_be_bytes());
+		midi.extend((Channel::ALL.len() as u16 + 1).to_be_bytes());
+		midi.extend(TICKS_PER_QUARTER.to_be_bytes());
+		// The tempo track: 120 BPM
+		midi.extend(track(vec![(0, vec![0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20])]));
+
+		for channel in Channel::ALL {
+			let midi_channel = match channel {
+				Channel::Noise | Channel::DMC => PERCUSSION,
+				_ => channel as u8,
+			};
+			let name = channel.name().as_bytes();
+			let mut messages = vec![(0, [vec![0xFF, 0x03, name.len() as u8], name.to_vec()].concat())];
+			if matches!(channel, Channel::Pulse1 | Channel::Pulse2) {
+				messages.push((0, vec![0xC0 | midi_channel, SQUARE_LEAD]));
+			}
+			let mut sounding: Option<u8> = None;
+			let mut tick = 0;
+			for event in self.events.iter().filter(|event| event.channel == channel) {
+				tick = event.cycle * TICKS_PER_SECOND / CPU_FREQUENCY;
+				let note = event.midi_note();
+				// A period change is a new note when it moves to another key, drums are only hit again by a trigger
+				let retrigger = match event.kind {
+					NoteKind::On => true,
+					NoteKind::Period => midi_channel != PERCUSSION && sounding.is_some_and(|sounding| sounding != note),
+					NoteKind::Off => false,
+				};
+				if let Some(sounding) = sounding.filter(|_| retrigger || event.kind == NoteKind::Off) {
+					messages.push((tick, vec![0x80 | midi_channel, sounding, 0]));
+				}
+				if retrigger {
+					messages.push((tick, vec![0x90 | midi_channel, note, event.volume * 8 + 7]));
+					sounding = Some(note);
+				} else if event.kind == NoteKind::Off {
+					sounding = None;
+				}
+			}
+			if let Some(sounding) = sounding {
+				messages.push((tick, vec![0x80 | midi_channel, sounding, 0]));
+			}
+			midi.extend(track(messages));
+		}
+		midi
+	}
+
+	/// Write the notes to a file: MIDI if it ends with .mid or .midi, else CSV.
+	pub fn save(&self, path: &str) -> Result<(), String> {
+		let lower = path.to_lowercase();
+		let data = if lower.ends_with(".mid") || lower.ends_with(".midi") { self.to_midi() } else { self.to_csv().into_bytes() };
+		fs::write(path, data).map_err(|e| format!("Can't write {}: {}", path, e))
+	}
+}
+
+/// A MIDI track chunk of the messages at their ticks (in order), and the end of the track.
+fn track(messages: Vec<(u64, Vec<u8>)>) -> Vec<u8> {
+	let mut data = vec![];
+	let mut last_tick = 0;
+	for (tick, message) in messages {
+		data.extend(variable_length(tick - last_tick));
+		data.extend(message);
+		last_tick = tick;
+	}
+	data.extend([0x00, 0xFF, 0x2F, 0x00]);
+	let mut chunk = b"MTrk".to_vec();
+	chunk.extend((data.len() as u32).to_be_bytes());
+	chunk.extend(data);
+	chunk
+}
+
+/// A MIDI variable-length quantity: 7 bits a byte, the most significant first, bit 7 set on all but the last.
+fn variable_length(mut value: u64) -> Vec<u8> {
+	let mut bytes = vec![(value & 0x7F) as u8];
+	value >>= 7;
+	while value > 0 {
+		bytes.insert(0, 0x80 | (value & 0x7F) as u8);
+		value >>= 7;
+	}
+	bytes
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{variable_length, NoteKind, NoteLog};
+	use crate::apu::scope::Channel;
+
+	#[test]
+	fn test_note_log() {
+		let mut log = NoteLog::new();
+		// Not enabled in $4015: no note
+		log.write(0, 0x4003, 0x08);
+		log.write(10, 0x4015, 0x05);
+		// Pulse 1 plays A4 at volume 12, slides, and is silenced
+		log.write(20, 0x4000, 0x1C);
+		log.write(30, 0x4002, 253);
+		log.write(40, 0x4003, 0x08);
+		log.write(50, 0x4002, 239);
+		log.write(60, 0x4000, 0x10);
+		// The triangle plays until its length counter runs out
+		log.write(70, 0x400B, 0x01);
+		log.update(80, [false, false, true, false, false]);
+		log.update(90, [false, false, false, false, false]);
+
+		let events: Vec<_> = log.events().iter().map(|event| (event.cycle, event.channel, event.kind, event.period)).collect();
+		assert_eq!(events, [
+			(40, Channel::Pulse1, NoteKind::On, 253),
+			(50, Channel::Pulse1, NoteKind::Period, 239),
+			(60, Channel::Pulse1, NoteKind::Off, 239),
+			(70, Channel::Triangle, NoteKind::On, 0x100),
+			(90, Channel::Triangle, NoteKind::Off, 0x100),
+		]);
+		assert_eq!((log.events()[0].midi_note(), log.events()[0].volume), (69, 12));
+		assert_eq!(log.events()[1].midi_note(), 70);
+		assert!(log.to_csv().contains("\n40,0.000022,pulse 1,on,253,440.40,69,12\n"));
+	}
+
+	#[test]
+	fn test_midi() {
+		let mut log = NoteLog::new();
+		log.write(0, 0x4015, 0x01);
+		log.write(0, 0x4002, 253);
+		log.write(0, 0x4003, 0x08);
+		log.write(1_789_773, 0x4015, 0x00);
+		let midi = log.to_midi();
+		assert_eq!(&midi[..14], b"MThd\x00\x00\x00\x06\x00\x01\x00\x06\x01\xE0");
+		// A4 on at the start, off a second (960 ticks) later
+		let on = [0x00, 0x90, 69, 127];
+		let off = [0x87, 0x40, 0x80, 69, 0];
+		assert!(midi.windows(4).any(|window| window == on));
+		assert!(midi.windows(5).any(|window| window == off));
+
+		assert_eq!(variable_length(0x7F), [0x7F]);
+		assert_eq!(variable_length(0x3FFF), [0xFF, 0x7F]);
+		assert_eq!(variable_length(0x200000), [0x81, 0x80, 0x80, 0x00]);
+	}
+}
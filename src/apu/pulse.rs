@@ -0,0 +1,158 @@
+use crate::savestate::{Serialize, Serializer};
+
+use super::length_counter::LengthCounter;
+
+/// Pulse channel ($4000-$4003 and $4004-$4007). Read here: https://www.nesdev.org/wiki/APU_Pulse
+#[derive(Clone, Copy, Default)]
+pub struct Pulse {
+	pub length: LengthCounter,
+	timer_period: u16,	// 11 bits
+	sweep: Sweep,
+	ones_complement: bool,	// Pulse 1 negates the sweep change with one's complement, pulse 2 with two's complement
+}
+
+/// Changes the period of the pulse every few half frames ($4001/$4005). Read here: https://www.nesdev.org/wiki/APU_Sweep
+#[derive(Clone, Copy, Default)]
+struct Sweep {
+	enabled: bool,
+	period: u8,		// Half frames between changes, minus 1
+	negate: bool,
+	shift: u8,
+	divider: u8,
+	reload: bool,	// The register was written, the divider reloads at the next half frame
+}
+
+impl Pulse {
+	/// Pulse 1 (`ones_complement`) or pulse 2.
+	pub fn new(ones_complement: bool) -> Self {
+		Pulse { ones_complement, ..Default::default() }
+	}
+
+	/// Write register 0-3 of the channel.
+	pub fn write_register(&mut self, reg: u16, value: u8) {
+		match reg {
+			0 => self.length.set_halt(value & 0x20 != 0),
+			1 => {
+				self.sweep.enabled = value & 0x80 != 0;
+				self.sweep.period = (value >> 4) & 0b111;
+				self.sweep.negate = value & 0x08 != 0;
+				self.sweep.shift = value & 0b111;
+				self.sweep.reload = true;
+			}
+			2 => self.timer_period = (self.timer_period & 0x700) | value as u16,
+			3 => {
+				self.timer_period = (self.timer_period & 0xFF) | ((value as u16 & 0b111) << 8);
+				self.length.load(value >> 3);
+			}
+			_ => (),
+		}
+	}
+
+	/// Half frame clock of the frame counter: the length counter and the sweep.
+	pub fn clock_half_frame(&mut self) {
+		self.length.clock();
+		let sweep = self.sweep;
+		if sweep.divider == 0 && sweep.enabled && sweep.shift > 0 && !self.muted() {
+			self.timer_period = self.target_period();
+		}
+		if self.sweep.divider == 0 || sweep.reload {
+			self.sweep.divider = sweep.period;
+			self.sweep.reload = false;
+		} else {
+			self.sweep.divider -= 1;
+		}
+	}
+
+	/// The period the sweep moves to. Computed all the time, even when the sweep is disabled, for the muting.
+	fn target_period(&self) -> u16 {
+		let change = self.timer_period >> self.sweep.shift;
+		if !self.sweep.negate {
+			self.timer_period + change
+		} else if self.ones_complement {
+			self.timer_period.saturating_sub(change + 1)
+		} else {
+			self.timer_period - change
+		}
+	}
+
+	/// The sweep mutes the channel when the period is too short (over 12.4 kHz), or the target period overflows 11
+	/// bits, even if the sweep is disabled.
+	pub fn muted(&self) -> bool {
+		self.timer_period < 8 || self.target_period() > 0x7FF
+	}
+
+	pub fn timer_period(&self) -> u16 {
+		self.timer_period
+	}
+}
+
+impl Serialize for Pulse {
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.length);
+		s.value(&mut self.timer_period);
+		s.value(&mut self.sweep);
+	}
+}
+
+impl Serialize for Sweep {
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.enabled);
+		s.value(&mut self.period);
+		s.value(&mut self.negate);
+		s.value(&mut self.shift);
+		s.value(&mut self.divider);
+		s.value(&mut self.reload);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Pulse;
+
+	fn new_pulse(ones_complement: bool, period: u16, sweep: u8) -> Pulse {
+		let mut pulse = Pulse::new(ones_complement);
+		pulse.write_register(2, period as u8);
+		pulse.write_register(3, (period >> 8) as u8);
+		pulse.write_register(1, sweep);
+		pulse
+	}
+
+	#[test]
+	fn test_sweep() {
+		// Negate with shift 1: pulse 1 subtracts one more
+		let [mut pulse1, mut pulse2] = [true, false].map(|ones_complement| new_pulse(ones_complement, 0x100, 0b1000_1001));
+		assert_eq!((pulse1.target_period(), pulse2.target_period()), (0x7F, 0x80));
+		pulse1.clock_half_frame();
+		pulse2.clock_half_frame();
+		assert_eq!((pulse1.timer_period(), pulse2.timer_period()), (0x7F, 0x80));
+
+		// Every 3 half frames (period 2), up
+		let mut pulse = new_pulse(false, 0x100, 0b1010_0010);
+		let periods: Vec<u16> = (0..7).map(|_| { pulse.clock_half_frame(); pulse.timer_period() }).collect();
+		assert_eq!(periods, [0x140, 0x140, 0x140, 0x190, 0x190, 0x190, 0x1F4]);
+
+		// Shift 0 or disabled: the period stays
+		for sweep in [0b1000_0000, 0b0000_0001] {
+			let mut pulse = new_pulse(false, 0x100, sweep);
+			pulse.clock_half_frame();
+			assert_eq!(pulse.timer_period(), 0x100);
+		}
+	}
+
+	#[test]
+	fn test_sweep_muting() {
+		assert!(new_pulse(false, 7, 0).muted());
+		assert!(!new_pulse(false, 8, 0b0000_1001).muted());
+		// The target overflows, even with the sweep disabled or shift 0
+		assert!(new_pulse(false, 0x600, 0b0000_0001).muted());
+		assert!(new_pulse(false, 0x400, 0).muted());
+		assert!(!new_pulse(false, 0x3FF, 0).muted());
+		// Negating never overflows
+		assert!(!new_pulse(true, 0x7FF, 0b0000_1000).muted());
+
+		// A muted channel doesn't sweep
+		let mut pulse = new_pulse(false, 0x600, 0b1000_0001);
+		pulse.clock_half_frame();
+		assert_eq!(pulse.timer_period(), 0x600);
+	}
+}
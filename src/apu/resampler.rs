@@ -0,0 +1,266 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+use crate::cpu::cpu::CPU_FREQUENCY;
+use crate::savestate::{Serialize, Serializer};
+
+use super::apu::Level;
+
+/// Width of the band-limited step, in output samples. It is also the delay of the sinc resampler.
+const SINC_TAPS: usize = 16;
+/// The fractions of a sample the steps are placed at.
+const SINC_PHASES: usize = 32;
+/// Highest frequency kept by the sinc resampler, relative to half the sample rate (20 kHz at 44.1 kHz).
+const SINC_CUTOFF: f64 = 0.9;
+/// The taps of each phase add up to this, the levels in the sinc resampler are this many times larger.
+const KERNEL_ONE: i64 = 1 << 15;
+
+/// How the APU output (a value each CPU cycle, 1.79 MHz) becomes audio samples. The better ones remove the tones above
+/// half the sample rate, which otherwise come back as tones that aren't in the game (aliasing).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResamplerQuality {
+	/// The value at each sample time. The cheapest, the high notes and the noise channel alias.
+	Nearest,
+	/// Each value is added to the two samples around it, weighted by the distance (the linear interpolation kernel).
+	/// About twice the cost of nearest, little aliasing.
+	Linear,
+	/// Band-limited steps, like blip_buf: each change of the value adds a windowed sinc step to the next samples. No
+	/// audible aliasing. The cost depends on how often the value changes, not on the cycles.
+	Sinc,
+}
+
+impl ResamplerQuality {
+	/// `nearest`, `linear` or `sinc`, for the command line.
+	pub fn parse(name: &str) -> Option<ResamplerQuality> {
+		match name {
+			"nearest" => Some(ResamplerQuality::Nearest),
+			"linear" => Some(ResamplerQuality::Linear),
+			"sinc" => Some(ResamplerQuality::Sinc),
+			_ => None,
+		}
+	}
+
+	pub fn name(self) -> &'static str {
+		match self {
+			ResamplerQuality::Nearest => "nearest",
+			ResamplerQuality::Linear => "linear",
+			ResamplerQuality::Sinc => "sinc",
+		}
+	}
+}
+
+/// Converts the CPU rate APU output to `rate` samples per second. Integer only, like the rest of the APU.
+#[derive(Clone)]
+pub struct Resampler {
+	quality: ResamplerQuality,
+	rate: u64,
+	phase: u64,		// Sample rate times the CPU cycles since the last sample, a sample is due at CPU_FREQUENCY
+
+	// Linear: the weighted sums and the sums of the weights (in CPU_FREQUENCY units) of the sample being finished and
+	// of the next one
+	left: (i64, u64),
+	right: (i64, u64),
+
+	// Sinc: the steps that are added to the next samples and the output level (KERNEL_ONE times the level), and the
+	// last input
+	deltas: VecDeque<i64>,
+	level: i64,
+	last: Level,
+	kernel: Vec<[i64; SINC_TAPS]>,	// For each phase
+}
+
+impl Resampler {
+	pub fn new(quality: ResamplerQuality, rate: u64) -> Self {
+		Resampler {
+			quality,
+			rate,
+			phase: 0,
+			left: (0, 0),
+			right: (0, 0),
+			deltas: VecDeque::from(vec![0; SINC_TAPS]),
+			level: 0,
+			last: 0,
+			kernel: if quality == ResamplerQuality::Sinc { sinc_kernel() } else { vec![] },
+		}
+	}
+
+	pub fn quality(&self) -> ResamplerQuality {
+		self.quality
+	}
+
+	pub fn rate(&self) -> u64 {
+		self.rate
+	}
+
+	/// Change the sample rate without restarting, for the small changes of the dynamic rate control.
+	pub fn set_rate(&mut self, rate: u64) {
+		self.rate = rate;
+	}
+
+	/// The value of one CPU cycle. Returns a sample when one is due.
+	pub fn clock(&mut self, value: Level) -> Option<Level> {
+		match self.quality {
+			ResamplerQuality::Nearest => {}
+			ResamplerQuality::Linear => {
+				let weight = self.phase;
+				self.left.0 += value as i64 * (CPU_FREQUENCY - weight) as i64;
+				self.left.1 += CPU_FREQUENCY - weight;
+				self.right.0 += value as i64 * weight as i64;
+				self.right.1 += weight;
+			}
+			ResamplerQuality::Sinc if value != self.last => {
+				let phase = (self.phase * SINC_PHASES as u64 / CPU_FREQUENCY) as usize;
+				let delta = (value - self.last) as i64;
+				for (sample, weight) in self.deltas.iter_mut().zip(self.kernel[phase]) {
+					*sample += delta * weight;
+				}
+				self.last = value;
+			}
+			ResamplerQuality::Sinc => {}
+		}
+
+		self.phase += self.rate;
+		if self.phase < CPU_FREQUENCY {
+			return None;
+		}
+		self.phase -= CPU_FREQUENCY;
+		Some(match self.quality {
+			ResamplerQuality::Nearest => value,
+			ResamplerQuality::Linear => {
+				let (sum, weight) = std::mem::replace(&mut self.left, std::mem::take(&mut self.right));
+				if weight > 0 { (sum / weight as i64) as Level } else { 0 }
+			}
+			ResamplerQuality::Sinc => {
+				self.level += self.deltas.pop_front().unwrap();
+				self.deltas.push_back(0);
+				(self.level >> KERNEL_ONE.trailing_zeros()) as Level
+			}
+		})
+	}
+}
+
+/// The samples being made, so a loaded state makes the same samples. The quality and the rate are settings.
+impl Serialize for Resampler {
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.phase);
+		s.value(&mut self.left.0);
+		s.value(&mut self.left.1);
+		s.value(&mut self.right.0);
+		s.value(&mut self.right.1);
+		let mut deltas = [0; SINC_TAPS];
+		for (saved, delta) in deltas.iter_mut().zip(&self.deltas) {
+			*saved = *delta;
+		}
+		s.value(&mut deltas);
+		self.deltas = VecDeque::from(deltas.to_vec());
+		s.value(&mut self.level);
+		s.value(&mut self.last);
+	}
+}
+
+/// The windowed sinc impulse at each phase, sampled at the output samples. Added up, the impulses of the changes are
+/// the band-limited steps. Each phase sums to exactly `KERNEL_ONE`, so a change moves the output by exactly its size.
+fn sinc_kernel() -> Vec<[i64; SINC_TAPS]> {
+	(0..SINC_PHASES)
+		.map(|phase| {
+			let offset = 1.0 - phase as f64 / SINC_PHASES as f64;
+			let mut taps = [0.0; SINC_TAPS];
+			for (k, tap) in taps.iter_mut().enumerate() {
+				let x = k as f64 + offset - SINC_TAPS as f64 / 2.0;
+				let sinc = if x == 0.0 { 1.0 } else { sin(PI * SINC_CUTOFF * x) / (PI * SINC_CUTOFF * x) };
+				// Blackman window over the taps
+				let w = (x + SINC_TAPS as f64 / 2.0) / SINC_TAPS as f64;
+				let window = 0.42 - 0.5 * cos(2.0 * PI * w) + 0.08 * cos(4.0 * PI * w);
+				*tap = sinc * window.max(0.0);
+			}
+			let sum: f64 = taps.iter().sum();
+			let mut taps = taps.map(|tap| (tap / sum * KERNEL_ONE as f64).round() as i64);
+			// The rounding error goes to the largest tap
+			let largest = (0..SINC_TAPS).max_by_key(|&k| taps[k]).unwrap();
+			taps[largest] += KERNEL_ONE - taps.iter().sum::<i64>();
+			taps
+		})
+		.collect()
+}
+
+/// The sine with the Taylor series, only with the basic operations that IEEE 754 rounds the same on every platform (the
+/// `sin` of the standard library comes from the platform's math library), so the kernel is the same everywhere.
+fn sin(x: f64) -> f64 {
+	let x = x - (x / (2.0 * PI)).round() * 2.0 * PI;
+	let (mut term, mut sum) = (x, x);
+	for n in 1..20 {
+		term *= -x * x / ((2 * n) as f64 * (2 * n + 1) as f64);
+		sum += term;
+	}
+	sum
+}
+
+fn cos(x: f64) -> f64 {
+	sin(x + PI / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Instant;
+
+	use super::{cos, sin, sinc_kernel, Resampler, ResamplerQuality, KERNEL_ONE};
+	use crate::apu::apu::{Level, LEVEL_ONE};
+	use crate::cpu::cpu::CPU_FREQUENCY;
+
+	const ALL: [ResamplerQuality; 3] = [ResamplerQuality::Nearest, ResamplerQuality::Linear, ResamplerQuality::Sinc];
+
+	/// A square wave of `frequency` Hz, resampled for a second.
+	fn square(quality: ResamplerQuality, frequency: u64) -> Vec<Level> {
+		let mut resampler = Resampler::new(quality, 44_100);
+		let half_period = CPU_FREQUENCY / frequency / 2;
+		(0..CPU_FREQUENCY).filter_map(|cycle| resampler.clock(((cycle / half_period + 1) % 2) as Level * LEVEL_ONE)).collect()
+	}
+
+	#[test]
+	fn test_resampler() {
+		for quality in ALL {
+			// The sample rate, and a low tone stays a square wave between 0 and 1 (the band-limited steps ring a bit)
+			let samples = square(quality, 100);
+			assert_eq!(samples.len(), 44_100, "{}", quality.name());
+			let (min, max) = (*samples[100..].iter().min().unwrap(), *samples[100..].iter().max().unwrap());
+			assert!(min.abs() < LEVEL_ONE * 15 / 100 && (max - LEVEL_ONE).abs() < LEVEL_ONE * 15 / 100, "{}: {} to {}", quality.name(), min, max);
+		}
+
+		// A tone above half the sample rate: nearest keeps it as a loud alias, the others filter it out
+		let loudness = |samples: Vec<Level>| {
+			let mean = samples.iter().map(|&sample| sample as i64).sum::<i64>() / samples.len() as i64;
+			samples.iter().map(|&sample| (sample as i64 - mean).abs()).sum::<i64>() / samples.len() as i64
+		};
+		let nearest = loudness(square(ResamplerQuality::Nearest, 30_000));
+		let linear = loudness(square(ResamplerQuality::Linear, 30_000));
+		let sinc = loudness(square(ResamplerQuality::Sinc, 30_000));
+		assert!(nearest > LEVEL_ONE as i64 * 4 / 10 && linear < nearest / 2 && sinc < linear, "{} {} {}", nearest, linear, sinc);
+	}
+
+	#[test]
+	fn test_sinc_kernel() {
+		for x in [-7.0, -1.0, 0.0, 0.5, 3.0, 25.0] {
+			assert!((sin(x) - f64::sin(x)).abs() < 1e-12 && (cos(x) - f64::cos(x)).abs() < 1e-12, "{}", x);
+		}
+		let kernel = sinc_kernel();
+		assert!(kernel.iter().all(|taps| taps.iter().sum::<i64>() == KERNEL_ONE));
+		// A step of the input ends as exactly that step in the output
+		let mut resampler = Resampler::new(ResamplerQuality::Sinc, 44_100);
+		let samples: Vec<Level> = (0..CPU_FREQUENCY / 100).filter_map(|cycle| resampler.clock(if cycle < 1000 { 0 } else { 12345 })).collect();
+		assert_eq!(*samples.last().unwrap(), 12345);
+	}
+
+	/// The cost of the resamplers: a second of a square wave that changes each 100 cycles (a 9 kHz tone).
+	/// Run with `cargo test --release bench_resampler -- --ignored --nocapture`.
+	#[test]
+	#[ignore]
+	fn bench_resampler() {
+		for quality in ALL {
+			let mut resampler = Resampler::new(quality, 44_100);
+			let start = Instant::now();
+			let samples = (0..CPU_FREQUENCY).filter_map(|cycle| resampler.clock(((cycle / 100) % 2) as Level * LEVEL_ONE)).count();
+			let elapsed = start.elapsed();
+			println!("{}: {} samples in {:.2}ms", quality.name(), samples, elapsed.as_secs_f64() * 1000.0);
+		}
+	}
+}
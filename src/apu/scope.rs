@@ -0,0 +1,128 @@
+use std::fmt;
+
+use crate::cpu::cpu::CPU_FREQUENCY;
+
+/// The output levels kept for each channel, at the sample rate: 1024 samples are 23ms, a period of a 43 Hz tone.
+pub const SCOPE_LENGTH: usize = 1024;
+
+/// The APU channels, for the visualizers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+	Pulse1,
+	Pulse2,
+	Triangle,
+	Noise,
+	DMC,
+}
+
+impl Channel {
+	pub const ALL: [Channel; 5] = [Channel::Pulse1, Channel::Pulse2, Channel::Triangle, Channel::Noise, Channel::DMC];
+
+	pub fn name(self) -> &'static str {
+		match self {
+			Channel::Pulse1 => "pulse 1",
+			Channel::Pulse2 => "pulse 2",
+			Channel::Triangle => "triangle",
+			Channel::Noise => "noise",
+			Channel::DMC => "DMC",
+		}
+	}
+
+	/// The highest output level: 15, 127 for the DMC.
+	pub fn max_level(self) -> u8 {
+		match self {
+			Channel::DMC => 127,
+			_ => 15,
+		}
+	}
+
+	/// The frequency of the tone of a timer period. Read here: https://www.nesdev.org/wiki/APU_Period_Table. For the
+	/// noise, how often the random bit changes, for the DMC, the bits played per second.
+	pub fn frequency(self, period: u16) -> f64 {
+		let period = period as f64;
+		match self {
+			Channel::Pulse1 | Channel::Pulse2 => CPU_FREQUENCY as f64 / (16.0 * (period + 1.0)),
+			Channel::Triangle => CPU_FREQUENCY as f64 / (32.0 * (period + 1.0)),
+			Channel::Noise | Channel::DMC => CPU_FREQUENCY as f64 / period,
+		}
+	}
+}
+
+/// What a channel is playing now, see `APU::channel_state`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelState {
+	pub channel: Channel,
+	pub enabled: bool,		// The length counter isn't 0 (the DMC: bytes remain)
+	pub length: u16,		// The length counter (the DMC: the bytes remaining)
+	pub period: Option<u16>,	// The timer period in CPU cycles, for the channels that have it
+	pub level: u8,			// The output level, 0 to `Channel::max_level`
+	pub muted: bool,		// The pulse sweep mutes the channel
+}
+
+impl ChannelState {
+	/// The frequency of the tone, see `Channel::frequency`.
+	pub fn frequency(&self) -> Option<f64> {
+		Some(self.channel.frequency(self.period?))
+	}
+}
+
+impl fmt::Display for ChannelState {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{:<8} {}, length {}", self.channel.name(), if self.enabled { "on " } else { "off" }, self.length)?;
+		if let (Some(period), Some(frequency)) = (self.period, self.frequency()) {
+			write!(f, ", period {} ({:.1} Hz)", period, frequency)?;
+		}
+		write!(f, ", level {}{}", self.level, if self.muted { ", muted by the sweep" } else { "" })
+	}
+}
+
+/// The last `SCOPE_LENGTH` output levels of each channel, for oscilloscope-style visualizers. Not saved in save states.
+#[derive(Clone)]
+pub struct Scope {
+	levels: [[u8; SCOPE_LENGTH]; 5],
+	position: usize,	// Where the next levels go, the oldest ones are there
+}
+
+impl Default for Scope {
+	fn default() -> Self {
+		Scope { levels: [[0; SCOPE_LENGTH]; 5], position: 0 }
+	}
+}
+
+impl Scope {
+	/// Add the levels of the channels (in the order of `Channel::ALL`) at a sample.
+	pub fn push(&mut self, levels: [u8; 5]) {
+		for (channel, level) in levels.into_iter().enumerate() {
+			self.levels[channel][self.position] = level;
+		}
+		self.position = (self.position + 1) % SCOPE_LENGTH;
+	}
+
+	/// The last levels of a channel, the oldest first.
+	pub fn waveform(&self, channel: Channel) -> Vec<u8> {
+		let levels = &self.levels[channel as usize];
+		[&levels[self.position..], &levels[..self.position]].concat()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Channel, ChannelState, Scope, SCOPE_LENGTH};
+
+	#[test]
+	fn test_scope() {
+		let mut scope = Scope::default();
+		for level in 0..SCOPE_LENGTH + 3 {
+			scope.push([0, 0, 0, 0, (level % 128) as u8]);
+		}
+		let waveform = scope.waveform(Channel::DMC);
+		assert_eq!(waveform.len(), SCOPE_LENGTH);
+		// The 3 oldest were replaced, the newest is last
+		assert_eq!((waveform[0], waveform[SCOPE_LENGTH - 1]), (3, ((SCOPE_LENGTH + 2) % 128) as u8));
+		assert!(scope.waveform(Channel::Pulse1).iter().all(|&level| level == 0));
+
+		// A4 is period 253 on the pulse channels
+		let state = ChannelState { channel: Channel::Pulse1, enabled: true, length: 10, period: Some(253), level: 0, muted: false };
+		assert_eq!(state.frequency().unwrap().round(), 440.0);
+	}
+}
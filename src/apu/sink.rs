@@ -0,0 +1,133 @@
+//! Where the samples of the APU go: the audio device of the window, a WAV file, nowhere. The APU only makes samples
+//! (`APU::take_samples`), the frontends give them to a sink.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+
+use log::error;
+
+use super::apu::SAMPLE_RATE;
+
+/// Takes the samples of each frame, 16 bit mono at `SAMPLE_RATE` (see `APU::take_samples`).
+pub trait AudioSink {
+	fn push_samples(&mut self, samples: &[i16]);
+}
+
+/// Closures, for the tests and tools.
+impl<F: FnMut(&[i16])> AudioSink for F {
+	fn push_samples(&mut self, samples: &[i16]) {
+		self(samples)
+	}
+}
+
+/// Drops the samples.
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+	fn push_samples(&mut self, _samples: &[i16]) {}
+}
+
+/// Size of the WAV header, the samples come after it.
+const WAV_HEADER_SIZE: u32 = 44;
+
+/// Writes the samples to a 16 bit mono WAV file. The header is updated after each push, so the file plays also when
+/// the emulator didn't exit cleanly.
+pub struct WavRecorder<W: Write + Seek> {
+	writer: W,
+	samples: u32,
+}
+
+impl WavRecorder<BufWriter<File>> {
+	pub fn create(path: &str) -> io::Result<Self> {
+		WavRecorder::new(BufWriter::new(File::create(path)?))
+	}
+}
+
+impl<W: Write + Seek> WavRecorder<W> {
+	pub fn new(writer: W) -> io::Result<Self> {
+		let mut recorder = WavRecorder { writer, samples: 0 };
+		recorder.write_header()?;
+		Ok(recorder)
+	}
+
+	/// Amount of samples written.
+	pub fn samples(&self) -> u32 {
+		self.samples
+	}
+
+	pub fn into_inner(self) -> W {
+		self.writer
+	}
+
+	fn write_header(&mut self) -> io::Result<()> {
+		let data_size = self.samples * 2;
+		let w = &mut self.writer;
+		w.seek(SeekFrom::Start(0))?;
+		w.write_all(b"RIFF")?;
+		w.write_all(&(WAV_HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+		w.write_all(b"WAVEfmt ")?;
+		w.write_all(&16u32.to_le_bytes())?;					// Size of the format chunk
+		w.write_all(&1u16.to_le_bytes())?;					// PCM
+		w.write_all(&1u16.to_le_bytes())?;					// Mono
+		w.write_all(&(SAMPLE_RATE as u32).to_le_bytes())?;
+		w.write_all(&(SAMPLE_RATE as u32 * 2).to_le_bytes())?;	// Bytes per second
+		w.write_all(&2u16.to_le_bytes())?;					// Bytes per sample
+		w.write_all(&16u16.to_le_bytes())?;					// Bits per sample
+		w.write_all(b"data")?;
+		w.write_all(&data_size.to_le_bytes())?;
+		w.seek(SeekFrom::End(0))?;
+		w.flush()
+	}
+
+	fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+		let data: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+		self.writer.write_all(&data)?;
+		self.samples += samples.len() as u32;
+		self.write_header()
+	}
+}
+
+impl<W: Write + Seek> AudioSink for WavRecorder<W> {
+	fn push_samples(&mut self, samples: &[i16]) {
+		if samples.is_empty() {
+			return;
+		}
+		if let Err(e) = self.write_samples(samples) {
+			error!("Failed to write the audio: {}", e);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Cursor;
+	use std::sync::{Arc, Mutex};
+
+	use super::{AudioSink, WavRecorder, WAV_HEADER_SIZE};
+	use crate::builder::NesBuilder;
+
+	#[test]
+	fn test_wav_recorder() {
+		let mut recorder = WavRecorder::new(Cursor::new(vec![])).unwrap();
+		recorder.push_samples(&[0, 16383, 32767]);
+		recorder.push_samples(&[-32768]);
+		assert_eq!(recorder.samples(), 4);
+		let wav = recorder.into_inner().into_inner();
+		assert_eq!(wav.len(), WAV_HEADER_SIZE as usize + 8);
+		assert_eq!((&wav[0..4], &wav[8..16], &wav[36..40]), (&b"RIFF"[..], &b"WAVEfmt "[..], &b"data"[..]));
+		assert_eq!((&wav[4..8], &wav[40..44]), (&44u32.to_le_bytes()[..], &8u32.to_le_bytes()[..]));
+		let samples: Vec<i16> = wav[44..].chunks_exact(2).map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]])).collect();
+		assert_eq!(samples, [0, 16383, 32767, -32768]);
+
+		// The NES gives the samples of each frame to its sinks
+		let mut nes = NesBuilder::new().rom_path("6502asm_programs/nestest/nestest.nes").build();
+		let pushes = Arc::new(Mutex::new(vec![]));
+		let pushes_clone = pushes.clone();
+		nes.add_audio_sink(Box::new(move |samples: &[i16]| pushes_clone.lock().unwrap().push(samples.len())));
+		nes.run_frames(2);
+		assert!(nes.cpu.apu_mut().take_samples().is_empty());
+		let pushes = pushes.lock().unwrap();
+		assert_eq!(pushes.len(), 2);
+		assert!(pushes.iter().all(|&samples| (730..740).contains(&samples)));
+	}
+}
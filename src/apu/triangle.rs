@@ -0,0 +1,27 @@
+use crate::savestate::{Serialize, Serializer};
+
+use super::length_counter::LengthCounter;
+
+/// Triangle channel ($4008-$400B). Read here: https://www.nesdev.org/wiki/APU_Triangle
+#[derive(Clone, Copy, Default)]
+pub struct Triangle {
+	pub length: LengthCounter,
+}
+
+impl Triangle {
+	/// Write register 0-3 of the channel ($4009 is unused).
+	pub fn write_register(&mut self, reg: u16, value: u8) {
+		match reg {
+			// The same bit is the linear counter control
+			0 => self.length.set_halt(value & 0x80 != 0),
+			3 => self.length.load(value >> 3),
+			_ => (),
+		}
+	}
+}
+
+impl Serialize for Triangle {
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.length);
+	}
+}
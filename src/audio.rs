@@ -0,0 +1,227 @@
+use std::time::Duration;
+
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::Sdl;
+use log::{error, warn};
+
+use crate::apu::{apu::SAMPLE_RATE, sink::AudioSink};
+use crate::config::Config;
+
+/// The most the sample rate is changed to keep the buffer filled. Nobody hears a 0.5% pitch change, and it covers the
+/// difference between the NES frame rate (60.1 Hz) and the display, and the drift of the audio device clock.
+const MAX_RATE_DELTA: f64 = 0.005;
+/// How much of the new buffer fill is taken each frame. The fill jumps by a frame of samples each time the frontend
+/// queues them, the rate follows the average.
+const FILL_SMOOTHING: f64 = 0.05;
+
+/// The change of the volume of one key press.
+const VOLUME_STEP: f32 = 0.1;
+
+/// The `audio.*` settings.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioSettings {
+	pub latency: Duration,	// The audio queued ahead of the device, more is less likely to crackle
+	pub volume: f32,		// Master volume, 0.0 to 1.0
+	pub muted: bool,
+	pub device: Option<String>,	// Name of the output device (see `devices`), the default device when None
+}
+
+impl AudioSettings {
+	/// `audio.latency` (milliseconds, 64 by default), `audio.volume` (1.0 by default), `audio.mute` and `audio.device`.
+	pub fn from_config(config: &Config) -> Self {
+		let device = config.get("audio.device", String::new());
+		AudioSettings {
+			latency: Duration::from_millis(config.get("audio.latency", 64)),
+			volume: config.get("audio.volume", 1.0f32).clamp(0.0, 1.0),
+			muted: config.get("audio.mute", false),
+			device: (!device.is_empty()).then_some(device),
+		}
+	}
+
+	/// Save the volume and the mute to the settings file.
+	pub fn write_config(&self, config: &mut Config) {
+		config.set("audio.volume", &self.volume.to_string());
+		config.set("audio.mute", &self.muted.to_string());
+	}
+
+	/// Change the volume by `steps` key presses (negative to lower it).
+	pub fn adjust_volume(&mut self, steps: i32) {
+		let volume = (self.volume + steps as f32 * VOLUME_STEP).clamp(0.0, 1.0);
+		self.volume = (volume * 100.0).round() / 100.0;
+	}
+
+	/// What the samples are multiplied by.
+	fn gain(&self) -> f32 {
+		if self.muted { 0.0 } else { self.volume }
+	}
+}
+
+/// The names of the audio output devices, for `audio.device`.
+pub fn devices() -> Result<Vec<String>, String> {
+	let audio = sdl2::init()?.audio()?;
+	let count = audio.num_audio_playback_devices().ok_or("The audio devices can't be listed")?;
+	(0..count).map(|index| audio.audio_playback_device_name(index)).collect()
+}
+
+/// Dynamic rate control: the emulator makes a bit more samples when the buffer of the audio device is emptying, a bit
+/// less when it is filling up. The video stays at 60 FPS and the buffer never runs out (which is heard as a crackle).
+pub struct RateControl {
+	target: usize,	// Samples
+	fill: f64,		// Average of the queued samples
+}
+
+impl RateControl {
+	pub fn new(target: usize) -> Self {
+		RateControl { target, fill: target as f64 }
+	}
+
+	/// The sample rate for the samples queued in the device. Empty buffer is `SAMPLE_RATE` + 0.5%, twice the target is
+	/// `SAMPLE_RATE` - 0.5%.
+	pub fn update(&mut self, queued: usize) -> u64 {
+		self.fill += (queued as f64 - self.fill) * FILL_SMOOTHING;
+		let error = ((self.target as f64 - self.fill) / self.target as f64).clamp(-1.0, 1.0);
+		(SAMPLE_RATE as f64 * (1.0 + MAX_RATE_DELTA * error)).round() as u64
+	}
+}
+
+/// The audio buffer, for the HUD.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AudioStats {
+	pub queued: usize,		// Samples in the device buffer
+	pub target: usize,
+	pub rate: u64,			// Samples per second the emulator makes
+	pub underruns: u64,		// Times the buffer ran out
+}
+
+impl AudioStats {
+	/// How long the queued samples play.
+	pub fn latency(&self) -> Duration {
+		Duration::from_secs_f64(self.queued as f64 / SAMPLE_RATE as f64)
+	}
+}
+
+/// The audio device, mono at `SAMPLE_RATE`, that the frontend queues the samples of each frame to.
+pub struct AudioOutput {
+	queue: AudioQueue<f32>,
+	settings: AudioSettings,
+	control: RateControl,
+	stats: AudioStats,
+	playing: bool,
+	rate_change: Option<u64>,	// Sample rate the emulator should make the samples at, see `take_rate_change`
+}
+
+impl AudioOutput {
+	/// Open the device of the settings. When it isn't there, the default device.
+	pub fn open(sdl: &Sdl, settings: AudioSettings) -> Result<Self, String> {
+		let audio = sdl.audio()?;
+		let spec = AudioSpecDesired { freq: Some(SAMPLE_RATE as i32), channels: Some(1), samples: Some(512) };
+		let queue = match audio.open_queue::<f32, _>(settings.device.as_deref(), &spec) {
+			Err(e) if settings.device.is_some() => {
+				warn!("Can't open the audio device {:?}, using the default device: {}", settings.device.as_ref().unwrap(), e);
+				audio.open_queue::<f32, _>(None, &spec)?
+			}
+			queue => queue?,
+		};
+		let target = (settings.latency.as_secs_f64() * SAMPLE_RATE as f64) as usize;
+		Ok(AudioOutput {
+			queue,
+			settings,
+			control: RateControl::new(target),
+			stats: AudioStats { target, rate: SAMPLE_RATE, ..AudioStats::default() },
+			playing: false,
+			rate_change: None,
+		})
+	}
+
+	/// Queue the samples of a frame. Returns the sample rate the emulator should make them at, when it changed. The
+	/// device starts playing when the buffer is filled to the latency.
+	fn play(&mut self, samples: &[i16]) -> Option<u64> {
+		if samples.is_empty() {
+			return None;
+		}
+		let queued = self.queue.size() as usize / std::mem::size_of::<f32>();
+		if self.playing && queued == 0 {
+			self.stats.underruns += 1;
+		}
+		// Muted is queued as silence, so the rate control goes on
+		let gain = self.settings.gain() / 32768.0;
+		let samples: Vec<f32> = samples.iter().map(|&sample| sample as f32 * gain).collect();
+		if let Err(e) = self.queue.queue_audio(&samples) {
+			error!("Failed to queue the audio: {}", e);
+		}
+		if !self.playing && queued + samples.len() >= self.stats.target {
+			self.queue.resume();
+			self.playing = true;
+		}
+
+		let rate = self.control.update(queued);
+		self.stats.queued = queued;
+		let changed = rate != self.stats.rate;
+		self.stats.rate = rate;
+		changed.then_some(rate)
+	}
+
+	/// The sample rate the emulator should make the samples at, when it changed since the last call.
+	pub fn take_rate_change(&mut self) -> Option<u64> {
+		self.rate_change.take()
+	}
+
+	pub fn stats(&self) -> &AudioStats {
+		&self.stats
+	}
+
+	pub fn settings(&self) -> &AudioSettings {
+		&self.settings
+	}
+
+	/// Change the volume or the mute, for the next samples. The latency and the device stay.
+	pub fn set_settings(&mut self, settings: AudioSettings) {
+		self.settings = settings;
+	}
+}
+
+impl AudioSink for AudioOutput {
+	fn push_samples(&mut self, samples: &[i16]) {
+		if let Some(rate) = self.play(samples) {
+			self.rate_change = Some(rate);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{AudioSettings, RateControl, MAX_RATE_DELTA};
+	use crate::{apu::apu::SAMPLE_RATE, config::Config};
+
+	#[test]
+	fn test_rate_control() {
+		// At the target, the rate stays
+		let mut control = RateControl::new(2048);
+		assert_eq!(control.update(2048), SAMPLE_RATE);
+
+		// Emptying: more samples, up to 0.5% more
+		let rates: Vec<u64> = (0..200).map(|_| control.update(0)).collect();
+		assert!(rates.windows(2).all(|rates| rates[1] >= rates[0]));
+		assert_eq!(rates[199], (SAMPLE_RATE as f64 * (1.0 + MAX_RATE_DELTA)).round() as u64);
+
+		// Filling up: less samples, never below 0.5% less
+		let rates: Vec<u64> = (0..400).map(|_| control.update(10_000)).collect();
+		assert!(rates[0] > SAMPLE_RATE && rates[399] < SAMPLE_RATE);
+		assert_eq!(rates[399], (SAMPLE_RATE as f64 * (1.0 - MAX_RATE_DELTA)).round() as u64);
+	}
+
+	#[test]
+	fn test_settings() {
+		let mut settings = AudioSettings::from_config(&Config::parse("audio.volume = 1.5\naudio.device = USB Audio"));
+		assert_eq!((settings.volume, settings.device.as_deref()), (1.0, Some("USB Audio")));
+		settings.adjust_volume(-3);
+		settings.muted = true;
+		assert_eq!((settings.volume, settings.gain()), (0.7, 0.0));
+
+		// The volume and the mute are saved
+		let mut config = Config::parse("");
+		settings.write_config(&mut config);
+		let loaded = AudioSettings::from_config(&config);
+		assert_eq!((loaded.volume, loaded.muted, loaded.device), (0.7, true, None));
+	}
+}
@@ -0,0 +1,188 @@
+//! The entry point for programs that use the emulator: one builder for every way to make a `NES`, and its settings.
+//!
+//! ```
+//! use rust_nes_emulator::{builder::NesBuilder, ppu::ppu::Renderer, rom_parser::TVSystem};
+//!
+//! let mut nes = NesBuilder::new()
+//!     .rom_path("6502asm_programs/nestest/nestest.nes")
+//!     .region(TVSystem::PAL)  // The PAL palette, the timing stays NTSC
+//!     .renderer_mode(Renderer::Scanline)
+//!     .audio(None)            // No samples, faster
+//!     .trace(true)            // Keep the last instructions, for the crash dumps
+//!     .try_build()?;
+//! nes.run_frames(60);
+//! # Ok::<(), rust_nes_emulator::error::EmuError>(())
+//! ```
+
+use crate::apu::resampler::ResamplerQuality;
+use crate::cartridge::Cartridge;
+use crate::cpu::cpu::Scheduler;
+use crate::error::{EmuError, RomError};
+use crate::nes::NES;
+use crate::ppu::colors::{Palette, PaletteSettings};
+use crate::ppu::ppu::Renderer;
+use crate::rom_parser::{MirrorType, SizeMismatch, TVSystem};
+
+/// Where the game comes from.
+enum Rom {
+	Path(String),
+	PrgChr { prg: Vec<u8>, chr: Vec<u8>, mapper: u8, mirroring: MirrorType },
+	Cartridge(Box<Cartridge>),
+}
+
+/// Makes a configured `NES`. Without a setting, the machine is like `NES::open_rom_file` makes it.
+pub struct NesBuilder {
+	rom: Option<Rom>,
+	size_mismatch: SizeMismatch,
+	region: Option<TVSystem>,			// The palette, None: from the ROM header
+	renderer: Renderer,
+	scheduler: Option<Scheduler>,		// None: the CPU default
+	audio: Option<ResamplerQuality>,	// None: no samples
+	trace: bool,
+	warm_up: bool,
+}
+
+impl Default for NesBuilder {
+	fn default() -> Self {
+		NesBuilder {
+			rom: None,
+			size_mismatch: SizeMismatch::default(),
+			region: None,
+			renderer: Renderer::Dot,
+			scheduler: None,
+			audio: Some(ResamplerQuality::Linear),
+			trace: true,
+			warm_up: true,
+		}
+	}
+}
+
+impl NesBuilder {
+	pub fn new() -> Self {
+		NesBuilder::default()
+	}
+
+	/// An iNES, NES 2.0 or FDS file. The battery save next to it is loaded.
+	pub fn rom_path(mut self, path: &str) -> Self {
+		self.rom = Some(Rom::Path(path.to_string()));
+		self
+	}
+
+	/// Raw PRG and CHR binaries, see `NES::try_from_prg_chr`.
+	pub fn prg_chr(mut self, prg: &[u8], chr: &[u8], mapper: u8, mirroring: MirrorType) -> Self {
+		self.rom = Some(Rom::PrgChr { prg: prg.to_vec(), chr: chr.to_vec(), mapper, mirroring });
+		self
+	}
+
+	/// A cartridge made by the program.
+	pub fn cartridge(mut self, cartridge: Cartridge) -> Self {
+		self.rom = Some(Rom::Cartridge(Box::new(cartridge)));
+		self
+	}
+
+	/// What to do when the iNES file is bigger or smaller than its header says.
+	pub fn size_mismatch(mut self, size_mismatch: SizeMismatch) -> Self {
+		self.size_mismatch = size_mismatch;
+		self
+	}
+
+	/// The palette of an NTSC or a PAL (2C07) PPU instead of the one of the ROM header. It doesn't choose the region of
+	/// the machine: the CPU, PPU and APU timing is always NTSC, a PAL game runs at 60 frames per second.
+	pub fn region(mut self, region: TVSystem) -> Self {
+		self.region = Some(region);
+		self
+	}
+
+	/// See `PPU::set_renderer`.
+	pub fn renderer_mode(mut self, renderer: Renderer) -> Self {
+		self.renderer = renderer;
+		self
+	}
+
+	/// See `CPU::set_scheduler`.
+	pub fn scheduler(mut self, scheduler: Scheduler) -> Self {
+		self.scheduler = Some(scheduler);
+		self
+	}
+
+	/// How the samples are made, None for no audio (see `APU::set_audio_enabled`). Linear by default.
+	pub fn audio(mut self, quality: Option<ResamplerQuality>) -> Self {
+		self.audio = quality;
+		self
+	}
+
+	/// Keep the last executed instructions (the default), see `CPU::set_trace_enabled`.
+	pub fn trace(mut self, enabled: bool) -> Self {
+		self.trace = enabled;
+		self
+	}
+
+	/// See `PPU::set_warm_up`.
+	pub fn warm_up(mut self, enabled: bool) -> Self {
+		self.warm_up = enabled;
+		self
+	}
+
+	/// For tests: `try_build` that panics with the error.
+	#[doc(hidden)]
+	pub fn build(self) -> NES {
+		self.try_build().unwrap_or_else(|e| panic!("{}", e))
+	}
+
+	/// Fails without a ROM, and when the ROM can't be loaded.
+	pub fn try_build(self) -> Result<NES, EmuError> {
+		let mut nes = match self.rom.ok_or(RomError::NoRom)? {
+			Rom::Path(path) => NES::open_rom_file(&path, self.size_mismatch)?,
+			Rom::PrgChr { prg, chr, mapper, mirroring } => NES::try_from_prg_chr(&prg, &chr, mapper, mirroring)?,
+			Rom::Cartridge(cartridge) => NES::new(*cartridge),
+		};
+		if let Some(region) = self.region {
+			nes.cpu.ppu_mut().set_rgb_palette(Palette::new(&PaletteSettings { tv_system: region, ..PaletteSettings::default() }));
+		}
+		nes.cpu.ppu_mut().set_renderer(self.renderer);
+		if let Some(scheduler) = self.scheduler {
+			nes.cpu.set_scheduler(scheduler);
+		}
+		match self.audio {
+			Some(quality) => nes.cpu.apu_mut().set_resampler_quality(quality),
+			None => nes.cpu.apu_mut().set_audio_enabled(false),
+		}
+		nes.cpu.set_trace_enabled(self.trace);
+		nes.cpu.ppu_mut().set_warm_up(self.warm_up);
+		Ok(nes)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::NesBuilder;
+	use crate::{apu::resampler::ResamplerQuality, cpu::cpu::Scheduler, ppu::ppu::Renderer, rom_parser::{MirrorType, TVSystem}};
+
+	#[test]
+	fn test_builder() {
+		// The defaults are the ones of the constructors
+		let mut nes = NesBuilder::new().rom_path("6502asm_programs/nestest/nestest.nes").build();
+		nes.run_frame();
+		assert!(!nes.cpu.apu_mut().take_samples().is_empty());
+		assert!(!nes.cpu.trace().is_empty());
+		assert_eq!((nes.cpu.ppu().renderer(), nes.cpu.ppu().warm_up()), (Renderer::Dot, true));
+
+		let mut nes = NesBuilder::new()
+			.prg_chr(&[0xEA; 1024 * 32], &[], 0, MirrorType::VERTICAL)
+			.region(TVSystem::PAL)
+			.renderer_mode(Renderer::Scanline)
+			.scheduler(Scheduler::Accurate)
+			.audio(None)
+			.trace(false)
+			.warm_up(false)
+			.build();
+		nes.run_frame();
+		assert!(nes.cpu.apu_mut().take_samples().is_empty());
+		assert!(nes.cpu.trace().is_empty());
+		assert_eq!((nes.cpu.ppu().renderer(), nes.cpu.scheduler(), nes.cpu.ppu().warm_up()), (Renderer::Scanline, Scheduler::Accurate, false));
+		assert_eq!(nes.cpu.ppu().rgb_palette().settings().tv_system, TVSystem::PAL);
+
+		let nes = NesBuilder::new().prg_chr(&[0xEA; 1024 * 8], &[], 0, MirrorType::VERTICAL).audio(Some(ResamplerQuality::Sinc)).build();
+		assert_eq!(nes.cpu.apu().resampler_quality(), ResamplerQuality::Sinc);
+	}
+}
@@ -0,0 +1,150 @@
+//! Callbacks that programs using the emulator register on the `NES` (`NES::on_frame`, `NES::add_video_sink`,
+//! `NES::on_scanline`, `NES::add_audio_sink`), for custom frontends, video encoders and analysis tools. They run whatever runs the emulation: `run_frame`, `run_until`, `step`.
+//!
+//! ```
+//! use std::sync::mpsc;
+//! use rust_nes_emulator::{builder::NesBuilder, callbacks::Scanline};
+//!
+//! let mut nes = NesBuilder::new().rom_path("6502asm_programs/nestest/nestest.nes").try_build()?;
+//! let (sender, visible) = mpsc::channel();
+//! nes.on_scanline(move |scanline: &Scanline| if scanline.pixels.is_some() {
+//!     sender.send((scanline.frame, scanline.scanline)).unwrap();
+//! });
+//! nes.run_frames(2);
+//! nes.clear_callbacks();
+//! assert_eq!(visible.try_iter().filter(|&(frame, _)| frame == 1).count(), 240);
+//! # Ok::<(), rust_nes_emulator::error::EmuError>(())
+//! ```
+
+use crate::apu::apu::APU;
+use crate::apu::sink::AudioSink;
+use crate::ppu::colors::Palette;
+use crate::ppu::ppu::{PPU, SCANLINES_PER_FRAME, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// A frame the PPU completed.
+pub struct Frame<'a> {
+	pub number: u64,			// `NES::frame` while it was drawn, 0 is the first frame
+	pub pixels: &'a [u8],		// 256x240 NES color indexes, see `PPU::framebuffer`
+	pub emphasis: &'a [u8],		// See `PPU::emphasis`
+	pub palette: &'a Palette,	// The RGB colors of the pixels
+}
+
+/// A scanline the PPU completed.
+pub struct Scanline<'a> {
+	pub frame: u64,
+	pub scanline: u16,				// 0-261, see `PPU::scanline`
+	pub pixels: Option<&'a [u8]>,	// The 256 pixels of the visible scanlines (0-239)
+}
+
+/// Takes each completed frame: a video recorder, a hash checker... A NES has any amount of them.
+pub trait VideoSink {
+	fn push_frame(&mut self, frame: &Frame);
+}
+
+/// Closures, see `NES::on_frame`.
+impl<F: FnMut(&Frame)> VideoSink for F {
+	fn push_frame(&mut self, frame: &Frame) {
+		self(frame)
+	}
+}
+
+pub type ScanlineCallback = Box<dyn FnMut(&Scanline) + Send>;
+
+/// The callbacks of a `NES`, called in the order they were registered.
+#[derive(Default)]
+pub struct Callbacks {
+	frame: Vec<Box<dyn VideoSink + Send>>,
+	scanline: Vec<ScanlineCallback>,
+	audio: Vec<Box<dyn AudioSink + Send>>,
+}
+
+impl Callbacks {
+	pub fn add_frame(&mut self, sink: Box<dyn VideoSink + Send>) {
+		self.frame.push(sink);
+	}
+
+	pub fn add_scanline(&mut self, callback: ScanlineCallback) {
+		self.scanline.push(callback);
+	}
+
+	pub fn add_audio(&mut self, sink: Box<dyn AudioSink + Send>) {
+		self.audio.push(sink);
+	}
+
+	pub fn clear(&mut self) {
+		self.frame.clear();
+		self.scanline.clear();
+		self.audio.clear();
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.frame.is_empty() && self.scanline.is_empty() && self.audio.is_empty()
+	}
+
+	/// Give the samples of the frame to the audio sinks. Without sinks, they stay for `APU::take_samples`.
+	pub fn end_frame(&mut self, apu: &mut APU) {
+		if self.audio.is_empty() {
+			return;
+		}
+		let samples = apu.take_samples();
+		for sink in &mut self.audio {
+			sink.push_samples(&samples);
+		}
+	}
+
+	/// Call the callbacks of the scanlines and the frame completed since `frame` and `scanline`, the PPU position before
+	/// an instruction. An OAM DMA completes a few scanlines at once.
+	pub fn after_step(&mut self, mut frame: u64, mut scanline: u16, ppu: &PPU) {
+		while (frame, scanline) != (ppu.frame(), ppu.scanline()) {
+			let visible = (scanline as usize) < SCREEN_HEIGHT;
+			let row = scanline as usize * SCREEN_WIDTH;
+			let line = Scanline { frame, scanline, pixels: visible.then(|| &ppu.framebuffer()[row..row + SCREEN_WIDTH]) };
+			for callback in &mut self.scanline {
+				callback(&line);
+			}
+
+			scanline += 1;
+			if scanline == SCANLINES_PER_FRAME {
+				let completed = Frame { number: frame, pixels: ppu.framebuffer(), emphasis: ppu.emphasis(), palette: ppu.rgb_palette() };
+				for sink in &mut self.frame {
+					sink.push_frame(&completed);
+				}
+				scanline = 0;
+				frame += 1;
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{Arc, Mutex};
+
+	use crate::builder::NesBuilder;
+	use crate::ppu::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+	#[test]
+	fn test_callbacks() {
+		let mut nes = NesBuilder::new().rom_path("6502asm_programs/nestest/nestest.nes").build();
+		let frames = Arc::new(Mutex::new(vec![]));
+		let scanlines = Arc::new(Mutex::new(vec![]));
+		let frames_clone = frames.clone();
+		nes.on_frame(move |frame| frames_clone.lock().unwrap().push((frame.number, frame.pixels.len())));
+		let scanlines_clone = scanlines.clone();
+		nes.on_scanline(move |line| scanlines_clone.lock().unwrap().push((line.scanline, line.pixels.map(|pixels| pixels.len()))));
+
+		nes.run_frames(2);
+		let size = SCREEN_WIDTH * SCREEN_HEIGHT;
+		assert_eq!(*frames.lock().unwrap(), [(0, size), (1, size)]);
+		let scanlines = std::mem::take(&mut *scanlines.lock().unwrap());
+		assert_eq!(scanlines.len(), 2 * 262);
+		assert_eq!((scanlines[239], scanlines[240], scanlines[261], scanlines[262]), ((239, Some(256)), (240, None), (261, None), (0, Some(256))));
+
+		// Also when the emulation runs by instructions
+		nes.clear_callbacks();
+		let frames_clone = frames.clone();
+		nes.on_frame(move |frame| frames_clone.lock().unwrap().push((frame.number, 0)));
+		nes.run_until(|nes| nes.frame() == 3);
+		assert_eq!(frames.lock().unwrap().last(), Some(&(2, 0)));
+	}
+}
@@ -1,6 +1,9 @@
-use log::debug;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-use crate::{rom_parser::{RomParser, MirrorType}, common::{CHR_Bank, PRG_Bank}};
+use log::{debug, info, warn};
+
+use crate::{apu::{apu::Level, expansion::ExpansionVolumes}, error::RomError, rom_db, rom_parser::{RomParser, MirrorType, TVSystem}, mapper::{self, fds::{self, FDS}, CpuMapping, Mapper, PpuFetch}, vs_system::{VsSystem, VsPpu}, savestate::{Serialize, Serializer}};
 
 pub struct Cartridge {
 	// from iNES header
@@ -8,62 +11,255 @@ pub struct Cartridge {
 	num_chr_banks: u8,
 	pub mapper_num: u8,
 	mirror_type: MirrorType,
+	crc32: u32,	// Identifies the ROM, see rom_db
+	tv_system: TVSystem,
 	has_battery: bool,
 	has_trainer: bool,
 
-	// cartridge ROM, RAM of PRG/CHR
-	prg_rom: Vec<PRG_Bank>,
-	pub chr_rom: Vec<CHR_Bank>
+	// Bank switching hardware, owns the PRG ROM/RAM and CHR ROM/RAM
+	mapper: Box<dyn Mapper>,
+
+	// VS System arcade games
+	vs_system: Option<VsSystem>,
+
+	// Where the battery backed save memory is saved
+	battery_path: Option<PathBuf>,
+}
+
+impl Default for Cartridge {
+	fn default() -> Self {
+		Cartridge::new()
+	}
 }
 
 impl Cartridge {
-	pub fn new_with_parser(rom_parser: RomParser) -> Self {
-		Cartridge {
-			num_prg_banks: rom_parser.header.prg_rom_size,
-			num_chr_banks: rom_parser.header.chr_rom_size,
-			mapper_num: rom_parser.header.mapper,
-			mirror_type: rom_parser.header.mirroring,
-			has_battery: rom_parser.header.battery_prg_ram,
-			has_trainer: rom_parser.header.trainer,
-			prg_rom: rom_parser.prg_rom,
-			chr_rom: rom_parser.chr_rom
+	/// Fails when the emulator doesn't support the mapper of the header.
+	pub fn new_with_parser(rom_parser: RomParser) -> Result<Self, RomError> {
+		let tv_system = *rom_parser.header.tv_system();
+		let mut cartridge = Cartridge::from_prg_chr(
+			rom_parser.prg_rom.concat(),
+			rom_parser.chr_rom.concat(),
+			rom_parser.header.mapper,
+			rom_parser.header.mirroring,
+			rom_parser.header.prg_ram_size
+		)?;
+		cartridge.has_battery = rom_parser.header.battery_prg_ram;
+		cartridge.has_trainer = rom_parser.header.trainer;
+		cartridge.tv_system = tv_system;
+		if rom_parser.header.vs_unit_system {
+			// iNES doesn't tell which PPU the game was made for (NES 2.0 does), the RP2C04 palette can be chosen with
+			// NES::set_vs_ppu
+			let ppu = rom_parser.header.vs_ppu.unwrap_or_else(|| {
+				warn!("VS System game, assuming the RP2C03 PPU (NES palette)");
+				VsPpu::Rp2c03
+			});
+			cartridge.vs_system = Some(VsSystem::new(ppu));
 		}
+		if rom_parser.header.play_choise_10 {
+			warn!("PlayChoice-10 game, running as a NES game (the hint screen is not emulated)");
+		}
+		Ok(cartridge)
 	}
 
-	pub fn new() -> Self {
-		Cartridge {
-			num_prg_banks: 2,
+	/// Cartridge from raw PRG ROM and CHR ROM data. Empty CHR means 8KB of CHR RAM. The PRG RAM size is in bytes, None
+	/// for the default size of the mapper. Fails when the emulator doesn't support the mapper.
+	pub fn from_prg_chr(prg_rom: Vec<u8>, chr: Vec<u8>, mapper_num: u8, mirror_type: MirrorType, prg_ram_size: Option<usize>) -> Result<Self, RomError> {
+		debug!("Cartridge: mapper {}, PRG ROM {}KB, CHR ROM {}KB", mapper_num, prg_rom.len() / 1024, chr.len() / 1024);
+		Ok(Cartridge {
+			num_prg_banks: (prg_rom.len() / (1024 * 16)) as u8,
+			num_chr_banks: (chr.len() / (1024 * 8)) as u8,
+			mapper_num,
+			mirror_type: mirror_type.clone(),
+			crc32: rom_db::crc32(&prg_rom, &chr),
+			tv_system: TVSystem::NTSC,
+			has_battery: false,
+			has_trainer: false,
+			mapper: mapper::new_mapper(mapper_num, prg_rom, chr, mirror_type, prg_ram_size)?,
+			vs_system: None,
+			battery_path: None,
+		})
+	}
+
+	/// Famicom Disk System disk image (.fds). The FDS BIOS (`disksys.rom`, 8KB) is loaded from the directory of the disk
+	/// image, or from the current directory.
+	pub fn new_fds(path: &str) -> Result<Self, RomError> {
+		let disk = fs::read(path).map_err(|e| RomError::Read { path: path.to_string(), reason: e.to_string() })?;
+		let bios_path = Path::new(path).with_file_name(fds::BIOS_FILE_NAME);
+		let bios = fs::read(&bios_path)
+			.or_else(|_| fs::read(fds::BIOS_FILE_NAME))
+			.map_err(|_| RomError::MissingBios { dir: format!("{:?}", bios_path.parent().unwrap()) })?;
+		if bios.len() != fds::BIOS_SIZE {
+			return Err(RomError::InvalidBios { len: bios.len() });
+		}
+		Ok(Cartridge {
+			num_prg_banks: 0,
 			num_chr_banks: 0,
-			mapper_num: 0,
+			mapper_num: fds::MAPPER_NUMBER,
 			mirror_type: MirrorType::HORIZONTAL,
+			crc32: rom_db::crc32(&disk, &[]),
+			tv_system: TVSystem::NTSC,
 			has_battery: false,
 			has_trainer: false,
-			prg_rom: vec![[0; 1024*16], [0; 1024*16]],
-			chr_rom: vec![]
-		}
+			mapper: Box::new(FDS::new(bios, &disk)),
+			vs_system: None,
+			battery_path: None,
+		})
+	}
+
+	pub fn new() -> Self {
+		Cartridge::from_prg_chr(vec![0; 1024*32], vec![], 0, MirrorType::HORIZONTAL, None).expect("NROM is supported")
 	}
 
 	pub fn new_with_custom_rom(rom: [u8;1024*32]) -> Self {
-		let mut cartridge = Cartridge::new();
+		Cartridge::from_prg_chr(rom.to_vec(), vec![], 0, MirrorType::HORIZONTAL, None).expect("NROM is supported")
+	}
 
-		// Copy first bank to cartridge
-		let first_prg_bank = cartridge.prg_rom.get_mut(0).unwrap();
-		first_prg_bank.copy_from_slice(&rom[0..1024*16]);
+	pub fn mirror_type(&self) -> MirrorType {
+		self.mirror_type.clone()
+	}
+
+	/// CRC32 of the PRG ROM and CHR ROM (of the disk image for the FDS).
+	pub fn crc32(&self) -> u32 {
+		self.crc32
+	}
 
-		// Copy second bank to cartridge
-		let first_prg_bank = cartridge.prg_rom.get_mut(1).unwrap();
-		first_prg_bank.copy_from_slice(&rom[1024*16..]);
+	/// From the iNES header, dual system ROMs run as NTSC. Only the palette depends on it.
+	pub fn tv_system(&self) -> TVSystem {
+		match self.tv_system {
+			TVSystem::PAL => TVSystem::PAL,
+			_ => TVSystem::NTSC,
+		}
+	}
+
+	pub fn vs_system(&self) -> Option<&VsSystem> {
+		self.vs_system.as_ref()
+	}
+
+	pub fn vs_system_mut(&mut self) -> Option<&mut VsSystem> {
+		self.vs_system.as_mut()
+	}
+
+	/// Cartridge without CHR ROM banks has 8KB of CHR RAM instead.
+	pub fn has_chr_ram(&self) -> bool {
+		self.num_chr_banks == 0
+	}
+
+	/// CPU read of $4020-$FFFF. None is open bus.
+	pub fn cpu_read(&mut self, addr: u16, peek: bool) -> Option<u8> {
+		self.mapper.cpu_read(addr, peek)
+	}
+
+	/// What a CPU read of $4020-$FFFF gets with the current banks.
+	pub fn cpu_mapping(&self, addr: u16) -> CpuMapping {
+		self.mapper.cpu_mapping(addr)
+	}
 
-		cartridge
+	/// A CPU write to the address does nothing.
+	pub fn ignores_write(&self, addr: u16) -> bool {
+		self.mapper.ignores_write(addr)
 	}
 
-	pub fn read_prg_rom(&self, num_bank: u8, addr: u16) -> u8 {
-		let prg_bank = self.prg_rom.get(num_bank as usize).expect("The PRG bank number doesn't exist");
-		prg_bank[addr as usize]
+	/// CPU write of $4020-$FFFF.
+	pub fn cpu_write(&mut self, addr: u16, value: u8, poke: bool) {
+		self.mapper.cpu_write(addr, value, poke);
 	}
 
-	pub fn write_prg_rom(&mut self, num_bank: u8, addr: u16, value: u8) {
-		let prg_bank = self.prg_rom.get_mut(num_bank as usize).expect("The CHR bank number doesn't exist");
-		prg_bank[addr as usize] = value;
+	/// PPU read of $0000-$3EFF, `ciram` is the nametable VRAM inside the NES.
+	pub fn ppu_read(&mut self, addr: u16, fetch: PpuFetch, ciram: &[u8]) -> u8 {
+		self.mapper.ppu_read(addr, fetch, ciram)
 	}
-}
\ No newline at end of file
+
+	/// PPU write of $0000-$3EFF.
+	pub fn ppu_write(&mut self, addr: u16, value: u8, ciram: &mut [u8]) {
+		self.mapper.ppu_write(addr, value, ciram);
+	}
+
+	pub fn ppu_register_write(&mut self, reg: u16, value: u8) {
+		self.mapper.ppu_register_write(reg, value);
+	}
+
+	pub fn ppu_tick(&mut self, scanline: u16, dot: u16, rendering: bool) {
+		self.mapper.ppu_tick(scanline, dot, rendering);
+	}
+
+	pub fn cpu_tick(&mut self) {
+		self.mapper.cpu_tick();
+	}
+
+	/// The expansion audio chips, mixed with their volumes.
+	pub fn audio_output(&self, volumes: &ExpansionVolumes) -> Level {
+		self.mapper.audio_sources().iter().map(|&source| volumes.apply(source, self.mapper.audio_output(source))).sum()
+	}
+
+	/// Amount of disk sides (FDS), 0 for cartridges.
+	pub fn disk_sides(&self) -> usize {
+		self.mapper.disk_sides()
+	}
+
+	/// Insert a disk side (FDS), or eject the disk.
+	pub fn insert_disk(&mut self, side: Option<usize>) {
+		self.mapper.insert_disk(side);
+	}
+
+	/// The reset button.
+	pub fn reset(&mut self) {
+		self.mapper.reset();
+	}
+
+	/// Load the battery backed save memory from `path` if the file exists. `save_battery` saves it there.
+	/// Cartridges without save memory ignore it.
+	pub fn load_battery(&mut self, path: PathBuf) {
+		if self.mapper.battery_data().is_none() {
+			return;
+		}
+		if let Ok(data) = fs::read(&path) {
+			info!("Loaded the save memory from {:?}", path);
+			self.mapper.load_battery_data(&data);
+		}
+		self.battery_path = Some(path);
+	}
+
+	/// Save the battery backed save memory to the file of `load_battery`.
+	pub fn save_battery(&self) {
+		let (Some(path), Some(data)) = (&self.battery_path, self.mapper.battery_data()) else {
+			return;
+		};
+		match fs::write(path, data) {
+			Ok(()) => info!("Saved the save memory to {:?}", path),
+			Err(err) => warn!("Could not save the save memory to {:?}: {}", path, err),
+		}
+	}
+
+	/// A copy for `NES::fork`, sharing the ROM. The copy doesn't save the battery file.
+	pub fn fork(&self) -> Cartridge {
+		Cartridge {
+			num_prg_banks: self.num_prg_banks,
+			num_chr_banks: self.num_chr_banks,
+			mapper_num: self.mapper_num,
+			mirror_type: self.mirror_type.clone(),
+			crc32: self.crc32,
+			tv_system: self.tv_system,
+			has_battery: self.has_battery,
+			has_trainer: self.has_trainer,
+			mapper: self.mapper.fork(),
+			vs_system: self.vs_system.clone(),
+			battery_path: None,
+		}
+	}
+
+	/// The mapper IRQ line.
+	pub fn irq(&self) -> bool {
+		self.mapper.irq()
+	}
+}
+
+/// The mapper state (RAM, bank registers, IRQ counters, audio) and the VS System switches. The ROM is not saved, so a
+/// state is loaded into a cartridge with the same ROM.
+impl Serialize for Cartridge {
+	fn serialize(&mut self, s: &mut Serializer) {
+		self.mapper.serialize(s);
+		if let Some(vs_system) = &mut self.vs_system {
+			s.value(vs_system);
+		}
+	}
+}
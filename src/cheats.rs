@@ -0,0 +1,85 @@
+/// A RAM address frozen to a value: the game's writes to it are replaced by the value, so e.g. the lives never go down.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Freeze {
+	pub addr: u16,
+	pub value: u8,
+	pub enabled: bool,
+}
+
+/// The frozen addresses. The CPU enforces them when the game writes memory (pokes are not affected, so the debugger
+/// can still change a frozen address).
+#[derive(Clone, Default)]
+pub struct FreezeList {
+	entries: Vec<Freeze>,
+}
+
+impl FreezeList {
+	pub fn new() -> Self {
+		FreezeList::default()
+	}
+
+	/// Freeze an address (enabled), or change the value of a frozen address.
+	pub fn set(&mut self, addr: u16, value: u8) {
+		match self.entries.iter_mut().find(|entry| entry.addr == addr) {
+			Some(entry) => {
+				entry.value = value;
+				entry.enabled = true;
+			}
+			None => self.entries.push(Freeze { addr, value, enabled: true }),
+		}
+	}
+
+	/// Returns the removed freeze.
+	pub fn remove(&mut self, addr: u16) -> Option<Freeze> {
+		let index = self.entries.iter().position(|entry| entry.addr == addr)?;
+		Some(self.entries.remove(index))
+	}
+
+	/// Enable or disable a freeze without forgetting its value. Returns the freeze, None if the address is not frozen.
+	pub fn set_enabled(&mut self, addr: u16, enabled: bool) -> Option<Freeze> {
+		let entry = self.entries.iter_mut().find(|entry| entry.addr == addr)?;
+		entry.enabled = enabled;
+		Some(*entry)
+	}
+
+	pub fn entries(&self) -> &[Freeze] {
+		&self.entries
+	}
+
+	/// The value to write to `addr`: the frozen value if the address is frozen (and enabled), otherwise `value`.
+	pub fn apply(&self, addr: u16, value: u8) -> u8 {
+		if self.entries.is_empty() {
+			return value;
+		}
+		match self.entries.iter().find(|entry| entry.enabled && entry.addr == addr) {
+			Some(entry) => entry.value,
+			None => value,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::FreezeList;
+
+	#[test]
+	fn test_freeze_list() {
+		let mut freezes = FreezeList::new();
+		assert_eq!(freezes.apply(0x075A, 2), 2);
+
+		freezes.set(0x075A, 9);
+		assert_eq!(freezes.apply(0x075A, 2), 9);
+		assert_eq!(freezes.apply(0x075B, 2), 2);
+
+		freezes.set(0x075A, 5);
+		assert_eq!(freezes.entries().len(), 1);
+		assert_eq!(freezes.apply(0x075A, 2), 5);
+
+		assert!(freezes.set_enabled(0x075A, false).is_some());
+		assert_eq!(freezes.apply(0x075A, 2), 2);
+		assert!(freezes.set_enabled(0x0000, false).is_none());
+
+		assert_eq!(freezes.remove(0x075A).map(|freeze| freeze.value), Some(5));
+		assert!(freezes.entries().is_empty());
+	}
+}
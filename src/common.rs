@@ -1,3 +1,5 @@
+use std::any::Any;
+
 pub mod bits {
 	pub fn set(flags: &mut u8, bit: u8, value: bool) {
 		if value {
@@ -14,4 +16,11 @@ pub mod bits {
 }
 
 pub type PRG_Bank = [u8; 16_384];
-pub type CHR_Bank = [u8; 8_192];
\ No newline at end of file
+pub type CHR_Bank = [u8; 8_192];
+
+/// The message of a caught panic (`catch_unwind`), e.g. to report why a ROM couldn't load.
+pub fn panic_message(err: &(dyn Any + Send)) -> String {
+	err.downcast_ref::<&str>().map(|s| s.to_string())
+		.or_else(|| err.downcast_ref::<String>().cloned())
+		.unwrap_or_else(|| "unknown panic".to_string())
+}
\ No newline at end of file
@@ -0,0 +1,260 @@
+//! Batch compatibility test: runs every ROM of a directory headless and reports which boot, which crash and which only
+//! show a blank screen. Keeping the reports of each release shows the compatibility progress, and the picture hashes
+//! show which games changed.
+
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+use log::info;
+
+use crate::{common, error::RomError, headless, nes::NES, rom_parser::{RomParser, SizeMismatch}, unimplemented::{Needs, UnimplementedPolicy}};
+
+/// Frames to run each ROM, enough for most games to show their title screen.
+pub const DEFAULT_FRAMES: u64 = 600;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompatStatus {
+	Boots,			// Ran all the frames and shows a picture
+	Blank,			// Ran all the frames, but the screen is a single color
+	Halted,			// The CPU executed a KIL opcode
+	Crash,			// The emulator panicked, or the file is not a valid ROM
+	Unsupported,	// The emulator doesn't support the mapper, not run
+}
+
+impl CompatStatus {
+	pub fn name(self) -> &'static str {
+		match self {
+			CompatStatus::Boots => "boots",
+			CompatStatus::Blank => "blank",
+			CompatStatus::Halted => "halted",
+			CompatStatus::Crash => "crash",
+			CompatStatus::Unsupported => "unsupported",
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompatResult {
+	pub rom: String,			// File name
+	pub mapper: Option<u8>,		// None when the header can't be parsed
+	pub status: CompatStatus,
+	pub frames: u64,			// Frames run before the end or the crash
+	pub hash: Option<u32>,		// Of the last picture, see `headless::framebuffer_hash`
+	pub unimplemented: u64,		// Uses of opcodes the emulator doesn't implement
+	pub needs: Needs,			// The unsupported mapper, or the features it used that the emulator doesn't implement
+	pub message: String,		// Why it crashed or halted
+}
+
+/// Run the ROM for the frames. Panics of the emulator are caught and reported as a crash.
+pub fn run_rom(path: &Path, frames: u64) -> CompatResult {
+	let mut result = CompatResult {
+		rom: path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default(),
+		mapper: None,
+		status: CompatStatus::Crash,
+		frames: 0,
+		hash: None,
+		unimplemented: 0,
+		needs: Needs::default(),
+		message: String::new(),
+	};
+	let mut nes = None;
+	let run = panic::catch_unwind(AssertUnwindSafe(|| {
+		let contents = fs::read(path).unwrap_or_else(|e| panic!("Can't read the file: {}", e));
+		let mut rom_parser = RomParser::new();
+		if let Err(err) = rom_parser.parse_contents(&contents) {
+			result.status = CompatStatus::Crash;
+			result.message = err.to_string();
+			return;
+		}
+		result.mapper = Some(rom_parser.header.mapper);
+
+		let opened = match NES::open_rom_file(path.to_str().expect("Non UTF-8 path"), SizeMismatch::Fix) {
+			Err(RomError::UnsupportedMapper(_)) => {
+				result.status = CompatStatus::Unsupported;
+				result.needs.mapper = result.mapper;
+				return;
+			}
+			opened => opened,
+		};
+		let nes = nes.insert(opened.unwrap_or_else(|e| panic!("{}", e)));
+		nes.cpu.unimplemented_mut().set_policy(UnimplementedPolicy::Quiet);
+		while result.frames < frames {
+			result.frames += 1;
+			if nes.try_run_frame().is_err() {
+				break;
+			}
+		}
+		let framebuffer = nes.cpu.ppu().framebuffer();
+		result.status = match nes.halted() {
+			Some(halted) => {
+				result.message = halted.to_string();
+				CompatStatus::Halted
+			}
+			None if framebuffer.iter().all(|&pixel| pixel == framebuffer[0]) => CompatStatus::Blank,
+			None => CompatStatus::Boots,
+		};
+	}));
+	if let Err(err) = run {
+		result.status = CompatStatus::Crash;
+		// The first line, assertions add the values on the next lines
+		result.message = common::panic_message(err.as_ref()).lines().next().unwrap_or_default().to_string();
+	}
+	if let Some(nes) = &nes {
+		result.hash = Some(headless::framebuffer_hash(nes));
+		result.unimplemented = nes.cpu.unimplemented().counts().map(|(_, count)| count).sum();
+		result.needs = nes.cpu.unimplemented().needs();
+	}
+	result
+}
+
+/// Run every .nes ROM of the directory, in the order of their names.
+pub fn run(dir: &str, frames: u64) -> Result<Vec<CompatResult>, String> {
+	let mut roms: Vec<_> = fs::read_dir(dir).map_err(|e| format!("Can't read {}: {}", dir, e))?
+		.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+		.filter(|path| path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("nes")))
+		.collect();
+	roms.sort();
+	let results: Vec<_> = roms.iter().map(|path| {
+		let result = run_rom(path, frames);
+		info!("{}: {}", result.rom, result.status.name());
+		result
+	}).collect();
+	let count = |status| results.iter().filter(|result| result.status == status).count();
+	info!("{} ROMs: {} boot, {} blank, {} halted, {} crash, {} unsupported", results.len(), count(CompatStatus::Boots),
+		count(CompatStatus::Blank), count(CompatStatus::Halted), count(CompatStatus::Crash), count(CompatStatus::Unsupported));
+	Ok(results)
+}
+
+fn csv_field(text: &str) -> String {
+	if text.contains([',', '"', '\n']) {
+		format!("\"{}\"", text.replace('"', "\"\""))
+	} else {
+		text.to_string()
+	}
+}
+
+fn json_string(text: &str) -> String {
+	let mut json = String::from("\"");
+	for c in text.chars() {
+		match c {
+			'"' => json += "\\\"",
+			'\\' => json += "\\\\",
+			c if (c as u32) < 0x20 => json += &format!("\\u{:04x}", c as u32),
+			c => json.push(c),
+		}
+	}
+	json + "\""
+}
+
+/// The report as CSV, a line for each ROM.
+pub fn csv_report(results: &[CompatResult]) -> String {
+	let mut csv = String::from("rom,mapper,status,frames,hash,unimplemented,needs,message\n");
+	for result in results {
+		let needs = if result.needs.is_empty() { String::new() } else { result.needs.to_string() };
+		csv += &format!("{},{},{},{},{},{},{},{}\n", csv_field(&result.rom), result.mapper.map(|m| m.to_string()).unwrap_or_default(),
+			result.status.name(), result.frames, result.hash.map(|hash| format!("{:08X}", hash)).unwrap_or_default(),
+			result.unimplemented, csv_field(&needs), csv_field(&result.message));
+	}
+	csv
+}
+
+/// The needs as a JSON object: the unsupported mapper, and the opcodes (addresses) of each kind with their counts, e.g.
+/// `{"mapper": null, "opcodes": {"0B": 2}, "addressing_modes": {}, "register_reads": {"5205": 10}}`.
+fn json_needs(needs: &Needs) -> String {
+	let mut fields = vec![format!("\"mapper\": {}", needs.mapper.map(|m| m.to_string()).unwrap_or("null".to_string()))];
+	for kind in Needs::KINDS {
+		let counts: Vec<String> = needs.of_kind(kind).map(|(value, count)| match kind {
+			"register_reads" => format!("\"{:04X}\": {}", value, count),
+			_ => format!("\"{:02X}\": {}", value, count),
+		}).collect();
+		fields.push(format!("\"{}\": {{{}}}", kind, counts.join(", ")));
+	}
+	format!("{{{}}}", fields.join(", "))
+}
+
+/// The report of one game for an issue, as JSON: the ROM, what it needs (see `Needs`) in words and by kind.
+pub fn needs_report(rom: &str, crc32: Option<u32>, frames: u64, needs: &Needs) -> String {
+	format!("{{\n  \"version\": \"{}\",\n  \"rom\": {},\n  \"crc32\": {},\n  \"frames\": {},\n  \"summary\": {},\n  \"needs\": {}\n}}\n",
+		env!("CARGO_PKG_VERSION"), json_string(rom), crc32.map(|crc32| format!("\"{:08X}\"", crc32)).unwrap_or("null".to_string()),
+		frames, json_string(&format!("needs {}", needs)), json_needs(needs))
+}
+
+/// The report as JSON, with the version of the emulator and the frames of the run.
+pub fn json_report(results: &[CompatResult], frames: u64) -> String {
+	let roms: Vec<String> = results.iter().map(|result| format!(
+		"    {{\"rom\": {}, \"mapper\": {}, \"status\": \"{}\", \"frames\": {}, \"hash\": {}, \"unimplemented\": {}, \"needs\": {}, \"message\": {}}}",
+		json_string(&result.rom), result.mapper.map(|m| m.to_string()).unwrap_or("null".to_string()), result.status.name(),
+		result.frames, result.hash.map(|hash| format!("\"{:08X}\"", hash)).unwrap_or("null".to_string()),
+		result.unimplemented, json_needs(&result.needs), json_string(&result.message),
+	)).collect();
+	format!("{{\n  \"version\": \"{}\",\n  \"frames\": {},\n  \"roms\": [\n{}\n  ]\n}}\n", env!("CARGO_PKG_VERSION"), frames, roms.join(",\n"))
+}
+
+/// Write the report, as JSON if the file name ends with .json, else as CSV.
+pub fn write_report(path: &str, results: &[CompatResult], frames: u64) -> Result<(), String> {
+	let report = if path.to_lowercase().ends_with(".json") { json_report(results, frames) } else { csv_report(results) };
+	fs::write(path, report).map_err(|e| format!("Can't write {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+
+	use super::{csv_report, json_report, needs_report, run, CompatStatus};
+	use crate::{program_loader::*, unimplemented::{Needs, UnimplementedFeature}};
+
+	/// An NROM ROM with the program, 32KB PRG and 8KB CHR with tile 1 solid, horizontal mirroring.
+	fn nrom<R>(load_program: impl FnOnce(&mut [u8; 1024*32]) -> R) -> Vec<u8> {
+		let mut rom_memory: [u8; 1024*32] = [0;1024*32];
+		load_program(&mut rom_memory);
+		set_reset_vector_after_warm_up(&mut rom_memory, 0x8000);
+		let mut rom = b"NES\x1A\x02\x01\x00\x00".to_vec();
+		rom.resize(16, 0);
+		rom.extend(rom_memory);
+		let mut chr = [0; 1024 * 8];
+		chr[0x10..0x18].fill(0xFF);
+		rom.extend(chr);
+		rom
+	}
+
+	#[test]
+	fn test_compat_run() {
+		let dir = std::env::temp_dir().join(format!("rust-nes-emulator-compat-{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		fs::write(dir.join("1 draws.nes"), nrom(load_program_scroll)).unwrap();
+		// JMP $8000
+		fs::write(dir.join("2 blank.nes"), nrom(|rom| rom[..3].copy_from_slice(&[0x4C, 0x00, 0x80]))).unwrap();
+		// KIL, after the warm-up (the second frame)
+		fs::write(dir.join("3 halts.nes"), nrom(|rom| rom[0] = 0x02)).unwrap();
+		let mut unsupported = nrom(load_program_scroll);
+		unsupported[6] = 0xF0;
+		fs::write(dir.join("4 unsupported.nes"), unsupported).unwrap();
+		fs::write(dir.join("5 invalid.nes"), b"Not a ROM").unwrap();
+		fs::write(dir.join("notes.txt"), b"Not a ROM").unwrap();
+
+		let results = run(dir.to_str().unwrap(), 5).unwrap();
+		fs::remove_dir_all(&dir).unwrap();
+		let statuses: Vec<_> = results.iter().map(|result| result.status).collect();
+		assert_eq!(statuses, [CompatStatus::Boots, CompatStatus::Blank, CompatStatus::Halted, CompatStatus::Unsupported, CompatStatus::Crash]);
+		assert_eq!(results.iter().map(|result| result.frames).collect::<Vec<_>>(), [5, 5, 2, 0, 0]);
+		assert_eq!(results[3].mapper, Some(15));
+		assert!(results[2].message.starts_with("CPU halted"));
+		assert!(results[0].hash.is_some() && results[3].hash.is_none());
+
+		let csv = csv_report(&results);
+		assert_eq!(csv.lines().count(), 6);
+		assert!(csv.lines().nth(4).unwrap().starts_with("4 unsupported.nes,15,unsupported,0,,0,mapper 15,"));
+		let json = json_report(&results, 5);
+		assert!(json.contains("{\"rom\": \"4 unsupported.nes\", \"mapper\": 15, \"status\": \"unsupported\", \"frames\": 0, \"hash\": null"));
+		assert!(json.contains("\"needs\": {\"mapper\": 15, \"opcodes\": {}, \"addressing_modes\": {}, \"register_reads\": {}}"));
+	}
+
+	#[test]
+	fn test_needs_report() {
+		let needs = Needs { mapper: None, features: vec![(UnimplementedFeature::Opcode(0x0B), 3), (UnimplementedFeature::Opcode(0x2B), 1), (UnimplementedFeature::RegisterRead(0x5205), 10)] };
+		let report = needs_report("game.nes", Some(0x1234ABCD), 600, &needs);
+		assert!(report.contains("\"crc32\": \"1234ABCD\",\n  \"frames\": 600,\n  \"summary\": \"needs opcodes $0B/$2B, $5205 reads\""), "{}", report);
+		assert!(report.contains("\"needs\": {\"mapper\": null, \"opcodes\": {\"0B\": 3, \"2B\": 1}, \"addressing_modes\": {}, \"register_reads\": {\"5205\": 10}}"), "{}", report);
+	}
+}
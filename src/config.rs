@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::str::FromStr;
+
+use log::{info, warn};
+
+/// The settings file, in the current directory.
+pub const CONFIG_PATH: &str = "nes-emulator.cfg";
+
+/// Settings file: a `key = value` setting on each line, `#` starts a comment. Settings that are missing use defaults.
+/// Example:
+/// ```text
+/// # CRT filter
+/// crt.scanlines = 0.4
+/// ```
+pub struct Config {
+	values: HashMap<String, String>,
+	lines: Vec<String>,	// The file, so saving keeps the comments and the order
+}
+
+impl Config {
+	/// Load the settings file. Without the file, all the settings are the defaults.
+	pub fn load(path: &str) -> Self {
+		match fs::read_to_string(path) {
+			Ok(text) => {
+				info!("Loaded settings from {}", path);
+				Config::parse(&text)
+			}
+			Err(_) => Config::parse(""),
+		}
+	}
+
+	pub fn parse(text: &str) -> Self {
+		let mut values = HashMap::new();
+		for (i, line) in text.lines().enumerate() {
+			let line = line.split('#').next().unwrap().trim();
+			if line.is_empty() {
+				continue;
+			}
+			match line.split_once('=') {
+				Some((key, value)) => {
+					values.insert(key.trim().to_string(), value.trim().to_string());
+				}
+				None => warn!("Settings line {} is not `key = value`: {}", i + 1, line),
+			}
+		}
+		Config {
+			values,
+			lines: text.lines().map(|line| line.to_string()).collect(),
+		}
+	}
+
+	/// Change a setting. The line of the setting is replaced (and its comment dropped), new settings are added at the end.
+	pub fn set(&mut self, key: &str, value: &str) {
+		self.values.insert(key.to_string(), value.to_string());
+		let setting = format!("{} = {}", key, value);
+		let line = self.lines.iter_mut().find(|line| {
+			let line = line.split('#').next().unwrap();
+			matches!(line.split_once('='), Some((line_key, _)) if line_key.trim() == key)
+		});
+		match line {
+			Some(line) => *line = setting,
+			None => self.lines.push(setting),
+		}
+	}
+
+	pub fn save(&self, path: &str) -> io::Result<()> {
+		fs::write(path, self.lines.join("\n") + "\n")
+	}
+
+	/// The value of a setting, or the default if it is missing or invalid.
+	pub fn get<T: FromStr>(&self, key: &str, default: T) -> T {
+		match self.values.get(key) {
+			Some(value) => value.parse().unwrap_or_else(|_| {
+				warn!("Invalid value for setting {}: {}", key, value);
+				default
+			}),
+			None => default,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Config;
+
+	#[test]
+	fn test_parse() {
+		let config = Config::parse("# comment\ncrt.scanlines = 0.5 # darker\n\ncrt.mask=abc\nnot a setting\n");
+		assert_eq!(config.get("crt.scanlines", 0.0), 0.5);
+		assert_eq!(config.get("crt.mask", 0.25), 0.25);
+		assert_eq!(config.get("crt.vignette", 0.1), 0.1);
+	}
+
+	#[test]
+	fn test_set() {
+		let mut config = Config::parse("# CRT\ncrt.scanlines = 0.5\ncrt.mask = 0.1");
+		config.set("crt.scanlines", "0.2");
+		config.set("input.1.a", "key:X");
+		assert_eq!(config.get("crt.scanlines", 0.0), 0.2);
+		assert_eq!(config.get("input.1.a", String::new()), "key:X");
+		assert_eq!(config.lines, ["# CRT", "crt.scanlines = 0.2", "crt.mask = 0.1", "input.1.a = key:X"]);
+	}
+}
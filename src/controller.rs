@@ -0,0 +1,203 @@
+use crate::savestate::{Serialize, Serializer};
+
+/// A button of the standard controller, in the order the controller reports them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Button {
+	A,
+	B,
+	Select,
+	Start,
+	Up,
+	Down,
+	Left,
+	Right,
+}
+
+impl Button {
+	pub const ALL: [Button; 8] = [Button::A, Button::B, Button::Select, Button::Start, Button::Up, Button::Down, Button::Left, Button::Right];
+
+	/// The bit of the button in `Controller::buttons`.
+	pub fn mask(self) -> u8 {
+		1 << self as u8
+	}
+
+	/// The name in the settings file.
+	pub fn name(self) -> &'static str {
+		match self {
+			Button::A => "a",
+			Button::B => "b",
+			Button::Select => "select",
+			Button::Start => "start",
+			Button::Up => "up",
+			Button::Down => "down",
+			Button::Left => "left",
+			Button::Right => "right",
+		}
+	}
+}
+
+/// The pressed buttons of a controller, bit per `Button` (bit 0 is A).
+pub type ButtonState = u8;
+
+/// Where the buttons come from when the emulator doesn't get them from the window: a movie, a script in the tests...
+/// The CPU polls it once per frame, at the first strobe of $4016, and the controllers latch what it returns.
+pub trait InputProvider {
+	/// The buttons of both players for `frame` (see `NES::frame`).
+	fn poll(&mut self, frame: u64) -> [ButtonState; 2];
+}
+
+/// Scripts: a closure of the frame.
+impl<F: FnMut(u64) -> [ButtonState; 2]> InputProvider for F {
+	fn poll(&mut self, frame: u64) -> [ButtonState; 2] {
+		self(frame)
+	}
+}
+
+/// The standard controller: a shift register. Writing 1 and then 0 to bit 0 of $4016 (strobe) latches the buttons,
+/// then each read of $4016 (player 1) or $4017 (player 2) returns the next button in bit 0. After the 8 buttons, reads
+/// return 1.
+/// Read here: https://www.nesdev.org/wiki/Standard_controller
+#[derive(Clone)]
+pub struct Controller {
+	buttons: u8,	// Pressed buttons, bit per `Button`
+	shift: u8,		// Latched buttons that were not read yet
+	strobe: bool,	// While set, the shift register reloads all the time (reads return A)
+	latches: u64,	// Amount of times the game latched the buttons
+}
+
+impl Default for Controller {
+	fn default() -> Self {
+		Controller::new()
+	}
+}
+
+impl Controller {
+	pub fn new() -> Self {
+		Controller {
+			buttons: 0,
+			shift: 0,
+			strobe: false,
+			latches: 0,
+		}
+	}
+
+	pub fn set_button(&mut self, button: Button, pressed: bool) {
+		if pressed {
+			self.buttons |= button.mask();
+		} else {
+			self.buttons &= !button.mask();
+		}
+	}
+
+	pub fn is_pressed(&self, button: Button) -> bool {
+		self.buttons & button.mask() != 0
+	}
+
+	/// All the pressed buttons, bit per `Button` (bit 0 is A). For movies.
+	pub fn buttons(&self) -> u8 {
+		self.buttons
+	}
+
+	pub fn set_buttons(&mut self, buttons: u8) {
+		self.buttons = buttons;
+	}
+
+	/// Write of $4016. Both controllers see the strobe.
+	pub fn write(&mut self, value: u8) {
+		let strobe = value & 1 == 1;
+		if self.strobe && !strobe {
+			self.latches += 1;
+		}
+		self.strobe = strobe;
+		self.shift = self.buttons;
+	}
+
+	/// Read of the controller port, bit 0 is the next button. When `peek` is true, the register doesn't shift.
+	pub fn read(&mut self, peek: bool) -> u8 {
+		if self.strobe {
+			return self.buttons & 1;
+		}
+		let bit = self.shift & 1;
+		if !peek {
+			self.shift = (self.shift >> 1) | 0x80;
+		}
+		bit
+	}
+
+	/// Amount of times the game latched the buttons (strobe went from 1 to 0). The buttons that were pressed before
+	/// a latch are seen by the game.
+	pub fn latches(&self) -> u64 {
+		self.latches
+	}
+}
+
+impl Serialize for Controller {
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.buttons);
+		s.value(&mut self.shift);
+		s.value(&mut self.strobe);
+		s.value(&mut self.latches);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{Arc, Mutex};
+
+	use super::{Button, Controller};
+	use crate::{nes::NES, program_loader::*};
+
+	#[test]
+	fn test_read_buttons() {
+		let mut controller = Controller::new();
+		controller.set_button(Button::A, true);
+		controller.set_button(Button::Start, true);
+		controller.set_button(Button::Left, true);
+
+		// While strobe is set, reads return A
+		controller.write(1);
+		assert_eq!(controller.read(false), 1);
+		assert_eq!(controller.read(false), 1);
+		controller.write(0);
+		assert_eq!(controller.latches(), 1);
+
+		let bits: Vec<u8> = (0..8).map(|_| controller.read(false)).collect();
+		assert_eq!(bits, [1, 0, 0, 1, 0, 0, 1, 0]);
+		// After the 8 buttons
+		assert_eq!(controller.read(false), 1);
+
+		// Buttons pressed after the latch are seen on the next latch
+		controller.set_button(Button::B, true);
+		assert_eq!(controller.read(true), 1);
+		controller.write(1);
+		controller.write(0);
+		assert_eq!(controller.latches(), 2);
+		assert_eq!((controller.read(false), controller.read(false)), (1, 1));
+	}
+
+	#[test]
+	fn test_input_provider() {
+		let mut rom_memory: [u8; 1024*32] = [0;1024*32];
+		load_program_count_a_presses(&mut rom_memory);
+		set_reset_vector(&mut rom_memory, 0x8000);
+		let mut nes = NES::new_custom_prg_rom(rom_memory);
+
+		// A script pressing A on the even frames, polled once per frame
+		let polls = Arc::new(Mutex::new(vec![]));
+		let polls_clone = polls.clone();
+		nes.set_input_provider(move |frame: u64| {
+			polls_clone.lock().unwrap().push(frame);
+			[if frame.is_multiple_of(2) { Button::A.mask() } else { 0 }, 0]
+		});
+		nes.set_button(0, Button::A, true);
+		nes.run_frames(6);
+		assert_eq!(*polls.lock().unwrap(), [0, 1, 2, 3, 4, 5]);
+		assert_eq!(nes.peek(0x0200), 3);
+
+		// The buttons of `set_button` again
+		nes.clear_input_provider();
+		nes.set_button(0, Button::A, true);
+		nes.run_frames(2);
+		assert_eq!(nes.peek(0x0200), 5);
+	}
+}
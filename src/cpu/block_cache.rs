@@ -0,0 +1,151 @@
+//! Threaded code, the `threaded` feature: the instructions of the PRG ROM are decoded once, in blocks that run until an
+//! unconditional jump (JMP, JSR, RTS, RTI, BRK, KIL) or the end of the bank. The CPU then takes the opcode and the
+//! operand bytes from the block instead of reading them on the bus, and runs the same handlers as the interpreter.
+//!
+//! A block is found by the PRG ROM offset of its first instruction (the bank and the address), so a bank switch maps
+//! other blocks in. A bank mirrored at two addresses recompiles its blocks for the address that runs. Writes to the cartridge end the current block (they can switch banks), ROM pokes drop the cache.
+//! Code in RAM and PRG RAM can change, it is always interpreted.
+
+use super::decoder::{decode_opcode, Instructions};
+
+/// Long blocks cost nothing when a branch leaves them, the next instruction is looked up again.
+const MAX_BLOCK_LEN: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CachedInstruction {
+	pub pc: u16,
+	pub opcode: u8,
+	pub operand: u16,	// The bytes after the opcode, little endian
+}
+
+#[derive(Default)]
+pub struct BlockCache {
+	enabled: bool,
+	blocks: Vec<Vec<CachedInstruction>>,
+	index: Vec<u32>,					// PRG ROM offset of the first instruction -> block + 1, 0 when not compiled
+	cursor: Option<(usize, usize)>,		// The block and the instruction after the last one executed
+}
+
+impl BlockCache {
+	pub fn new() -> Self {
+		BlockCache { enabled: true, ..BlockCache::default() }
+	}
+
+	pub fn enabled(&self) -> bool {
+		self.enabled
+	}
+
+	pub fn set_enabled(&mut self, enabled: bool) {
+		self.enabled = enabled;
+		self.clear();
+	}
+
+	/// The next instruction of the current block, when the PC didn't leave it (a taken branch, an interrupt), or its
+	/// first one when the PC looped back to its start.
+	pub fn next(&mut self, pc: u16) -> Option<CachedInstruction> {
+		let (block, position) = self.cursor?;
+		let instructions = &self.blocks[block];
+		let position = match instructions.get(position) {
+			Some(instruction) if instruction.pc == pc => position,
+			_ if instructions.first()?.pc == pc => 0,
+			_ => return None,
+		};
+		self.cursor = Some((block, position + 1));
+		Some(instructions[position])
+	}
+
+	/// Start the block at `pc`, mapped to a PRG ROM offset, compiled by `compile` the first time. None when it has no
+	/// instruction.
+	pub fn enter(&mut self, offset: usize, pc: u16, compile: impl FnOnce() -> Vec<CachedInstruction>) -> Option<CachedInstruction> {
+		if offset >= self.index.len() {
+			self.index.resize(offset + 1, 0);
+		}
+		let block = match self.index[offset] as usize {
+			0 => None,
+			block => Some(block - 1).filter(|&block| self.blocks[block].first().is_none_or(|instruction| instruction.pc == pc)),
+		};
+		let block = block.unwrap_or_else(|| {
+			self.blocks.push(compile());
+			self.index[offset] = self.blocks.len() as u32;
+			self.blocks.len() - 1
+		});
+		self.cursor = Some((block, 0));
+		self.next(pc)
+	}
+
+	/// An empty cache for a copy of the machine, the copy compiles its blocks again.
+	pub fn fork(&self) -> Self {
+		BlockCache { enabled: self.enabled, ..BlockCache::default() }
+	}
+
+	/// The next instruction is looked up again, e.g. after a bank switch.
+	pub fn end_block(&mut self) {
+		self.cursor = None;
+	}
+
+	/// The ROM changed.
+	pub fn clear(&mut self) {
+		self.blocks.clear();
+		self.index.clear();
+		self.cursor = None;
+	}
+}
+
+/// Decode the instructions from `start`, without going past the `bank_left` bytes of the bank. Stops before an illegal
+/// opcode, the interpreter reports it.
+pub fn compile_block(start: u16, bank_left: usize, mut peek: impl FnMut(u16) -> u8) -> Vec<CachedInstruction> {
+	let mut block = Vec::new();
+	let mut pc = start;
+	let mut used = 0;
+	while block.len() < MAX_BLOCK_LEN {
+		let opcode = peek(pc);
+		let Some((instr, _, bytes, ..)) = decode_opcode(opcode) else { break };
+		if used + bytes as usize > bank_left || pc as usize + bytes as usize > 0x10000 {
+			break;
+		}
+		let operand = match bytes {
+			2 => peek(pc + 1) as u16,
+			3 => peek(pc + 1) as u16 | (peek(pc + 2) as u16) << 8,
+			_ => 0,
+		};
+		block.push(CachedInstruction { pc, opcode, operand });
+		if matches!(instr, Instructions::JMP | Instructions::JSR | Instructions::RTS | Instructions::RTI | Instructions::BRK | Instructions::KIL) {
+			break;
+		}
+		pc = pc.wrapping_add(bytes as u16);
+		used += bytes as usize;
+	}
+	block
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{compile_block, BlockCache, CachedInstruction};
+
+	#[test]
+	fn test_block_cache() {
+		// LDA #$01, STA $0200, BNE -7, JMP $8000, NOP
+		let rom = [0xA9, 0x01, 0x8D, 0x00, 0x02, 0xD0, 0xF9, 0x4C, 0x00, 0x80, 0xEA];
+		let peek = |addr: u16| rom[(addr - 0x8000) as usize];
+		let block = compile_block(0x8000, 0x4000, peek);
+		assert_eq!(block.iter().map(|i| (i.pc, i.opcode, i.operand)).collect::<Vec<_>>(), vec![
+			(0x8000, 0xA9, 0x01), (0x8002, 0x8D, 0x0200), (0x8005, 0xD0, 0xF9), (0x8007, 0x4C, 0x8000),
+		]);
+		// The STA doesn't fit in the 4 bytes left in the bank
+		assert_eq!(compile_block(0x8000, 4, peek).len(), 1);
+
+		let mut cache = BlockCache::new();
+		let first = cache.enter(0x10, 0x8000, || block.clone());
+		assert_eq!(first, Some(CachedInstruction { pc: 0x8000, opcode: 0xA9, operand: 0x01 }));
+		assert_eq!(cache.next(0x8002).map(|i| i.opcode), Some(0x8D));
+		// A taken branch leaves the block, or loops back to its start
+		assert_eq!(cache.next(0x8010), None);
+		assert_eq!(cache.next(0x8000).map(|i| i.opcode), Some(0xA9));
+		assert_eq!(cache.enter(0x10, 0x8000, || unreachable!()).map(|i| i.pc), Some(0x8000));
+		cache.end_block();
+		assert_eq!(cache.next(0x8002), None);
+		// The same bank mirrored at $C000
+		assert_eq!(cache.enter(0x10, 0xC000, || compile_block(0xC000, 0x4000, |addr| rom[(addr - 0xC000) as usize])).map(|i| i.pc), Some(0xC000));
+		assert_eq!(cache.blocks.len(), 2);
+	}
+}
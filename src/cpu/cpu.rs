@@ -1,65 +1,270 @@
 use core::panic;
-use log::{debug, error, warn};
+use log::{debug, info, warn};
 
+use crate::apu::apu::APU;
 use crate::cartridge::Cartridge;
+use crate::cheats::FreezeList;
+use crate::controller::{Controller, InputProvider};
 use crate::cpu::registers::{Registers, ProcessorStatusBits, ProcessorStatus};
-use crate::cpu::decoder::{OopsCycle, Instructions, AddressingMode, decode_opcode};
-use crate::ppu::ppu::PPU;
+use crate::cpu::decoder::{OopsCycle, Instructions, AddressingMode, Operand, decode_opcode};
+use crate::cpu::events::{AccessKind, BusEvent, EventLog};
+use crate::cpu::io_log::IoFilter;
+use crate::cpu::irq::{IrqLine, IrqSource};
+use crate::cpu::trace::{ExecutedInstruction, InstructionSender, TraceBuffer, TraceEntry, TRACE_BUFFER_SIZE};
+use crate::cpu::disassembler::disassemble;
+use crate::cpu::instructions::HANDLERS;
+#[cfg(feature = "threaded")]
+use crate::cpu::block_cache::{compile_block, BlockCache, CachedInstruction};
+#[cfg(feature = "threaded")]
+use crate::mapper::CpuMapping;
+use crate::ppu::ppu::{PPU, DOTS_PER_SCANLINE};
+use crate::profiling::span;
+use crate::savestate::{Component, Serialize, Serializer};
+use crate::stats::StatsCollector;
+use crate::suspicious::{Suspicious, SuspiciousLog};
+use crate::unimplemented::{UnimplementedFeature, UnimplementedLog};
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// NTSC CPU clock rate (Hz).
+pub const CPU_FREQUENCY: u64 = 1_789_773;
+
+/// Internal RAM, at $0000-$07FF and mirrored three times up to $1FFF.
+const RAM_SIZE: usize = 0x800;
+
+/// How the CPU and the other devices (PPU, APU, cartridge) take turns.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scheduler {
+	/// The devices catch up with the CPU after each instruction. The CPU sees the PPU as it was at the start of the
+	/// instruction, which is a few dots early.
+	Fast,
+	/// The devices run one CPU cycle before each memory access of the CPU, so reads and writes of PPU and mapper
+	/// registers happen at their cycle inside the instruction. The rest of the instruction cycles run at the end.
+	Accurate,
+}
 
-use hex::FromHex;
+impl Scheduler {
+	/// `fast` or `accurate`, for the command line and the debugger.
+	pub fn parse(name: &str) -> Option<Scheduler> {
+		match name {
+			"fast" => Some(Scheduler::Fast),
+			"accurate" => Some(Scheduler::Accurate),
+			_ => None,
+		}
+	}
+
+	pub fn name(self) -> &'static str {
+		match self {
+			Scheduler::Fast => "fast",
+			Scheduler::Accurate => "accurate",
+		}
+	}
+}
 
 pub struct CPU {
-	registers: Registers,
+	pub(super) registers: Registers,
 	cycles: u64,
 	cartridge: Cartridge,
 	ppu: PPU,
-	lower_memory: [u8;1024*32],
-	
-	// The CPU can only access up to 2 program memory banks and 1 character bank at once. The MMU can switch between diffirent banks.
-	active_prgbank_number_lower: u8,
-	active_prgbank_number_upper: u8,
-	active_chrbank_number: u8
+	apu: APU,
+	controllers: [Controller; 2],
+	input: Option<Box<dyn InputProvider + Send>>,	// When set, presses the buttons of the controllers
+	input_frame: Option<u64>,						// Last frame the input was polled
+	ram: [u8; RAM_SIZE],	// Zero page, stack ($0100-$01FF) and the rest, mirrored up to $1FFF
+
+	// Last memory write (address, value) done by the current instruction. Used by the NES run helpers.
+	last_write: Option<(u16, u8)>,
+
+	// Last value on the data bus. Reading an address that nothing drives (open bus) returns it.
+	data_bus: u8,
+
+	// Last executed instructions, for post-mortem dumps
+	trace: TraceBuffer,
+	// Executed instructions for external tools, when a tool asked for them
+	instruction_stream: Option<InstructionSender>,
+
+	// PPU/IO register accesses of each frame, for the event viewer
+	events: EventLog,
+	// The accesses that are logged, see `set_io_log`
+	io_log: Option<IoFilter>,
+
+	// Devices that assert IRQ (mapper, APU)
+	irq_line: IrqLine,
+
+	// Host time spent per subsystem
+	stats: StatsCollector,
+
+	// Overclocking: extra vblank scanlines, the CPU runs while the PPU, APU and cartridge are paused
+	overclock_scanlines: u16,
+	// Extra cycles left in the current vblank
+	overclock_left: u64,
+
+	scheduler: Scheduler,
+	// The CPU cycle that the PPU, APU and cartridge ran up to. Ahead of `cycles` during an instruction in the accurate
+	// scheduler.
+	devices_cycles: u64,
+
+	// Frozen RAM addresses, enforced on the writes of the game
+	freezes: FreezeList,
+
+	// Jammed by a KIL opcode: the CPU stopped, only reset recovers
+	halted: Option<CpuHalted>,
+
+	// The operand of the current instruction crossed a page, for the oops cycle
+	page_crossed: bool,
+
+	// Unimplemented instructions and addressing modes the game used
+	unimplemented: UnimplementedLog,
+
+	// Strict mode: writes to ROM, reads of write-only registers, stack overflows
+	suspicious: SuspiciousLog,
+
+	// Pre-decoded PRG ROM instructions, see `block_cache`
+	#[cfg(feature = "threaded")]
+	block_cache: BlockCache,
+	// The operand bytes of the current instruction, when it came from the block cache
+	#[cfg(feature = "threaded")]
+	prefetched_operand: Option<u16>,
+}
+
+/// The CPU executed a KIL (jam) opcode and stopped: it doesn't fetch instructions nor answer interrupts anymore, while
+/// the PPU and APU keep running. Some test ROMs do it on purpose when they are done. Only reset recovers.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CpuHalted {
+	pub pc: u16,	// Of the KIL opcode
+	pub opcode: u8,
+}
+
+impl fmt::Display for CpuHalted {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "CPU halted by KIL opcode ${:02X} at ${:04X}", self.opcode, self.pc)
+	}
+}
+
+impl Serialize for CpuHalted {
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.pc);
+		s.value(&mut self.opcode);
+	}
 }
 
 impl CPU {
 	pub fn new(cartridge: Cartridge, ppu: PPU) -> Self {
 		let registers: Registers = Registers::default();
 
-		// Defautl configuration: first bank goes to lower memory, second bank goes to upper memory
-		let mut active_prgbank_number_lower = 0;
-		let mut active_prgbank_number_upper = 1;
-
-		// If there is only 1 bank, we MIRROR THE MEMORY for both lower 16KB and upper 16KB.
-		if cartridge.num_prg_banks == 1 {
-			active_prgbank_number_lower = 0;
-			active_prgbank_number_upper = 0;
-		}
-
 		let mut cpu = CPU {
 			registers,
 			cycles: 0,
 			cartridge,
 			ppu,
-			lower_memory: [0;1024*32],
-			active_prgbank_number_lower,
-			active_prgbank_number_upper,
-			active_chrbank_number: 0
-
+			apu: APU::new(),
+			controllers: [Controller::new(), Controller::new()],
+			input: None,
+			input_frame: None,
+			ram: [0; RAM_SIZE],
+			last_write: None,
+			data_bus: 0,
+			trace: TraceBuffer::new(TRACE_BUFFER_SIZE),
+			instruction_stream: None,
+			events: EventLog::new(),
+			io_log: None,
+			irq_line: IrqLine::new(),
+			stats: StatsCollector::new(),
+			overclock_scanlines: 0,
+			overclock_left: 0,
+			scheduler: Scheduler::Fast,
+			devices_cycles: 0,
+			freezes: FreezeList::new(),
+			halted: None,
+			page_crossed: false,
+			unimplemented: UnimplementedLog::new(),
+			suspicious: SuspiciousLog::new(),
+			#[cfg(feature = "threaded")]
+			block_cache: BlockCache::new(),
+			#[cfg(feature = "threaded")]
+			prefetched_operand: None,
 		};
 		cpu.res_interrupt();
 		cpu
 	}
 
+	/// A copy of the machine for `NES::fork`: the state, the settings and the debugging history. The ROM is shared until
+	/// a copy writes to it. The input provider and the instruction stream are not copied.
+	pub fn fork(&self) -> CPU {
+		CPU {
+			registers: self.registers,
+			cycles: self.cycles,
+			cartridge: self.cartridge.fork(),
+			ppu: self.ppu.clone(),
+			apu: self.apu.clone(),
+			controllers: self.controllers.clone(),
+			input: None,
+			input_frame: self.input_frame,
+			ram: self.ram,
+			last_write: self.last_write,
+			data_bus: self.data_bus,
+			trace: self.trace.clone(),
+			instruction_stream: None,
+			events: self.events.clone(),
+			io_log: self.io_log.clone(),
+			irq_line: self.irq_line.clone(),
+			stats: self.stats.clone(),
+			overclock_scanlines: self.overclock_scanlines,
+			overclock_left: self.overclock_left,
+			scheduler: self.scheduler,
+			devices_cycles: self.devices_cycles,
+			freezes: self.freezes.clone(),
+			halted: self.halted,
+			page_crossed: self.page_crossed,
+			unimplemented: self.unimplemented.clone(),
+			suspicious: self.suspicious.clone(),
+			#[cfg(feature = "threaded")]
+			block_cache: self.block_cache.fork(),
+			#[cfg(feature = "threaded")]
+			prefetched_operand: None,
+		}
+	}
+
 	/// A single clock cycle is executed here.
 	/// Original NES CPU needs multiple cycles to execute instruction.
 	/// Emulation does not do that; Its much simpler to do everything at once, and emulate the cycles.
 	pub fn clock_tick(&mut self) {
+		span!(TRACE, "instruction", pc = self.registers.PC);
 		debug!("Tick, cycle: {}", self.cycles);
 		debug!("{}", self.registers);
 
+		self.last_write = None;
+		self.page_crossed = false;
+		let frame_before = self.ppu.frame();
+		if self.halted.is_some() {
+			self.halted_tick(frame_before);
+			return;
+		}
+		let start_time = self.stats.subsystem_times().then(Instant::now);
+
 		// Read next instruction.
-		let opcode = self.read_memory(self.registers.PC); // Read at address of Program Counter (duh!)
-		let instruction = decode_opcode(opcode);
+		let opcode = self.fetch_opcode();
+		// Record before decoding, so an illegal opcode is the last entry in the trace
+		let before = TraceEntry {
+			pc: self.registers.PC,
+			opcode,
+			a: self.registers.A,
+			x: self.registers.X,
+			y: self.registers.Y,
+			s: self.registers.S,
+			p: self.registers.P.flags,
+			cycles: self.cycles,
+		};
+		self.trace.push(before);
+		self.stats.add_opcode(opcode);
+		let instruction = decode_opcode(opcode).unwrap_or_else(|| {
+			self.unimplemented.report(UnimplementedFeature::Opcode(opcode));
+			(Instructions::NOP, AddressingMode::IMPLIED, 1, 2, OopsCycle::NONE)
+		});
+		if self.instruction_stream.is_some() {
+			self.stream_instruction(before, &instruction.0, &instruction.1, instruction.2);
+		}
 
 		let instr = instruction.0;
 		let addrmode = instruction.1;
@@ -69,7 +274,15 @@ impl CPU {
 
 		debug!("{:#X}: {:?}\t{:?}\tBytes: {}, Cycles: {}, Oops cycle: {}", opcode, instr, addrmode, bytes, cycles, oops_cycle);
 
-		self.execute_instruction(&instr, addrmode);
+		if instr == Instructions::KIL {
+			self.halted = Some(CpuHalted { pc: before.pc, opcode });
+			warn!("{}", self.halted.unwrap());
+		}
+		HANDLERS[opcode as usize](self, addrmode);
+		#[cfg(feature = "threaded")]
+		{
+			self.prefetched_operand = None;
+		}
 
 		// Increment PC by amount of bytes needed for the instruction, other than opcode (which is 1 byte).
 		// We do this at the end of the execution, because we need to access the PC (for the current instruction) before we increment it.
@@ -78,7 +291,10 @@ impl CPU {
 		match instr {
 			Instructions::JMP => (),
 			Instructions::JSR => (),
-			_ => {self.registers.PC += bytes as u16;}
+			Instructions::RTI => (),
+			Instructions::BRK => (),
+			Instructions::KIL => (),	// Stays on the jam, for the debugger
+			_ => {self.registers.PC = self.registers.PC.wrapping_add(bytes as u16);}
 		}
 
 		self.cycles += cycles as u64;
@@ -87,10 +303,11 @@ impl CPU {
 			OopsCycle::NONE => { 
 				// don't change amount of cycles.
 			},
-			OopsCycle::PageBoundryCrossed => { 
-				//TODO: Impliment. For now, I don't change amount of cycles.
-
-				//add 1 to cycles if page boundary is crossed
+			OopsCycle::PageBoundryCrossed => {
+				// add 1 to cycles if page boundary is crossed
+				if self.page_crossed {
+					self.cycles += 1;
+				}
 			},
 			OopsCycle::BranchOccursOn => {
 				//TODO: Impliment. For now, I don't change amount of cycles.
@@ -99,505 +316,315 @@ impl CPU {
 				//add 2 to cycles if branch occurs to different page
 			}
 		}
+
+		// Catch up the PPU and APU with the CPU, and check if the PPU raised NMI (vblank started)
+		let cpu_time = start_time.map_or(Duration::ZERO, |start| start.elapsed());
+		let (mut ppu_time, mut apu_time) = self.sync_devices();
+		if self.ppu.take_nmi() {
+			self.nmi_interrupt();
+			let (ppu, apu) = self.sync_devices();
+			ppu_time += ppu;
+			apu_time += apu;
+		}
+		// The devices hold the IRQ line until the program acknowledges them
+		self.update_irq_line();
+		if self.irq_line.is_asserted() {
+			self.irq_interrupt();
+			let (ppu, apu) = self.sync_devices();
+			ppu_time += ppu;
+			apu_time += apu;
+		}
+
+		self.stats.add_instruction(cpu_time, ppu_time, apu_time);
+		if self.ppu.frame() != frame_before {
+			self.stats.end_frame(self.cycles);
+		}
 	}
 
-	/// The main brains of the CPU. Execute instruction.
-	fn execute_instruction(&mut self, instr: &Instructions, addrmode: AddressingMode) {
-		//The main brains of the CPU. Execute instruction.
-		match instr {
-			Instructions::LDX | 
-			Instructions::LDY | 
-			Instructions::LDA => {
-				/*
-				LDX:
-				Load Index X with Memory
-				M -> X
-
-				LDY:
-				Load Index Y with Memory
-				M -> Y
-
-				LDA:
-				Load Accumulator with Memory
-				M -> A
-				*/
-				let fetched_memory = self.fetch_memory(&addrmode);
-				if *instr == Instructions::LDX {
-					self.registers.X = fetched_memory;
-				} else if *instr == Instructions::LDY {
-					self.registers.Y = fetched_memory;
-				} else {
-					// LDA
-					self.registers.A = fetched_memory;
-				}
+	/// The bus is frozen, only the PPU, APU and cartridge run. Their interrupts are lost.
+	fn halted_tick(&mut self, frame_before: u64) {
+		self.cycles += 1;
+		let (ppu_time, apu_time) = self.sync_devices();
+		self.ppu.take_nmi();
+		self.stats.add_instruction(Duration::ZERO, ppu_time, apu_time);
+		if self.ppu.frame() != frame_before {
+			self.stats.end_frame(self.cycles);
+		}
+	}
 
-				self.registers.P.modify_n(fetched_memory);
-				self.registers.P.modify_z(fetched_memory);
-			}
-			Instructions::PHA => {
-				// Push Accumulator on Stack
-				// push A
-				self.push_stack(self.registers.A);
-			}
-			Instructions::NOP => {
-				// No Operation
-			}
-			Instructions::PLA => {
-				// Pull Accumulator from Stack
-				// pull A
-				let fetched_memory = self.pop_stack();
-				self.registers.A = fetched_memory;
-
-				self.registers.P.modify_n(fetched_memory);
-				self.registers.P.modify_z(fetched_memory);
-			}
-			Instructions::SEC => {
-				// Set Carry Flag
-				self.registers.P.set(ProcessorStatusBits::CARRY, true);
-			}
-			Instructions::CLC => {
-				// Clear Carry Flag
-				self.registers.P.set(ProcessorStatusBits::CARRY, false);
-			}
-			Instructions::SED => {
-				// Set Decimal Flag
-				self.registers.P.set(ProcessorStatusBits::DECIMAL, true);
-			}
-			Instructions::CLD => {
-				// Clear Decimal Mode
-				self.registers.P.set(ProcessorStatusBits::DECIMAL, false);
-			}
-			Instructions::SEI => {
-				// Set Interrupt Disable Status
-				self.registers.P.set(ProcessorStatusBits::InterruptDisable, true);
-			}
-			Instructions::CLI => {
-				// Clear Interrupt Disable Bit
-				self.registers.P.set(ProcessorStatusBits::InterruptDisable, false);
-			}
-			Instructions::CLV => {
-				// Clear Overflow Flag
-				self.registers.P.set(ProcessorStatusBits::OVERFLOW, false);
-			}
-			Instructions::ADC => {
-				// Add Memory to Accumulator with Carry
-				// A + M + C -> A, C
-				// NOTE: This is the first instruction that actually does 'complex' arithmetic
-				// After reading a lot of forums, its actually the most complex thing to emulate, I must understand this
+	/// Report an unimplemented feature of the current instruction.
+	pub(super) fn report_unimplemented(&mut self, feature: fn(u8) -> UnimplementedFeature) {
+		let opcode = self.trace.iter().last().map_or(0, |entry| entry.opcode);
+		self.unimplemented.report(feature(opcode));
+	}
 
-				let fetched_memory = self.fetch_memory(&addrmode);
+	pub fn unimplemented(&self) -> &UnimplementedLog {
+		&self.unimplemented
+	}
 
-				let a = self.registers.A;
-				let m = fetched_memory;
-				let carry: u8 = self.registers.P.get(ProcessorStatusBits::CARRY) as u8;
+	pub fn unimplemented_mut(&mut self) -> &mut UnimplementedLog {
+		&mut self.unimplemented
+	}
 
-				// Carry flag: Only for unsigned. If result is > 255, carry is set.
-				// Overflow flag: Only if (Positive+Positive=Negative) or (Negative+Negative=Positive)
+	/// Report a suspicious access of the current instruction (strict mode).
+	fn report_suspicious(&mut self, suspicious: Suspicious) {
+		let pc = self.trace.iter().last().map_or(self.registers.PC, |entry| entry.pc);
+		self.suspicious.report(pc, suspicious);
+	}
 
-				// Perform regular unsigned addition, allowing arithmetic overflow.
-				let first_addition = a.overflowing_add(m);
-				let second_addition = first_addition.0.overflowing_add(carry);
-				let mut result = second_addition.0;
+	pub fn suspicious(&self) -> &SuspiciousLog {
+		&self.suspicious
+	}
 
-				// Set A register.
+	pub fn suspicious_mut(&mut self) -> &mut SuspiciousLog {
+		&mut self.suspicious
+	}
 
-				// Check decimal mode, check if CPU is in binary/decimal coded mode
-				// TODO: I read that NES doesn't use this mode. Maybe remove it so I don't have any problems?
-				if self.registers.P.get(ProcessorStatusBits::DECIMAL) {
-					result = self.decimal_mode(result);
-				}
-				self.registers.A = result;
-
-				// Set carry accordingly.
-				let new_carry = first_addition.1 || second_addition.1;
-
-				// Set overflow accordingly.
-				let is_a_negative = (a >> 7) == 1;
-				let is_m_negative = (m >> 7) == 1;
-				let is_result_negative = (result >> 7) == 1;
-				let new_overflow = 
-					(is_a_negative 				&& is_m_negative 			&& is_result_negative == false 	) ||
-					(is_a_negative == false 	&& is_m_negative == false 	&& is_result_negative 			);
-				
-				self.registers.P.modify_n(self.registers.A);
-				self.registers.P.modify_z(self.registers.A);
-				self.registers.P.set(ProcessorStatusBits::CARRY, new_carry);
-				self.registers.P.set(ProcessorStatusBits::OVERFLOW, new_overflow);
+	/// Why the CPU stopped, None while it runs.
+	pub fn halted(&self) -> Option<CpuHalted> {
+		self.halted
+	}
+
+	fn update_irq_line(&mut self) {
+		self.irq_line.set(IrqSource::Mapper, self.cartridge.irq());
+		self.irq_line.set(IrqSource::FrameCounter, self.apu.frame_irq());
+		self.irq_line.set(IrqSource::Dmc, self.apu.dmc_irq());
+	}
+
+	/// Run the devices up to the CPU cycle counter. Returns the host time of the PPU and the APU.
+	fn sync_devices(&mut self) -> (Duration, Duration) {
+		// The accurate scheduler can be ahead, when the instruction made more memory accesses than its cycle count
+		let cycles = self.cycles.saturating_sub(self.devices_cycles);
+		self.devices_cycles += cycles;
+		self.catch_up(cycles)
+	}
+
+	/// Run the PPU, APU and cartridge for the CPU cycles. Returns the host time of the PPU and the APU, when measured.
+	/// The overclocking cycles are not passed on: the devices are paused, so the audio, the mapper timers and the frame
+	/// rate stay the same.
+	fn catch_up(&mut self, cpu_cycles: u64) -> (Duration, Duration) {
+		let cpu_cycles = self.skip_overclock_cycles(cpu_cycles);
+		if !self.stats.subsystem_times() {
+			self.tick_ppu(cpu_cycles);
+			self.tick_apu(cpu_cycles);
+			return (Duration::ZERO, Duration::ZERO);
+		}
+		let start_time = Instant::now();
+		self.tick_ppu(cpu_cycles);
+		let ppu_done_time = Instant::now();
+		self.tick_apu(cpu_cycles);
+		(ppu_done_time - start_time, ppu_done_time.elapsed())
+	}
+
+	/// Use the CPU cycles for the overclocking cycles left in this vblank. Returns the cycles that the devices run.
+	fn skip_overclock_cycles(&mut self, cpu_cycles: u64) -> u64 {
+		let extra_cycles = cpu_cycles.min(self.overclock_left);
+		self.overclock_left -= extra_cycles;
+		cpu_cycles - extra_cycles
+	}
+
+	/// The PPU runs 3 dots for each CPU cycle.
+	fn tick_ppu(&mut self, cpu_cycles: u64) {
+		let frame = self.ppu.frame();
+		let in_vblank = self.ppu.in_vblank();
+		for _ in 0..cpu_cycles * 3 {
+			self.ppu.tick(&mut self.cartridge);
+		}
+		if !in_vblank && self.ppu.in_vblank() {
+			self.overclock_left = self.overclock_scanlines as u64 * DOTS_PER_SCANLINE as u64 / 3;
+		}
+		if self.ppu.frame() != frame {
+			self.events.end_frame();
+			if let Some(vs) = self.cartridge.vs_system_mut() {
+				vs.end_frame();
 			}
-			Instructions::STX | 
-			Instructions::STY | 
-			Instructions::STA => {
-				/*
-				STX:
-				Store Index X in Memory
-				X -> M
-
-				STY:
-				Store Index Y in Memory
-				Y -> M
-
-				STA:
-				Store Accumulator in Memory
-				A -> M
-				*/
-				let addr = self.fetch_instruction_address(addrmode);
-				if *instr == Instructions::STX {
-					self.write_memory(addr, self.registers.X);
-				} else if *instr == Instructions::STY {
-					self.write_memory(addr, self.registers.Y);
-				} else {
-					//STA
-					self.write_memory(addr, self.registers.A);
-				}
+		}
+	}
+
+	/// The APU and the cartridge run at the CPU clock. The cartridge expansion audio is mixed with the APU at the volumes
+	/// of the APU. The DMC sample
+	/// bytes are read through the mapper, so they come from the banks mapped at the time (the CPU isn't stalled).
+	fn tick_apu(&mut self, cpu_cycles: u64) {
+		for _ in 0..cpu_cycles {
+			self.cartridge.cpu_tick();
+			self.apu.clock(self.cartridge.audio_output(self.apu.expansion_volumes()));
+			if let Some(addr) = self.apu.dmc_fetch_address() {
+				let byte = self.cartridge.cpu_read(addr, false).unwrap_or(self.data_bus);
+				self.apu.dmc_fill(byte);
 			}
-			Instructions::INX => {
-				// Increment Index X by One
-				// X + 1 -> X
-				self.registers.X = self.registers.X.wrapping_add(1);
-				self.registers.P.modify_n(self.registers.X);
-				self.registers.P.modify_z(self.registers.X);
+		}
+	}
+
+	/// Give the CPU extra time at the start of each vblank, as if the frame had more vblank scanlines (0 disables it).
+	/// Games that slow down because the frame logic doesn't finish in time (lag frames) run smoother. The PPU, APU and
+	/// cartridge don't see the extra cycles.
+	pub fn set_overclock(&mut self, scanlines: u16) {
+		self.overclock_scanlines = scanlines;
+	}
+
+	pub fn overclock(&self) -> u16 {
+		self.overclock_scanlines
+	}
+
+	/// Save or load a part of the machine state: the CPU or a device on its bus. The debugging aids (trace, events,
+	/// stats, freezes) and the settings (scheduler, overclock) are not saved.
+	pub fn serialize_component(&mut self, component: Component, s: &mut Serializer) {
+		match component {
+			Component::Cpu => {
+				s.value(&mut self.registers);
+				s.value(&mut self.cycles);
+				s.value(&mut self.ram);
+				self.serialize_cpu_latches(s);
+				s.value(&mut self.halted);
 			}
-			Instructions::INY => {
-				// Increment Index Y by One
-				// Y + 1 -> Y
-				self.registers.Y = self.registers.Y.wrapping_add(1);
-				self.registers.P.modify_n(self.registers.Y);
-				self.registers.P.modify_z(self.registers.Y);
+			Component::Cartridge => {
+				s.value(&mut self.cartridge);
+				#[cfg(feature = "threaded")]
+				self.block_cache.end_block();
 			}
-			Instructions::INC | 
-			Instructions::DEC => {
-				/*
-				INC:
-				Increment Memory by One
-				M + 1 -> M
-				*/
-				/*
-				DEC:
-				Decrement Memory by One
-				M - 1 -> M
-				*/
-				let fetched_memory = self.fetch_memory(&addrmode);
-				let new_memory = if *instr == Instructions::INC {
-					fetched_memory.wrapping_add(1)
-				} else {
-					// DEC
-					fetched_memory.wrapping_sub(1)
-				};
+			Component::Ppu => s.value(&mut self.ppu),
+			Component::Apu => s.value(&mut self.apu),
+			Component::Controllers => s.value(&mut self.controllers),
+		}
+	}
 
-				let addr = self.fetch_instruction_address(addrmode);
-				self.write_memory(addr, new_memory);
+	/// The states without a header have the CPU component of version 1, the CPU couldn't halt then.
+	pub fn load_version_0_cpu(&mut self, s: &mut Serializer) {
+		self.serialize_cpu_version_1(s);
+		self.halted = None;
+	}
 
-				self.registers.P.modify_n(new_memory);
-				self.registers.P.modify_z(new_memory);
-			}
-			Instructions::JMP => {
-				// Jump to New Location
-				// (PC+1) -> PCL
-				// (PC+2) -> PCH
+	/// The CPU component of version 1. The RAM was the first 2KB of a 32KB block.
+	pub fn serialize_cpu_version_1(&mut self, s: &mut Serializer) {
+		s.value(&mut self.registers);
+		s.value(&mut self.cycles);
+		let mut memory = [0; 1024 * 32];
+		memory[..RAM_SIZE].copy_from_slice(&self.ram);
+		s.value(&mut memory);
+		self.ram.copy_from_slice(&memory[..RAM_SIZE]);
+		self.serialize_cpu_latches(s);
+	}
 
-				let addr = self.fetch_instruction_address(addrmode);
-				self.registers.PC = addr;
-			}
-			Instructions::JSR => {
-				// Jump to New Location Saving Return Address
+	fn serialize_cpu_latches(&mut self, s: &mut Serializer) {
+		s.value(&mut self.data_bus);
+		s.value(&mut self.irq_line);
+		s.value(&mut self.overclock_left);
+		s.value(&mut self.devices_cycles);
+	}
 
-				// push (PC+2),
-				// (PC+1) -> PCL
-				// (PC+2) -> PCH
+	pub fn freezes(&self) -> &FreezeList {
+		&self.freezes
+	}
 
-				// What order of bytes to push?
-				// After a lot of googling: https://stackoverflow.com/a/63886154
-				// Basically push the PC like so: "...You need to push the high byte first, and then the low byte."
+	pub fn freezes_mut(&mut self) -> &mut FreezeList {
+		&mut self.freezes
+	}
 
-				// Push PC onto stack (return address)
-				// NOTE: I push the 3rd byte of the instruction (PC + 2). Why not PC+3 (next instruction)?
-				// Idk, but its important to emulate this exactly, because some games use this feature.
-				self.push_pc(2);
+	/// Change the scheduler, also in the middle of a game.
+	pub fn set_scheduler(&mut self, scheduler: Scheduler) {
+		self.scheduler = scheduler;
+	}
 
-				// Jump to the address operand
-				let addr = self.fetch_instruction_address(addrmode);
-				self.registers.PC = addr;
-			}
-			Instructions::CMP => {
-				// Compare Memory with Accumulator
-				// A - M
+	pub fn scheduler(&self) -> Scheduler {
+		self.scheduler
+	}
 
-				self.exec_cmp(addrmode, self.registers.A);
-			}
-			Instructions::CPX => {
-				// Compare Memory and Index X
-				// X - M
+	pub fn registers(&self) -> &Registers {
+		&self.registers
+	}
 
-				self.exec_cmp(addrmode, self.registers.X);
-			}
-			Instructions::CPY => {
-				// Compare Memory and Index Y
-				// Y - M
+	pub fn ppu(&self) -> &PPU {
+		&self.ppu
+	}
 
-				self.exec_cmp(addrmode, self.registers.Y);
-			}
-			Instructions::TAX => {
-				// Transfer Accumulator to Index X
-				// A -> X
+	pub fn ppu_mut(&mut self) -> &mut PPU {
+		&mut self.ppu
+	}
 
-				self.registers.X = self.registers.A;
-				self.registers.P.modify_n(self.registers.X);
-				self.registers.P.modify_z(self.registers.X);
-			}
-			Instructions::TAY => {
-				// Transfer Accumulator to Index Y
-				// A -> Y
+	/// The controller of player 0 or 1.
+	pub fn controller(&self, player: usize) -> &Controller {
+		&self.controllers[player]
+	}
 
-				self.registers.Y = self.registers.A;
-				self.registers.P.modify_n(self.registers.Y);
-				self.registers.P.modify_z(self.registers.Y);
-			}
-			Instructions::TSX => {
-				// Transfer Stack Pointer to Index X
-				// SP -> X
+	pub fn controller_mut(&mut self, player: usize) -> &mut Controller {
+		&mut self.controllers[player]
+	}
 
-				self.registers.X = self.registers.S;
-				self.registers.P.modify_n(self.registers.X);
-				self.registers.P.modify_z(self.registers.X);
-			}
-			Instructions::TXA => {
-				// Transfer Index X to Accumulator
-				// X -> A
+	/// Take the buttons from `input` instead of `controller_mut`, None to stop.
+	pub fn set_input_provider(&mut self, input: Option<Box<dyn InputProvider + Send>>) {
+		self.input = input;
+		self.input_frame = None;
+	}
 
-				self.registers.A = self.registers.X;
-				self.registers.P.modify_n(self.registers.A);
-				self.registers.P.modify_z(self.registers.A);
-			}
-			Instructions::TXS => {
-				// Transfer Index X to Stack Register
-				// X -> SP
+	pub fn irq_line(&self) -> &IrqLine {
+		&self.irq_line
+	}
 
-				self.registers.S = self.registers.X;
-				// We don't modify N or Z.
-			}
-			Instructions::TYA => {
-				// Transfer Index Y to Accumulator
-				// Y -> A
+	pub fn apu(&self) -> &APU {
+		&self.apu
+	}
 
-				self.registers.A = self.registers.Y;
-				self.registers.P.modify_n(self.registers.A);
-				self.registers.P.modify_z(self.registers.A);
-			}
-			Instructions::AND => {
-				// AND Memory with Accumulator
-				// A AND M -> A
-
-				let fetched_memory = self.fetch_memory(&addrmode);
-				self.registers.A = self.registers.A & fetched_memory;
-				self.registers.P.modify_n(self.registers.A);
-				self.registers.P.modify_z(self.registers.A);
-			}
-			Instructions::ASL | Instructions::LSR => {
-				/*
-				ASL:
-				Shift Left One Bit (Memory or Accumulator)
-				C <- [76543210] <- 0
-				*/
-				/*
-				LSR:
-				Shift One Bit Right (Memory or Accumulator)
-				0 -> [76543210] -> C
-				*/
-
-				// Memory can be register.
-				let fetched_memory = self.fetch_memory(&addrmode);
-
-				let result = if *instr == Instructions::ASL {
-					fetched_memory << 1
-				} else {
-					// LSR
-					fetched_memory >> 1
-				};
+	pub fn apu_mut(&mut self) -> &mut APU {
+		&mut self.apu
+	}
 
-				// Determine if shift overflowed (if yes, then set carry)
-				// If last bit is 1, and we left shift, then that bit is the carry.
-				let new_carry = (fetched_memory >> 7) == 1;
+	pub fn cartridge(&self) -> &Cartridge {
+		&self.cartridge
+	}
 
-				// Now we need to know where to put the result. Register or memory?
-				if addrmode == AddressingMode::ACCUMULATOR {
-					self.registers.A = result;
-				} else {
-					// Get memory location.
-					let addr = self.fetch_instruction_address(addrmode);
-					self.write_memory(addr, result);
-				}
+	pub fn cartridge_mut(&mut self) -> &mut Cartridge {
+		&mut self.cartridge
+	}
 
-				self.registers.P.modify_n(result);
-				self.registers.P.modify_z(result);
-				self.registers.P.set(ProcessorStatusBits::CARRY, new_carry);
-			}
-			Instructions::BIT => {
-				// Test Bits in Memory with Accumulator
-
-				//bits 7 and 6 of operand are transfered to bit 7 and 6 of SR (N,V);
-				//the zero-flag is set to the result of operand AND accumulator.
-				//A AND M, M7 -> N, M6 -> V
-
-				let fetched_memory = self.fetch_memory(&addrmode);
-				let result = self.registers.A & fetched_memory;
-				let bit7 = (fetched_memory >> 7) == 1;
-				let bit6 = ((fetched_memory >> 6) & 1) == 1;
-				
-				self.registers.P.set(ProcessorStatusBits::NEGATIVE, bit7);
-				self.registers.P.set(ProcessorStatusBits::OVERFLOW, bit6);
-				self.registers.P.modify_z(result);
-			}
-			Instructions::BMI | 
-			Instructions::BPL | 
-			Instructions::BNE | 
-			Instructions::BVC | 
-			Instructions::BVS |
-			Instructions::BEQ |
-			Instructions::BCS |
-			Instructions::BCC
-			 => {
-				/*
-				BMI:
-				Branch on Result Minus
-				branch on N = 1
-
-				BPL:
-				Branch on Result Plus
-				branch on N = 0
-
-				BNE:
-				Branch on Result not Zero
-				branch on Z = 0
-
-				BVC:
-				Branch on Overflow Clear
-				branch on V = 0
-
-				BVS:
-				Branch on Overflow Set
-				branch on V = 1
-
-				BEQ:
- 				Branch on Result Zero
-				branch on Z = 1
-
-				BCS:
-				Branch on Carry Set
-				branch on C = 1
-
-				BCC:
-				Branch on Carry Clear
-				branch on C = 0
-				*/
-
-				if 
-					(*instr == Instructions::BMI && self.registers.P.get(ProcessorStatusBits::NEGATIVE		) == true	) || 
-					(*instr == Instructions::BPL && self.registers.P.get(ProcessorStatusBits::NEGATIVE		) == false	) ||
-					(*instr == Instructions::BNE && self.registers.P.get(ProcessorStatusBits::ZERO			) == false	) ||
-					(*instr == Instructions::BVC && self.registers.P.get(ProcessorStatusBits::OVERFLOW		) == false	) ||
-					(*instr == Instructions::BVS && self.registers.P.get(ProcessorStatusBits::OVERFLOW		) == true	) ||
-					(*instr == Instructions::BEQ && self.registers.P.get(ProcessorStatusBits::ZERO			) == true	) ||
-					(*instr == Instructions::BCS && self.registers.P.get(ProcessorStatusBits::CARRY		) == true	) ||
-					(*instr == Instructions::BCC && self.registers.P.get(ProcessorStatusBits::CARRY		) == false	)
-				{
-					let new_pc = self.read_instruction_relative_address();
-					self.registers.PC = new_pc;
-				}
-			}
-			Instructions::BRK => {
-				// Force Break
-				/*
-				BRK initiates a software interrupt similar to a hardware
-				interrupt (IRQ). The return address pushed to the stack is
-				PC+2, providing an extra byte of spacing for a break mark
-				(identifying a reason for the break.)
-				The status register will be pushed to the stack with the break
-				flag set to 1. However, when retrieved during RTI or by a PLP
-				instruction, the break flag will be ignored.
-				The interrupt disable flag is not set automatically.
-				*/
-				// interrupt,
-				// push PC+2, push SR
-
-				todo!();
-				//self.push_pc(offset);
-			}
-			Instructions::DEX => {
-				// Decrement Index X by One
-				// X - 1 -> X
+	/// Amount of CPU cycles since power on.
+	pub fn cycles(&self) -> u64 {
+		self.cycles
+	}
 
-				self.registers.X = self.registers.X.wrapping_sub(1);
-				self.registers.P.modify_n(self.registers.X);
-				self.registers.P.modify_z(self.registers.X);
-			}
-			Instructions::DEY => {
-				// Decrement Index Y by One
-				// Y - 1 -> Y
+	/// The last executed instructions, the newest is the current (or last) instruction.
+	pub fn trace(&self) -> &TraceBuffer {
+		&self.trace
+	}
 
-				self.registers.Y = self.registers.Y.wrapping_sub(1);
-				self.registers.P.modify_n(self.registers.Y);
-				self.registers.P.modify_z(self.registers.Y);
-			}
-			Instructions::EOR | 
-			Instructions::ORA => {
-				/*
-				EOR:
-				Exclusive-OR Memory with Accumulator
-				A EOR M -> A
-
-				ORA:
-				OR Memory with Accumulator
-				A OR M -> A
-				*/
-
-				let fetched_memory = self.fetch_memory(&addrmode);
-				let new_a = if *instr == Instructions::EOR {
-					self.registers.A ^ fetched_memory
-				} else {
-					// ORA
-					self.registers.A | fetched_memory
-				};
-				self.registers.A = new_a;
-				self.registers.P.modify_n(self.registers.A);
-				self.registers.P.modify_z(self.registers.A);
-			}
-			Instructions::PHP => {
-				// Push Processor Status on Stack
-				// The status register will be pushed with the break flag and bit 5 set to 1.
-				// push SR
+	/// Keep the last `TRACE_BUFFER_SIZE` executed instructions (the default), for the crash dumps and the debugger. Off
+	/// saves a bit of time per instruction.
+	pub fn set_trace_enabled(&mut self, enabled: bool) {
+		self.trace = TraceBuffer::new(if enabled { TRACE_BUFFER_SIZE } else { 0 });
+	}
 
-				self.push_p();
-			}
-			Instructions::PLP => {
-				// Pull Processor Status from Stack
-				// The status register will be pulled with the break flag and bit 5 ignored.
-				// pull SR
+	/// Send each executed instruction to `sender`, until the stream is dropped. Replaces the previous stream.
+	pub fn set_instruction_stream(&mut self, sender: InstructionSender) {
+		self.instruction_stream = Some(sender);
+	}
 
-				let p_flags = self.pop_stack();
-				self.registers.P.flags = p_flags;
-			}
-			Instructions::RTI => {
-				// Return from Interrupt
-				// The status register is pulled with the break flag and bit 5 ignored. Then PC is pulled from the stack.
-				// pull SR, pull PC
-
-				let p = self.pop_stack();
-				self.registers.P = ProcessorStatus {flags: p };
-				let b = self.registers.P.get(ProcessorStatusBits::BREAK);
-				self.registers.P.set(ProcessorStatusBits::BREAK, !b);
-				
-				self.registers.PC =  self.pop_pc();
-			}
-			Instructions::ROL => {
-				// Rotate One Bit Left (Memory or Accumulator)
-				// C <- [76543210] <- C
-			}
-			_ => {
-				panic!("Could not execute instruction: {:?}, not implimented, yet", instr);
-			}
+	fn stream_instruction(&mut self, before: TraceEntry, instr: &Instructions, addrmode: &AddressingMode, bytes: u8) {
+		let operand: Vec<u8> = (1..bytes as u16).map(|i| self.peek_memory(before.pc.wrapping_add(i))).collect();
+		let disassembly = disassemble(instr, addrmode, before.pc, &operand);
+		let sent = self.instruction_stream.as_ref().is_some_and(|sender| sender.send(ExecutedInstruction { before, disassembly }));
+		if !sent {
+			// Nobody listens anymore
+			self.instruction_stream = None;
 		}
 	}
 
+	pub fn stats(&self) -> &StatsCollector {
+		&self.stats
+	}
+
+	pub fn stats_mut(&mut self) -> &mut StatsCollector {
+		&mut self.stats
+	}
+
+	/// PPU/IO register accesses of the current and last frame.
+	pub fn events(&self) -> &EventLog {
+		&self.events
+	}
+
+	/// The last memory write (address, value) of the previous instruction, if it wrote to memory.
+	pub fn last_write(&self) -> Option<(u16, u8)> {
+		self.last_write
+	}
+
 	/// Reset interrupt. Address: $0xFFFC, $0xFFFD
 	fn res_interrupt(&mut self) {
 		debug!("Reset interrupt called");
@@ -607,12 +634,33 @@ impl CPU {
 		self.registers.Y = 0;
 		self.registers.S = 0xFF;
 		self.registers.P = ProcessorStatus::default();
+		// Reset disables interrupts, otherwise the APU frame IRQ would interrupt the program before it sets up the vector
+		self.registers.P.set(ProcessorStatusBits::InterruptDisable, true);
 		
 		let new_addr = self.read_address_from_memory(0xFFFC);
 		debug!("Jumping to interrupt address: {:#X}", new_addr);
 		self.registers.PC = new_addr;
 
 		self.cycles = 8;
+		self.devices_cycles = self.cycles;
+	}
+
+	/// The reset button. Unlike power on, A, X, Y and the RAM keep their values. The CPU does 3 stack reads instead of the
+	/// pushes of an interrupt, so the stack pointer is decremented by 3.
+	pub fn reset(&mut self) {
+		debug!("Reset button");
+		self.halted = None;
+		self.registers.S = self.registers.S.wrapping_sub(3);
+		self.registers.P.set(ProcessorStatusBits::InterruptDisable, true);
+		self.ppu.reset();
+		self.apu.reset();
+		self.cartridge.reset();
+		#[cfg(feature = "threaded")]
+		self.block_cache.end_block();
+
+		self.registers.PC = self.read_address_from_memory(0xFFFC);
+		self.cycles += 7;
+		self.sync_devices();
 	}
 
 	/// Non-maskable interrupt. Address: $0xFFFA, $0xFFFB
@@ -622,14 +670,15 @@ impl CPU {
 		self.push_pc(0);
 
 		self.registers.P.set(ProcessorStatusBits::BREAK, false);
-		self.registers.P.set(ProcessorStatusBits::InterruptDisable, true);
+		// The status is pushed before disabling interrupts, so RTI enables them again
 		self.push_p();
+		self.registers.P.set(ProcessorStatusBits::InterruptDisable, true);
 
 		let new_addr = self.read_address_from_memory(0xFFFA);
 		debug!("Jumping to interrupt address: {:#X}", new_addr);
 		self.registers.PC = new_addr;
 
-		self.cycles = 8;
+		self.cycles += 7;
 	}
 
 	/// Maskable interrupt. Address: $0xFFFE, $0xFFFF
@@ -642,151 +691,150 @@ impl CPU {
 
 			//TODO: Not sure if we set break flag to 0. Research
 			self.registers.P.set(ProcessorStatusBits::BREAK, false);
-			self.registers.P.set(ProcessorStatusBits::InterruptDisable, true);
 			self.push_p();
+			self.registers.P.set(ProcessorStatusBits::InterruptDisable, true);
 
 			let new_addr = self.read_address_from_memory(0xFFFE);
 			debug!("Jumping to interrupt address: {:#X}", new_addr);
 			self.registers.PC = new_addr;
 
-			self.cycles = 7;
+			self.cycles += 7;
 		}
 	}
 
-	fn push_stack(&mut self, data: u8) {
+	pub(super) fn push_stack(&mut self, data: u8) {
+		if self.registers.S == 0x00 {
+			self.report_suspicious(Suspicious::StackOverflow);
+		}
 		self.write_memory(0x100 + self.registers.S as u16, data);
-		self.registers.S -= 1;
+		self.registers.S = self.registers.S.wrapping_sub(1);
 		debug!("Pushed to stack: \t{:#X}", data);
 	}
 
-	fn pop_stack(&mut self) -> u8 {
+	pub(super) fn pop_stack(&mut self) -> u8 {
 		if self.registers.S == 0xFF {
-			warn!("Stack pop: stack pointer is at beginning, overflowing stack pointer");
+			self.report_suspicious(Suspicious::StackUnderflow);
 		}
-		let head_addr: u16 = 0x100 + (self.registers.S as u16) + 1;  // we add 1 before the current SP points to get the head (the stack is down going)
-		let res = self.read_memory(head_addr);
-		self.registers.S = self.registers.S.wrapping_add(1);  // NOTE: We allow the programmer to overflow SP.
+		self.registers.S = self.registers.S.wrapping_add(1);  // S points below the head (the stack is down going). NOTE: We allow the programmer to overflow SP.
+		let res = self.read_memory(0x100 + self.registers.S as u16);  // The stack wraps within page 1
 		debug!("Poped stack: \t{:#X}", res);
 		res
 	}
 
-	/// Convert data from hex (example: 0x0B) to another hex (0x11), but is represented in 'decimal hex' form.
-	fn decimal_mode(&self, data: u8) -> u8 {
-		let hex_str = data.to_string();
-		let decoded = <[u8; 1]>::from_hex(hex_str).expect("Could not convert decimal");
-		decoded[0]
-	}
-
-	fn fetch_absolute_indexed(&mut self, index: u8) -> u8 {
-		let addr = self.read_instruction_absolute_indexed_address(index);
-		self.read_memory(addr)
-	}
-
-	fn fetch_zero_page_indexed(&mut self, index: u8) -> u8 {
-		let instr_addr = self.read_instruction_zero_page_address();
-		let addr = instr_addr.wrapping_add(index);
-		self.read_memory(addr as u16)
-	}
-
-	/// Fetch memory required by the instruction. This can be in ROM (immediate, for example) or in RAM (absolute, for example), or CPU register.
-	/// All load instructions use this.
-	fn fetch_memory(&mut self, addrmode: &AddressingMode) -> u8 {
-		match addrmode {
+	/// Resolve the operand of the current instruction. Reads the operand bytes and the pointer of indirect modes, not
+	/// the operand itself. None when the addressing mode is not implemented, the instruction does nothing then.
+	pub(super) fn resolve_operand(&mut self, addrmode: AddressingMode) -> Option<Operand> {
+		let operand = match addrmode {
 			AddressingMode::IMPLIED => {
-				panic!("Instruction with implied addressing mode should never ask to fetch memory.");
+				panic!("Instruction with implied addressing mode should never ask for an operand.");
 			}
-			AddressingMode::IMMEDIATE => {
-				let addr = self.registers.PC + 1;
-				let res = self.read_memory(addr);
-				debug!("Fetched immediate: {:#X}", res);
-				res
+			AddressingMode::ACCUMULATOR =>	Operand::Accumulator,
+			AddressingMode::IMMEDIATE => 	Operand::Immediate(self.read_instruction_byte()),
+			AddressingMode::ZEROPAGE => 	Operand::zero_page(self.read_instruction_byte(), 0),
+			AddressingMode::ZEROPAGEX => 	Operand::zero_page(self.read_instruction_byte(), self.registers.X),
+			AddressingMode::ZEROPAGEY => 	Operand::zero_page(self.read_instruction_byte(), self.registers.Y),
+			AddressingMode::ABSOLUTE => 	Operand::absolute(self.read_instruction_absolute_address(), 0),
+			AddressingMode::ABSOLUTEX => 	Operand::absolute(self.read_instruction_absolute_address(), self.registers.X),
+			AddressingMode::ABSOLUTEY => 	Operand::absolute(self.read_instruction_absolute_address(), self.registers.Y),
+			AddressingMode::INDIRECT => {
+				let indirect_addr = self.read_instruction_absolute_address();
+				Operand::absolute(self.read_address_in_page(indirect_addr), 0)
 			}
-			AddressingMode::ACCUMULATOR => {
-				let res = self.registers.A;
-				debug!("Fetched accumulator: {}", res);
-				res
-			},
-			AddressingMode::ZEROPAGE => {
-				let addr = self.read_instruction_zero_page_address();
-				let res = self.read_memory(addr as u16);
-				debug!("Fetched from zero page: {:#X}", res);
-				res
-			},
-			AddressingMode::ZEROPAGEX => {
-				let res = self.fetch_zero_page_indexed(self.registers.X);
-				debug!("Fetched zeropage,x: {:#X}", res);
-				res
+			AddressingMode::INDIRECTX => {
+				// The pointer is in the zero page, at the operand plus X
+				let pointer = self.read_instruction_byte().wrapping_add(self.registers.X);
+				Operand::absolute(self.read_address_in_page(pointer as u16), 0)
 			}
-			AddressingMode::ZEROPAGEY => {
-				let res = self.fetch_zero_page_indexed(self.registers.Y);
-				debug!("Fetched zeropage,y: {:#X}", res);
-				res
-			},
-			AddressingMode::ABSOLUTE => {
-				let res = self.fetch_absolute_indexed(0);
-				debug!("Fetched absolute: {:#X}", res);
-				res
-			},
-			AddressingMode::ABSOLUTEX => {
-				let res = self.fetch_absolute_indexed(self.registers.X);
-				debug!("Fetched absolute,X: {:#X}", res);
-				res
-			}
-			AddressingMode::ABSOLUTEY => {
-				let res = self.fetch_absolute_indexed(self.registers.Y);
-				debug!("Fetched absolute,Y: {:#X}", res);
-				res
+			AddressingMode::INDIRECTY => {
+				// The pointer is in the zero page, at the operand. Y is added to the address it points to.
+				let pointer = self.read_instruction_byte();
+				Operand::absolute(self.read_address_in_page(pointer as u16), self.registers.Y)
 			}
 			_ => {
-				error!("The instruction doesn't support addressing mode: {:?}, panic", addrmode);
-				panic!();
+				self.report_unimplemented(UnimplementedFeature::AddressingMode);
+				return None;
 			}
+		};
+		debug!("Operand: {:?}", operand);
+		if let Operand::Memory { page_crossed: true, .. } = operand {
+			self.page_crossed = true;
+		}
+		Some(operand)
+	}
+
+	pub(super) fn read_operand(&mut self, operand: Operand) -> u8 {
+		match operand {
+			Operand::Accumulator => self.registers.A,
+			Operand::Immediate(value) => value,
+			Operand::Memory { addr, .. } => self.read_memory(addr),
+		}
+	}
+
+	pub(super) fn write_operand(&mut self, operand: Operand, value: u8) {
+		match operand {
+			Operand::Accumulator => self.registers.A = value,
+			Operand::Immediate(_) => panic!("Instruction with immediate addressing mode should never write its operand."),
+			Operand::Memory { addr, .. } => self.write_memory(addr, value),
 		}
 	}
 
-	/// Extract the address from instruction. This function will access ROM and RAM, aswell as indirect addressing.
-	/// All store instructions use this.
-	fn fetch_instruction_address(&mut self, addrmode: AddressingMode) -> u16 {
-		match addrmode {
-			AddressingMode::IMMEDIATE => {
-				let res = self.read_memory(self.registers.PC + 1) as u16;
-				debug!("Fetched immediate address: {:#X}", res);
-				res
+	/// Read the opcode at PC. With the threaded code, the opcode and its operand bytes come from the block cache when
+	/// the PC is in PRG ROM.
+	fn fetch_opcode(&mut self) -> u8 {
+		#[cfg(feature = "threaded")]
+		if self.block_cache.enabled() && self.scheduler == Scheduler::Fast && self.io_log.is_none() {
+			if let Some(instruction) = self.cached_instruction() {
+				self.prefetched_operand = Some(instruction.operand);
+				self.data_bus = instruction.opcode;
+				return instruction.opcode;
 			}
-			AddressingMode::ABSOLUTE => 	self.read_instruction_absolute_address(),
-			AddressingMode::ZEROPAGE => 	self.read_instruction_zero_page_address() as u16,
-			AddressingMode::INDIRECT => 	self.read_instruction_indirect_address(),
-			AddressingMode::ABSOLUTEX => 	self.read_instruction_absolute_indexed_address(self.registers.X),
-			AddressingMode::ABSOLUTEY => 	self.read_instruction_absolute_indexed_address(self.registers.Y),
-			_ => todo!()
 		}
+		self.read_memory(self.registers.PC) // Read at address of Program Counter (duh!)
 	}
 
-	/// Reads address stored in ROM at the current PC.
-	fn read_instruction_absolute_address(&mut self) -> u16 {
-		self.read_address_from_memory(self.registers.PC + 1)
+	/// The instruction at PC from the block cache. None for code in RAM and PRG RAM, that can change.
+	#[cfg(feature = "threaded")]
+	fn cached_instruction(&mut self) -> Option<CachedInstruction> {
+		let pc = self.registers.PC;
+		if let Some(instruction) = self.block_cache.next(pc) {
+			return Some(instruction);
+		}
+		let CpuMapping::PrgRom { offset, bank_size } = self.cartridge.cpu_mapping(pc) else { return None };
+		let bank_left = bank_size - offset % bank_size;
+		let cartridge = &mut self.cartridge;
+		self.block_cache.enter(offset, pc, || compile_block(pc, bank_left, |addr| cartridge.cpu_read(addr, true).unwrap_or(0)))
 	}
 
-	/// Adds absolute address with index.
-	fn read_instruction_absolute_indexed_address(&mut self, index: u8) -> u16 {
-		self.read_instruction_absolute_address() + (index as u16)
+	/// Use the block cache, on by default with the `threaded` feature. The interpreter is the reference.
+	#[cfg(feature = "threaded")]
+	pub fn set_block_cache(&mut self, enabled: bool) {
+		self.block_cache.set_enabled(enabled);
 	}
 
-	/// Reads zero-page address stored in ROM at the current PC.
-	fn read_instruction_zero_page_address(&mut self) -> u8 {
-		self.read_memory(self.registers.PC + 1)
+	/// Reads address stored in ROM at the current PC.
+	fn read_instruction_absolute_address(&mut self) -> u16 {
+		#[cfg(feature = "threaded")]
+		if let Some(operand) = self.prefetched_operand {
+			self.data_bus = (operand >> 8) as u8;
+			return operand;
+		}
+		self.read_address_from_memory(self.registers.PC.wrapping_add(1))
 	}
 
-	/// Returns address stored in memory, from the absolute address in ROM, at the current PC.
-	fn read_instruction_indirect_address(&mut self) -> u16 {
-		let indirect_addr = self.read_instruction_absolute_address();
-		self.read_address_from_memory(indirect_addr)
+	/// Reads the byte after the opcode: an immediate value or a zero page address.
+	fn read_instruction_byte(&mut self) -> u8 {
+		#[cfg(feature = "threaded")]
+		if let Some(operand) = self.prefetched_operand {
+			self.data_bus = operand as u8;
+			return operand as u8;
+		}
+		self.read_memory(self.registers.PC.wrapping_add(1))
 	}
 
 	/// Execute cmp instruction.
 	/// Possible instructions: CMP (A register), CPX (X register), CPY (Y register).
 	/// CMP is quite complex, which is why it has its own CPU function.
-	fn exec_cmp(&mut self, addrmode: AddressingMode, register: u8) {
+	pub(super) fn exec_cmp(&mut self, addrmode: AddressingMode, register: u8) {
 		/*
 		Link: http://www.6502.org/tutorials/compare_instructions.html
 		Compare Results | N | Z | C
@@ -797,8 +845,9 @@ impl CPU {
 
 		*The N flag will be bit 7 of A, X, or Y - Memory
 		*/
-		let fetched_memory = self.fetch_memory(&addrmode);
-				
+		let Some(operand) = self.resolve_operand(addrmode) else { return };
+		let fetched_memory = self.read_operand(operand);
+
 		let sub = register.wrapping_sub(fetched_memory);
 		let last_bit = (sub >> 7) == 1;
 
@@ -816,22 +865,30 @@ impl CPU {
 		self.registers.P.set(ProcessorStatusBits::CARRY, new_c);
 	}
 
-	/// Read 2 bytes from memory that represent an address
-	fn read_address_from_memory(&mut self, addr: u16) -> u16 {
+	/// Read 2 bytes from memory that represent an address. The second byte of $FFFF is at $0000.
+	pub(super) fn read_address_from_memory(&mut self, addr: u16) -> u16 {
 		let lsb = self.read_memory(addr) as u16;
-		let msb = self.read_memory(addr + 1) as u16;
+		let msb = self.read_memory(addr.wrapping_add(1)) as u16;
+		(msb << 8) | lsb
+	}
+
+	/// Read an address of the pointer of an indirect addressing mode. The 6502 doesn't carry into the high byte of the
+	/// pointer: the second byte of $xxFF is at $xx00 (JMP ($10FF) reads $10FF and $1000, ($FF),Y reads $FF and $00).
+	fn read_address_in_page(&mut self, pointer: u16) -> u16 {
+		let lsb = self.read_memory(pointer) as u16;
+		let msb = self.read_memory((pointer & 0xFF00) | (pointer.wrapping_add(1) & 0x00FF)) as u16;
 		(msb << 8) | lsb
 	}
 
 	/// Calculate PC after applying relative offset. The offset is represented as signed integer.
-	fn read_instruction_relative_address(&mut self) -> u16 {
-		let offset = self.read_memory(self.registers.PC + 1);
+	pub(super) fn read_instruction_relative_address(&mut self) -> u16 {
+		let offset = self.read_instruction_byte();
 		debug!("Relative offset: {:}", (offset as i8) as i16);
 		self.registers.PC.wrapping_add_signed((offset as i8) as i16)
 	}
 
 	/// Push PC onto stack, adding offset to PC.
-	fn push_pc(&mut self, offset: u16) {
+	pub(super) fn push_pc(&mut self, offset: u16) {
 		let pc_msb = (self.registers.PC.wrapping_add(offset) >> 8) as u8;
 		let pc_lsb = (self.registers.PC.wrapping_add(offset)) as u8;
 		self.push_stack(pc_msb); // store high
@@ -844,7 +901,7 @@ impl CPU {
 	}
 
 	/// Pops PC from stack.
-	fn pop_pc(&mut self) -> u16 {
+	pub(super) fn pop_pc(&mut self) -> u16 {
 		let lsb = self.pop_stack() as u16;
 		let msb = self.pop_stack() as u16;
 		(msb << 8) | lsb
@@ -852,48 +909,211 @@ impl CPU {
 
 	/// Generic function to read memory from CPU address space.
 	fn read_memory(&mut self, addr: u16) -> u8 {
-		let result = match addr {
-			// High 32KB
-			0x8000..=0xBFFF => {
-				// Lower PRG ROM
-				self.cartridge.read_prg_rom(self.active_prgbank_number_lower, addr - 0x8000)
-			}
+		self.bus_read(addr, false)
+	}
+
+	/// Generic function to write memory from CPU address space.
+	pub(super) fn write_memory(&mut self, addr: u16, value: u8) {
+		self.bus_write(addr, value, false);
+	}
 
-			0xC000..=0xFFFF => {
-				// Upper PRG ROM
-				self.cartridge.read_prg_rom(self.active_prgbank_number_upper, addr - 0xC000)
+	/// Read memory without side effects (no vblank clear on $2002, no VRAM address increment on $2007).
+	/// For tests, cheats and the debugger.
+	pub fn peek_memory(&mut self, addr: u16) -> u8 {
+		self.bus_read(addr, true)
+	}
+
+	/// Write memory without side effects. Writing to PRG ROM patches the ROM.
+	/// For tests, cheats and the debugger.
+	pub fn poke_memory(&mut self, addr: u16, value: u8) {
+		self.bus_write(addr, value, true);
+	}
+
+	/// The accurate scheduler runs the devices for the cycle of each memory access, before the access. Not timed for the
+	/// stats (that would cost more than the cycle itself), the time is counted as CPU time.
+	fn access_cycle(&mut self) {
+		if self.scheduler == Scheduler::Accurate {
+			self.devices_cycles += 1;
+			let cpu_cycles = self.skip_overclock_cycles(1);
+			self.tick_ppu(cpu_cycles);
+			self.tick_apu(cpu_cycles);
+		}
+	}
+
+	/// Read from CPU address space. When `peek` is true, the read must not change the state of any device.
+	fn bus_read(&mut self, addr: u16, peek: bool) -> u8 {
+		if !peek {
+			self.access_cycle();
+			let write_only = match addr {
+				0x2000..=0x3FFF => matches!(addr & 7, 0 | 1 | 3 | 5 | 6),
+				0x4000..=0x4014 => true,
+				_ => false,
+			};
+			if write_only && self.suspicious.strict() {
+				self.report_suspicious(Suspicious::WriteOnlyRead(addr));
 			}
-			0x2002 => {
-				self.ppu.ppu_status
+		}
+		let result = match addr {
+			0x4020..=0xFFFF => {
+				// Cartridge: the expansion area ($4020-$5FFF), PRG RAM, PRG ROM and mapper registers. The mapper decides
+				// which addresses it answers, the others are open bus. A game reading the expansion area expects a chip there.
+				match self.cartridge.cpu_read(addr, peek) {
+					Some(value) => value,
+					None => {
+						if !peek && addr < 0x6000 {
+							self.unimplemented.report(UnimplementedFeature::RegisterRead(addr));
+						}
+						self.data_bus
+					}
+				}
 			}
-			_ => {
-				// TODO: Phase out big memory block, we want PPU address space aswell........ RAM, ZEROPAGE, STACK...
-				self.lower_memory[addr as usize]
+			0x2000..=0x3FFF => {
+				// PPU registers, mirrored every 8 bytes
+				self.ppu.read_register(addr & 7, peek, &mut self.cartridge)
+			}
+			0x4015 => self.apu.read_status(peek),
+			0x4016 | 0x4017 => {
+				// Controller ports. The upper bits are open bus, the VS System has its coin slots and DIP switches there.
+				let value = self.controllers[(addr - 0x4016) as usize].read(peek);
+				match self.cartridge.vs_system() {
+					Some(vs) => value | vs.read(addr),
+					None => value | (self.data_bus & 0xE0),
+				}
 			}
+			0x0000..=0x1FFF => self.ram[(addr & 0x07FF) as usize],
+			// The other APU and I/O registers are write-only or disabled
+			_ => self.data_bus,
 		};
-		debug!("Reading memory: [{:#X}] = {:#X}", addr, result);
+		if !peek {
+			self.log_io(addr, result, AccessKind::Read);
+			self.record_event(addr, result, AccessKind::Read);
+			self.data_bus = result;
+		}
 		result
 	}
 
-	/// Generic function to write memory from CPU address space.
-	fn write_memory(&mut self, addr: u16, value: u8) {
+	/// Which device and region the CPU sees at the address, with the current mapper banks, e.g. "PPU register PPUSTATUS
+	/// (mirror of $2002)" or "PRG ROM bank 3 (8KB) + $0123". For the debugger and error messages.
+	pub fn describe_address(&self, addr: u16) -> String {
+		const PPU_REGISTERS: [&str; 8] = ["PPUCTRL", "PPUMASK", "PPUSTATUS", "OAMADDR", "OAMDATA", "PPUSCROLL", "PPUADDR", "PPUDATA"];
+		let name = match addr {
+			0x0000..=0x07FF => return format!("RAM ${:04X}", addr),
+			0x0800..=0x1FFF => return format!("RAM ${:04X} (mirror)", addr & 0x07FF),
+			0x2000..=0x2007 => return format!("PPU register {}", PPU_REGISTERS[(addr & 7) as usize]),
+			0x2008..=0x3FFF => return format!("PPU register {} (mirror of ${:04X})", PPU_REGISTERS[(addr & 7) as usize], 0x2000 | (addr & 7)),
+			0x4000..=0x4003 => "APU pulse 1 register",
+			0x4004..=0x4007 => "APU pulse 2 register",
+			0x4008..=0x400B => "APU triangle register",
+			0x400C..=0x400F => "APU noise register",
+			0x4010..=0x4013 => "APU DMC register",
+			0x4014 => "OAM DMA register",
+			0x4015 => "APU status register",
+			0x4016 => "Controller port 1",
+			0x4017 => "Controller port 2 (writes: APU frame counter)",
+			0x4018..=0x401F => "APU test registers (disabled)",
+			_ => return format!("Cartridge: {}", self.cartridge.cpu_mapping(addr)),
+		};
+		name.to_string()
+	}
+
+	/// Write to CPU address space. When `poke` is true, the write must not trigger any side effects (DMA, PPU address increment...).
+	fn bus_write(&mut self, addr: u16, mut value: u8, poke: bool) {
+		if !poke {
+			value = self.freezes.apply(addr, value);
+			self.access_cycle();
+			self.log_io(addr, value, AccessKind::Write);
+			self.record_event(addr, value, AccessKind::Write);
+		}
 		match addr {
-			// High 32KB
-			0x8000..=0xBFFF => {
-				//TODO: We should never write to ROM
-				todo!();
+			0x4020..=0xFFFF => {
+				// Cartridge: PRG RAM and mapper registers. Poke patches the PRG ROM.
+				if !poke && self.suspicious.strict() && self.cartridge.ignores_write(addr) {
+					self.report_suspicious(Suspicious::RomWrite(addr));
+				}
+				self.cartridge.cpu_write(addr, value, poke);
+				// A mapper register can switch banks, a poke can patch the ROM
+				#[cfg(feature = "threaded")]
+				if poke {
+					self.block_cache.clear();
+				} else {
+					self.block_cache.end_block();
+				}
 			}
-			0xC000..=0xFFFF => {
-				//TODO: We should never write to ROM
-				todo!();
+			0x2000..=0x3FFF => {
+				self.ppu.write_register(addr & 7, value, poke, &mut self.cartridge);
 			}
-			_ => {
-				debug!("Writing memory: [{:#X}] = {:#X}", addr, value);
-				self.lower_memory[addr as usize] = value;
+			0x0000..=0x1FFF => {
+				self.ram[(addr & 0x07FF) as usize] = value;
+			}
+			0x4016 if !poke => {
+				let frame = self.ppu.frame();
+				if let Some(input) = self.input.as_mut().filter(|_| value & 1 == 1 && self.input_frame != Some(frame)) {
+					self.input_frame = Some(frame);
+					for (controller, buttons) in self.controllers.iter_mut().zip(input.poll(frame)) {
+						controller.set_buttons(buttons);
+					}
+				}
+				for controller in &mut self.controllers {
+					controller.write(value);
+				}
+			}
+			0x4014 if !poke => {
+				self.oam_dma(value);
 			}
+			0x4000..=0x4013 | 0x4015 | 0x4017 if !poke => {
+				self.apu.write_register(addr, value);
+			}
+			_ => {}
+		}
+		if !poke {
+			self.last_write = Some((addr, value));
+			self.data_bus = value;
 		}
 	}
 
+	/// Log the accesses to the addresses of `filter`, e.g. `writes 2000-2007, reads 4016` (see `IoFilter`). None logs
+	/// nothing.
+	pub fn set_io_log(&mut self, filter: Option<IoFilter>) {
+		self.io_log = filter;
+	}
+
+	pub fn io_log(&self) -> Option<&IoFilter> {
+		self.io_log.as_ref()
+	}
+
+	fn log_io(&self, addr: u16, value: u8, kind: AccessKind) {
+		if self.io_log.as_ref().is_some_and(|filter| filter.matches(addr, kind)) {
+			let pc = self.trace.iter().last().map_or(self.registers.PC, |entry| entry.pc);
+			let kind = if kind == AccessKind::Read { "Read" } else { "Write" };
+			info!("{} ${:04X} = ${:02X} at ${:04X} (frame {}, scanline {}, dot {})", kind, addr, value, pc, self.ppu.frame(), self.ppu.scanline(), self.ppu.dot());
+		}
+	}
+
+	fn record_event(&mut self, addr: u16, value: u8, kind: AccessKind) {
+		if EventLog::is_logged(addr) {
+			self.events.record(BusEvent {
+				scanline: self.ppu.scanline(),
+				dot: self.ppu.dot(),
+				addr,
+				value,
+				kind,
+			});
+		}
+	}
+
+	/// OAM DMA: copy 256 bytes from CPU page $XX00-$XXFF to OAM. The CPU is suspended for 513 cycles (+1 on odd cycle).
+	fn oam_dma(&mut self, page: u8) {
+		span!(DEBUG, "dma", page = page);
+		debug!("OAM DMA from page: {:#X}", page);
+
+		let start = (page as u16) << 8;
+		for i in 0..256 {
+			let value = self.read_memory(start + i);
+			self.ppu.write_oam(value);
+		}
+		self.cycles += 513 + (self.cycles & 1);
+	}
+
 }
 
 
@@ -959,6 +1179,30 @@ mod tests {
 		cpu.clock_tick();
 	}
 
+	#[test]
+	fn test_stack_ram() {
+		let mut nes = initialize(load_program_stack);
+		let mut cpu = nes.cpu;
+
+		// The stack is page 1 of the RAM, the mirrors see it
+		cpu.registers.S = 0xFD;
+		cpu.push_stack(0x42);
+		cpu.push_stack(0x43);
+		assert_eq!((cpu.read_memory(0x01FD), cpu.read_memory(0x09FD), cpu.read_memory(0x19FC)), (0x42, 0x42, 0x43));
+		cpu.write_memory(0x11FC, 0x44);
+		assert_eq!((cpu.pop_stack(), cpu.pop_stack()), (0x44, 0x42));
+
+		// S wraps within page 1
+		cpu.registers.S = 0x00;
+		cpu.push_stack(0x45);
+		assert_eq!((cpu.registers.S, cpu.read_memory(0x0100)), (0xFF, 0x45));
+		let pc = cpu.registers.PC;
+		cpu.push_pc(0);
+		assert_eq!(cpu.pop_pc(), pc);
+		assert_eq!(cpu.pop_stack(), 0x45);
+		assert_eq!(cpu.registers.S, 0x00);
+	}
+
 	#[test]
 	fn test_lda() {
 		let mut nes = initialize(load_program_lda);
@@ -991,7 +1235,7 @@ mod tests {
 		cpu.clock_tick();
 		cpu.clock_tick();
 		cpu.clock_tick();
-		assert_eq!(cpu.registers.A, 0x11);
+		assert_eq!(cpu.registers.A, 0x0B);
 
 		cpu.clock_tick();
 		assert_eq!(cpu.registers.P.get(ProcessorStatusBits::DECIMAL), false);
@@ -1145,6 +1389,30 @@ mod tests {
 		assert_eq!(cpu.registers.P.get(ProcessorStatusBits::DECIMAL), true);
 	}
 
+	#[test]
+	fn test_decimal_flag() {
+		let mut nes = initialize(load_program_decimal_flag);
+		let mut cpu = nes.cpu;
+
+		cpu.clock_tick();
+		cpu.clock_tick();
+		let pushed = cpu.read_memory(0x0100 + cpu.registers.S as u16 + 1);
+		assert_eq!(pushed & 0b0011_1000, 0b0011_1000);	// PHP sets B and bit 5 in the pushed copy
+		cpu.clock_tick();
+		assert_eq!(cpu.registers.P.get(ProcessorStatusBits::DECIMAL), false);
+		cpu.clock_tick();
+		assert_eq!(cpu.registers.P.get(ProcessorStatusBits::DECIMAL), true);
+		assert_eq!(cpu.registers.P.get(ProcessorStatusBits::BREAK), false);	// PLP ignores B
+		for _ in 0..3 {
+			cpu.clock_tick();
+		}
+		assert_eq!(cpu.registers.A, 0x0A);
+		cpu.clock_tick();
+		cpu.clock_tick();
+		assert_eq!(cpu.registers.A, pushed);
+		cpu.clock_tick();
+	}
+
 	#[test]
 	fn test_jmp_indirect() {
 		let mut nes = initialize(load_program_jmp_indirect);
@@ -1247,6 +1515,41 @@ mod tests {
 		assert_eq!(cpu.registers.S, 0xFF);
 	}
 
+	#[test]
+	fn test_absolute_indexed_wrap() {
+		let mut nes = initialize(load_program_absolute_indexed_wrap);
+		let mut cpu = nes.cpu;
+		for _ in 0..4 {
+			cpu.clock_tick();
+		}
+		cpu.registers.A = 0;
+		cpu.clock_tick();
+		assert_eq!(cpu.registers.A, 0x5A);
+		cpu.clock_tick();
+		assert_eq!(cpu.read_memory(0x0000), 0x5A);
+	}
+
+	#[test]
+	fn test_pc_wrap() {
+		let mut nes = initialize(load_program_pc_wrap);
+		let mut cpu = nes.cpu;
+		for _ in 0..6 {
+			cpu.clock_tick();
+		}
+		assert_eq!(cpu.registers.PC, 0xFFFE);
+
+		// The PC wraps to $0000
+		cpu.clock_tick();
+		assert_eq!((cpu.registers.A, cpu.registers.PC), (0xA2, 0x0000));
+		cpu.clock_tick();
+		assert_eq!(cpu.registers.PC, 0xFFFF);
+		// The operand is read from $0000
+		cpu.clock_tick();
+		assert_eq!((cpu.registers.X, cpu.registers.PC), (0x4C, 0x0001));
+		// The address of $FFFF is $FFFF and $0000
+		assert_eq!(cpu.read_address_from_memory(0xFFFF), 0x4CA2);
+	}
+
 	#[test]
 	fn test_indexed_absolute() {
 		let mut nes = initialize(load_program_absolute_indexed_with_carry);
@@ -1431,5 +1734,135 @@ mod tests {
 	// 	cpu.clock_tick();
 	// }
 
+	#[test]
+	fn test_sbc() {
+		let mut nes = initialize(load_program_sbc);
+		let cpu = &mut nes.cpu;
+
+		for _ in 0..3 {
+			cpu.clock_tick();
+		}
+		assert_eq!(cpu.registers.A, 0x60);
+		assert_eq!(cpu.registers.P.get(ProcessorStatusBits::CARRY), false);
+		assert_eq!(cpu.registers.P.get(ProcessorStatusBits::OVERFLOW), false);
+
+		for _ in 0..3 {
+			cpu.clock_tick();
+		}
+		assert_eq!(cpu.registers.A, 0xA0);
+		assert_eq!(cpu.registers.P.get(ProcessorStatusBits::OVERFLOW), true);
+		assert_eq!(cpu.registers.P.get(ProcessorStatusBits::NEGATIVE), true);
+
+		for _ in 0..3 {
+			cpu.clock_tick();
+		}
+		assert_eq!(cpu.registers.A, 0x03);
+		assert_eq!(cpu.registers.P.get(ProcessorStatusBits::CARRY), true);
+		assert_eq!(cpu.registers.P.get(ProcessorStatusBits::OVERFLOW), false);
+	}
+
+	#[test]
+	fn test_rts() {
+		let mut nes = initialize(load_program_rts);
+		let cpu = &mut nes.cpu;
+
+		cpu.clock_tick(); // JSR
+		cpu.clock_tick(); // LDX
+		cpu.clock_tick(); // RTS
+		assert_eq!(cpu.registers.PC, 0x8003);
+		assert_eq!(cpu.registers.S, 0xFF);
+		cpu.clock_tick();
+		assert_eq!((cpu.registers.A, cpu.registers.X), (0x01, 0x02));
+	}
+
+	#[test]
+	fn test_rotate() {
+		let mut nes = initialize(load_program_rotate);
+		let cpu = &mut nes.cpu;
+
+		cpu.clock_tick();
+		cpu.clock_tick();
+		cpu.clock_tick(); // ROL A
+		assert_eq!(cpu.registers.A, 0x03);
+		assert_eq!(cpu.registers.P.get(ProcessorStatusBits::CARRY), true);
+		cpu.clock_tick(); // ROR A
+		assert_eq!(cpu.registers.A, 0x81);
+		assert_eq!(cpu.registers.P.get(ProcessorStatusBits::NEGATIVE), true);
+		cpu.clock_tick();
+		cpu.clock_tick(); // ROR A
+		assert_eq!(cpu.registers.A, 0x40);
+		assert_eq!(cpu.registers.P.get(ProcessorStatusBits::CARRY), true);
+
+		cpu.clock_tick();
+		cpu.clock_tick();
+		cpu.clock_tick(); // ROL $10
+		assert_eq!(cpu.read_memory(0x10), 0x01);
+		assert_eq!(cpu.registers.P.get(ProcessorStatusBits::CARRY), true);
+		cpu.clock_tick(); // LSR $10, the carry is bit 0
+		assert_eq!(cpu.read_memory(0x10), 0x00);
+		assert_eq!(cpu.registers.P.get(ProcessorStatusBits::CARRY), true);
+		assert_eq!(cpu.registers.P.get(ProcessorStatusBits::ZERO), true);
+	}
+
+	#[test]
+	fn test_brk() {
+		let mut nes = initialize(load_program_brk);
+		let cpu = &mut nes.cpu;
+		cpu.registers.P.set(ProcessorStatusBits::InterruptDisable, false);
+
+		cpu.clock_tick();
+		cpu.clock_tick(); // BRK
+		assert_eq!(cpu.registers.PC, 0x8010);
+		assert_eq!(cpu.registers.S, 0xFC);
+		assert_eq!(cpu.registers.P.get(ProcessorStatusBits::InterruptDisable), true);
+		// The return address skips the break mark, the pushed status has the break flag
+		assert_eq!(cpu.read_memory(0x01FD) & 0b0001_0000, 0b0001_0000);
+		assert_eq!((cpu.read_memory(0x01FF), cpu.read_memory(0x01FE)), (0x80, 0x04));
+
+		cpu.clock_tick();
+		cpu.clock_tick(); // RTI
+		assert_eq!(cpu.registers.PC, 0x8004);
+		assert_eq!(cpu.registers.S, 0xFF);
+		assert_eq!(cpu.registers.P.get(ProcessorStatusBits::InterruptDisable), false);
+		cpu.clock_tick();
+		assert_eq!((cpu.registers.A, cpu.registers.X), (0x03, 0x02));
+	}
+
+	#[test]
+	fn test_indirect_indexed() {
+		let mut nes = initialize(load_program_indirect_indexed);
+		let cpu = &mut nes.cpu;
+
+		for _ in 0..7 {
+			cpu.clock_tick();
+		}
+		assert_eq!(cpu.read_memory(0x0210), 0x5A);
+		cpu.clock_tick();
+		cpu.clock_tick();
+		assert_eq!(cpu.read_memory(0x0200), 0x5A);
+		cpu.clock_tick();
+		cpu.clock_tick();
+		assert_eq!(cpu.registers.A, 0x5A);
+	}
+
+	/// nestest in automation mode (from $C000, nothing drawn): the registers before each instruction match its log, up
+	/// to the illegal opcodes.
+	#[test]
+	fn test_nestest() {
+		let mut nes = NES::new_open_rom_file("6502asm_programs/nestest/nestest.nes");
+		let cpu = &mut nes.cpu;
+		cpu.registers.PC = 0xC000;
+		cpu.registers.S = 0xFD;
+		cpu.registers.P.flags = 0x24;
+		let log = std::fs::read_to_string("6502asm_programs/nestest/nestest.log.txt").unwrap();
+		for (number, line) in log.lines().take_while(|line| !line.contains('*')).enumerate() {
+			let r = &cpu.registers;
+			let state = format!("{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}", r.PC, r.A, r.X, r.Y, r.P.flags, r.S);
+			assert_eq!(state, format!("{} {}", &line[..4], &line[48..73]), "line {}: {}", number + 1, line);
+			cpu.clock_tick();
+		}
+		// The official opcodes passed, the result codes are 0
+		assert_eq!((cpu.peek_memory(0x02), cpu.peek_memory(0x03)), (0, 0));
+	}
 }
 
@@ -1,11 +1,10 @@
 /// The decoder's purpose is to take OPCODE and translate it to the appropriate instruction.
 // https://www.masswerk.at/6502/6502_instruction_set.html
 
-use log::error;
 use std::fmt;
 
 /// All possible CPU instructions. This is written like in 6502 assembler.
-#[derive(PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Instructions {
 	ADC, // add with carry
 	AND, // and (with accumulator)
@@ -36,6 +35,7 @@ pub enum Instructions {
 	INY, // increment Y
 	JMP, // jump
 	JSR, // jump subroutine
+	KIL, // jam: halts the CPU until reset (illegal opcode)
 	LDA, // load accumulator
 	LDX, // load X
 	LDY, // load Y
@@ -82,7 +82,7 @@ pub enum Instructions {
 /// | INDIRECTY |  |
 /// | ZEROPAGEX | Like ZEROPAGE, but also add X index |
 /// | ZEROPAGEY | Like ZEROPAGE, but also add Y index |
-#[derive(PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum AddressingMode {
 	IMPLIED,
 	ABSOLUTE,
@@ -99,6 +99,38 @@ pub enum AddressingMode {
 	IMMEDIATE,
 }
 
+impl AddressingMode {
+	/// The bytes after the opcode.
+	pub const fn operand_bytes(self) -> u8 {
+		match self {
+			AddressingMode::IMPLIED | AddressingMode::ACCUMULATOR => 0,
+			AddressingMode::ABSOLUTE | AddressingMode::ABSOLUTEX | AddressingMode::ABSOLUTEY | AddressingMode::INDIRECT => 2,
+			_ => 1,
+		}
+	}
+}
+
+/// The operand of an instruction, resolved from its addressing mode by the CPU.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Operand {
+	Accumulator,
+	Immediate(u8),
+	/// page_crossed: the index moved the address to the next page, see `OopsCycle::PageBoundryCrossed`
+	Memory { addr: u16, page_crossed: bool },
+}
+
+impl Operand {
+	/// The zero page address wraps around in the zero page.
+	pub fn zero_page(base: u8, index: u8) -> Self {
+		Operand::Memory { addr: base.wrapping_add(index) as u16, page_crossed: false }
+	}
+
+	pub fn absolute(base: u16, index: u8) -> Self {
+		let addr = base.wrapping_add(index as u16);
+		Operand::Memory { addr, page_crossed: addr & 0xFF00 != base & 0xFF00 }
+	}
+}
+
 
 /// Instruction's cycles can be changed if some conditions are met. \
 /// Explanation:\
@@ -111,6 +143,7 @@ pub enum AddressingMode {
 /// | BranchOccursOn     | add 2 to cycles if branch occurs on same page <br> or add 2 to cycles if branch occurs to different page |
 /// 
 /// 
+#[derive(Clone, Copy)]
 pub enum OopsCycle {
 	NONE,
 	PageBoundryCrossed,
@@ -126,166 +159,210 @@ impl fmt::Display for OopsCycle {
     }
 }
 
+/// An opcode decoded: the instruction, the addressing mode, the bytes (with the opcode), the cycles and the extra cycle.
+pub type Decoded = (Instructions, AddressingMode, u8, u8, OopsCycle);
+
+/// Builds the opcode table at compile time, one line per opcode: `opcode => instruction mode, bytes, cycles, oops;`.
+/// The build fails when an opcode is twice in the table, when the bytes don't match the addressing mode (the
+/// disassembler reads the operand of the mode), and when an extra cycle of a branch is not on a branch.
+macro_rules! opcode_table {
+	($($opcode:literal => $instr:ident $mode:ident, $bytes:literal, $cycles:literal, $oops:ident;)*) => {{
+		let mut table: [Option<Decoded>; 256] = [None; 256];
+		$(
+			assert!(table[$opcode].is_none(), concat!("Opcode ", stringify!($opcode), " is twice in the table"));
+			assert!($bytes == 1 + AddressingMode::$mode.operand_bytes(), concat!("The bytes of opcode ", stringify!($opcode), " don't match its addressing mode"));
+			assert!(matches!(OopsCycle::$oops, OopsCycle::BranchOccursOn) == matches!(AddressingMode::$mode, AddressingMode::RELATIVE),
+				concat!("Only branches have the extra cycles of a branch, opcode ", stringify!($opcode)));
+			table[$opcode] = Some((Instructions::$instr, AddressingMode::$mode, $bytes, $cycles, OopsCycle::$oops));
+		)*
+		table
+	}};
+}
+
+/// The opcodes the emulator knows, the others (illegal opcodes) are None.
+pub const OPCODES: [Option<Decoded>; 256] = opcode_table! {
+	0x00 => BRK IMPLIED,     1, 2, NONE;
+	0x01 => ORA INDIRECTX,   2, 6, NONE;
+	0x02 => KIL IMPLIED,     1, 2, NONE;
+	0x05 => ORA ZEROPAGE,    2, 3, NONE;
+	0x06 => ASL ZEROPAGE,    2, 5, NONE;
+	0x08 => PHP IMPLIED,     1, 3, NONE;
+	0x09 => ORA IMMEDIATE,   2, 2, NONE;
+	0x0A => ASL ACCUMULATOR, 1, 2, NONE;
+	0x0D => ORA ABSOLUTE,    3, 4, NONE;
+	0x0E => ASL ABSOLUTE,    3, 6, NONE;
+	0x10 => BPL RELATIVE,    2, 2, BranchOccursOn;
+	0x11 => ORA INDIRECTY,   2, 5, PageBoundryCrossed;
+	0x12 => KIL IMPLIED,     1, 2, NONE;
+	0x15 => ORA ZEROPAGEX,   2, 4, NONE;
+	0x16 => ASL ZEROPAGEX,   2, 6, NONE;
+	0x18 => CLC IMPLIED,     1, 2, NONE;
+	0x19 => ORA ABSOLUTEY,   3, 4, PageBoundryCrossed;
+	0x1D => ORA ABSOLUTEX,   3, 4, PageBoundryCrossed;
+	0x1E => ASL ABSOLUTEX,   3, 7, NONE;
+	0x20 => JSR ABSOLUTE,    3, 6, NONE;
+	0x21 => AND INDIRECTX,   2, 6, NONE;
+	0x22 => KIL IMPLIED,     1, 2, NONE;
+	0x24 => BIT ZEROPAGE,    2, 3, NONE;
+	0x25 => AND ZEROPAGE,    2, 3, NONE;
+	0x26 => ROL ZEROPAGE,    2, 5, NONE;
+	0x28 => PLP IMPLIED,     1, 4, NONE;
+	0x29 => AND IMMEDIATE,   2, 2, NONE;
+	0x2A => ROL ACCUMULATOR, 1, 2, NONE;
+	0x2C => BIT ABSOLUTE,    3, 4, NONE;
+	0x2D => AND ABSOLUTE,    3, 4, NONE;
+	0x2E => ROL ABSOLUTE,    3, 6, NONE;
+	0x30 => BMI RELATIVE,    2, 2, BranchOccursOn;
+	0x31 => AND INDIRECTY,   2, 5, PageBoundryCrossed;
+	0x32 => KIL IMPLIED,     1, 2, NONE;
+	0x35 => AND ZEROPAGEX,   2, 4, NONE;
+	0x36 => ROL ZEROPAGEX,   2, 6, NONE;
+	0x38 => SEC IMPLIED,     1, 2, NONE;
+	0x39 => AND ABSOLUTEY,   3, 4, PageBoundryCrossed;
+	0x3D => AND ABSOLUTEX,   3, 4, PageBoundryCrossed;
+	0x3E => ROL ABSOLUTEX,   3, 7, NONE;
+	0x40 => RTI IMPLIED,     1, 6, NONE;
+	0x41 => EOR INDIRECTX,   2, 6, NONE;
+	0x42 => KIL IMPLIED,     1, 2, NONE;
+	0x45 => EOR ZEROPAGE,    2, 3, NONE;
+	0x46 => LSR ZEROPAGE,    2, 5, NONE;
+	0x48 => PHA IMPLIED,     1, 3, NONE;
+	0x49 => EOR IMMEDIATE,   2, 2, NONE;
+	0x4A => LSR ACCUMULATOR, 1, 2, NONE;
+	0x4C => JMP ABSOLUTE,    3, 3, NONE;
+	0x4D => EOR ABSOLUTE,    3, 4, NONE;
+	0x4E => LSR ABSOLUTE,    3, 6, NONE;
+	0x50 => BVC RELATIVE,    2, 2, BranchOccursOn;
+	0x51 => EOR INDIRECTY,   2, 5, PageBoundryCrossed;
+	0x52 => KIL IMPLIED,     1, 2, NONE;
+	0x55 => EOR ZEROPAGEX,   2, 4, NONE;
+	0x56 => LSR ZEROPAGEX,   2, 6, NONE;
+	0x58 => CLI IMPLIED,     1, 2, NONE;
+	0x59 => EOR ABSOLUTEY,   3, 4, PageBoundryCrossed;
+	0x5D => EOR ABSOLUTEX,   3, 4, PageBoundryCrossed;
+	0x5E => LSR ABSOLUTEX,   3, 7, NONE;
+	0x60 => RTS IMPLIED,     1, 6, NONE;
+	0x61 => ADC INDIRECTX,   2, 6, NONE;
+	0x62 => KIL IMPLIED,     1, 2, NONE;
+	0x65 => ADC ZEROPAGE,    2, 3, NONE;
+	0x66 => ROR ZEROPAGE,    2, 5, NONE;
+	0x68 => PLA IMPLIED,     1, 4, NONE;
+	0x69 => ADC IMMEDIATE,   2, 2, NONE;
+	0x6A => ROR ACCUMULATOR, 1, 2, NONE;
+	0x6C => JMP INDIRECT,    3, 5, NONE;
+	0x6D => ADC ABSOLUTE,    3, 4, NONE;
+	0x6E => ROR ABSOLUTE,    3, 6, NONE;
+	0x70 => BVS RELATIVE,    2, 2, BranchOccursOn;
+	0x71 => ADC INDIRECTY,   2, 5, PageBoundryCrossed;
+	0x72 => KIL IMPLIED,     1, 2, NONE;
+	0x75 => ADC ZEROPAGEX,   2, 4, NONE;
+	0x76 => ROR ZEROPAGEX,   2, 6, NONE;
+	0x78 => SEI IMPLIED,     1, 2, NONE;
+	0x79 => ADC ABSOLUTEY,   3, 4, PageBoundryCrossed;
+	0x7D => ADC ABSOLUTEX,   3, 4, PageBoundryCrossed;
+	0x7E => ROR ABSOLUTEX,   3, 7, NONE;
+	0x81 => STA INDIRECTX,   2, 6, NONE;
+	0x84 => STY ZEROPAGE,    2, 3, NONE;
+	0x85 => STA ZEROPAGE,    2, 3, NONE;
+	0x86 => STX ZEROPAGE,    2, 3, NONE;
+	0x88 => DEY IMPLIED,     1, 2, NONE;
+	0x8A => TXA IMPLIED,     1, 2, NONE;
+	0x8C => STY ABSOLUTE,    3, 4, NONE;
+	0x8D => STA ABSOLUTE,    3, 4, NONE;
+	0x8E => STX ABSOLUTE,    3, 4, NONE;
+	0x90 => BCC RELATIVE,    2, 2, BranchOccursOn;
+	0x91 => STA INDIRECTY,   2, 6, NONE;
+	0x92 => KIL IMPLIED,     1, 2, NONE;
+	0x94 => STY ZEROPAGEX,   2, 4, NONE;
+	0x95 => STA ZEROPAGEX,   2, 4, NONE;
+	0x96 => STX ZEROPAGEY,   2, 4, NONE;
+	0x98 => TYA IMPLIED,     1, 2, NONE;
+	0x99 => STA ABSOLUTEY,   3, 5, NONE;
+	0x9A => TXS IMPLIED,     1, 2, NONE;
+	0x9D => STA ABSOLUTEX,   3, 5, NONE;
+	0xA0 => LDY IMMEDIATE,   2, 2, NONE;
+	0xA1 => LDA INDIRECTX,   2, 6, NONE;
+	0xA2 => LDX IMMEDIATE,   2, 2, NONE;
+	0xA4 => LDY ZEROPAGE,    2, 3, NONE;
+	0xA5 => LDA ZEROPAGE,    2, 3, NONE;
+	0xA6 => LDX ZEROPAGE,    2, 3, NONE;
+	0xA8 => TAY IMPLIED,     1, 2, NONE;
+	0xA9 => LDA IMMEDIATE,   2, 2, NONE;
+	0xAA => TAX IMPLIED,     1, 2, NONE;
+	0xAC => LDY ABSOLUTE,    3, 4, NONE;
+	0xAD => LDA ABSOLUTE,    3, 4, NONE;
+	0xAE => LDX ABSOLUTE,    3, 4, NONE;
+	0xB0 => BCS RELATIVE,    2, 2, BranchOccursOn;
+	0xB1 => LDA INDIRECTY,   2, 5, PageBoundryCrossed;
+	0xB2 => KIL IMPLIED,     1, 2, NONE;
+	0xB4 => LDY ZEROPAGEX,   2, 4, NONE;
+	0xB5 => LDA ZEROPAGEX,   2, 4, NONE;
+	0xB6 => LDX ZEROPAGEY,   2, 4, NONE;
+	0xB8 => CLV IMPLIED,     1, 2, NONE;
+	0xB9 => LDA ABSOLUTEY,   3, 4, PageBoundryCrossed;
+	0xBA => TSX IMPLIED,     1, 2, NONE;
+	0xBC => LDY ABSOLUTEX,   3, 4, PageBoundryCrossed;
+	0xBD => LDA ABSOLUTEX,   3, 4, PageBoundryCrossed;
+	0xBE => LDX ABSOLUTEY,   3, 4, PageBoundryCrossed;
+	0xC0 => CPY IMMEDIATE,   2, 2, NONE;
+	0xC1 => CMP INDIRECTX,   2, 6, NONE;
+	0xC4 => CPY ZEROPAGE,    2, 3, NONE;
+	0xC5 => CMP ZEROPAGE,    2, 3, NONE;
+	0xC6 => DEC ZEROPAGE,    2, 5, NONE;
+	0xC8 => INY IMPLIED,     1, 2, NONE;
+	0xC9 => CMP IMMEDIATE,   2, 2, NONE;
+	0xCA => DEX IMPLIED,     1, 2, NONE;
+	0xCC => CPY ABSOLUTE,    3, 4, NONE;
+	0xCD => CMP ABSOLUTE,    3, 4, NONE;
+	0xCE => DEC ABSOLUTE,    3, 6, NONE;
+	0xD0 => BNE RELATIVE,    2, 2, BranchOccursOn;
+	0xD1 => CMP INDIRECTY,   2, 5, PageBoundryCrossed;
+	0xD2 => KIL IMPLIED,     1, 2, NONE;
+	0xD5 => CMP ZEROPAGEX,   2, 4, NONE;
+	0xD6 => DEC ZEROPAGEX,   2, 6, NONE;
+	0xD8 => CLD IMPLIED,     1, 2, NONE;
+	0xD9 => CMP ABSOLUTEY,   3, 4, PageBoundryCrossed;
+	0xDD => CMP ABSOLUTEX,   3, 4, PageBoundryCrossed;
+	0xDE => DEC ABSOLUTEX,   3, 7, NONE;
+	0xE0 => CPX IMMEDIATE,   2, 2, NONE;
+	0xE1 => SBC INDIRECTX,   2, 6, NONE;
+	0xE4 => CPX ZEROPAGE,    2, 3, NONE;
+	0xE5 => SBC ZEROPAGE,    2, 3, NONE;
+	0xE6 => INC ZEROPAGE,    2, 5, NONE;
+	0xE8 => INX IMPLIED,     1, 2, NONE;
+	0xE9 => SBC IMMEDIATE,   2, 2, NONE;
+	0xEA => NOP IMPLIED,     1, 2, NONE;
+	0xEC => CPX ABSOLUTE,    3, 4, NONE;
+	0xED => SBC ABSOLUTE,    3, 4, NONE;
+	0xEE => INC ABSOLUTE,    3, 6, NONE;
+	0xF0 => BEQ RELATIVE,    2, 2, BranchOccursOn;
+	0xF1 => SBC INDIRECTY,   2, 5, PageBoundryCrossed;
+	0xF2 => KIL IMPLIED,     1, 2, NONE;
+	0xF5 => SBC ZEROPAGEX,   2, 4, NONE;
+	0xF6 => INC ZEROPAGEX,   2, 6, NONE;
+	0xF8 => SED IMPLIED,     1, 2, NONE;
+	0xF9 => SBC ABSOLUTEY,   3, 4, PageBoundryCrossed;
+	0xFD => SBC ABSOLUTEX,   3, 4, PageBoundryCrossed;
+	0xFE => INC ABSOLUTEX,   3, 7, NONE;
+};
+
 /// Decode CPU instruction, probably from ROM or something. \
-/// Returns the Instruction (like in assembly), Addressing Mode, Bytes, Cycles.
-pub fn decode_opcode(opcode: u8) -> (Instructions, AddressingMode, u8, u8, OopsCycle) {
-	match opcode {
-		0x00 => (Instructions::BRK, AddressingMode::IMPLIED, 		1, 2, OopsCycle::NONE),
-		0x01 => (Instructions::ORA, AddressingMode::INDIRECTX, 		2, 6, OopsCycle::NONE),
-		0x05 => (Instructions::ORA, AddressingMode::ZEROPAGE, 		2, 3, OopsCycle::NONE),
-		0x06 => (Instructions::ASL, AddressingMode::ZEROPAGE, 		2, 5, OopsCycle::NONE),
-		0x08 => (Instructions::PHP, AddressingMode::IMPLIED, 		1, 3, OopsCycle::NONE),
-		0x09 => (Instructions::ORA, AddressingMode::IMMEDIATE, 		2, 2, OopsCycle::NONE),
-		0x0A => (Instructions::ASL, AddressingMode::ACCUMULATOR, 	1, 2, OopsCycle::NONE),
-		0x0D => (Instructions::ORA, AddressingMode::ABSOLUTE, 		3, 4, OopsCycle::NONE),
-		0x0E => (Instructions::ASL, AddressingMode::ABSOLUTE, 		3, 6, OopsCycle::NONE),
-		0x10 => (Instructions::BPL, AddressingMode::RELATIVE, 		2, 2, OopsCycle::BranchOccursOn),
-		0x11 => (Instructions::ORA, AddressingMode::INDIRECTY, 		2, 5, OopsCycle::PageBoundryCrossed),
-		0x15 => (Instructions::ORA, AddressingMode::ZEROPAGEX, 		2, 4, OopsCycle::NONE),
-		0x16 => (Instructions::ASL, AddressingMode::ZEROPAGEX, 		2, 6, OopsCycle::NONE),
-		0x18 => (Instructions::CLC, AddressingMode::IMPLIED, 		1, 2, OopsCycle::NONE),
-		0x19 => (Instructions::ORA, AddressingMode::ABSOLUTEY, 		3, 4, OopsCycle::PageBoundryCrossed),
-		0x1D => (Instructions::ORA, AddressingMode::ABSOLUTEX, 		3, 4, OopsCycle::PageBoundryCrossed),
-		0x1E => (Instructions::ASL, AddressingMode::ABSOLUTEX, 		3, 7, OopsCycle::NONE),
-		0x20 => (Instructions::JSR, AddressingMode::ABSOLUTE, 		3, 6, OopsCycle::NONE),
-		0x21 => (Instructions::AND, AddressingMode::INDIRECTX, 		2, 6, OopsCycle::NONE),
-		0x24 => (Instructions::BIT, AddressingMode::ZEROPAGE, 		2, 3, OopsCycle::NONE),
-		0x25 => (Instructions::AND, AddressingMode::ZEROPAGE, 		2, 3, OopsCycle::NONE),
-		0x26 => (Instructions::ROL, AddressingMode::ZEROPAGE, 		2, 5, OopsCycle::NONE),
-		0x28 => (Instructions::PLP, AddressingMode::IMPLIED, 		1, 4, OopsCycle::NONE),
-		0x29 => (Instructions::AND, AddressingMode::IMMEDIATE, 		2, 2, OopsCycle::NONE),
-		0x2A => (Instructions::ROL, AddressingMode::ACCUMULATOR, 	1, 2, OopsCycle::NONE),
-		0x2C => (Instructions::BIT, AddressingMode::ABSOLUTE, 		3, 4, OopsCycle::NONE),
-		0x2D => (Instructions::AND, AddressingMode::ABSOLUTE, 		3, 4, OopsCycle::NONE),
-		0x2E => (Instructions::ROL, AddressingMode::ABSOLUTE, 		3, 6, OopsCycle::NONE),
-		0x30 => (Instructions::BMI, AddressingMode::RELATIVE, 		2, 2, OopsCycle::BranchOccursOn),
-		0x31 => (Instructions::AND, AddressingMode::INDIRECTY, 		2, 5, OopsCycle::PageBoundryCrossed),
-		0x35 => (Instructions::AND, AddressingMode::ZEROPAGEX, 		2, 4, OopsCycle::NONE),
-		0x36 => (Instructions::ROL, AddressingMode::ZEROPAGEX, 		2, 6, OopsCycle::NONE),
-		0x38 => (Instructions::SEC, AddressingMode::IMPLIED, 		1, 2, OopsCycle::NONE),
-		0x39 => (Instructions::AND, AddressingMode::ABSOLUTEY, 		3, 4, OopsCycle::PageBoundryCrossed),
-		0x3D => (Instructions::AND, AddressingMode::ABSOLUTEX, 		3, 4, OopsCycle::PageBoundryCrossed),
-		0x3E => (Instructions::ROL, AddressingMode::ABSOLUTEX, 		3, 7, OopsCycle::NONE),
-		0x40 => (Instructions::RTI, AddressingMode::IMMEDIATE, 		1, 6, OopsCycle::NONE),
-		0x41 => (Instructions::EOR, AddressingMode::INDIRECTX, 		2, 6, OopsCycle::NONE),
-		0x45 => (Instructions::EOR, AddressingMode::ZEROPAGE, 		2, 3, OopsCycle::NONE),
-		0x46 => (Instructions::LSR, AddressingMode::ZEROPAGE, 		2, 5, OopsCycle::NONE),
-		0x48 => (Instructions::PHA, AddressingMode::IMPLIED, 		1, 3, OopsCycle::NONE),
-		0x49 => (Instructions::EOR, AddressingMode::IMMEDIATE, 		2, 2, OopsCycle::NONE),
-		0x4A => (Instructions::LSR, AddressingMode::ACCUMULATOR, 	1, 2, OopsCycle::NONE),
-		0x4C => (Instructions::JMP, AddressingMode::ABSOLUTE, 		3, 3, OopsCycle::NONE),
-		0x4D => (Instructions::EOR, AddressingMode::ABSOLUTE, 		3, 4, OopsCycle::NONE),
-		0x4E => (Instructions::LSR, AddressingMode::ABSOLUTE, 		3, 6, OopsCycle::NONE),
-		0x50 => (Instructions::BVC, AddressingMode::RELATIVE, 		2, 2, OopsCycle::BranchOccursOn),
-		0x51 => (Instructions::EOR, AddressingMode::INDIRECTY, 		2, 5, OopsCycle::BranchOccursOn),
-		0x55 => (Instructions::EOR, AddressingMode::ZEROPAGEX, 		2, 4, OopsCycle::NONE),
-		0x56 => (Instructions::LSR, AddressingMode::ZEROPAGEX, 		2, 6, OopsCycle::NONE),
-		0x58 => (Instructions::CLI, AddressingMode::IMPLIED, 		1, 2, OopsCycle::NONE),
-		0x59 => (Instructions::EOR, AddressingMode::ABSOLUTEY, 		3, 4, OopsCycle::PageBoundryCrossed),
-		0x5D => (Instructions::EOR, AddressingMode::ABSOLUTEX, 		3, 4, OopsCycle::PageBoundryCrossed),
-		0x5E => (Instructions::LSR, AddressingMode::ABSOLUTEX, 		3, 7, OopsCycle::NONE),
-		0x60 => (Instructions::RTS, AddressingMode::IMPLIED, 		1, 6, OopsCycle::NONE),
-		0x61 => (Instructions::ADC, AddressingMode::INDIRECTX, 		2, 6, OopsCycle::NONE),
-		0x65 => (Instructions::ADC, AddressingMode::ZEROPAGE, 		2, 3, OopsCycle::NONE),
-		0x66 => (Instructions::ROR, AddressingMode::ZEROPAGE, 		2, 5, OopsCycle::NONE),
-		0x68 => (Instructions::PLA, AddressingMode::IMPLIED, 		1, 4, OopsCycle::NONE),
-		0x69 => (Instructions::ADC, AddressingMode::IMMEDIATE, 		2, 2, OopsCycle::NONE),
-		0x6A => (Instructions::ROR, AddressingMode::ACCUMULATOR, 	1, 2, OopsCycle::NONE),
-		0x6C => (Instructions::JMP, AddressingMode::INDIRECT, 		3, 5, OopsCycle::NONE),
-		0x6D => (Instructions::ADC, AddressingMode::ABSOLUTE, 		3, 4, OopsCycle::NONE),
-		0x6E => (Instructions::ROR, AddressingMode::ABSOLUTE, 		3, 6, OopsCycle::NONE),
-		0x70 => (Instructions::BVS, AddressingMode::RELATIVE, 		2, 2, OopsCycle::BranchOccursOn),
-		0x71 => (Instructions::ADC, AddressingMode::INDIRECTY, 		2, 5, OopsCycle::PageBoundryCrossed),
-		0x75 => (Instructions::ADC, AddressingMode::ZEROPAGEX, 		2, 4, OopsCycle::NONE),
-		0x76 => (Instructions::ROR, AddressingMode::ZEROPAGEX, 		2, 6, OopsCycle::NONE),
-		0x78 => (Instructions::SEI, AddressingMode::IMPLIED, 		1, 2, OopsCycle::NONE),
-		0x79 => (Instructions::ADC, AddressingMode::ABSOLUTEY, 		3, 4, OopsCycle::PageBoundryCrossed),
-		0x7D => (Instructions::ADC, AddressingMode::ABSOLUTEX, 		3, 4, OopsCycle::PageBoundryCrossed),
-		0x7E => (Instructions::ROR, AddressingMode::ABSOLUTEX, 		3, 7, OopsCycle::NONE),
-		0x81 => (Instructions::STA, AddressingMode::INDIRECTX, 		2, 6, OopsCycle::NONE),
-		0x84 => (Instructions::STY, AddressingMode::ZEROPAGE, 		2, 3, OopsCycle::NONE),
-		0x85 => (Instructions::STA, AddressingMode::ZEROPAGE, 		2, 3, OopsCycle::NONE),
-		0x86 => (Instructions::STX, AddressingMode::ZEROPAGE, 		2, 3, OopsCycle::NONE),
-		0x88 => (Instructions::DEY, AddressingMode::IMPLIED, 		1, 2, OopsCycle::NONE),
-		0x8A => (Instructions::TXA, AddressingMode::IMPLIED, 		1, 2, OopsCycle::NONE),
-		0x8C => (Instructions::STY, AddressingMode::ABSOLUTE, 		3, 4, OopsCycle::NONE),
-		0x8D => (Instructions::STA, AddressingMode::ABSOLUTE, 		3, 4, OopsCycle::NONE),
-		0x8E => (Instructions::STX, AddressingMode::ABSOLUTE, 		3, 4, OopsCycle::NONE),
-		0x90 => (Instructions::BCC, AddressingMode::RELATIVE, 		2, 2, OopsCycle::BranchOccursOn),
-		0x91 => (Instructions::STA, AddressingMode::INDIRECTY, 		2, 6, OopsCycle::NONE),
-		0x94 => (Instructions::STY, AddressingMode::ZEROPAGEX, 		2, 4, OopsCycle::NONE),
-		0x95 => (Instructions::STA, AddressingMode::ZEROPAGEX, 		2, 4, OopsCycle::NONE),
-		0x96 => (Instructions::STX, AddressingMode::ZEROPAGEY, 		2, 4, OopsCycle::NONE),
-		0x98 => (Instructions::TYA, AddressingMode::IMPLIED, 		1, 2, OopsCycle::NONE),
-		0x99 => (Instructions::STA, AddressingMode::ABSOLUTEY, 		3, 5, OopsCycle::NONE),
-		0x9A => (Instructions::TXS, AddressingMode::IMPLIED, 		1, 2, OopsCycle::NONE),
-		0x9D => (Instructions::STA, AddressingMode::ABSOLUTEX, 		3, 5, OopsCycle::NONE),
-		0xA0 => (Instructions::LDY, AddressingMode::IMMEDIATE, 		2, 2, OopsCycle::NONE),
-		0xA1 => (Instructions::LDA, AddressingMode::INDIRECTX, 		2, 6, OopsCycle::NONE),
-		0xA2 => (Instructions::LDX, AddressingMode::IMMEDIATE, 		2, 2, OopsCycle::NONE),
-		0xA4 => (Instructions::LDY, AddressingMode::ZEROPAGE, 		2, 3, OopsCycle::NONE),
-		0xA5 => (Instructions::LDA, AddressingMode::ZEROPAGE, 		2, 3, OopsCycle::NONE),
-		0xA6 => (Instructions::LDX, AddressingMode::ZEROPAGE, 		2, 3, OopsCycle::NONE),
-		0xA8 => (Instructions::TAY, AddressingMode::IMPLIED, 		1, 2, OopsCycle::NONE),
-		0xA9 => (Instructions::LDA, AddressingMode::IMMEDIATE, 		2, 2, OopsCycle::NONE),
-		0xAA => (Instructions::TAX, AddressingMode::IMPLIED, 		1, 2, OopsCycle::NONE),
-		0xAC => (Instructions::LDY, AddressingMode::ABSOLUTE, 		3, 4, OopsCycle::NONE),
-		0xAD => (Instructions::LDA, AddressingMode::ABSOLUTE, 		3, 4, OopsCycle::NONE),
-		0xAE => (Instructions::LDX, AddressingMode::ABSOLUTE, 		3, 4, OopsCycle::NONE),
-		0xB0 => (Instructions::BCS, AddressingMode::RELATIVE, 		2, 2, OopsCycle::BranchOccursOn),
-		0xB1 => (Instructions::LDA, AddressingMode::INDIRECTY, 		2, 5, OopsCycle::PageBoundryCrossed),
-		0xB4 => (Instructions::LDY, AddressingMode::ZEROPAGEX, 		2, 4, OopsCycle::NONE),
-		0xB5 => (Instructions::LDA, AddressingMode::ZEROPAGEX, 		2, 4, OopsCycle::NONE),
-		0xB6 => (Instructions::LDX, AddressingMode::ZEROPAGEY, 		2, 4, OopsCycle::NONE),
-		0xB8 => (Instructions::CLV, AddressingMode::IMPLIED, 		1, 2, OopsCycle::NONE),
-		0xB9 => (Instructions::LDA, AddressingMode::ABSOLUTEY, 		3, 4, OopsCycle::PageBoundryCrossed),
-		0xBA => (Instructions::TSX, AddressingMode::IMPLIED, 		1, 2, OopsCycle::NONE),
-		0xBC => (Instructions::LDY, AddressingMode::ABSOLUTEX, 		3, 4, OopsCycle::PageBoundryCrossed),
-		0xBD => (Instructions::LDA, AddressingMode::ABSOLUTEX, 		3, 4, OopsCycle::PageBoundryCrossed),
-		0xBE => (Instructions::LDX, AddressingMode::ABSOLUTEY, 		3, 4, OopsCycle::PageBoundryCrossed),
-		0xC0 => (Instructions::CPY, AddressingMode::IMMEDIATE, 		2, 2, OopsCycle::NONE),
-		0xC1 => (Instructions::CMP, AddressingMode::INDIRECTX, 		2, 6, OopsCycle::NONE),
-		0xC4 => (Instructions::CPY, AddressingMode::ZEROPAGE, 		2, 3, OopsCycle::NONE),
-		0xC5 => (Instructions::CMP, AddressingMode::ZEROPAGE, 		2, 3, OopsCycle::NONE),
-		0xC6 => (Instructions::DEC, AddressingMode::ZEROPAGE, 		2, 5, OopsCycle::NONE),
-		0xC8 => (Instructions::INY, AddressingMode::IMPLIED, 		1, 2, OopsCycle::NONE),
-		0xC9 => (Instructions::CMP, AddressingMode::IMMEDIATE, 		2, 2, OopsCycle::NONE),
-		0xCA => (Instructions::DEX, AddressingMode::IMPLIED, 		1, 2, OopsCycle::NONE),
-		0xCC => (Instructions::CPY, AddressingMode::ABSOLUTE, 		3, 4, OopsCycle::NONE),
-		0xCD => (Instructions::CMP, AddressingMode::ABSOLUTE, 		3, 4, OopsCycle::NONE),
-		0xCE => (Instructions::DEC, AddressingMode::ABSOLUTE, 		3, 6, OopsCycle::NONE),
-		0xD0 => (Instructions::BNE, AddressingMode::RELATIVE, 		2, 2, OopsCycle::BranchOccursOn),
-		0xD1 => (Instructions::CMP, AddressingMode::INDIRECTY, 		2, 5, OopsCycle::PageBoundryCrossed),
-		0xD5 => (Instructions::CMP, AddressingMode::ZEROPAGEX, 		2, 4, OopsCycle::NONE),
-		0xD6 => (Instructions::DEC, AddressingMode::ZEROPAGEX, 		2, 6, OopsCycle::NONE),
-		0xD8 => (Instructions::CLD, AddressingMode::IMPLIED, 		1, 2, OopsCycle::NONE),
-		0xD9 => (Instructions::CMP, AddressingMode::ABSOLUTEY, 		3, 4, OopsCycle::PageBoundryCrossed),
-		0xDD => (Instructions::CMP, AddressingMode::ABSOLUTEX, 		3, 4, OopsCycle::PageBoundryCrossed),
-		0xDE => (Instructions::DEC, AddressingMode::ABSOLUTEX, 		3, 7, OopsCycle::NONE),
-		0xE0 => (Instructions::CPX, AddressingMode::IMMEDIATE, 		2, 2, OopsCycle::NONE),
-		0xE1 => (Instructions::SBC, AddressingMode::INDIRECTX, 		2, 6, OopsCycle::NONE),
-		0xE4 => (Instructions::CPX, AddressingMode::ZEROPAGE, 		2, 3, OopsCycle::NONE),
-		0xE5 => (Instructions::SBC, AddressingMode::ZEROPAGE, 		2, 3, OopsCycle::NONE),
-		0xE6 => (Instructions::INC, AddressingMode::ZEROPAGE, 		2, 5, OopsCycle::NONE),
-		0xE8 => (Instructions::INX, AddressingMode::IMPLIED, 		1, 2, OopsCycle::NONE),
-		0xE9 => (Instructions::SBC, AddressingMode::IMMEDIATE, 		2, 2, OopsCycle::NONE),
-		0xEA => (Instructions::NOP, AddressingMode::IMPLIED, 		1, 2, OopsCycle::NONE),
-		0xEC => (Instructions::CPX, AddressingMode::ABSOLUTE, 		3, 4, OopsCycle::NONE),
-		0xED => (Instructions::SBC, AddressingMode::ABSOLUTE, 		3, 4, OopsCycle::NONE),
-		0xEE => (Instructions::INC, AddressingMode::ABSOLUTE, 		3, 6, OopsCycle::NONE),
-		0xF0 => (Instructions::BEQ, AddressingMode::RELATIVE, 		2, 2, OopsCycle::BranchOccursOn),
-		0xF1 => (Instructions::SBC, AddressingMode::INDIRECTY, 		2, 5, OopsCycle::PageBoundryCrossed),
-		0xF5 => (Instructions::SBC, AddressingMode::ZEROPAGEX, 		2, 4, OopsCycle::NONE),
-		0xF6 => (Instructions::INC, AddressingMode::ZEROPAGEX, 		2, 6, OopsCycle::NONE),
-		0xF8 => (Instructions::SED, AddressingMode::IMPLIED, 		1, 2, OopsCycle::NONE),
-		0xF9 => (Instructions::SBC, AddressingMode::ABSOLUTEY, 		3, 4, OopsCycle::PageBoundryCrossed),
-		0xFD => (Instructions::SBC, AddressingMode::ABSOLUTEX, 		3, 4, OopsCycle::PageBoundryCrossed),
-		0xFE => (Instructions::INC, AddressingMode::ABSOLUTEX, 		3, 7, OopsCycle::NONE),
-		_ => {
-			//TODO: For now we panic, but we must handle this later. What happens when illegal instruction is called in real NES?
-			error!("Could not decode instruction, opcode: {:#X}", opcode);
-			panic!();
-		}
+/// Returns the Instruction (like in assembly), Addressing Mode, Bytes, Cycles. None for the opcodes the emulator doesn't
+/// know (illegal opcodes).
+pub fn decode_opcode(opcode: u8) -> Option<Decoded> {
+	OPCODES[opcode as usize]
+}
 
+#[cfg(test)]
+mod tests {
+	use super::{decode_opcode, AddressingMode, Instructions, OPCODES};
+
+	#[test]
+	fn test_decode_opcode() {
+		assert!(matches!(decode_opcode(0xA9), Some((Instructions::LDA, AddressingMode::IMMEDIATE, 2, 2, _))));
+		assert!(matches!(decode_opcode(0x40), Some((Instructions::RTI, AddressingMode::IMPLIED, 1, 6, _))));
+		assert!(decode_opcode(0x03).is_none());
+		// The official opcodes and 12 KIL
+		assert_eq!(OPCODES.iter().flatten().count(), 151 + 12);
 	}
-}	
+}
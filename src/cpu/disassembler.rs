@@ -0,0 +1,47 @@
+use crate::cpu::decoder::{AddressingMode, Instructions};
+
+/// Disassemble a decoded instruction, in 6502 assembler syntax (e.g. `LDA $0200,X`). `operand` are the bytes after the
+/// opcode (little endian), `pc` is the address of the opcode, to resolve the target of branches.
+pub fn disassemble(instr: &Instructions, addrmode: &AddressingMode, pc: u16, operand: &[u8]) -> String {
+	let byte = operand.first().copied().unwrap_or(0);
+	let word = u16::from_le_bytes([byte, operand.get(1).copied().unwrap_or(0)]);
+	let operand = match addrmode {
+		AddressingMode::IMPLIED => String::new(),
+		AddressingMode::ACCUMULATOR => "A".to_string(),
+		AddressingMode::IMMEDIATE => format!("#${:02X}", byte),
+		AddressingMode::ZEROPAGE => format!("${:02X}", byte),
+		AddressingMode::ZEROPAGEX => format!("${:02X},X", byte),
+		AddressingMode::ZEROPAGEY => format!("${:02X},Y", byte),
+		AddressingMode::ABSOLUTE => format!("${:04X}", word),
+		AddressingMode::ABSOLUTEX => format!("${:04X},X", word),
+		AddressingMode::ABSOLUTEY => format!("${:04X},Y", word),
+		AddressingMode::INDIRECT => format!("(${:04X})", word),
+		AddressingMode::INDIRECTX => format!("(${:02X},X)", byte),
+		AddressingMode::INDIRECTY => format!("(${:02X}),Y", byte),
+		// The offset is from the next instruction
+		AddressingMode::RELATIVE => format!("${:04X}", pc.wrapping_add(2).wrapping_add(byte as i8 as u16)),
+	};
+	if operand.is_empty() {
+		format!("{:?}", instr)
+	} else {
+		format!("{:?} {}", instr, operand)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::disassemble;
+	use crate::cpu::decoder::{AddressingMode, Instructions};
+
+	#[test]
+	fn test_disassemble() {
+		assert_eq!(disassemble(&Instructions::NOP, &AddressingMode::IMPLIED, 0x8000, &[]), "NOP");
+		assert_eq!(disassemble(&Instructions::ASL, &AddressingMode::ACCUMULATOR, 0x8000, &[]), "ASL A");
+		assert_eq!(disassemble(&Instructions::LDA, &AddressingMode::IMMEDIATE, 0x8000, &[0x0F]), "LDA #$0F");
+		assert_eq!(disassemble(&Instructions::STA, &AddressingMode::ABSOLUTEX, 0x8000, &[0x00, 0x02]), "STA $0200,X");
+		assert_eq!(disassemble(&Instructions::LDA, &AddressingMode::INDIRECTY, 0x8000, &[0x10]), "LDA ($10),Y");
+		assert_eq!(disassemble(&Instructions::JMP, &AddressingMode::INDIRECT, 0x8000, &[0xFC, 0xFF]), "JMP ($FFFC)");
+		// Backwards branch: $8010 + 2 - 4
+		assert_eq!(disassemble(&Instructions::BNE, &AddressingMode::RELATIVE, 0x8010, &[0xFC]), "BNE $800E");
+	}
+}
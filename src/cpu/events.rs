@@ -0,0 +1,86 @@
+use std::fmt;
+
+/// Read or write.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessKind {
+	Read,
+	Write,
+}
+
+/// A CPU access to a PPU/IO register, and where the PPU beam was when it happened.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BusEvent {
+	pub scanline: u16,
+	pub dot: u16,
+	pub addr: u16,
+	pub value: u8,
+	pub kind: AccessKind,
+}
+
+impl BusEvent {
+	/// The register address without the mirrors ($3456 is $2006).
+	pub fn register(&self) -> u16 {
+		match self.addr {
+			0x2000..=0x3FFF => 0x2000 | (self.addr & 7),
+			_ => self.addr,
+		}
+	}
+}
+
+impl fmt::Display for BusEvent {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let kind = match self.kind {
+			AccessKind::Read => "R",
+			AccessKind::Write => "W",
+		};
+		write!(f, "Scanline: {:3}, dot: {:3}, {} ${:04X} = ${:02X}", self.scanline, self.dot, kind, self.addr, self.value)
+	}
+}
+
+/// Log of the register accesses ($2000-$2007 and mirrors, $4014, $4016) of each frame, for the event viewer.
+/// Like the event viewer of FCEUX or Mesen, it shows when in the frame a game touches the PPU.
+#[derive(Clone)]
+pub struct EventLog {
+	current_frame: Vec<BusEvent>,
+	last_frame: Vec<BusEvent>,
+}
+
+impl Default for EventLog {
+	fn default() -> Self {
+		EventLog::new()
+	}
+}
+
+impl EventLog {
+	pub fn new() -> Self {
+		EventLog {
+			current_frame: vec![],
+			last_frame: vec![],
+		}
+	}
+
+	/// Is the address one of the registers we log.
+	pub fn is_logged(addr: u16) -> bool {
+		matches!(addr, 0x2000..=0x3FFF | 0x4014 | 0x4016)
+	}
+
+	pub fn record(&mut self, event: BusEvent) {
+		self.current_frame.push(event);
+	}
+
+	/// Called when the PPU completes a frame. Reuses the buffers, so logging doesn't allocate each frame.
+	pub fn end_frame(&mut self) {
+		std::mem::swap(&mut self.last_frame, &mut self.current_frame);
+		self.current_frame.clear();
+	}
+
+	/// The events of the last completed frame, in order.
+	pub fn last_frame(&self) -> &[BusEvent] {
+		&self.last_frame
+	}
+
+	/// The events of the frame in progress, up to now.
+	pub fn current_frame(&self) -> &[BusEvent] {
+		&self.current_frame
+	}
+}
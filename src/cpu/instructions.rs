@@ -0,0 +1,633 @@
+//! The instructions, a handler function each. `HANDLERS` has the handler of every opcode, next to the decoder's
+//! `OPCODES`, so the CPU calls it without matching the instruction. Adding an instruction is a handler and a line in
+//! `handler`.
+// https://www.masswerk.at/6502/6502_instruction_set.html
+
+use crate::cpu::cpu::CPU;
+use crate::cpu::decoder::{AddressingMode, Instructions, Operand, OPCODES};
+use crate::cpu::registers::ProcessorStatusBits;
+
+/// Executes an instruction in an addressing mode. The CPU moves the PC past the instruction afterwards, unless it
+/// jumps.
+pub type Handler = fn(&mut CPU, AddressingMode);
+
+/// The handler of each opcode, the opcodes the decoder doesn't know run as NOP.
+pub const HANDLERS: [Handler; 256] = {
+	let mut table: [Handler; 256] = [nop; 256];
+	let mut opcode = 0;
+	while opcode < 256 {
+		if let Some((instr, ..)) = OPCODES[opcode] {
+			table[opcode] = handler(instr);
+		}
+		opcode += 1;
+	}
+	table
+};
+
+const fn handler(instr: Instructions) -> Handler {
+	match instr {
+		Instructions::ADC => adc,
+		Instructions::AND => and,
+		Instructions::ASL => asl,
+		Instructions::BCC => bcc,
+		Instructions::BCS => bcs,
+		Instructions::BEQ => beq,
+		Instructions::BIT => bit,
+		Instructions::BMI => bmi,
+		Instructions::BNE => bne,
+		Instructions::BPL => bpl,
+		Instructions::BRK => brk,
+		Instructions::BVC => bvc,
+		Instructions::BVS => bvs,
+		Instructions::CLC => clc,
+		Instructions::CLD => cld,
+		Instructions::CLI => cli,
+		Instructions::CLV => clv,
+		Instructions::CMP => cmp,
+		Instructions::CPX => cpx,
+		Instructions::CPY => cpy,
+		Instructions::DEC => dec,
+		Instructions::DEX => dex,
+		Instructions::DEY => dey,
+		Instructions::EOR => eor,
+		Instructions::INC => inc,
+		Instructions::INX => inx,
+		Instructions::INY => iny,
+		Instructions::JMP => jmp,
+		Instructions::JSR => jsr,
+		Instructions::KIL => nop,	// Halted by clock_tick
+		Instructions::LDA => lda,
+		Instructions::LDX => ldx,
+		Instructions::LDY => ldy,
+		Instructions::LSR => lsr,
+		Instructions::NOP => nop,
+		Instructions::ORA => ora,
+		Instructions::PHA => pha,
+		Instructions::PHP => php,
+		Instructions::PLA => pla,
+		Instructions::PLP => plp,
+		Instructions::ROL => rol,
+		Instructions::ROR => ror,
+		Instructions::RTI => rti,
+		Instructions::RTS => rts,
+		Instructions::SBC => sbc,
+		Instructions::SEC => sec,
+		Instructions::SED => sed,
+		Instructions::SEI => sei,
+		Instructions::STA => sta,
+		Instructions::STX => stx,
+		Instructions::STY => sty,
+		Instructions::TAX => tax,
+		Instructions::TAY => tay,
+		Instructions::TSX => tsx,
+		Instructions::TXA => txa,
+		Instructions::TXS => txs,
+		Instructions::TYA => tya,
+	}
+}
+
+fn nop(_cpu: &mut CPU, _addrmode: AddressingMode) {
+	// No Operation
+}
+
+/// The operand of the instruction and its value.
+fn fetch(cpu: &mut CPU, addrmode: AddressingMode) -> Option<(Operand, u8)> {
+	let operand = cpu.resolve_operand(addrmode)?;
+	Some((operand, cpu.read_operand(operand)))
+}
+
+/// Set N and Z of the value.
+fn set_nz(cpu: &mut CPU, value: u8) {
+	cpu.registers.P.modify_n(value);
+	cpu.registers.P.modify_z(value);
+}
+
+/// Load Accumulator with Memory
+/// M -> A
+fn lda(cpu: &mut CPU, addrmode: AddressingMode) {
+	let Some((_, value)) = fetch(cpu, addrmode) else { return };
+	cpu.registers.A = value;
+	set_nz(cpu, value);
+}
+
+/// Load Index X with Memory
+/// M -> X
+fn ldx(cpu: &mut CPU, addrmode: AddressingMode) {
+	let Some((_, value)) = fetch(cpu, addrmode) else { return };
+	cpu.registers.X = value;
+	set_nz(cpu, value);
+}
+
+/// Load Index Y with Memory
+/// M -> Y
+fn ldy(cpu: &mut CPU, addrmode: AddressingMode) {
+	let Some((_, value)) = fetch(cpu, addrmode) else { return };
+	cpu.registers.Y = value;
+	set_nz(cpu, value);
+}
+
+/// Store Accumulator in Memory
+/// A -> M
+fn sta(cpu: &mut CPU, addrmode: AddressingMode) {
+	store(cpu, addrmode, cpu.registers.A);
+}
+
+/// Store Index X in Memory
+/// X -> M
+fn stx(cpu: &mut CPU, addrmode: AddressingMode) {
+	store(cpu, addrmode, cpu.registers.X);
+}
+
+/// Store Index Y in Memory
+/// Y -> M
+fn sty(cpu: &mut CPU, addrmode: AddressingMode) {
+	store(cpu, addrmode, cpu.registers.Y);
+}
+
+fn store(cpu: &mut CPU, addrmode: AddressingMode, value: u8) {
+	let Some(operand) = cpu.resolve_operand(addrmode) else { return };
+	cpu.write_operand(operand, value);
+}
+
+/// Push Accumulator on Stack
+/// push A
+fn pha(cpu: &mut CPU, _addrmode: AddressingMode) {
+	cpu.push_stack(cpu.registers.A);
+}
+
+/// Pull Accumulator from Stack
+/// pull A
+fn pla(cpu: &mut CPU, _addrmode: AddressingMode) {
+	cpu.registers.A = cpu.pop_stack();
+	set_nz(cpu, cpu.registers.A);
+}
+
+/// Push Processor Status on Stack
+/// The status register will be pushed with the break flag and bit 5 set to 1.
+/// push SR
+fn php(cpu: &mut CPU, _addrmode: AddressingMode) {
+	let flags = cpu.registers.P.flags | 0b0011_0000;
+	cpu.push_stack(flags);
+}
+
+/// Pull Processor Status from Stack
+/// The status register will be pulled with the break flag and bit 5 ignored.
+/// pull SR
+fn plp(cpu: &mut CPU, _addrmode: AddressingMode) {
+	let p_flags = cpu.pop_stack();
+	cpu.registers.P.flags = (p_flags & 0b1100_1111) | (cpu.registers.P.flags & 0b0011_0000);
+}
+
+/// Set Carry Flag
+fn sec(cpu: &mut CPU, _addrmode: AddressingMode) {
+	cpu.registers.P.set(ProcessorStatusBits::CARRY, true);
+}
+
+/// Clear Carry Flag
+fn clc(cpu: &mut CPU, _addrmode: AddressingMode) {
+	cpu.registers.P.set(ProcessorStatusBits::CARRY, false);
+}
+
+/// Set Decimal Flag
+fn sed(cpu: &mut CPU, _addrmode: AddressingMode) {
+	cpu.registers.P.set(ProcessorStatusBits::DECIMAL, true);
+}
+
+/// Clear Decimal Mode
+fn cld(cpu: &mut CPU, _addrmode: AddressingMode) {
+	cpu.registers.P.set(ProcessorStatusBits::DECIMAL, false);
+}
+
+/// Set Interrupt Disable Status
+fn sei(cpu: &mut CPU, _addrmode: AddressingMode) {
+	cpu.registers.P.set(ProcessorStatusBits::InterruptDisable, true);
+}
+
+/// Clear Interrupt Disable Bit
+fn cli(cpu: &mut CPU, _addrmode: AddressingMode) {
+	cpu.registers.P.set(ProcessorStatusBits::InterruptDisable, false);
+}
+
+/// Clear Overflow Flag
+fn clv(cpu: &mut CPU, _addrmode: AddressingMode) {
+	cpu.registers.P.set(ProcessorStatusBits::OVERFLOW, false);
+}
+
+/// Add Memory to Accumulator with Carry
+/// A + M + C -> A, C
+fn adc(cpu: &mut CPU, addrmode: AddressingMode) {
+	let Some((_, m)) = fetch(cpu, addrmode) else { return };
+	add_with_carry(cpu, m);
+}
+
+/// Subtract Memory from Accumulator with Borrow
+/// A - M - C̅ -> A
+fn sbc(cpu: &mut CPU, addrmode: AddressingMode) {
+	// The carry is the inverted borrow, so A - M - (1 - C) = A + !M + C
+	let Some((_, m)) = fetch(cpu, addrmode) else { return };
+	add_with_carry(cpu, !m);
+}
+
+fn add_with_carry(cpu: &mut CPU, m: u8) {
+	// NOTE: This is the first instruction that actually does 'complex' arithmetic
+	// After reading a lot of forums, its actually the most complex thing to emulate, I must understand this
+	let a = cpu.registers.A;
+	let carry: u8 = cpu.registers.P.get(ProcessorStatusBits::CARRY) as u8;
+
+	// Carry flag: Only for unsigned. If result is > 255, carry is set.
+	// Overflow flag: Only if (Positive+Positive=Negative) or (Negative+Negative=Positive)
+
+	// Perform regular unsigned addition, allowing arithmetic overflow.
+	let first_addition = a.overflowing_add(m);
+	let second_addition = first_addition.0.overflowing_add(carry);
+	let result = second_addition.0;
+
+	// Set A register. The 2A03 has no decimal mode: the D flag can be set, but the addition is binary.
+	cpu.registers.A = result;
+
+	// Set carry accordingly.
+	let new_carry = first_addition.1 || second_addition.1;
+
+	// Set overflow accordingly.
+	let is_a_negative = (a >> 7) == 1;
+	let is_m_negative = (m >> 7) == 1;
+	let is_result_negative = (result >> 7) == 1;
+	let new_overflow =
+		(is_a_negative 				&& is_m_negative 			&& !is_result_negative	) ||
+		(!is_a_negative			 	&& !is_m_negative 			&& is_result_negative	);
+
+	set_nz(cpu, result);
+	cpu.registers.P.set(ProcessorStatusBits::CARRY, new_carry);
+	cpu.registers.P.set(ProcessorStatusBits::OVERFLOW, new_overflow);
+}
+
+/// Increment Index X by One
+/// X + 1 -> X
+fn inx(cpu: &mut CPU, _addrmode: AddressingMode) {
+	cpu.registers.X = cpu.registers.X.wrapping_add(1);
+	set_nz(cpu, cpu.registers.X);
+}
+
+/// Increment Index Y by One
+/// Y + 1 -> Y
+fn iny(cpu: &mut CPU, _addrmode: AddressingMode) {
+	cpu.registers.Y = cpu.registers.Y.wrapping_add(1);
+	set_nz(cpu, cpu.registers.Y);
+}
+
+/// Decrement Index X by One
+/// X - 1 -> X
+fn dex(cpu: &mut CPU, _addrmode: AddressingMode) {
+	cpu.registers.X = cpu.registers.X.wrapping_sub(1);
+	set_nz(cpu, cpu.registers.X);
+}
+
+/// Decrement Index Y by One
+/// Y - 1 -> Y
+fn dey(cpu: &mut CPU, _addrmode: AddressingMode) {
+	cpu.registers.Y = cpu.registers.Y.wrapping_sub(1);
+	set_nz(cpu, cpu.registers.Y);
+}
+
+/// Increment Memory by One
+/// M + 1 -> M
+fn inc(cpu: &mut CPU, addrmode: AddressingMode) {
+	read_modify_write(cpu, addrmode, |m| m.wrapping_add(1));
+}
+
+/// Decrement Memory by One
+/// M - 1 -> M
+fn dec(cpu: &mut CPU, addrmode: AddressingMode) {
+	read_modify_write(cpu, addrmode, |m| m.wrapping_sub(1));
+}
+
+fn read_modify_write(cpu: &mut CPU, addrmode: AddressingMode, modify: fn(u8) -> u8) {
+	let Some((operand, fetched_memory)) = fetch(cpu, addrmode) else { return };
+	let new_memory = modify(fetched_memory);
+	cpu.write_operand(operand, new_memory);
+	set_nz(cpu, new_memory);
+}
+
+/// Jump to New Location
+/// (PC+1) -> PCL
+/// (PC+2) -> PCH
+fn jmp(cpu: &mut CPU, addrmode: AddressingMode) {
+	if let Some(Operand::Memory { addr, .. }) = cpu.resolve_operand(addrmode) {
+		cpu.registers.PC = addr;
+	}
+}
+
+/// Jump to New Location Saving Return Address
+/// push (PC+2),
+/// (PC+1) -> PCL
+/// (PC+2) -> PCH
+fn jsr(cpu: &mut CPU, addrmode: AddressingMode) {
+	// What order of bytes to push?
+	// After a lot of googling: https://stackoverflow.com/a/63886154
+	// Basically push the PC like so: "...You need to push the high byte first, and then the low byte."
+
+	// Push PC onto stack (return address)
+	// NOTE: I push the 3rd byte of the instruction (PC + 2). Why not PC+3 (next instruction)?
+	// Idk, but its important to emulate this exactly, because some games use this feature.
+	cpu.push_pc(2);
+
+	// Jump to the address operand
+	jmp(cpu, addrmode);
+}
+
+/// Compare Memory with Accumulator
+/// A - M
+fn cmp(cpu: &mut CPU, addrmode: AddressingMode) {
+	cpu.exec_cmp(addrmode, cpu.registers.A);
+}
+
+/// Compare Memory and Index X
+/// X - M
+fn cpx(cpu: &mut CPU, addrmode: AddressingMode) {
+	cpu.exec_cmp(addrmode, cpu.registers.X);
+}
+
+/// Compare Memory and Index Y
+/// Y - M
+fn cpy(cpu: &mut CPU, addrmode: AddressingMode) {
+	cpu.exec_cmp(addrmode, cpu.registers.Y);
+}
+
+/// Transfer Accumulator to Index X
+/// A -> X
+fn tax(cpu: &mut CPU, _addrmode: AddressingMode) {
+	cpu.registers.X = cpu.registers.A;
+	set_nz(cpu, cpu.registers.X);
+}
+
+/// Transfer Accumulator to Index Y
+/// A -> Y
+fn tay(cpu: &mut CPU, _addrmode: AddressingMode) {
+	cpu.registers.Y = cpu.registers.A;
+	set_nz(cpu, cpu.registers.Y);
+}
+
+/// Transfer Stack Pointer to Index X
+/// SP -> X
+fn tsx(cpu: &mut CPU, _addrmode: AddressingMode) {
+	cpu.registers.X = cpu.registers.S;
+	set_nz(cpu, cpu.registers.X);
+}
+
+/// Transfer Index X to Accumulator
+/// X -> A
+fn txa(cpu: &mut CPU, _addrmode: AddressingMode) {
+	cpu.registers.A = cpu.registers.X;
+	set_nz(cpu, cpu.registers.A);
+}
+
+/// Transfer Index X to Stack Register
+/// X -> SP
+fn txs(cpu: &mut CPU, _addrmode: AddressingMode) {
+	cpu.registers.S = cpu.registers.X;
+	// We don't modify N or Z.
+}
+
+/// Transfer Index Y to Accumulator
+/// Y -> A
+fn tya(cpu: &mut CPU, _addrmode: AddressingMode) {
+	cpu.registers.A = cpu.registers.Y;
+	set_nz(cpu, cpu.registers.A);
+}
+
+/// AND Memory with Accumulator
+/// A AND M -> A
+fn and(cpu: &mut CPU, addrmode: AddressingMode) {
+	let Some((_, value)) = fetch(cpu, addrmode) else { return };
+	cpu.registers.A &= value;
+	set_nz(cpu, cpu.registers.A);
+}
+
+/// Exclusive-OR Memory with Accumulator
+/// A EOR M -> A
+fn eor(cpu: &mut CPU, addrmode: AddressingMode) {
+	let Some((_, value)) = fetch(cpu, addrmode) else { return };
+	cpu.registers.A ^= value;
+	set_nz(cpu, cpu.registers.A);
+}
+
+/// OR Memory with Accumulator
+/// A OR M -> A
+fn ora(cpu: &mut CPU, addrmode: AddressingMode) {
+	let Some((_, value)) = fetch(cpu, addrmode) else { return };
+	cpu.registers.A |= value;
+	set_nz(cpu, cpu.registers.A);
+}
+
+/// Shift Left One Bit (Memory or Accumulator)
+/// C <- [76543210] <- 0
+fn asl(cpu: &mut CPU, addrmode: AddressingMode) {
+	shift(cpu, addrmode, |m, _| (m << 1, m >> 7 == 1));
+}
+
+/// Shift One Bit Right (Memory or Accumulator)
+/// 0 -> [76543210] -> C
+fn lsr(cpu: &mut CPU, addrmode: AddressingMode) {
+	shift(cpu, addrmode, |m, _| (m >> 1, m & 1 == 1));
+}
+
+/// Rotate One Bit Left (Memory or Accumulator)
+/// C <- [76543210] <- C
+fn rol(cpu: &mut CPU, addrmode: AddressingMode) {
+	shift(cpu, addrmode, |m, carry| ((m << 1) | carry as u8, m >> 7 == 1));
+}
+
+/// Rotate One Bit Right (Memory or Accumulator)
+/// C -> [76543210] -> C
+fn ror(cpu: &mut CPU, addrmode: AddressingMode) {
+	shift(cpu, addrmode, |m, carry| ((m >> 1) | ((carry as u8) << 7), m & 1 == 1));
+}
+
+/// Shift or rotate: the result and the bit shifted out (the new carry), of the memory and the carry.
+fn shift(cpu: &mut CPU, addrmode: AddressingMode, shift: fn(u8, bool) -> (u8, bool)) {
+	// Memory can be register.
+	let Some((operand, fetched_memory)) = fetch(cpu, addrmode) else { return };
+	let (result, new_carry) = shift(fetched_memory, cpu.registers.P.get(ProcessorStatusBits::CARRY));
+
+	// Back to the register or memory.
+	cpu.write_operand(operand, result);
+
+	set_nz(cpu, result);
+	cpu.registers.P.set(ProcessorStatusBits::CARRY, new_carry);
+}
+
+/// Test Bits in Memory with Accumulator
+/// A AND M, M7 -> N, M6 -> V
+fn bit(cpu: &mut CPU, addrmode: AddressingMode) {
+	// bits 7 and 6 of operand are transfered to bit 7 and 6 of SR (N,V);
+	// the zero-flag is set to the result of operand AND accumulator.
+	let Some((_, fetched_memory)) = fetch(cpu, addrmode) else { return };
+	let result = cpu.registers.A & fetched_memory;
+	cpu.registers.P.set(ProcessorStatusBits::NEGATIVE, (fetched_memory >> 7) == 1);
+	cpu.registers.P.set(ProcessorStatusBits::OVERFLOW, ((fetched_memory >> 6) & 1) == 1);
+	cpu.registers.P.modify_z(result);
+}
+
+/// Branch on Result Minus
+/// branch on N = 1
+fn bmi(cpu: &mut CPU, _addrmode: AddressingMode) {
+	branch(cpu, ProcessorStatusBits::NEGATIVE, true);
+}
+
+/// Branch on Result Plus
+/// branch on N = 0
+fn bpl(cpu: &mut CPU, _addrmode: AddressingMode) {
+	branch(cpu, ProcessorStatusBits::NEGATIVE, false);
+}
+
+/// Branch on Result not Zero
+/// branch on Z = 0
+fn bne(cpu: &mut CPU, _addrmode: AddressingMode) {
+	branch(cpu, ProcessorStatusBits::ZERO, false);
+}
+
+/// Branch on Result Zero
+/// branch on Z = 1
+fn beq(cpu: &mut CPU, _addrmode: AddressingMode) {
+	branch(cpu, ProcessorStatusBits::ZERO, true);
+}
+
+/// Branch on Overflow Clear
+/// branch on V = 0
+fn bvc(cpu: &mut CPU, _addrmode: AddressingMode) {
+	branch(cpu, ProcessorStatusBits::OVERFLOW, false);
+}
+
+/// Branch on Overflow Set
+/// branch on V = 1
+fn bvs(cpu: &mut CPU, _addrmode: AddressingMode) {
+	branch(cpu, ProcessorStatusBits::OVERFLOW, true);
+}
+
+/// Branch on Carry Set
+/// branch on C = 1
+fn bcs(cpu: &mut CPU, _addrmode: AddressingMode) {
+	branch(cpu, ProcessorStatusBits::CARRY, true);
+}
+
+/// Branch on Carry Clear
+/// branch on C = 0
+fn bcc(cpu: &mut CPU, _addrmode: AddressingMode) {
+	branch(cpu, ProcessorStatusBits::CARRY, false);
+}
+
+fn branch(cpu: &mut CPU, flag: ProcessorStatusBits, taken_when: bool) {
+	if cpu.registers.P.get(flag) == taken_when {
+		cpu.registers.PC = cpu.read_instruction_relative_address();
+	}
+}
+
+/// Force Break
+/// BRK initiates a software interrupt similar to a hardware interrupt (IRQ). The return address pushed to the stack is
+/// PC+2, providing an extra byte of spacing for a break mark (identifying a reason for the break.)
+/// The status register will be pushed to the stack with the break flag set to 1. However, when retrieved during RTI or
+/// by a PLP instruction, the break flag will be ignored.
+/// interrupt, push PC+2, push SR
+fn brk(cpu: &mut CPU, _addrmode: AddressingMode) {
+	cpu.push_pc(2);
+	php(cpu, AddressingMode::IMPLIED);
+	cpu.registers.P.set(ProcessorStatusBits::InterruptDisable, true);
+	cpu.registers.PC = cpu.read_address_from_memory(0xFFFE);
+}
+
+/// Return from Interrupt
+/// The status register is pulled with the break flag and bit 5 ignored. Then PC is pulled from the stack.
+/// pull SR, pull PC
+fn rti(cpu: &mut CPU, _addrmode: AddressingMode) {
+	plp(cpu, AddressingMode::IMPLIED);
+	cpu.registers.PC = cpu.pop_pc();
+}
+
+/// Return from Subroutine
+/// pull PC, PC+1 -> PC
+fn rts(cpu: &mut CPU, _addrmode: AddressingMode) {
+	// JSR pushed the last byte of the JSR, the CPU moves past RTS (1 byte) to the next instruction
+	cpu.registers.PC = cpu.pop_pc();
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{adc, asl, bcc, bne, inc, tax, HANDLERS};
+	use crate::cpu::cpu::CPU;
+	use crate::cpu::decoder::{AddressingMode, Operand};
+	use crate::cpu::registers::ProcessorStatusBits;
+	use crate::{cartridge::Cartridge, ppu::ppu::PPU};
+
+	/// A CPU with the instruction operand at $8001 (PC is at $8000).
+	fn new_cpu(operand: &[u8]) -> CPU {
+		let cartridge = Cartridge::new();
+		let ppu = PPU::new(&cartridge);
+		let mut cpu = CPU::new(cartridge, ppu);
+		cpu.registers.PC = 0x8000;
+		for (i, &byte) in operand.iter().enumerate() {
+			cpu.poke_memory(0x8001 + i as u16, byte);
+		}
+		cpu
+	}
+
+	#[test]
+	fn test_handlers() {
+		// $7F + $01 overflows to negative
+		let mut cpu = new_cpu(&[0x01]);
+		cpu.registers.A = 0x7F;
+		adc(&mut cpu, AddressingMode::IMMEDIATE);
+		assert_eq!(cpu.registers.A, 0x80);
+		assert!(cpu.registers.P.get(ProcessorStatusBits::OVERFLOW) && cpu.registers.P.get(ProcessorStatusBits::NEGATIVE));
+		assert!(!cpu.registers.P.get(ProcessorStatusBits::CARRY));
+
+		cpu.registers.A = 0;
+		tax(&mut cpu, AddressingMode::IMPLIED);
+		assert!(cpu.registers.X == 0 && cpu.registers.P.get(ProcessorStatusBits::ZERO));
+
+		// INC $10 wraps
+		let mut cpu = new_cpu(&[0x10]);
+		cpu.poke_memory(0x0010, 0xFF);
+		inc(&mut cpu, AddressingMode::ZEROPAGE);
+		assert_eq!(cpu.peek_memory(0x0010), 0);
+		assert!(cpu.registers.P.get(ProcessorStatusBits::ZERO));
+
+		// Branches are relative to the branch
+		let mut cpu = new_cpu(&[0xFE]);
+		cpu.registers.P.set(ProcessorStatusBits::CARRY, true);
+		bcc(&mut cpu, AddressingMode::RELATIVE);
+		assert_eq!(cpu.registers.PC, 0x8000);
+		cpu.registers.P.set(ProcessorStatusBits::ZERO, false);
+		bne(&mut cpu, AddressingMode::RELATIVE);
+		assert_eq!(cpu.registers.PC, 0x7FFE);
+
+		// LDA #$42 through the table
+		let mut cpu = new_cpu(&[0x42]);
+		HANDLERS[0xA9](&mut cpu, AddressingMode::IMMEDIATE);
+		assert_eq!(cpu.registers.A, 0x42);
+	}
+
+	#[test]
+	fn test_resolve_operand() {
+		let mut cpu = new_cpu(&[0xF0, 0x02]);
+		cpu.registers.X = 0x20;
+		assert_eq!(cpu.resolve_operand(AddressingMode::ABSOLUTEX), Some(Operand::Memory { addr: 0x0310, page_crossed: true }));
+		assert_eq!(cpu.resolve_operand(AddressingMode::ABSOLUTE), Some(Operand::Memory { addr: 0x02F0, page_crossed: false }));
+		assert_eq!(cpu.resolve_operand(AddressingMode::ZEROPAGEX), Some(Operand::zero_page(0x10, 0)));
+		assert_eq!(cpu.resolve_operand(AddressingMode::IMMEDIATE), Some(Operand::Immediate(0xF0)));
+		assert_eq!(cpu.resolve_operand(AddressingMode::ACCUMULATOR), Some(Operand::Accumulator));
+
+		// ASL A writes back to A, STA $F0,X to the zero page
+		cpu.registers.A = 0xC1;
+		asl(&mut cpu, AddressingMode::ACCUMULATOR);
+		assert_eq!(cpu.registers.A, 0x82);
+		HANDLERS[0x95](&mut cpu, AddressingMode::ZEROPAGEX);
+		assert_eq!(cpu.peek_memory(0x0010), 0x82);
+
+		// JMP ($02FF) reads the high byte at $0200, not $0300
+		let mut cpu = new_cpu(&[0xFF, 0x02]);
+		cpu.poke_memory(0x02FF, 0x34);
+		cpu.poke_memory(0x0200, 0x12);
+		cpu.poke_memory(0x0300, 0x56);
+		assert_eq!(cpu.resolve_operand(AddressingMode::INDIRECT), Some(Operand::Memory { addr: 0x1234, page_crossed: false }));
+	}
+}
@@ -0,0 +1,100 @@
+//! Logging of the CPU memory accesses, by a filter of addresses: `writes 2000-2007, reads 4016` logs the writes to the
+//! PPU registers and the reads of the first controller port, nothing else. For debugging a game without adding
+//! `println!`s to the bus.
+//!
+//! The filter is clauses separated by `,` or `;`: `reads`, `writes` or `accesses` (both), then addresses and ranges in
+//! hex (`2000`, `$4016`, `2000-2007`). The filter is compiled to a bit per address for reads and for writes, so the bus
+//! only checks a bit.
+
+use std::fmt;
+
+use super::events::AccessKind;
+
+const WORDS: usize = 0x10000 / 64;
+
+#[derive(Clone, PartialEq)]
+pub struct IoFilter {
+	source: String,
+	reads: Box<[u64; WORDS]>,
+	writes: Box<[u64; WORDS]>,
+}
+
+impl IoFilter {
+	pub fn parse(source: &str) -> Result<Self, String> {
+		let mut filter = IoFilter { source: source.trim().to_string(), reads: Box::new([0; WORDS]), writes: Box::new([0; WORDS]) };
+		for clause in source.split([',', ';']).map(str::trim).filter(|clause| !clause.is_empty()) {
+			let mut words = clause.split_whitespace();
+			let (reads, writes) = match words.next().unwrap() {
+				"read" | "reads" => (true, false),
+				"write" | "writes" => (false, true),
+				"access" | "accesses" => (true, true),
+				kind => return Err(format!("Expected `reads`, `writes` or `accesses`, not {:?}", kind)),
+			};
+			let mut ranges = words.peekable();
+			if ranges.peek().is_none() {
+				return Err(format!("No addresses in {:?}", clause));
+			}
+			for range in ranges {
+				let address = |s: &str| u16::from_str_radix(s.trim_start_matches('$'), 16).map_err(|_| format!("Invalid address: {:?}", s));
+				let (start, end) = match range.split_once('-') {
+					Some((start, end)) => (address(start)?, address(end)?),
+					None => (address(range)?, address(range)?),
+				};
+				if start > end {
+					return Err(format!("Empty range: {}", range));
+				}
+				for addr in start..=end {
+					if reads {
+						filter.reads[addr as usize / 64] |= 1 << (addr % 64);
+					}
+					if writes {
+						filter.writes[addr as usize / 64] |= 1 << (addr % 64);
+					}
+				}
+			}
+		}
+		Ok(filter)
+	}
+
+	pub fn matches(&self, addr: u16, kind: AccessKind) -> bool {
+		let bits = match kind {
+			AccessKind::Read => &self.reads,
+			AccessKind::Write => &self.writes,
+		};
+		bits[addr as usize / 64] & (1 << (addr % 64)) != 0
+	}
+}
+
+impl fmt::Display for IoFilter {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.source)
+	}
+}
+
+impl fmt::Debug for IoFilter {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "IoFilter({:?})", self.source)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::IoFilter;
+	use crate::cpu::events::AccessKind::{Read, Write};
+
+	#[test]
+	fn test_parse() {
+		let filter = IoFilter::parse("writes 2000-2007 $4014, reads 4016; accesses 0300").unwrap();
+		assert!(filter.matches(0x2000, Write) && filter.matches(0x2007, Write) && filter.matches(0x4014, Write));
+		assert!(!filter.matches(0x2008, Write) && !filter.matches(0x2002, Read));
+		assert!(filter.matches(0x4016, Read) && !filter.matches(0x4016, Write) && !filter.matches(0x4017, Read));
+		assert!(filter.matches(0x0300, Read) && filter.matches(0x0300, Write));
+		assert!(IoFilter::parse("writes FFC0-FFFF").unwrap().matches(0xFFFF, Write));
+		assert_eq!(filter.to_string(), "writes 2000-2007 $4014, reads 4016; accesses 0300");
+
+		assert!(IoFilter::parse("peeks 2000").unwrap_err().contains("Expected"));
+		assert!(IoFilter::parse("reads").unwrap_err().contains("No addresses"));
+		assert!(IoFilter::parse("reads 20000").unwrap_err().contains("Invalid address"));
+		assert!(IoFilter::parse("reads 2007-2000").unwrap_err().contains("Empty range"));
+	}
+}
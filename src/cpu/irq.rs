@@ -0,0 +1,101 @@
+use std::fmt;
+use crate::savestate::{Serialize, Serializer};
+
+/// A device that can assert the CPU IRQ input.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IrqSource {
+	Mapper,			// Scanline counters, CPU cycle counters, FDS timer/disk...
+	FrameCounter,	// APU frame counter, acknowledged by reading $4015 or setting the inhibit flag in $4017
+	Dmc,			// APU DMC sample end, acknowledged by writing $4015
+}
+
+impl IrqSource {
+	pub const ALL: [IrqSource; 3] = [IrqSource::Mapper, IrqSource::FrameCounter, IrqSource::Dmc];
+
+	fn mask(self) -> u8 {
+		1 << self as u8
+	}
+}
+
+/// The CPU IRQ input is an open collector line: it is asserted while any of the sources asserts it. Each source keeps
+/// asserting until the program acknowledges it on the device, so acknowledging one source doesn't hide the others.
+/// Read here: https://www.nesdev.org/wiki/IRQ
+#[derive(Clone)]
+pub struct IrqLine {
+	sources: u8,
+}
+
+impl Default for IrqLine {
+	fn default() -> Self {
+		IrqLine::new()
+	}
+}
+
+impl IrqLine {
+	pub fn new() -> Self {
+		IrqLine { sources: 0 }
+	}
+
+	/// Update the level of one source.
+	pub fn set(&mut self, source: IrqSource, asserted: bool) {
+		if asserted {
+			self.sources |= source.mask();
+		} else {
+			self.sources &= !source.mask();
+		}
+	}
+
+	pub fn is_asserted_by(&self, source: IrqSource) -> bool {
+		self.sources & source.mask() != 0
+	}
+
+	/// Is any source asserting the line.
+	pub fn is_asserted(&self) -> bool {
+		self.sources != 0
+	}
+
+	/// The sources that assert the line.
+	pub fn sources(&self) -> Vec<IrqSource> {
+		IrqSource::ALL.into_iter().filter(|&source| self.is_asserted_by(source)).collect()
+	}
+}
+
+impl fmt::Display for IrqLine {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if !self.is_asserted() {
+			return write!(f, "released");
+		}
+		write!(f, "asserted by {:?}", self.sources())
+	}
+}
+
+impl Serialize for IrqLine {
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.sources);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{IrqLine, IrqSource};
+
+	#[test]
+	fn test_sources_are_ored() {
+		let mut line = IrqLine::new();
+		assert!(!line.is_asserted());
+
+		line.set(IrqSource::Mapper, true);
+		line.set(IrqSource::FrameCounter, true);
+		assert_eq!(line.sources(), vec![IrqSource::Mapper, IrqSource::FrameCounter]);
+		assert_eq!(line.to_string(), "asserted by [Mapper, FrameCounter]");
+
+		// The frame counter still holds the line after the mapper is acknowledged
+		line.set(IrqSource::Mapper, false);
+		assert!(line.is_asserted());
+		assert!(!line.is_asserted_by(IrqSource::Mapper));
+
+		line.set(IrqSource::FrameCounter, false);
+		assert!(!line.is_asserted());
+		assert_eq!(line.to_string(), "released");
+	}
+}
@@ -1,4 +1,12 @@
-mod registers;
-mod decoder;
+pub mod registers;
+pub mod decoder;
+mod instructions;
+#[cfg(feature = "threaded")]
+mod block_cache;
+mod disassembler;
+pub mod events;
+pub mod io_log;
+pub mod irq;
+pub mod trace;
 
 pub mod cpu;
\ No newline at end of file
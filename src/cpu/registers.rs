@@ -1,9 +1,10 @@
 use std::fmt;
 use crate::common::bits;
+use crate::savestate::{Serialize, Serializer};
 
 /// # CPU Registers
 /// (Chip: 6502), wikipedia: https://en.wikipedia.org/wiki/MOS_Technology_6502#Registers
-#[derive(Default)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 #[allow(non_snake_case)]
 pub struct Registers {
 	pub A: u8,							// accumulator
@@ -46,6 +47,7 @@ pub enum ProcessorStatusBits {
 	NEGATIVE
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct ProcessorStatus {
 	pub flags: u8
 }
@@ -85,6 +87,17 @@ impl fmt::Display for ProcessorStatus {
     }
 }
 
+impl Serialize for Registers {
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.A);
+		s.value(&mut self.X);
+		s.value(&mut self.Y);
+		s.value(&mut self.P.flags);
+		s.value(&mut self.S);
+		s.value(&mut self.PC);
+	}
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,209 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::{Arc, atomic::{AtomicU64, Ordering}, mpsc::{self, Receiver, SyncSender, TrySendError}};
+
+/// Amount of executed instructions the CPU remembers.
+pub const TRACE_BUFFER_SIZE: usize = 1024;
+
+/// CPU state just before executing an instruction.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct TraceEntry {
+	pub pc: u16,
+	pub opcode: u8,
+	pub a: u8,
+	pub x: u8,
+	pub y: u8,
+	pub s: u8,
+	pub p: u8,
+	pub cycles: u64,
+}
+
+impl fmt::Display for TraceEntry {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		// Similar to the nestest.log format
+		write!(f, "{:04X}  {:02X}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+			self.pc, self.opcode, self.a, self.x, self.y, self.p, self.s, self.cycles)
+	}
+}
+
+/// Ring buffer of the last executed instructions. When the emulator crashes (illegal opcode, unimplemented instruction)
+/// the buffer is dumped to a file, so we know how we got there.
+#[derive(Clone)]
+pub struct TraceBuffer {
+	entries: Vec<TraceEntry>,
+	capacity: usize,
+	next: usize,	// Where the next entry is written. When the buffer is full, this is also the oldest entry.
+}
+
+impl TraceBuffer {
+	pub fn new(capacity: usize) -> Self {
+		TraceBuffer {
+			entries: Vec::with_capacity(capacity),
+			capacity,
+			next: 0,
+		}
+	}
+
+	/// A buffer of capacity 0 keeps nothing.
+	pub fn push(&mut self, entry: TraceEntry) {
+		if self.capacity == 0 {
+			return;
+		}
+		if self.entries.len() < self.capacity {
+			self.entries.push(entry);
+		} else {
+			self.entries[self.next] = entry;
+		}
+		self.next = (self.next + 1) % self.capacity;
+	}
+
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.entries.is_empty()
+	}
+
+	/// From oldest to newest. The newest entry is the last (or current) instruction.
+	pub fn iter(&self) -> impl Iterator<Item = &TraceEntry> {
+		// Until the buffer is full, 'next' equals to the length, so the first part is empty
+		self.entries[self.next..].iter().chain(self.entries[..self.next].iter())
+	}
+
+	pub fn dump<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+		for entry in self.iter() {
+			writeln!(writer, "{}", entry)?;
+		}
+		Ok(())
+	}
+}
+
+/// An executed instruction, as the instruction stream reports it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExecutedInstruction {
+	pub before: TraceEntry,	// PC, opcode and the registers before the instruction
+	pub disassembly: String,
+}
+
+/// Executed instructions for external tools (tracers, profilers, coverage), see `NES::instruction_stream`. The
+/// instructions are buffered up to a capacity: when the tool doesn't keep up, the newest instructions are dropped (the
+/// emulation never waits for the tool) and counted.
+///
+/// Iterating returns the buffered instructions without waiting, `recv` waits for the next one, e.g. in another thread.
+pub struct InstructionStream {
+	receiver: Receiver<ExecutedInstruction>,
+	dropped: Arc<AtomicU64>,
+}
+
+impl InstructionStream {
+	/// Wait for the next instruction. None when the emulator is gone (or stopped streaming) and the buffer is empty.
+	pub fn recv(&self) -> Option<ExecutedInstruction> {
+		self.receiver.recv().ok()
+	}
+
+	/// Amount of instructions that were dropped because the buffer was full.
+	pub fn dropped(&self) -> u64 {
+		self.dropped.load(Ordering::Relaxed)
+	}
+
+	/// Write a line per instruction, the registers before it and its disassembly, until the emulator is gone. Returns
+	/// the amount of lines.
+	pub fn write_log(&self, mut writer: impl Write) -> io::Result<u64> {
+		let mut lines = 0;
+		while let Some(instruction) = self.recv() {
+			writeln!(writer, "{}  {}", instruction.before, instruction.disassembly)?;
+			lines += 1;
+		}
+		writer.flush()?;
+		Ok(lines)
+	}
+}
+
+impl Iterator for InstructionStream {
+	type Item = ExecutedInstruction;
+
+	fn next(&mut self) -> Option<ExecutedInstruction> {
+		self.receiver.try_recv().ok()
+	}
+}
+
+/// The emulator side of an `InstructionStream`.
+pub struct InstructionSender {
+	sender: SyncSender<ExecutedInstruction>,
+	dropped: Arc<AtomicU64>,
+}
+
+impl InstructionSender {
+	/// Returns false when the stream was dropped, so the emulator can stop disassembling.
+	pub fn send(&self, instruction: ExecutedInstruction) -> bool {
+		match self.sender.try_send(instruction) {
+			Ok(()) => true,
+			Err(TrySendError::Full(_)) => {
+				self.dropped.fetch_add(1, Ordering::Relaxed);
+				true
+			}
+			Err(TrySendError::Disconnected(_)) => false,
+		}
+	}
+}
+
+/// A stream that buffers up to `capacity` instructions.
+pub fn instruction_stream(capacity: usize) -> (InstructionSender, InstructionStream) {
+	let (sender, receiver) = mpsc::sync_channel(capacity);
+	let dropped = Arc::new(AtomicU64::new(0));
+	(InstructionSender { sender, dropped: dropped.clone() }, InstructionStream { receiver, dropped })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{instruction_stream, ExecutedInstruction, TraceBuffer, TraceEntry};
+
+	fn entry(pc: u16) -> TraceEntry {
+		TraceEntry { pc, ..Default::default() }
+	}
+
+	#[test]
+	fn test_ring_buffer() {
+		let mut trace = TraceBuffer::new(3);
+		assert!(trace.is_empty());
+
+		trace.push(entry(1));
+		trace.push(entry(2));
+		assert_eq!(trace.iter().map(|e| e.pc).collect::<Vec<u16>>(), vec![1, 2]);
+
+		trace.push(entry(3));
+		trace.push(entry(4));
+		trace.push(entry(5));
+		assert_eq!(trace.len(), 3);
+		assert_eq!(trace.iter().map(|e| e.pc).collect::<Vec<u16>>(), vec![3, 4, 5]);
+	}
+
+	#[test]
+	fn test_dump() {
+		let mut trace = TraceBuffer::new(2);
+		trace.push(TraceEntry { pc: 0xC000, opcode: 0x4C, a: 0, x: 0, y: 0, s: 0xFD, p: 0x24, cycles: 7 });
+
+		let mut out = vec![];
+		trace.dump(&mut out).unwrap();
+		assert_eq!(String::from_utf8(out).unwrap(), "C000  4C  A:00 X:00 Y:00 P:24 SP:FD CYC:7\n");
+	}
+
+	#[test]
+	fn test_instruction_stream() {
+		let (sender, mut stream) = instruction_stream(2);
+		let instruction = |pc| ExecutedInstruction { before: entry(pc), disassembly: "NOP".to_string() };
+		for pc in 1..=3 {
+			assert!(sender.send(instruction(pc)));
+		}
+		// The buffer is full after 2, the 3rd is dropped
+		assert_eq!(stream.by_ref().map(|i| i.before.pc).collect::<Vec<u16>>(), vec![1, 2]);
+		assert_eq!(stream.dropped(), 1);
+
+		assert!(sender.send(instruction(4)));
+		assert_eq!(stream.next().map(|i| i.before.pc), Some(4));
+
+		drop(stream);
+		assert!(!sender.send(instruction(5)));
+	}
+}
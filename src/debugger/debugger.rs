@@ -0,0 +1,564 @@
+use std::path::Path;
+
+use log::{error, info, warn};
+
+use crate::{apu::scope::Channel, controller::{Button, ButtonState}, cpu::{cpu::Scheduler, io_log::IoFilter}, nes::NES, ppu::{layers::save_pam, ppu::Renderer}, suspicious::EmulationMode, tas::TasEditor, vs_system::VsPpu};
+use super::{diagnose::diagnose, ram_search::{Comparison, RamSearch}, state_diff::{Snapshot, StateDiff}, watch::Watch};
+
+/// Debugger commands, typed in the terminal while stepping:
+///
+/// | Command | |
+/// |---|---|
+/// | (empty line) | Execute one instruction |
+/// | `watch <expr>` | Add a watch expression, see `watch.rs` for the syntax |
+/// | `unwatch <index>` | Remove a watch expression |
+/// | `watches` | Print the watch expressions and their current values |
+/// | `search` | Start a RAM search: snapshot the CPU RAM, every address is a candidate |
+/// | `search <comparison>` | Keep the candidates that compare so to the last snapshot: `= <n>`, `changed`, `unchanged`, `+`, `-`, `+<n>`, `-<n>` |
+/// | `search list [count]` | Print the candidates and their values (default 20) |
+/// | `freeze` | Print the frozen RAM addresses |
+/// | `freeze <$addr> <value>` | Freeze an address to a value, the game's writes to it are ignored |
+/// | `freeze <$addr> on\|off` | Resume or pause a frozen address |
+/// | `unfreeze <$addr>` | Forget a frozen address |
+/// | `map <$addr>` | Print which device and region the address maps to, e.g. the PRG ROM bank with the current banks |
+/// | `trace [count]` | Print the last executed instructions (default 20) |
+/// | `opcodes [count]` | Print the most executed opcodes since power on (default 20) |
+/// | `diagnose` | Run a frame and report why the screen could be black: rendering or NMI disabled, stuck loop, IRQ storm, CPU halted |
+/// | `events [$addr]` | Print the PPU/IO register accesses of the last frame, optionally only of one register (mirrors included) |
+/// | `reset` | Press the reset button |
+/// | `irq` | Print the IRQ line and which sources (mapper, APU frame counter, DMC) assert it |
+/// | `dmc` | Print the DMC sample address and length, and where the playback is |
+/// | `channels` | Print the length counter, period, frequency and output level of each APU channel |
+/// | `state [file]` | Print the CPU registers, timers and PPU latches as JSON, or write them to a file |
+/// | `statediff <file> [file]` | Print the bytes (RAM, PRG RAM, nametables, palette, OAM) and registers that differ between two save states, or a save state and the machine |
+/// | `iolog [filter\|off]` | Print or set the memory accesses that are logged, e.g. `iolog writes 2000-2007, reads 4016` (see `IoFilter`) |
+/// | `mode [strict\|permissive]` | Print or set the emulation mode, strict stops at writes to ROM, reads of write-only registers and stack overflows |
+/// | `overclock [scanlines]` | Print or set the extra vblank scanlines for the CPU |
+/// | `scheduler [fast\|accurate]` | Print or set how the CPU and the PPU take turns, see `Scheduler` |
+/// | `renderer [dot\|scanline]` | Print or set the PPU renderer, see `Renderer` |
+/// | `spritelimit [on\|off\|rotate]` | Print or set the 8 sprites per scanline limit, rotate keeps it with less flicker |
+/// | `layers on\|off` | Render the background and sprite layers apart (F4 in the window shows them) |
+/// | `layers save <prefix>` | Save the layers to `<prefix>-combined.pam`, `<prefix>-background.pam` and `<prefix>-sprites.pam` |
+/// | `disk <side>` / `disk eject` | Insert a disk side (0 is side A of the first disk), or eject the disk (FDS) |
+/// | `coin [1\|2]` | Insert a coin (VS System, default coin slot 1) |
+/// | `dip <hex>` | Set the DIP switches, switch 1 is bit 0 (VS System) |
+/// | `vsppu <2c03\|0001-0004>` | Choose the PPU, which decides the palette (VS System) |
+pub struct Debugger {
+	watches: Vec<Watch>,
+	last_frame: u64,
+	ram_search: Option<RamSearch>,
+	tas: Option<TasEditor>,
+	halted: bool,	// The CPU halt was reported
+}
+
+impl Default for Debugger {
+	fn default() -> Self {
+		Debugger::new()
+	}
+}
+
+impl Debugger {
+	pub fn new() -> Self {
+		Debugger {
+			watches: vec![],
+			last_frame: 0,
+			ram_search: None,
+			tas: None,
+			halted: false,
+		}
+	}
+
+	pub fn add_watch(&mut self, source: &str) -> Result<usize, String> {
+		self.watches.push(Watch::new(source)?);
+		Ok(self.watches.len() - 1)
+	}
+
+	pub fn remove_watch(&mut self, index: usize) -> Option<Watch> {
+		if index < self.watches.len() {
+			Some(self.watches.remove(index))
+		} else {
+			None
+		}
+	}
+
+	pub fn watches(&self) -> &[Watch] {
+		&self.watches
+	}
+
+	/// Evaluate all watch expressions, in the order they were added.
+	pub fn evaluate_watches(&self, nes: &mut NES) -> Vec<i64> {
+		self.watches.iter().map(|watch| watch.evaluate(nes)).collect()
+	}
+
+	fn log_watches(&self, nes: &mut NES) {
+		for (i, (watch, value)) in self.watches.iter().zip(self.evaluate_watches(nes)).enumerate() {
+			info!("Watch {}: {} = {} (${:X})", i, watch, value, value);
+		}
+	}
+
+	/// Call after each instruction. When a frame is completed the watch expressions are logged, when the CPU halts the
+	/// trace is. Returns true if a frame was completed.
+	pub fn after_step(&mut self, nes: &mut NES) -> bool {
+		match nes.cpu.halted() {
+			Some(halted) if !self.halted => {
+				self.halted = true;
+				error!("{}. The last instructions:", halted);
+				log_trace(nes, 10);
+				info!("Type `reset` to press the reset button");
+			}
+			None => self.halted = false,
+			_ => {}
+		}
+		if nes.cpu.suspicious_mut().take_break().is_some() {
+			info!("Stopped by strict mode. The last instructions:");
+			log_trace(nes, 10);
+		}
+		let frame = nes.frame();
+		if frame == self.last_frame {
+			return false;
+		}
+		self.last_frame = frame;
+		if !self.watches.is_empty() {
+			info!("Frame {}", frame);
+			self.log_watches(nes);
+		}
+		true
+	}
+
+	/// Handle a line typed by the user. Returns true if the emulator should execute an instruction.
+	pub fn command(&mut self, line: &str, nes: &mut NES) -> bool {
+		let line = line.trim();
+		let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+		match command {
+			"" => return true,
+			"watch" => match self.add_watch(args) {
+				Ok(index) => info!("Watch {}: {}", index, args.trim()),
+				Err(err) => warn!("Invalid watch expression '{}': {}", args.trim(), err),
+			},
+			"unwatch" => match args.trim().parse::<usize>().ok().and_then(|i| self.remove_watch(i)) {
+				Some(watch) => info!("Removed watch: {}", watch),
+				None => warn!("No such watch: {}", args.trim()),
+			},
+			"watches" => self.log_watches(nes),
+			"search" => self.search(args.trim(), nes),
+			"tas" => self.tas(args.trim(), nes),
+			"freeze" => freeze(args.trim(), nes),
+			"unfreeze" => match parse_address(args) {
+				Some(addr) if nes.unfreeze(addr) => info!("Unfrozen ${:04X}", addr),
+				_ => warn!("Not frozen: {}", args.trim()),
+			},
+			"events" => {
+				let filter = args.trim().strip_prefix('$').and_then(|addr| u16::from_str_radix(addr, 16).ok());
+				for event in nes.cpu.events().last_frame() {
+					if filter.is_none_or(|register| register == event.register()) {
+						info!("{}", event);
+					}
+				}
+			}
+			"map" => match parse_address(args) {
+				Some(addr) => info!("${:04X}: {}", addr, nes.describe_address(addr)),
+				None => warn!("Usage: map <$addr>"),
+			},
+			"trace" => log_trace(nes, args.trim().parse::<usize>().unwrap_or(20)),
+			"opcodes" => {
+				let count = args.trim().parse::<usize>().unwrap_or(20);
+				for line in nes.opcode_stats().table().lines().take(count + 1) {
+					info!("{}", line);
+				}
+			}
+			"diagnose" => {
+				let findings = diagnose(nes);
+				if findings.is_empty() {
+					info!("Nothing looks wrong in this frame");
+				}
+				for finding in findings {
+					warn!("{}", finding);
+				}
+			}
+			"reset" => nes.reset(),
+			"irq" => info!("IRQ line: {}", nes.cpu.irq_line()),
+			"dmc" => info!("DMC: {}", nes.cpu.apu().dmc_status()),
+			"channels" => {
+				for channel in Channel::ALL {
+					info!("{}", nes.cpu.apu().channel_state(channel));
+				}
+			}
+			"state" => match args.trim() {
+				"" => info!("State:\n{}", nes.dump_state_json()),
+				path => match std::fs::write(path, nes.dump_state_json()) {
+					Ok(()) => info!("Wrote the state to {}", path),
+					Err(e) => error!("Can't write {}: {}", path, e),
+				},
+			},
+			"statediff" => state_diff(args.trim(), nes),
+			"iolog" => match args.trim() {
+				"" => info!("I/O log: {}", nes.cpu.io_log().map_or("off".to_string(), |filter| filter.to_string())),
+				"off" => nes.cpu.set_io_log(None),
+				source => match IoFilter::parse(source) {
+					Ok(filter) => nes.cpu.set_io_log(Some(filter)),
+					Err(e) => warn!("{}", e),
+				},
+			},
+			"layers" => match args.trim().split_once(' ').unwrap_or((args.trim(), "")) {
+				("on", _) => nes.cpu.ppu_mut().set_layers_enabled(true),
+				("off", _) => nes.cpu.ppu_mut().set_layers_enabled(false),
+				("save", prefix) if !prefix.is_empty() => save_layers(nes, prefix.trim()),
+				_ => warn!("Usage: layers on|off|save <prefix>"),
+			},
+			"spritelimit" => match args.trim() {
+				"" => {
+					let ppu = nes.cpu.ppu();
+					info!("Sprite limit: {}", match (ppu.sprite_limit(), ppu.sprite_rotation()) { (false, _) => "off", (true, false) => "on", (true, true) => "rotate" });
+				}
+				"on" | "off" | "rotate" => {
+					nes.cpu.ppu_mut().set_sprite_limit(args.trim() != "off");
+					nes.cpu.ppu_mut().set_sprite_rotation(args.trim() == "rotate");
+				}
+				_ => warn!("Sprite limit must be on, off or rotate"),
+			},
+			"scheduler" => match args.trim() {
+				"" => info!("Scheduler: {}", nes.cpu.scheduler().name()),
+				name => match Scheduler::parse(name) {
+					Some(scheduler) => nes.cpu.set_scheduler(scheduler),
+					None => warn!("Scheduler must be fast or accurate"),
+				},
+			},
+			"renderer" => match args.trim() {
+				"" => info!("Renderer: {}", nes.cpu.ppu().renderer().name()),
+				name => match Renderer::parse(name) {
+					Some(renderer) => nes.cpu.ppu_mut().set_renderer(renderer),
+					None => warn!("Renderer must be dot or scanline"),
+				},
+			},
+			"mode" => match args.trim() {
+				"" => info!("Emulation mode: {}", nes.cpu.suspicious().mode().name()),
+				name => match EmulationMode::parse(name) {
+					Some(mode) => nes.cpu.suspicious_mut().set_mode(mode),
+					None => warn!("Mode must be strict or permissive"),
+				},
+			},
+			"overclock" => match args.trim() {
+				"" => info!("Overclock: {} extra vblank scanlines", nes.cpu.overclock()),
+				scanlines => match scanlines.parse::<u16>() {
+					Ok(scanlines) => nes.cpu.set_overclock(scanlines),
+					Err(_) => warn!("Overclock must be an amount of scanlines"),
+				},
+			},
+			"disk" => {
+				let sides = nes.cpu.cartridge().disk_sides();
+				match args.trim() {
+					_ if sides == 0 => warn!("There is no disk drive"),
+					"eject" => nes.cpu.cartridge_mut().insert_disk(None),
+					side => match side.parse::<usize>() {
+						Ok(side) if side < sides => nes.cpu.cartridge_mut().insert_disk(Some(side)),
+						_ => warn!("Disk side must be 0-{}", sides - 1),
+					},
+				}
+			}
+			"coin" | "dip" | "vsppu" if nes.cpu.cartridge().vs_system().is_none() => warn!("Not a VS System game"),
+			"coin" => match args.trim() {
+				"" | "1" => nes.cpu.cartridge_mut().vs_system_mut().unwrap().insert_coin(0),
+				"2" => nes.cpu.cartridge_mut().vs_system_mut().unwrap().insert_coin(1),
+				_ => warn!("Coin slot must be 1 or 2"),
+			},
+			"dip" => match u8::from_str_radix(args.trim().trim_start_matches('$'), 16) {
+				Ok(dip_switches) => nes.cpu.cartridge_mut().vs_system_mut().unwrap().set_dip_switches(dip_switches),
+				Err(_) => warn!("DIP switches must be a hex byte"),
+			},
+			"vsppu" => match VsPpu::parse(args.trim()) {
+				Some(ppu) => nes.set_vs_ppu(ppu).unwrap_or_else(|e| warn!("{}", e)),
+				None => warn!("PPU must be 2c03 or 0001-0004"),
+			},
+			_ => warn!("Unknown command: {}", command),
+		}
+		false
+	}
+
+	/// The RAM search commands.
+	fn search(&mut self, args: &str, nes: &mut NES) {
+		if args.is_empty() {
+			let search = RamSearch::new(nes);
+			info!("RAM search: {} candidates", search.len());
+			self.ram_search = Some(search);
+			return;
+		}
+		let Some(search) = &mut self.ram_search else {
+			warn!("No RAM search, start one with `search`");
+			return;
+		};
+		let (subcommand, count) = args.split_once(' ').unwrap_or((args, ""));
+		if subcommand == "list" {
+			for (addr, value) in search.candidates().take(count.trim().parse::<usize>().unwrap_or(20)) {
+				info!("${:04X} = {} (${:02X})", addr, value, value);
+			}
+			return;
+		}
+		match Comparison::parse(args) {
+			Some(comparison) => {
+				let left = search.filter(nes, comparison);
+				info!("RAM search: {} candidates {}", left, comparison);
+			}
+			None => warn!("Usage: search [list [count] | = <n> | changed | unchanged | + | - | +<n> | -<n>]"),
+		}
+	}
+
+	/// The TAS editor commands, the frames are the ones of the editor (0 is where it was opened).
+	fn tas(&mut self, args: &str, nes: &mut NES) {
+		if args.is_empty() && self.tas.is_none() {
+			self.tas = Some(TasEditor::new(nes));
+			info!("TAS editor from frame {}, run the frames with `tas advance`", nes.frame());
+			return;
+		}
+		let Some(editor) = &mut self.tas else {
+			warn!("No TAS editor, start one with `tas`");
+			return;
+		};
+		let args: Vec<&str> = args.split_whitespace().collect();
+		let frame = |index: usize| args.get(index).and_then(|frame| frame.parse::<usize>().ok());
+		match args[..] {
+			[] => {
+				let frame = editor.frame(nes);
+				info!("TAS frame {}: {}, {}", frame, describe_buttons(editor.buttons(frame, 0)), describe_buttons(editor.buttons(frame, 1)));
+			}
+			["advance"] | ["advance", _] => match args.get(1).map_or(Some(1), |count| count.parse::<usize>().ok()) {
+				Some(count) => {
+					for _ in 0..count {
+						editor.advance(nes);
+					}
+					info!("TAS frame {}", editor.frame(nes));
+				}
+				None => warn!("The number of frames must be a number"),
+			},
+			["seek", _] => match frame(1) {
+				Some(frame) => editor.seek(nes, frame),
+				None => warn!("Usage: tas seek <frame>"),
+			},
+			["press" | "release", _, name] | ["press" | "release", _, name, _] => {
+				let button = Button::ALL.into_iter().find(|button| button.name() == name);
+				let player = match args.get(3) {
+					None | Some(&"1") => Some(0),
+					Some(&"2") => Some(1),
+					Some(_) => None,
+				};
+				match (frame(1), button, player) {
+					(Some(frame), Some(button), Some(player)) => editor.set_button(frame, player, button, args[0] == "press"),
+					_ => warn!("Usage: tas press|release <frame> <a|b|select|start|up|down|left|right> [1|2]"),
+				}
+			}
+			["insert" | "remove", _] => match frame(1) {
+				Some(frame) if args[0] == "insert" => editor.insert_frame(frame),
+				Some(frame) => editor.remove_frame(frame),
+				None => warn!("Usage: tas insert|remove <frame>"),
+			},
+			["save", path] => match editor.movie().save(Path::new(path)) {
+				Ok(()) => info!("Saved the movie to {}, play it with --play", path),
+				Err(e) => error!("Can't save the movie to {}: {}", path, e),
+			},
+			["off"] => {
+				self.tas = None;
+				info!("TAS editor closed");
+			}
+			_ => warn!("Usage: tas [advance [frames] | seek <frame> | press|release <frame> <button> [1|2] | insert|remove <frame> | save <file> | off]"),
+		}
+	}
+}
+
+/// The pressed buttons, e.g. `a+right`.
+fn describe_buttons(buttons: ButtonState) -> String {
+	let names: Vec<&str> = Button::ALL.iter().filter(|button| buttons & button.mask() != 0).map(|button| button.name()).collect();
+	if names.is_empty() { "-".to_string() } else { names.join("+") }
+}
+
+/// The last `count` executed instructions.
+fn log_trace(nes: &NES, count: usize) {
+	let trace = nes.cpu.trace();
+	for entry in trace.iter().skip(trace.len().saturating_sub(count)) {
+		info!("{}", entry);
+	}
+}
+
+/// `statediff <before> [after]`: the after state is the machine when there's one file.
+fn state_diff(args: &str, nes: &mut NES) {
+	let files: Vec<&str> = args.split_whitespace().collect();
+	let snapshots = match files[..] {
+		[before] => Snapshot::from_file(nes, Path::new(before)).map(|before| (before, Snapshot::capture(nes))),
+		[before, after] => Snapshot::from_file(nes, Path::new(before)).and_then(|before| Ok((before, Snapshot::from_file(nes, Path::new(after))?))),
+		_ => {
+			warn!("Usage: statediff <file> [file]");
+			return;
+		}
+	};
+	match snapshots.map(|(before, after)| StateDiff::new(&before, &after)) {
+		Ok(diff) if diff.is_empty() => info!("The states are the same"),
+		Ok(diff) => info!("State diff:\n{}", diff.report().trim_end()),
+		Err(e) => error!("Can't load the state: {}", e),
+	}
+}
+
+fn parse_address(source: &str) -> Option<u16> {
+	u16::from_str_radix(source.trim().strip_prefix('$')?, 16).ok()
+}
+
+/// The freeze commands.
+fn freeze(args: &str, nes: &mut NES) {
+	if args.is_empty() {
+		for freeze in nes.cpu.freezes().entries() {
+			info!("${:04X} = {} (${:02X}){}", freeze.addr, freeze.value, freeze.value, if freeze.enabled { "" } else { ", off" });
+		}
+		return;
+	}
+	let (addr, value) = args.split_once(' ').unwrap_or((args, ""));
+	let Some(addr) = parse_address(addr) else {
+		warn!("Usage: freeze [<$addr> <value>|on|off]");
+		return;
+	};
+	let value = value.trim();
+	let number = match value.strip_prefix('$') {
+		Some(hex) => u8::from_str_radix(hex, 16).ok(),
+		None => value.parse::<u8>().ok(),
+	};
+	match (value, number) {
+		(_, Some(number)) => {
+			nes.freeze(addr, number);
+			info!("Frozen ${:04X} ({}) = {}", addr, nes.describe_address(addr), number);
+		}
+		("on" | "off", _) => {
+			if !nes.set_freeze_enabled(addr, value == "on") {
+				warn!("Not frozen: ${:04X}", addr);
+			}
+		}
+		_ => warn!("The value must be a byte, decimal or $hex"),
+	}
+}
+
+/// Save the picture and its layers as images.
+fn save_layers(nes: &NES, prefix: &str) {
+	let ppu = nes.cpu.ppu();
+	let Some(layers) = ppu.layers() else {
+		warn!("The layers are not rendered, use `layers on` and run a frame first");
+		return;
+	};
+	for (name, pixels) in [("combined", ppu.framebuffer()), ("background", &layers.background), ("sprites", &layers.sprites)] {
+		let path = format!("{}-{}.pam", prefix, name);
+		match save_pam(&path, pixels, ppu.rgb_palette()) {
+			Ok(()) => info!("Saved {}", path),
+			Err(e) => error!("Failed to save {}: {}", path, e),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Debugger;
+	use crate::{controller::Button, nes::NES, program_loader::*};
+
+	fn initialize() -> NES {
+		let mut rom_memory: [u8; 1024*32] = [0;1024*32];
+		load_program_run_helpers(&mut rom_memory);
+		set_reset_vector(&mut rom_memory, 0x8000);
+		NES::new_custom_prg_rom(rom_memory)
+	}
+
+	#[test]
+	fn test_watches_every_frame() {
+		let mut nes = initialize();
+		let mut debugger = Debugger::new();
+
+		assert!(!debugger.command("watch $0300", &mut nes));
+		assert!(!debugger.command("watch A + X", &mut nes));
+		assert!(!debugger.command("watch $", &mut nes));
+		assert_eq!(debugger.watches().len(), 2);
+
+		assert_eq!(debugger.evaluate_watches(&mut nes), vec![0, 0]);
+
+		let mut frames = 0;
+		while frames < 1 {
+			assert!(debugger.command("", &mut nes));
+			nes.step();
+			if debugger.after_step(&mut nes) {
+				frames += 1;
+			}
+		}
+		assert_eq!(nes.frame(), 1);
+		// The program stores A=1 to $0300 after counting X down to 0
+		assert_eq!(debugger.evaluate_watches(&mut nes), vec![1, 1]);
+
+		assert!(!debugger.command("unwatch 0", &mut nes));
+		assert!(!debugger.command("unwatch 5", &mut nes));
+		assert_eq!(debugger.watches().len(), 1);
+		assert_eq!(debugger.watches()[0].source, "A + X");
+	}
+
+	#[test]
+	fn test_freeze() {
+		let mut nes = initialize();
+		let mut debugger = Debugger::new();
+		debugger.command("freeze $0300 $20", &mut nes);
+		debugger.command("freeze $0200 7", &mut nes);
+		debugger.command("freeze $0200 off", &mut nes);
+		debugger.command("freeze $0400 on", &mut nes);
+		debugger.command("freeze", &mut nes);
+		assert!(nes.run_until_pc(0x800D));
+		assert_eq!((nes.peek(0x0200), nes.peek(0x0300)), (1, 0x20));
+
+		debugger.command("unfreeze $0300", &mut nes);
+		assert_eq!(nes.cpu.freezes().entries().len(), 1);
+	}
+
+	#[test]
+	fn test_iolog() {
+		let mut nes = initialize();
+		let mut debugger = Debugger::new();
+		debugger.command("iolog writes 0300, reads 4016", &mut nes);
+		assert_eq!(nes.cpu.io_log().map(|filter| filter.to_string()), Some("writes 0300, reads 4016".to_string()));
+		debugger.command("iolog reads 99999", &mut nes);
+		assert!(nes.cpu.io_log().is_some());
+		debugger.command("iolog off", &mut nes);
+		assert!(nes.cpu.io_log().is_none());
+	}
+
+	#[test]
+	fn test_ram_search() {
+		let mut nes = initialize();
+		let mut debugger = Debugger::new();
+		assert!(!debugger.command("search changed", &mut nes));
+		assert!(debugger.ram_search.is_none());
+
+		debugger.command("search", &mut nes);
+		debugger.command("search = 0", &mut nes);
+		// The program stores A=1 to $0200 and $0300
+		assert!(nes.run_until_pc(0x800D));
+		debugger.command("search +1", &mut nes);
+		debugger.command("search list", &mut nes);
+		let candidates: Vec<_> = debugger.ram_search.as_ref().unwrap().candidates().collect();
+		assert_eq!(candidates, vec![(0x0200, 1), (0x0300, 1)]);
+	}
+
+	#[test]
+	fn test_tas() {
+		// Counts the frames with A pressed in $0200
+		let mut rom_memory: [u8; 1024*32] = [0;1024*32];
+		load_program_count_a_presses(&mut rom_memory);
+		set_reset_vector(&mut rom_memory, 0x8000);
+		let mut nes = NES::new_custom_prg_rom(rom_memory);
+		let mut debugger = Debugger::new();
+		debugger.command("tas advance", &mut nes);
+		assert!(debugger.tas.is_none());
+
+		debugger.command("tas", &mut nes);
+		debugger.command("tas press 0 a", &mut nes);
+		debugger.command("tas press 1 a 2", &mut nes);
+		debugger.command("tas press 2 turbo", &mut nes);
+		debugger.command("tas advance 3", &mut nes);
+		assert_eq!(nes.peek(0x0200), 1);
+		let editor = debugger.tas.as_ref().unwrap();
+		assert_eq!((editor.frame(&nes), editor.buttons(1, 0), editor.buttons(1, 1)), (3, 0, Button::A.mask()));
+
+		// Press A on frame 1 too, and replay from there
+		debugger.command("tas press 1 a", &mut nes);
+		debugger.command("tas seek 3", &mut nes);
+		assert_eq!(nes.peek(0x0200), 2);
+		debugger.command("tas off", &mut nes);
+		assert!(debugger.tas.is_none());
+	}
+}
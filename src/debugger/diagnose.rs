@@ -0,0 +1,128 @@
+//! "Why is the screen black": runs a frame and looks at the PPU and CPU for the usual reasons a ROM doesn't show
+//! anything, to triage ROMs that don't boot.
+
+use std::fmt;
+
+use crate::{cpu::{cpu::CpuHalted, irq::IrqSource}, nes::NES};
+
+/// A loop that never leaves this many bytes for a whole frame is stuck.
+const STUCK_LOOP_SIZE: u16 = 16;
+/// More IRQs in a frame is a storm: the handler returns without acknowledging the device.
+const IRQ_STORM: usize = 100;
+/// The run gives up on a frame after this many instructions.
+const MAX_INSTRUCTIONS: usize = 100_000;
+
+#[derive(Debug, PartialEq)]
+pub enum Finding {
+	Halted(CpuHalted),
+	RenderingDisabled,	// PPUMASK shows neither the background nor the sprites
+	NmiDisabled,		// PPUCTRL bit 7 is clear, and no NMI happened
+	StuckLoop { start: u16, end: u16 },	// The CPU stayed in these addresses for the whole frame, without interrupts
+	IrqStorm { count: usize, sources: Vec<IrqSource> },
+	OneColor(u8),		// The whole picture is this NES color
+}
+
+impl fmt::Display for Finding {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Finding::Halted(halted) => write!(f, "{}, the game can't continue until reset", halted),
+			Finding::RenderingDisabled => write!(f, "Rendering is disabled: PPUMASK ($2001) shows neither the background nor the sprites"),
+			Finding::NmiDisabled => write!(f, "NMI is disabled in PPUCTRL ($2000), a game waiting for vblank NMI waits forever"),
+			Finding::StuckLoop { start, end } => write!(f, "The CPU is stuck in a loop at ${:04X}-${:04X}, waiting for something that doesn't happen", start, end),
+			Finding::IrqStorm { count, sources } => write!(f, "IRQ storm: {} IRQs in a frame, the handler doesn't acknowledge {:?}", count, sources),
+			Finding::OneColor(color) => write!(f, "The whole picture is color ${:02X}", color),
+		}
+	}
+}
+
+/// Run a frame and report what keeps the picture from showing. Empty when nothing looks wrong.
+pub fn diagnose(nes: &mut NES) -> Vec<Finding> {
+	let nmi_handler = u16::from_le_bytes([nes.peek(0xFFFA), nes.peek(0xFFFB)]);
+	let irq_handler = u16::from_le_bytes([nes.peek(0xFFFE), nes.peek(0xFFFF)]);
+	let (mut nmis, mut irqs) = (0, 0);
+	let (mut start, mut end) = (u16::MAX, 0);
+	let mut irq_sources = vec![];
+
+	let frame = nes.frame();
+	for _ in 0..MAX_INSTRUCTIONS {
+		if nes.frame() != frame {
+			break;
+		}
+		let pc = nes.cpu.registers().PC;
+		(start, end) = (start.min(pc), end.max(pc));
+		let sources = nes.cpu.irq_line().sources();
+		nes.step();
+		// Entering a handler: the interrupt was taken after the instruction
+		let pc = nes.cpu.registers().PC;
+		if pc == nmi_handler {
+			nmis += 1;
+		} else if pc == irq_handler && !sources.is_empty() {
+			irqs += 1;
+			irq_sources = sources;
+		}
+	}
+
+	let mut findings = vec![];
+	if let Some(halted) = nes.halted() {
+		findings.push(Finding::Halted(halted));
+	}
+	if !nes.cpu.ppu().rendering_enabled() {
+		findings.push(Finding::RenderingDisabled);
+	}
+	if !nes.cpu.ppu().nmi_enabled() && nmis == 0 {
+		findings.push(Finding::NmiDisabled);
+	}
+	if nes.halted().is_none() && nmis + irqs == 0 && end.wrapping_sub(start) < STUCK_LOOP_SIZE {
+		findings.push(Finding::StuckLoop { start, end });
+	}
+	if irqs > IRQ_STORM {
+		findings.push(Finding::IrqStorm { count: irqs, sources: irq_sources });
+	}
+	let pixels = nes.cpu.ppu().framebuffer();
+	if pixels.iter().all(|&color| color == pixels[0]) {
+		findings.push(Finding::OneColor(pixels[0]));
+	}
+	findings
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{diagnose, Finding};
+	use crate::{cpu::{cpu::CpuHalted, irq::IrqSource}, nes::NES, program_loader::*};
+
+	fn initialize(f: fn(&mut [u8;1024*32]) -> u8) -> NES {
+		let mut rom_memory: [u8; 1024*32] = [0;1024*32];
+		f(&mut rom_memory);
+		set_reset_vector(&mut rom_memory, 0x8000);
+		NES::new_custom_prg_rom(rom_memory)
+	}
+
+	#[test]
+	fn test_black_screens() {
+		// JMP $8000, with NMI and rendering off
+		let mut nes = initialize(|rom| { rom[..3].copy_from_slice(&[0x4C, 0x00, 0x80]); 0 });
+		assert_eq!(diagnose(&mut nes), vec![
+			Finding::RenderingDisabled,
+			Finding::NmiDisabled,
+			Finding::StuckLoop { start: 0x8000, end: 0x8000 },
+			Finding::OneColor(nes.cpu.ppu().framebuffer()[0]),
+		]);
+
+		let mut nes = initialize(load_program_kil);
+		let findings = diagnose(&mut nes);
+		assert_eq!(findings[0], Finding::Halted(CpuHalted { pc: 0x800A, opcode: 0x02 }));
+		assert!(!findings.contains(&Finding::NmiDisabled));
+
+		// The IRQ handler returns without reading $4015
+		let mut nes = initialize(|rom| { load_program_frame_irq(rom); rom[4] = 0x40; 0 });
+		nes.run_frame();
+		let findings = diagnose(&mut nes);
+		assert!(findings.iter().any(|finding| matches!(finding, Finding::IrqStorm { sources, .. } if *sources == vec![IrqSource::FrameCounter])));
+		assert!(!findings.iter().any(|finding| matches!(finding, Finding::StuckLoop { .. })));
+
+		// Counts frames in NMI and shows the background
+		let mut nes = initialize(load_program_nmi_counter);
+		nes.run_frame();
+		assert!(!diagnose(&mut nes).iter().any(|finding| matches!(finding, Finding::NmiDisabled | Finding::RenderingDisabled | Finding::StuckLoop { .. })));
+	}
+}
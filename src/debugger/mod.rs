@@ -0,0 +1,5 @@
+pub mod debugger;
+pub mod diagnose;
+pub mod ram_search;
+pub mod state_diff;
+pub mod watch;
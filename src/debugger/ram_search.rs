@@ -0,0 +1,163 @@
+//! RAM search, to find where a game keeps a value (lives, health, timer) when the address is not known:
+//!
+//! 1. Start a search, it remembers the whole CPU RAM
+//! 2. Play until the value changes (lose a life), filter with how it changed (`decreased by 1`)
+//! 3. Repeat until few addresses are left, then watch them, freeze or patch them
+//!
+//! Each filter compares the RAM with the snapshot taken by the previous filter (or the start).
+
+use std::fmt;
+
+use crate::nes::NES;
+
+/// The CPU RAM ($0000-$07FF, the rest of $0000-$1FFF are mirrors).
+const RAM_SIZE: u16 = 0x800;
+
+/// How the value at an address compares to the previous snapshot.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Comparison {
+	Equal(u8),		// Equals to a number, ignores the previous snapshot
+	Changed,
+	Unchanged,
+	Increased,
+	Decreased,
+	IncreasedBy(u8),	// Wraps around, like 6502 arithmetic
+	DecreasedBy(u8),
+}
+
+impl Comparison {
+	/// `= <n>`, `changed`, `unchanged`, `+`, `-`, `+<n>`, `-<n>`. Numbers are decimal, or hex with `$`.
+	pub fn parse(source: &str) -> Option<Comparison> {
+		let number = |s: &str| match s.trim().strip_prefix('$') {
+			Some(hex) => u8::from_str_radix(hex, 16).ok(),
+			None => s.trim().parse::<u8>().ok(),
+		};
+		match source.trim() {
+			"changed" => Some(Comparison::Changed),
+			"unchanged" => Some(Comparison::Unchanged),
+			"+" => Some(Comparison::Increased),
+			"-" => Some(Comparison::Decreased),
+			s => {
+				if let Some(n) = s.strip_prefix('=') {
+					number(n).map(Comparison::Equal)
+				} else if let Some(n) = s.strip_prefix('+') {
+					number(n).map(Comparison::IncreasedBy)
+				} else if let Some(n) = s.strip_prefix('-') {
+					number(n).map(Comparison::DecreasedBy)
+				} else {
+					None
+				}
+			}
+		}
+	}
+
+	fn matches(self, previous: u8, value: u8) -> bool {
+		match self {
+			Comparison::Equal(n) => value == n,
+			Comparison::Changed => value != previous,
+			Comparison::Unchanged => value == previous,
+			Comparison::Increased => value > previous,
+			Comparison::Decreased => value < previous,
+			Comparison::IncreasedBy(n) => value.wrapping_sub(previous) == n,
+			Comparison::DecreasedBy(n) => previous.wrapping_sub(value) == n,
+		}
+	}
+}
+
+impl fmt::Display for Comparison {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Comparison::Equal(n) => write!(f, "equal to {}", n),
+			Comparison::Changed => write!(f, "changed"),
+			Comparison::Unchanged => write!(f, "unchanged"),
+			Comparison::Increased => write!(f, "increased"),
+			Comparison::Decreased => write!(f, "decreased"),
+			Comparison::IncreasedBy(n) => write!(f, "increased by {}", n),
+			Comparison::DecreasedBy(n) => write!(f, "decreased by {}", n),
+		}
+	}
+}
+
+pub struct RamSearch {
+	snapshot: Vec<u8>,		// The whole RAM at the last filter
+	candidates: Vec<u16>,	// Addresses that passed all the filters
+}
+
+impl RamSearch {
+	/// Start a search: every address is a candidate.
+	pub fn new(nes: &mut NES) -> Self {
+		RamSearch {
+			snapshot: Self::snapshot(nes),
+			candidates: (0..RAM_SIZE).collect(),
+		}
+	}
+
+	fn snapshot(nes: &mut NES) -> Vec<u8> {
+		(0..RAM_SIZE).map(|addr| nes.peek(addr)).collect()
+	}
+
+	/// Keep the candidates whose value compares to the previous snapshot, then take a new snapshot. Returns the amount
+	/// of candidates left.
+	pub fn filter(&mut self, nes: &mut NES, comparison: Comparison) -> usize {
+		let snapshot = Self::snapshot(nes);
+		self.candidates.retain(|&addr| comparison.matches(self.snapshot[addr as usize], snapshot[addr as usize]));
+		self.snapshot = snapshot;
+		self.candidates.len()
+	}
+
+	/// The candidate addresses and their values at the last snapshot.
+	pub fn candidates(&self) -> impl Iterator<Item = (u16, u8)> + '_ {
+		self.candidates.iter().map(|&addr| (addr, self.snapshot[addr as usize]))
+	}
+
+	pub fn len(&self) -> usize {
+		self.candidates.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.candidates.is_empty()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Comparison, RamSearch};
+	use crate::nes::NES;
+
+	#[test]
+	fn test_parse() {
+		assert_eq!(Comparison::parse("= 3"), Some(Comparison::Equal(3)));
+		assert_eq!(Comparison::parse("=$FF"), Some(Comparison::Equal(0xFF)));
+		assert_eq!(Comparison::parse("changed"), Some(Comparison::Changed));
+		assert_eq!(Comparison::parse("+"), Some(Comparison::Increased));
+		assert_eq!(Comparison::parse("-1"), Some(Comparison::DecreasedBy(1)));
+		assert_eq!(Comparison::parse("+ $10"), Some(Comparison::IncreasedBy(0x10)));
+		assert_eq!(Comparison::parse("= 256"), None);
+		assert_eq!(Comparison::parse("bigger"), None);
+	}
+
+	#[test]
+	fn test_find_lives() {
+		let mut nes = NES::new_custom_prg_rom([0; 1024*32]);
+		for addr in 0..0x800 {
+			nes.poke(addr, 0);
+		}
+		let lives = 0x075A;
+		nes.poke(lives, 3);
+		nes.poke(0x0100, 3);
+
+		let mut search = RamSearch::new(&mut nes);
+		assert_eq!(search.filter(&mut nes, Comparison::Equal(3)), 2);
+
+		// Lose a life; another address changes too
+		nes.poke(lives, 2);
+		nes.poke(0x0100, 7);
+		nes.poke(0x0200, 1);
+		assert_eq!(search.filter(&mut nes, Comparison::DecreasedBy(1)), 1);
+		assert_eq!(search.candidates().collect::<Vec<_>>(), vec![(lives, 2)]);
+
+		nes.poke(lives, 1);
+		assert_eq!(search.filter(&mut nes, Comparison::Unchanged), 0);
+		assert!(search.is_empty());
+	}
+}
@@ -0,0 +1,166 @@
+//! Diff of two save states: which bytes of the RAM, the PRG RAM, the nametables, the palette and the OAM changed, and
+//! which registers. To find what a game stores where (save before and after losing a life), or where two runs that
+//! should be the same desync.
+
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::nes::NES;
+
+/// Runs of changed bytes shown per region, the rest are only counted.
+const MAX_RUNS: usize = 16;
+
+/// The memories and the registers of a machine.
+pub struct Snapshot {
+	regions: Vec<(&'static str, u16, Vec<u8>)>,	// Name, address of the first byte, bytes
+	registers: Vec<(String, String)>,				// `cpu.pc`, value
+}
+
+impl Snapshot {
+	pub fn capture(nes: &mut NES) -> Self {
+		let ppu = nes.cpu.ppu();
+		let (name_table, palette, oam) = (ppu.name_table().to_vec(), ppu.palette_table().to_vec(), ppu.oam().to_vec());
+		let ram = (0x0000..0x0800).map(|addr| nes.peek(addr)).collect();
+		let prg_ram = (0x6000..=0x7FFF).map(|addr| nes.peek(addr)).collect();
+		let registers = nes.dump_state().chips().into_iter()
+			.flat_map(|(chip, values)| values.into_iter().map(move |(name, value)| (format!("{}.{}", chip, name), value)))
+			.collect();
+		Snapshot {
+			regions: vec![
+				("RAM", 0x0000, ram),
+				("PRG RAM", 0x6000, prg_ram),
+				("Nametables", 0x2000, name_table),
+				("Palette", 0x3F00, palette),
+				("OAM", 0x00, oam),
+			],
+			registers,
+		}
+	}
+
+	/// The machine of a state file. `nes` (with the ROM of the state) loads it, and goes back to where it was.
+	pub fn from_file(nes: &mut NES, path: &Path) -> Result<Self, String> {
+		let current = nes.save_state();
+		nes.load_state_file(path).map_err(|e| e.to_string())?;
+		let snapshot = Snapshot::capture(nes);
+		nes.load_state(current).expect("The state that was just saved loads");
+		Ok(snapshot)
+	}
+}
+
+/// The bytes of a region that changed, in runs of consecutive addresses.
+#[derive(Debug, PartialEq)]
+pub struct RegionDiff {
+	pub name: &'static str,
+	pub runs: Vec<(u16, Vec<u8>, Vec<u8>)>,	// Address, bytes before, bytes after
+}
+
+impl RegionDiff {
+	pub fn changed_bytes(&self) -> usize {
+		self.runs.iter().map(|(_, before, _)| before.len()).sum()
+	}
+}
+
+/// What changed from `before` to `after`.
+#[derive(Debug, PartialEq)]
+pub struct StateDiff {
+	pub regions: Vec<RegionDiff>,					// Only the regions with changes
+	pub registers: Vec<(String, String, String)>,	// Name, before, after
+}
+
+impl StateDiff {
+	pub fn new(before: &Snapshot, after: &Snapshot) -> Self {
+		let mut regions = vec![];
+		for ((name, base, before), (_, _, after)) in before.regions.iter().zip(&after.regions) {
+			let mut runs: Vec<(u16, Vec<u8>, Vec<u8>)> = vec![];
+			for (i, (&a, &b)) in before.iter().zip(after).enumerate().filter(|(_, (a, b))| a != b) {
+				let addr = base + i as u16;
+				match runs.last_mut() {
+					Some((start, run_before, run_after)) if *start as usize + run_before.len() == addr as usize => {
+						run_before.push(a);
+						run_after.push(b);
+					}
+					_ => runs.push((addr, vec![a], vec![b])),
+				}
+			}
+			if !runs.is_empty() {
+				regions.push(RegionDiff { name, runs });
+			}
+		}
+		let registers = before.registers.iter().zip(&after.registers)
+			.filter(|((_, a), (_, b))| a != b)
+			.map(|((name, a), (_, b))| (name.clone(), a.clone(), b.clone()))
+			.collect();
+		StateDiff { regions, registers }
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.regions.is_empty() && self.registers.is_empty()
+	}
+
+	/// A line per run of changed bytes, grouped by region, then a line per register.
+	pub fn report(&self) -> String {
+		let mut report = String::new();
+		for region in &self.regions {
+			writeln!(report, "{}: {} bytes changed", region.name, region.changed_bytes()).unwrap();
+			for (addr, before, after) in region.runs.iter().take(MAX_RUNS) {
+				let hex = |bytes: &[u8]| bytes.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(" ");
+				let end = *addr as usize + before.len() - 1;
+				let range = if before.len() == 1 { format!("${:04X}", addr) } else { format!("${:04X}-${:04X}", addr, end) };
+				writeln!(report, "  {}: {} -> {}", range, hex(before), hex(after)).unwrap();
+			}
+			if region.runs.len() > MAX_RUNS {
+				writeln!(report, "  ... {} more runs", region.runs.len() - MAX_RUNS).unwrap();
+			}
+		}
+		if !self.registers.is_empty() {
+			writeln!(report, "Registers:").unwrap();
+			for (name, before, after) in &self.registers {
+				writeln!(report, "  {}: {} -> {}", name, before, after).unwrap();
+			}
+		}
+		report
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Snapshot, StateDiff};
+	use crate::{nes::NES, program_loader::*};
+
+	#[test]
+	fn test_state_diff() {
+		let mut rom_memory: [u8; 1024*32] = [0;1024*32];
+		load_program_nmi_counter(&mut rom_memory);
+		set_reset_vector(&mut rom_memory, 0x8000);
+		let mut nes = NES::new_custom_prg_rom(rom_memory);
+		// In vblank, OAM can be written
+		nes.run_frames(2);
+		nes.run_until(|nes| nes.cpu.ppu().in_vblank());
+		let before = Snapshot::capture(&mut nes);
+		assert!(StateDiff::new(&before, &Snapshot::capture(&mut nes)).is_empty());
+
+		nes.poke(0x0300, 1);
+		nes.poke(0x0301, 2);
+		nes.poke(0x0305, 3);
+		nes.cpu.ppu_mut().write_oam(0x21);
+		let diff = StateDiff::new(&before, &Snapshot::capture(&mut nes));
+		assert_eq!(diff.regions.iter().map(|region| (region.name, region.changed_bytes())).collect::<Vec<_>>(), [("RAM", 3), ("OAM", 1)]);
+		assert_eq!(diff.regions[0].runs, [(0x0300, vec![0, 0], vec![1, 2]), (0x0305, vec![0], vec![3])]);
+		let report = diff.report();
+		assert!(report.contains("RAM: 3 bytes changed\n  $0300-$0301: 00 00 -> 01 02\n  $0305: 00 -> 03\n"));
+		assert!(report.contains("OAM: 1 bytes changed\n  $0000: 00 -> 21\nRegisters:\n  ppu.oam_addr: 0 -> 1\n"));
+
+		// From save state files, the machine stays where it was
+		nes.run_frame();
+		let path = std::env::temp_dir().join(format!("rust-nes-emulator-diff-{}.state", std::process::id()));
+		nes.save_state_file(&path).unwrap();
+		nes.run_frame();
+		let frame = nes.frame();
+		let diff = StateDiff::new(&Snapshot::from_file(&mut nes, &path).unwrap(), &Snapshot::capture(&mut nes));
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!(nes.frame(), frame);
+		assert!(diff.registers.iter().any(|(name, _, _)| name == "ppu.frame"));
+		assert_eq!(diff.regions[0].runs[0].0, 0x0000);
+		assert!(Snapshot::from_file(&mut nes, &path).is_err());
+	}
+}
@@ -0,0 +1,393 @@
+//! Watch expressions over memory and CPU registers, evaluated every frame by the debugger.
+//!
+//! Examples:
+//!
+//! | Expression | Meaning |
+//! |---|---|
+//! | `$00D0` | Byte at $00D0 |
+//! | `$00D0 as u16` | Little-endian word at $00D0-$00D1 |
+//! | `$00D0 as u16 big-endian` | Big-endian word (also `be`, `little-endian`, `le`) |
+//! | `$0040 as i8` | Signed byte |
+//! | `A + X` | CPU registers: `A`, `X`, `Y`, `S`, `P`, `PC` |
+//! | `($0200 & #$0F) * 2` | Operators: `+ - * & \|`, and parentheses |
+//!
+//! Like in 6502 assembly, `$0F` is memory at address $000F while `#$0F` is the number $0F. Plain decimal numbers (`2`) are numbers too.
+//!
+//! Memory is read with `NES::peek`, so watching PPU registers doesn't change their state.
+
+use std::fmt;
+
+use crate::nes::NES;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Register {
+	A,
+	X,
+	Y,
+	S,
+	P,
+	PC,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MemoryType {
+	U8,
+	I8,
+	U16,
+	I16,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Operator {
+	Add,
+	Sub,
+	Mul,
+	And,
+	Or,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Expr {
+	Number(i64),
+	Register(Register),
+	Memory { addr: u16, mem_type: MemoryType, big_endian: bool },
+	Binary(Box<Expr>, Operator, Box<Expr>),
+}
+
+pub struct Watch {
+	pub source: String,
+	expr: Expr,
+}
+
+impl Watch {
+	pub fn new(source: &str) -> Result<Self, String> {
+		Ok(Watch {
+			source: source.trim().to_string(),
+			expr: parse(source)?,
+		})
+	}
+
+	pub fn evaluate(&self, nes: &mut NES) -> i64 {
+		self.expr.evaluate(nes)
+	}
+}
+
+impl fmt::Display for Watch {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.source)
+	}
+}
+
+impl Expr {
+	pub fn evaluate(&self, nes: &mut NES) -> i64 {
+		match self {
+			Expr::Number(n) => *n,
+			Expr::Register(reg) => {
+				let registers = nes.cpu.registers();
+				match reg {
+					Register::A => registers.A as i64,
+					Register::X => registers.X as i64,
+					Register::Y => registers.Y as i64,
+					Register::S => registers.S as i64,
+					Register::P => registers.P.flags as i64,
+					Register::PC => registers.PC as i64,
+				}
+			}
+			Expr::Memory { addr, mem_type, big_endian } => {
+				let first = nes.peek(*addr);
+				match mem_type {
+					MemoryType::U8 => first as i64,
+					MemoryType::I8 => first as i8 as i64,
+					MemoryType::U16 | MemoryType::I16 => {
+						let second = nes.peek(addr.wrapping_add(1));
+						let word = if *big_endian {
+							u16::from_be_bytes([first, second])
+						} else {
+							u16::from_le_bytes([first, second])
+						};
+						if *mem_type == MemoryType::I16 {
+							word as i16 as i64
+						} else {
+							word as i64
+						}
+					}
+				}
+			}
+			Expr::Binary(lhs, op, rhs) => {
+				let lhs = lhs.evaluate(nes);
+				let rhs = rhs.evaluate(nes);
+				match op {
+					Operator::Add => lhs.wrapping_add(rhs),
+					Operator::Sub => lhs.wrapping_sub(rhs),
+					Operator::Mul => lhs.wrapping_mul(rhs),
+					Operator::And => lhs & rhs,
+					Operator::Or => lhs | rhs,
+				}
+			}
+		}
+	}
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Token {
+	Number(i64),
+	Hex(i64),
+	Immediate,
+	Word(String),
+	Op(char),
+	LParen,
+	RParen,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+	let mut tokens = vec![];
+	let mut chars = source.chars().peekable();
+	while let Some(&c) = chars.peek() {
+		if c.is_whitespace() {
+			chars.next();
+		} else if c == '$' {
+			chars.next();
+			let mut digits = String::new();
+			while let Some(&d) = chars.peek() {
+				if !d.is_ascii_hexdigit() {
+					break;
+				}
+				digits.push(d);
+				chars.next();
+			}
+			let value = i64::from_str_radix(&digits, 16).map_err(|_| format!("Invalid hex number: ${}", digits))?;
+			tokens.push(Token::Hex(value));
+		} else if c.is_ascii_digit() {
+			let mut digits = String::new();
+			while let Some(&d) = chars.peek() {
+				if !d.is_ascii_digit() {
+					break;
+				}
+				digits.push(d);
+				chars.next();
+			}
+			tokens.push(Token::Number(digits.parse().map_err(|_| format!("Invalid number: {}", digits))?));
+		} else if c.is_ascii_alphabetic() {
+			let mut word = String::new();
+			while let Some(&d) = chars.peek() {
+				if !d.is_ascii_alphanumeric() {
+					break;
+				}
+				word.push(d);
+				chars.next();
+			}
+			let mut word = word.to_lowercase();
+			// "little-endian" and "big-endian" are one word, elsewhere '-' is a subtraction ("A-X")
+			let suffix: String = chars.clone().take("-endian".len()).collect();
+			if (word == "little" || word == "big") && suffix.eq_ignore_ascii_case("-endian") {
+				word.push_str("-endian");
+				chars.nth("-endian".len() - 1);
+			}
+			tokens.push(Token::Word(word));
+		} else if "+-*&|".contains(c) {
+			tokens.push(Token::Op(c));
+			chars.next();
+		} else if c == '#' {
+			tokens.push(Token::Immediate);
+			chars.next();
+		} else if c == '(' {
+			tokens.push(Token::LParen);
+			chars.next();
+		} else if c == ')' {
+			tokens.push(Token::RParen);
+			chars.next();
+		} else {
+			return Err(format!("Unexpected character: '{}'", c));
+		}
+	}
+	Ok(tokens)
+}
+
+/// Parse watch expression. Precedence from low to high: `|`, `&`, `+ -`, `*`.
+pub fn parse(source: &str) -> Result<Expr, String> {
+	let tokens = tokenize(source)?;
+	if tokens.is_empty() {
+		return Err("Empty expression".to_string());
+	}
+	let mut parser = Parser { tokens, pos: 0 };
+	let expr = parser.parse_binary(0)?;
+	if parser.pos != parser.tokens.len() {
+		return Err(format!("Unexpected token: {:?}", parser.tokens[parser.pos]));
+	}
+	Ok(expr)
+}
+
+struct Parser {
+	tokens: Vec<Token>,
+	pos: usize,
+}
+
+/// Operators of each precedence level, from low to high
+const PRECEDENCE: [&[(char, Operator)]; 4] = [
+	&[('|', Operator::Or)],
+	&[('&', Operator::And)],
+	&[('+', Operator::Add), ('-', Operator::Sub)],
+	&[('*', Operator::Mul)],
+];
+
+impl Parser {
+	fn peek(&self) -> Option<&Token> {
+		self.tokens.get(self.pos)
+	}
+
+	fn next(&mut self) -> Option<Token> {
+		let token = self.tokens.get(self.pos).cloned();
+		self.pos += 1;
+		token
+	}
+
+	fn parse_binary(&mut self, level: usize) -> Result<Expr, String> {
+		if level == PRECEDENCE.len() {
+			return self.parse_term();
+		}
+		let mut lhs = self.parse_binary(level + 1)?;
+		while let Some(Token::Op(c)) = self.peek() {
+			let op = match PRECEDENCE[level].iter().find(|(symbol, _)| symbol == c) {
+				Some((_, op)) => *op,
+				None => break,
+			};
+			self.next();
+			let rhs = self.parse_binary(level + 1)?;
+			lhs = Expr::Binary(Box::new(lhs), op, Box::new(rhs));
+		}
+		Ok(lhs)
+	}
+
+	fn parse_term(&mut self) -> Result<Expr, String> {
+		match self.next() {
+			Some(Token::Number(n)) => Ok(Expr::Number(n)),
+			Some(Token::Hex(addr)) => {
+				if addr > 0xFFFF {
+					return Err(format!("Address out of range: ${:X}", addr));
+				}
+				self.parse_memory(addr as u16)
+			}
+			Some(Token::Immediate) => match self.next() {
+				Some(Token::Number(n)) | Some(Token::Hex(n)) => Ok(Expr::Number(n)),
+				_ => Err("Expected number after '#'".to_string()),
+			},
+			Some(Token::Word(word)) => {
+				let reg = match word.as_str() {
+					"a" => Register::A,
+					"x" => Register::X,
+					"y" => Register::Y,
+					"s" | "sp" => Register::S,
+					"p" => Register::P,
+					"pc" => Register::PC,
+					_ => return Err(format!("Unknown register: {}", word)),
+				};
+				Ok(Expr::Register(reg))
+			}
+			Some(Token::LParen) => {
+				let expr = self.parse_binary(0)?;
+				match self.next() {
+					Some(Token::RParen) => Ok(expr),
+					_ => Err("Missing ')'".to_string()),
+				}
+			}
+			Some(token) => Err(format!("Unexpected token: {:?}", token)),
+			None => Err("Unexpected end of expression".to_string()),
+		}
+	}
+
+	/// `$addr [as u8|i8|u16|i16] [little-endian|le|big-endian|be]`
+	fn parse_memory(&mut self, addr: u16) -> Result<Expr, String> {
+		let mut mem_type = MemoryType::U8;
+		let mut big_endian = false;
+
+		if self.peek() == Some(&Token::Word("as".to_string())) {
+			self.next();
+			mem_type = match self.next() {
+				Some(Token::Word(word)) => match word.as_str() {
+					"u8" => MemoryType::U8,
+					"i8" => MemoryType::I8,
+					"u16" => MemoryType::U16,
+					"i16" => MemoryType::I16,
+					_ => return Err(format!("Unknown type: {}", word)),
+				},
+				_ => return Err("Expected type after 'as'".to_string()),
+			};
+		}
+
+		if let Some(Token::Word(word)) = self.peek() {
+			match word.as_str() {
+				"little-endian" | "le" => { self.next(); }
+				"big-endian" | "be" => { self.next(); big_endian = true; }
+				_ => {}
+			}
+		}
+
+		Ok(Expr::Memory { addr, mem_type, big_endian })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::program_loader::*;
+
+	fn initialize() -> NES {
+		let mut rom_memory: [u8; 1024*32] = [0;1024*32];
+		load_program_run_helpers(&mut rom_memory);
+		set_reset_vector(&mut rom_memory, 0x8000);
+		NES::new_custom_prg_rom(rom_memory)
+	}
+
+	#[test]
+	fn test_parse() {
+		assert_eq!(parse("$00D0").unwrap(), Expr::Memory { addr: 0xD0, mem_type: MemoryType::U8, big_endian: false });
+		assert_eq!(parse("$00D0 as u16 little-endian").unwrap(), Expr::Memory { addr: 0xD0, mem_type: MemoryType::U16, big_endian: false });
+		assert_eq!(parse("$10 AS i16 BE").unwrap(), Expr::Memory { addr: 0x10, mem_type: MemoryType::I16, big_endian: true });
+		assert_eq!(
+			parse("A + X").unwrap(),
+			Expr::Binary(Box::new(Expr::Register(Register::A)), Operator::Add, Box::new(Expr::Register(Register::X)))
+		);
+		assert_eq!(
+			parse("A-X").unwrap(),
+			Expr::Binary(Box::new(Expr::Register(Register::A)), Operator::Sub, Box::new(Expr::Register(Register::X)))
+		);
+		assert_eq!(parse("$00D0 as u16 Big-Endian").unwrap(), Expr::Memory { addr: 0xD0, mem_type: MemoryType::U16, big_endian: true });
+
+		assert!(parse("").is_err());
+		assert!(parse("$10000").is_err());
+		assert!(parse("A +").is_err());
+		assert!(parse("Q").is_err());
+		assert!(parse("(A").is_err());
+		assert!(parse("$10 as u32").is_err());
+		assert!(parse("A X").is_err());
+		assert!(parse("#A").is_err());
+		assert_eq!(parse("#$10000").unwrap(), Expr::Number(0x10000));
+	}
+
+	#[test]
+	fn test_evaluate() {
+		let mut nes = initialize();
+		nes.poke(0x00D0, 0x34);
+		nes.poke(0x00D1, 0x12);
+		nes.poke(0x0040, 0xFF);
+
+		let eval = |nes: &mut NES, source: &str| Watch::new(source).unwrap().evaluate(nes);
+
+		assert_eq!(eval(&mut nes, "$00D0"), 0x34);
+		assert_eq!(eval(&mut nes, "$00D0 as u16"), 0x1234);
+		assert_eq!(eval(&mut nes, "$00D0 as u16 big-endian"), 0x3412);
+		assert_eq!(eval(&mut nes, "$0040 as i8"), -1);
+		assert_eq!(eval(&mut nes, "$0040 as u8"), 255);
+		assert_eq!(eval(&mut nes, "1 + 2 * 3"), 7);
+		assert_eq!(eval(&mut nes, "(1 + 2) * 3"), 9);
+		assert_eq!(eval(&mut nes, "$00D0 & #$0F | #$100"), 0x104);
+		assert_eq!(eval(&mut nes, "$00D0 & $0F"), 0);
+		assert_eq!(eval(&mut nes, "10 - 3 - 2"), 5);
+
+		// LDA #$01, STA $0200, LDX #$05
+		nes.run_until_pc(0x8007);
+		assert_eq!(eval(&mut nes, "A + X"), 6);
+		assert_eq!(eval(&mut nes, "X-A"), 4);
+		assert_eq!(eval(&mut nes, "pc"), 0x8007);
+	}
+}
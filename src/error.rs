@@ -0,0 +1,179 @@
+//! The errors of the emulator API: opening a ROM, running the CPU and loading a save state. Each has its own enum, and
+//! `EmuError` is any of them, for the functions that can fail in more than one way (e.g. `NesBuilder::try_build`).
+//! The messages are the ones shown to the user, the variants are for the programs that handle some errors.
+
+use std::{error::Error, fmt};
+
+use crate::{cpu::cpu::CpuHalted, mapper::fds, savestate::Component};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmuError {
+	Rom(RomError),
+	Cpu(CpuError),
+	State(StateError),
+}
+
+/// The ROM can't be loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RomError {
+	/// `NesBuilder` without `rom_path`, `prg_chr` or `cartridge`
+	NoRom,
+	/// The file can't be read
+	Read { path: String, reason: String },
+	/// Not an iNES file, or a header with values the emulator doesn't understand
+	InvalidHeader(String),
+	/// The file is bigger or smaller than the header says, with `SizeMismatch::Strict`
+	SizeMismatch(String),
+	/// PRG or CHR sizes the cartridge can't have
+	InvalidSize(String),
+	UnsupportedMapper(u8),
+	/// The FDS BIOS is not next to the disk image nor in the current directory
+	MissingBios { dir: String },
+	InvalidBios { len: usize },
+	/// A VS System setting (`NES::set_vs_ppu`) for a game that is not one
+	NotVsSystem,
+}
+
+/// The CPU can't go on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CpuError {
+	Halted(CpuHalted),
+}
+
+/// The save state can't be saved or loaded. The NES is not changed when loading fails.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateError {
+	Read { path: String, reason: String },
+	Write { path: String, reason: String },
+	Truncated,
+	/// The format version of the header is newer than this emulator
+	NewerFormat(u16),
+	UnknownComponent(String),
+	MissingComponent(Component),
+	/// The version of a component is newer than this emulator
+	NewerComponent { component: Component, version: u16 },
+	/// The version of a component has no migration to the current version
+	OldComponent { component: Component, version: u16 },
+	/// The CRC32 of the state is not the one of the ROM
+	OtherRom { state: u32, rom: u32 },
+	/// The data doesn't match its version. None: a version 0 state, or not a state at all.
+	Damaged(Option<Component>),
+}
+
+impl fmt::Display for EmuError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			EmuError::Rom(err) => err.fmt(f),
+			EmuError::Cpu(err) => err.fmt(f),
+			EmuError::State(err) => err.fmt(f),
+		}
+	}
+}
+
+impl fmt::Display for RomError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			RomError::NoRom => write!(f, "NesBuilder needs a ROM: rom_path, prg_chr or cartridge"),
+			RomError::Read { path, reason } => write!(f, "Could not read the ROM {}: {}", path, reason),
+			RomError::InvalidHeader(reason) => write!(f, "Invalid iNES header: {}", reason),
+			RomError::SizeMismatch(adjustment) => write!(f, "The file size doesn't match the header: {}", adjustment),
+			RomError::InvalidSize(reason) => write!(f, "{}", reason),
+			RomError::UnsupportedMapper(fds::MAPPER_NUMBER) => write!(f, "Mapper 20 is the FDS, open the .fds disk image instead"),
+			RomError::UnsupportedMapper(mapper) => write!(f, "The emulator doesn't support mapper {}", mapper),
+			RomError::MissingBios { dir } => write!(f, "The FDS BIOS ({}) was not found in {} or in the current directory", fds::BIOS_FILE_NAME, dir),
+			RomError::InvalidBios { len } => write!(f, "The FDS BIOS must be 8KB, it is {} bytes", len),
+			RomError::NotVsSystem => write!(f, "Not a VS System game"),
+		}
+	}
+}
+
+impl fmt::Display for CpuError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			CpuError::Halted(halted) => halted.fmt(f),
+		}
+	}
+}
+
+impl fmt::Display for StateError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			StateError::Read { path, reason } => write!(f, "Could not read the state {}: {}", path, reason),
+			StateError::Write { path, reason } => write!(f, "Could not write the state {}: {}", path, reason),
+			StateError::Truncated => write!(f, "The state file is truncated"),
+			StateError::NewerFormat(version) => write!(f, "The state was saved by a newer emulator (format version {}, this emulator reads up to {})", version, crate::savestate::FORMAT_VERSION),
+			StateError::UnknownComponent(tag) => write!(f, "Unknown component in the state: {}", tag),
+			StateError::MissingComponent(component) => write!(f, "The state has no {:?} component", component),
+			StateError::NewerComponent { component, version } => write!(f, "The {:?} state was saved by a newer emulator (version {}, this emulator reads up to {})", component, version, component.version()),
+			StateError::OldComponent { component, version } => write!(f, "The {:?} state is too old (version {}), it can't be converted to version {}", component, version, component.version()),
+			StateError::OtherRom { state, rom } => write!(f, "The state is of another ROM (CRC32 {:08X}, this ROM is {:08X})", state, rom),
+			StateError::Damaged(Some(component)) => write!(f, "The {:?} state doesn't match its version, the state is damaged", component),
+			StateError::Damaged(None) => write!(f, "Not a save state, or the state is damaged"),
+		}
+	}
+}
+
+impl Error for EmuError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			EmuError::Rom(err) => Some(err),
+			EmuError::Cpu(err) => Some(err),
+			EmuError::State(err) => Some(err),
+		}
+	}
+}
+
+impl Error for RomError {}
+impl Error for CpuError {}
+impl Error for StateError {}
+
+impl From<RomError> for EmuError {
+	fn from(err: RomError) -> Self {
+		EmuError::Rom(err)
+	}
+}
+
+impl From<CpuError> for EmuError {
+	fn from(err: CpuError) -> Self {
+		EmuError::Cpu(err)
+	}
+}
+
+impl From<StateError> for EmuError {
+	fn from(err: StateError) -> Self {
+		EmuError::State(err)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::error::Error;
+
+	use super::{EmuError, RomError, StateError};
+	use crate::{builder::NesBuilder, cartridge::Cartridge, nes::NES, rom_parser::{MirrorType, SizeMismatch}, vs_system::VsPpu};
+
+	#[test]
+	fn test_errors() {
+		let err = NesBuilder::new().try_build().err().unwrap();
+		assert_eq!(err, EmuError::Rom(RomError::NoRom));
+		let err = NesBuilder::new().rom_path("no such file.nes").try_build().err().unwrap();
+		assert!(matches!(err, EmuError::Rom(RomError::Read { .. })));
+		assert!(err.source().unwrap().to_string().starts_with("Could not read the ROM no such file.nes"));
+		let err = NesBuilder::new().prg_chr(&[0xEA; 100], &[], 0, MirrorType::VERTICAL).try_build().err().unwrap();
+		assert!(matches!(err, EmuError::Rom(RomError::InvalidSize(_))));
+		let err = NesBuilder::new().prg_chr(&[0xEA; 1024 * 32], &[], 4, MirrorType::VERTICAL).try_build().err().unwrap();
+		assert_eq!(err, EmuError::Rom(RomError::UnsupportedMapper(4)));
+		assert_eq!(RomError::UnsupportedMapper(20).to_string(), "Mapper 20 is the FDS, open the .fds disk image instead");
+		// The cartridge for NesBuilder::cartridge fails before the builder
+		let err = Cartridge::from_prg_chr(vec![0xEA; 1024 * 32], vec![], 20, MirrorType::VERTICAL, None).err();
+		assert_eq!(err, Some(RomError::UnsupportedMapper(20)));
+		assert!(matches!(NES::open_rom_file("src/main.rs", SizeMismatch::Fix), Err(RomError::InvalidHeader(_))));
+
+		let mut nes = NES::new_open_rom_file("6502asm_programs/nestest/nestest.nes");
+		assert_eq!(nes.set_vs_ppu(VsPpu::Rp2c03), Err(RomError::NotVsSystem));
+		let state = nes.save_state();
+		assert_eq!(nes.load_state(state[..state.len() - 1].to_vec()), Err(StateError::Truncated));
+		let err = nes.load_state_file(std::path::Path::new("no such file.state0")).unwrap_err();
+		assert!(matches!(err, StateError::Read { .. }));
+	}
+}
\ No newline at end of file
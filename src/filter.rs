@@ -0,0 +1,360 @@
+use crate::config::Config;
+use crate::ppu::{colors::Palette, layers::TRANSPARENT};
+
+/// An RGB picture, 3 bytes per pixel, row by row.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+	pub width: usize,
+	pub height: usize,
+	pub rgb: Vec<u8>,
+}
+
+impl Image {
+	pub fn new(width: usize, height: usize) -> Self {
+		Image {
+			width,
+			height,
+			rgb: vec![0; width * height * 3],
+		}
+	}
+
+	/// The picture of a framebuffer (NES color indexes, see PPU::framebuffer) with the color emphasis of each scanline.
+	/// `TRANSPARENT` pixels (layers) are black.
+	pub fn from_framebuffer(width: usize, pixels: &[u8], emphasis: &[u8], palette: &Palette) -> Self {
+		let mut image = Image::new(width, pixels.len() / width);
+		for (i, &color) in pixels.iter().enumerate() {
+			let (r, g, b) = if color == TRANSPARENT { (0, 0, 0) } else { palette.rgb(color, emphasis[i / width]) };
+			image.rgb[i * 3..i * 3 + 3].copy_from_slice(&[r, g, b]);
+		}
+		image
+	}
+
+	/// The pixel at (x, y). Outside the picture, the nearest edge pixel.
+	pub fn pixel(&self, x: isize, y: isize) -> [u8; 3] {
+		let x = x.clamp(0, self.width as isize - 1) as usize;
+		let y = y.clamp(0, self.height as isize - 1) as usize;
+		let i = (y * self.width + x) * 3;
+		[self.rgb[i], self.rgb[i + 1], self.rgb[i + 2]]
+	}
+
+	pub fn set_pixel(&mut self, x: usize, y: usize, pixel: [u8; 3]) {
+		let i = (y * self.width + x) * 3;
+		self.rgb[i..i + 3].copy_from_slice(&pixel);
+	}
+}
+
+/// A step between the PPU picture and the window: scaling, effects...
+/// Filters run on the render thread, so they must be `Send`.
+pub trait Filter: Send {
+	fn name(&self) -> &str;
+
+	fn apply(&mut self, input: &Image) -> Image;
+}
+
+/// A user provided filter.
+pub struct FnFilter<F: FnMut(&Image) -> Image + Send> {
+	name: String,
+	f: F,
+}
+
+impl<F: FnMut(&Image) -> Image + Send> FnFilter<F> {
+	pub fn new(name: &str, f: F) -> Self {
+		FnFilter {
+			name: name.to_string(),
+			f,
+		}
+	}
+}
+
+impl<F: FnMut(&Image) -> Image + Send> Filter for FnFilter<F> {
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	fn apply(&mut self, input: &Image) -> Image {
+		(self.f)(input)
+	}
+}
+
+/// Filters applied one after the other. An empty chain shows the picture as is.
+pub struct FilterChain {
+	filters: Vec<Box<dyn Filter>>,
+}
+
+impl FilterChain {
+	pub fn new(filters: Vec<Box<dyn Filter>>) -> Self {
+		FilterChain { filters }
+	}
+
+	pub fn name(&self) -> String {
+		if self.filters.is_empty() {
+			return "none".to_string();
+		}
+		self.filters.iter().map(|filter| filter.name()).collect::<Vec<_>>().join(" + ")
+	}
+
+	pub fn apply(&mut self, input: Image) -> Image {
+		self.filters.iter_mut().fold(input, |image, filter| filter.apply(&image))
+	}
+}
+
+/// The filter chains that the window cycles through (F5). The first one is used on start.
+pub fn presets(config: &Config) -> Vec<FilterChain> {
+	presets_with(config, vec![])
+}
+
+/// The presets followed by the chains of the program, e.g. to add its own filter to the screenshots:
+///
+/// ```
+/// use rust_nes_emulator::{config::Config, filter::{self, FilterChain, FnFilter, Image, Scale2x}};
+///
+/// let gray = FnFilter::new("gray", |image: &Image| {
+///     let rgb = image.rgb.chunks_exact(3).flat_map(|p| [((p[0] as u16 + p[1] as u16 + p[2] as u16) / 3) as u8; 3]).collect();
+///     Image { rgb, ..image.clone() }
+/// });
+/// let mut chains = filter::presets_with(&Config::parse(""), vec![FilterChain::new(vec![Box::new(Scale2x), Box::new(gray)])]);
+/// let chain = chains.last_mut().unwrap();
+/// assert_eq!(chain.name(), "scale2x + gray");
+/// assert_eq!(chain.apply(Image::new(256, 240)).width, 512);
+/// ```
+pub fn presets_with(config: &Config, extra: Vec<FilterChain>) -> Vec<FilterChain> {
+	let mut chains = vec![
+		FilterChain::new(vec![]),
+		FilterChain::new(vec![Box::new(Scale2x)]),
+		FilterChain::new(vec![Box::new(Scale3x)]),
+		FilterChain::new(vec![Box::new(Scale2x), Box::new(Scale2x)]),
+		FilterChain::new(vec![Box::new(Crt::from_config(config))]),
+	];
+	chains.extend(extra);
+	chains
+}
+
+/// Scale2x (AdvanceMAME): doubles the size, and rounds the diagonal edges instead of making them blocky.
+/// Read here: https://www.scale2x.it/algorithm
+pub struct Scale2x;
+
+impl Filter for Scale2x {
+	fn name(&self) -> &str {
+		"scale2x"
+	}
+
+	fn apply(&mut self, input: &Image) -> Image {
+		let mut output = Image::new(input.width * 2, input.height * 2);
+		for y in 0..input.height {
+			for x in 0..input.width {
+				let (xi, yi) = (x as isize, y as isize);
+				//   B
+				// D E F
+				//   H
+				let b = input.pixel(xi, yi - 1);
+				let d = input.pixel(xi - 1, yi);
+				let e = input.pixel(xi, yi);
+				let f = input.pixel(xi + 1, yi);
+				let h = input.pixel(xi, yi + 1);
+
+				let (mut e0, mut e1, mut e2, mut e3) = (e, e, e, e);
+				if b != h && d != f {
+					if d == b { e0 = d; }
+					if b == f { e1 = f; }
+					if d == h { e2 = d; }
+					if h == f { e3 = f; }
+				}
+				output.set_pixel(x * 2, y * 2, e0);
+				output.set_pixel(x * 2 + 1, y * 2, e1);
+				output.set_pixel(x * 2, y * 2 + 1, e2);
+				output.set_pixel(x * 2 + 1, y * 2 + 1, e3);
+			}
+		}
+		output
+	}
+}
+
+/// Scale3x (AdvanceMAME): like Scale2x, but triples the size.
+pub struct Scale3x;
+
+impl Filter for Scale3x {
+	fn name(&self) -> &str {
+		"scale3x"
+	}
+
+	fn apply(&mut self, input: &Image) -> Image {
+		let mut output = Image::new(input.width * 3, input.height * 3);
+		for y in 0..input.height {
+			for x in 0..input.width {
+				let (xi, yi) = (x as isize, y as isize);
+				// A B C
+				// D E F
+				// G H I
+				let a = input.pixel(xi - 1, yi - 1);
+				let b = input.pixel(xi, yi - 1);
+				let c = input.pixel(xi + 1, yi - 1);
+				let d = input.pixel(xi - 1, yi);
+				let e = input.pixel(xi, yi);
+				let f = input.pixel(xi + 1, yi);
+				let g = input.pixel(xi - 1, yi + 1);
+				let h = input.pixel(xi, yi + 1);
+				let i = input.pixel(xi + 1, yi + 1);
+
+				let mut out = [e; 9];
+				if b != h && d != f {
+					if d == b { out[0] = d; }
+					if (d == b && e != c) || (b == f && e != a) { out[1] = b; }
+					if b == f { out[2] = f; }
+					if (d == b && e != g) || (d == h && e != a) { out[3] = d; }
+					if (b == f && e != i) || (h == f && e != c) { out[5] = f; }
+					if d == h { out[6] = d; }
+					if (d == h && e != i) || (h == f && e != g) { out[7] = h; }
+					if h == f { out[8] = f; }
+				}
+				for (n, &pixel) in out.iter().enumerate() {
+					output.set_pixel(x * 3 + n % 3, y * 3 + n / 3, pixel);
+				}
+			}
+		}
+		output
+	}
+}
+
+/// CRT look: dark lines between the scanlines, a phosphor mask (red, green and blue columns), darker corners (vignette)
+/// and a curved screen. Triples the size, so the scanlines and the mask are visible.
+/// The intensities are 0.0 (off) to 1.0.
+pub struct Crt {
+	pub scanlines: f32,
+	pub mask: f32,
+	pub vignette: f32,
+	pub curvature: f32,
+}
+
+impl Crt {
+	const SCALE: usize = 3;
+
+	/// The intensities from the settings file: `crt.scanlines`, `crt.mask`, `crt.vignette` and `crt.curvature`.
+	pub fn from_config(config: &Config) -> Self {
+		Crt {
+			scanlines: config.get("crt.scanlines", 0.3),
+			mask: config.get("crt.mask", 0.15),
+			vignette: config.get("crt.vignette", 0.2),
+			curvature: config.get("crt.curvature", 0.03),
+		}
+	}
+}
+
+impl Filter for Crt {
+	fn name(&self) -> &str {
+		"crt"
+	}
+
+	fn apply(&mut self, input: &Image) -> Image {
+		let (width, height) = (input.width * Crt::SCALE, input.height * Crt::SCALE);
+		let mut output = Image::new(width, height);
+		for y in 0..height {
+			for x in 0..width {
+				// -1.0 to 1.0 from the center of the screen
+				let u = (x as f32 + 0.5) / width as f32 * 2.0 - 1.0;
+				let v = (y as f32 + 0.5) / height as f32 * 2.0 - 1.0;
+				let distance = u * u + v * v;	// 0.0 at the center, 2.0 at the corners
+
+				// The picture is bent away from the center, the corners of the output are outside of it (black)
+				let bend = 1.0 + self.curvature * distance;
+				let (u, v) = (u * bend, v * bend);
+				if u.abs() > 1.0 || v.abs() > 1.0 {
+					continue;
+				}
+				let source_x = (((u + 1.0) / 2.0 * width as f32) as usize).min(width - 1);
+				let source_y = (((v + 1.0) / 2.0 * height as f32) as usize).min(height - 1);
+				let pixel = input.pixel((source_x / Crt::SCALE) as isize, (source_y / Crt::SCALE) as isize);
+
+				let mut brightness = 1.0 - self.vignette * distance / 2.0;
+				if source_y % Crt::SCALE == Crt::SCALE - 1 {
+					brightness *= 1.0 - self.scanlines;
+				}
+				let mut color = [0; 3];
+				for (channel, value) in color.iter_mut().enumerate() {
+					let mask = if source_x % Crt::SCALE == channel { 1.0 } else { 1.0 - self.mask };
+					*value = (pixel[channel] as f32 * brightness * mask).round().clamp(0.0, 255.0) as u8;
+				}
+				output.set_pixel(x, y, color);
+			}
+		}
+		output
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Crt, Filter, FilterChain, FnFilter, Image, Scale2x, Scale3x};
+
+	const BLACK: [u8; 3] = [0, 0, 0];
+	const WHITE: [u8; 3] = [255, 255, 255];
+
+	/// A diagonal line of white pixels on black, from the top left.
+	fn diagonal() -> Image {
+		let mut image = Image::new(3, 3);
+		for i in 0..3 {
+			image.set_pixel(i, i, WHITE);
+		}
+		image
+	}
+
+	#[test]
+	fn test_scale2x() {
+		let output = Scale2x.apply(&diagonal());
+		assert_eq!((output.width, output.height), (6, 6));
+		// The center pixel grows towards its diagonal neighbours, the corners of the black area are rounded
+		assert_eq!(output.pixel(2, 2), WHITE);
+		assert_eq!(output.pixel(3, 2), WHITE);
+		assert_eq!(output.pixel(2, 3), WHITE);
+		assert_eq!(output.pixel(4, 2), BLACK);
+		assert_eq!(output.pixel(2, 4), BLACK);
+		assert_eq!(output.pixel(3, 1), BLACK);
+		assert_eq!(output.pixel(1, 3), BLACK);
+	}
+
+	#[test]
+	fn test_scale3x_flat() {
+		let mut image = Image::new(2, 2);
+		image.rgb.fill(0x80);
+		let output = Scale3x.apply(&image);
+		assert_eq!((output.width, output.height), (6, 6));
+		assert!(output.rgb.iter().all(|&value| value == 0x80));
+	}
+
+	#[test]
+	fn test_crt() {
+		let mut image = Image::new(4, 4);
+		image.rgb.fill(255);
+		let mut crt = Crt { scanlines: 0.5, mask: 0.0, vignette: 0.0, curvature: 0.0 };
+		let output = crt.apply(&image);
+		assert_eq!((output.width, output.height), (12, 12));
+		assert_eq!(output.pixel(0, 1), WHITE);
+		assert_eq!(output.pixel(0, 2), [128; 3]);
+
+		// Phosphor mask: red, green and blue columns
+		let mut crt = Crt { scanlines: 0.0, mask: 0.5, vignette: 0.0, curvature: 0.0 };
+		let output = crt.apply(&image);
+		assert_eq!(output.pixel(0, 0), [255, 128, 128]);
+		assert_eq!(output.pixel(1, 0), [128, 255, 128]);
+
+		// The corners are darker, and cut by the curvature
+		let mut crt = Crt { scanlines: 0.0, mask: 0.0, vignette: 0.5, curvature: 0.0 };
+		let output = crt.apply(&image);
+		assert!(output.pixel(0, 0)[0] < output.pixel(6, 6)[0]);
+		let mut crt = Crt { scanlines: 0.0, mask: 0.0, vignette: 0.0, curvature: 0.2 };
+		let output = crt.apply(&image);
+		assert_eq!(output.pixel(0, 0), BLACK);
+		assert_eq!(output.pixel(6, 6), WHITE);
+	}
+
+	#[test]
+	fn test_chain() {
+		let invert = FnFilter::new("invert", |image: &Image| {
+			Image { rgb: image.rgb.iter().map(|value| !value).collect(), ..image.clone() }
+		});
+		let mut chain = FilterChain::new(vec![Box::new(Scale2x), Box::new(invert)]);
+		assert_eq!(chain.name(), "scale2x + invert");
+		let output = chain.apply(diagonal());
+		assert_eq!((output.width, output.height), (6, 6));
+		assert_eq!(output.pixel(0, 0), BLACK);
+		assert_eq!(output.pixel(5, 0), WHITE);
+	}
+}
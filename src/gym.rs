@@ -0,0 +1,159 @@
+//! Reinforcement learning environment, in the style of OpenAI Gym: `reset` starts an episode, `step` plays one action
+//! and returns what the agent sees, its reward and whether the episode is over.
+//!
+//! The reward and the end of the episode are callbacks that read the game memory, since every game keeps its score and
+//! lives somewhere else (use the debugger `search` command to find them):
+//!
+//! ```ignore
+//! let mut env = Env::new(NES::new_open_rom_file("smb.nes"))
+//!     .with_reward(ram_delta(0x0086))             // Mario's X position
+//!     .with_done(|nes: &mut NES| nes.peek(0x075A) == 0xFF)  // Game over
+//!     .with_frame_skip(4);
+//! let mut observation = env.reset();
+//! loop {
+//!     let step = env.step(Button::Right.mask() | Button::A.mask());
+//!     if step.done { break; }
+//! }
+//! ```
+//!
+//! Only built with the `gym` feature.
+
+use crate::nes::NES;
+
+/// What the agent gets after each action.
+pub struct Step {
+	/// The picture, 256x240 NES color indexes (0x00-0x3F), see `PPU::framebuffer`.
+	pub observation: Vec<u8>,
+	pub reward: f32,
+	pub done: bool,
+}
+
+pub type RewardFn = Box<dyn FnMut(&mut NES) -> f32 + Send>;
+pub type DoneFn = Box<dyn FnMut(&mut NES) -> bool + Send>;
+
+pub struct Env {
+	nes: NES,
+	start_state: Vec<u8>,	// Each episode starts here
+	reward: RewardFn,
+	done: DoneFn,
+	frame_skip: u32,		// Frames per action
+	max_frames: Option<u64>,	// Frames per episode
+	frames: u64,			// Frames of the current episode
+}
+
+impl Env {
+	/// The episodes start from the current state of `nes`, e.g. after skipping the title screen. Without callbacks the
+	/// reward is always 0 and the episodes never end.
+	pub fn new(mut nes: NES) -> Self {
+		Env {
+			start_state: nes.save_state(),
+			nes,
+			reward: Box::new(|_| 0.0),
+			done: Box::new(|_| false),
+			frame_skip: 1,
+			max_frames: None,
+			frames: 0,
+		}
+	}
+
+	/// The reward of a step, called after the frames of the action ran.
+	pub fn with_reward<F: FnMut(&mut NES) -> f32 + Send + 'static>(mut self, reward: F) -> Self {
+		self.reward = Box::new(reward);
+		self
+	}
+
+	/// Is the episode over, called after the frames of the action ran.
+	pub fn with_done<F: FnMut(&mut NES) -> bool + Send + 'static>(mut self, done: F) -> Self {
+		self.done = Box::new(done);
+		self
+	}
+
+	/// Hold each action for this many frames (at least 1).
+	pub fn with_frame_skip(mut self, frames: u32) -> Self {
+		self.frame_skip = frames.max(1);
+		self
+	}
+
+	/// End the episodes after this many frames.
+	pub fn with_max_frames(mut self, frames: u64) -> Self {
+		self.max_frames = Some(frames);
+		self
+	}
+
+	/// Start a new episode. Returns the first observation.
+	pub fn reset(&mut self) -> Vec<u8> {
+		self.nes.load_state(self.start_state.clone()).expect("The start state is of this NES");
+		self.frames = 0;
+		// Sets up the callbacks that remember the last value (e.g. `ram_delta`)
+		(self.reward)(&mut self.nes);
+		self.nes.cpu.ppu().framebuffer().to_vec()
+	}
+
+	/// Press the buttons of `action` (bit per `Button`, player 1) and run the frames of the action.
+	pub fn step(&mut self, action: u8) -> Step {
+		self.nes.cpu.controller_mut(0).set_buttons(action);
+		for _ in 0..self.frame_skip {
+			self.nes.run_frame();
+		}
+		self.frames += self.frame_skip as u64;
+
+		let reward = (self.reward)(&mut self.nes);
+		let done = (self.done)(&mut self.nes) || self.max_frames.is_some_and(|max| self.frames >= max);
+		Step { observation: self.nes.cpu.ppu().framebuffer().to_vec(), reward, done }
+	}
+
+	/// The NES, e.g. to read more of the memory.
+	pub fn nes(&mut self) -> &mut NES {
+		&mut self.nes
+	}
+}
+
+/// Reward the change of a byte of memory since the last call, e.g. a score or a position. Wraps around like the byte.
+pub fn ram_delta(addr: u16) -> impl FnMut(&mut NES) -> f32 + Send {
+	let mut last = None;
+	move |nes: &mut NES| {
+		let value = nes.peek(addr);
+		let delta = last.map_or(0, |last: u8| value.wrapping_sub(last) as i8);
+		last = Some(value);
+		delta as f32
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{ram_delta, Env};
+	use crate::{controller::Button, nes::NES, program_loader::*};
+
+	/// Counts the frames with A pressed in $0200.
+	fn initialize() -> NES {
+		let mut rom_memory: [u8; 1024*32] = [0;1024*32];
+		load_program_count_a_presses(&mut rom_memory);
+		set_reset_vector(&mut rom_memory, 0x8000);
+		NES::new_custom_prg_rom(rom_memory)
+	}
+
+	#[test]
+	fn test_episodes() {
+		let mut env = Env::new(initialize())
+			.with_reward(ram_delta(0x0200))
+			.with_done(|nes: &mut NES| nes.peek(0x0200) >= 6)
+			.with_frame_skip(2)
+			.with_max_frames(100);
+
+		let observation = env.reset();
+		assert_eq!(observation.len(), 256 * 240);
+		let step = env.step(0);
+		assert_eq!((step.reward, step.done), (0.0, false));
+		let step = env.step(Button::A.mask());
+		assert_eq!((step.reward, step.done), (2.0, false));
+		env.step(Button::A.mask());
+		let step = env.step(Button::A.mask());
+		assert_eq!((step.reward, step.done), (2.0, true));
+
+		// A new episode starts from the start state, and ends after 100 frames
+		env.reset();
+		assert_eq!(env.nes().peek(0x0200), 0);
+		let steps = std::iter::repeat_with(|| env.step(0)).take_while(|step| !step.done).count();
+		assert_eq!(steps, 49);
+	}
+}
@@ -0,0 +1,270 @@
+//! Runs a ROM without a window and saves the picture as a PNG, to check the palette test ROMs (full_palette, color_test)
+//! and other picture tests against reference screenshots. The video sinks record or check the frames, with a window
+//! too.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::sync::{Arc, Mutex};
+
+use log::{error, info};
+
+use crate::callbacks::{Frame, VideoSink};
+use crate::filter::Image;
+use crate::nes::NES;
+use crate::ppu::ppu::SCREEN_WIDTH;
+
+/// The picture the PPU shows, with the color emphasis.
+pub fn screenshot(nes: &NES) -> Image {
+	let ppu = nes.cpu.ppu();
+	Image::from_framebuffer(SCREEN_WIDTH, ppu.framebuffer(), ppu.emphasis(), ppu.rgb_palette())
+}
+
+/// CRC32 of the picture, to compare pictures without keeping a PNG of each (see `mapper_suite`).
+pub fn framebuffer_hash(nes: &NES) -> u32 {
+	let ppu = nes.cpu.ppu();
+	pixels_hash(ppu.framebuffer(), ppu.emphasis())
+}
+
+/// CRC32 of what the PPU outputs: the color indexes and the emphasis of each scanline. Not of the RGB colors, which
+/// are the TV's (the palette settings, and floating point math that may round differently on other platforms).
+fn pixels_hash(pixels: &[u8], emphasis: &[u8]) -> u32 {
+	let mut hasher = crc32fast::Hasher::new();
+	hasher.update(pixels);
+	hasher.update(emphasis);
+	hasher.finalize()
+}
+
+/// The picture of a frame of the video sinks.
+pub fn frame_image(frame: &Frame) -> Image {
+	Image::from_framebuffer(SCREEN_WIDTH, frame.pixels, frame.emphasis, frame.palette)
+}
+
+pub fn save_png(path: &str, image: &Image) -> io::Result<()> {
+	let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), image.width as u32, image.height as u32);
+	encoder.set_color(png::ColorType::Rgb);
+	encoder.set_depth(png::BitDepth::Eight);
+	encoder.write_header()?.write_image_data(&image.rgb)?;
+	Ok(())
+}
+
+/// Load an RGB or RGBA PNG (the alpha is ignored), e.g. a screenshot of another emulator.
+pub fn load_png(path: &str) -> io::Result<Image> {
+	let mut decoder = png::Decoder::new(File::open(path)?);
+	// Indexed and 16 bit images become 8 bit RGB
+	decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+	let mut reader = decoder.read_info()?;
+	let mut data = vec![0; reader.output_buffer_size()];
+	let info = reader.next_frame(&mut data)?;
+	let channels = match info.color_type {
+		png::ColorType::Rgb => 3,
+		png::ColorType::Rgba => 4,
+		color_type => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?} PNG, expected RGB", color_type))),
+	};
+	let mut image = Image::new(info.width as usize, info.height as usize);
+	for (pixel, rgb) in data[..info.buffer_size()].chunks_exact(channels).zip(image.rgb.chunks_exact_mut(3)) {
+		rgb.copy_from_slice(&pixel[..3]);
+	}
+	Ok(image)
+}
+
+/// The number of pixels that differ, None when the sizes differ.
+pub fn compare(image: &Image, reference: &Image) -> Option<usize> {
+	if (image.width, image.height) != (reference.width, reference.height) {
+		return None;
+	}
+	Some(image.rgb.chunks_exact(3).zip(reference.rgb.chunks_exact(3)).filter(|(a, b)| a != b).count())
+}
+
+/// Run `frames` frames, then save the picture to `screenshot_path` and compare it with the `reference_path` PNG. Returns
+/// whether the picture is the same as the reference (true without a reference).
+pub fn run(nes: &mut NES, frames: u64, screenshot_path: Option<&str>, reference_path: Option<&str>) -> bool {
+	nes.run_frames(frames);
+	let image = screenshot(nes);
+	if let Some(path) = screenshot_path {
+		match save_png(path, &image) {
+			Ok(()) => info!("Saved the screenshot of frame {} to {}", nes.frame(), path),
+			Err(e) => error!("Could not save the screenshot to {}: {}", path, e),
+		}
+	}
+	let Some(path) = reference_path else {
+		return true;
+	};
+	match load_png(path).map(|reference| compare(&image, &reference)) {
+		Ok(Some(0)) => {
+			info!("The picture is the same as {}", path);
+			true
+		}
+		Ok(Some(pixels)) => {
+			error!("{} pixels differ from {}", pixels, path);
+			false
+		}
+		Ok(None) => {
+			error!("{} isn't {}x{}", path, image.width, image.height);
+			false
+		}
+		Err(e) => {
+			error!("Could not load the reference {}: {}", path, e);
+			false
+		}
+	}
+}
+
+/// Saves each frame as a PNG in a directory, `000123.png` for frame 123. Stops at the first error.
+pub struct PngRecorder {
+	dir: String,
+	failed: bool,
+}
+
+impl PngRecorder {
+	pub fn new(dir: &str) -> io::Result<Self> {
+		fs::create_dir_all(dir)?;
+		Ok(PngRecorder { dir: dir.to_string(), failed: false })
+	}
+}
+
+impl VideoSink for PngRecorder {
+	fn push_frame(&mut self, frame: &Frame) {
+		if self.failed {
+			return;
+		}
+		let path = format!("{}/{:06}.png", self.dir, frame.number);
+		if let Err(e) = save_png(&path, &frame_image(frame)) {
+			error!("Could not save the frame to {}, stopped recording: {}", path, e);
+			self.failed = true;
+		}
+	}
+}
+
+#[derive(Default)]
+struct Hashes {
+	expected: HashMap<u64, u32>,
+	seen: Vec<(u64, u32)>,
+	mismatches: Vec<u64>,
+}
+
+/// Checks the CRC32 of the frame pictures (like `framebuffer_hash`) against the expected ones. Clones share the
+/// hashes, so the program keeps one and gives one to the NES.
+#[derive(Clone, Default)]
+pub struct HashChecker(Arc<Mutex<Hashes>>);
+
+impl HashChecker {
+	/// Checks the frames of `expected` (frame, hash), the other frames are only hashed.
+	pub fn new(expected: HashMap<u64, u32>) -> Self {
+		HashChecker(Arc::new(Mutex::new(Hashes { expected, ..Hashes::default() })))
+	}
+
+	/// A file of `save`: a line `<frame> <CRC32 in hex>` per frame.
+	pub fn load(path: &str) -> Result<Self, String> {
+		let text = fs::read_to_string(path).map_err(|e| format!("Can't read {}: {}", path, e))?;
+		let mut expected = HashMap::new();
+		for (number, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+			let hash = line.split_once(' ').and_then(|(frame, hash)| Some((frame.parse().ok()?, u32::from_str_radix(hash.trim(), 16).ok()?)));
+			let (frame, hash) = hash.ok_or(format!("{}:{}: expected <frame> <CRC32>", path, number + 1))?;
+			expected.insert(frame, hash);
+		}
+		Ok(HashChecker::new(expected))
+	}
+
+	/// Write the hashes of all the frames seen.
+	pub fn save(&self, path: &str) -> io::Result<()> {
+		let text: String = self.hashes().iter().map(|(frame, hash)| format!("{} {:08X}\n", frame, hash)).collect();
+		fs::write(path, text)
+	}
+
+	/// The (frame, hash) of each frame seen.
+	pub fn hashes(&self) -> Vec<(u64, u32)> {
+		self.0.lock().unwrap().seen.clone()
+	}
+
+	/// The frames whose hash isn't the expected one.
+	pub fn mismatches(&self) -> Vec<u64> {
+		self.0.lock().unwrap().mismatches.clone()
+	}
+}
+
+impl VideoSink for HashChecker {
+	fn push_frame(&mut self, frame: &Frame) {
+		let hash = pixels_hash(frame.pixels, frame.emphasis);
+		let mut hashes = self.0.lock().unwrap();
+		hashes.seen.push((frame.number, hash));
+		if hashes.expected.get(&frame.number).is_some_and(|&expected| expected != hash) {
+			error!("Frame {} has the hash {:08X}, expected {:08X}", frame.number, hash, hashes.expected[&frame.number]);
+			hashes.mismatches.push(frame.number);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use super::{compare, framebuffer_hash, load_png, run, save_png, screenshot, HashChecker, PngRecorder};
+	use crate::{nes::NES, program_loader::*};
+
+	#[test]
+	fn test_screenshots() {
+		let mut rom_memory: [u8; 1024*32] = [0;1024*32];
+		load_program_scroll(&mut rom_memory);
+		set_reset_vector(&mut rom_memory, 0x8000);
+		let mut nes = NES::new_custom_prg_rom(rom_memory);
+
+		let path = std::env::temp_dir().join(format!("rust-nes-emulator-{}.png", std::process::id()));
+		let path = path.to_str().unwrap();
+		assert!(run(&mut nes, 3, Some(path), None));
+		let reference = load_png(path).unwrap();
+		assert_eq!(reference, screenshot(&nes));
+		assert!(run(&mut nes, 1, None, Some(path)));
+
+		// Red emphasis darkens the green and blue of the red tile, the black backdrop stays black
+		nes.poke(0x2001, 0b0010_1010);
+		nes.run_frame();
+		assert!(matches!(compare(&screenshot(&nes), &reference), Some(pixels) if pixels > 0 && pixels < 256 * 240));
+		assert!(!run(&mut nes, 1, None, Some(path)));
+
+		let mut small = reference.clone();
+		small.height = 1;
+		small.rgb.truncate(256 * 3);
+		save_png(path, &small).unwrap();
+		assert!(!run(&mut nes, 1, None, Some(path)));
+		std::fs::remove_file(path).unwrap();
+	}
+
+	#[test]
+	fn test_video_sinks() {
+		let mut rom_memory: [u8; 1024*32] = [0;1024*32];
+		load_program_scroll(&mut rom_memory);
+		set_reset_vector(&mut rom_memory, 0x8000);
+		let mut nes = NES::new_custom_prg_rom(rom_memory);
+
+		// Recording and checking at the same time
+		let dir = std::env::temp_dir().join(format!("rust-nes-emulator-frames-{}", std::process::id()));
+		let dir = dir.to_str().unwrap();
+		let checker = HashChecker::default();
+		nes.add_video_sink(Box::new(PngRecorder::new(dir).unwrap()));
+		nes.add_video_sink(Box::new(checker.clone()));
+		nes.run_frames(3);
+		let hashes = checker.hashes();
+		assert_eq!(hashes.iter().map(|&(frame, _)| frame).collect::<Vec<_>>(), [0, 1, 2]);
+		assert_eq!(hashes[2].1, framebuffer_hash(&nes));
+		assert_eq!(load_png(&format!("{}/000002.png", dir)).unwrap(), screenshot(&nes));
+		std::fs::remove_dir_all(dir).unwrap();
+
+		// The same hashes from the file, and a wrong one
+		let path = std::env::temp_dir().join(format!("rust-nes-emulator-hashes-{}.txt", std::process::id()));
+		let path = path.to_str().unwrap();
+		checker.save(path).unwrap();
+		let loaded = HashChecker::load(path).unwrap();
+		std::fs::remove_file(path).unwrap();
+		let mut other = NES::new_custom_prg_rom(rom_memory);
+		other.add_video_sink(Box::new(loaded.clone()));
+		other.run_frames(3);
+		assert!(loaded.mismatches().is_empty());
+
+		let wrong = HashChecker::new(HashMap::from([(1, hashes[1].1 ^ 1)]));
+		let mut other = NES::new_custom_prg_rom(rom_memory);
+		other.add_video_sink(Box::new(wrong.clone()));
+		other.run_frames(3);
+		assert_eq!(wrong.mismatches(), [1]);
+	}
+}
@@ -0,0 +1,94 @@
+use std::{path::{Path, PathBuf}, sync::mpsc::{self, Receiver}, thread, time::Duration};
+
+use log::warn;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+/// Time to let the assembler finish writing the ROM before we read it.
+const SETTLE_TIME: Duration = Duration::from_millis(100);
+
+/// Watches the ROM files, so the emulator can reload the ROM when it is assembled again.
+/// The directories are watched and not the files, because many tools replace the file (write a new file and rename
+/// it) instead of writing to it.
+pub struct RomWatcher {
+	_watcher: RecommendedWatcher,	// Stops watching when dropped
+	events: Receiver<notify::Result<Event>>,
+	paths: Vec<PathBuf>,
+}
+
+impl RomWatcher {
+	/// Fails when a file doesn't exist or its directory can't be watched.
+	pub fn new(paths: &[&str]) -> Result<Self, String> {
+		let (sender, events) = mpsc::channel();
+		let mut watcher = notify::recommended_watcher(sender).map_err(|e| format!("Can't create the file watcher: {}", e))?;
+
+		let paths: Vec<PathBuf> = paths.iter()
+			.map(|path| Path::new(path).canonicalize().map_err(|e| format!("Can't watch {}: {}", path, e)))
+			.collect::<Result<_, _>>()?;
+		let mut dirs: Vec<&Path> = paths.iter().map(|path| path.parent().expect("A file has a parent directory")).collect();
+		dirs.sort();
+		dirs.dedup();
+		for dir in dirs {
+			watcher.watch(dir, RecursiveMode::NonRecursive).map_err(|e| format!("Can't watch {}: {}", dir.display(), e))?;
+		}
+
+		Ok(RomWatcher {
+			_watcher: watcher,
+			events,
+			paths,
+		})
+	}
+
+	/// Was any of the ROM files written since the last call. Doesn't block, unless a file was written (then it waits
+	/// for the write to finish).
+	pub fn changed(&self) -> bool {
+		let mut changed = false;
+		while let Ok(event) = self.events.try_recv() {
+			match event {
+				Ok(event) => changed |= self.is_rom_write(&event),
+				Err(e) => warn!("File watcher error: {}", e),
+			}
+		}
+		if changed {
+			// One write is usually a few events, drop the rest of them
+			thread::sleep(SETTLE_TIME);
+			while self.events.try_recv().is_ok() {}
+		}
+		changed
+	}
+
+	fn is_rom_write(&self, event: &Event) -> bool {
+		matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+			&& event.paths.iter().any(|path| self.paths.contains(path))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{fs, thread};
+	use super::{RomWatcher, SETTLE_TIME};
+
+	#[test]
+	fn test_changed() {
+		let dir = std::env::temp_dir().join(format!("rom_watcher_{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let rom = dir.join("game.nes");
+		let other = dir.join("game.lst");
+		fs::write(&rom, [0]).unwrap();
+
+		let watcher = RomWatcher::new(&[rom.to_str().unwrap()]).unwrap();
+		assert!(!watcher.changed());
+		assert!(RomWatcher::new(&[dir.join("missing.nes").to_str().unwrap()]).is_err());
+
+		// Other files in the directory are ignored
+		fs::write(&other, [0]).unwrap();
+		thread::sleep(SETTLE_TIME);
+		assert!(!watcher.changed());
+
+		fs::write(&rom, [1]).unwrap();
+		thread::sleep(SETTLE_TIME);
+		assert!(watcher.changed());
+		assert!(!watcher.changed());
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
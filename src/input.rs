@@ -0,0 +1,288 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::config::Config;
+use crate::controller::Button;
+
+/// Amount of controllers.
+pub const PLAYERS: usize = 2;
+
+/// Amount of measurements in the average input latency.
+const LATENCY_HISTORY: usize = 16;
+
+/// A button press or release in the window, sent to the emulator.
+pub struct InputEvent {
+	pub player: usize,	// 0 or 1
+	pub button: Button,
+	pub pressed: bool,
+	pub time: Instant,	// When the window got the host event
+}
+
+/// A host input that presses a controller button. The names are the SDL names of the key, gamepad button or axis.
+/// In the settings file: `key:<name>`, `pad<N>:button:<name>` or `pad<N>:axis:<name><+ or ->` (N is the gamepad, in
+/// the order they were connected, from 0).
+#[derive(Clone, Debug, PartialEq)]
+pub enum Binding {
+	Key(String),
+	PadButton { pad: usize, button: String },
+	PadAxis { pad: usize, axis: String, positive: bool },	// The axis pushed to one side
+}
+
+impl Binding {
+	pub fn parse(text: &str) -> Option<Binding> {
+		let (device, input) = text.trim().split_once(':')?;
+		if device == "key" {
+			return Some(Binding::Key(input.to_string()));
+		}
+		let pad = device.strip_prefix("pad")?.parse().ok()?;
+		match input.split_once(':')? {
+			("button", button) => Some(Binding::PadButton { pad, button: button.to_string() }),
+			("axis", axis) => {
+				let positive = match axis.chars().last()? {
+					'+' => true,
+					'-' => false,
+					_ => return None,
+				};
+				Some(Binding::PadAxis { pad, axis: axis[..axis.len() - 1].to_string(), positive })
+			}
+			_ => None,
+		}
+	}
+
+	/// The keyboard, or the number of the gamepad.
+	fn device(&self) -> Option<usize> {
+		match self {
+			Binding::Key(_) => None,
+			Binding::PadButton { pad, .. } | Binding::PadAxis { pad, .. } => Some(*pad),
+		}
+	}
+}
+
+impl fmt::Display for Binding {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Binding::Key(key) => write!(f, "key:{}", key),
+			Binding::PadButton { pad, button } => write!(f, "pad{}:button:{}", pad, button),
+			Binding::PadAxis { pad, axis, positive } => write!(f, "pad{}:axis:{}{}", pad, axis, if *positive { '+' } else { '-' }),
+		}
+	}
+}
+
+/// Which host inputs press which controller buttons. A button can have a few bindings (a key and a gamepad button).
+/// In the settings file: `input.<player>.<button> = <binding>, <binding>`, e.g. `input.1.a = key:X, pad0:button:b`.
+pub struct Bindings {
+	bindings: Vec<(usize, Button, Binding)>,
+}
+
+impl Bindings {
+	/// The bindings from the settings file, buttons that are not in the file get the defaults.
+	pub fn from_config(config: &Config) -> Self {
+		let mut bindings = vec![];
+		for player in 0..PLAYERS {
+			for button in Button::ALL {
+				let value = config.get(&Bindings::config_key(player, button), String::new());
+				let button_bindings = if value.is_empty() {
+					Bindings::defaults(player, button)
+				} else {
+					value.split(',').filter_map(|text| {
+						let binding = Binding::parse(text);
+						if binding.is_none() {
+							warn!("Invalid input binding for player {} {}: {}", player + 1, button.name(), text.trim());
+						}
+						binding
+					}).collect()
+				};
+				bindings.extend(button_bindings.into_iter().map(|binding| (player, button, binding)));
+			}
+		}
+		Bindings { bindings }
+	}
+
+	/// Player 1 on the keyboard (X is A, Z is B, Right Shift is Select, Enter is Start, arrows) and gamepad 0, player 2 on
+	/// gamepad 1. The gamepad buttons are by position: B is the bottom button, A the right one.
+	fn defaults(player: usize, button: Button) -> Vec<Binding> {
+		let mut bindings = vec![];
+		if player == 0 {
+			let key = match button {
+				Button::A => "X",
+				Button::B => "Z",
+				Button::Select => "Right Shift",
+				Button::Start => "Return",
+				Button::Up => "Up",
+				Button::Down => "Down",
+				Button::Left => "Left",
+				Button::Right => "Right",
+			};
+			bindings.push(Binding::Key(key.to_string()));
+		}
+		let pad_button = match button {
+			Button::A => "b",
+			Button::B => "a",
+			Button::Select => "back",
+			Button::Start => "start",
+			Button::Up => "dpup",
+			Button::Down => "dpdown",
+			Button::Left => "dpleft",
+			Button::Right => "dpright",
+		};
+		bindings.push(Binding::PadButton { pad: player, button: pad_button.to_string() });
+		let stick = match button {
+			Button::Up => Some(("lefty", false)),
+			Button::Down => Some(("lefty", true)),
+			Button::Left => Some(("leftx", false)),
+			Button::Right => Some(("leftx", true)),
+			_ => None,
+		};
+		if let Some((axis, positive)) = stick {
+			bindings.push(Binding::PadAxis { pad: player, axis: axis.to_string(), positive });
+		}
+		bindings
+	}
+
+	fn config_key(player: usize, button: Button) -> String {
+		format!("input.{}.{}", player + 1, button.name())
+	}
+
+	/// The buttons that the host input presses.
+	pub fn buttons(&self, binding: &Binding) -> Vec<(usize, Button)> {
+		self.bindings.iter()
+			.filter(|(_, _, other)| other == binding)
+			.map(|&(player, button, _)| (player, button))
+			.collect()
+	}
+
+	/// Bind a host input to a button. It replaces the binding of the button on the same device (the keyboard or the
+	/// same gamepad), and the host input doesn't press other buttons anymore.
+	pub fn set(&mut self, player: usize, button: Button, binding: Binding) {
+		self.bindings.retain(|(other_player, other_button, other)| {
+			let same_button = (*other_player, *other_button) == (player, button);
+			!(same_button && other.device() == binding.device()) && *other != binding
+		});
+		self.bindings.push((player, button, binding));
+	}
+
+	/// Save the bindings of all the buttons in the settings.
+	pub fn write_config(&self, config: &mut Config) {
+		for player in 0..PLAYERS {
+			for button in Button::ALL {
+				let value = self.bindings.iter()
+					.filter(|&&(other_player, other_button, _)| (other_player, other_button) == (player, button))
+					.map(|(_, _, binding)| binding.to_string())
+					.collect::<Vec<_>>()
+					.join(", ");
+				config.set(&Bindings::config_key(player, button), &value);
+			}
+		}
+	}
+}
+
+/// Input latency diagnostics: the host time from a key press to the end of the frame in which the game read the
+/// controller and saw it. Includes the frontend polling, the emulation of the frame and the game's own reaction time
+/// up to the controller read, but not the display. Useful to tune the audio buffer and run-ahead settings.
+pub struct LatencyMeter {
+	pending: Vec<(Instant, usize, u64)>,	// Events the game didn't see yet: host time, player, controller latches at that time
+	history: VecDeque<Duration>,
+}
+
+impl LatencyMeter {
+	pub fn new() -> Self {
+		LatencyMeter {
+			pending: vec![],
+			history: VecDeque::new(),
+		}
+	}
+
+	/// An input event was given to the controller, which was latched `latches` times so far.
+	pub fn input(&mut self, event: &InputEvent, latches: u64) {
+		self.pending.push((event.time, event.player, latches));
+	}
+
+	/// A frame was completed, `latches` are the controller latches of each player so far. Returns the latency of the
+	/// events that the game saw during the frame.
+	pub fn end_frame(&mut self, now: Instant, latches: [u64; 2]) -> Vec<Duration> {
+		let mut measured = vec![];
+		self.pending.retain(|&(time, player, latches_before)| {
+			if latches[player] == latches_before {
+				return true;
+			}
+			measured.push(now - time);
+			false
+		});
+		for &latency in &measured {
+			if self.history.len() == LATENCY_HISTORY {
+				self.history.pop_front();
+			}
+			self.history.push_back(latency);
+		}
+		measured
+	}
+
+	/// The average latency of the last events.
+	pub fn average(&self) -> Option<Duration> {
+		if self.history.is_empty() {
+			return None;
+		}
+		Some(self.history.iter().sum::<Duration>() / self.history.len() as u32)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::{Duration, Instant};
+	use crate::{config::Config, controller::Button};
+	use super::{Binding, Bindings, InputEvent, LatencyMeter};
+
+	#[test]
+	fn test_bindings() {
+		let key = |name: &str| Binding::Key(name.to_string());
+		let pad_button = Binding::PadButton { pad: 0, button: "x".to_string() };
+		let axis = Binding::parse("pad1:axis:leftx-").unwrap();
+		assert_eq!(axis, Binding::PadAxis { pad: 1, axis: "leftx".to_string(), positive: false });
+		assert_eq!(Binding::parse("pad0:button:x"), Some(pad_button.clone()));
+		assert_eq!(Binding::parse("pad0:stick:x"), None);
+
+		// Player 2 A is K and the gamepad 1 X button, the other buttons have the defaults
+		let config = Config::parse("input.2.a = key:K, pad1:button:x");
+		let mut bindings = Bindings::from_config(&config);
+		assert_eq!(bindings.buttons(&key("K")), [(1, Button::A)]);
+		assert_eq!(bindings.buttons(&key("X")), [(0, Button::A)]);
+		assert_eq!(bindings.buttons(&axis), [(1, Button::Left)]);
+
+		// Remapping replaces the binding of the same device only
+		bindings.set(0, Button::A, key("S"));
+		bindings.set(0, Button::A, pad_button.clone());
+		assert_eq!(bindings.buttons(&key("X")), []);
+		assert_eq!(bindings.buttons(&key("S")), [(0, Button::A)]);
+		assert_eq!(bindings.buttons(&Binding::PadButton { pad: 0, button: "b".to_string() }), []);
+		assert_eq!(bindings.buttons(&pad_button), [(0, Button::A)]);
+		// A host input presses one button
+		bindings.set(0, Button::B, key("S"));
+		assert_eq!(bindings.buttons(&key("S")), [(0, Button::B)]);
+
+		let mut config = Config::parse("");
+		bindings.write_config(&mut config);
+		assert_eq!(config.get("input.1.a", String::new()), "pad0:button:x");
+		assert_eq!(config.get("input.1.b", String::new()), "pad0:button:a, key:S");
+		assert_eq!(config.get("input.2.a", String::new()), "key:K, pad1:button:x");
+	}
+
+	#[test]
+	fn test_latency() {
+		let start = Instant::now();
+		let mut meter = LatencyMeter::new();
+		meter.input(&InputEvent { player: 0, button: Button::A, pressed: true, time: start }, 5);
+		meter.input(&InputEvent { player: 1, button: Button::A, pressed: true, time: start + Duration::from_millis(10) }, 7);
+
+		// The game didn't read the controllers in this frame
+		assert!(meter.end_frame(start + Duration::from_millis(16), [5, 7]).is_empty());
+		assert_eq!(meter.average(), None);
+
+		// Player 1 was read
+		assert_eq!(meter.end_frame(start + Duration::from_millis(33), [6, 7]), [Duration::from_millis(33)]);
+		assert_eq!(meter.end_frame(start + Duration::from_millis(50), [7, 8]), [Duration::from_millis(40)]);
+		assert_eq!(meter.average(), Some(Duration::from_micros(36_500)));
+	}
+}
@@ -0,0 +1,39 @@
+//! The emulator without the SDL frontend: start with `builder::NesBuilder` and `nes::NES`. The `rust-nes-emulator`
+//! binary is the window, the audio device and the command line around it.
+#![cfg_attr(test, deny(arithmetic_overflow))]
+
+pub mod achievements;
+pub mod apu;
+pub mod builder;
+pub mod callbacks;
+pub mod cartridge;
+pub mod cheats;
+pub mod common;
+pub mod compat;
+pub mod config;
+pub mod controller;
+pub mod cpu;
+pub mod debugger;
+pub mod error;
+pub mod filter;
+#[cfg(feature = "gym")]
+pub mod gym;
+pub mod headless;
+pub mod mapper;
+pub mod mapper_suite;
+pub mod movie;
+pub mod nes;
+pub mod ppu;
+pub mod profiling;
+pub mod program_loader;
+pub mod rom_db;
+pub mod rom_info;
+pub mod rom_parser;
+pub mod savestate;
+pub mod scenario;
+pub mod state_dump;
+pub mod stats;
+pub mod suspicious;
+pub mod tas;
+pub mod unimplemented;
+pub mod vs_system;
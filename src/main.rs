@@ -1,46 +1,575 @@
 //#![feature(mixed_integer_ops)]  // stable since 1.67.0-nightly
-mod cartridge;
-mod common;
-mod cpu;
-mod nes;
-mod ppu;
-pub mod program_loader;
+#![cfg_attr(test, deny(arithmetic_overflow))]
+mod audio;
+mod hot_reload;
+mod input;
 mod render;
-mod rom_parser;
+mod session;
 
-use std::io;
+use rust_nes_emulator::{achievements, apu, builder, common, compat, config, controller, cpu, debugger, error, filter, headless, mapper_suite, movie, nes, ppu, profiling, rom_db, rom_info, rom_parser, savestate, scenario, stats, suspicious, unimplemented};
+
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+use std::panic::{self, AssertUnwindSafe};
 use std::thread;
+use std::time::{Duration, Instant};
 use std::sync::mpsc;
 use std::sync::mpsc::{Sender, Receiver};
 use std::sync::{Mutex, Arc};
 
+use apu::expansion::ExpansionVolumes;
+use apu::resampler::ResamplerQuality;
+use achievements::Achievements;
+use apu::sink::{AudioSink, NullSink, WavRecorder};
+use headless::{HashChecker, PngRecorder};
+use audio::AudioSettings;
+use builder::NesBuilder;
+use config::{Config, CONFIG_PATH};
+use cpu::{cpu::Scheduler, io_log::IoFilter};
+use debugger::debugger::Debugger;
+use error::{EmuError, RomError};
+use hot_reload::RomWatcher;
+use input::{Bindings, InputEvent, LatencyMeter};
+use movie::{Movie, MovieMode};
 use nes::NES;
+use ppu::colors::{Palette, PaletteSettings};
+use ppu::ppu::Renderer;
 use simple_logger::SimpleLogger;
-use log::{debug, info};
+use log::{debug, error, info, warn};
+use rom_parser::{MirrorType, SizeMismatch};
+use savestate::{autosave_path, slot_path, Autosave};
+use session::{session_path, WindowLayout};
+use suspicious::EmulationMode;
+use unimplemented::{Needs, UnimplementedPolicy};
+
+/// Instructions buffered for the `--trace-log` thread, about 2 frames.
+const TRACE_LOG_CAPACITY: usize = 1 << 16;
+
+const USAGE: &str = "Usage: rust-nes-emulator [OPTIONS] [ROM]
+       rust-nes-emulator [OPTIONS] --prg <FILE> [--chr <FILE>] [--mapper <N>] [--mirroring horizontal|vertical]
+Options:
+  --overclock <SCANLINES>  Extra vblank scanlines for the CPU, the default is the game.<CRC32>.overclock setting or 0
+  --scheduler <MODE>       fast (the PPU catches up after each instruction) or accurate (before each memory access),
+                           the default depends on the game
+  --renderer <MODE>        dot (mid-scanline effects) or scanline (faster), the default depends on the game
+  --no-sprite-limit        Draw more than 8 sprites on a scanline
+  --sprite-rotation        Keep the sprite limit, rotate the sprite priority each frame (less flicker)
+  --no-warm-up             The PPU registers work right away after power on and reset (for test programs)
+  --resampler <QUALITY>    nearest (cheapest), linear (the default) or sinc (no aliasing), how the audio samples are made
+  --notes <FILE>           Log the notes the APU channels play and write them on exit, as MIDI (.mid) or CSV
+  --audio-device <NAME>    Play the audio on this device instead of the default one (the audio.device setting)
+  --audio-devices          Print the names of the audio devices and exit
+  --wav <FILE>             Write the audio to a WAV file, also with --headless
+  --unimplemented <MODE>   What to do when the game uses an opcode the emulator doesn't implement: warn (log it
+                           once and go on, the default), quiet (only the summary on exit) or panic
+  --log-io <FILTER>        Log the memory accesses of the filter, e.g. 'writes 2000-2007, reads 4016' (also the
+                           debug.log_io setting)
+  --strict                 Report writes to ROM, reads of write-only registers and stack overflows as errors and stop
+                           in the debugger (for homebrew), with --headless the exit code is 1 when there were any
+  --opcode-stats           Print how many times each opcode was executed on exit
+  --trace-log <FILE>       Write every executed instruction to the file: the registers before it and its disassembly
+  --subsystem-times        Show the host time of the CPU, PPU and APU in the window title (reads the clock on every
+                           instruction, about half the speed)
+  --input-latency          Measure the input latency, from a key press to the frame the game saw it in
+  --record <FILE>          Record a movie of the controller input, saved on exit. Loading a state rerecords
+  --play <FILE>            Play a movie
+  --achievements <FILE>    Conditions on the memory that unlock achievements, see achievements.rs for the format
+  --watch                  Reload the ROM when it changes, keep the debugger watches
+  --watch-fresh            Reload the ROM when it changes, with a new debugger session
+  --fresh                  Start from power on, not from the session of the last exit (the session setting)
+  --headless <FRAMES>      Run the frames without a window and exit
+  --screenshot <FILE>      With --headless, save the last frame as a PNG
+  --reference <FILE>       With --headless, compare the last frame with a PNG, the exit code is 1 when they differ
+  --dump-state <FILE>      With --headless, write the CPU registers, timers and PPU latches at the end as JSON
+  --frame-hashes <FILE>    With --headless, check the CRC32 of each frame picture against the file, the exit code is 1
+                           when one differs. When the file isn't there, write the hashes to it
+  --record-frames <DIR>    Save each frame as a PNG in the directory, also with --headless
+  --mapper-suite <DIR>     Run the mapper test ROMs of the directory (Holy Mapperel) and check their pictures against
+                           DIR/expected.txt, the exit code is 1 when one differs
+  --suite-mappers <LIST>   With --mapper-suite, only the ROMs of these mappers, e.g. 24,69
+  --record-suite           With --mapper-suite, write DIR/expected.txt from the pictures of all the ROMs
+  --scenarios <DIR>        Play the regression scenarios (.scenario files) of the directory and check the hashes of
+                           their checkpoints, the exit code is 1 when one differs
+  --record-scenarios       With --scenarios, write the hashes of the checkpoints to the scenario files
+  --info                   Print the header, sizes, hashes and mapper support of the ROM and exit
+  --compat <DIR>           Run every ROM of the directory headless and write a report of which boot, crash or show a
+                           blank screen
+  --compat-frames <FRAMES> With --compat, the frames to run each ROM (default 600)
+  --compat-report <FILE>   With --compat, the report file, JSON if it ends with .json, else CSV (default compat.csv)";
+
+/// Command line arguments.
+struct Options {
+	rom_path: Option<String>,
+	prg_path: Option<String>,
+	chr_path: Option<String>,
+	mapper: u8,
+	mirroring: MirrorType,
+	overclock: Option<u16>,	// Extra vblank scanlines, see CPU::set_overclock. By default, the game.<CRC32>.overclock setting
+	scheduler: Option<Scheduler>,	// None chooses by the game, see rom_db
+	renderer: Option<Renderer>,
+	sprite_limit: bool,		// Draw at most 8 sprites on a scanline, like the hardware
+	sprite_rotation: bool,	// Rotate the sprite priority each frame, with the sprite limit
+	warm_up: bool,			// The PPU ignores the register writes of the first frame, like the hardware
+	resampler: ResamplerQuality,
+	notes_path: Option<String>,		// MIDI or CSV of the notes, written on exit
+	audio_device: Option<String>,	// Overrides the audio.device setting
+	audio_devices: bool,	// Print the audio devices instead of running
+	wav_path: Option<String>,	// Audio recording
+	input_latency: bool,	// Measure the input latency
+	watch: bool,			// Reload the ROM when the file changes
+	fresh_debugger: bool,	// Start a new debugger session when the ROM is reloaded, instead of keeping the watches
+	fresh: bool,			// Don't resume the session
+	record_path: Option<String>,	// Movie to record
+	play_path: Option<String>,		// Movie to play
+	achievements_path: Option<String>,
+	unimplemented: UnimplementedPolicy,
+	mode: EmulationMode,
+	io_log: Option<String>,			// Memory accesses to log, see IoFilter
+	opcode_stats: bool,				// Print the executions of each opcode on exit
+	trace_log_path: Option<String>,	// Every executed instruction, see NES::instruction_stream
+	subsystem_times: bool,			// Time the CPU, PPU and APU apart
+	headless: Option<u64>,			// Frames to run without a window
+	screenshot_path: Option<String>,	// PNG of the last headless frame
+	reference_path: Option<String>,		// PNG the last headless frame should look like
+	dump_state_path: Option<String>,	// JSON of the state after the headless frames
+	frame_hashes_path: Option<String>,	// Hashes of the headless frames, checked or written
+	frames_dir: Option<String>,			// PNG of each frame
+	suite_path: Option<String>,		// Directory of mapper test ROMs
+	suite_mappers: Vec<u8>,			// Run only the test ROMs of these mappers, all when empty
+	record_suite: bool,				// Record the expected pictures of the test ROMs
+	scenarios_path: Option<String>,	// Directory of regression scenarios
+	record_scenarios: bool,			// Record the hashes of the checkpoints of the scenarios
+	info: bool,						// Print what's in the ROM file instead of running it
+	compat_path: Option<String>,	// Directory of ROMs for the compatibility report
+	compat_frames: u64,
+	compat_report: String,
+}
+
+impl Options {
+	fn parse(args: Vec<String>) -> Self {
+		let mut options = Options {
+			rom_path: None,
+			prg_path: None,
+			chr_path: None,
+			mapper: 0,
+			mirroring: MirrorType::HORIZONTAL,
+			overclock: None,
+			scheduler: None,
+			renderer: None,
+			sprite_limit: true,
+			sprite_rotation: false,
+			warm_up: true,
+			resampler: ResamplerQuality::Linear,
+			notes_path: None,
+			audio_device: None,
+			audio_devices: false,
+			wav_path: None,
+			input_latency: false,
+			watch: false,
+			fresh_debugger: false,
+			fresh: false,
+			record_path: None,
+			play_path: None,
+			achievements_path: None,
+			unimplemented: UnimplementedPolicy::Warn,
+			mode: EmulationMode::Permissive,
+			io_log: None,
+			opcode_stats: false,
+			trace_log_path: None,
+			subsystem_times: false,
+			headless: None,
+			screenshot_path: None,
+			reference_path: None,
+			dump_state_path: None,
+			frame_hashes_path: None,
+			frames_dir: None,
+			suite_path: None,
+			suite_mappers: vec![],
+			record_suite: false,
+			scenarios_path: None,
+			record_scenarios: false,
+			info: false,
+			compat_path: None,
+			compat_frames: compat::DEFAULT_FRAMES,
+			compat_report: "compat.csv".to_string(),
+		};
+
+		let mut args = args.into_iter();
+		while let Some(arg) = args.next() {
+			let mut value = || args.next().unwrap_or_else(|| panic!("Missing value for {}\n{}", arg, USAGE));
+			match arg.as_str() {
+				"--prg" => options.prg_path = Some(value()),
+				"--chr" => options.chr_path = Some(value()),
+				"--mapper" => options.mapper = value().parse().unwrap_or_else(|_| panic!("Invalid mapper number\n{}", USAGE)),
+				"--mirroring" => options.mirroring = match value().as_str() {
+					"horizontal" => MirrorType::HORIZONTAL,
+					"vertical" => MirrorType::VERTICAL,
+					other => panic!("Invalid mirroring: {}\n{}", other, USAGE),
+				},
+				"--overclock" => options.overclock = Some(value().parse().unwrap_or_else(|_| panic!("Invalid overclock scanlines\n{}", USAGE))),
+				"--scheduler" => options.scheduler = Some(Scheduler::parse(&value()).unwrap_or_else(|| panic!("Invalid scheduler\n{}", USAGE))),
+				"--renderer" => options.renderer = Some(Renderer::parse(&value()).unwrap_or_else(|| panic!("Invalid renderer\n{}", USAGE))),
+				"--no-sprite-limit" => options.sprite_limit = false,
+				"--sprite-rotation" => options.sprite_rotation = true,
+				"--no-warm-up" => options.warm_up = false,
+				"--notes" => options.notes_path = Some(value()),
+				"--audio-device" => options.audio_device = Some(value()),
+				"--audio-devices" => options.audio_devices = true,
+				"--wav" => options.wav_path = Some(value()),
+				"--resampler" => options.resampler = ResamplerQuality::parse(&value()).unwrap_or_else(|| panic!("Invalid resampler\n{}", USAGE)),
+				"--unimplemented" => options.unimplemented = UnimplementedPolicy::parse(&value()).unwrap_or_else(|| panic!("Invalid unimplemented mode\n{}", USAGE)),
+				"--log-io" => options.io_log = Some(value()),
+				"--strict" => options.mode = EmulationMode::Strict,
+				"--opcode-stats" => options.opcode_stats = true,
+				"--trace-log" => options.trace_log_path = Some(value()),
+				"--subsystem-times" => options.subsystem_times = true,
+				"--input-latency" => options.input_latency = true,
+				"--record" => options.record_path = Some(value()),
+				"--play" => options.play_path = Some(value()),
+				"--achievements" => options.achievements_path = Some(value()),
+				"--headless" => options.headless = Some(value().parse().unwrap_or_else(|_| panic!("Invalid number of frames\n{}", USAGE))),
+				"--screenshot" => options.screenshot_path = Some(value()),
+				"--reference" => options.reference_path = Some(value()),
+				"--dump-state" => options.dump_state_path = Some(value()),
+				"--frame-hashes" => options.frame_hashes_path = Some(value()),
+				"--record-frames" => options.frames_dir = Some(value()),
+				"--mapper-suite" => options.suite_path = Some(value()),
+				"--suite-mappers" => options.suite_mappers = value().split(',')
+					.map(|mapper| mapper.trim().parse().unwrap_or_else(|_| panic!("Invalid mapper number: {}\n{}", mapper, USAGE)))
+					.collect(),
+				"--record-suite" => options.record_suite = true,
+				"--scenarios" => options.scenarios_path = Some(value()),
+				"--record-scenarios" => options.record_scenarios = true,
+				"--info" => options.info = true,
+				"--compat" => options.compat_path = Some(value()),
+				"--compat-frames" => options.compat_frames = value().parse().unwrap_or_else(|_| panic!("Invalid number of frames\n{}", USAGE)),
+				"--compat-report" => options.compat_report = value(),
+				"--watch" => options.watch = true,
+				"--fresh" => options.fresh = true,
+				"--watch-fresh" => {
+					options.watch = true;
+					options.fresh_debugger = true;
+				}
+				_ if arg.starts_with("--") => panic!("Unknown option: {}\n{}", arg, USAGE),
+				_ => options.rom_path = Some(arg),
+			}
+		}
+		options
+	}
+
+	/// The files the ROM is loaded from.
+	fn rom_files(&self) -> Vec<&str> {
+		match &self.prg_path {
+			Some(prg_path) => [Some(prg_path), self.chr_path.as_ref()].into_iter().flatten().map(|path| path.as_str()).collect(),
+			None => vec![self.rom_path()],
+		}
+	}
+
+	fn rom_path(&self) -> &str {
+		//let path = "C:\\Users\\Shlomi\\Desktop\\Projects\\nes-test-roms\\blargg_nes_cpu_test5\\official.nes";
+		//let path = "6502asm_programs/greenscreen.nes";
+		//let path = "6502asm_programs/background/background.nes";
+		self.rom_path.as_deref().unwrap_or("6502asm_programs/nestest/nestest.nes")
+	}
+
+	/// Open the ROM, exits when it can't be opened.
+	fn open_nes(&self, config: &Config) -> NES {
+		self.try_open_nes(config).unwrap_or_else(|e| {
+			error!("Could not open {}: {}", self.rom_path(), e);
+			if let EmuError::Rom(RomError::UnsupportedMapper(mapper)) = e {
+				self.save_needs_report(None, 0, &Needs { mapper: Some(mapper), features: vec![] });
+			}
+			std::process::exit(1);
+		})
+	}
+
+	/// Open the ROM: an iNES/FDS file, or raw PRG and CHR binaries (for homebrew, without an iNES header). Without a ROM,
+	/// opens nestest.
+	fn try_open_nes(&self, config: &Config) -> Result<NES, EmuError> {
+		let size_mismatch = config.get("rom.size_mismatch", String::new());
+		let mut nes = self.open_rom(SizeMismatch::parse(&size_mismatch).unwrap_or_default())?
+			.audio(Some(self.resampler))
+			.warm_up(self.warm_up)
+			.try_build()?;
+		// The accuracy settings are by the CRC32 of the ROM
+		let accuracy = rom_db::accuracy(nes.cpu.cartridge().crc32(), config);
+		nes.cpu.set_overclock(self.overclock.unwrap_or_else(|| rom_db::overclock(nes.cpu.cartridge().crc32(), config)));
+		nes.cpu.set_scheduler(self.scheduler.unwrap_or(accuracy.scheduler));
+		nes.set_subsystem_times(self.subsystem_times);
+		nes.cpu.ppu_mut().set_sprite_limit(self.sprite_limit);
+		nes.cpu.ppu_mut().set_sprite_rotation(self.sprite_rotation);
+		nes.cpu.ppu_mut().set_renderer(self.renderer.unwrap_or(accuracy.renderer));
+		nes.cpu.unimplemented_mut().set_policy(self.unimplemented);
+		nes.cpu.suspicious_mut().set_mode(self.mode);
+		let io_log = self.io_log.clone().unwrap_or_else(|| config.get("debug.log_io", String::new()));
+		if !io_log.is_empty() {
+			nes.cpu.set_io_log(Some(IoFilter::parse(&io_log).unwrap_or_else(|e| panic!("Invalid I/O log filter: {}\n{}", e, USAGE))));
+		}
+		nes.cpu.apu_mut().set_expansion_volumes(ExpansionVolumes::from_config(config));
+		if self.notes_path.is_some() {
+			nes.cpu.apu_mut().start_note_log();
+		}
+		let tv_system = nes.cpu.cartridge().tv_system();
+		nes.cpu.ppu_mut().set_rgb_palette(Palette::new(&PaletteSettings::from_config(config, tv_system)));
+		if let Some(dir) = &self.frames_dir {
+			let recorder = PngRecorder::new(dir).unwrap_or_else(|e| panic!("Can't save the frames in {}: {}", dir, e));
+			nes.add_video_sink(Box::new(recorder));
+		}
+		Ok(nes)
+	}
+
+	/// The hash checker of `--frame-hashes`, and whether it checks the hashes of the file (else it writes them).
+	fn frame_hashes(&self, nes: &mut NES) -> Option<(HashChecker, bool)> {
+		let path = self.frame_hashes_path.as_ref()?;
+		let checking = Path::new(path).exists();
+		let checker = if checking {
+			HashChecker::load(path).unwrap_or_else(|e| panic!("{}", e))
+		} else {
+			HashChecker::default()
+		};
+		nes.add_video_sink(Box::new(checker.clone()));
+		Some((checker, checking))
+	}
+
+	/// Start recording or playing the movie of the command line, from the current frame.
+	fn open_movie(&self, nes: &mut NES) -> Option<Movie> {
+		let mut movie = if let Some(path) = &self.play_path {
+			let mut movie = Movie::load(Path::new(path)).unwrap_or_else(|e| panic!("Can't load the movie {}: {}", path, e));
+			movie.play(nes).unwrap_or_else(|e| panic!("Can't play the movie {}: {}", path, e));
+			info!("Playing the movie {}, {} frames, {} rerecords", path, movie.len(), movie.rerecords());
+			movie
+		} else {
+			info!("Recording a movie to {}", self.record_path.as_ref()?);
+			Movie::record(nes)
+		};
+		movie.frame(nes);
+		Some(movie)
+	}
+
+	/// Write the instructions of `--trace-log` in another thread, until the NES is dropped (or replaced by a reload).
+	fn start_trace_log(&self, nes: &mut NES) -> Option<thread::JoinHandle<()>> {
+		let path = self.trace_log_path.clone()?;
+		let file = File::create(&path).unwrap_or_else(|e| panic!("Can't write the trace log {}: {}", path, e));
+		let stream = nes.instruction_stream(TRACE_LOG_CAPACITY);
+		Some(thread::spawn(move || {
+			match stream.write_log(BufWriter::new(file)) {
+				Ok(lines) => info!("Wrote {} instructions to {}", lines, path),
+				Err(e) => error!("Can't write the trace log {}: {}", path, e),
+			}
+			if stream.dropped() > 0 {
+				warn!("{} instructions are missing from the trace log, the disk didn't keep up", stream.dropped());
+			}
+		}))
+	}
+
+	fn log_opcode_stats(&self, nes: &NES) {
+		if self.opcode_stats {
+			info!("Executed opcodes:\n{}", nes.opcode_stats().table());
+		}
+	}
+
+	/// Where the audio goes besides the window: the WAV file of `--wav`.
+	fn audio_sink(&self) -> Box<dyn AudioSink + Send> {
+		let Some(path) = &self.wav_path else {
+			return Box::new(NullSink);
+		};
+		match WavRecorder::create(path) {
+			Ok(recorder) => {
+				info!("Recording the audio to {}", path);
+				Box::new(recorder)
+			}
+			Err(e) => panic!("Can't write the audio to {}: {}", path, e),
+		}
+	}
+
+	/// Write the notes of `--notes`.
+	fn save_notes(&self, nes: &NES) {
+		if let (Some(path), Some(notes)) = (&self.notes_path, nes.cpu.apu().note_log()) {
+			match notes.save(path) {
+				Ok(()) => info!("Saved {} note events to {}", notes.events().len(), path),
+				Err(e) => error!("{}", e),
+			}
+		}
+	}
+
+	/// When the game needed something the emulator doesn't have, write what to game.needs.json next to the ROM, for an
+	/// issue.
+	fn save_needs_report(&self, crc32: Option<u32>, frames: u64, needs: &Needs) {
+		if needs.is_empty() {
+			return;
+		}
+		let rom = self.prg_path.as_deref().unwrap_or(self.rom_path());
+		let path = Path::new(rom).with_extension("needs.json");
+		match std::fs::write(&path, compat::needs_report(rom, crc32, frames, needs)) {
+			Ok(()) => warn!("The game needs {}, attach {:?} to an issue", needs, path),
+			Err(e) => error!("Can't write {:?}: {}", path, e),
+		}
+	}
+
+	fn open_rom(&self, size_mismatch: SizeMismatch) -> Result<NesBuilder, RomError> {
+		if let Some(prg_path) = &self.prg_path {
+			let read = |path: &str| std::fs::read(path).map_err(|e| RomError::Read { path: path.to_string(), reason: e.to_string() });
+			let prg = read(prg_path)?;
+			let chr = self.chr_path.as_deref().map(read).transpose()?.unwrap_or_default();
+			info!("Booting raw PRG {} ({} bytes), CHR {} bytes, mapper {}", prg_path, prg.len(), chr.len(), self.mapper);
+			return Ok(NesBuilder::new().prg_chr(&prg, &chr, self.mapper, self.mirroring.clone()));
+		}
+		Ok(NesBuilder::new().rom_path(self.rom_path()).size_mismatch(size_mismatch))
+	}
+}
 
 fn main() {
     SimpleLogger::new().init().unwrap();
 
+	// Flushes the chrome trace when main returns
+	#[cfg(feature = "tracing")]
+	let _trace_guard = profiling::init();
+
+	let config = Config::load(CONFIG_PATH);
+	let options = Options::parse(std::env::args().skip(1).collect());
+	if options.info {
+		let size_mismatch = config.get("rom.size_mismatch", String::new());
+		match rom_info::rom_info(options.rom_path(), SizeMismatch::parse(&size_mismatch).unwrap_or_default()) {
+			Ok(info) => println!("{}", info),
+			Err(e) => {
+				error!("{}", e);
+				std::process::exit(1);
+			}
+		}
+		return;
+	}
+	if options.audio_devices {
+		match audio::devices() {
+			Ok(devices) => devices.iter().for_each(|device| println!("{}", device)),
+			Err(e) => {
+				error!("{}", e);
+				std::process::exit(1);
+			}
+		}
+		return;
+	}
+	if let Some(dir) = &options.compat_path {
+		let result = compat::run(dir, options.compat_frames)
+			.and_then(|results| compat::write_report(&options.compat_report, &results, options.compat_frames));
+		match result {
+			Ok(()) => info!("Wrote the report to {}", options.compat_report),
+			Err(e) => {
+				error!("{}", e);
+				std::process::exit(1);
+			}
+		}
+		return;
+	}
+	if let Some(dir) = &options.suite_path {
+		let result = if options.record_suite {
+			mapper_suite::record(dir, mapper_suite::RECORD_FRAMES).map(|roms| {
+				info!("Recorded the pictures of {} ROMs", roms);
+				true
+			})
+		} else {
+			mapper_suite::run(dir, &options.suite_mappers)
+		};
+		let passed = result.unwrap_or_else(|e| {
+			error!("{}", e);
+			false
+		});
+		std::process::exit(if passed { 0 } else { 1 });
+	}
+	if let Some(dir) = &options.scenarios_path {
+		let result = if options.record_scenarios {
+			scenario::record(dir).map(|scenarios| {
+				info!("Recorded the checkpoints of {} scenarios", scenarios);
+				true
+			})
+		} else {
+			scenario::run(dir)
+		};
+		let passed = result.unwrap_or_else(|e| {
+			error!("{}", e);
+			false
+		});
+		std::process::exit(if passed { 0 } else { 1 });
+	}
+	if let Some(frames) = options.headless {
+		let mut nes = options.open_nes(&config);
+		nes.add_audio_sink(options.audio_sink());
+		let trace_log = options.start_trace_log(&mut nes);
+		let hashes = options.frame_hashes(&mut nes);
+		let mut same = headless::run(&mut nes, frames, options.screenshot_path.as_deref(), options.reference_path.as_deref());
+		if let (Some((checker, checking)), Some(path)) = (&hashes, &options.frame_hashes_path) {
+			if *checking {
+				let mismatches = checker.mismatches();
+				if mismatches.is_empty() {
+					info!("The frames have the hashes of {}", path);
+				}
+				same &= mismatches.is_empty();
+			} else {
+				match checker.save(path) {
+					Ok(()) => info!("Wrote the hashes of {} frames to {}", checker.hashes().len(), path),
+					Err(e) => error!("Can't write {}: {}", path, e),
+				}
+			}
+		}
+		nes.cpu.unimplemented().log_summary();
+		options.save_needs_report(Some(nes.cpu.cartridge().crc32()), nes.frame(), &nes.cpu.unimplemented().needs());
+		nes.cpu.suspicious().log_summary();
+		options.log_opcode_stats(&nes);
+		options.save_notes(&nes);
+		if let Some(path) = &options.dump_state_path {
+			match std::fs::write(path, nes.dump_state_json()) {
+				Ok(()) => info!("Wrote the state to {}", path),
+				Err(e) => error!("Can't write {}: {}", path, e),
+			}
+		}
+		let passed = same && nes.cpu.suspicious().total() == 0;
+		finish_trace_log(nes, trace_log);
+		std::process::exit(if passed { 0 } else { 1 });
+	}
+
 	let closed_window_mutex = Arc::new(Mutex::new(false));
 	let closed_window_mutex_clone = Arc::clone(&closed_window_mutex);
+	// Frames for the render thread. Sending blocks while the previous frame wasn't drawn yet, which also limits the emulation speed to the display.
+	let (frame_sender, frame_receiver) = mpsc::sync_channel::<render::Frame>(1);
+	let (input_sender, input_receiver) = mpsc::channel::<InputEvent>();
+	let (command_sender, command_receiver) = mpsc::channel::<render::Command>();
+	let filters = filter::presets(&config);
+	let bindings = Bindings::from_config(&config);
+	let mut audio_settings = AudioSettings::from_config(&config);
+	if let Some(device) = &options.audio_device {
+		audio_settings.device = Some(device.clone());
+	}
+    let mut nes = options.open_nes(&config);
+	let trace_log = options.start_trace_log(&mut nes);
+	let state_path = options.rom_files()[0].to_string();
+	let session = session::enabled(&config);
+	let layout = WindowLayout::from_config(&config, nes.cpu.cartridge().crc32()).filter(|_| session && !options.fresh);
 	// Create thread for handling drawing/graphics, the NES is executed on main thread
     let handle = thread::spawn(move || {
-        render::sdl2_setup();
+        render::sdl2_setup(frame_receiver, input_sender, command_sender, filters, bindings, audio_settings, layout);
 
 		// Set flag that the SDL window finished
 		let mut value = closed_window_mutex_clone.lock().unwrap();
         *value = true;
     });
 
-    //let path = "C:\\Users\\Shlomi\\Desktop\\Projects\\nes-test-roms\\blargg_nes_cpu_test5\\official.nes";
-    let path = "6502asm_programs/nestest/nestest.nes";
-    //let path = "6502asm_programs/greenscreen.nes";
-    //let path = "6502asm_programs/background/background.nes";
-
-    let mut nes = NES::new_open_rom_file(path);
+    let rom_watcher = options.watch.then(|| RomWatcher::new(&options.rom_files())).and_then(|watcher| {
+		watcher.map_err(|e| warn!("{}, running without --watch", e)).ok()
+	});
 
     let allow_stepping = true;
     let stdin = io::stdin();
+    let mut debugger = Debugger::new();
+    let mut latency_meter = options.input_latency.then(LatencyMeter::new);
+	if session && !options.fresh && options.play_path.is_none() {
+		resume_session(&mut nes, &session_path(&state_path));
+	}
+	let mut window_layout = None;
+	let mut autosave = Autosave::new(Duration::from_secs(config.get("autosave", 0)), Instant::now());
+	let mut movie = options.open_movie(&mut nes);
+	let mut audio_sink = options.audio_sink();
+	let mut achievements = options.achievements_path.as_ref()
+		.map(|path| Achievements::load(path).unwrap_or_else(|e| panic!("Can't load the achievements: {}", e)));
 
     loop {
 		let value = closed_window_mutex.lock().unwrap();
@@ -49,16 +578,190 @@ fn main() {
         }
 		drop(value);
 
+		if rom_watcher.as_ref().is_some_and(|watcher| watcher.changed()) {
+			info!("ROM changed, reloading");
+			nes.save_battery();
+			// A broken ROM (e.g. an assembler error) keeps the running game, the next write reloads again
+			match options.try_open_nes(&config) {
+				Ok(reloaded) => {
+					nes = reloaded;
+					if options.fresh_debugger {
+						debugger = Debugger::new();
+					}
+					let _ = frame_sender.send(render::Frame::capture(&nes));
+				}
+				Err(e) => error!("Could not reload {}: {}, keeping the running game", options.rom_path(), e),
+			}
+		}
+
+		while let Ok(command) = command_receiver.try_recv() {
+			match command {
+				render::Command::SaveState(slot) => save_state(&mut nes, &slot_path(&state_path, slot)),
+				render::Command::LoadState(slot) => {
+					let path = slot_path(&state_path, slot);
+					match nes.load_state_file(&path) {
+						Ok(()) => {
+							info!("Loaded state from {:?}", path);
+							if let Some(movie) = &mut movie {
+								movie.state_loaded(&nes);
+							}
+						}
+						Err(e) => error!("Could not load state from {:?}: {}", path, e),
+					}
+					let _ = frame_sender.send(render::Frame::capture(&nes));
+				}
+				render::Command::Reset => nes.reset(),
+				render::Command::SetPalette(settings) => nes.cpu.ppu_mut().set_rgb_palette(Palette::new(&settings)),
+				render::Command::SetSampleRate(rate) => nes.cpu.apu_mut().set_sample_rate(rate),
+				render::Command::WindowClosed(layout) => window_layout = Some(layout),
+			}
+		}
+		if autosave.due(Instant::now()) {
+			save_state(&mut nes, &autosave_path(&state_path));
+		}
+
+		// Buttons pressed in the window
+		while let Ok(event) = input_receiver.try_recv() {
+			// The movie presses the buttons while it plays
+			if movie.as_ref().is_some_and(|movie| movie.mode() == MovieMode::Playing) {
+				continue;
+			}
+			nes.set_button(event.player, event.button, event.pressed);
+			if let Some(meter) = &mut latency_meter {
+				meter.input(&event, nes.cpu.controller(event.player).latches());
+			}
+		}
+
         if allow_stepping {
-            // Read and discard
+            // Empty line executes an instruction, anything else is a debugger command
             let mut buf: String = String::new();
             let _ = stdin.read_line(&mut buf).unwrap();
+            let frame = nes.frame();
+            if !debugger.command(&buf, &mut nes) {
+                // Commands that run frames (`tas advance`, `diagnose`...) show where they stopped
+                if nes.frame() != frame {
+                    let _ = frame_sender.send(render::Frame::capture(&nes));
+                }
+                continue;
+            }
+        }
+        let frame = nes.frame();
+        step_with_post_mortem(&mut nes);
+        debugger.after_step(&mut nes);
+        if let Some(movie) = movie.as_mut().filter(|_| nes.frame() != frame) {
+            movie.frame(&mut nes);
+        }
+        if let Some(achievements) = achievements.as_mut().filter(|_| nes.frame() != frame) {
+            for unlock in achievements.frame(&mut nes) {
+                info!("Achievement unlocked: {} (frame {})", unlock.title, unlock.frame);
+            }
+        }
+
+        // When stepping, show every instruction (so the beam overlay follows), otherwise only completed frames
+        if allow_stepping || nes.frame() != frame {
+            let mut captured = render::Frame::capture(&nes);
+            captured.samples = nes.cpu.apu_mut().take_samples();
+            audio_sink.push_samples(&captured.samples);
+            if let Some(meter) = latency_meter.as_mut().filter(|_| nes.frame() != frame) {
+                let latches = [nes.cpu.controller(0).latches(), nes.cpu.controller(1).latches()];
+                for latency in meter.end_frame(Instant::now(), latches) {
+                    info!("Input latency: {:.1}ms", latency.as_secs_f64() * 1000.0);
+                }
+                captured.input_latency = meter.average();
+            }
+            // Fails only when the window was closed, we check that at the top of the loop
+            let _ = frame_sender.send(captured);
         }
-        nes.cpu.clock_tick();
         //std::thread::sleep(std::time::Duration::from_millis(200));
     }
 
+	nes.save_battery();
+	if let Some(achievements) = &achievements {
+		let unlocked = achievements.list().iter().filter(|achievement| achievement.unlocked().is_some()).count();
+		info!("Unlocked {} of {} achievements", unlocked, achievements.list().len());
+	}
+	if session {
+		// The window sends its layout right before it closes
+		while let Ok(command) = command_receiver.try_recv() {
+			if let render::Command::WindowClosed(layout) = command {
+				window_layout = Some(layout);
+			}
+		}
+		save_session(&mut nes, &session_path(&state_path), window_layout);
+	}
+	nes.cpu.unimplemented().log_summary();
+	options.save_needs_report(Some(nes.cpu.cartridge().crc32()), nes.frame(), &nes.cpu.unimplemented().needs());
+	nes.cpu.suspicious().log_summary();
+	options.log_opcode_stats(&nes);
+	options.save_notes(&nes);
+	if let (Some(movie), Some(path)) = (&movie, &options.record_path) {
+		match movie.save(Path::new(path)) {
+			Ok(()) => info!("Saved the movie to {} ({} frames, {} rerecords)", path, movie.len(), movie.rerecords()),
+			Err(e) => error!("Could not save the movie to {}: {}", path, e),
+		}
+	}
+
+	finish_trace_log(nes, trace_log);
+
 	// Wait for the thread to finish executing
 	handle.join().expect("Failed to join the thread.");
 
 }
+
+/// The trace log ends with the NES, wait until it is written.
+fn finish_trace_log(nes: NES, trace_log: Option<thread::JoinHandle<()>>) {
+	drop(nes);
+	if let Some(handle) = trace_log {
+		handle.join().expect("The trace log thread panicked");
+	}
+}
+
+fn save_state(nes: &mut NES, path: &Path) {
+	match nes.save_state_file(path) {
+		Ok(()) => info!("Saved state to {:?}", path),
+		Err(e) => error!("Could not save state to {:?}: {}", path, e),
+	}
+}
+
+/// Start from the machine of the last exit, when there is one.
+fn resume_session(nes: &mut NES, path: &Path) {
+	if !path.exists() {
+		return;
+	}
+	match nes.load_state_file(path) {
+		Ok(()) => info!("Resumed where you left off ({:?}), --fresh starts from power on", path),
+		Err(e) => warn!("Could not resume the session {:?}, starting from power on: {}", path, e),
+	}
+}
+
+/// Save the machine, and the window layout of the game to the settings file.
+fn save_session(nes: &mut NES, path: &Path, layout: Option<WindowLayout>) {
+	save_state(nes, path);
+	if let Some(layout) = layout {
+		let mut config = Config::load(CONFIG_PATH);
+		layout.write_config(&mut config, nes.cpu.cartridge().crc32());
+		if let Err(e) = config.save(CONFIG_PATH) {
+			error!("Failed to save the settings to {}: {}", CONFIG_PATH, e);
+		}
+	}
+}
+
+/// Execute an instruction. If the emulator panics (illegal opcode, unimplemented instruction...), dump the last
+/// executed instructions and the machine state to files before crashing, so they can be attached to the bug report
+/// and the crash reproduced by loading the state.
+fn step_with_post_mortem(nes: &mut NES) {
+    if let Err(err) = panic::catch_unwind(AssertUnwindSafe(|| nes.step())) {
+        let reason = common::panic_message(err.as_ref());
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let path = format!("crash-{}.log", timestamp);
+        match nes.dump_trace(&path, &reason) {
+            Ok(()) => error!("Emulator crashed, trace of the last instructions saved to: {}", path),
+            Err(e) => error!("Emulator crashed, could not save trace to {}: {}", path, e),
+        }
+        save_state(nes, Path::new(&format!("crash-{}.state", timestamp)));
+        panic::resume_unwind(err);
+    }
+}
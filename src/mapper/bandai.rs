@@ -0,0 +1,250 @@
+use log::warn;
+
+use crate::{rom_parser::MirrorType, savestate::Serializer};
+use super::{ciram_index, eeprom::{Chip, Eeprom}, CpuMapping, Mapper, PpuFetch, SharedRom};
+
+/// Mappers 16 and 159 (Bandai FCG-1, FCG-2 and LZ93D50): Dragon Ball Z, SD Gundam Gaiden, Famicom Jump II.
+/// Read here: https://www.nesdev.org/wiki/Bandai_FCG_board
+///
+/// - PRG: 16KB bank at $8000, the last 16KB bank fixed at $C000
+/// - CHR: 8 1KB banks
+/// - IRQ counter, clocked by CPU cycles
+/// - Saves in a serial EEPROM: 24C02 (256 bytes) on mapper 16, 24C01 (128 bytes) on mapper 159. Bit 4 of reads of
+///   $6000-$7FFF is the EEPROM data line.
+///
+/// The FCG chips have the registers at $6000-$7FFF and the IRQ counter is written directly, the LZ93D50 has them at
+/// $8000-$FFFF and the counter is reloaded from a latch. Mapper 16 is used for both, so both are emulated.
+#[derive(Clone)]
+pub struct FCG {
+	prg_rom: SharedRom,
+	chr: SharedRom,
+	chr_ram: bool,
+
+	prg_bank: u8,		// $x008
+	chr_banks: [u8; 8],	// $x000-$x007
+	mirroring: u8,		// $x009
+
+	irq_enabled: bool,	// $x00A bit 0
+	irq_counter: u16,
+	irq_latch: u16,		// $x00B-$x00C (LZ93D50)
+	irq_pending: bool,
+
+	eeprom: Eeprom,		// $x00D: bit 5 is SCL, bit 6 is SDA
+}
+
+impl FCG {
+	pub fn new(prg_rom: Vec<u8>, chr: Vec<u8>, eeprom_chip: Chip) -> Self {
+		let chr_ram = chr.is_empty();
+		FCG {
+			prg_rom: SharedRom::new(prg_rom),
+			chr: SharedRom::new(if chr_ram { vec![0; 1024 * 8] } else { chr }),
+			chr_ram,
+			prg_bank: 0,
+			chr_banks: [0; 8],
+			mirroring: 0,
+			irq_enabled: false,
+			irq_counter: 0,
+			irq_latch: 0,
+			irq_pending: false,
+			eeprom: Eeprom::new(eeprom_chip),
+		}
+	}
+
+	fn prg_offset(&self, addr: u16) -> usize {
+		let offset = match addr {
+			0x8000..=0xBFFF => (self.prg_bank & 0x0F) as usize * 0x4000 + (addr & 0x3FFF) as usize,
+			_ => self.prg_rom.len() - 0x4000 + (addr & 0x3FFF) as usize,
+		};
+		offset % self.prg_rom.len()
+	}
+
+	fn chr_offset(&self, addr: u16) -> usize {
+		let slot = (addr as usize & 0x1FFF) / 0x400;
+		(self.chr_banks[slot] as usize * 0x400 + (addr & 0x3FF) as usize) % self.chr.len()
+	}
+
+	fn ciram_index(&self, addr: u16) -> usize {
+		match self.mirroring & 0b11 {
+			0 => ciram_index(addr, &MirrorType::VERTICAL),
+			1 => ciram_index(addr, &MirrorType::HORIZONTAL),
+			// One screen
+			page => (page as usize - 2) * 0x400 + (addr & 0x3FF) as usize,
+		}
+	}
+}
+
+impl Mapper for FCG {
+	fn cpu_read(&mut self, addr: u16, _peek: bool) -> Option<u8> {
+		match addr {
+			// The other bits are open bus, games only look at bit 4
+			0x6000..=0x7FFF => Some((self.eeprom.output() as u8) << 4),
+			0x8000..=0xFFFF => Some(self.prg_rom[self.prg_offset(addr)]),
+			_ => None,
+		}
+	}
+
+	fn cpu_mapping(&self, addr: u16) -> CpuMapping {
+		match addr {
+			0x6000..=0x7FFF => CpuMapping::Register("EEPROM data"),
+			0x8000..=0xFFFF => CpuMapping::PrgRom { offset: self.prg_offset(addr), bank_size: 0x4000 },
+			_ => CpuMapping::OpenBus,
+		}
+	}
+
+	fn cpu_write(&mut self, addr: u16, value: u8, poke: bool) {
+		if poke && addr >= 0x8000 {
+			let offset = self.prg_offset(addr);
+			self.prg_rom.make_mut()[offset] = value;
+			return;
+		}
+		if addr < 0x6000 {
+			return;
+		}
+
+		let lz93d50 = addr >= 0x8000;
+		match addr & 0x0F {
+			reg @ 0x0..=0x7 => self.chr_banks[reg as usize] = value,
+			0x8 => self.prg_bank = value,
+			0x9 => self.mirroring = value,
+			0xA => {
+				self.irq_enabled = value & 1 != 0;
+				self.irq_pending = false;
+				if lz93d50 {
+					self.irq_counter = self.irq_latch;
+				}
+			}
+			0xB | 0xC => {
+				let shift = if addr & 0x0F == 0xB { 0 } else { 8 };
+				let target = if lz93d50 { &mut self.irq_latch } else { &mut self.irq_counter };
+				*target = (*target & !(0xFF << shift)) | ((value as u16) << shift);
+			}
+			0xD => self.eeprom.write(value & 0x20 != 0, value & 0x40 != 0),
+			_ => {}
+		}
+	}
+
+	fn ppu_read(&mut self, addr: u16, _fetch: PpuFetch, ciram: &[u8]) -> u8 {
+		match addr {
+			0x0000..=0x1FFF => self.chr[self.chr_offset(addr)],
+			_ => ciram[self.ciram_index(addr)],
+		}
+	}
+
+	fn ppu_write(&mut self, addr: u16, value: u8, ciram: &mut [u8]) {
+		match addr {
+			0x0000..=0x1FFF => {
+				if self.chr_ram {
+					let offset = self.chr_offset(addr);
+					self.chr.make_mut()[offset] = value;
+				} else {
+					warn!("Write to CHR ROM ignored: [{:#X}] = {:#X}", addr, value);
+				}
+			}
+			_ => ciram[self.ciram_index(addr)] = value,
+		}
+	}
+
+	fn cpu_tick(&mut self) {
+		if self.irq_enabled {
+			// The IRQ fires on the cycle the counter is 0, before it wraps around
+			if self.irq_counter == 0 {
+				self.irq_pending = true;
+			}
+			self.irq_counter = self.irq_counter.wrapping_sub(1);
+		}
+	}
+
+	fn irq(&self) -> bool {
+		self.irq_pending
+	}
+
+	fn battery_data(&self) -> Option<Vec<u8>> {
+		Some(self.eeprom.data().to_vec())
+	}
+
+	fn load_battery_data(&mut self, data: &[u8]) {
+		self.eeprom.load_data(data);
+	}
+
+	fn serialize(&mut self, s: &mut Serializer) {
+		if self.chr_ram {
+			s.value(&mut self.chr);
+		}
+		s.value(&mut self.prg_bank);
+		s.value(&mut self.chr_banks);
+		s.value(&mut self.mirroring);
+		s.value(&mut self.irq_enabled);
+		s.value(&mut self.irq_counter);
+		s.value(&mut self.irq_latch);
+		s.value(&mut self.irq_pending);
+		s.value(&mut self.eeprom);
+	}
+
+	fn fork(&self) -> Box<dyn Mapper> {
+		Box::new(self.clone())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::FCG;
+	use crate::mapper::{copy_state, eeprom::Chip, Mapper, PpuFetch};
+
+	/// 128KB PRG ROM where each byte is its 16KB bank number, 32KB CHR ROM where each byte is its 1KB bank number.
+	fn initialize() -> FCG {
+		let prg_rom = (0..128 * 1024).map(|i| (i / 0x4000) as u8).collect();
+		let chr = (0..32 * 1024).map(|i| (i / 0x400) as u8).collect();
+		FCG::new(prg_rom, chr, Chip::X24C02)
+	}
+
+	#[test]
+	fn test_banking() {
+		let mut fcg = initialize();
+		fcg.cpu_write(0x8008, 3, false);
+		assert_eq!((fcg.cpu_read(0x8000, false), fcg.cpu_read(0xC000, false)), (Some(3), Some(7)));
+		// The FCG registers at $6000
+		fcg.cpu_write(0x6008, 5, false);
+		assert_eq!(fcg.cpu_read(0xBFFF, false), Some(5));
+
+		let mut ciram = [0; 2048];
+		for i in 0..8 {
+			fcg.cpu_write(0x8000 + i, 10 + i as u8, false);
+		}
+		assert_eq!(fcg.ppu_read(0x0000, PpuFetch::Background, &ciram), 10);
+		assert_eq!(fcg.ppu_read(0x1C00, PpuFetch::Background, &ciram), 17);
+
+		// One screen B
+		fcg.cpu_write(0x8009, 3, false);
+		fcg.ppu_write(0x2000, 0x42, &mut ciram);
+		assert_eq!(ciram[0x400], 0x42);
+
+		let mut copy = initialize();
+		copy_state(&mut fcg, &mut copy);
+		assert_eq!(copy.cpu_read(0x8000, false), Some(5));
+	}
+
+	#[test]
+	fn test_irq() {
+		let mut fcg = initialize();
+		// LZ93D50: the counter is reloaded from the latch when enabling
+		fcg.cpu_write(0x800B, 2, false);
+		fcg.cpu_write(0x800C, 0, false);
+		fcg.cpu_write(0x800A, 1, false);
+		fcg.cpu_tick();
+		fcg.cpu_tick();
+		assert!(!fcg.irq());
+		fcg.cpu_tick();
+		assert!(fcg.irq());
+		fcg.cpu_write(0x800A, 0, false);
+		assert!(!fcg.irq());
+
+		// FCG: the counter is written directly
+		fcg.cpu_write(0x600B, 1, false);
+		fcg.cpu_write(0x600C, 0, false);
+		fcg.cpu_write(0x600A, 1, false);
+		fcg.cpu_tick();
+		assert!(!fcg.irq());
+		fcg.cpu_tick();
+		assert!(fcg.irq());
+	}
+}
@@ -0,0 +1,349 @@
+use crate::savestate::{Serialize, Serializer};
+
+/// The serial EEPROM chips on Bandai boards.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Chip {
+	/// 128 bytes (mapper 159). No device address: the start condition is followed by the word address (7 bits) and
+	/// the R/W bit. Bits are sent LSB first.
+	X24C01,
+	/// 256 bytes (mapper 16). Standard I²C: device address (1010xxx + R/W), word address, data. Bits are sent MSB first.
+	X24C02,
+}
+
+impl Chip {
+	fn size(self) -> usize {
+		match self {
+			Chip::X24C01 => 128,
+			Chip::X24C02 => 256,
+		}
+	}
+
+	/// Writes of more than a page wrap around inside the page.
+	fn page_size(self) -> usize {
+		match self {
+			Chip::X24C01 => 4,
+			Chip::X24C02 => 8,
+		}
+	}
+
+	/// The mask of the bit that goes on the wire at position `bit` (0-7) of a byte.
+	fn bit_mask(self, bit: u8) -> u8 {
+		match self {
+			Chip::X24C01 => 1 << bit,
+			Chip::X24C02 => 0x80 >> bit,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+	Idle,
+	DeviceAddress,	// 24C02 only
+	WordAddress,
+	Read,
+	Write,
+	SendAck,		// The EEPROM acknowledges the byte it got
+	WaitAck,		// The game acknowledges the byte it read, to read the next one
+}
+
+impl Mode {
+	const ALL: [Mode; 7] = [Mode::Idle, Mode::DeviceAddress, Mode::WordAddress, Mode::Read, Mode::Write, Mode::SendAck, Mode::WaitAck];
+}
+
+impl Serialize for Mode {
+	fn serialize(&mut self, s: &mut Serializer) {
+		let mut index = *self as u8;
+		s.value(&mut index);
+		*self = Mode::ALL[index as usize];
+	}
+}
+
+/// I²C serial EEPROM (24C01/24C02), which the game drives by toggling the clock (SCL) and data (SDA) lines.
+/// SDA falling while SCL is high is a start condition, SDA rising while SCL is high is a stop condition. Otherwise the
+/// data changes while SCL is low, the EEPROM samples it (or outputs its bit) when SCL rises. After each byte, the
+/// receiver pulls SDA low for one clock (acknowledge).
+/// Read here: https://www.nesdev.org/wiki/Bandai_FCG_board#Serial_EEPROM
+#[derive(Clone)]
+pub struct Eeprom {
+	chip: Chip,
+	data: Vec<u8>,
+
+	mode: Mode,
+	next_mode: Mode,	// After the acknowledge
+	address: u8,
+	byte: u8,			// The byte being sent or received
+	bit: u8,			// Bits of `byte` sent or received
+	output: bool,		// SDA driven by the EEPROM, high when released
+
+	// The lines, to detect the edges
+	scl: bool,
+	sda: bool,
+}
+
+impl Eeprom {
+	pub fn new(chip: Chip) -> Self {
+		Eeprom {
+			chip,
+			data: vec![0xFF; chip.size()],	// Erased
+			mode: Mode::Idle,
+			next_mode: Mode::Idle,
+			address: 0,
+			byte: 0,
+			bit: 0,
+			output: true,
+			scl: false,
+			sda: false,
+		}
+	}
+
+	/// The game sets the lines.
+	pub fn write(&mut self, scl: bool, sda: bool) {
+		if self.scl && scl && self.sda && !sda {
+			self.start();
+		} else if self.scl && scl && !self.sda && sda {
+			// Stop
+			self.mode = Mode::Idle;
+			self.output = true;
+		} else if scl && !self.scl {
+			self.clock_rise(sda);
+		} else if !scl && self.scl {
+			self.clock_fall();
+		}
+		self.scl = scl;
+		self.sda = sda;
+	}
+
+	/// The SDA line as the game reads it: low when the game or the EEPROM pulls it low.
+	pub fn output(&self) -> bool {
+		self.output && self.sda
+	}
+
+	/// The contents, for the battery file.
+	pub fn data(&self) -> &[u8] {
+		&self.data
+	}
+
+	pub fn load_data(&mut self, data: &[u8]) {
+		let len = data.len().min(self.data.len());
+		self.data[..len].copy_from_slice(&data[..len]);
+	}
+
+	fn start(&mut self) {
+		// The 24C02 keeps the word address, so a repeated start can read from the address that was just written
+		self.mode = match self.chip {
+			Chip::X24C01 => Mode::WordAddress,
+			Chip::X24C02 => Mode::DeviceAddress,
+		};
+		self.bit = 0;
+		self.output = true;
+	}
+
+	fn clock_rise(&mut self, sda: bool) {
+		match self.mode {
+			Mode::DeviceAddress | Mode::WordAddress | Mode::Write if self.bit < 8 => {
+				let mask = self.chip.bit_mask(self.bit);
+				if sda {
+					self.byte |= mask;
+				} else {
+					self.byte &= !mask;
+				}
+				self.bit += 1;
+			}
+			Mode::Read if self.bit < 8 => {
+				self.output = self.byte & self.chip.bit_mask(self.bit) != 0;
+				self.bit += 1;
+			}
+			Mode::SendAck => self.output = false,
+			Mode::WaitAck => {
+				if sda {
+					// No acknowledge: the game doesn't want more bytes
+					self.next_mode = Mode::Idle;
+				} else {
+					self.next_mode = Mode::Read;
+					self.byte = self.data[self.address as usize];
+				}
+			}
+			_ => {}
+		}
+	}
+
+	fn clock_fall(&mut self) {
+		match self.mode {
+			Mode::DeviceAddress if self.bit == 8 => {
+				if self.byte & 0xF0 != 0xA0 {
+					// Another device
+					self.mode = Mode::Idle;
+					return;
+				}
+				let read = self.byte & 1 != 0;
+				self.acknowledge(if read { Mode::Read } else { Mode::WordAddress });
+			}
+			Mode::WordAddress if self.bit == 8 => {
+				match self.chip {
+					Chip::X24C01 => {
+						self.address = self.byte & 0x7F;
+						let read = self.byte & 0x80 != 0;
+						self.acknowledge(if read { Mode::Read } else { Mode::Write });
+					}
+					Chip::X24C02 => {
+						self.address = self.byte;
+						self.acknowledge(Mode::Write);
+					}
+				}
+			}
+			Mode::Read if self.bit == 8 => {
+				self.mode = Mode::WaitAck;
+				self.output = true;
+				self.address = ((self.address as usize + 1) % self.chip.size()) as u8;
+			}
+			Mode::Write if self.bit == 8 => {
+				self.data[self.address as usize] = self.byte;
+				let page_size = self.chip.page_size();
+				let address = self.address as usize;
+				self.address = ((address & !(page_size - 1)) | ((address + 1) & (page_size - 1))) as u8;
+				self.acknowledge(Mode::Write);
+			}
+			Mode::SendAck | Mode::WaitAck => {
+				self.mode = self.next_mode;
+				self.bit = 0;
+				self.output = true;
+			}
+			_ => {}
+		}
+	}
+
+	/// A byte was received, acknowledge it on the next clock and then continue in `next_mode`.
+	fn acknowledge(&mut self, next_mode: Mode) {
+		if next_mode == Mode::Read {
+			self.byte = self.data[self.address as usize];
+		}
+		self.mode = Mode::SendAck;
+		self.next_mode = next_mode;
+		self.output = true;
+	}
+}
+
+impl Serialize for Eeprom {
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.data);
+		s.value(&mut self.mode);
+		s.value(&mut self.next_mode);
+		s.value(&mut self.address);
+		s.value(&mut self.byte);
+		s.value(&mut self.bit);
+		s.value(&mut self.output);
+		s.value(&mut self.scl);
+		s.value(&mut self.sda);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Chip, Eeprom};
+
+	/// Bit-bangs the EEPROM the way games do.
+	struct Bus {
+		eeprom: Eeprom,
+	}
+
+	impl Bus {
+		fn start(&mut self) {
+			self.eeprom.write(false, true);
+			self.eeprom.write(true, true);
+			self.eeprom.write(true, false);
+			self.eeprom.write(false, false);
+		}
+
+		fn stop(&mut self) {
+			self.eeprom.write(false, false);
+			self.eeprom.write(true, false);
+			self.eeprom.write(true, true);
+		}
+
+		/// Clock one bit out, returns SDA while the clock is high.
+		fn clock(&mut self, sda: bool) -> bool {
+			self.eeprom.write(false, sda);
+			self.eeprom.write(true, sda);
+			let bit = self.eeprom.output();
+			self.eeprom.write(false, sda);
+			bit
+		}
+
+		/// Send a byte, returns true when the EEPROM acknowledged it.
+		fn send(&mut self, byte: u8, lsb_first: bool) -> bool {
+			for i in 0..8 {
+				let bit = if lsb_first { byte >> i } else { byte >> (7 - i) } & 1;
+				self.clock(bit != 0);
+			}
+			!self.clock(true)
+		}
+
+		/// Receive a byte and acknowledge it when `ack` is true.
+		fn receive(&mut self, ack: bool, lsb_first: bool) -> u8 {
+			let mut byte = 0;
+			for i in 0..8 {
+				let bit = self.clock(true) as u8;
+				byte |= if lsb_first { bit << i } else { bit << (7 - i) };
+			}
+			self.clock(!ack);
+			byte
+		}
+	}
+
+	#[test]
+	fn test_24c02() {
+		let mut bus = Bus { eeprom: Eeprom::new(Chip::X24C02) };
+		// Write 2 bytes at $10
+		bus.start();
+		assert!(bus.send(0xA0, false));
+		assert!(bus.send(0x10, false));
+		assert!(bus.send(0x12, false));
+		assert!(bus.send(0x34, false));
+		bus.stop();
+		assert_eq!(&bus.eeprom.data()[0x10..0x12], [0x12, 0x34]);
+
+		// Random read: write the address, then a repeated start to read
+		bus.start();
+		assert!(bus.send(0xA0, false));
+		assert!(bus.send(0x10, false));
+		bus.start();
+		assert!(bus.send(0xA1, false));
+		assert_eq!(bus.receive(true, false), 0x12);
+		assert_eq!(bus.receive(false, false), 0x34);
+		bus.stop();
+
+		// Another device on the bus
+		bus.start();
+		assert!(!bus.send(0x50, false));
+		bus.stop();
+
+		// Writes wrap around inside the 8 byte page
+		bus.start();
+		bus.send(0xA0, false);
+		bus.send(0x07, false);
+		bus.send(0xAA, false);
+		bus.send(0xBB, false);
+		bus.stop();
+		assert_eq!((bus.eeprom.data()[0x07], bus.eeprom.data()[0x00], bus.eeprom.data()[0x08]), (0xAA, 0xBB, 0xFF));
+	}
+
+	#[test]
+	fn test_24c01() {
+		let mut bus = Bus { eeprom: Eeprom::new(Chip::X24C01) };
+		// Address $05 and the R/W bit (bit 7 on the wire, since the bits are sent LSB first)
+		bus.start();
+		assert!(bus.send(0x05, true));
+		assert!(bus.send(0x5A, true));
+		bus.stop();
+		assert_eq!(bus.eeprom.data()[0x05], 0x5A);
+
+		bus.start();
+		assert!(bus.send(0x80 | 0x05, true));
+		assert_eq!(bus.receive(false, true), 0x5A);
+		bus.stop();
+
+		let mut loaded = Eeprom::new(Chip::X24C01);
+		loaded.load_data(bus.eeprom.data());
+		assert_eq!(loaded.data(), bus.eeprom.data());
+	}
+}
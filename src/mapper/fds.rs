@@ -0,0 +1,827 @@
+use log::{info, warn};
+
+use crate::{apu::{apu::{Level, PULSE_STEP}, expansion::ExpansionAudio}, rom_parser::MirrorType, savestate::{Serialize, Serializer}};
+use super::{ciram_index, CpuMapping, Mapper, PpuFetch, SharedRom};
+
+/// Size of a disk side in a .fds file, without the gaps and CRCs.
+pub const DISK_SIDE_SIZE: usize = 65_500;
+
+/// The BIOS is mapped at $E000-$FFFF.
+pub const BIOS_SIZE: usize = 1024 * 8;
+pub const BIOS_FILE_NAME: &str = "disksys.rom";
+
+/// iNES mapper number of the FDS, used by some FDS conversions.
+pub const MAPPER_NUMBER: u8 = 20;
+
+// The disk image the drive reads, has gaps between the blocks. Read here: https://www.nesdev.org/wiki/FDS_disk_format
+const LEADING_GAP: usize = 28_300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+const GAP_END: u8 = 0x80;
+
+/// CPU cycles between bytes, when the disk is spinning (about 96.4 kbit/s).
+const BYTE_TRANSFER_CYCLES: u32 = 149;
+/// CPU cycles from the end of the disk until the head is back at the start.
+const REWIND_CYCLES: u32 = 50_000;
+
+/// Famicom Disk System: the RAM adapter in the cartridge slot, with the disk drive.
+/// Read here: https://www.nesdev.org/wiki/Family_Computer_Disk_System
+///
+/// - 32KB RAM at $6000-$DFFF, the BIOS ROM at $E000-$FFFF, 8KB CHR RAM
+/// - Disk drive registers ($4020-$4026, $4030-$4033). The BIOS reads and writes the disk one byte at a time, on IRQ.
+/// - Timer IRQ
+/// - Wavetable audio channel ($4040-$4092)
+///
+/// Writes to the disk change the disk in memory only, the .fds file is not saved.
+#[derive(Clone)]
+pub struct FDS {
+	bios: SharedRom,
+	ram: Vec<u8>,
+	chr_ram: Vec<u8>,
+
+	// Disk sides, with gaps and CRCs added, as the drive sees them
+	sides: Vec<SharedRom>,
+	inserted_side: Option<usize>,
+
+	// $4020-$4023
+	timer_reload: u16,
+	timer_counter: u16,
+	timer_repeat: bool,
+	timer_enabled: bool,
+	timer_irq: bool,
+	disk_registers_enabled: bool,
+	sound_registers_enabled: bool,
+
+	// $4024, $4025, $4031
+	write_data: u8,
+	read_data: u8,
+	motor_on: bool,
+	reset_transfer: bool,
+	read_mode: bool,
+	mirror_type: MirrorType,
+	crc_control: bool,
+	disk_ready: bool,
+	disk_irq_enabled: bool,
+
+	// Drive state
+	disk_irq: bool,
+	transfer_complete: bool,
+	end_of_head: bool,
+	scanning_disk: bool,
+	gap_ended: bool,
+	head_position: usize,
+	delay: u32,
+
+	audio: FdsAudio,
+}
+
+impl FDS {
+	/// `disk` is the contents of the .fds file, with or without the 16 bytes fwNES header.
+	pub fn new(bios: Vec<u8>, disk: &[u8]) -> Self {
+		assert_eq!(bios.len(), BIOS_SIZE, "The FDS BIOS must be 8KB");
+		let sides: Vec<SharedRom> = parse_disk(disk).iter().map(|side| SharedRom::new(add_gaps(side))).collect();
+		info!("FDS disk: {} side(s)", sides.len());
+		FDS {
+			bios: SharedRom::new(bios),
+			ram: vec![0; 1024 * 32],
+			chr_ram: vec![0; 1024 * 8],
+			inserted_side: if sides.is_empty() { None } else { Some(0) },
+			sides,
+			timer_reload: 0,
+			timer_counter: 0,
+			timer_repeat: false,
+			timer_enabled: false,
+			timer_irq: false,
+			disk_registers_enabled: false,
+			sound_registers_enabled: false,
+			write_data: 0,
+			read_data: 0,
+			motor_on: false,
+			reset_transfer: false,
+			read_mode: true,
+			mirror_type: MirrorType::HORIZONTAL,
+			crc_control: false,
+			disk_ready: false,
+			disk_irq_enabled: false,
+			disk_irq: false,
+			transfer_complete: false,
+			end_of_head: true,
+			scanning_disk: false,
+			gap_ended: false,
+			head_position: 0,
+			delay: 0,
+			audio: FdsAudio::new(),
+		}
+	}
+
+	/// The drive moves the head to the next byte.
+	fn clock_disk(&mut self) {
+		let Some(side) = self.inserted_side else {
+			return;
+		};
+		if !self.motor_on {
+			self.end_of_head = true;
+			self.scanning_disk = false;
+			return;
+		}
+		if self.reset_transfer && !self.scanning_disk {
+			return;
+		}
+		if self.end_of_head {
+			self.delay = REWIND_CYCLES;
+			self.end_of_head = false;
+			self.head_position = 0;
+			self.gap_ended = false;
+			return;
+		}
+		if self.delay > 0 {
+			self.delay -= 1;
+			return;
+		}
+
+		self.scanning_disk = true;
+		let mut need_irq = self.disk_irq_enabled;
+		if self.read_mode {
+			let data = self.sides[side][self.head_position];
+			if !self.disk_ready {
+				self.gap_ended = false;
+			} else if data == GAP_END && !self.gap_ended {
+				// The start of the block, the BIOS is waiting for the next byte
+				self.gap_ended = true;
+				need_irq = false;
+			}
+			if self.gap_ended {
+				self.transfer_complete = true;
+				self.read_data = data;
+				if need_irq {
+					self.disk_irq = true;
+				}
+			}
+		} else {
+			let mut data = 0;
+			if !self.crc_control {
+				self.transfer_complete = true;
+				data = self.write_data;
+				if need_irq {
+					self.disk_irq = true;
+				}
+			}
+			if !self.disk_ready {
+				data = 0;
+			}
+			self.sides[side].make_mut()[self.head_position] = data;
+			self.gap_ended = false;
+		}
+
+		self.head_position += 1;
+		if self.head_position >= self.sides[side].len() {
+			self.motor_on = false;
+			self.end_of_head = true;
+		} else {
+			self.delay = BYTE_TRANSFER_CYCLES;
+		}
+	}
+
+	fn clock_timer(&mut self) {
+		if !self.timer_enabled || !self.disk_registers_enabled {
+			return;
+		}
+		if self.timer_counter == 0 {
+			self.timer_irq = true;
+			self.timer_counter = self.timer_reload;
+			if !self.timer_repeat {
+				self.timer_enabled = false;
+			}
+		} else {
+			self.timer_counter -= 1;
+		}
+	}
+}
+
+impl Mapper for FDS {
+	fn cpu_read(&mut self, addr: u16, peek: bool) -> Option<u8> {
+		let value = match addr {
+			0x4030 if self.disk_registers_enabled => {
+				let status = (self.timer_irq as u8)
+					| ((self.transfer_complete as u8) << 1)
+					| ((self.end_of_head as u8) << 6);
+				if !peek {
+					self.transfer_complete = false;
+					self.timer_irq = false;
+					self.disk_irq = false;
+				}
+				status
+			}
+			0x4031 if self.disk_registers_enabled => {
+				if !peek {
+					self.transfer_complete = false;
+					self.disk_irq = false;
+				}
+				self.read_data
+			}
+			0x4032 if self.disk_registers_enabled => {
+				let inserted = self.inserted_side.is_some();
+				let mut status = 0x40;
+				if !inserted {
+					// Not inserted, not ready, write protected
+					status |= 0b111;
+				} else if !self.scanning_disk {
+					status |= 0b10;
+				}
+				status
+			}
+			// Battery is good
+			0x4033 if self.disk_registers_enabled => 0x80,
+			0x4040..=0x4097 if self.sound_registers_enabled => self.audio.read(addr),
+			0x6000..=0xDFFF => self.ram[(addr - 0x6000) as usize],
+			0xE000..=0xFFFF => self.bios[(addr - 0xE000) as usize],
+			_ => return None,
+		};
+		Some(value)
+	}
+
+	fn cpu_mapping(&self, addr: u16) -> CpuMapping {
+		match addr {
+			0x4030..=0x4033 if self.disk_registers_enabled => CpuMapping::Register("FDS disk"),
+			0x4040..=0x4097 if self.sound_registers_enabled => CpuMapping::Register("FDS audio"),
+			0x6000..=0xDFFF => CpuMapping::PrgRam { offset: (addr - 0x6000) as usize },
+			0xE000..=0xFFFF => CpuMapping::Other(format!("BIOS + ${:04X}", addr - 0xE000)),
+			_ => CpuMapping::OpenBus,
+		}
+	}
+
+	fn cpu_write(&mut self, addr: u16, value: u8, poke: bool) {
+		if poke && addr >= 0xE000 {
+			self.bios.make_mut()[(addr - 0xE000) as usize] = value;
+			return;
+		}
+		if !self.disk_registers_enabled && (0x4024..=0x4026).contains(&addr) {
+			return;
+		}
+		match addr {
+			0x4020 => self.timer_reload = (self.timer_reload & 0xFF00) | value as u16,
+			0x4021 => self.timer_reload = (self.timer_reload & 0x00FF) | ((value as u16) << 8),
+			0x4022 => {
+				self.timer_repeat = value & 1 != 0;
+				self.timer_enabled = value & 0b10 != 0 && self.disk_registers_enabled;
+				if self.timer_enabled {
+					self.timer_counter = self.timer_reload;
+				} else {
+					self.timer_irq = false;
+				}
+			}
+			0x4023 => {
+				self.disk_registers_enabled = value & 1 != 0;
+				self.sound_registers_enabled = value & 0b10 != 0;
+				if !self.disk_registers_enabled {
+					self.timer_irq = false;
+					self.disk_irq = false;
+					self.timer_enabled = false;
+				}
+			}
+			0x4024 => {
+				self.write_data = value;
+				self.transfer_complete = false;
+				self.disk_irq = false;
+			}
+			0x4025 => {
+				self.motor_on = value & 1 != 0;
+				self.reset_transfer = value & 0b10 != 0;
+				self.read_mode = value & 0b100 != 0;
+				self.mirror_type = if value & 0b1000 != 0 { MirrorType::HORIZONTAL } else { MirrorType::VERTICAL };
+				self.crc_control = value & 0x10 != 0;
+				self.disk_ready = value & 0x40 != 0;
+				self.disk_irq_enabled = value & 0x80 != 0;
+				self.disk_irq = false;
+			}
+			0x4040..=0x4097 if self.sound_registers_enabled => self.audio.write(addr, value),
+			0x6000..=0xDFFF => self.ram[(addr - 0x6000) as usize] = value,
+			_ => {}
+		}
+	}
+
+	fn ppu_read(&mut self, addr: u16, _fetch: PpuFetch, ciram: &[u8]) -> u8 {
+		match addr {
+			0x0000..=0x1FFF => self.chr_ram[addr as usize],
+			_ => ciram[ciram_index(addr, &self.mirror_type)],
+		}
+	}
+
+	fn ppu_write(&mut self, addr: u16, value: u8, ciram: &mut [u8]) {
+		match addr {
+			0x0000..=0x1FFF => self.chr_ram[addr as usize] = value,
+			_ => ciram[ciram_index(addr, &self.mirror_type)] = value,
+		}
+	}
+
+	fn cpu_tick(&mut self) {
+		self.clock_timer();
+		self.clock_disk();
+		self.audio.clock();
+	}
+
+	fn audio_sources(&self) -> &'static [ExpansionAudio] {
+		&[ExpansionAudio::Fds]
+	}
+
+	fn audio_output(&self, _source: ExpansionAudio) -> Level {
+		self.audio.output()
+	}
+
+	fn irq(&self) -> bool {
+		self.timer_irq || self.disk_irq
+	}
+
+	fn disk_sides(&self) -> usize {
+		self.sides.len()
+	}
+
+	fn insert_disk(&mut self, side: Option<usize>) {
+		match side {
+			Some(side) if side >= self.sides.len() => warn!("The disk has only {} side(s)", self.sides.len()),
+			_ => {
+				self.inserted_side = side;
+				self.motor_on = false;
+				self.end_of_head = true;
+				self.scanning_disk = false;
+			}
+		}
+	}
+
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.ram);
+		s.value(&mut self.chr_ram);
+		// The disk is saved too, games write their saves to it
+		s.value(&mut self.sides);
+		s.value(&mut self.inserted_side);
+		s.value(&mut self.timer_reload);
+		s.value(&mut self.timer_counter);
+		s.value(&mut self.timer_repeat);
+		s.value(&mut self.timer_enabled);
+		s.value(&mut self.timer_irq);
+		s.value(&mut self.disk_registers_enabled);
+		s.value(&mut self.sound_registers_enabled);
+		s.value(&mut self.write_data);
+		s.value(&mut self.read_data);
+		s.value(&mut self.motor_on);
+		s.value(&mut self.reset_transfer);
+		s.value(&mut self.read_mode);
+		s.value(&mut self.mirror_type);
+		s.value(&mut self.crc_control);
+		s.value(&mut self.disk_ready);
+		s.value(&mut self.disk_irq_enabled);
+		s.value(&mut self.disk_irq);
+		s.value(&mut self.transfer_complete);
+		s.value(&mut self.end_of_head);
+		s.value(&mut self.scanning_disk);
+		s.value(&mut self.gap_ended);
+		s.value(&mut self.head_position);
+		s.value(&mut self.delay);
+		s.value(&mut self.audio);
+	}
+
+	fn fork(&self) -> Box<dyn Mapper> {
+		Box::new(self.clone())
+	}
+}
+
+/// Split the .fds file to disk sides. The file may start with the fwNES header: "FDS\x1A" and the number of sides.
+pub fn parse_disk(contents: &[u8]) -> Vec<Vec<u8>> {
+	let contents = if contents.starts_with(b"FDS\x1A") { &contents[16..] } else { contents };
+	if contents.len() % DISK_SIDE_SIZE != 0 {
+		warn!("The FDS disk size ({}) is not a multiple of {} bytes", contents.len(), DISK_SIDE_SIZE);
+	}
+	contents.chunks(DISK_SIDE_SIZE).map(|side| side.to_vec()).collect()
+}
+
+/// Add the gaps between blocks and the CRCs, which the .fds format doesn't have.
+fn add_gaps(side: &[u8]) -> Vec<u8> {
+	let mut output = vec![0; LEADING_GAP];
+	let mut position = 0;
+	let mut file_size = 0;
+	while position < side.len() {
+		let length = match side[position] {
+			1 => 56,					// Disk info
+			2 => 2,						// File amount
+			3 => {
+				// File header, the file size is at bytes 13-14
+				file_size = side.get(position + 13..position + 15).map_or(0, |size| u16::from_le_bytes([size[0], size[1]]) as usize);
+				16
+			}
+			4 => 1 + file_size,			// File data
+			_ => break,
+		};
+		let end = (position + length).min(side.len());
+		output.push(GAP_END);
+		output.extend_from_slice(&side[position..end]);
+		// The BIOS doesn't check the CRC value, only the CRC error flag (which we never raise)
+		output.extend_from_slice(&[0x4D, 0x62]);
+		output.extend(std::iter::repeat_n(0, BLOCK_GAP));
+		position = end;
+	}
+	// The rest of the side is empty, for writes
+	if output.len() < LEADING_GAP + DISK_SIDE_SIZE {
+		output.resize(LEADING_GAP + DISK_SIDE_SIZE, 0);
+	}
+	output
+}
+
+/// The FDS wavetable channel. Read here: https://www.nesdev.org/wiki/FDS_audio
+#[derive(Clone)]
+struct FdsAudio {
+	wave_table: [u8; 64],			// $4040-$407F, 6 bit samples
+	wave_write_enabled: bool,		// $4089 bit 7, halts the wave
+	master_volume: u8,				// $4089 bits 0-1
+	frequency: u16,					// $4082, $4083 bits 0-3
+	wave_halted: bool,				// $4083 bit 7
+	envelopes_halted: bool,			// $4083 bit 6
+	wave_accumulator: u32,
+	wave_position: u8,
+	envelope_speed: u8,				// $408A, for both envelopes
+	volume: Envelope,				// $4080
+	mod_envelope: Envelope,			// $4084
+	mod_table: [u8; 64],			// $4088, 3 bit entries
+	mod_counter: i8,				// $4085, 7 bit signed
+	mod_frequency: u16,				// $4086, $4087 bits 0-3
+	mod_halted: bool,				// $4087 bit 7
+	mod_accumulator: u32,
+	mod_position: u8,
+}
+
+#[derive(Default, Clone)]
+struct Envelope {
+	direct: bool,		// bit 7: the gain is set directly
+	increase: bool,		// bit 6
+	speed: u8,			// bits 0-5
+	gain: u8,
+	counter: u32,
+}
+
+impl Envelope {
+	fn write(&mut self, value: u8) {
+		self.direct = value & 0x80 != 0;
+		self.increase = value & 0x40 != 0;
+		self.speed = value & 0x3F;
+		if self.direct {
+			self.gain = value & 0x3F;
+		}
+		self.counter = 0;
+	}
+
+	fn clock(&mut self, envelope_speed: u8) {
+		if self.direct {
+			return;
+		}
+		self.counter += 1;
+		if self.counter >= 8 * (envelope_speed as u32 + 1) * (self.speed as u32 + 1) {
+			self.counter = 0;
+			if self.increase && self.gain < 32 {
+				self.gain += 1;
+			} else if !self.increase && self.gain > 0 {
+				self.gain -= 1;
+			}
+		}
+	}
+}
+
+impl FdsAudio {
+	fn new() -> Self {
+		FdsAudio {
+			wave_table: [0; 64],
+			wave_write_enabled: false,
+			master_volume: 0,
+			frequency: 0,
+			wave_halted: true,
+			envelopes_halted: true,
+			wave_accumulator: 0,
+			wave_position: 0,
+			envelope_speed: 0xE8,
+			volume: Envelope::default(),
+			mod_envelope: Envelope::default(),
+			mod_table: [0; 64],
+			mod_counter: 0,
+			mod_frequency: 0,
+			mod_halted: true,
+			mod_accumulator: 0,
+			mod_position: 0,
+		}
+	}
+
+	fn read(&self, addr: u16) -> u8 {
+		match addr {
+			0x4040..=0x407F => self.wave_table[(addr - 0x4040) as usize],
+			0x4090 => self.volume.gain | 0x40,
+			0x4092 => self.mod_envelope.gain | 0x40,
+			_ => 0,
+		}
+	}
+
+	fn write(&mut self, addr: u16, value: u8) {
+		match addr {
+			0x4040..=0x407F => {
+				if self.wave_write_enabled {
+					self.wave_table[(addr - 0x4040) as usize] = value & 0x3F;
+				}
+			}
+			0x4080 => self.volume.write(value),
+			0x4082 => self.frequency = (self.frequency & 0x0F00) | value as u16,
+			0x4083 => {
+				self.frequency = (self.frequency & 0x00FF) | (((value & 0x0F) as u16) << 8);
+				self.wave_halted = value & 0x80 != 0;
+				self.envelopes_halted = value & 0x40 != 0;
+				if self.wave_halted {
+					self.wave_accumulator = 0;
+					self.wave_position = 0;
+				}
+			}
+			0x4084 => self.mod_envelope.write(value),
+			0x4085 => {
+				// 7 bit signed
+				self.mod_counter = ((value << 1) as i8) >> 1;
+			}
+			0x4086 => self.mod_frequency = (self.mod_frequency & 0x0F00) | value as u16,
+			0x4087 => {
+				self.mod_frequency = (self.mod_frequency & 0x00FF) | (((value & 0x0F) as u16) << 8);
+				self.mod_halted = value & 0x80 != 0;
+				if self.mod_halted {
+					self.mod_accumulator = 0;
+				}
+			}
+			0x4088 => {
+				// Each entry is written twice, at the position of the modulator
+				if self.mod_halted {
+					let position = (self.mod_position & 0x3E) as usize;
+					self.mod_table[position] = value & 0b111;
+					self.mod_table[position + 1] = value & 0b111;
+					self.mod_position = (self.mod_position + 2) & 0x3F;
+				}
+			}
+			0x4089 => {
+				self.wave_write_enabled = value & 0x80 != 0;
+				self.master_volume = value & 0b11;
+			}
+			0x408A => self.envelope_speed = value,
+			_ => {}
+		}
+	}
+
+	fn clock(&mut self) {
+		if !self.envelopes_halted && !self.wave_halted && self.envelope_speed != 0 {
+			self.volume.clock(self.envelope_speed);
+			self.mod_envelope.clock(self.envelope_speed);
+		}
+
+		if !self.mod_halted && self.mod_frequency != 0 {
+			self.mod_accumulator += self.mod_frequency as u32;
+			if self.mod_accumulator >= 0x10000 {
+				self.mod_accumulator -= 0x10000;
+				self.step_modulator();
+			}
+		}
+
+		if !self.wave_halted && !self.wave_write_enabled {
+			self.wave_accumulator += self.pitch();
+			if self.wave_accumulator >= 0x10000 {
+				self.wave_accumulator &= 0xFFFF;
+				self.wave_position = (self.wave_position + 1) & 0x3F;
+			}
+		}
+	}
+
+	fn step_modulator(&mut self) {
+		let counter = self.mod_counter as i16;
+		let counter = match self.mod_table[self.mod_position as usize] {
+			0 => counter,
+			1 => counter + 1,
+			2 => counter + 2,
+			3 => counter + 4,
+			4 => 0,
+			5 => counter - 4,
+			6 => counter - 2,
+			_ => counter - 1,
+		};
+		// Wrap to 7 bit signed
+		self.mod_counter = (((counter as u8) << 1) as i8) >> 1;
+		self.mod_position = (self.mod_position + 1) & 0x3F;
+	}
+
+	/// The wave frequency, changed by the modulator.
+	fn pitch(&self) -> u32 {
+		let mut temp = self.mod_counter as i32 * self.mod_envelope.gain as i32;
+		let remainder = temp & 0x0F;
+		temp >>= 4;
+		if remainder > 0 && temp & 0x80 == 0 {
+			temp += if self.mod_counter < 0 { -1 } else { 2 };
+		}
+		if temp >= 192 {
+			temp -= 256;
+		} else if temp < -64 {
+			temp += 256;
+		}
+		let mut adjustment = self.frequency as i32 * temp;
+		let remainder = adjustment & 0x3F;
+		adjustment >>= 6;
+		if remainder >= 32 {
+			adjustment += 1;
+		}
+		(self.frequency as i32 + adjustment).max(0) as u32
+	}
+
+	fn output(&self) -> Level {
+		// 1, 2/3, 2/4 and 2/5, in 60ths
+		const MASTER_VOLUME: [i64; 4] = [60, 40, 30, 24];
+		let gain = self.volume.gain.min(32) as i64;
+		let sample = self.wave_table[self.wave_position as usize] as i64 * gain * MASTER_VOLUME[self.master_volume as usize];
+		// At full volume, the FDS is about 2.4 times as loud as a full volume APU pulse channel
+		(sample * 36 * PULSE_STEP as i64 / (63 * 32 * 60)) as Level
+	}
+}
+
+impl Serialize for FdsAudio {
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.wave_table);
+		s.value(&mut self.wave_write_enabled);
+		s.value(&mut self.master_volume);
+		s.value(&mut self.frequency);
+		s.value(&mut self.wave_halted);
+		s.value(&mut self.envelopes_halted);
+		s.value(&mut self.wave_accumulator);
+		s.value(&mut self.wave_position);
+		s.value(&mut self.envelope_speed);
+		s.value(&mut self.volume);
+		s.value(&mut self.mod_envelope);
+		s.value(&mut self.mod_table);
+		s.value(&mut self.mod_counter);
+		s.value(&mut self.mod_frequency);
+		s.value(&mut self.mod_halted);
+		s.value(&mut self.mod_accumulator);
+		s.value(&mut self.mod_position);
+	}
+}
+
+impl Serialize for Envelope {
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.direct);
+		s.value(&mut self.increase);
+		s.value(&mut self.speed);
+		s.value(&mut self.gain);
+		s.value(&mut self.counter);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{add_gaps, parse_disk, BIOS_SIZE, DISK_SIDE_SIZE, FDS, GAP_END, LEADING_GAP};
+	use crate::{apu::expansion::ExpansionAudio, mapper::{copy_state, Mapper}};
+
+	/// A disk side with the disk info block, the file amount block and a 4 bytes file.
+	fn disk_side() -> Vec<u8> {
+		let mut side = vec![0; DISK_SIDE_SIZE];
+		side[0] = 1;
+		side[1..15].copy_from_slice(b"*NINTENDO-HVC*");
+		side[56..58].copy_from_slice(&[2, 1]);
+		side[58] = 3;
+		side[58 + 13] = 4; // File size
+		side[74..79].copy_from_slice(&[4, 0xA, 0xB, 0xC, 0xD]);
+		side
+	}
+
+	fn initialize() -> FDS {
+		let mut disk = b"FDS\x1A\x01".to_vec();
+		disk.resize(16, 0);
+		disk.extend(disk_side());
+		FDS::new(vec![0; BIOS_SIZE], &disk)
+	}
+
+	#[test]
+	fn test_parse_disk() {
+		let mut disk = disk_side();
+		disk.extend(disk_side());
+		assert_eq!(parse_disk(&disk).len(), 2);
+
+		let side = add_gaps(&disk_side());
+		assert!(side[..LEADING_GAP].iter().all(|&byte| byte == 0));
+		assert_eq!(side[LEADING_GAP], GAP_END);
+		assert_eq!(&side[LEADING_GAP + 1..LEADING_GAP + 15], &disk_side()[..14]);
+		// The file data block, after 3 blocks with their gap end, CRC and gap
+		let file_data = side.windows(6).position(|bytes| bytes == [GAP_END, 4, 0xA, 0xB, 0xC, 0xD]);
+		assert!(file_data.is_some());
+	}
+
+	#[test]
+	fn test_ram_and_timer_irq() {
+		let mut fds = initialize();
+		fds.cpu_write(0x6000, 0x42, false);
+		fds.cpu_write(0xDFFF, 0x43, false);
+		assert_eq!(fds.cpu_read(0x6000, false), Some(0x42));
+		assert_eq!(fds.cpu_read(0xDFFF, false), Some(0x43));
+
+		// The timer doesn't run while the disk registers are disabled
+		fds.cpu_write(0x4020, 2, false);
+		fds.cpu_write(0x4022, 0b11, false);
+		fds.cpu_tick();
+		assert!(!fds.irq());
+
+		fds.cpu_write(0x4023, 1, false);
+		fds.cpu_write(0x4022, 0b11, false);
+		fds.cpu_tick();
+		fds.cpu_tick();
+		assert!(!fds.irq());
+		fds.cpu_tick();
+		assert!(fds.irq());
+		// Reading the status acknowledges
+		assert_eq!(fds.cpu_read(0x4030, false).unwrap() & 1, 1);
+		assert!(!fds.irq());
+	}
+
+	#[test]
+	fn test_read_disk() {
+		let mut fds = initialize();
+		fds.cpu_write(0x4023, 1, false);
+		assert_eq!(fds.cpu_read(0x4032, false).unwrap() & 0b11, 0b10); // Inserted, not ready
+
+		// Motor on, read mode, disk ready, transfer IRQ
+		fds.cpu_write(0x4025, 0b1100_0101, false);
+		let mut bytes = Vec::new();
+		for _ in 0..1_000_000 {
+			fds.cpu_tick();
+			if fds.irq() {
+				bytes.push(fds.cpu_read(0x4031, false).unwrap());
+				if bytes.len() == 14 {
+					break;
+				}
+			}
+		}
+		// The gap end byte doesn't raise IRQ, the first byte is the block type
+		assert_eq!(bytes[0], 1);
+		assert_eq!(&bytes[1..14], b"*NINTENDO-HVC");
+		assert_eq!(fds.cpu_read(0x4032, false).unwrap() & 0b11, 0);
+
+		// Eject
+		fds.insert_disk(None);
+		assert_eq!(fds.cpu_read(0x4032, false).unwrap() & 0b111, 0b111);
+	}
+
+	#[test]
+	fn test_audio() {
+		let mut fds = initialize();
+		fds.cpu_write(0x4023, 0b11, false);
+
+		// Square wave in the wave table
+		fds.cpu_write(0x4089, 0x80, false);
+		for i in 0..64 {
+			fds.cpu_write(0x4040 + i, if i < 32 { 63 } else { 0 }, false);
+		}
+		assert_eq!(fds.cpu_read(0x4040, false), Some(63));
+		fds.cpu_write(0x4089, 0x00, false);
+
+		// Full volume, frequency $800: the wave position moves every 32 cycles
+		fds.cpu_write(0x4080, 0x80 | 32, false);
+		fds.cpu_write(0x4082, 0x00, false);
+		fds.cpu_write(0x4083, 0x08, false);
+		assert!(fds.audio_output(ExpansionAudio::Fds) > 0);
+		let mut high = 0;
+		for _ in 0..64 * 32 {
+			fds.cpu_tick();
+			if fds.audio_output(ExpansionAudio::Fds) > 0 {
+				high += 1;
+			}
+		}
+		assert_eq!(high, 32 * 32);
+
+		// Halt
+		fds.cpu_write(0x4083, 0x80, false);
+		assert!(fds.audio_output(ExpansionAudio::Fds) > 0);
+		fds.cpu_write(0x4080, 0x80, false);
+		assert_eq!(fds.audio_output(ExpansionAudio::Fds), 0);
+	}
+
+	#[test]
+	fn test_serialize() {
+		let mut fds = initialize();
+		fds.cpu_write(0x6000, 0x42, false);
+		fds.cpu_write(0x4023, 1, false);
+		fds.cpu_write(0x4025, 0b1100_0101, false);
+		// Stop in the middle of the disk info block, after the block type and "*NIN"
+		let mut bytes_read = 0;
+		while bytes_read < 5 {
+			fds.cpu_tick();
+			if fds.irq() {
+				fds.cpu_read(0x4031, false);
+				bytes_read += 1;
+			}
+		}
+
+		let mut loaded = initialize();
+		copy_state(&mut fds, &mut loaded);
+		assert_eq!(loaded.cpu_read(0x6000, false), Some(0x42));
+		// The drive continues from the same position
+		let mut bytes = Vec::new();
+		while bytes.len() < 5 {
+			loaded.cpu_tick();
+			if loaded.irq() {
+				bytes.push(loaded.cpu_read(0x4031, false).unwrap());
+			}
+		}
+		assert_eq!(bytes, b"TENDO");
+	}
+}
@@ -0,0 +1,584 @@
+use log::warn;
+
+use crate::{apu::{apu::Level, expansion::ExpansionAudio}, rom_parser::MirrorType, savestate::{Serialize, Serializer}};
+use super::{ciram_index, CpuMapping, Mapper, PpuFetch, SharedRom};
+
+/// Mapper 69 (Sunsoft FME-7, 5A and 5B): Batman: Return of the Joker, Gimmick!, Hebereke.
+/// Read here: https://www.nesdev.org/wiki/Sunsoft_FME-7
+///
+/// The registers are written through a command ($8000-$9FFF) and a parameter ($A000-$BFFF):
+/// - PRG: 8KB banks at $6000 (ROM or RAM), $8000, $A000 and $C000, the last 8KB bank fixed at $E000
+/// - CHR: 8 1KB banks
+/// - IRQ counter, clocked by CPU cycles
+/// - Expansion audio (5B, Gimmick!): 3 square channels with noise and an envelope, like the AY-3-8910. The audio
+///   registers are written through $C000 (register) and $E000 (value).
+#[derive(Clone)]
+pub struct FME7 {
+	prg_rom: SharedRom,
+	prg_ram: Vec<u8>,
+	chr: SharedRom,
+	chr_ram: bool,
+
+	command: u8,			// $8000
+	chr_banks: [u8; 8],		// Commands 0-7
+	prg_banks: [u8; 4],		// Commands 8-B: $6000 (bit 6: RAM, bit 7: RAM enabled), $8000, $A000, $C000
+	mirroring: u8,			// Command C
+
+	irq_enabled: bool,			// Command D bit 0
+	irq_counter_enabled: bool,	// Command D bit 7
+	irq_counter: u16,			// Commands E-F
+	irq_pending: bool,
+
+	audio: Sunsoft5B,
+}
+
+impl FME7 {
+	pub fn new(prg_rom: Vec<u8>, chr: Vec<u8>, prg_ram: Vec<u8>) -> Self {
+		let chr_ram = chr.is_empty();
+		FME7 {
+			prg_rom: SharedRom::new(prg_rom),
+			prg_ram,
+			chr: SharedRom::new(if chr_ram { vec![0; 1024 * 8] } else { chr }),
+			chr_ram,
+			command: 0,
+			chr_banks: [0; 8],
+			prg_banks: [0; 4],
+			mirroring: 0,
+			irq_enabled: false,
+			irq_counter_enabled: false,
+			irq_counter: 0,
+			irq_pending: false,
+			audio: Sunsoft5B::new(),
+		}
+	}
+
+	fn prg_offset(&self, addr: u16) -> usize {
+		let offset = match addr {
+			0xE000..=0xFFFF => self.prg_rom.len() - 0x2000 + (addr & 0x1FFF) as usize,
+			_ => (self.prg_banks[(addr as usize - 0x6000) / 0x2000] & 0x3F) as usize * 0x2000 + (addr & 0x1FFF) as usize,
+		};
+		offset % self.prg_rom.len()
+	}
+
+	/// What is at $6000-$7FFF: RAM (when enabled) or a ROM bank.
+	fn prg_ram_selected(&self) -> bool {
+		self.prg_banks[0] & 0x40 != 0
+	}
+
+	fn prg_ram_enabled(&self) -> bool {
+		self.prg_banks[0] & 0x80 != 0 && !self.prg_ram.is_empty()
+	}
+
+	fn chr_offset(&self, addr: u16) -> usize {
+		let slot = (addr as usize & 0x1FFF) / 0x400;
+		(self.chr_banks[slot] as usize * 0x400 + (addr & 0x3FF) as usize) % self.chr.len()
+	}
+
+	fn ciram_index(&self, addr: u16) -> usize {
+		match self.mirroring & 0b11 {
+			0 => ciram_index(addr, &MirrorType::VERTICAL),
+			1 => ciram_index(addr, &MirrorType::HORIZONTAL),
+			// One screen
+			page => (page as usize - 2) * 0x400 + (addr & 0x3FF) as usize,
+		}
+	}
+
+	fn write_parameter(&mut self, value: u8) {
+		match self.command {
+			0..=7 => self.chr_banks[self.command as usize] = value,
+			8..=0xB => self.prg_banks[self.command as usize - 8] = value,
+			0xC => self.mirroring = value,
+			0xD => {
+				self.irq_enabled = value & 0x01 != 0;
+				self.irq_counter_enabled = value & 0x80 != 0;
+				self.irq_pending = false;
+			}
+			0xE => self.irq_counter = (self.irq_counter & 0xFF00) | value as u16,
+			_ => self.irq_counter = (self.irq_counter & 0x00FF) | ((value as u16) << 8),
+		}
+	}
+}
+
+impl Mapper for FME7 {
+	fn cpu_read(&mut self, addr: u16, _peek: bool) -> Option<u8> {
+		match addr {
+			0x6000..=0x7FFF if !self.prg_ram_selected() => Some(self.prg_rom[self.prg_offset(addr)]),
+			0x6000..=0x7FFF if self.prg_ram_enabled() => Some(self.prg_ram[(addr - 0x6000) as usize % self.prg_ram.len()]),
+			0x8000..=0xFFFF => Some(self.prg_rom[self.prg_offset(addr)]),
+			_ => None,
+		}
+	}
+
+	fn cpu_mapping(&self, addr: u16) -> CpuMapping {
+		match addr {
+			0x6000..=0x7FFF if !self.prg_ram_selected() => CpuMapping::PrgRom { offset: self.prg_offset(addr), bank_size: 0x2000 },
+			0x6000..=0x7FFF if self.prg_ram_enabled() => CpuMapping::PrgRam { offset: (addr - 0x6000) as usize % self.prg_ram.len() },
+			0x8000..=0xFFFF => CpuMapping::PrgRom { offset: self.prg_offset(addr), bank_size: 0x2000 },
+			_ => CpuMapping::OpenBus,
+		}
+	}
+
+	fn cpu_write(&mut self, addr: u16, value: u8, poke: bool) {
+		if poke && addr >= 0x8000 {
+			let offset = self.prg_offset(addr);
+			self.prg_rom.make_mut()[offset] = value;
+			return;
+		}
+		match addr {
+			0x6000..=0x7FFF => {
+				if self.prg_ram_selected() && (self.prg_ram_enabled() || poke) && !self.prg_ram.is_empty() {
+					let len = self.prg_ram.len();
+					self.prg_ram[(addr - 0x6000) as usize % len] = value;
+				}
+			}
+			0x8000..=0x9FFF => self.command = value & 0x0F,
+			0xA000..=0xBFFF => self.write_parameter(value),
+			0xC000..=0xDFFF => self.audio.register = value,
+			0xE000..=0xFFFF => self.audio.write(value),
+			_ => {}
+		}
+	}
+
+	fn ppu_read(&mut self, addr: u16, _fetch: PpuFetch, ciram: &[u8]) -> u8 {
+		match addr {
+			0x0000..=0x1FFF => self.chr[self.chr_offset(addr)],
+			_ => ciram[self.ciram_index(addr)],
+		}
+	}
+
+	fn ppu_write(&mut self, addr: u16, value: u8, ciram: &mut [u8]) {
+		match addr {
+			0x0000..=0x1FFF => {
+				if self.chr_ram {
+					let offset = self.chr_offset(addr);
+					self.chr.make_mut()[offset] = value;
+				} else {
+					warn!("Write to CHR ROM ignored: [{:#X}] = {:#X}", addr, value);
+				}
+			}
+			_ => ciram[self.ciram_index(addr)] = value,
+		}
+	}
+
+	fn cpu_tick(&mut self) {
+		if self.irq_counter_enabled {
+			// The IRQ fires when the counter wraps from 0 to $FFFF
+			self.irq_counter = self.irq_counter.wrapping_sub(1);
+			if self.irq_counter == 0xFFFF && self.irq_enabled {
+				self.irq_pending = true;
+			}
+		}
+		self.audio.clock();
+	}
+
+	fn audio_sources(&self) -> &'static [ExpansionAudio] {
+		&[ExpansionAudio::Sunsoft5B]
+	}
+
+	fn audio_output(&self, _source: ExpansionAudio) -> Level {
+		self.audio.output()
+	}
+
+	fn irq(&self) -> bool {
+		self.irq_pending
+	}
+
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.prg_ram);
+		if self.chr_ram {
+			s.value(&mut self.chr);
+		}
+		s.value(&mut self.command);
+		s.value(&mut self.chr_banks);
+		s.value(&mut self.prg_banks);
+		s.value(&mut self.mirroring);
+		s.value(&mut self.irq_enabled);
+		s.value(&mut self.irq_counter_enabled);
+		s.value(&mut self.irq_counter);
+		s.value(&mut self.irq_pending);
+		s.value(&mut self.audio);
+	}
+
+	fn fork(&self) -> Box<dyn Mapper> {
+		Box::new(self.clone())
+	}
+}
+
+/// The volume of the 32 levels of the envelope (the 16 levels of the channel volumes are every other level): 1.5 dB per
+/// level, level 0 is silent. A channel at full volume is as loud as an APU pulse channel at full volume (15
+/// `PULSE_STEP`s).
+const LEVEL_VOLUMES: [Level; 32] = [
+	0, 42, 49, 59, 70, 83, 99, 117, 139, 166, 197, 234, 278, 330, 393, 467,
+	555, 659, 783, 931, 1106, 1315, 1563, 1858, 2208, 2624, 3118, 3706, 4405, 5235, 6222, 7395,
+];
+
+/// The 5B audio of the FME-7 (a YM2149F, the Yamaha version of the AY-3-8910). Read here:
+/// https://www.nesdev.org/wiki/Sunsoft_5B_audio
+#[derive(Default, Clone, Copy)]
+struct Sunsoft5B {
+	register: u8,		// $C000, the $E000 writes are ignored when the high 4 bits aren't 0
+	tones: [Tone; 3],
+	noise_period: u8,	// Register 6, 5 bits
+	noise_counter: u8,
+	noise: u32,			// 17 bit LFSR
+	mixer: u8,			// Register 7: bits 0-2 disable the tones, bits 3-5 the noise, of channels A-C
+	volumes: [u8; 3],	// Registers 8-A: bits 0-3 volume, bit 4 use the envelope
+	envelope: Envelope,
+	prescaler: u8,		// The tones and the noise are clocked every 16 CPU cycles, the envelope every 8
+}
+
+#[derive(Default, Clone, Copy)]
+struct Tone {
+	period: u16,	// 12 bits
+	counter: u16,
+	high: bool,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Envelope {
+	period: u16,		// Registers B-C
+	counter: u16,
+	shape: u8,			// Register D: bit 0 hold, bit 1 alternate, bit 2 attack, bit 3 continue
+	step: u8,			// 0-31
+	attack: bool,		// Counting up
+	holding: bool,
+}
+
+impl Sunsoft5B {
+	fn new() -> Self {
+		Sunsoft5B { noise: 1, ..Default::default() }
+	}
+
+	fn write(&mut self, value: u8) {
+		match self.register {
+			0..=5 => {
+				let tone = &mut self.tones[self.register as usize / 2];
+				tone.period = if self.register & 1 == 0 {
+					(tone.period & 0xF00) | value as u16
+				} else {
+					(tone.period & 0x0FF) | ((value as u16 & 0x0F) << 8)
+				};
+			}
+			6 => self.noise_period = value & 0x1F,
+			7 => self.mixer = value,
+			8..=0xA => self.volumes[self.register as usize - 8] = value & 0x1F,
+			0xB => self.envelope.period = (self.envelope.period & 0xFF00) | value as u16,
+			0xC => self.envelope.period = (self.envelope.period & 0x00FF) | ((value as u16) << 8),
+			0xD => self.envelope.restart(value & 0x0F),
+			0xE..=0xF => {}		// I/O ports, not connected
+			_ => warn!("5B audio write ignored, the register select ${:02X} has high bits", self.register),
+		}
+	}
+
+	fn clock(&mut self) {
+		self.prescaler = (self.prescaler + 1) % 16;
+		if self.prescaler.is_multiple_of(8) {
+			self.envelope.clock();
+		}
+		if self.prescaler != 0 {
+			return;
+		}
+		for tone in self.tones.iter_mut() {
+			tone.counter += 1;
+			if tone.counter >= tone.period {
+				tone.counter = 0;
+				tone.high = !tone.high;
+			}
+		}
+		self.noise_counter += 1;
+		if self.noise_counter >= self.noise_period * 2 {
+			self.noise_counter = 0;
+			// Taps 0 and 3
+			let feedback = (self.noise ^ (self.noise >> 3)) & 1;
+			self.noise = (self.noise >> 1) | (feedback << 16);
+		}
+	}
+
+	fn output(&self) -> Level {
+		let noise = self.noise & 1 != 0;
+		(0..3).map(|channel| {
+			let tone_on = self.tones[channel].high || self.mixer & (1 << channel) != 0;
+			let noise_on = noise || self.mixer & (8 << channel) != 0;
+			if !(tone_on && noise_on) {
+				return 0;
+			}
+			let volume = self.volumes[channel];
+			let level = if volume & 0x10 != 0 {
+				self.envelope.level()
+			} else if volume == 0 {
+				0
+			} else {
+				volume * 2 + 1
+			};
+			LEVEL_VOLUMES[level as usize]
+		}).sum()
+	}
+}
+
+impl Envelope {
+	fn restart(&mut self, shape: u8) {
+		self.shape = shape;
+		self.attack = shape & 0b100 != 0;
+		self.step = 0;
+		self.counter = 0;
+		self.holding = false;
+	}
+
+	fn clock(&mut self) {
+		if self.holding {
+			return;
+		}
+		self.counter += 1;
+		if self.counter < self.period {
+			return;
+		}
+		self.counter = 0;
+		if self.step < 31 {
+			self.step += 1;
+			return;
+		}
+		// The end of a ramp
+		let (continues, attack, alternate, hold) = (self.shape & 0b1000 != 0, self.shape & 0b100 != 0, self.shape & 0b10 != 0, self.shape & 1 != 0);
+		if !continues {
+			// Down to 0 and stay
+			self.attack = false;
+			self.holding = true;
+		} else if hold {
+			self.attack = attack != alternate;
+			self.holding = true;
+		} else {
+			if alternate {
+				self.attack = !self.attack;
+			}
+			self.step = 0;
+		}
+	}
+
+	/// 0-31
+	fn level(&self) -> u8 {
+		match (self.holding, self.attack) {
+			(true, attack) => if attack { 31 } else { 0 },
+			(false, true) => self.step,
+			(false, false) => 31 - self.step,
+		}
+	}
+}
+
+impl Serialize for Sunsoft5B {
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.register);
+		s.value(&mut self.tones);
+		s.value(&mut self.noise_period);
+		s.value(&mut self.noise_counter);
+		s.value(&mut self.noise);
+		s.value(&mut self.mixer);
+		s.value(&mut self.volumes);
+		s.value(&mut self.envelope);
+		s.value(&mut self.prescaler);
+	}
+}
+
+impl Serialize for Tone {
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.period);
+		s.value(&mut self.counter);
+		s.value(&mut self.high);
+	}
+}
+
+impl Serialize for Envelope {
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.period);
+		s.value(&mut self.counter);
+		s.value(&mut self.shape);
+		s.value(&mut self.step);
+		s.value(&mut self.attack);
+		s.value(&mut self.holding);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{FME7, LEVEL_VOLUMES};
+	use crate::apu::apu::PULSE_STEP;
+	use crate::{apu::expansion::ExpansionAudio, mapper::{copy_state, harness::MapperHarness, Mapper, PpuFetch}};
+
+	/// 256KB PRG ROM where each byte is its 8KB bank number, 256KB CHR ROM where each byte is its 1KB bank number.
+	fn initialize() -> FME7 {
+		let prg_rom = (0..256 * 1024).map(|i| (i / 0x2000) as u8).collect();
+		let chr = (0..256 * 1024).map(|i| (i / 0x400) as u8).collect();
+		FME7::new(prg_rom, chr, vec![0; 1024 * 8])
+	}
+
+	fn command(fme7: &mut FME7, command: u8, value: u8) {
+		fme7.cpu_write(0x8000, command, false);
+		fme7.cpu_write(0xA000, value, false);
+	}
+
+	fn audio(fme7: &mut FME7, register: u8, value: u8) {
+		fme7.cpu_write(0xC000, register, false);
+		fme7.cpu_write(0xE000, value, false);
+	}
+
+	#[test]
+	fn test_banking() {
+		let mut fme7 = initialize();
+		for (i, bank) in [9, 10, 11].into_iter().enumerate() {
+			command(&mut fme7, 9 + i as u8, bank);
+		}
+		let banks = [0x8000, 0xA000, 0xC000, 0xE000].map(|addr| fme7.cpu_read(addr, false).unwrap());
+		assert_eq!(banks, [9, 10, 11, 31]);
+
+		let ciram = [0; 2048];
+		for i in 0..8 {
+			command(&mut fme7, i, 100 + i);
+		}
+		assert_eq!(fme7.ppu_read(0x0000, PpuFetch::Background, &ciram), 100);
+		assert_eq!(fme7.ppu_read(0x1C00, PpuFetch::Sprite, &ciram), 107);
+
+		// $6000: a ROM bank, RAM (disabled is open bus), enabled RAM
+		command(&mut fme7, 8, 5);
+		assert_eq!(fme7.cpu_read(0x6000, false), Some(5));
+		command(&mut fme7, 8, 0x40);
+		fme7.cpu_write(0x6000, 0x42, false);
+		assert_eq!(fme7.cpu_read(0x6000, false), None);
+		command(&mut fme7, 8, 0xC0);
+		fme7.cpu_write(0x6000, 0x42, false);
+		assert_eq!(fme7.cpu_read(0x6000, false), Some(0x42));
+
+		// One screen, the second page
+		let mut ciram = [0; 2048];
+		command(&mut fme7, 0xC, 3);
+		fme7.ppu_write(0x2005, 2, &mut ciram);
+		assert_eq!(ciram[0x405], 2);
+	}
+
+	#[test]
+	fn test_bank_switch_program() {
+		// Command 9 ($8000): 8KB bank 5 (1KB banks 40-47), command 8 ($6000): ROM bank 2, command 3: CHR bank 77 at $0C00
+		let mut harness = MapperHarness::new(69, 256, 256);
+		harness.run(&[(0x8000, 9), (0xA000, 5), (0x8000, 8), (0xA000, 2), (0x8000, 3), (0xA000, 77)]);
+		assert_eq!([0x6000, 0x8000, 0x9C00, 0xE000].map(|addr| harness.prg_bank(addr)), [16, 40, 47, 248]);
+		assert_eq!([0x0800, 0x0C00].map(|addr| harness.chr_bank(addr)), [0, 77]);
+	}
+
+	#[test]
+	fn test_irq() {
+		let mut fme7 = initialize();
+		command(&mut fme7, 0xE, 2);
+		command(&mut fme7, 0xF, 0);
+		command(&mut fme7, 0xD, 0x81);
+		// 1, 0, then the IRQ when it wraps to $FFFF
+		for _ in 0..2 {
+			fme7.cpu_tick();
+		}
+		assert!(!fme7.irq());
+		fme7.cpu_tick();
+		assert!(fme7.irq());
+		assert_eq!(fme7.irq_counter, 0xFFFF);
+
+		// Writing the control acknowledges. Counting without the IRQ enabled, the counter still wraps.
+		command(&mut fme7, 0xD, 0x80);
+		assert!(!fme7.irq());
+		for _ in 0..0x10000 {
+			fme7.cpu_tick();
+		}
+		assert!(!fme7.irq());
+		assert_eq!(fme7.irq_counter, 0xFFFF);
+
+		// The counter stops
+		command(&mut fme7, 0xD, 0x01);
+		fme7.cpu_tick();
+		assert_eq!(fme7.irq_counter, 0xFFFF);
+	}
+
+	#[test]
+	fn test_audio() {
+		let mut fme7 = initialize();
+		// All the tones and noise are enabled after power on, but the volumes are 0
+		assert_eq!(fme7.audio_output(ExpansionAudio::Sunsoft5B), 0);
+
+		// Channel A: tone only, volume 15, period 2: toggles every 32 CPU cycles
+		audio(&mut fme7, 7, 0b111_110);
+		audio(&mut fme7, 0, 2);
+		audio(&mut fme7, 8, 15);
+		let mut outputs = vec![];
+		for _ in 0..128 {
+			fme7.cpu_tick();
+			outputs.push(fme7.audio_output(ExpansionAudio::Sunsoft5B));
+		}
+		assert_eq!(outputs.iter().filter(|&&output| output > 0).count(), 64);
+		assert!(outputs.iter().all(|&output| output == 0 || output == LEVEL_VOLUMES[31]));
+		assert_eq!(LEVEL_VOLUMES[31], 15 * PULSE_STEP);
+		// Volume 14 is 3 dB lower
+		assert!((707..=709).contains(&(LEVEL_VOLUMES[29] * 1000 / LEVEL_VOLUMES[31])));
+
+		// A register select with high bits ignores the writes
+		fme7.cpu_write(0xC000, 0x18, false);
+		fme7.cpu_write(0xE000, 0, false);
+		assert_eq!(fme7.audio.volumes[0], 15);
+
+		// Envelope: decay once (shape 0) with period 1, then silent
+		audio(&mut fme7, 7, 0b111_111);	// Tone and noise disabled: the output is the volume
+		audio(&mut fme7, 8, 0x10);
+		audio(&mut fme7, 0xB, 1);
+		audio(&mut fme7, 0xD, 0);
+		let mut levels = vec![];
+		for _ in 0..8 * 40 {
+			fme7.cpu_tick();
+			levels.push(fme7.audio.envelope.level());
+		}
+		levels.dedup();
+		assert_eq!(levels, (0..32).rev().collect::<Vec<u8>>());
+
+		// Shape 1011 (continue, alternate, hold): decay, then up and hold
+		audio(&mut fme7, 0xD, 0b1011);
+		for _ in 0..8 * 40 {
+			fme7.cpu_tick();
+		}
+		assert_eq!(fme7.audio.envelope.level(), 31);
+		// Shape 1110 (continue, attack, alternate): up and down
+		audio(&mut fme7, 0xD, 0b1110);
+		for _ in 0..8 * 32 {
+			fme7.cpu_tick();
+		}
+		assert_eq!(fme7.audio.envelope.level(), 31);
+		for _ in 0..8 * 8 {
+			fme7.cpu_tick();
+		}
+		assert_eq!(fme7.audio.envelope.level(), 23);
+	}
+
+	#[test]
+	fn test_serialize() {
+		let mut fme7 = initialize();
+		command(&mut fme7, 9, 3);
+		command(&mut fme7, 1, 9);
+		command(&mut fme7, 8, 0xC0);
+		fme7.cpu_write(0x6000, 0x42, false);
+		command(&mut fme7, 0xE, 0x40);
+		command(&mut fme7, 0xD, 0x81);
+		audio(&mut fme7, 7, 0b110_110);
+		audio(&mut fme7, 0, 3);
+		audio(&mut fme7, 6, 1);
+		audio(&mut fme7, 8, 0x1F);
+		audio(&mut fme7, 0xB, 2);
+		audio(&mut fme7, 0xD, 0b1110);
+		for _ in 0..7 {
+			fme7.cpu_tick();
+		}
+
+		let mut loaded = initialize();
+		copy_state(&mut fme7, &mut loaded);
+		assert_eq!(loaded.cpu_read(0x8000, false), Some(3));
+		assert_eq!(loaded.cpu_read(0x6000, false), Some(0x42));
+		assert_eq!(loaded.ppu_read(0x0400, PpuFetch::Data, &[0; 2048]), 9);
+		// The IRQ counter, the tones, the noise and the envelope continue where they were
+		for _ in 0..1000 {
+			fme7.cpu_tick();
+			loaded.cpu_tick();
+			assert_eq!(loaded.irq(), fme7.irq());
+			assert_eq!(loaded.audio_output(ExpansionAudio::Sunsoft5B), fme7.audio_output(ExpansionAudio::Sunsoft5B));
+		}
+	}
+}
@@ -0,0 +1,85 @@
+//! Test harness for mappers: the CPU runs tiny programs that write the mapper registers, then the tests check which
+//! PRG and CHR banks became visible where. Every 1KB of the test ROMs is filled with its bank number, so no real game
+//! is needed.
+
+use crate::{nes::NES, rom_parser::MirrorType};
+use super::PpuFetch;
+
+/// RAM address of the loop the CPU spins in between programs: `JMP $0300`. A program runs when the loop jumps to it.
+const SPIN: u16 = 0x0300;
+const PROGRAM: u16 = 0x0310;
+
+/// ROM of `kb` KB where each byte is the number of its 1KB bank (modulo 256).
+pub fn numbered_rom(kb: usize) -> Vec<u8> {
+	(0..kb * 1024).map(|i| (i / 0x400) as u8).collect()
+}
+
+pub struct MapperHarness {
+	pub nes: NES,
+}
+
+impl MapperHarness {
+	/// A NES with the mapper, numbered PRG ROM and CHR ROM (0 KB is CHR RAM). The last 8KB of PRG must be at $E000 at
+	/// power on (it is for the mappers with a fixed last bank): the reset code jumps from there to the spin loop in RAM.
+	pub fn new(mapper: u8, prg_kb: usize, chr_kb: usize) -> Self {
+		let mut prg = numbered_rom(prg_kb);
+		let last_bank = prg.len() - 0x2000;
+		// $E000: JMP $0300, $E003: RTI. The vectors: NMI and IRQ at $E003, reset at $E000.
+		prg[last_bank..last_bank + 4].copy_from_slice(&[0x4C, SPIN as u8, (SPIN >> 8) as u8, 0x40]);
+		let len = prg.len();
+		prg[len - 6..].copy_from_slice(&[0x03, 0xE0, 0x00, 0xE0, 0x03, 0xE0]);
+
+		let mut nes = NES::new_from_prg_chr(&prg, &numbered_rom(chr_kb), mapper, MirrorType::VERTICAL);
+		for (i, byte) in [0x4C, SPIN as u8, (SPIN >> 8) as u8].into_iter().enumerate() {
+			nes.poke(SPIN + i as u16, byte);
+		}
+		MapperHarness { nes }
+	}
+
+	/// Assemble the register writes (`LDA #value`, `STA addr` for each) and run them in order. The program ends by
+	/// pointing the spin loop back to itself.
+	pub fn run(&mut self, writes: &[(u16, u8)]) {
+		let mut program = vec![];
+		for &(addr, value) in writes.iter().chain(&[(SPIN + 1, SPIN as u8)]) {
+			program.extend([0xA9, value, 0x8D, addr as u8, (addr >> 8) as u8]);
+		}
+		program.extend([0x4C, SPIN as u8, (SPIN >> 8) as u8]);
+		assert!(PROGRAM as usize + program.len() <= 0x0800, "Too many writes for the program RAM");
+		for (i, &byte) in program.iter().enumerate() {
+			self.nes.poke(PROGRAM + i as u16, byte);
+		}
+		self.nes.poke(SPIN + 1, PROGRAM as u8);
+		// Until the last JMP
+		assert!(self.nes.run_until_pc(PROGRAM + program.len() as u16 - 3), "The bank switch program didn't finish");
+	}
+
+	/// The 1KB PRG bank the CPU sees at the address (the numbers are read away from the reset code and the vectors).
+	pub fn prg_bank(&mut self, addr: u16) -> u8 {
+		self.nes.peek((addr & !0x3FF) + 0x200)
+	}
+
+	/// The 1KB CHR bank the PPU sees at the address.
+	pub fn chr_bank(&mut self, addr: u16) -> u8 {
+		self.nes.cpu.cartridge_mut().ppu_read(addr, PpuFetch::Data, &[0; 2048])
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::MapperHarness;
+
+	#[test]
+	fn test_harness() {
+		// NROM-128: the 16KB is mirrored at $C000, 8KB CHR
+		let mut harness = MapperHarness::new(0, 16, 8);
+		harness.run(&[]);
+		assert_eq!([0x8000, 0xBC00, 0xC000, 0xE000].map(|addr| harness.prg_bank(addr)), [0, 15, 0, 8]);
+		assert_eq!([0x0000, 0x1C00].map(|addr| harness.chr_bank(addr)), [0, 7]);
+
+		// The writes happen in order, through the CPU
+		harness.run(&[(0x0200, 1), (0x0201, 2), (0x0200, 3)]);
+		assert_eq!((harness.nes.peek(0x0200), harness.nes.peek(0x0201)), (3, 2));
+		harness.run(&[(0x0200, 4)]);
+		assert_eq!(harness.nes.peek(0x0200), 4);
+	}
+}
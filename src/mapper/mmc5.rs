@@ -0,0 +1,699 @@
+use log::warn;
+
+use crate::savestate::Serializer;
+use super::{CpuMapping, Mapper, PpuFetch, SharedRom};
+
+/// Mapper 5 (MMC5): Castlevania III, Just Breed, Uncharted Waters...
+/// Read here: https://www.nesdev.org/wiki/MMC5
+///
+/// - PRG banking: 32KB, 16KB, 16KB+8KB+8KB or 8KB banks, RAM or ROM, and PRG RAM banking at $6000-$7FFF
+/// - CHR banking: 8KB, 4KB, 2KB or 1KB banks. In 8x16 sprite mode, the sprites and background have separate banks.
+/// - 1KB of ExRAM: extra nametable, extended attributes (each tile chooses its own palette and 4KB CHR bank), or CPU RAM
+/// - Nametable mapping of each nametable to CIRAM, ExRAM or fill mode (a single tile and attribute)
+/// - Vertical split screen, from ExRAM with its own vertical scroll and CHR bank
+/// - Scanline counter IRQ
+/// - 8x8 -> 16 bit multiplier
+///
+/// The real MMC5 finds out what the PPU is doing by watching the PPU bus. Here the PPU tells us (`PpuFetch`, `ppu_tick`).
+/// The audio is not implemented.
+#[derive(Clone)]
+pub struct MMC5 {
+	prg_rom: SharedRom,
+	prg_ram: Vec<u8>,
+	chr: SharedRom,
+	chr_ram: bool,
+	exram: [u8; 1024],
+
+	prg_mode: u8,				// $5100
+	chr_mode: u8,				// $5101
+	prg_ram_protect: [u8; 2],	// $5102, $5103
+	exram_mode: u8,				// $5104
+	nametable_mapping: u8,		// $5105
+	fill_tile: u8,				// $5106
+	fill_attribute: u8,			// $5107
+	prg_banks: [u8; 5],			// $5113-$5117
+	chr_banks_a: [u16; 8],		// $5120-$5127, sprites in 8x16 mode
+	chr_banks_b: [u16; 4],		// $5128-$512B, background in 8x16 mode
+	chr_set_b_last: bool,		// In 8x8 mode, the last written set is used for everything
+	chr_upper: u8,				// $5130
+	split_control: u8,			// $5200
+	split_scroll: u8,			// $5201
+	split_bank: u8,				// $5202
+	irq_compare: u8,			// $5203
+	irq_enabled: bool,			// $5204
+	irq_pending: bool,
+	multiplicand: u8,			// $5205
+	multiplier: u8,				// $5206
+
+	// PPU state
+	sprites_8x16: bool,
+	in_frame: bool,
+	scanline_counter: u8,
+	tile_counter: u8,			// Background tile being fetched on this scanline (0-33, tiles 0 and 1 are prefetched on the previous scanline)
+	split_y: u8,				// Vertical scroll of the split for the scanline being fetched
+	split_tile: bool,			// The background tile being fetched is inside the split
+	split_column: u8,
+	last_nametable_offset: usize,	// ExRAM index of the background tile being fetched, for the extended attributes
+}
+
+impl MMC5 {
+	pub fn new(prg_rom: Vec<u8>, chr: Vec<u8>, prg_ram: Vec<u8>) -> Self {
+		let chr_ram = chr.is_empty();
+		MMC5 {
+			prg_rom: SharedRom::new(prg_rom),
+			prg_ram,
+			chr: SharedRom::new(if chr_ram { vec![0; 1024 * 8] } else { chr }),
+			chr_ram,
+			exram: [0; 1024],
+			// At power on, the last bank is at $E000 in any mode
+			prg_mode: 3,
+			chr_mode: 0,
+			prg_ram_protect: [0; 2],
+			exram_mode: 0,
+			nametable_mapping: 0,
+			fill_tile: 0,
+			fill_attribute: 0,
+			prg_banks: [0, 0, 0, 0, 0xFF],
+			chr_banks_a: [0; 8],
+			chr_banks_b: [0; 4],
+			chr_set_b_last: false,
+			chr_upper: 0,
+			split_control: 0,
+			split_scroll: 0,
+			split_bank: 0,
+			irq_compare: 0,
+			irq_enabled: false,
+			irq_pending: false,
+			multiplicand: 0xFF,
+			multiplier: 0xFF,
+			sprites_8x16: false,
+			in_frame: false,
+			scanline_counter: 0,
+			tile_counter: 0,
+			split_y: 0,
+			split_tile: false,
+			split_column: 0,
+			last_nametable_offset: 0,
+		}
+	}
+
+	/// Which register ($5113 + index) maps CPU $8000-$FFFF, and the bank size.
+	fn prg_window(&self, addr: u16) -> (usize, usize) {
+		match (self.prg_mode, addr) {
+			(0, _) => (4, 0x8000),
+			(1, 0x8000..=0xBFFF) => (2, 0x4000),
+			(1, _) => (4, 0x4000),
+			(2, 0x8000..=0xBFFF) => (2, 0x4000),
+			(2, 0xC000..=0xDFFF) => (3, 0x2000),
+			(2, _) => (4, 0x2000),
+			_ => (1 + ((addr - 0x8000) / 0x2000) as usize, 0x2000),
+		}
+	}
+
+	/// Map CPU $6000-$FFFF. Returns (is ROM, offset in ROM/RAM).
+	fn map_prg(&self, addr: u16) -> (bool, usize) {
+		if addr < 0x8000 {
+			let bank = (self.prg_banks[0] & 0x07) as usize;
+			return (false, (bank * 0x2000 + (addr & 0x1FFF) as usize) % self.prg_ram.len().max(1));
+		}
+
+		let (index, size) = self.prg_window(addr);
+		let value = self.prg_banks[index];
+		// Bit 7: ROM (1) or RAM (0). $5117 is always ROM.
+		let rom = index == 4 || value & 0x80 != 0;
+		// The bank number is in 8KB units, bigger banks ignore the low bits
+		let base = ((value & 0x7F) as usize * 0x2000) & !(size - 1);
+		let offset = base + (addr as usize & (size - 1));
+		if rom {
+			(true, offset % self.prg_rom.len())
+		} else {
+			(false, offset % self.prg_ram.len().max(1))
+		}
+	}
+
+	fn prg_ram_writable(&self) -> bool {
+		self.prg_ram_protect == [0b10, 0b01]
+	}
+
+	/// Map PPU $0000-$1FFF to CHR offset, with set A ($5120-$5127) or set B ($5128-$512B).
+	fn map_chr(&self, addr: u16, set_b: bool) -> usize {
+		let addr = addr as usize & 0x1FFF;
+		let (bank, size) = if !set_b {
+			let a = &self.chr_banks_a;
+			match self.chr_mode {
+				0 => (a[7], 0x2000),
+				1 => (a[3 + (addr / 0x1000) * 4], 0x1000),
+				2 => (a[1 + (addr / 0x800) * 2], 0x800),
+				_ => (a[addr / 0x400], 0x400),
+			}
+		} else {
+			// Set B is the same for both pattern tables
+			let b = &self.chr_banks_b;
+			let half = addr & 0x0FFF;
+			match self.chr_mode {
+				0 => (b[3], 0x2000),
+				1 => (b[3], 0x1000),
+				2 => (b[1 + (half / 0x800) * 2], 0x800),
+				_ => (b[half / 0x400], 0x400),
+			}
+		};
+		(bank as usize * size + (addr & (size - 1))) % self.chr.len()
+	}
+
+	fn chr_set_b(&self, fetch: PpuFetch) -> bool {
+		match fetch {
+			PpuFetch::Background if self.sprites_8x16 => true,
+			PpuFetch::Sprite if self.sprites_8x16 => false,
+			_ => self.chr_set_b_last,
+		}
+	}
+
+	fn split_enabled(&self) -> bool {
+		self.split_control & 0x80 != 0
+	}
+
+	/// Is the tile column inside the split region.
+	fn in_split(&self, column: u8) -> bool {
+		let threshold = self.split_control & 0x1F;
+		if self.split_control & 0x40 == 0 {
+			column < threshold
+		} else {
+			column >= threshold
+		}
+	}
+
+	/// Which of the 4 nametables, where: 0/1 is CIRAM page, 2 is ExRAM, 3 is fill mode.
+	fn nametable_source(&self, addr: u16) -> u8 {
+		let quadrant = (addr >> 10) & 0b11;
+		(self.nametable_mapping >> (quadrant * 2)) & 0b11
+	}
+
+	fn read_nametable(&self, addr: u16, ciram: &[u8]) -> u8 {
+		let offset = (addr & 0x3FF) as usize;
+		match self.nametable_source(addr) {
+			0 => ciram[offset],
+			1 => ciram[0x400 + offset],
+			2 => if self.exram_mode <= 1 { self.exram[offset] } else { 0 },
+			_ => {
+				if offset >= 0x3C0 {
+					// The same attribute for all 4 quadrants
+					self.fill_attribute * 0b0101_0101
+				} else {
+					self.fill_tile
+				}
+			}
+		}
+	}
+
+	fn write_nametable(&mut self, addr: u16, value: u8, ciram: &mut [u8]) {
+		let offset = (addr & 0x3FF) as usize;
+		match self.nametable_source(addr) {
+			0 => ciram[offset] = value,
+			1 => ciram[0x400 + offset] = value,
+			2 if self.exram_mode <= 1 => self.exram[offset] = value,
+			_ => {}
+		}
+	}
+
+	/// Background fetches go through here, because of the split and the extended attributes.
+	fn read_background(&mut self, addr: u16, ciram: &[u8]) -> u8 {
+		if addr < 0x2000 {
+			// Pattern
+			if self.split_tile {
+				let fine_y = (self.split_y & 0b111) as usize;
+				let offset = self.split_bank as usize * 0x1000 + (addr as usize & 0x0FF8) + fine_y;
+				return self.chr[offset % self.chr.len()];
+			}
+			if self.exram_mode == 1 {
+				let bank = (self.exram[self.last_nametable_offset] & 0x3F) as usize | ((self.chr_upper as usize) << 6);
+				let offset = bank * 0x1000 + (addr as usize & 0x0FFF);
+				return self.chr[offset % self.chr.len()];
+			}
+			return self.chr[self.map_chr(addr, self.chr_set_b(PpuFetch::Background))];
+		}
+
+		if addr & 0x3FF >= 0x3C0 {
+			// Attribute
+			if self.split_tile {
+				let row = (self.split_y / 8) as usize;
+				let column = self.split_column as usize;
+				let attribute = self.exram[0x3C0 + (row / 4) * 8 + column / 4];
+				let shift = ((row & 2) << 1) | (column & 2);
+				return ((attribute >> shift) & 0b11) * 0b0101_0101;
+			}
+			if self.exram_mode == 1 {
+				// Bits 6-7 of the tile's ExRAM byte, for all 4 quadrants
+				return (self.exram[self.last_nametable_offset] >> 6) * 0b0101_0101;
+			}
+			return self.read_nametable(addr, ciram);
+		}
+
+		// Nametable, which is the first fetch of each tile
+		let tile = self.tile_counter;
+		self.tile_counter = self.tile_counter.wrapping_add(1);
+		self.split_column = tile & 0x1F;
+		self.split_tile = self.split_enabled() && self.exram_mode <= 1 && self.in_split(self.split_column);
+		if self.split_tile {
+			let row = (self.split_y / 8) as usize;
+			self.last_nametable_offset = row * 32 + self.split_column as usize;
+			return self.exram[self.last_nametable_offset];
+		}
+		self.last_nametable_offset = (addr & 0x3FF) as usize;
+		self.read_nametable(addr, ciram)
+	}
+}
+
+impl Mapper for MMC5 {
+	fn cpu_read(&mut self, addr: u16, peek: bool) -> Option<u8> {
+		let value = match addr {
+			0x5204 => {
+				let status = ((self.irq_pending as u8) << 7) | ((self.in_frame as u8) << 6);
+				if !peek {
+					self.irq_pending = false;
+				}
+				status
+			}
+			0x5205 => (self.multiplicand as u16 * self.multiplier as u16) as u8,
+			0x5206 => ((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8,
+			0x5C00..=0x5FFF if self.exram_mode >= 2 => self.exram[(addr - 0x5C00) as usize],
+			0x6000..=0xFFFF => {
+				let (rom, offset) = self.map_prg(addr);
+				if rom {
+					self.prg_rom[offset]
+				} else if !self.prg_ram.is_empty() {
+					self.prg_ram[offset]
+				} else {
+					return None;
+				}
+			}
+			_ => return None,
+		};
+		Some(value)
+	}
+
+	fn cpu_mapping(&self, addr: u16) -> CpuMapping {
+		match addr {
+			0x5204 => CpuMapping::Register("MMC5 IRQ status"),
+			0x5205 | 0x5206 => CpuMapping::Register("MMC5 multiplier"),
+			0x5C00..=0x5FFF if self.exram_mode >= 2 => CpuMapping::Other(format!("ExRAM + ${:03X}", addr - 0x5C00)),
+			0x6000..=0xFFFF => match self.map_prg(addr) {
+				(true, offset) => CpuMapping::PrgRom { offset, bank_size: if addr < 0x8000 { 0x2000 } else { self.prg_window(addr).1 } },
+				(false, _) if self.prg_ram.is_empty() => CpuMapping::OpenBus,
+				(false, offset) => CpuMapping::PrgRam { offset },
+			},
+			_ => CpuMapping::OpenBus,
+		}
+	}
+
+	fn cpu_write(&mut self, addr: u16, value: u8, poke: bool) {
+		if poke && addr >= 0x6000 {
+			let (rom, offset) = self.map_prg(addr);
+			if rom { self.prg_rom.make_mut()[offset] = value } else if !self.prg_ram.is_empty() { self.prg_ram[offset] = value }
+			return;
+		}
+
+		match addr {
+			0x5100 => self.prg_mode = value & 0b11,
+			0x5101 => self.chr_mode = value & 0b11,
+			0x5102 => self.prg_ram_protect[0] = value & 0b11,
+			0x5103 => self.prg_ram_protect[1] = value & 0b11,
+			0x5104 => self.exram_mode = value & 0b11,
+			0x5105 => self.nametable_mapping = value,
+			0x5106 => self.fill_tile = value,
+			0x5107 => self.fill_attribute = value & 0b11,
+			0x5113..=0x5117 => self.prg_banks[(addr - 0x5113) as usize] = value,
+			0x5120..=0x5127 => {
+				self.chr_banks_a[(addr - 0x5120) as usize] = value as u16 | ((self.chr_upper as u16) << 8);
+				self.chr_set_b_last = false;
+			}
+			0x5128..=0x512B => {
+				self.chr_banks_b[(addr - 0x5128) as usize] = value as u16 | ((self.chr_upper as u16) << 8);
+				self.chr_set_b_last = true;
+			}
+			0x5130 => self.chr_upper = value & 0b11,
+			0x5200 => self.split_control = value,
+			0x5201 => self.split_scroll = value,
+			0x5202 => self.split_bank = value,
+			0x5203 => self.irq_compare = value,
+			0x5204 => self.irq_enabled = value & 0x80 != 0,
+			0x5205 => self.multiplicand = value,
+			0x5206 => self.multiplier = value,
+			0x5C00..=0x5FFF => {
+				// Mode 3 is read only
+				if self.exram_mode != 3 {
+					self.exram[(addr - 0x5C00) as usize] = value;
+				}
+			}
+			0x6000..=0xFFFF => {
+				let (rom, offset) = self.map_prg(addr);
+				if !rom && self.prg_ram_writable() && !self.prg_ram.is_empty() {
+					self.prg_ram[offset] = value;
+				}
+			}
+			_ => {}
+		}
+	}
+
+	fn ppu_read(&mut self, addr: u16, fetch: PpuFetch, ciram: &[u8]) -> u8 {
+		if fetch == PpuFetch::Background {
+			return self.read_background(addr, ciram);
+		}
+		match addr {
+			0x0000..=0x1FFF => self.chr[self.map_chr(addr, self.chr_set_b(fetch))],
+			_ => self.read_nametable(addr, ciram),
+		}
+	}
+
+	fn ppu_write(&mut self, addr: u16, value: u8, ciram: &mut [u8]) {
+		match addr {
+			0x0000..=0x1FFF => {
+				if self.chr_ram {
+					let offset = self.map_chr(addr, self.chr_set_b_last);
+					self.chr.make_mut()[offset] = value;
+				} else {
+					warn!("Write to CHR ROM ignored: [{:#X}] = {:#X}", addr, value);
+				}
+			}
+			_ => self.write_nametable(addr, value, ciram),
+		}
+	}
+
+	fn ppu_register_write(&mut self, reg: u16, value: u8) {
+		if reg == 0 {
+			self.sprites_8x16 = value & 0x20 != 0;
+		}
+	}
+
+	fn ppu_tick(&mut self, scanline: u16, dot: u16, rendering: bool) {
+		if !rendering || (240..261).contains(&scanline) {
+			self.in_frame = false;
+			return;
+		}
+
+		// Scanline counter, at the start of each visible scanline
+		if dot == 1 && scanline < 240 {
+			if !self.in_frame {
+				self.in_frame = true;
+				self.scanline_counter = 0;
+			} else {
+				self.scanline_counter = self.scanline_counter.wrapping_add(1);
+				if self.scanline_counter == self.irq_compare {
+					self.irq_pending = true;
+				}
+			}
+		}
+
+		// The background prefetch of the next scanline starts at dot 321
+		if dot == 321 {
+			self.tile_counter = 0;
+			if scanline == 261 {
+				self.split_y = if self.split_scroll >= 240 { self.split_scroll - 240 } else { self.split_scroll };
+			} else {
+				self.split_y = if self.split_y >= 239 { 0 } else { self.split_y + 1 };
+			}
+		}
+	}
+
+	fn irq(&self) -> bool {
+		self.irq_enabled && self.irq_pending
+	}
+
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.prg_ram);
+		if self.chr_ram {
+			s.value(&mut self.chr);
+		}
+		s.value(&mut self.exram);
+		s.value(&mut self.prg_mode);
+		s.value(&mut self.chr_mode);
+		s.value(&mut self.prg_ram_protect);
+		s.value(&mut self.exram_mode);
+		s.value(&mut self.nametable_mapping);
+		s.value(&mut self.fill_tile);
+		s.value(&mut self.fill_attribute);
+		s.value(&mut self.prg_banks);
+		s.value(&mut self.chr_banks_a);
+		s.value(&mut self.chr_banks_b);
+		s.value(&mut self.chr_set_b_last);
+		s.value(&mut self.chr_upper);
+		s.value(&mut self.split_control);
+		s.value(&mut self.split_scroll);
+		s.value(&mut self.split_bank);
+		s.value(&mut self.irq_compare);
+		s.value(&mut self.irq_enabled);
+		s.value(&mut self.irq_pending);
+		s.value(&mut self.multiplicand);
+		s.value(&mut self.multiplier);
+		s.value(&mut self.sprites_8x16);
+		s.value(&mut self.in_frame);
+		s.value(&mut self.scanline_counter);
+		s.value(&mut self.tile_counter);
+		s.value(&mut self.split_y);
+		s.value(&mut self.split_tile);
+		s.value(&mut self.split_column);
+		s.value(&mut self.last_nametable_offset);
+	}
+
+	fn fork(&self) -> Box<dyn Mapper> {
+		Box::new(self.clone())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::MMC5;
+	use crate::mapper::{copy_state, CpuMapping, Mapper, PpuFetch};
+
+	/// 128KB PRG ROM where each byte is its 8KB bank number, 256KB CHR ROM where each byte is its 1KB bank number.
+	fn initialize() -> MMC5 {
+		let prg_rom = (0..128 * 1024).map(|i| (i / 0x2000) as u8).collect();
+		let chr = (0..256 * 1024).map(|i| (i / 0x400) as u8).collect();
+		MMC5::new(prg_rom, chr, vec![0; 1024 * 64])
+	}
+
+	fn read_prg(mmc5: &mut MMC5) -> [u8; 4] {
+		[0x8000, 0xA000, 0xC000, 0xE000].map(|addr| mmc5.cpu_read(addr, false).unwrap())
+	}
+
+	#[test]
+	fn test_prg_banking() {
+		let mut mmc5 = initialize();
+		// Power on: last bank at $E000
+		assert_eq!(mmc5.cpu_read(0xE000, false), Some(15));
+
+		// Mode 3: 8KB banks
+		for (i, bank) in [0x82, 0x85, 0x87, 0x09].iter().enumerate() {
+			mmc5.cpu_write(0x5114 + i as u16, *bank, false);
+		}
+		assert_eq!(read_prg(&mut mmc5), [2, 5, 7, 9]);
+
+		// Mode 0: 32KB from $5117, ignoring the low 2 bits
+		mmc5.cpu_write(0x5100, 0, false);
+		assert_eq!(read_prg(&mut mmc5), [8, 9, 10, 11]);
+
+		// Mode 1: 16KB + 16KB
+		mmc5.cpu_write(0x5100, 1, false);
+		assert_eq!(read_prg(&mut mmc5), [4, 5, 8, 9]);
+
+		// Mode 2: 16KB + 8KB + 8KB
+		mmc5.cpu_write(0x5100, 2, false);
+		assert_eq!(read_prg(&mut mmc5), [4, 5, 7, 9]);
+	}
+
+	#[test]
+	fn test_prg_ram() {
+		let mut mmc5 = initialize();
+		mmc5.cpu_write(0x5113, 3, false); // RAM bank 3 at $6000
+		mmc5.cpu_write(0x5114, 0x01, false); // RAM bank 1 at $8000
+
+		// Write protected
+		mmc5.cpu_write(0x6000, 0x42, false);
+		assert_eq!(mmc5.cpu_read(0x6000, false), Some(0));
+
+		mmc5.cpu_write(0x5102, 0b10, false);
+		mmc5.cpu_write(0x5103, 0b01, false);
+		mmc5.cpu_write(0x6000, 0x42, false);
+		mmc5.cpu_write(0x8000, 0x43, false);
+		assert_eq!(mmc5.cpu_read(0x6000, false), Some(0x42));
+		assert_eq!(mmc5.cpu_read(0x8000, false), Some(0x43));
+
+		// The same RAM bank in both windows
+		mmc5.cpu_write(0x5113, 1, false);
+		assert_eq!(mmc5.cpu_read(0x6000, false), Some(0x43));
+	}
+
+	#[test]
+	fn test_small_prg_ram() {
+		// 8KB of PRG RAM from the header: the banks are mirrors of it
+		let mut mmc5 = MMC5::new(vec![0; 128 * 1024], vec![0; 8 * 1024], vec![0; 8 * 1024]);
+		mmc5.cpu_write(0x5102, 0b10, false);
+		mmc5.cpu_write(0x5103, 0b01, false);
+		mmc5.cpu_write(0x5113, 3, false);
+		mmc5.cpu_write(0x6000, 0x42, false);
+		mmc5.cpu_write(0x6001, 0x43, true);
+		assert_eq!(mmc5.cpu_read(0x6000, false), Some(0x42));
+		assert_eq!(mmc5.cpu_mapping(0x6001), CpuMapping::PrgRam { offset: 1 });
+		mmc5.cpu_write(0x5113, 0, false);
+		assert_eq!((mmc5.cpu_read(0x6000, false), mmc5.cpu_read(0x6001, true)), (Some(0x42), Some(0x43)));
+	}
+
+	#[test]
+	fn test_chr_banking() {
+		let mut mmc5 = initialize();
+		let ciram = [0; 2048];
+
+		// 1KB mode
+		mmc5.cpu_write(0x5101, 3, false);
+		for i in 0..8 {
+			mmc5.cpu_write(0x5120 + i, 10 + i as u8, false);
+		}
+		for i in 0..8 {
+			assert_eq!(mmc5.ppu_read(i * 0x400, PpuFetch::Sprite, &ciram), 10 + i as u8);
+		}
+
+		// 4KB mode, bank 2 is 1KB banks 8-11
+		mmc5.cpu_write(0x5101, 1, false);
+		mmc5.cpu_write(0x5127, 2, false);
+		assert_eq!(mmc5.ppu_read(0x1000, PpuFetch::Sprite, &ciram), 8);
+		assert_eq!(mmc5.ppu_read(0x1C00, PpuFetch::Sprite, &ciram), 11);
+
+		// In 8x16 mode the background uses set B, which is the same for both pattern tables
+		mmc5.cpu_write(0x512B, 5, false);
+		mmc5.ppu_register_write(0, 0x20);
+		assert_eq!(mmc5.ppu_read(0x0000, PpuFetch::Background, &ciram), 20);
+		assert_eq!(mmc5.ppu_read(0x1000, PpuFetch::Background, &ciram), 20);
+		assert_eq!(mmc5.ppu_read(0x1000, PpuFetch::Sprite, &ciram), 8);
+
+		// In 8x8 mode the last written set is used for everything
+		mmc5.ppu_register_write(0, 0);
+		assert_eq!(mmc5.ppu_read(0x1000, PpuFetch::Sprite, &ciram), 20);
+		mmc5.cpu_write(0x5127, 3, false);
+		assert_eq!(mmc5.ppu_read(0x1000, PpuFetch::Background, &ciram), 12);
+
+		// Upper CHR bits
+		mmc5.cpu_write(0x5101, 0, false);
+		mmc5.cpu_write(0x5130, 1, false);
+		mmc5.cpu_write(0x5127, 0, false); // 8KB bank 256 = 2MB, wraps around the 256KB CHR
+		assert_eq!(mmc5.ppu_read(0x0000, PpuFetch::Sprite, &ciram), 0);
+	}
+
+	#[test]
+	fn test_nametable_mapping_and_fill_mode() {
+		let mut mmc5 = initialize();
+		let mut ciram = [0; 2048];
+		ciram[0x005] = 1;
+		ciram[0x405] = 2;
+		mmc5.cpu_write(0x5C05, 3, false); // ExRAM in mode 0
+
+		// $2000: CIRAM 0, $2400: CIRAM 1, $2800: ExRAM, $2C00: fill mode
+		mmc5.cpu_write(0x5105, 0b11_10_01_00, false);
+		mmc5.cpu_write(0x5106, 0x77, false);
+		mmc5.cpu_write(0x5107, 2, false);
+		assert_eq!(mmc5.ppu_read(0x2005, PpuFetch::Data, &ciram), 1);
+		assert_eq!(mmc5.ppu_read(0x2405, PpuFetch::Data, &ciram), 2);
+		assert_eq!(mmc5.ppu_read(0x2805, PpuFetch::Data, &ciram), 3);
+		assert_eq!(mmc5.ppu_read(0x2C05, PpuFetch::Data, &ciram), 0x77);
+		assert_eq!(mmc5.ppu_read(0x2FC0, PpuFetch::Data, &ciram), 0b10_10_10_10);
+
+		mmc5.ppu_write(0x2805, 4, &mut ciram);
+		assert_eq!(mmc5.ppu_read(0x2805, PpuFetch::Data, &ciram), 4);
+	}
+
+	#[test]
+	fn test_extended_attributes() {
+		let mut mmc5 = initialize();
+		let ciram = [0; 2048];
+		mmc5.cpu_write(0x5104, 1, false);
+		// Tile 5: palette 3, 4KB CHR bank 7 (1KB bank 28)
+		mmc5.cpu_write(0x5C05, 0b11_000111, false);
+
+		mmc5.ppu_read(0x2005, PpuFetch::Background, &ciram);
+		assert_eq!(mmc5.ppu_read(0x23C1, PpuFetch::Background, &ciram), 0xFF);
+		assert_eq!(mmc5.ppu_read(0x0010, PpuFetch::Background, &ciram), 28);
+		// Sprites are not affected
+		assert_eq!(mmc5.ppu_read(0x0010, PpuFetch::Sprite, &ciram), 0);
+	}
+
+	#[test]
+	fn test_split_screen() {
+		let mut mmc5 = initialize();
+		let ciram = [0; 2048];
+		// Split the left 2 tiles, scroll 8 (tile row 1), CHR bank 1 (1KB banks 4-7)
+		mmc5.cpu_write(0x5200, 0x80 | 2, false);
+		mmc5.cpu_write(0x5201, 8, false);
+		mmc5.cpu_write(0x5202, 1, false);
+		mmc5.cpu_write(0x5C00 + 32, 0xAA, false); // Row 1, column 0
+		mmc5.ppu_tick(261, 321, true);
+
+		// Tile 0 is in the split
+		assert_eq!(mmc5.ppu_read(0x2000, PpuFetch::Background, &ciram), 0xAA);
+		assert_eq!(mmc5.ppu_read(0x0AA0, PpuFetch::Background, &ciram), 6);
+		// Tile 1 too, tile 2 is not
+		mmc5.ppu_read(0x2001, PpuFetch::Background, &ciram);
+		assert_eq!(mmc5.ppu_read(0x2002, PpuFetch::Background, &ciram), 0);
+		assert_eq!(mmc5.ppu_read(0x0AA0, PpuFetch::Background, &ciram), 2);
+	}
+
+	#[test]
+	fn test_scanline_irq() {
+		let mut mmc5 = initialize();
+		mmc5.cpu_write(0x5203, 3, false);
+		mmc5.cpu_write(0x5204, 0x80, false);
+
+		for scanline in 0..3 {
+			mmc5.ppu_tick(scanline, 1, true);
+		}
+		assert!(!mmc5.irq());
+		assert_eq!(mmc5.cpu_read(0x5204, true), Some(0x40)); // In frame
+
+		mmc5.ppu_tick(3, 1, true);
+		assert!(mmc5.irq());
+		// Peek doesn't acknowledge
+		assert_eq!(mmc5.cpu_read(0x5204, true), Some(0xC0));
+		assert_eq!(mmc5.cpu_read(0x5204, false), Some(0xC0));
+		assert!(!mmc5.irq());
+
+		// Vblank ends the frame, the counter starts again on the next frame
+		mmc5.ppu_tick(241, 1, true);
+		assert_eq!(mmc5.cpu_read(0x5204, false), Some(0));
+		for scanline in 0..4 {
+			mmc5.ppu_tick(scanline, 1, true);
+		}
+		assert!(mmc5.irq());
+	}
+
+	#[test]
+	fn test_multiplier() {
+		let mut mmc5 = initialize();
+		mmc5.cpu_write(0x5205, 200, false);
+		mmc5.cpu_write(0x5206, 100, false);
+		assert_eq!(mmc5.cpu_read(0x5205, false), Some((20000 & 0xFF) as u8));
+		assert_eq!(mmc5.cpu_read(0x5206, false), Some((20000 >> 8) as u8));
+	}
+
+	#[test]
+	fn test_serialize() {
+		let mut mmc5 = initialize();
+		mmc5.cpu_write(0x5100, 2, false);
+		mmc5.cpu_write(0x5115, 0x84, false);
+		mmc5.cpu_write(0x5116, 0x87, false);
+		mmc5.cpu_write(0x5102, 0b10, false);
+		mmc5.cpu_write(0x5103, 0b01, false);
+		mmc5.cpu_write(0x6000, 0x42, false);
+		mmc5.cpu_write(0x5127, 3, false);
+		mmc5.cpu_write(0x5203, 3, false);
+		mmc5.cpu_write(0x5204, 0x80, false);
+		for scanline in 0..3 {
+			mmc5.ppu_tick(scanline, 1, true);
+		}
+
+		let mut loaded = initialize();
+		copy_state(&mut mmc5, &mut loaded);
+		assert_eq!(read_prg(&mut loaded), read_prg(&mut mmc5));
+		assert_eq!(loaded.cpu_read(0x6000, false), Some(0x42));
+		assert_eq!(loaded.ppu_read(0x0000, PpuFetch::Data, &[0; 2048]), mmc5.ppu_read(0x0000, PpuFetch::Data, &[0; 2048]));
+		// The scanline counter continues
+		loaded.ppu_tick(3, 1, true);
+		assert!(loaded.irq());
+	}
+}
@@ -0,0 +1,233 @@
+pub mod bandai;
+pub mod eeprom;
+pub mod fds;
+pub mod fme7;
+#[cfg(test)]
+pub mod harness;
+pub mod mmc5;
+pub mod nrom;
+pub mod vrc6;
+
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use crate::{apu::{apu::Level, expansion::ExpansionAudio}, error::RomError, rom_parser::MirrorType, savestate::Serializer};
+
+/// PRG RAM size at $6000-$7FFF when the iNES header doesn't say.
+pub const DEFAULT_PRG_RAM_SIZE: usize = 1024 * 8;
+
+/// What the PPU is fetching. Some mappers (MMC5) map the pattern tables differently for background and sprites.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PpuFetch {
+	Background,
+	Sprite,
+	Data,	// CPU access through PPUDATA ($2007)
+}
+
+/// What a CPU read of the cartridge gets with the current banks, see `Mapper::cpu_mapping`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CpuMapping {
+	PrgRom { offset: usize, bank_size: usize },	// Offset in the PRG ROM, in a bank of `bank_size` bytes
+	PrgRam { offset: usize },
+	Register(&'static str),		// A register the CPU can read
+	Other(String),				// E.g. the FDS BIOS
+	OpenBus,
+}
+
+impl fmt::Display for CpuMapping {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			CpuMapping::PrgRom { offset, bank_size } => {
+				write!(f, "PRG ROM bank {} ({}KB) + ${:04X}", offset / bank_size, bank_size / 1024, offset % bank_size)
+			}
+			CpuMapping::PrgRam { offset } => write!(f, "PRG RAM + ${:04X}", offset),
+			CpuMapping::Register(name) => write!(f, "{} register", name),
+			CpuMapping::Other(name) => write!(f, "{}", name),
+			CpuMapping::OpenBus => write!(f, "open bus"),
+		}
+	}
+}
+
+/// ROM data (PRG ROM, CHR ROM, the FDS BIOS and disks), shared by the forks of a machine (`NES::fork`) until one of
+/// them writes to it: a ROM poke, CHR RAM, a disk write. Saving a state doesn't copy it.
+#[derive(Clone, Default)]
+pub struct SharedRom(Arc<Vec<u8>>);
+
+impl SharedRom {
+	pub fn new(data: Vec<u8>) -> Self {
+		SharedRom(Arc::new(data))
+	}
+
+	/// The data, copied first when a fork shares it.
+	pub fn make_mut(&mut self) -> &mut Vec<u8> {
+		Arc::make_mut(&mut self.0)
+	}
+}
+
+impl Deref for SharedRom {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		&self.0
+	}
+}
+
+/// The cartridge hardware: decides what the CPU sees at $4020-$FFFF and what the PPU sees at $0000-$3EFF, by bank switching.
+/// Read here: https://www.nesdev.org/wiki/Mapper
+///
+/// Mappers are `Send`, so a whole NES can move to another thread (e.g. many instances in parallel).
+pub trait Mapper: Send {
+	/// CPU read of $4020-$FFFF. When `peek` is true, the read must not have side effects (like acknowledging IRQ).
+	/// None when the cartridge doesn't drive the data bus (open bus), e.g. there is no PRG RAM.
+	fn cpu_read(&mut self, addr: u16, peek: bool) -> Option<u8>;
+
+	/// What `cpu_read` of the address reads with the current banks, for the debugger and error messages.
+	fn cpu_mapping(&self, addr: u16) -> CpuMapping;
+
+	/// CPU write of $4020-$FFFF. Writes to ROM are usually mapper registers.
+	/// When `poke` is true, the ROM byte at the address is changed instead (for tests, cheats and the debugger).
+	fn cpu_write(&mut self, addr: u16, value: u8, poke: bool);
+
+	/// The address is ROM without mapper registers, a CPU write to it does nothing. For strict mode.
+	fn ignores_write(&self, _addr: u16) -> bool {
+		false
+	}
+
+	/// PPU read of $0000-$3EFF: pattern tables and nametables. `ciram` is the 2KB of VRAM inside the NES, which is
+	/// usually where the mapper puts the nametables.
+	fn ppu_read(&mut self, addr: u16, fetch: PpuFetch, ciram: &[u8]) -> u8;
+
+	/// PPU write of $0000-$3EFF.
+	fn ppu_write(&mut self, addr: u16, value: u8, ciram: &mut [u8]);
+
+	/// The CPU wrote to a PPU register (0-7). Some mappers watch the CPU bus (MMC5 takes the sprite size from PPUCTRL).
+	fn ppu_register_write(&mut self, _reg: u16, _value: u8) {}
+
+	/// Called by the PPU on each dot, before it fetches anything. For mappers that count scanlines.
+	fn ppu_tick(&mut self, _scanline: u16, _dot: u16, _rendering: bool) {}
+
+	/// Called on each CPU cycle. For mappers that count CPU cycles (IRQ timers, expansion audio).
+	fn cpu_tick(&mut self) {}
+
+	/// The expansion audio chips of the cartridge, mixed with the APU output.
+	fn audio_sources(&self) -> &'static [ExpansionAudio] {
+		&[]
+	}
+
+	/// The output of one of the `audio_sources`. The unit is the same as `APU::mix` (a full volume APU pulse channel is
+	/// 15 `PULSE_STEP`s).
+	fn audio_output(&self, _source: ExpansionAudio) -> Level {
+		0
+	}
+
+	/// The mapper IRQ line. The CPU takes the interrupt while it is high (and interrupts are not disabled).
+	fn irq(&self) -> bool {
+		false
+	}
+
+	/// Amount of disk sides, for disk based systems (FDS). 0 for cartridges.
+	fn disk_sides(&self) -> usize {
+		0
+	}
+
+	/// Insert a disk side, or eject the disk (`None`).
+	fn insert_disk(&mut self, _side: Option<usize>) {}
+
+	/// The reset button. The cartridge connector has no reset line, so most mappers don't know about it and keep their
+	/// registers (NROM, MMC5, VRC6, FME-7 and the FDS RAM adapter). Mappers that detect the reset from the CPU bus override this.
+	fn reset(&mut self) {}
+
+	/// The battery backed save memory (PRG RAM, EEPROM...), saved to disk when the emulator exits. None when the
+	/// cartridge has none.
+	fn battery_data(&self) -> Option<Vec<u8>> {
+		None
+	}
+
+	/// Load the save memory from `battery_data` of an earlier run.
+	fn load_battery_data(&mut self, _data: &[u8]) {}
+
+	/// Save or load the mapper state for save states: RAM, bank registers, IRQ counters, audio. The ROM is not saved.
+	fn serialize(&mut self, s: &mut Serializer);
+
+	/// A copy of the mapper, for `NES::fork`. The copy shares the ROM.
+	fn fork(&self) -> Box<dyn Mapper>;
+}
+
+/// The mapper numbers `new_mapper` knows.
+pub const SUPPORTED_MAPPERS: &[u8] = &[0, 5, 16, 24, 26, 69, 159];
+
+/// The board or chip name of common mappers, supported or not, for the ROM info.
+pub fn mapper_name(mapper_num: u8) -> Option<&'static str> {
+	Some(match mapper_num {
+		0 => "NROM",
+		1 => "MMC1",
+		2 => "UxROM",
+		3 => "CNROM",
+		4 => "MMC3",
+		5 => "MMC5",
+		7 => "AxROM",
+		9 => "MMC2",
+		10 => "MMC4",
+		11 => "Color Dreams",
+		16 => "Bandai FCG/LZ93D50",
+		19 => "Namco 163",
+		20 => "FDS",
+		21 | 23 | 25 => "VRC2/VRC4",
+		24 | 26 => "VRC6",
+		66 => "GxROM",
+		69 => "Sunsoft FME-7",
+		71 => "Camerica",
+		85 => "VRC7",
+		159 => "Bandai LZ93D50 (24C01)",
+		_ => return None,
+	})
+}
+
+/// Create the mapper from the iNES mapper number. PRG and CHR are the whole ROM data, empty CHR means 8KB of CHR RAM.
+/// `prg_ram_size` is the PRG RAM size in bytes from the header, None when the header doesn't say (the mapper decides).
+/// Fails for the mappers not in `SUPPORTED_MAPPERS` (the FDS has its own cartridge, see `Cartridge::new_fds`).
+pub fn new_mapper(mapper_num: u8, prg_rom: Vec<u8>, chr: Vec<u8>, mirror_type: MirrorType, prg_ram_size: Option<usize>) -> Result<Box<dyn Mapper>, RomError> {
+	let prg_ram = |default_size: usize| vec![0; prg_ram_size.unwrap_or(default_size)];
+	Ok(match mapper_num {
+		0 => Box::new(nrom::NROM::new(prg_rom, chr, mirror_type, prg_ram(DEFAULT_PRG_RAM_SIZE))),
+		// MMC5 boards have up to 64KB, games that need less don't mind more
+		5 => Box::new(mmc5::MMC5::new(prg_rom, chr, prg_ram(1024 * 64))),
+		16 => Box::new(bandai::FCG::new(prg_rom, chr, eeprom::Chip::X24C02)),
+		24 => Box::new(vrc6::VRC6::new(prg_rom, chr, false, prg_ram(DEFAULT_PRG_RAM_SIZE))),
+		26 => Box::new(vrc6::VRC6::new(prg_rom, chr, true, prg_ram(DEFAULT_PRG_RAM_SIZE))),
+		69 => Box::new(fme7::FME7::new(prg_rom, chr, prg_ram(DEFAULT_PRG_RAM_SIZE))),
+		159 => Box::new(bandai::FCG::new(prg_rom, chr, eeprom::Chip::X24C01)),
+		_ => return Err(RomError::UnsupportedMapper(mapper_num)),
+	})
+}
+
+/// There are 4 logical nametables ($2000, $2400, $2800, $2C00) but only 2KB of CIRAM, so 2 of them are mirrors.
+/// $3000-$3EFF is a mirror of $2000-$2EFF.
+pub fn ciram_index(addr: u16, mirror_type: &MirrorType) -> usize {
+	let offset = (addr & 0x3FF) as usize;
+	let table = match mirror_type {
+		// $2000 = $2400, $2800 = $2C00
+		MirrorType::HORIZONTAL => (addr >> 11) & 1,
+		// $2000 = $2800, $2400 = $2C00
+		MirrorType::VERTICAL => (addr >> 10) & 1,
+	};
+	table as usize * 0x400 + offset
+}
+
+/// Save the state of a mapper and load it into another mapper of the same ROM. Saving the loaded state again must give
+/// the same state.
+#[cfg(test)]
+pub fn copy_state(from: &mut dyn Mapper, to: &mut dyn Mapper) {
+	let mut s = Serializer::saving();
+	from.serialize(&mut s);
+	let data = s.into_bytes();
+
+	let mut s = Serializer::loading(data.clone());
+	to.serialize(&mut s);
+	assert!(s.is_done());
+
+	let mut s = Serializer::saving();
+	to.serialize(&mut s);
+	assert_eq!(s.into_bytes(), data);
+}
\ No newline at end of file
@@ -0,0 +1,126 @@
+use log::{debug, warn};
+
+use crate::{rom_parser::MirrorType, savestate::Serializer};
+use super::{ciram_index, CpuMapping, Mapper, PpuFetch, SharedRom};
+
+/// Mapper 0: no bank switching. 16KB or 32KB PRG ROM (16KB is mirrored at $C000), 8KB CHR ROM or RAM.
+#[derive(Clone)]
+pub struct NROM {
+	prg_rom: SharedRom,
+	prg_ram: Vec<u8>,	// At $6000-$7FFF, mirrored if smaller (Family Basic has 2KB or 4KB). Empty when not present.
+	chr: SharedRom,
+	chr_ram: bool,
+	mirror_type: MirrorType,
+}
+
+impl NROM {
+	pub fn new(prg_rom: Vec<u8>, chr: Vec<u8>, mirror_type: MirrorType, prg_ram: Vec<u8>) -> Self {
+		let chr_ram = chr.is_empty();
+		NROM {
+			prg_rom: SharedRom::new(prg_rom),
+			prg_ram,
+			chr: SharedRom::new(if chr_ram { vec![0; 1024 * 8] } else { chr }),
+			chr_ram,
+			mirror_type,
+		}
+	}
+}
+
+impl Mapper for NROM {
+	fn cpu_read(&mut self, addr: u16, _peek: bool) -> Option<u8> {
+		match addr {
+			0x6000..=0x7FFF if !self.prg_ram.is_empty() => Some(self.prg_ram[(addr - 0x6000) as usize % self.prg_ram.len()]),
+			0x8000..=0xFFFF => Some(self.prg_rom[(addr - 0x8000) as usize % self.prg_rom.len()]),
+			_ => None,
+		}
+	}
+
+	fn cpu_mapping(&self, addr: u16) -> CpuMapping {
+		match addr {
+			0x6000..=0x7FFF if !self.prg_ram.is_empty() => CpuMapping::PrgRam { offset: (addr - 0x6000) as usize % self.prg_ram.len() },
+			0x8000..=0xFFFF => CpuMapping::PrgRom { offset: (addr - 0x8000) as usize % self.prg_rom.len(), bank_size: self.prg_rom.len() },
+			_ => CpuMapping::OpenBus,
+		}
+	}
+
+	fn cpu_write(&mut self, addr: u16, value: u8, poke: bool) {
+		match addr {
+			0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+				let len = self.prg_ram.len();
+				self.prg_ram[(addr - 0x6000) as usize % len] = value;
+			}
+			0x8000..=0xFFFF if poke => {
+				let len = self.prg_rom.len();
+				self.prg_rom.make_mut()[(addr - 0x8000) as usize % len] = value;
+			}
+			// NROM has no registers, a write to ROM does nothing
+			0x8000..=0xFFFF => debug!("Write to PRG ROM ignored: [{:#X}] = {:#X}", addr, value),
+			_ => {}
+		}
+	}
+
+	fn ignores_write(&self, addr: u16) -> bool {
+		addr >= 0x8000
+	}
+
+	fn ppu_read(&mut self, addr: u16, _fetch: PpuFetch, ciram: &[u8]) -> u8 {
+		match addr {
+			0x0000..=0x1FFF => self.chr[addr as usize],
+			_ => ciram[ciram_index(addr, &self.mirror_type)],
+		}
+	}
+
+	fn ppu_write(&mut self, addr: u16, value: u8, ciram: &mut [u8]) {
+		match addr {
+			0x0000..=0x1FFF => {
+				if self.chr_ram {
+					self.chr.make_mut()[addr as usize] = value;
+				} else {
+					warn!("Write to CHR ROM ignored: [{:#X}] = {:#X}", addr, value);
+				}
+			}
+			_ => ciram[ciram_index(addr, &self.mirror_type)] = value,
+		}
+	}
+
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.prg_ram);
+		if self.chr_ram {
+			s.value(&mut self.chr);
+		}
+	}
+
+	fn fork(&self) -> Box<dyn Mapper> {
+		Box::new(self.clone())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::NROM;
+	use crate::{mapper::{copy_state, Mapper, PpuFetch}, rom_parser::MirrorType};
+
+	#[test]
+	fn test_serialize() {
+		let initialize = || NROM::new(vec![0; 1024 * 32], vec![], MirrorType::HORIZONTAL, vec![0; 1024 * 8]);
+		let mut nrom = initialize();
+		nrom.cpu_write(0x6123, 0x42, false);
+		nrom.ppu_write(0x1234, 0x43, &mut [0; 2048]);
+
+		let mut loaded = initialize();
+		copy_state(&mut nrom, &mut loaded);
+		assert_eq!(loaded.cpu_read(0x6123, false), Some(0x42));
+		assert_eq!(loaded.ppu_read(0x1234, PpuFetch::Data, &[0; 2048]), 0x43);
+	}
+
+	#[test]
+	fn test_rom_write_ignored() {
+		let mut nrom = NROM::new(vec![0x11; 1024 * 16], vec![], MirrorType::HORIZONTAL, vec![]);
+		nrom.cpu_write(0x8000, 0x42, false);
+		nrom.cpu_write(0xFFFF, 0x42, false);
+		assert_eq!((nrom.cpu_read(0x8000, false), nrom.cpu_read(0xFFFF, false)), (Some(0x11), Some(0x11)));
+		// Poke patches the ROM, the 16KB is mirrored
+		nrom.cpu_write(0xC000, 0x42, true);
+		assert_eq!(nrom.cpu_read(0x8000, false), Some(0x42));
+	}
+}
\ No newline at end of file
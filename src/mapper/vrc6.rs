@@ -0,0 +1,583 @@
+use log::warn;
+
+use crate::{apu::{apu::{Level, PULSE_STEP}, expansion::ExpansionAudio}, rom_parser::MirrorType, savestate::{Serialize, Serializer}};
+use super::{ciram_index, CpuMapping, Mapper, PpuFetch, SharedRom};
+
+/// Mappers 24 and 26 (Konami VRC6): Akumajou Densetsu, Madara, Esper Dream 2.
+/// Read here: https://www.nesdev.org/wiki/VRC6
+///
+/// - PRG: 16KB bank at $8000, 8KB bank at $C000, the last 8KB bank fixed at $E000. 8KB PRG RAM at $6000.
+/// - CHR: 1KB or 2KB banks (PPU banking mode in $B003)
+/// - IRQ counter, clocked by scanlines (every 341/3 CPU cycles) or by CPU cycles
+/// - Expansion audio: 2 pulse channels and a sawtooth channel
+///
+/// Mapper 26 is the same chip with the address lines A0 and A1 swapped.
+/// Nametables from CHR ROM ($B003 bit 4) are not implemented, the nametables are always in CIRAM.
+#[derive(Clone)]
+pub struct VRC6 {
+	prg_rom: SharedRom,
+	prg_ram: Vec<u8>,
+	chr: SharedRom,
+	chr_ram: bool,
+	swap_address_lines: bool,
+
+	prg_bank_16k: u8,	// $8000
+	prg_bank_8k: u8,	// $C000
+	chr_banks: [u8; 8],	// $D000-$E003
+	ppu_banking: u8,	// $B003
+
+	irq_latch: u8,			// $F000
+	irq_enable_after_ack: bool,
+	irq_enabled: bool,
+	irq_cycle_mode: bool,	// Clock the counter on each CPU cycle, instead of each scanline
+	irq_counter: u8,
+	irq_prescaler: i16,
+	irq_pending: bool,
+
+	pulses: [Pulse; 2],
+	sawtooth: Sawtooth,
+	halt: bool,			// $9003 bit 0
+	frequency_shift: u8,	// $9003 bits 1-2: the timers run 16 or 256 times faster
+}
+
+impl VRC6 {
+	pub fn new(prg_rom: Vec<u8>, chr: Vec<u8>, swap_address_lines: bool, prg_ram: Vec<u8>) -> Self {
+		let chr_ram = chr.is_empty();
+		VRC6 {
+			prg_rom: SharedRom::new(prg_rom),
+			prg_ram,
+			chr: SharedRom::new(if chr_ram { vec![0; 1024 * 8] } else { chr }),
+			chr_ram,
+			swap_address_lines,
+			prg_bank_16k: 0,
+			prg_bank_8k: 0,
+			chr_banks: [0; 8],
+			ppu_banking: 0,
+			irq_latch: 0,
+			irq_enable_after_ack: false,
+			irq_enabled: false,
+			irq_cycle_mode: false,
+			irq_counter: 0,
+			irq_prescaler: 341,
+			irq_pending: false,
+			pulses: [Pulse::default(); 2],
+			sawtooth: Sawtooth::default(),
+			halt: false,
+			frequency_shift: 0,
+		}
+	}
+
+	fn prg_offset(&self, addr: u16) -> usize {
+		let offset = match addr {
+			0x8000..=0xBFFF => self.prg_bank_16k as usize * 0x4000 + (addr & 0x3FFF) as usize,
+			0xC000..=0xDFFF => self.prg_bank_8k as usize * 0x2000 + (addr & 0x1FFF) as usize,
+			_ => self.prg_rom.len() - 0x2000 + (addr & 0x1FFF) as usize,
+		};
+		offset % self.prg_rom.len()
+	}
+
+	fn prg_ram_enabled(&self) -> bool {
+		self.ppu_banking & 0x80 != 0 && !self.prg_ram.is_empty()
+	}
+
+	fn chr_offset(&self, addr: u16) -> usize {
+		let addr = addr as usize & 0x1FFF;
+		let slot = addr / 0x400;
+		let a10 = slot & 1;
+		// 1KB bank number
+		let bank = match self.ppu_banking & 0b11 {
+			0 => self.chr_banks[slot] as usize,
+			// 2KB banks from R0-R3, the low bit comes from the PPU address
+			1 => (self.chr_banks[slot / 2] as usize & !1) | a10,
+			// The first pattern table in 1KB banks, the second in 2KB banks from R4-R5
+			_ if slot < 4 => self.chr_banks[slot] as usize,
+			_ => (self.chr_banks[4 + (slot - 4) / 2] as usize & !1) | a10,
+		};
+		(bank * 0x400 + (addr & 0x3FF)) % self.chr.len()
+	}
+
+	fn ciram_index(&self, addr: u16) -> usize {
+		match (self.ppu_banking >> 2) & 0b11 {
+			0 => ciram_index(addr, &MirrorType::VERTICAL),
+			1 => ciram_index(addr, &MirrorType::HORIZONTAL),
+			// One screen
+			page => (page as usize - 2) * 0x400 + (addr & 0x3FF) as usize,
+		}
+	}
+
+	fn clock_irq_counter(&mut self) {
+		if self.irq_counter == 0xFF {
+			self.irq_counter = self.irq_latch;
+			self.irq_pending = true;
+		} else {
+			self.irq_counter += 1;
+		}
+	}
+}
+
+impl Mapper for VRC6 {
+	fn cpu_read(&mut self, addr: u16, _peek: bool) -> Option<u8> {
+		match addr {
+			0x6000..=0x7FFF if self.prg_ram_enabled() => Some(self.prg_ram[(addr - 0x6000) as usize % self.prg_ram.len()]),
+			0x8000..=0xFFFF => Some(self.prg_rom[self.prg_offset(addr)]),
+			_ => None,
+		}
+	}
+
+	fn cpu_mapping(&self, addr: u16) -> CpuMapping {
+		match addr {
+			0x6000..=0x7FFF if self.prg_ram_enabled() => CpuMapping::PrgRam { offset: (addr - 0x6000) as usize % self.prg_ram.len() },
+			0x8000..=0xBFFF => CpuMapping::PrgRom { offset: self.prg_offset(addr), bank_size: 0x4000 },
+			0xC000..=0xFFFF => CpuMapping::PrgRom { offset: self.prg_offset(addr), bank_size: 0x2000 },
+			_ => CpuMapping::OpenBus,
+		}
+	}
+
+	fn cpu_write(&mut self, addr: u16, value: u8, poke: bool) {
+		if poke && addr >= 0x8000 {
+			let offset = self.prg_offset(addr);
+			self.prg_rom.make_mut()[offset] = value;
+			return;
+		}
+		if (0x6000..=0x7FFF).contains(&addr) {
+			if (self.prg_ram_enabled() || poke) && !self.prg_ram.is_empty() {
+				let len = self.prg_ram.len();
+				self.prg_ram[(addr - 0x6000) as usize % len] = value;
+			}
+			return;
+		}
+
+		let mut reg = addr & 0xF003;
+		if self.swap_address_lines {
+			reg = (reg & 0xF000) | ((reg & 1) << 1) | ((reg & 2) >> 1);
+		}
+		match reg {
+			0x8000..=0x8003 => self.prg_bank_16k = value,
+			0x9000..=0x9002 => self.pulses[0].write(reg & 3, value),
+			0x9003 => {
+				self.halt = value & 1 != 0;
+				self.frequency_shift = if value & 0b100 != 0 { 8 } else if value & 0b10 != 0 { 4 } else { 0 };
+			}
+			0xA000..=0xA002 => self.pulses[1].write(reg & 3, value),
+			0xB000..=0xB002 => self.sawtooth.write(reg & 3, value),
+			0xB003 => self.ppu_banking = value,
+			0xC000..=0xC003 => self.prg_bank_8k = value,
+			0xD000..=0xD003 => self.chr_banks[(reg & 3) as usize] = value,
+			0xE000..=0xE003 => self.chr_banks[4 + (reg & 3) as usize] = value,
+			0xF000 => self.irq_latch = value,
+			0xF001 => {
+				self.irq_enable_after_ack = value & 1 != 0;
+				self.irq_enabled = value & 0b10 != 0;
+				self.irq_cycle_mode = value & 0b100 != 0;
+				if self.irq_enabled {
+					self.irq_counter = self.irq_latch;
+					self.irq_prescaler = 341;
+				}
+				self.irq_pending = false;
+			}
+			0xF002 => {
+				self.irq_pending = false;
+				self.irq_enabled = self.irq_enable_after_ack;
+			}
+			_ => {}
+		}
+	}
+
+	fn ppu_read(&mut self, addr: u16, _fetch: PpuFetch, ciram: &[u8]) -> u8 {
+		match addr {
+			0x0000..=0x1FFF => self.chr[self.chr_offset(addr)],
+			_ => ciram[self.ciram_index(addr)],
+		}
+	}
+
+	fn ppu_write(&mut self, addr: u16, value: u8, ciram: &mut [u8]) {
+		match addr {
+			0x0000..=0x1FFF => {
+				if self.chr_ram {
+					let offset = self.chr_offset(addr);
+					self.chr.make_mut()[offset] = value;
+				} else {
+					warn!("Write to CHR ROM ignored: [{:#X}] = {:#X}", addr, value);
+				}
+			}
+			_ => ciram[self.ciram_index(addr)] = value,
+		}
+	}
+
+	fn cpu_tick(&mut self) {
+		if self.irq_enabled {
+			if self.irq_cycle_mode {
+				self.clock_irq_counter();
+			} else {
+				// 341 PPU dots per scanline, 3 dots per CPU cycle
+				self.irq_prescaler -= 3;
+				if self.irq_prescaler <= 0 {
+					self.irq_prescaler += 341;
+					self.clock_irq_counter();
+				}
+			}
+		}
+
+		if !self.halt {
+			for pulse in self.pulses.iter_mut() {
+				pulse.clock(self.frequency_shift);
+			}
+			self.sawtooth.clock(self.frequency_shift);
+		}
+	}
+
+	fn audio_sources(&self) -> &'static [ExpansionAudio] {
+		&[ExpansionAudio::Vrc6]
+	}
+
+	fn audio_output(&self, _source: ExpansionAudio) -> Level {
+		// The pulse volume steps are about the same as the APU pulse steps
+		let output = self.pulses[0].output() + self.pulses[1].output() + self.sawtooth.output();
+		output as Level * PULSE_STEP
+	}
+
+	fn irq(&self) -> bool {
+		self.irq_pending
+	}
+
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.prg_ram);
+		if self.chr_ram {
+			s.value(&mut self.chr);
+		}
+		s.value(&mut self.prg_bank_16k);
+		s.value(&mut self.prg_bank_8k);
+		s.value(&mut self.chr_banks);
+		s.value(&mut self.ppu_banking);
+		s.value(&mut self.irq_latch);
+		s.value(&mut self.irq_enable_after_ack);
+		s.value(&mut self.irq_enabled);
+		s.value(&mut self.irq_cycle_mode);
+		s.value(&mut self.irq_counter);
+		s.value(&mut self.irq_prescaler);
+		s.value(&mut self.irq_pending);
+		s.value(&mut self.pulses);
+		s.value(&mut self.sawtooth);
+		s.value(&mut self.halt);
+		s.value(&mut self.frequency_shift);
+	}
+
+	fn fork(&self) -> Box<dyn Mapper> {
+		Box::new(self.clone())
+	}
+}
+
+/// 12 bit timer, shared by the audio channels. Returns true when the timer reloads.
+fn clock_timer(counter: &mut u16, period: u16, frequency_shift: u8) -> bool {
+	if *counter == 0 {
+		*counter = period >> frequency_shift;
+		true
+	} else {
+		*counter -= 1;
+		false
+	}
+}
+
+#[derive(Default, Clone, Copy)]
+struct Pulse {
+	volume: u8,		// $9000 bits 0-3
+	duty: u8,		// $9000 bits 4-6, the output is high for duty + 1 of the 16 steps
+	constant: bool,	// $9000 bit 7: ignore the duty, always output the volume
+	period: u16,	// $9001, $9002 bits 0-3
+	enabled: bool,	// $9002 bit 7
+	counter: u16,
+	step: u8,
+}
+
+impl Pulse {
+	fn write(&mut self, reg: u16, value: u8) {
+		match reg {
+			0 => {
+				self.volume = value & 0x0F;
+				self.duty = (value >> 4) & 0b111;
+				self.constant = value & 0x80 != 0;
+			}
+			1 => self.period = (self.period & 0x0F00) | value as u16,
+			_ => {
+				self.period = (self.period & 0x00FF) | (((value & 0x0F) as u16) << 8);
+				self.enabled = value & 0x80 != 0;
+				if !self.enabled {
+					self.step = 15;
+				}
+			}
+		}
+	}
+
+	fn clock(&mut self, frequency_shift: u8) {
+		if self.enabled && clock_timer(&mut self.counter, self.period, frequency_shift) {
+			self.step = self.step.wrapping_sub(1) & 0x0F;
+		}
+	}
+
+	fn output(&self) -> u8 {
+		if self.enabled && (self.constant || self.step <= self.duty) {
+			self.volume
+		} else {
+			0
+		}
+	}
+}
+
+impl Serialize for Pulse {
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.volume);
+		s.value(&mut self.duty);
+		s.value(&mut self.constant);
+		s.value(&mut self.period);
+		s.value(&mut self.enabled);
+		s.value(&mut self.counter);
+		s.value(&mut self.step);
+	}
+}
+
+#[derive(Default, Clone, Copy)]
+struct Sawtooth {
+	rate: u8,		// $B000 bits 0-5, added to the accumulator every second step
+	period: u16,	// $B001, $B002 bits 0-3
+	enabled: bool,	// $B002 bit 7
+	counter: u16,
+	step: u8,		// 0-13, the accumulator is reset after 7 additions
+	accumulator: u8,
+}
+
+impl Sawtooth {
+	fn write(&mut self, reg: u16, value: u8) {
+		match reg {
+			0 => self.rate = value & 0x3F,
+			1 => self.period = (self.period & 0x0F00) | value as u16,
+			_ => {
+				self.period = (self.period & 0x00FF) | (((value & 0x0F) as u16) << 8);
+				self.enabled = value & 0x80 != 0;
+				if !self.enabled {
+					self.step = 0;
+					self.accumulator = 0;
+				}
+			}
+		}
+	}
+
+	fn clock(&mut self, frequency_shift: u8) {
+		if !self.enabled || !clock_timer(&mut self.counter, self.period, frequency_shift) {
+			return;
+		}
+		self.step += 1;
+		if self.step == 14 {
+			self.step = 0;
+			self.accumulator = 0;
+		} else if self.step & 1 == 0 {
+			self.accumulator = self.accumulator.wrapping_add(self.rate);
+		}
+	}
+
+	/// The high 5 bits of the accumulator.
+	fn output(&self) -> u8 {
+		self.accumulator >> 3
+	}
+}
+
+impl Serialize for Sawtooth {
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.rate);
+		s.value(&mut self.period);
+		s.value(&mut self.enabled);
+		s.value(&mut self.counter);
+		s.value(&mut self.step);
+		s.value(&mut self.accumulator);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{PULSE_STEP, VRC6};
+	use crate::{apu::expansion::ExpansionAudio, mapper::{copy_state, harness::MapperHarness, Mapper, PpuFetch}};
+
+	/// 128KB PRG ROM where each byte is its 8KB bank number, 128KB CHR ROM where each byte is its 1KB bank number.
+	fn initialize(swap_address_lines: bool) -> VRC6 {
+		let prg_rom = (0..128 * 1024).map(|i| (i / 0x2000) as u8).collect();
+		let chr = (0..128 * 1024).map(|i| (i / 0x400) as u8).collect();
+		VRC6::new(prg_rom, chr, swap_address_lines, vec![0; 1024 * 8])
+	}
+
+	#[test]
+	fn test_banking() {
+		let mut vrc6 = initialize(false);
+		vrc6.cpu_write(0x8000, 3, false); // 16KB bank 3 = 8KB banks 6, 7
+		vrc6.cpu_write(0xC000, 9, false);
+		let banks = [0x8000, 0xA000, 0xC000, 0xE000].map(|addr| vrc6.cpu_read(addr, false).unwrap());
+		assert_eq!(banks, [6, 7, 9, 15]);
+
+		let ciram = [0; 2048];
+		for i in 0..8 {
+			vrc6.cpu_write(0xD000 + (i / 4) * 0x1000 + i % 4, 20 + i as u8, false);
+		}
+		assert_eq!(vrc6.ppu_read(0x0000, PpuFetch::Background, &ciram), 20);
+		assert_eq!(vrc6.ppu_read(0x1C00, PpuFetch::Background, &ciram), 27);
+
+		// 2KB banks: R1 at $0800, the low bit from the PPU address
+		vrc6.cpu_write(0xB003, 0x21, false);
+		assert_eq!(vrc6.ppu_read(0x0800, PpuFetch::Background, &ciram), 20);
+		assert_eq!(vrc6.ppu_read(0x0C00, PpuFetch::Background, &ciram), 21);
+	}
+
+	#[test]
+	fn test_bank_switch_program() {
+		// 16KB bank 3 at $8000 (1KB banks 48-63), 8KB bank 9 at $C000 (72-79), the last 8KB (120-127) at $E000
+		let mut harness = MapperHarness::new(24, 128, 128);
+		harness.run(&[(0x8000, 3), (0xC000, 9), (0xD002, 40), (0xE003, 50)]);
+		assert_eq!([0x8000, 0xBC00, 0xC000, 0xE000].map(|addr| harness.prg_bank(addr)), [48, 63, 72, 120]);
+		assert_eq!([0x0800, 0x1C00].map(|addr| harness.chr_bank(addr)), [40, 50]);
+		assert_eq!(harness.nes.describe_address(0xC123), "Cartridge: PRG ROM bank 9 (8KB) + $0123");
+		assert_eq!(harness.nes.describe_address(0x6000), "Cartridge: open bus");
+		// Mapper 26 swaps A0 and A1: $D001 is R2
+		let mut harness = MapperHarness::new(26, 128, 128);
+		harness.run(&[(0xD001, 40)]);
+		assert_eq!(harness.chr_bank(0x0800), 40);
+	}
+
+	#[test]
+	fn test_swapped_address_lines() {
+		// Mapper 26: $D001 is R2, $D002 is R1
+		let mut vrc6 = initialize(true);
+		let ciram = [0; 2048];
+		vrc6.cpu_write(0xD001, 5, false);
+		vrc6.cpu_write(0xD002, 6, false);
+		assert_eq!(vrc6.ppu_read(0x0400, PpuFetch::Background, &ciram), 6);
+		assert_eq!(vrc6.ppu_read(0x0800, PpuFetch::Background, &ciram), 5);
+	}
+
+	#[test]
+	fn test_prg_ram_and_mirroring() {
+		let mut vrc6 = initialize(false);
+		vrc6.cpu_write(0x6000, 0x42, false);
+		// Disabled PRG RAM is open bus
+		assert_eq!(vrc6.cpu_read(0x6000, false), None);
+
+		// PRG RAM enabled, horizontal mirroring
+		vrc6.cpu_write(0xB003, 0x84, false);
+		vrc6.cpu_write(0x6000, 0x42, false);
+		assert_eq!(vrc6.cpu_read(0x6000, false), Some(0x42));
+
+		let mut ciram = [0; 2048];
+		vrc6.ppu_write(0x2005, 1, &mut ciram);
+		assert_eq!(vrc6.ppu_read(0x2405, PpuFetch::Data, &ciram), 1);
+		// One screen, the second page
+		vrc6.cpu_write(0xB003, 0x8C, false);
+		vrc6.ppu_write(0x2C05, 2, &mut ciram);
+		assert_eq!(ciram[0x405], 2);
+		assert_eq!(vrc6.ppu_read(0x2005, PpuFetch::Data, &ciram), 2);
+	}
+
+	#[test]
+	fn test_irq() {
+		let mut vrc6 = initialize(false);
+		// Cycle mode: the counter counts up from the latch and the IRQ fires when it wraps
+		vrc6.cpu_write(0xF000, 0xFD, false);
+		vrc6.cpu_write(0xF001, 0b111, false);
+		vrc6.cpu_tick();
+		vrc6.cpu_tick();
+		assert!(!vrc6.irq());
+		vrc6.cpu_tick();
+		assert!(vrc6.irq());
+
+		// Acknowledge, and keep counting (enable after acknowledge)
+		vrc6.cpu_write(0xF002, 0, false);
+		assert!(!vrc6.irq());
+		for _ in 0..3 {
+			vrc6.cpu_tick();
+		}
+		assert!(vrc6.irq());
+
+		// Scanline mode: 3 scanlines are 341 CPU cycles
+		vrc6.cpu_write(0xF001, 0b010, false);
+		for _ in 0..340 {
+			vrc6.cpu_tick();
+		}
+		assert!(!vrc6.irq());
+		vrc6.cpu_tick();
+		assert!(vrc6.irq());
+
+		// Disabled after acknowledge
+		vrc6.cpu_write(0xF002, 0, false);
+		for _ in 0..1000 {
+			vrc6.cpu_tick();
+		}
+		assert!(!vrc6.irq());
+	}
+
+	#[test]
+	fn test_audio() {
+		let mut vrc6 = initialize(false);
+		assert_eq!(vrc6.audio_output(ExpansionAudio::Vrc6), 0);
+
+		// Pulse 1: volume 15, duty 7 (8/16), period 0 (a step every CPU cycle)
+		vrc6.cpu_write(0x9000, 0x7F, false);
+		vrc6.cpu_write(0x9002, 0x80, false);
+		let mut high = 0;
+		for _ in 0..32 {
+			vrc6.cpu_tick();
+			if vrc6.audio_output(ExpansionAudio::Vrc6) > 0 {
+				high += 1;
+			}
+		}
+		assert_eq!(high, 16);
+
+		// Constant volume mode
+		vrc6.cpu_write(0x9000, 0x8F, false);
+		assert_eq!(vrc6.audio_output(ExpansionAudio::Vrc6), 15 * PULSE_STEP);
+		vrc6.cpu_write(0x9002, 0x00, false);
+
+		// Sawtooth: rate 8, the accumulator goes 0, 8, ..., 48 and resets
+		vrc6.cpu_write(0xB000, 8, false);
+		vrc6.cpu_write(0xB002, 0x80, false);
+		let mut outputs = Vec::new();
+		for _ in 0..14 {
+			vrc6.cpu_tick();
+			outputs.push((vrc6.audio_output(ExpansionAudio::Vrc6) / PULSE_STEP) as u8);
+		}
+		outputs.dedup();
+		assert_eq!(outputs, [0, 1, 2, 3, 4, 5, 6, 0]);
+
+		// Halt
+		vrc6.cpu_write(0x9003, 1, false);
+		let output = vrc6.audio_output(ExpansionAudio::Vrc6);
+		for _ in 0..100 {
+			vrc6.cpu_tick();
+		}
+		assert_eq!(vrc6.audio_output(ExpansionAudio::Vrc6), output);
+	}
+
+	#[test]
+	fn test_serialize() {
+		let mut vrc6 = initialize(false);
+		vrc6.cpu_write(0x8000, 3, false);
+		vrc6.cpu_write(0xD001, 9, false);
+		vrc6.cpu_write(0xB003, 0x84, false);
+		vrc6.cpu_write(0x6000, 0x42, false);
+		vrc6.cpu_write(0xF000, 0xF0, false);
+		vrc6.cpu_write(0xF001, 0b111, false);
+		vrc6.cpu_write(0x9000, 0x3F, false);
+		vrc6.cpu_write(0x9001, 0x10, false);
+		vrc6.cpu_write(0x9002, 0x80, false);
+		for _ in 0..7 {
+			vrc6.cpu_tick();
+		}
+
+		let mut loaded = initialize(false);
+		copy_state(&mut vrc6, &mut loaded);
+		assert_eq!(loaded.cpu_read(0x8000, false), Some(6));
+		assert_eq!(loaded.cpu_read(0x6000, false), Some(0x42));
+		assert_eq!(loaded.ppu_read(0x0400, PpuFetch::Data, &[0; 2048]), 9);
+		// The IRQ counter and the pulse continue where they were
+		for _ in 0..100 {
+			vrc6.cpu_tick();
+			loaded.cpu_tick();
+			assert_eq!(loaded.irq(), vrc6.irq());
+			assert_eq!(loaded.audio_output(ExpansionAudio::Vrc6), vrc6.audio_output(ExpansionAudio::Vrc6));
+		}
+	}
+}
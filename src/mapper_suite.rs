@@ -0,0 +1,188 @@
+//! Runs a set of mapper test ROMs headless and checks the picture of each against a known hash. Made for the "Holy
+//! Mapperel" ROMs, which test the banking, mirroring and RAM of a mapper and show the results on screen. The ROMs are
+//! named by their board, e.g. `M69_P128K_C64K_W8K.nes` is mapper 69, so a mapper change can run only its ROMs.
+//!
+//! The directory has the ROMs and `expected.txt`, a `<ROM> <frames> <hash>` line for each ROM (`#` starts a comment).
+//! `record` writes it: check the screens first (`--headless <FRAMES> --screenshot`), the hash keeps whatever is shown.
+
+use std::fs;
+use std::path::Path;
+
+use log::{error, info, warn};
+
+use crate::{error::RomError, headless, mapper, nes::NES, rom_parser::SizeMismatch};
+
+pub const EXPECTED_FILE: &str = "expected.txt";
+
+/// Frames to run each ROM when recording. Holy Mapperel shows its results in about a second.
+pub const RECORD_FRAMES: u64 = 120;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SuiteEntry {
+	pub rom: String,
+	pub frames: u64,
+	pub hash: u32,
+}
+
+/// Parse `expected.txt`.
+pub fn parse_expected(text: &str) -> Result<Vec<SuiteEntry>, String> {
+	let mut entries = vec![];
+	for (i, line) in text.lines().enumerate() {
+		let line = line.split('#').next().unwrap().trim();
+		if line.is_empty() {
+			continue;
+		}
+		let fields: Vec<&str> = line.split_whitespace().collect();
+		let entry = match fields[..] {
+			[rom, frames, hash] => frames.parse().ok().zip(u32::from_str_radix(hash, 16).ok())
+				.map(|(frames, hash)| SuiteEntry { rom: rom.to_string(), frames, hash }),
+			_ => None,
+		};
+		entries.push(entry.ok_or_else(|| format!("Line {} is not `<ROM> <frames> <hash>`: {}", i + 1, line))?);
+	}
+	Ok(entries)
+}
+
+/// The mapper number from the Holy Mapperel file name: `M<mapper>_...`.
+pub fn rom_mapper(rom: &str) -> Option<u8> {
+	rom.strip_prefix('M')?.split('_').next()?.parse().ok()
+}
+
+/// The hash of the picture after running the ROM for the frames. None when the emulator doesn't support the mapper, an error
+/// when the ROM can't be loaded.
+fn run_rom(path: &Path, frames: u64) -> Result<Option<u32>, RomError> {
+	let Some(mapper_num) = path.file_name().and_then(|name| rom_mapper(&name.to_string_lossy())) else {
+		return Ok(None);
+	};
+	if !mapper::SUPPORTED_MAPPERS.contains(&mapper_num) {
+		return Ok(None);
+	}
+	let mut nes = NES::open_rom_file(&path.to_string_lossy(), SizeMismatch::default())?;
+	nes.run_frames(frames);
+	Ok(Some(headless::framebuffer_hash(&nes)))
+}
+
+/// Run the ROMs of `expected.txt` in the directory, only of the `mappers` if not empty. Missing ROMs and mappers the
+/// emulator doesn't support are skipped. Returns whether all the ROMs that ran show the expected picture.
+pub fn run(dir: &str, mappers: &[u8]) -> Result<bool, String> {
+	let path = Path::new(dir).join(EXPECTED_FILE);
+	let text = fs::read_to_string(&path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+	let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+	for entry in parse_expected(&text)? {
+		if !mappers.is_empty() && !rom_mapper(&entry.rom).is_some_and(|mapper_num| mappers.contains(&mapper_num)) {
+			continue;
+		}
+		let rom_path = Path::new(dir).join(&entry.rom);
+		if !rom_path.exists() {
+			warn!("{}: missing, skipped", entry.rom);
+			skipped += 1;
+			continue;
+		}
+		match run_rom(&rom_path, entry.frames) {
+			Ok(Some(hash)) if hash == entry.hash => passed += 1,
+			Ok(Some(hash)) => {
+				error!("{}: the picture changed, hash {:08X} instead of {:08X}", entry.rom, hash, entry.hash);
+				failed += 1;
+			}
+			Err(e) => {
+				error!("{}: {}", entry.rom, e);
+				failed += 1;
+			}
+			Ok(None) => {
+				warn!("{}: unsupported mapper, skipped", entry.rom);
+				skipped += 1;
+			}
+		}
+	}
+	info!("Mapper suite: {} passed, {} failed, {} skipped", passed, failed, skipped);
+	Ok(failed == 0)
+}
+
+/// Run every .nes ROM in the directory with a supported mapper for `frames` frames, and write the hashes to
+/// `expected.txt`.
+pub fn record(dir: &str, frames: u64) -> Result<usize, String> {
+	let mut roms: Vec<_> = fs::read_dir(dir).map_err(|e| format!("Can't read {}: {}", dir, e))?
+		.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+		.filter(|path| path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("nes")))
+		.collect();
+	roms.sort();
+	let mut text = String::from("# <ROM> <frames> <hash of the picture>, written by the mapper suite\n");
+	let mut recorded = 0;
+	for path in roms {
+		match run_rom(&path, frames) {
+			Ok(Some(hash)) => {
+				text += &format!("{} {} {:08X}\n", path.file_name().unwrap().to_string_lossy(), frames, hash);
+				recorded += 1;
+			}
+			Ok(None) => {}
+			Err(e) => warn!("{}: {}, not recorded", path.display(), e),
+		}
+	}
+	let path = Path::new(dir).join(EXPECTED_FILE);
+	fs::write(&path, text).map_err(|e| format!("Can't write {}: {}", path.display(), e))?;
+	Ok(recorded)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+
+	use super::{parse_expected, record, rom_mapper, run, SuiteEntry, EXPECTED_FILE};
+	use crate::program_loader::*;
+
+	#[test]
+	fn test_parse() {
+		assert_eq!(rom_mapper("M69_P128K_C64K_W8K.nes"), Some(69));
+		assert_eq!(rom_mapper("M0_P32K_C8K_V.nes"), Some(0));
+		assert_eq!(rom_mapper("holy.nes"), None);
+
+		let entries = parse_expected("# Comment\nM0_P32K_C8K_V.nes 120 0123ABCD\n\n");
+		assert_eq!(entries, Ok(vec![SuiteEntry { rom: "M0_P32K_C8K_V.nes".to_string(), frames: 120, hash: 0x0123ABCD }]));
+		assert!(parse_expected("M0.nes 120").unwrap_err().starts_with("Line 1"));
+	}
+
+	#[test]
+	fn test_record_and_run() {
+		// An NROM ROM (iNES header, 32KB PRG, 8KB CHR with tile 1 solid, horizontal mirroring) that draws a tile, and a
+		// mapper the emulator doesn't support
+		let mut rom_memory: [u8; 1024*32] = [0;1024*32];
+		load_program_scroll(&mut rom_memory);
+		set_reset_vector_after_warm_up(&mut rom_memory, 0x8000);
+		let mut rom = b"NES\x1A\x02\x01\x00\x00".to_vec();
+		rom.resize(16, 0);
+		rom.extend(rom_memory);
+		let mut chr = [0; 1024 * 8];
+		chr[0x10..0x18].fill(0xFF);
+		rom.extend(chr);
+
+		let dir = std::env::temp_dir().join(format!("rust-nes-emulator-suite-{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		fs::write(dir.join("M0_P32K_V.nes"), &rom).unwrap();
+		fs::write(dir.join("M255_P32K.nes"), &rom).unwrap();
+		let dir_path = dir.to_str().unwrap();
+		assert_eq!(record(dir_path, 3), Ok(1));
+		assert_eq!(run(dir_path, &[]), Ok(true));
+
+		// Another picture fails, unless only other mappers run
+		let expected = fs::read_to_string(dir.join(EXPECTED_FILE)).unwrap();
+		fs::write(dir.join(EXPECTED_FILE), expected.replace(" 3 ", " 1 ")).unwrap();
+		assert_eq!(run(dir_path, &[]), Ok(false));
+		assert_eq!(run(dir_path, &[69]), Ok(true));
+		// A ROM that can't be loaded fails
+		fs::write(dir.join(EXPECTED_FILE), expected.clone()).unwrap();
+		fs::write(dir.join("M0_P32K_V.nes"), b"Not a ROM").unwrap();
+		assert_eq!(run(dir_path, &[]), Ok(false));
+		// Missing ROMs are skipped
+		fs::remove_file(dir.join("M0_P32K_V.nes")).unwrap();
+		assert_eq!(run(dir_path, &[]), Ok(true));
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	/// Put the Holy Mapperel ROMs and their `expected.txt` in `test_roms/holy_mapperel` and run
+	/// `cargo test -- --ignored test_holy_mapperel`.
+	#[test]
+	#[ignore]
+	fn test_holy_mapperel() {
+		assert_eq!(run("test_roms/holy_mapperel", &[]), Ok(true));
+	}
+}
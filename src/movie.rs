@@ -0,0 +1,273 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use log::info;
+
+use crate::{controller::{ButtonState, InputProvider}, nes::NES, savestate::Serializer};
+
+/// Movie files start with this.
+const MAGIC: [u8; 4] = *b"NESM";
+const VERSION: u16 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MovieMode {
+	Recording,
+	Playing,
+	Finished,	// Played to the end, the players have the controllers again
+}
+
+/// A movie: the controller buttons of each frame, from a save state (the anchor). Playing it loads the anchor and
+/// presses the buttons again, so the game does exactly the same.
+///
+/// Re-recording (the TAS workflow): while recording, loading a save state goes back in the movie. The frames after the
+/// state are dropped and recorded again from there, and the rerecord count goes up.
+pub struct Movie {
+	crc32: u32,			// The ROM, movies only play on the ROM they were recorded on
+	rerecords: u32,
+	anchor: Vec<u8>,	// Save state at the first frame
+	anchor_frame: u64,	// `NES::frame` of the anchor
+	inputs: Vec<[u8; 2]>,	// Buttons of each player at the start of each frame, see `Controller::buttons`
+	mode: MovieMode,
+}
+
+impl Movie {
+	/// Start recording from the current state.
+	pub fn record(nes: &mut NES) -> Self {
+		Movie {
+			crc32: nes.cpu.cartridge().crc32(),
+			rerecords: 0,
+			anchor: nes.save_state(),
+			anchor_frame: nes.frame(),
+			inputs: vec![],
+			mode: MovieMode::Recording,
+		}
+	}
+
+	/// Play from the start: loads the anchor state, and the movie presses the buttons (see `MoviePlayer`).
+	pub fn play(&mut self, nes: &mut NES) -> Result<(), String> {
+		if self.crc32 != nes.cpu.cartridge().crc32() {
+			return Err(format!("The movie is of another ROM (CRC32 {:08X}, this ROM is {:08X})", self.crc32, nes.cpu.cartridge().crc32()));
+		}
+		nes.load_state(self.anchor.clone()).map_err(|e| e.to_string())?;
+		nes.set_input_provider(MoviePlayer { anchor_frame: self.anchor_frame, inputs: self.inputs.clone() });
+		self.mode = MovieMode::Playing;
+		Ok(())
+	}
+
+	/// Call at the start of each frame, before it runs: records the buttons, or ends the playing.
+	pub fn frame(&mut self, nes: &mut NES) {
+		let Some(index) = nes.frame().checked_sub(self.anchor_frame).map(|index| index as usize) else {
+			return;
+		};
+		match self.mode {
+			MovieMode::Recording => {
+				self.inputs.truncate(index);
+				self.inputs.resize(index, [0; 2]);
+				self.inputs.push([nes.cpu.controller(0).buttons(), nes.cpu.controller(1).buttons()]);
+			}
+			MovieMode::Playing => {
+				if index >= self.inputs.len() {
+					info!("Movie finished, {} frames", self.inputs.len());
+					nes.clear_input_provider();
+					self.mode = MovieMode::Finished;
+				}
+			}
+			MovieMode::Finished => {}
+		}
+	}
+
+	/// Call after loading a save state. While recording, the movie goes back to the frame of the state, and the frames
+	/// after it are recorded again.
+	pub fn state_loaded(&mut self, nes: &NES) {
+		if self.mode != MovieMode::Recording {
+			return;
+		}
+		let index = nes.frame().saturating_sub(self.anchor_frame) as usize;
+		self.inputs.truncate(index);
+		self.rerecords += 1;
+		info!("Rerecord {}, at movie frame {}", self.rerecords, index);
+	}
+
+	/// The buttons of both players at a frame of the movie (0 is the anchor). No buttons after the end.
+	pub fn input(&self, index: usize) -> [u8; 2] {
+		self.inputs.get(index).copied().unwrap_or([0; 2])
+	}
+
+	/// Change the buttons at a frame. A frame after the end makes the movie longer, with no buttons in between.
+	pub fn set_input(&mut self, index: usize, buttons: [u8; 2]) {
+		if index >= self.inputs.len() {
+			self.inputs.resize(index + 1, [0; 2]);
+		}
+		self.inputs[index] = buttons;
+	}
+
+	/// Insert a frame without buttons, the next frames move one frame later.
+	pub fn insert_input(&mut self, index: usize) {
+		if index <= self.inputs.len() {
+			self.inputs.insert(index, [0; 2]);
+		}
+	}
+
+	/// Remove a frame, the next frames move one frame earlier.
+	pub fn remove_input(&mut self, index: usize) {
+		if index < self.inputs.len() {
+			self.inputs.remove(index);
+		}
+	}
+
+	pub fn mode(&self) -> MovieMode {
+		self.mode
+	}
+
+	pub fn rerecords(&self) -> u32 {
+		self.rerecords
+	}
+
+	/// Amount of frames.
+	pub fn len(&self) -> usize {
+		self.inputs.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.inputs.is_empty()
+	}
+
+	/// The file: a header with the ROM CRC32 and the rerecord count, the anchor state, then the buttons of each frame.
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut s = Serializer::saving();
+		let (mut magic, mut version) = (MAGIC, VERSION);
+		let (mut crc32, mut rerecords, mut anchor_frame) = (self.crc32, self.rerecords, self.anchor_frame);
+		s.value(&mut magic);
+		s.value(&mut version);
+		s.value(&mut crc32);
+		s.value(&mut rerecords);
+		s.value(&mut anchor_frame);
+		s.value(&mut self.anchor.clone());
+		s.value(&mut self.inputs.clone());
+		s.into_bytes()
+	}
+
+	/// Loads in the playing mode, call `play` to start.
+	pub fn from_bytes(data: Vec<u8>) -> Result<Self, String> {
+		let mut s = Serializer::loading(data);
+		let (mut magic, mut version) = ([0u8; 4], 0u16);
+		s.value(&mut magic);
+		s.value(&mut version);
+		if magic != MAGIC {
+			return Err("Not a movie file".to_string());
+		}
+		if version > VERSION {
+			return Err(format!("The movie was recorded by a newer emulator (version {}, this emulator reads up to {})", version, VERSION));
+		}
+		let mut movie = Movie { crc32: 0, rerecords: 0, anchor: vec![], anchor_frame: 0, inputs: vec![], mode: MovieMode::Playing };
+		s.value(&mut movie.crc32);
+		s.value(&mut movie.rerecords);
+		s.value(&mut movie.anchor_frame);
+		s.value(&mut movie.anchor);
+		s.value(&mut movie.inputs);
+		if !s.is_done() {
+			return Err("The movie file is damaged".to_string());
+		}
+		Ok(movie)
+	}
+
+	pub fn save(&self, path: &Path) -> io::Result<()> {
+		fs::write(path, self.to_bytes())
+	}
+
+	pub fn load(path: &Path) -> Result<Self, String> {
+		Movie::from_bytes(fs::read(path).map_err(|e| e.to_string())?)
+	}
+}
+
+/// The input provider of a playing movie, the buttons of the movie when `play` started.
+struct MoviePlayer {
+	anchor_frame: u64,
+	inputs: Vec<[ButtonState; 2]>,
+}
+
+impl InputProvider for MoviePlayer {
+	fn poll(&mut self, frame: u64) -> [ButtonState; 2] {
+		frame.checked_sub(self.anchor_frame).and_then(|index| self.inputs.get(index as usize)).copied().unwrap_or([0; 2])
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Movie, MovieMode};
+	use crate::{controller::Button, nes::NES, program_loader::*};
+
+	/// Counts the frames with A pressed in $0200.
+	fn initialize() -> NES {
+		let mut rom_memory: [u8; 1024*32] = [0;1024*32];
+		load_program_count_a_presses(&mut rom_memory);
+		set_reset_vector(&mut rom_memory, 0x8000);
+		NES::new_custom_prg_rom(rom_memory)
+	}
+
+	#[test]
+	fn test_record_and_play() {
+		let mut nes = initialize();
+		nes.run_frame();
+		let mut movie = Movie::record(&mut nes);
+		for frame in 0..10 {
+			nes.set_button(0, Button::A, frame % 3 == 0);
+			movie.frame(&mut nes);
+			nes.run_frame();
+		}
+		let presses = nes.peek(0x0200);
+		assert_eq!((presses, movie.len()), (4, 10));
+
+		let mut movie = Movie::from_bytes(movie.to_bytes()).unwrap();
+		let mut other = initialize();
+		movie.play(&mut other).unwrap();
+		other.set_button(0, Button::A, false);
+		for _ in 0..10 {
+			movie.frame(&mut other);
+			other.run_frame();
+		}
+		assert_eq!(other.peek(0x0200), presses);
+		movie.frame(&mut other);
+		assert_eq!(movie.mode(), MovieMode::Finished);
+
+		assert!(Movie::from_bytes(vec![1, 2, 3]).is_err());
+		let mut nestest = NES::new_open_rom_file("6502asm_programs/nestest/nestest.nes");
+		assert!(movie.play(&mut nestest).unwrap_err().contains("another ROM"));
+	}
+
+	#[test]
+	fn test_rerecord() {
+		let mut nes = initialize();
+		let mut movie = Movie::record(&mut nes);
+		let mut state = vec![];
+		for frame in 0..6 {
+			if frame == 2 {
+				state = nes.save_state();
+			}
+			nes.set_button(0, Button::A, true);
+			movie.frame(&mut nes);
+			nes.run_frame();
+		}
+		assert_eq!(movie.len(), 6);
+
+		// Go back to frame 2 and play it differently: the frames after it are recorded again
+		nes.load_state(state).unwrap();
+		movie.state_loaded(&nes);
+		assert_eq!((movie.len(), movie.rerecords()), (2, 1));
+		for _ in 0..3 {
+			nes.set_button(0, Button::A, false);
+			movie.frame(&mut nes);
+			nes.run_frame();
+		}
+		assert_eq!(movie.len(), 5);
+
+		let mut other = initialize();
+		movie.play(&mut other).unwrap();
+		for _ in 0..5 {
+			movie.frame(&mut other);
+			other.run_frame();
+		}
+		assert_eq!(other.peek(0x0200), nes.peek(0x0200));
+	}
+}
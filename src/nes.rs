@@ -1,11 +1,26 @@
-use crate::{cpu::cpu::CPU, ppu::ppu::PPU, cartridge::Cartridge, rom_parser::RomParser};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::{apu::sink::AudioSink, callbacks::{Callbacks, Frame, Scanline, VideoSink}, controller::{Button, InputProvider}, cpu::{cpu::{CPU, CpuHalted, CPU_FREQUENCY}, trace::{instruction_stream, InstructionStream}}, ppu::ppu::PPU, cartridge::Cartridge, error::{CpuError, RomError, StateError}, rom_parser::{RomParser, MirrorType, SizeMismatch}, profiling::span, savestate::{Component, Serializer, StateReader, StateWriter}, state_dump::{CpuState, StateDump}, stats::{OpcodeStats, Stats}, vs_system::VsPpu};
+
+/// The run helpers give up after this many CPU cycles (about 10 seconds of emulated time), so a test waiting on something that never happens fails instead of hanging.
+const RUN_UNTIL_MAX_CYCLES: u64 = CPU_FREQUENCY * 10;
 
 pub struct NES {
-	pub cpu: CPU
+	pub cpu: CPU,
+	callbacks: Callbacks,	// Of the programs that use the emulator, not saved in save states
 }
 
+// The whole machine can move to another thread, to run many instances in parallel
+const _: () = {
+	const fn assert_send<T: Send>() {}
+	assert_send::<NES>();
+};
+
 impl NES {
-	fn new(cartridge: Cartridge) -> Self {	
+	/// The machine with a cartridge, powered on. `NesBuilder` makes configured machines from the ROM files.
+	pub fn new(cartridge: Cartridge) -> Self {	
 		// Shared 32KB of lower memory, shared between CPU, PPU
 
 		let ppu: PPU = PPU::new(&cartridge);
@@ -14,21 +29,1253 @@ impl NES {
 		let cpu: CPU = CPU::new(cartridge, ppu);
 
 		NES {
-			cpu
+			cpu,
+			callbacks: Callbacks::default(),
 		}
 	}
 
+	/// For tests: `open_rom_file` that panics when the ROM can't be loaded.
+	#[doc(hidden)]
 	pub fn new_open_rom_file(path: &str) -> Self {
+		NES::open_rom_file(path, SizeMismatch::default()).unwrap_or_else(|e| panic!("{}", e))
+	}
+
+	/// Open an iNES ROM (.nes) or a Famicom Disk System disk image (.fds), `size_mismatch` decides what happens when the iNES file is bigger or smaller than its header says.
+	pub fn open_rom_file(path: &str, size_mismatch: SizeMismatch) -> Result<Self, RomError> {
+		if path.to_lowercase().ends_with(".fds") {
+			return Ok(NES::new(Cartridge::new_fds(path)?));
+		}
+
 		let mut rom_parser = RomParser::new();
-		rom_parser.parse(path);
+		rom_parser.size_mismatch = size_mismatch;
+		rom_parser.parse(path)?;
 	
-		let cartridge: Cartridge = Cartridge::new_with_parser(rom_parser);
-		NES::new(cartridge)
+		let mut cartridge: Cartridge = Cartridge::new_with_parser(rom_parser)?;
+		// The save memory is next to the ROM, e.g. game.sav for game.nes
+		cartridge.load_battery(Path::new(path).with_extension("sav"));
+		Ok(NES::new(cartridge))
+	}
+
+	/// For tests: `try_from_prg_chr` that panics on invalid sizes and unsupported mappers.
+	#[doc(hidden)]
+	pub fn new_from_prg_chr(prg: &[u8], chr: &[u8], mapper: u8, mirroring: MirrorType) -> Self {
+		NES::try_from_prg_chr(prg, chr, mapper, mirroring).unwrap_or_else(|e| panic!("{}", e))
 	}
 
+	/// Boot raw PRG and CHR binaries, without an iNES header (e.g. the output of an assembler). Empty CHR means 8KB of
+	/// CHR RAM. The interrupt vectors are the last 6 bytes of the PRG. Fails on invalid sizes and unsupported mappers.
+	pub fn try_from_prg_chr(prg: &[u8], chr: &[u8], mapper: u8, mirroring: MirrorType) -> Result<Self, RomError> {
+		NES::check_prg_chr(prg, chr)?;
+		Ok(NES::new(Cartridge::from_prg_chr(prg.to_vec(), chr.to_vec(), mapper, mirroring, None)?))
+	}
+
+	fn check_prg_chr(prg: &[u8], chr: &[u8]) -> Result<(), RomError> {
+		if prg.is_empty() || !prg.len().is_multiple_of(1024 * 8) {
+			return Err(RomError::InvalidSize(format!("PRG size must be a multiple of 8KB, got {} bytes", prg.len())));
+		}
+		if !chr.len().is_multiple_of(1024 * 8) {
+			return Err(RomError::InvalidSize(format!("CHR size must be a multiple of 8KB, got {} bytes", chr.len())));
+		}
+		Ok(())
+	}
+
+	/// The test programs use the PPU right away, without the warm-up.
 	#[cfg(test)]
 	pub fn new_custom_prg_rom(prg_rom: [u8;1024*32]) -> Self {
 		let cartridge: Cartridge = Cartridge::new_with_custom_rom(prg_rom);
-		NES::new(cartridge)
+		let mut nes = NES::new(cartridge);
+		nes.cpu.ppu_mut().set_warm_up(false);
+		nes
+	}
+
+	/// Save the battery backed save memory of the cartridge (next to the ROM), if it has any.
+	pub fn save_battery(&self) {
+		self.cpu.cartridge().save_battery();
+	}
+
+	/// Execute a single instruction (the PPU catches up with the CPU afterwards).
+	pub fn step(&mut self) {
+		if self.callbacks.is_empty() {
+			self.cpu.clock_tick();
+			return;
+		}
+		let (frame, scanline) = (self.frame(), self.cpu.ppu().scanline());
+		self.cpu.clock_tick();
+		self.callbacks.after_step(frame, scanline, self.cpu.ppu());
+		if self.frame() != frame {
+			self.callbacks.end_frame(self.cpu.apu_mut());
+		}
+	}
+
+	/// Call `callback` at the end of each frame, see `callbacks::Frame`.
+	pub fn on_frame(&mut self, callback: impl FnMut(&Frame) + Send + 'static) {
+		self.callbacks.add_frame(Box::new(callback));
+	}
+
+	/// Give each completed frame to `sink` too (a recorder, a hash checker...), after the sinks added before.
+	pub fn add_video_sink(&mut self, sink: Box<dyn VideoSink + Send>) {
+		self.callbacks.add_frame(sink);
+	}
+
+	/// Call `callback` at the end of each scanline, see `callbacks::Scanline`.
+	pub fn on_scanline(&mut self, callback: impl FnMut(&Scanline) + Send + 'static) {
+		self.callbacks.add_scanline(Box::new(callback));
+	}
+
+	/// Give the samples of each frame to `sink` (a WAV file, a custom frontend...) instead of `APU::take_samples`.
+	pub fn add_audio_sink(&mut self, sink: Box<dyn AudioSink + Send>) {
+		self.callbacks.add_audio(sink);
+	}
+
+	/// Remove the callbacks of `on_frame` and `on_scanline`, and the video and audio sinks.
+	pub fn clear_callbacks(&mut self) {
+		self.callbacks.clear();
+	}
+
+	/// Press the reset button (soft reset). The RAM and the mapper registers keep their values.
+	pub fn reset(&mut self) {
+		self.cpu.reset();
+	}
+
+	/// Read memory without emulation side effects (no vblank clear on $2002, no VRAM address increment on $2007).
+	/// For tests, cheats and the debugger.
+	pub fn peek(&mut self, addr: u16) -> u8 {
+		self.cpu.peek_memory(addr)
 	}
-}
\ No newline at end of file
+
+	/// Write memory without emulation side effects. Writing to PRG ROM patches the ROM.
+	/// For tests, cheats and the debugger.
+	pub fn poke(&mut self, addr: u16, value: u8) {
+		self.cpu.poke_memory(addr, value);
+	}
+
+	/// A copy of the machine, e.g. for a tree search that tries many inputs from the same state. The ROM is shared (copied
+	/// when a copy writes to it), the rest is copied: the state, the settings and the debugging history. The callbacks,
+	/// the input provider and the instruction stream stay with this machine. Cheaper than a save state and a new
+	/// machine, see `bench_fork`.
+	pub fn fork(&self) -> NES {
+		NES {
+			cpu: self.cpu.fork(),
+			callbacks: Callbacks::default(),
+		}
+	}
+
+	/// Save the whole machine state. Only the state is saved, not the ROM: the state is loaded into a NES with the same
+	/// ROM. See `savestate::StateWriter` for the layout.
+	pub fn save_state(&mut self) -> Vec<u8> {
+		let mut writer = StateWriter::new(self.cpu.cartridge().crc32());
+		for component in Component::ALL {
+			let mut s = Serializer::saving();
+			self.cpu.serialize_component(component, &mut s);
+			writer.component(component, &s.into_bytes());
+		}
+		writer.into_bytes()
+	}
+
+	/// Load a state from `save_state`, also of older emulator versions. Fails when the state is of another ROM, of a newer
+	/// emulator, or damaged; the NES is not changed then.
+	pub fn load_state(&mut self, data: Vec<u8>) -> Result<(), StateError> {
+		let backup = self.save_state();
+		let result = match StateReader::parse(&data) {
+			Some(state) => state.and_then(|state| {
+				self.check_state_rom(state.crc32)?;
+				self.load_components(state.components)
+			}),
+			None => self.load_version_0_state(data),
+		};
+		if result.is_err() {
+			let state = StateReader::parse(&backup).unwrap().unwrap();
+			self.load_components(state.components).unwrap();
+		}
+		result
+	}
+
+	fn check_state_rom(&self, crc32: u32) -> Result<(), StateError> {
+		if crc32 != self.cpu.cartridge().crc32() {
+			return Err(StateError::OtherRom { state: crc32, rom: self.cpu.cartridge().crc32() });
+		}
+		Ok(())
+	}
+
+	fn load_components(&mut self, components: Vec<(Component, Vec<u8>)>) -> Result<(), StateError> {
+		for (component, data) in components {
+			let mut s = Serializer::loading(data);
+			self.cpu.serialize_component(component, &mut s);
+			if !s.is_done() {
+				return Err(StateError::Damaged(Some(component)));
+			}
+		}
+		Ok(())
+	}
+
+	/// The states of the first emulator versions had no header: the CRC32, then the components one after the other,
+	/// with the layout of version 1.
+	fn load_version_0_state(&mut self, data: Vec<u8>) -> Result<(), StateError> {
+		let mut s = Serializer::loading(data);
+		let mut crc32 = 0u32;
+		s.value(&mut crc32);
+		self.check_state_rom(crc32)?;
+		for component in Component::ALL {
+			match component {
+				Component::Cpu => self.cpu.load_version_0_cpu(&mut s),
+				_ => self.cpu.serialize_component(component, &mut s),
+			}
+		}
+		if !s.is_done() {
+			return Err(StateError::Damaged(None));
+		}
+		Ok(())
+	}
+
+	pub fn save_state_file(&mut self, path: &Path) -> Result<(), StateError> {
+		std::fs::write(path, self.save_state()).map_err(|e| StateError::Write { path: path.display().to_string(), reason: e.to_string() })
+	}
+
+	pub fn load_state_file(&mut self, path: &Path) -> Result<(), StateError> {
+		let data = std::fs::read(path).map_err(|e| StateError::Read { path: path.display().to_string(), reason: e.to_string() })?;
+		self.load_state(data)
+	}
+
+	/// Freeze a RAM address to a value: the game's writes to it write the value instead. Can be changed at any time,
+	/// freezing an address again changes its value.
+	pub fn freeze(&mut self, addr: u16, value: u8) {
+		self.cpu.freezes_mut().set(addr, value);
+		self.poke(addr, value);
+	}
+
+	/// Stop enforcing a frozen address, the game can change it again. Returns false if the address is not frozen.
+	pub fn unfreeze(&mut self, addr: u16) -> bool {
+		self.cpu.freezes_mut().remove(addr).is_some()
+	}
+
+	/// Pause or resume a frozen address, without forgetting its value. Returns false if the address is not frozen.
+	pub fn set_freeze_enabled(&mut self, addr: u16, enabled: bool) -> bool {
+		match self.cpu.freezes_mut().set_enabled(addr, enabled) {
+			Some(freeze) => {
+				if enabled {
+					self.poke(addr, freeze.value);
+				}
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Which device and region the address maps to, with the current mapper banks. See `CPU::describe_address`.
+	pub fn describe_address(&self, addr: u16) -> String {
+		self.cpu.describe_address(addr)
+	}
+
+	/// Write the last executed instructions to a file, oldest first. The last line is the instruction that was executing
+	/// when `reason` happened (e.g. the panic message).
+	pub fn dump_trace(&self, path: &str, reason: &str) -> io::Result<()> {
+		let mut file = BufWriter::new(File::create(path)?);
+		writeln!(file, "Post-mortem: {}", reason)?;
+		writeln!(file, "Frame: {}, scanline: {}, dot: {}", self.frame(), self.cpu.ppu().scanline(), self.cpu.ppu().dot())?;
+		let pc = self.cpu.registers().PC;
+		writeln!(file, "PC: ${:04X}, {}", pc, self.describe_address(pc))?;
+		writeln!(file, "Last {} instructions:", self.cpu.trace().len())?;
+		self.cpu.trace().dump(&mut file)?;
+		file.flush()
+	}
+
+	/// Follow the execution from outside the emulator: the returned stream gets every instruction executed from now on,
+	/// with its address, opcode, disassembly and the registers before it. Up to `capacity` instructions are buffered,
+	/// when the stream is not read fast enough the newer instructions are dropped (see `InstructionStream::dropped`).
+	/// Dropping the stream stops the streaming, a new stream replaces the previous one.
+	pub fn instruction_stream(&mut self, capacity: usize) -> InstructionStream {
+		let (sender, stream) = instruction_stream(capacity);
+		self.cpu.set_instruction_stream(sender);
+		stream
+	}
+
+	/// The CPU executed a KIL opcode and stopped, see `CpuHalted`. Reset recovers.
+	pub fn halted(&self) -> Option<CpuHalted> {
+		self.cpu.halted()
+	}
+
+	/// Run a frame, or fail when the CPU is halted at the end of it (the frame is complete, the PPU and APU keep running).
+	pub fn try_run_frame(&mut self) -> Result<(), CpuError> {
+		self.run_frame();
+		match self.halted() {
+			Some(halted) => Err(CpuError::Halted(halted)),
+			None => Ok(()),
+		}
+	}
+
+	/// Amount of frames the PPU completed since power on.
+	pub fn frame(&self) -> u64 {
+		self.cpu.ppu().frame()
+	}
+
+	/// The CPU registers, the timers and the PPU latches, to compare with another emulator.
+	pub fn dump_state(&self) -> StateDump {
+		StateDump {
+			cpu: CpuState {
+				registers: *self.cpu.registers(),
+				cycles: self.cpu.cycles(),
+				irq: self.cpu.irq_line().is_asserted(),
+				halted: self.halted().is_some(),
+			},
+			ppu: self.cpu.ppu().dump_state(),
+			apu: self.cpu.apu().dump_state(),
+		}
+	}
+
+	/// `dump_state` as JSON, see `StateDump::to_json`.
+	pub fn dump_state_json(&self) -> String {
+		self.dump_state().to_json()
+	}
+
+	/// Emulation counters, and where the host time of the last frame went.
+	pub fn stats(&self) -> Stats {
+		Stats {
+			cpu_cycles: self.cpu.cycles(),
+			instructions: self.cpu.stats().instructions(),
+			frames: self.frame(),
+			apu_samples: self.cpu.apu().samples_generated(),
+			last_frame: self.cpu.stats().last_frame(),
+			subsystem_times: self.cpu.stats().subsystem_times(),
+		}
+	}
+
+	/// Measure where the host time of the frames goes (CPU, PPU, APU) in `stats`. Off by default, it reads the clock on
+	/// every instruction.
+	pub fn set_subsystem_times(&mut self, enabled: bool) {
+		self.cpu.stats_mut().set_subsystem_times(enabled);
+	}
+
+	/// How many times each opcode was executed since power on.
+	pub fn opcode_stats(&self) -> &OpcodeStats {
+		self.cpu.stats().opcodes()
+	}
+
+	/// Press or release a button of the controller of player 0 or 1.
+	pub fn set_button(&mut self, player: usize, button: Button, pressed: bool) {
+		self.cpu.controller_mut(player).set_button(button, pressed);
+	}
+
+	/// Take the buttons from `input` (a movie, a script...), polled once per frame. See `controller::InputProvider`.
+	pub fn set_input_provider(&mut self, input: impl InputProvider + Send + 'static) {
+		self.cpu.set_input_provider(Some(Box::new(input)));
+	}
+
+	/// The buttons come from `set_button` again.
+	pub fn clear_input_provider(&mut self) {
+		self.cpu.set_input_provider(None);
+	}
+
+	/// Change the PPU of a VS System game, which decides the palette. The iNES header doesn't say which PPU the game
+	/// needs, the default is the RP2C03 (NES palette). Fails when the game is not a VS System game.
+	pub fn set_vs_ppu(&mut self, ppu: VsPpu) -> Result<(), RomError> {
+		self.cpu.cartridge_mut().vs_system_mut().ok_or(RomError::NotVsSystem)?.set_ppu(ppu);
+		self.cpu.ppu_mut().set_palette_lut(ppu.palette_lut());
+		Ok(())
+	}
+
+	/// Run until the PPU finishes the current frame.
+	pub fn run_frame(&mut self) {
+		span!(DEBUG, "frame", frame = self.frame());
+		let frame = self.frame();
+		while self.frame() == frame {
+			self.run_scanline();
+		}
+	}
+
+	/// Run until the PPU moves to the next scanline. An instruction that crosses the boundary belongs to the scanline it started on.
+	fn run_scanline(&mut self) {
+		span!(DEBUG, "scanline", scanline = self.cpu.ppu().scanline());
+		let scanline = self.cpu.ppu().scanline();
+		while self.cpu.ppu().scanline() == scanline {
+			self.step();
+		}
+	}
+
+	pub fn run_frames(&mut self, frames: u64) {
+		for _ in 0..frames {
+			self.run_frame();
+		}
+	}
+
+	/// Execute instructions until the predicate returns true. The predicate is checked before each instruction.
+	/// Returns false if the predicate didn't become true within `RUN_UNTIL_MAX_CYCLES`.
+	pub fn run_until<F: FnMut(&NES) -> bool>(&mut self, mut predicate: F) -> bool {
+		let max_cycles = self.cpu.cycles() + RUN_UNTIL_MAX_CYCLES;
+		while !predicate(self) {
+			if self.cpu.cycles() >= max_cycles {
+				return false;
+			}
+			self.step();
+		}
+		true
+	}
+
+	/// Run until the PC reaches the address (the instruction at the address is not executed).
+	pub fn run_until_pc(&mut self, addr: u16) -> bool {
+		self.run_until(|nes| nes.cpu.registers().PC == addr)
+	}
+
+	/// Run until an instruction writes to the address (the writing instruction is executed).
+	pub fn run_until_write(&mut self, addr: u16) -> bool {
+		self.run_until(|nes| matches!(nes.cpu.last_write(), Some((write_addr, _)) if write_addr == addr))
+	}
+
+	/// Run until the start of the next vblank. If we are already in vblank, wait for the next one.
+	pub fn run_until_vblank(&mut self) -> bool {
+		let mut left_vblank = !self.cpu.ppu().in_vblank();
+		self.run_until(|nes| {
+			let in_vblank = nes.cpu.ppu().in_vblank();
+			if !in_vblank {
+				left_vblank = true;
+			}
+			left_vblank && in_vblank
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{program_loader::*, ppu::ppu::VBLANK_SCANLINE, cpu::events::AccessKind, stats::FrameStats, cartridge::Cartridge, rom_parser::MirrorType, cpu::{irq::IrqSource, registers::ProcessorStatusBits}};
+	use super::NES;
+	use crate::controller::Button;
+	use crate::cpu::cpu::{CpuHalted, Scheduler};
+	use crate::ppu::ppu::Renderer;
+	use crate::savestate::{Component, Serializer};
+	use crate::mapper::PpuFetch;
+	use crate::error::{CpuError, StateError};
+	use crate::unimplemented::{UnimplementedFeature, UnimplementedPolicy};
+	use crate::suspicious::{EmulationMode, Suspicious};
+	use std::time::Instant;
+
+	fn initialize(f: fn(&mut [u8;1024*32]) -> u8) -> NES {
+		let mut rom_memory: [u8; 1024*32] = [0;1024*32];
+		f(&mut rom_memory);
+		set_reset_vector(&mut rom_memory, 0x8000);
+		NES::new_custom_prg_rom(rom_memory)
+	}
+
+	#[test]
+	fn test_parallel_instances() {
+		// Each instance presses A with its own rhythm, from the same state
+		let run = |state: &[u8], instance: u64| {
+			let mut nes = initialize(load_program_count_a_presses);
+			nes.load_state(state.to_vec()).unwrap();
+			for frame in 0..60 {
+				nes.set_button(0, Button::A, frame % (instance + 1) == 0);
+				nes.run_frame();
+			}
+			crc32fast::hash(&nes.save_state())
+		};
+		let mut nes = initialize(load_program_count_a_presses);
+		nes.run_frame();
+		let state = nes.save_state();
+
+		let (run, state) = (&run, &state);
+		let hashes: Vec<u32> = std::thread::scope(|scope| {
+			let threads: Vec<_> = (0..8).map(|instance| scope.spawn(move || run(state, instance))).collect();
+			threads.into_iter().map(|thread| thread.join().unwrap()).collect()
+		});
+		let expected: Vec<u32> = (0..8).map(|instance| run(state, instance)).collect();
+		assert_eq!(hashes, expected);
+		assert_ne!(hashes[0], hashes[1]);
+	}
+
+	#[test]
+	fn test_fork() {
+		let mut nes = initialize(load_program_count_a_presses);
+		nes.run_frames(2);
+		let state = nes.save_state();
+
+		// The fork has the same state, and goes its own way
+		let mut fork = nes.fork();
+		assert_eq!(fork.save_state(), state);
+		fork.set_button(0, Button::A, true);
+		fork.run_frames(3);
+		assert_eq!(fork.peek(0x0200), 3);
+		assert_eq!(nes.save_state(), state);
+
+		// Same inputs, same states
+		let mut twin = nes.fork();
+		nes.run_frames(3);
+		twin.run_frames(3);
+		assert_eq!(twin.save_state(), nes.save_state());
+
+		// The ROM and the CHR RAM are copied when a fork writes to them
+		fork.poke(0x8001, 0x42);
+		assert_eq!((fork.peek(0x8001), nes.peek(0x8001)), (0x42, 0x80));
+		fork.cpu.cartridge_mut().ppu_write(0x0010, 0x55, &mut [0; 2048]);
+		let chr = |nes: &mut NES| nes.cpu.cartridge_mut().ppu_read(0x0010, PpuFetch::Data, &[0; 2048]);
+		assert_eq!((chr(&mut fork), chr(&mut nes)), (0x55, 0x00));
+	}
+
+	#[test]
+	fn test_run_until_pc() {
+		let mut nes = initialize(load_program_run_helpers);
+
+		assert!(nes.run_until_pc(0x800A)); // STA $0300, after the loop
+		assert_eq!(nes.cpu.registers().X, 0);
+		assert_eq!(nes.cpu.registers().A, 1);
+	}
+
+	#[test]
+	fn test_kil_halts() {
+		let mut nes = initialize(load_program_kil);
+		for _ in 0..3 {
+			nes.run_frame();
+		}
+		// The PPU keeps running, the CPU ignores NMI and stays on the KIL
+		assert_eq!(nes.frame(), 3);
+		assert_eq!(nes.halted(), Some(CpuHalted { pc: 0x800A, opcode: 0x02 }));
+		assert_eq!(nes.cpu.registers().PC, 0x800A);
+		assert_eq!((nes.peek(0x0000), nes.peek(0x0001)), (0, 0x2A));
+		assert_eq!(nes.try_run_frame(), Err(CpuError::Halted(CpuHalted { pc: 0x800A, opcode: 0x02 })));
+
+		// Save states keep the halt
+		let state = nes.save_state();
+		nes.reset();
+		assert_eq!(nes.halted(), None);
+		nes.load_state(state).unwrap();
+		assert!(nes.halted().is_some());
+
+		nes.reset();
+		nes.poke(0x0001, 0);
+		nes.step();
+		assert_eq!(nes.cpu.registers().PC, 0x8002);
+		nes.run_frame();
+		assert!(nes.halted().is_some());
+		assert_eq!(nes.peek(0x0001), 0x2A);
+	}
+
+	#[test]
+	fn test_run_until_write() {
+		let mut nes = initialize(load_program_run_helpers);
+
+		assert!(nes.run_until_write(0x0200));
+		assert_eq!(nes.cpu.registers().PC, 0x8005);
+		assert!(nes.run_until_write(0x0300));
+		assert_eq!(nes.cpu.last_write(), Some((0x0300, 0x01)));
+
+		// The program is now stuck in an infinite loop and never writes again
+		assert!(!nes.run_until_write(0x0400));
+	}
+
+	#[test]
+	fn test_run_frames_and_vblank() {
+		let mut nes = initialize(load_program_run_helpers);
+
+		assert_eq!(nes.frame(), 0);
+		assert!(nes.run_until_vblank());
+		assert_eq!(nes.cpu.ppu().scanline(), VBLANK_SCANLINE);
+		assert_eq!(nes.frame(), 0);
+
+		nes.run_frames(2);
+		assert_eq!(nes.frame(), 2);
+
+		// We are at the start of frame 2, so its vblank is still ahead
+		assert!(nes.run_until_vblank());
+		assert_eq!(nes.frame(), 2);
+		assert!(nes.run_until_vblank());
+		assert_eq!(nes.frame(), 3);
+	}
+
+	#[test]
+	fn test_peek_ppustatus() {
+		let mut nes = initialize(load_program_ppu_status_poll);
+
+		assert!(nes.run_until_vblank());
+		// Peeking doesn't clear the vblank flag
+		assert_eq!(nes.peek(0x2002) & 0x80, 0x80);
+		assert_eq!(nes.peek(0x2002) & 0x80, 0x80);
+
+		// But the program reading $2002 does
+		assert!(nes.run_until_write(0x0200));
+		assert_eq!(nes.cpu.registers().A & 0x80, 0x80);
+		assert_eq!(nes.peek(0x2002) & 0x80, 0);
+	}
+
+	#[test]
+	fn test_peek_ppudata() {
+		let mut nes = initialize(load_program_ppudata);
+
+		assert!(nes.run_until_pc(0x801C));
+		// The program wrote $AB to $2108 and read $2007 once, so $AB is now in the read buffer.
+		// Peeking returns the buffer without incrementing the VRAM address or refilling the buffer.
+		assert_eq!(nes.peek(0x2007), 0xAB);
+		assert_eq!(nes.peek(0x2007), 0xAB);
+		// Mirror of $2007
+		assert_eq!(nes.peek(0x3FFF), 0xAB);
+	}
+
+	#[test]
+	fn test_scroll() {
+		let mut nes = initialize(load_program_scroll);
+		nes.run_frame();
+		nes.run_frame();
+
+		// The top left tile moved 5 pixels right (the wrap around at x 256 shows nametable 0 again, horizontal
+		// mirroring) and 2 pixels up
+		let pixel = |x: usize, y: usize| nes.cpu.ppu().framebuffer()[y * 256 + x];
+		for (x, y) in [(5, 0), (12, 0), (5, 5), (12, 5)] {
+			assert_eq!(pixel(x, y), 0x16, "({}, {})", x, y);
+		}
+		for (x, y) in [(4, 0), (13, 0), (5, 6), (0, 0), (255, 239)] {
+			assert_eq!(pixel(x, y), 0x0F, "({}, {})", x, y);
+		}
+	}
+
+	#[test]
+	fn test_dump_state() {
+		let mut nes = initialize(load_program_scroll);
+		assert!(nes.run_until_pc(0x805E));
+		let state = nes.dump_state();
+		assert_eq!((state.cpu.registers.A, state.cpu.registers.PC, state.cpu.halted), (0x0A, 0x805E, false));
+		// Fine X 3 and coarse X 31 of scroll 251, fine Y 2
+		assert_eq!((state.ppu.mask, state.ppu.x, state.ppu.t, state.ppu.w), (0x0A, 3, 0x201F, false));
+
+		let json = nes.dump_state_json();
+		assert!(json.contains("\"cpu\": {\n    \"a\": 10,\n"), "{}", json);
+		assert!(json.contains("    \"pc\": 32862,\n") && json.contains("    \"dmc_irq\": false\n  }\n}\n"), "{}", json);
+		nes.step();
+		assert_ne!(nes.dump_state(), state);
+	}
+
+	#[test]
+	fn test_event_log() {
+		let mut nes = initialize(load_program_ppudata);
+
+		assert!(nes.run_until_pc(0x801C));
+		let events = nes.cpu.events().current_frame();
+		let accesses: Vec<_> = events.iter().map(|e| (e.kind, e.addr, e.value)).collect();
+		assert_eq!(accesses, vec![
+			(AccessKind::Write, 0x2006, 0x21),
+			(AccessKind::Write, 0x2006, 0x08),
+			(AccessKind::Write, 0x2007, 0xAB),
+			(AccessKind::Write, 0x2006, 0x21),
+			(AccessKind::Write, 0x2006, 0x08),
+			(AccessKind::Read, 0x2007, 0x00),
+		]);
+		// The beam moves forward between the accesses
+		assert!(events.windows(2).all(|pair| pair[0].dot < pair[1].dot));
+		// Peeking is not logged
+		nes.peek(0x2002);
+		assert_eq!(nes.cpu.events().current_frame().len(), 6);
+
+		nes.run_frame();
+		assert_eq!(nes.cpu.events().last_frame().len(), 6);
+		assert!(nes.cpu.events().current_frame().is_empty());
+	}
+
+	#[test]
+	fn test_stats() {
+		let mut nes = initialize(load_program_run_helpers);
+		assert_eq!(nes.stats().last_frame, FrameStats::default());
+
+		nes.run_frames(2);
+		let stats = nes.stats();
+		assert_eq!(stats.frames, 2);
+		assert_eq!(stats.cpu_cycles, nes.cpu.cycles());
+		assert!(stats.instructions > 0);
+
+		// A frame is 29780.5 CPU cycles, give or take the instruction that crosses the end of the frame
+		let frame = stats.last_frame;
+		assert!((29_775..=29_790).contains(&frame.cpu_cycles), "{}", frame.cpu_cycles);
+		assert!(frame.cpu_time + frame.ppu_time + frame.apu_time <= frame.wall_time);
+		assert!(stats.apu_samples > 0);
+		assert!(frame.speed() > 0.0);
+		// The subsystems are timed only when asked
+		assert!(!stats.subsystem_times);
+		assert_eq!((frame.cpu_time, frame.ppu_time, frame.apu_time), Default::default());
+		nes.set_subsystem_times(true);
+		nes.run_frame();
+		let frame = nes.stats().last_frame;
+		assert!(!frame.ppu_time.is_zero());
+		assert!(frame.cpu_time + frame.ppu_time + frame.apu_time <= frame.wall_time);
+	}
+
+	#[test]
+	fn test_opcode_stats() {
+		let mut nes = initialize(load_program_scroll);
+		assert!(nes.run_until_pc(0x805E));
+		nes.step();
+		nes.step();
+		let stats = nes.opcode_stats();
+		// LDA #, STA abs, LDA abs, JMP abs
+		assert_eq!([0xA9, 0x8D, 0xAD, 0x4C, 0xEA].map(|opcode| stats.count(opcode)), [14, 21, 1, 2, 0]);
+		assert_eq!(stats.executed(), vec![(0x8D, 21), (0xA9, 14), (0x4C, 2), (0xAD, 1)]);
+		assert!(stats.table().lines().nth(1).unwrap().starts_with("$8D    STA ABSOLUTE"), "{}", stats.table());
+
+		let mut nes = initialize(|rom| { rom[0] = 0x03; 0 });
+		nes.step();
+		assert!(nes.opcode_stats().table().contains("$03    illegal, as NOP"));
+	}
+
+	#[test]
+	fn test_describe_address() {
+		let nes = initialize(load_program_scroll);
+		let descriptions = [0x01FF, 0x0A00, 0x2002, 0x3FFF, 0x4011, 0x4017, 0x6010, 0xC123].map(|addr| nes.describe_address(addr));
+		assert_eq!(descriptions, [
+			"RAM $01FF", "RAM $0200 (mirror)", "PPU register PPUSTATUS", "PPU register PPUDATA (mirror of $2007)",
+			"APU DMC register", "Controller port 2 (writes: APU frame counter)", "Cartridge: PRG RAM + $0010",
+			"Cartridge: PRG ROM bank 0 (32KB) + $4123",
+		]);
+	}
+
+	#[test]
+	fn test_strict_mode() {
+		// LDA $2000 (write-only), STA $8000 (NROM ROM), TXS with X = 0, PHA
+		let program = |rom: &mut [u8; 1024*32]| { write_rom(rom, "ad 00 20 8d 00 80 a2 00 9a 48"); 0 };
+		let mut nes = initialize(program);
+		nes.run_until_pc(0x800A);
+		assert_eq!(nes.cpu.suspicious().total(), 0);
+
+		let mut nes = initialize(program);
+		nes.cpu.suspicious_mut().set_mode(EmulationMode::Strict);
+		assert!(nes.run_until_pc(0x800A));
+		assert_eq!(nes.cpu.suspicious().total(), 3);
+		assert_eq!(nes.cpu.suspicious_mut().take_break(), Some((0x8009, Suspicious::StackOverflow)));
+		assert_eq!(nes.cpu.registers().S, 0xFF);
+	}
+
+	#[test]
+	fn test_poke_rom() {
+		let mut nes = initialize(load_program_run_helpers);
+
+		// Patch the operand of LDA #$01
+		nes.poke(0x8001, 0x42);
+		assert_eq!(nes.peek(0x8001), 0x42);
+
+		assert!(nes.run_until_write(0x0200));
+		assert_eq!(nes.cpu.last_write(), Some((0x0200, 0x42)));
+	}
+
+	#[test]
+	fn test_trace_on_illegal_opcode() {
+		// LDA #$01, then illegal opcode $03 (SLO)
+		let mut nes = initialize(|rom| { rom[0] = 0xA9; rom[1] = 0x01; rom[2] = 0x03; 0 });
+		nes.cpu.unimplemented_mut().set_policy(UnimplementedPolicy::Panic);
+
+		let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| nes.run_frame()));
+		assert!(result.is_err());
+
+		let trace: Vec<_> = nes.cpu.trace().iter().collect();
+		assert_eq!(trace.len(), 2);
+		assert_eq!((trace[0].pc, trace[0].opcode), (0x8000, 0xA9));
+		assert_eq!((trace[1].pc, trace[1].opcode, trace[1].a), (0x8002, 0x03, 0x01));
+	}
+
+	#[test]
+	fn test_unimplemented_features() {
+		// Illegal opcode $03 (SLO) twice, then LDA #$01 still runs. LDA $5205 reads open bus, NROM has no register there.
+		let mut nes = initialize(|rom| { rom[..7].copy_from_slice(&[0x03, 0x03, 0xA9, 0x01, 0xAD, 0x05, 0x52]); 0 });
+		for _ in 0..3 {
+			nes.step();
+		}
+		assert_eq!((nes.cpu.registers().PC, nes.cpu.registers().A), (0x8004, 0x01));
+		nes.step();
+		assert_eq!(nes.cpu.registers().A, 0x52);
+		assert_eq!(nes.cpu.unimplemented().counts().collect::<Vec<_>>(), vec![
+			(UnimplementedFeature::Opcode(0x03), 2),
+			(UnimplementedFeature::RegisterRead(0x5205), 1),
+		]);
+		assert_eq!(nes.cpu.unimplemented().needs().to_string(), "opcodes $03, $5205 reads");
+	}
+
+	#[test]
+	fn test_instruction_stream() {
+		// LDA #$01, STA $0200,X, loop: JMP loop
+		let mut nes = initialize(|rom| { rom[..8].copy_from_slice(&[0xA9, 0x01, 0x9D, 0x00, 0x02, 0x4C, 0x05, 0x80]); 0 });
+		let mut stream = nes.instruction_stream(3);
+		for _ in 0..4 {
+			nes.step();
+		}
+
+		let instructions: Vec<_> = stream.by_ref().collect();
+		let disassembly: Vec<_> = instructions.iter().map(|i| (i.before.pc, i.disassembly.as_str())).collect();
+		assert_eq!(disassembly, vec![(0x8000, "LDA #$01"), (0x8002, "STA $0200,X"), (0x8005, "JMP $8005")]);
+		// The registers before the instruction
+		assert_eq!((instructions[0].before.a, instructions[1].before.a), (0, 1));
+		assert_eq!(stream.dropped(), 1);
+
+		// Read as the emulation goes
+		nes.step();
+		assert_eq!(stream.next().map(|i| i.before.opcode), Some(0x4C));
+		assert!(stream.next().is_none());
+
+		// The log of --trace-log ends with the emulator
+		nes.step();
+		drop(nes);
+		let mut log = Vec::new();
+		assert_eq!(stream.write_log(&mut log).unwrap(), 1);
+		assert_eq!(String::from_utf8(log).unwrap(), "8005  4C  A:01 X:00 Y:00 P:24 SP:FF CYC:24  JMP $8005\n");
+	}
+
+	#[test]
+	fn test_freeze() {
+		// The program stores A=1 to $0200 and $0300
+		let mut nes = initialize(load_program_run_helpers);
+		nes.freeze(0x0200, 9);
+		nes.freeze(0x0300, 9);
+		assert!(nes.set_freeze_enabled(0x0300, false));
+		assert!(nes.run_until_pc(0x800D));
+		assert_eq!((nes.peek(0x0200), nes.peek(0x0300)), (9, 1));
+
+		// The debugger can still poke a frozen address
+		nes.poke(0x0200, 3);
+		assert_eq!(nes.peek(0x0200), 3);
+		assert!(nes.set_freeze_enabled(0x0300, true));
+		assert_eq!(nes.peek(0x0300), 9);
+		assert!(nes.unfreeze(0x0200));
+		assert!(!nes.unfreeze(0x0200));
+	}
+
+	#[test]
+	fn test_save_state() {
+		// Counts frames in $00 from NMI, and draws the background
+		let mut nes = initialize(load_program_nmi_counter);
+		nes.run_frame();
+		let state = nes.save_state();
+		for _ in 0..3 {
+			nes.run_frame();
+		}
+		let registers = format!("{}", nes.cpu.registers());
+		let framebuffer = nes.cpu.ppu().framebuffer().to_vec();
+		let (frame, cycles, counter) = (nes.frame(), nes.cpu.cycles(), nes.peek(0x0000));
+
+		// Running from the state again ends the same way
+		nes.load_state(state.clone()).unwrap();
+		assert_eq!(nes.frame(), 1);
+		for _ in 0..3 {
+			nes.run_frame();
+		}
+		assert_eq!(format!("{}", nes.cpu.registers()), registers);
+		assert_eq!(nes.cpu.ppu().framebuffer(), &framebuffer[..]);
+		assert_eq!((nes.frame(), nes.cpu.cycles(), nes.peek(0x0000)), (frame, cycles, counter));
+
+		// Another ROM
+		let mut other = NES::new_open_rom_file("6502asm_programs/nestest/nestest.nes");
+		assert!(matches!(other.load_state(state.clone()), Err(StateError::OtherRom { .. })));
+
+		// A damaged state doesn't change the NES
+		let mut damaged = state.clone();
+		damaged.truncate(damaged.len() - 1);
+		assert!(nes.load_state(damaged).is_err());
+		assert_eq!(nes.frame(), frame);
+
+		// A state of the first emulator versions, without the header
+		let mut s = Serializer::saving();
+		let mut crc32 = nes.cpu.cartridge().crc32();
+		s.value(&mut crc32);
+		nes.load_state(state).unwrap();
+		for component in Component::ALL {
+			match component {
+				Component::Cpu => nes.cpu.serialize_cpu_version_1(&mut s),
+				_ => nes.cpu.serialize_component(component, &mut s),
+			}
+		}
+		let version_0 = s.into_bytes();
+		nes.run_frame();
+		nes.load_state(version_0).unwrap();
+		assert_eq!(nes.frame(), 1);
+	}
+
+	#[test]
+	fn test_save_state_round_trip() {
+		// A state loaded into a new NES runs exactly like the one that saved it: no state is left out
+		type Game = (&'static str, fn() -> NES);
+		let games: [Game; 8] = [
+			("nestest", || NES::new_open_rom_file("6502asm_programs/nestest/nestest.nes")),
+			("background", || NES::new_open_rom_file("6502asm_programs/background/background.nes")),
+			("greenscreen", || NES::new_open_rom_file("6502asm_programs/greenscreen/greenscreen.nes")),
+			("minimal", || NES::new_open_rom_file("6502asm_programs/minimal/minimal.nes")),
+			("nmi counter", || initialize(load_program_nmi_counter)),
+			("frame IRQ", || initialize(load_program_frame_irq)),
+			("scroll", || initialize(load_program_scroll)),
+			("MMC5 IRQ", || {
+				let mut rom_memory: [u8; 1024*32] = [0;1024*32];
+				load_program_mmc5_irq(&mut rom_memory);
+				set_reset_vector(&mut rom_memory, 0xE000);
+				NES::new(Cartridge::from_prg_chr(rom_memory.to_vec(), vec![], 5, MirrorType::HORIZONTAL, None).unwrap())
+			}),
+		];
+		let trace = |nes: &NES| format!("{} cycle {} scanline {} dot {}", nes.cpu.registers(), nes.cpu.cycles(), nes.cpu.ppu().scanline(), nes.cpu.ppu().dot());
+		for (game, new) in games {
+			// Saved in the middle of a frame
+			let mut nes = new();
+			nes.run_frames(2);
+			for _ in 0..1000 {
+				nes.step();
+			}
+			nes.cpu.apu_mut().take_samples();
+			let mut loaded = new();
+			loaded.load_state(nes.save_state()).unwrap();
+
+			for _ in 0..20_000 {
+				assert_eq!(trace(&loaded), trace(&nes), "{}", game);
+				nes.step();
+				loaded.step();
+			}
+			assert_eq!(loaded.cpu.apu_mut().take_samples(), nes.cpu.apu_mut().take_samples(), "{}", game);
+			assert_eq!(loaded.cpu.ppu().framebuffer(), nes.cpu.ppu().framebuffer(), "{}", game);
+		}
+	}
+
+	#[test]
+	fn test_vectors_through_mapper() {
+		// MMC5 with 4 banks of 8KB, the reset vector of bank 1 is $E100, of bank 3 (at $E000 at power on) $E000
+		let mut prg = vec![0; 1024 * 32];
+		prg[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xE1]);
+		prg[0x7FFC..0x7FFE].copy_from_slice(&[0x00, 0xE0]);
+		let mut nes = NES::new(Cartridge::from_prg_chr(prg, vec![], 5, MirrorType::HORIZONTAL, None).unwrap());
+		assert_eq!(nes.cpu.registers().PC, 0xE000);
+
+		// The reset reads the vector of the bank the mapper has at $E000 then
+		nes.poke(0x5117, 0x81);
+		nes.reset();
+		assert_eq!(nes.cpu.registers().PC, 0xE100);
+	}
+
+	#[test]
+	fn test_mapper_irq() {
+		let mut rom_memory: [u8; 1024*32] = [0;1024*32];
+		load_program_mmc5_irq(&mut rom_memory);
+		set_reset_vector(&mut rom_memory, 0xE000);
+		let cartridge = Cartridge::from_prg_chr(rom_memory.to_vec(), vec![], 5, MirrorType::HORIZONTAL, None).unwrap();
+		let mut nes = NES::new(cartridge);
+		nes.cpu.ppu_mut().set_warm_up(false);
+
+		// Rendering was enabled during scanline 0, so the counter starts on scanline 1
+		assert!(nes.run_until_pc(0xE018));
+		assert_eq!((nes.frame(), nes.cpu.ppu().scanline()), (0, 3));
+		assert_eq!(nes.peek(0x5204) & 0x80, 0x80);
+		assert_eq!(nes.cpu.irq_line().sources(), vec![IrqSource::Mapper]);
+
+		// The handler acknowledges the IRQ, so it runs once per frame
+		assert!(nes.run_until_write(0x0200));
+		assert_eq!(nes.cpu.last_write(), Some((0x0200, 0xC0)));
+		assert!(nes.run_until_pc(0xE015));
+		assert!(!nes.cpu.irq_line().is_asserted());
+		assert!(nes.run_until_pc(0xE018));
+		assert_eq!((nes.frame(), nes.cpu.ppu().scanline()), (1, 2));
+	}
+
+	#[test]
+	fn test_frame_irq() {
+		let mut nes = initialize(load_program_frame_irq);
+
+		// The APU frame counter raises IRQ at the end of its 4-step sequence
+		assert!(nes.run_until_pc(0x8004));
+		assert_eq!(nes.cpu.irq_line().sources(), vec![IrqSource::FrameCounter]);
+		assert!(nes.cpu.cycles() >= 29830);
+
+		// Reading $4015 acknowledges it
+		assert!(nes.run_until_write(0x0200));
+		assert_eq!(nes.cpu.last_write(), Some((0x0200, 0x40)));
+		assert!(nes.run_until_pc(0x8001));
+		assert!(!nes.cpu.irq_line().is_asserted());
+	}
+
+	#[test]
+	fn test_read_controller() {
+		let mut nes = initialize(load_program_read_controller);
+		nes.set_button(0, Button::B, true);
+
+		assert!(nes.run_until_write(0x0201));
+		assert_eq!((nes.peek(0x0200), nes.peek(0x0201)), (0, 1));
+		assert_eq!(nes.cpu.controller(0).latches(), 1);
+	}
+
+	#[test]
+	fn test_overclock() {
+		// CPU cycles and audio samples of one frame
+		let run_frame = |nes: &mut NES| {
+			let (cycles, samples) = (nes.cpu.cycles(), nes.cpu.apu().samples_generated());
+			nes.run_frame();
+			(nes.cpu.cycles() - cycles, nes.cpu.apu().samples_generated() - samples)
+		};
+		let mut nes = initialize(load_program_run_helpers);
+		nes.run_frames(2);
+		let (cycles, samples) = run_frame(&mut nes);
+
+		let mut overclocked = initialize(load_program_run_helpers);
+		overclocked.cpu.set_overclock(20);
+		overclocked.run_frames(2);
+		let (overclocked_cycles, overclocked_samples) = run_frame(&mut overclocked);
+
+		// 20 scanlines of 341 dots, 3 dots per CPU cycle. The APU doesn't see the extra cycles.
+		assert!((overclocked_cycles - cycles).abs_diff(20 * 341 / 3) <= 7);
+		assert!(overclocked_samples.abs_diff(samples) <= 1);
+	}
+
+	#[test]
+	fn test_new_from_prg_chr() {
+		// 16KB PRG is mirrored at $C000, so the reset vector is at the end of it
+		let mut rom_memory: [u8; 1024*32] = [0;1024*32];
+		load_program_run_helpers(&mut rom_memory);
+		set_reset_vector(&mut rom_memory, 0x8000);
+		let mut prg = rom_memory[..0x4000].to_vec();
+		prg[0x3FFC..].copy_from_slice(&rom_memory[0x7FFC..]);
+
+		let mut nes = NES::new_from_prg_chr(&prg, &[], 0, MirrorType::VERTICAL);
+		assert!(nes.run_until_write(0x0300));
+		assert_eq!(nes.cpu.registers().PC, 0x800D);
+	}
+
+	#[test]
+	fn test_expansion_area() {
+		let mut rom_memory: [u8; 1024*32] = [0;1024*32];
+		load_program_mmc5_exram(&mut rom_memory);
+		set_reset_vector(&mut rom_memory, 0xE000);
+		let cartridge = Cartridge::from_prg_chr(rom_memory.to_vec(), vec![], 5, MirrorType::HORIZONTAL, None).unwrap();
+		let mut nes = NES::new(cartridge);
+
+		assert!(nes.run_until_pc(0xE016));
+		assert_eq!(nes.peek(0x0200), 0x42);
+		assert_eq!(nes.peek(0x0201), 0x58);
+	}
+
+	#[test]
+	fn test_dmc_banked_samples() {
+		// VRC6 with 8 banks of 8KB: a sample of $FF bytes raises the output by 16 per byte, $00 lowers it
+		let mut program = [0; 1024*32];
+		load_program_dmc_banked_sample(&mut program);
+		let mut prg = vec![];
+		for byte in [0x00, 0x00, 0xFF, 0xFF, 0x00, 0xFF, 0x00] {
+			prg.extend([byte; 0x2000]);
+		}
+		let mut last_bank = [0; 0x2000];
+		last_bank[0x08E1..0x08E1 + 0x30].copy_from_slice(&program[..0x30]);
+		// The end of the bank and the vectors (all $E8E1) have as many 1 as 0 bits: the output ends where it started
+		last_bank[0x1FC0..0x1FFA].fill(0x55);
+		last_bank[0x1FFA..].copy_from_slice(&[0xE1, 0xE8].repeat(3));
+		prg.extend(last_bank);
+
+		// (16KB bank, 8KB bank, $4012, $4013) -> the address after the sample, the output
+		let cases = [
+			((0, 5, 0x00, 0x00), 0xC001, 64 + 16),	// 1 byte at $C000, of the 8KB bank
+			((0, 4, 0x00, 0x00), 0xC001, 64 - 16),
+			((1, 4, 0xFF, 0x04), 0x8001, 64 + 16),	// 65 bytes at $FFC0, the last one wraps to $8000 of the 16KB bank
+			((0, 5, 0xFF, 0x04), 0x8001, 64 - 16),
+		];
+		for (ram, address, output) in cases {
+			let mut nes = NES::new(Cartridge::from_prg_chr(prg.clone(), vec![], 24, MirrorType::VERTICAL, None).unwrap());
+			for (addr, value) in [ram.0, ram.1, ram.2, ram.3].into_iter().enumerate() {
+				nes.poke(addr as u16, value);
+			}
+			nes.run_frames(2);
+			let status = nes.cpu.apu().dmc_status();
+			assert_eq!((status.address, status.bytes_remaining, status.output), (address, 0, output), "{:?}", ram);
+			assert_eq!(nes.peek(0x4015) & 0x10, 0);
+		}
+	}
+
+	#[test]
+	fn test_prg_ram_size() {
+		let mut rom_memory: [u8; 1024*32] = [0;1024*32];
+		load_program_read_prg_ram(&mut rom_memory);
+		set_reset_vector(&mut rom_memory, 0x8000);
+
+		// 2KB of PRG RAM is mirrored, no PRG RAM is open bus: the last byte on the bus is the high byte of the address
+		for (prg_ram_size, expected) in [(None, 0x42), (Some(1024 * 2), 0x43), (Some(0), 0x60)] {
+			let cartridge = Cartridge::from_prg_chr(rom_memory.to_vec(), vec![], 0, MirrorType::HORIZONTAL, prg_ram_size).unwrap();
+			let mut nes = NES::new(cartridge);
+			nes.poke(0x6000, 0x42);
+			nes.poke(0x6800, 0x43);
+			assert!(nes.run_until_write(0x0200));
+			assert_eq!(nes.cpu.last_write(), Some((0x0200, expected)));
+		}
+	}
+
+	#[test]
+	fn test_reset() {
+		let mut nes = initialize(load_program_run_helpers);
+		assert!(nes.run_until_pc(0x800D));
+		assert_eq!(nes.cpu.registers().S, 0xFF);
+
+		// The registers and RAM keep their values, the program starts again
+		nes.reset();
+		let registers = nes.cpu.registers();
+		assert_eq!((registers.PC, registers.A, registers.S), (0x8000, 1, 0xFC));
+		assert!(registers.P.get(ProcessorStatusBits::InterruptDisable));
+		assert_eq!(nes.peek(0x0300), 1);
+		assert!(nes.run_until_write(0x0200));
+	}
+
+	#[test]
+	fn test_poke_ram() {
+		let mut nes = initialize(load_program_run_helpers);
+
+		nes.poke(0x0010, 0x99);
+		assert_eq!(nes.peek(0x0010), 0x99);
+		// Poke is not an instruction write
+		assert_eq!(nes.cpu.last_write(), None);
+	}
+
+	#[test]
+	fn test_scheduler() {
+		// The basic programs give the same results with both schedulers
+		let programs: [fn(&mut [u8; 1024 * 32]) -> u8; 12] = [load_program_stack, load_program_lda, load_program_adc,
+			load_program_absolute_store, load_program_index_increment, load_program_zeropage_store_load_and_memory_increment,
+			load_program_zeropage_x, load_program_absolute_indexed, load_program_cmp, load_program_transfers,
+			load_program_asl, load_program_ppudata];
+		for program in programs {
+			let [fast, accurate] = [Scheduler::Fast, Scheduler::Accurate].map(|scheduler| {
+				let mut nes = initialize(program);
+				nes.cpu.set_scheduler(scheduler);
+				let mut rom = [0; 1024 * 32];
+				for _ in 0..program(&mut rom) {
+					nes.step();
+				}
+				let registers = nes.cpu.registers();
+				let state = (registers.A, registers.X, registers.Y, registers.S, registers.PC, registers.P.flags, nes.cpu.cycles());
+				let ram: Vec<u8> = (0..0x800).map(|addr| nes.peek(addr)).collect();
+				let dots: Vec<_> = nes.cpu.events().current_frame().iter().map(|e| e.scanline as u32 * 341 + e.dot as u32).collect();
+				(state, ram, dots, nes.cpu.ppu().dot())
+			});
+			assert_eq!(fast.0, accurate.0);
+			assert_eq!(fast.1, accurate.1);
+			// The PPU is at the same place after each instruction
+			assert_eq!(fast.3, accurate.3);
+			// The accurate scheduler sees the PPU register accesses later, at their cycle inside the instruction (after the
+			// opcode and the operand fetches)
+			assert_eq!(fast.2.len(), accurate.2.len());
+			for (fast_dot, accurate_dot) in fast.2.iter().zip(&accurate.2) {
+				assert!((2 * 3..=7 * 3).contains(&(accurate_dot - fast_dot)));
+			}
+		}
+
+		// Both wait for vblank and run whole frames
+		let [fast, accurate] = [Scheduler::Fast, Scheduler::Accurate].map(|scheduler| {
+			let mut nes = initialize(load_program_ppu_status_poll);
+			nes.cpu.set_scheduler(scheduler);
+			assert!(nes.run_until_write(0x0200));
+			nes.run_frames(2);
+			(nes.frame(), nes.cpu.ppu().scanline())
+		});
+		assert_eq!(fast, accurate);
+	}
+
+	/// The cost of the accurate scheduler, on a program that is all memory accesses (a JMP loop).
+	/// Run with `cargo test --release bench_scheduler -- --ignored --nocapture`.
+	#[test]
+	#[ignore]
+	fn bench_scheduler() {
+		for scheduler in [Scheduler::Fast, Scheduler::Accurate] {
+			let mut nes = initialize(load_program_run_helpers);
+			nes.cpu.set_scheduler(scheduler);
+			let start = Instant::now();
+			nes.run_frames(600);
+			let elapsed = start.elapsed();
+			println!("{}: 600 frames in {:.2}s ({:.0} FPS)", scheduler.name(), elapsed.as_secs_f64(), 600.0 / elapsed.as_secs_f64());
+		}
+	}
+
+	/// The threaded code gives the same state as the interpreter, also when a mapper switches the bank of the running code.
+	#[test]
+	#[cfg(feature = "threaded")]
+	fn test_block_cache() {
+		// FME-7 with 4 banks of 8KB. Each bank at $8000: STA $A000 (switch the bank), LDA #$11 * bank, RTS. Bank 3 at
+		// $E000: select the bank at $8000, bank 1, JSR $8000 with A = 2, so the LDA comes from bank 2.
+		let mut prg = vec![0; 1024 * 32];
+		for bank in 0..4 {
+			prg[bank * 0x2000..bank * 0x2000 + 6].copy_from_slice(&[0x8D, 0x00, 0xA0, 0xA9, 0x11 * bank as u8, 0x60]);
+		}
+		prg[0x6000..0x6014].copy_from_slice(&[0xA9, 0x09, 0x8D, 0x00, 0x80, 0xA9, 0x01, 0x8D, 0x00, 0xA0, 0xA9, 0x02, 0x20,
+			0x00, 0x80, 0x85, 0x00, 0x4C, 0x11, 0xE0]);
+		prg[0x7FFC..0x7FFE].copy_from_slice(&[0x00, 0xE0]);
+		let nestest = || {
+			let mut nes = NES::new_open_rom_file("6502asm_programs/nestest/nestest.nes");
+			// The automated mode
+			nes.poke(0xFFFC, 0x00);
+			nes.poke(0xFFFD, 0xC0);
+			nes.reset();
+			nes
+		};
+		let machines: [Box<dyn Fn() -> NES>; 3] = [
+			Box::new(|| NES::new(Cartridge::from_prg_chr(prg.clone(), vec![], 69, MirrorType::HORIZONTAL, None).unwrap())),
+			Box::new(nestest),
+			Box::new(|| initialize(load_program_run_helpers)),
+		];
+		for new in machines {
+			let [threaded, interpreted] = [true, false].map(|enabled| {
+				let mut nes = new();
+				nes.cpu.set_block_cache(enabled);
+				for _ in 0..10_000 {
+					nes.step();
+				}
+				nes.save_state()
+			});
+			assert!(threaded == interpreted);
+		}
+		let mut nes = NES::new(Cartridge::from_prg_chr(prg, vec![], 69, MirrorType::HORIZONTAL, None).unwrap());
+		nes.run_until_pc(0xE011);
+		assert_eq!(nes.peek(0x00), 0x22);
+	}
+
+	/// The speed of the threaded code and the interpreter. The PPU takes most of a frame, the CPU time is apart.
+	/// Run with `cargo test --release --features threaded bench_block_cache -- --ignored --nocapture`.
+	#[test]
+	#[ignore]
+	#[cfg(feature = "threaded")]
+	fn bench_block_cache() {
+		for enabled in [false, true] {
+			let mut nes = initialize(load_program_run_helpers);
+			nes.cpu.set_block_cache(enabled);
+			nes.set_subsystem_times(true);
+			let start = Instant::now();
+			let mut cpu_time = std::time::Duration::ZERO;
+			for _ in 0..600 {
+				nes.run_frames(1);
+				cpu_time += nes.stats().last_frame.cpu_time;
+			}
+			let elapsed = start.elapsed();
+			let name = if enabled { "Threaded" } else { "Interpreter" };
+			println!("{}: 600 frames in {:.2}s ({:.0} FPS), CPU {:.2}s", name, elapsed.as_secs_f64(), 600.0 / elapsed.as_secs_f64(),
+				cpu_time.as_secs_f64());
+		}
+	}
+
+	/// The cost of a fork, against a save state loaded into another machine. The ROM is shared, so a big ROM (MMC5 with
+	/// 512KB of PRG ROM and 512KB of CHR ROM) costs as much as a small one.
+	/// Run with `cargo test --release bench_fork -- --ignored --nocapture`.
+	#[test]
+	#[ignore]
+	fn bench_fork() {
+		let machines: [(&str, NES); 2] = [
+			("NROM 32KB", initialize(load_program_count_a_presses)),
+			("MMC5 1MB", NES::new(Cartridge::from_prg_chr(vec![0; 1024 * 512], vec![0; 1024 * 512], 5, MirrorType::HORIZONTAL, None).unwrap())),
+		];
+		for (name, mut nes) in machines {
+			nes.run_frames(10);
+			let start = Instant::now();
+			for _ in 0..10_000 {
+				std::hint::black_box(nes.fork());
+			}
+			let fork = start.elapsed() / 10_000;
+
+			let mut other = nes.fork();
+			let start = Instant::now();
+			for _ in 0..10_000 {
+				other.load_state(nes.save_state()).unwrap();
+			}
+			let state = start.elapsed() / 10_000;
+			println!("{}: fork {:.1}us ({:.0}/s), save and load state {:.1}us", name, fork.as_secs_f64() * 1e6, 1.0 / fork.as_secs_f64(),
+				state.as_secs_f64() * 1e6);
+		}
+	}
+
+	/// The speed of the renderers, with the background and the sprites shown.
+	/// Run with `cargo test --release bench_renderer -- --ignored --nocapture`.
+	#[test]
+	#[ignore]
+	fn bench_renderer() {
+		for renderer in [Renderer::Dot, Renderer::Scanline] {
+			let mut nes = initialize(load_program_run_helpers);
+			nes.cpu.ppu_mut().set_renderer(renderer);
+			nes.poke(0x2001, 0b0001_1110);
+			let start = Instant::now();
+			nes.run_frames(600);
+			let elapsed = start.elapsed();
+			println!("{}: 600 frames in {:.2}s ({:.0} FPS)", renderer.name(), elapsed.as_secs_f64(), 600.0 / elapsed.as_secs_f64());
+		}
+	}
+}
@@ -1,67 +1,256 @@
+use std::f32::consts::PI;
 
-pub const palette: [(u8, u8, u8); 64] = [
-    (0x52, 0x52, 0x52), /* 0x00 */
-    (0x01, 0x1a, 0x51), /* 0x01 */
-    (0x0f, 0x0f, 0x65), /* 0x02 */
-    (0x23, 0x06, 0x63), /* 0x03 */
-    (0x36, 0x03, 0x4b), /* 0x04 */
-    (0x40, 0x04, 0x26), /* 0x05 */
-    (0x3f, 0x09, 0x04), /* 0x06 */
-    (0x32, 0x13, 0x00), /* 0x07 */
-    (0x1f, 0x20, 0x00), /* 0x08 */
-    (0x0b, 0x2a, 0x00), /* 0x09 */
-    (0x00, 0x2f, 0x00), /* 0x0a */
-    (0x00, 0x2e, 0x0a), /* 0x0b */
-    (0x00, 0x26, 0x2d), /* 0x0c */
-    (0x00, 0x00, 0x00), /* 0x0d */
-    (0x00, 0x00, 0x00), /* 0x0e */
-    (0x00, 0x00, 0x00), /* 0x0f */
-    (0xa0, 0xa0, 0xa0), /* 0x10 */
-    (0x1e, 0x4a, 0x9d), /* 0x11 */
-    (0x38, 0x37, 0xbc), /* 0x12 */
-    (0x58, 0x28, 0xb8), /* 0x13 */
-    (0x75, 0x21, 0x94), /* 0x14 */
-    (0x84, 0x23, 0x5c), /* 0x15 */
-    (0x82, 0x2e, 0x24), /* 0x16 */
-    (0x6f, 0x3f, 0x00), /* 0x17 */
-    (0x51, 0x52, 0x00), /* 0x18 */
-    (0x31, 0x63, 0x00), /* 0x19 */
-    (0x1a, 0x6b, 0x05), /* 0x1a */
-    (0x0e, 0x69, 0x2e), /* 0x1b */
-    (0x10, 0x5c, 0x68), /* 0x1c */
-    (0x00, 0x00, 0x00), /* 0x1d */
-    (0x00, 0x00, 0x00), /* 0x1e */
-    (0x00, 0x00, 0x00), /* 0x1f */
-    (0xfe, 0xff, 0xff), /* 0x20 */
-    (0x69, 0x9e, 0xfc), /* 0x21 */
-    (0x89, 0x87, 0xff), /* 0x22 */
-    (0xae, 0x76, 0xff), /* 0x23 */
-    (0xce, 0x6d, 0xf1), /* 0x24 */
-    (0xe0, 0x70, 0xb2), /* 0x25 */
-    (0xde, 0x7c, 0x70), /* 0x26 */
-    (0xc8, 0x91, 0x3e), /* 0x27 */
-    (0xa6, 0xa7, 0x25), /* 0x28 */
-    (0x81, 0xba, 0x28), /* 0x29 */
-    (0x63, 0xc4, 0x46), /* 0x2a */
-    (0x54, 0xc1, 0x7d), /* 0x2b */
-    (0x56, 0xb3, 0xc0), /* 0x2c */
-    (0x3c, 0x3c, 0x3c), /* 0x2d */
-    (0x00, 0x00, 0x00), /* 0x2e */
-    (0x00, 0x00, 0x00), /* 0x2f */
-    (0xfe, 0xff, 0xff), /* 0x30 */
-    (0xbe, 0xd6, 0xfd), /* 0x31 */
-    (0xcc, 0xcc, 0xff), /* 0x32 */
-    (0xdd, 0xc4, 0xff), /* 0x33 */
-    (0xea, 0xc0, 0xf9), /* 0x34 */
-    (0xf2, 0xc1, 0xdf), /* 0x35 */
-    (0xf1, 0xc7, 0xc2), /* 0x36 */
-    (0xe8, 0xd0, 0xaa), /* 0x37 */
-    (0xd9, 0xda, 0x9d), /* 0x38 */
-    (0xc9, 0xe2, 0x9e), /* 0x39 */
-    (0xbc, 0xe6, 0xae), /* 0x3a */
-    (0xb4, 0xe5, 0xc7), /* 0x3b */
-    (0xb5, 0xdf, 0xe4), /* 0x3c */
-    (0xa9, 0xa9, 0xa9), /* 0x3d */
-    (0x00, 0x00, 0x00), /* 0x3e */
-    (0x00, 0x00, 0x00), /* 0x3f */
-];
\ No newline at end of file
+use crate::config::Config;
+use crate::rom_parser::TVSystem;
+
+/// The voltages of the PPU video signal, low and high of the square wave of each brightness level (bits 4-5 of the
+/// color). Read here: https://www.nesdev.org/wiki/NTSC_video
+const SIGNAL_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const SIGNAL_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const BLACK: f32 = 0.518;
+const WHITE: f32 = 1.962;
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+/// The 2C07 (PAL PPU) generates the colors 15 degrees off the 2C02 ones.
+const PAL_HUE_SHIFT: f32 = -15.0;
+
+/// How the TV decodes the PPU video signal to RGB.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PaletteSettings {
+    pub tv_system: TVSystem,    // PAL: the 2C07 PPU, with other hues and the red and green emphasis bits swapped
+    pub hue: f32,               // Degrees, added to the phase of the colors (the tint knob)
+    pub saturation: f32,        // 1.0 is the signal as is, 0.0 is greyscale
+    pub gamma: f32,             // Of the screen, the signal is made for 2.2. Lower values are darker.
+    pub brightness: f32,        // Added to the luma, 0.0 is the signal as is
+    pub contrast: f32,          // Multiplies the signal, 1.0 is the signal as is
+}
+
+/// The palette settings that can be adjusted while the game runs (the window: F6 and -/+).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VideoSetting {
+    Brightness,
+    Contrast,
+    Saturation,
+    Hue,
+    Gamma,
+}
+
+impl VideoSetting {
+    pub const ALL: [VideoSetting; 5] = [VideoSetting::Brightness, VideoSetting::Contrast, VideoSetting::Saturation, VideoSetting::Hue, VideoSetting::Gamma];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            VideoSetting::Brightness => "brightness",
+            VideoSetting::Contrast => "contrast",
+            VideoSetting::Saturation => "saturation",
+            VideoSetting::Hue => "hue",
+            VideoSetting::Gamma => "gamma",
+        }
+    }
+
+    /// The next one, after the last comes the first.
+    pub fn next(self) -> VideoSetting {
+        let index = VideoSetting::ALL.iter().position(|&setting| setting == self).unwrap();
+        VideoSetting::ALL[(index + 1) % VideoSetting::ALL.len()]
+    }
+
+    /// The change of one key press, and the range.
+    fn step(self) -> (f32, f32, f32) {
+        match self {
+            VideoSetting::Brightness => (0.02, -0.5, 0.5),
+            VideoSetting::Contrast => (0.05, 0.0, 2.0),
+            VideoSetting::Saturation => (0.05, 0.0, 2.0),
+            VideoSetting::Hue => (5.0, -180.0, 180.0),
+            VideoSetting::Gamma => (0.1, 1.0, 3.0),
+        }
+    }
+}
+
+impl Default for PaletteSettings {
+    fn default() -> Self {
+        PaletteSettings {
+            tv_system: TVSystem::NTSC,
+            hue: 0.0,
+            saturation: 1.0,
+            gamma: 1.8,
+            brightness: 0.0,
+            contrast: 1.0,
+        }
+    }
+}
+
+impl PaletteSettings {
+    /// The settings from the settings file: `palette.tv_system` (`ntsc` or `pal`, the ROM's TV system otherwise),
+    /// `palette.hue`, `palette.saturation`, `palette.gamma`, `palette.brightness` and `palette.contrast`.
+    pub fn from_config(config: &Config, tv_system: TVSystem) -> Self {
+        let default = PaletteSettings::default();
+        PaletteSettings {
+            tv_system: match config.get("palette.tv_system", String::new()).as_str() {
+                "ntsc" => TVSystem::NTSC,
+                "pal" => TVSystem::PAL,
+                _ => tv_system,
+            },
+            hue: config.get("palette.hue", default.hue),
+            saturation: config.get("palette.saturation", default.saturation),
+            gamma: config.get("palette.gamma", default.gamma),
+            brightness: config.get("palette.brightness", default.brightness),
+            contrast: config.get("palette.contrast", default.contrast),
+        }
+    }
+
+    /// Save the adjustable settings to the settings file. The TV system stays as it was.
+    pub fn write_config(&self, config: &mut Config) {
+        for setting in VideoSetting::ALL {
+            config.set(&format!("palette.{}", setting.name()), &self.get(setting).to_string());
+        }
+    }
+
+    pub fn get(&self, setting: VideoSetting) -> f32 {
+        match setting {
+            VideoSetting::Brightness => self.brightness,
+            VideoSetting::Contrast => self.contrast,
+            VideoSetting::Saturation => self.saturation,
+            VideoSetting::Hue => self.hue,
+            VideoSetting::Gamma => self.gamma,
+        }
+    }
+
+    /// Change a setting by `steps` key presses (negative to lower it), within its range.
+    pub fn adjust(&mut self, setting: VideoSetting, steps: i32) {
+        let (step, min, max) = setting.step();
+        // Rounded to the step, so the value is e.g. 1.1 and not 1.0999999
+        let value = ((self.get(setting) / step).round() + steps as f32) * step;
+        let value = ((value.clamp(min, max) * 100.0).round()) / 100.0;
+        match setting {
+            VideoSetting::Brightness => self.brightness = value,
+            VideoSetting::Contrast => self.contrast = value,
+            VideoSetting::Saturation => self.saturation = value,
+            VideoSetting::Hue => self.hue = value,
+            VideoSetting::Gamma => self.gamma = value,
+        }
+    }
+}
+
+/// The RGB color of each NES color index (0x00-0x3F) with each color emphasis (PPUMASK bits 5-7).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+    settings: PaletteSettings,
+    colors: [(u8, u8, u8); 8 * 64],  // 64 colors for each emphasis
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::new(&PaletteSettings::default())
+    }
+}
+
+impl Palette {
+    /// Generate the palette: the signal of each color over a color cycle (12 phases), decoded like a TV does (YIQ).
+    pub fn new(settings: &PaletteSettings) -> Self {
+        let hue = match settings.tv_system {
+            TVSystem::PAL => settings.hue + PAL_HUE_SHIFT,
+            _ => settings.hue,
+        };
+        let colors = std::array::from_fn(|index| {
+            let (color, mut emphasis) = ((index & 0x3F) as u8, (index >> 6) as u8);
+            if settings.tv_system == TVSystem::PAL {
+                emphasis = (emphasis & 0b100) | ((emphasis & 1) << 1) | ((emphasis >> 1) & 1);
+            }
+            let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+            for phase in 0..12 {
+                let level = signal(color, emphasis, phase);
+                let angle = PI * (phase as f32 + 4.0 + hue / 30.0) / 6.0;
+                y += level;
+                i += level * angle.cos();
+                q += level * angle.sin();
+            }
+            let chroma = settings.saturation * settings.contrast / 12.0;
+            let (y, i, q) = (y / 12.0 * settings.contrast + settings.brightness, i * chroma, q * chroma);
+
+            let gamma = |value: f32| (value.clamp(0.0, 1.0).powf(2.2 / settings.gamma) * 255.0).round() as u8;
+            (
+                gamma(y + 0.956 * i + 0.621 * q),
+                gamma(y - 0.272 * i - 0.647 * q),
+                gamma(y - 1.106 * i + 1.703 * q),
+            )
+        });
+        Palette { settings: *settings, colors }
+    }
+
+    /// The settings it was generated with.
+    pub fn settings(&self) -> &PaletteSettings {
+        &self.settings
+    }
+
+    /// The RGB color of a NES color index (0x00-0x3F) with the PPUMASK color emphasis bits (bit 0 red, 1 green, 2 blue).
+    pub fn rgb(&self, color: u8, emphasis: u8) -> (u8, u8, u8) {
+        self.colors[(emphasis as usize & 7) * 64 + (color as usize & 0x3F)]
+    }
+}
+
+/// The signal level (0.0 black, 1.0 white) of a color at a phase of the color cycle. The hue (bits 0-3) is the phase of
+/// a square wave between the low and high voltage of the brightness. Emphasis attenuates the phases of its color.
+fn signal(color: u8, emphasis: u8, phase: usize) -> f32 {
+    let hue = (color & 0x0F) as usize;
+    // Colors $xE and $xF are black, $x0 is a grey without the wave, $xD is the low voltage only
+    let level = if hue > 13 { 1 } else { (color >> 4) as usize };
+    let in_phase = |hue: usize| (hue + phase) % 12 < 6;
+    let voltage = match hue {
+        0 => SIGNAL_HIGH[level],
+        13.. => SIGNAL_LOW[level],
+        _ if in_phase(hue) => SIGNAL_HIGH[level],
+        _ => SIGNAL_LOW[level],
+    };
+    let attenuated = (emphasis & 1 != 0 && in_phase(0)) || (emphasis & 2 != 0 && in_phase(4)) || (emphasis & 4 != 0 && in_phase(8));
+    let voltage = if attenuated { voltage * EMPHASIS_ATTENUATION } else { voltage };
+    (voltage - BLACK) / (WHITE - BLACK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Palette, PaletteSettings, VideoSetting};
+    use crate::{config::Config, rom_parser::TVSystem};
+
+    #[test]
+    fn test_generate() {
+        let ntsc = Palette::default();
+        // The greys, black and white
+        assert_eq!(ntsc.rgb(0x00, 0), (0x53, 0x53, 0x53));
+        assert_eq!(ntsc.rgb(0x10, 0), (0xA0, 0xA0, 0xA0));
+        assert_eq!(ntsc.rgb(0x0D, 0), (0, 0, 0));
+        assert_eq!(ntsc.rgb(0x30, 0), (0xFF, 0xFF, 0xFF));
+        // Red, green and blue
+        let (r, g, b) = ntsc.rgb(0x16, 0);
+        assert!(r > 2 * g && r > 2 * b);
+        let (r, g, b) = ntsc.rgb(0x1A, 0);
+        assert!(g > 2 * r && g > 2 * b);
+        let (r, g, b) = ntsc.rgb(0x12, 0);
+        assert!(b > 2 * r && b > 2 * g);
+
+        // PAL has other hues, and the green emphasis bit of the 2C02 is red on the 2C07
+        let pal = Palette::new(&PaletteSettings { tv_system: TVSystem::PAL, ..PaletteSettings::default() });
+        assert_ne!(pal.rgb(0x16, 0), ntsc.rgb(0x16, 0));
+        assert_eq!(pal.rgb(0x20, 0b010), Palette::new(&PaletteSettings { hue: -15.0, ..PaletteSettings::default() }).rgb(0x20, 0b001));
+
+        // No saturation is grey
+        let grey = Palette::new(&PaletteSettings { saturation: 0.0, ..PaletteSettings::default() });
+        let (r, g, b) = grey.rgb(0x16, 0);
+        assert!(r == g && g == b);
+    }
+    #[test]
+    fn test_adjust() {
+        let mut settings = PaletteSettings::default();
+        settings.adjust(VideoSetting::Gamma, 3);
+        settings.adjust(VideoSetting::Hue, -40);
+        settings.adjust(VideoSetting::Brightness, 1);
+        assert_eq!((settings.gamma, settings.hue, settings.brightness), (2.1, -180.0, 0.02));
+        assert_eq!(VideoSetting::Gamma.next(), VideoSetting::Brightness);
+
+        // Brighter, and saved to the settings file
+        let (r, _, _) = Palette::new(&settings).rgb(0x00, 0);
+        assert!(r > Palette::new(&PaletteSettings { brightness: 0.0, ..settings }).rgb(0x00, 0).0);
+        let mut config = Config::parse("palette.tv_system = pal\n");
+        settings.write_config(&mut config);
+        assert_eq!(PaletteSettings::from_config(&config, TVSystem::NTSC), PaletteSettings { tv_system: TVSystem::PAL, ..settings });
+    }
+}
@@ -0,0 +1,66 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use super::colors::Palette;
+use super::ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
+
+/// A pixel that the layer doesn't cover. PPU colors are 0x00-0x3F.
+pub const TRANSPARENT: u8 = 0xFF;
+
+/// The background and the sprites, each in its own framebuffer (NES color indexes or `TRANSPARENT`), next to the
+/// combined picture of `PPU::framebuffer`. Sprites behind the background are in the sprite layer too, so priority bugs
+/// are easy to see.
+#[derive(Clone)]
+pub struct Layers {
+    pub background: Vec<u8>,    // Without the backdrop color
+    pub sprites: Vec<u8>,
+}
+
+impl Default for Layers {
+    fn default() -> Self {
+        Layers::new()
+    }
+}
+
+impl Layers {
+    pub fn new() -> Self {
+        Layers {
+            background: vec![TRANSPARENT; SCREEN_WIDTH * SCREEN_HEIGHT],
+            sprites: vec![TRANSPARENT; SCREEN_WIDTH * SCREEN_HEIGHT],
+        }
+    }
+}
+
+/// Which picture the window shows.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Layer {
+    Combined,
+    Background,
+    Sprites,
+}
+
+impl Layer {
+    pub fn next(self) -> Self {
+        match self {
+            Layer::Combined => Layer::Background,
+            Layer::Background => Layer::Sprites,
+            Layer::Sprites => Layer::Combined,
+        }
+    }
+}
+
+/// Save a 256x240 framebuffer as a PAM image (RGB with alpha, `TRANSPARENT` pixels are transparent). GIMP and
+/// ImageMagick open it.
+pub fn save_pam(path: &str, pixels: &[u8], palette: &Palette) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write!(file, "P7\nWIDTH {}\nHEIGHT {}\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n", SCREEN_WIDTH, SCREEN_HEIGHT)?;
+    for &color in pixels {
+        if color == TRANSPARENT {
+            file.write_all(&[0, 0, 0, 0])?;
+        } else {
+            let (r, g, b) = palette.rgb(color, 0);
+            file.write_all(&[r, g, b, 255])?;
+        }
+    }
+    file.flush()
+}
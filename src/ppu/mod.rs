@@ -1,3 +1,4 @@
-mod colors;
+pub mod colors;
+pub mod layers;
 
 pub mod ppu;
\ No newline at end of file
@@ -1,22 +1,156 @@
 use crate::{
     cartridge::Cartridge,
     common::{self, bits, CHR_Bank},
+    mapper::PpuFetch,
+    ppu::{colors::{Palette, PaletteSettings}, layers::{Layers, TRANSPARENT}},
+    savestate::{Serialize, Serializer},
+    state_dump::PpuState,
 };
 
 use log::{debug, error, warn};
 
+#[derive(Clone)]
 pub struct PPU {
     // active_chr_rom_num: u8,
     // oam_data: [u8; 256],
     // mirroring: MirrorType
     registers: [u8; 8],
-    pattern_tables: [u8; 1024 * 8], // PPU address space: 0x0000-0x1FFF
     name_table: [u8; 2048],  		// PPU address space: 0x2000-0x3EFF
     palette_table: [u8; 32], 		// PPU address space: 0x3F00-0x3FFF (Background palette: 0x3F00-0x3F10 and Sprite palette: 0x3F10-0x3FFF)
 
     pub ppu_status: u8,
+    oam_addr: u8,
+    oam: [u8; 256],
+
+    // Internal registers, read here: https://www.nesdev.org/wiki/PPU_scrolling
+    v: u16,          // Current VRAM address (15 bits)
+    t: u16,          // Temporary VRAM address (15 bits), the address of the top left onscreen tile
+    x: u8,           // Fine X scroll (3 bits)
+    w: bool,         // First or second write toggle, shared by PPUSCROLL and PPUADDR
+    read_buffer: u8, // PPUDATA reads are delayed by one read, except for palette
+
+    // Timing. A frame is 262 scanlines of 341 dots, the PPU does 3 dots per CPU cycle.
+    scanline: u16, // 0-239 visible, 240 post-render, 241-260 vblank, 261 pre-render
+    dot: u16,      // 0-340
+    frame: u64,
+    nmi_pending: bool,
+    warm_up: bool,    // Emulate the warm-up after power on and reset, see `set_warm_up`
+    warming_up: bool, // PPUCTRL, PPUMASK, PPUSCROLL and PPUADDR writes are ignored until the pre-render scanline
+
+    bg: BackgroundShifters,
+
+    // Sprites found by the sprite evaluation of the previous scanline (max 8, unless the sprite limit is disabled)
+    sprites: [SpriteSlot; 64],
+    secondary_oam: [u8; 32], // The sprites the evaluation found for the next scanline, 4 bytes each
+    evaluation: SpriteEvaluation,
+    sprite_count: usize,
+    sprite_limit: bool,
+    sprite_rotation: bool, // The sprite evaluation starts at another sprite each frame, see `evaluation_oam_index`
+
+    renderer: Renderer,
+    framebuffer: [u8; FRAMEBUFFER_SIZE], // 256x240 NES color indexes (0x00-0x3F), inline: written every dot
+    emphasis: [u8; SCREEN_HEIGHT], // PPUMASK color emphasis bits (5-7, shifted down) of each scanline of the framebuffer
+    palette_lut: Option<&'static [u8; 64]>, // VS System RP2C04 PPUs output the colors in a different order
+    rgb_palette: Palette, // The colors the TV shows, for the NES color indexes of the framebuffer
+    layers: Option<Box<Layers>>, // Debug render of the background and sprites apart, None when disabled
+}
+
+/// How the PPU draws the picture.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Renderer {
+    /// Like the hardware: the background is fetched and drawn dot by dot, so register writes in the middle of a
+    /// scanline (mid-scanline scroll or palette changes) show up where they happen.
+    Dot,
+    /// Each visible scanline is drawn at once at its first dot, from the registers at that time. Faster, games without
+    /// mid-scanline effects look the same. The scroll updates, sprite evaluation and the mapper still see every dot.
+    Scanline,
+}
+
+impl Renderer {
+    /// `dot` or `scanline`, for the command line and the debugger.
+    pub fn parse(name: &str) -> Option<Renderer> {
+        match name {
+            "dot" => Some(Renderer::Dot),
+            "scanline" => Some(Renderer::Scanline),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Renderer::Dot => "dot",
+            Renderer::Scanline => "scanline",
+        }
+    }
+}
+
+/// The progress of the sprite evaluation of the current scanline, see `PPU::evaluate_sprites_dot`.
+#[derive(Clone, Copy, Default)]
+struct SpriteEvaluation {
+    data: u8,              // The OAM byte read on the odd dot, written to the secondary OAM on the even dot
+    found: u8,             // Sprites copied to the secondary OAM
+    byte: u8,              // 0 when checking a Y, 1-3 when copying the rest of a sprite in range
+    overflow_search: bool, // 8 sprites found, the next ones in range set the sprite overflow flag
+    done: bool,            // All the sprites were checked
+    sprite_zero: bool,     // The first sprite checked is in range, it can set sprite 0 hit
+}
+
+impl Serialize for SpriteEvaluation {
+    fn serialize(&mut self, s: &mut Serializer) {
+        s.value(&mut self.data);
+        s.value(&mut self.found);
+        s.value(&mut self.byte);
+        s.value(&mut self.overflow_search);
+        s.value(&mut self.done);
+        s.value(&mut self.sprite_zero);
+    }
+}
+
+/// Background rendering, like the real PPU: the next tile is fetched over 8 dots, then loaded into the low byte of 16
+/// bit shift registers, which shift every dot. Fine X selects the bit. Together, they are read every dot.
+#[derive(Clone, Copy, Default)]
+struct BackgroundShifters {
+    next_tile: u8,
+    next_attribute: u8,
+    next_pattern_low: u8,
+    next_pattern_high: u8,
+    pattern_low: u16,
+    pattern_high: u16,
+    attribute_low: u16,
+    attribute_high: u16,
+}
+
+impl Serialize for BackgroundShifters {
+    fn serialize(&mut self, s: &mut Serializer) {
+        s.value(&mut self.next_tile);
+        s.value(&mut self.next_attribute);
+        s.value(&mut self.next_pattern_low);
+        s.value(&mut self.next_pattern_high);
+        s.value(&mut self.pattern_low);
+        s.value(&mut self.pattern_high);
+        s.value(&mut self.attribute_low);
+        s.value(&mut self.attribute_high);
+    }
+}
+
+/// A sprite that is drawn on the current scanline.
+#[derive(Clone, Copy, Default)]
+struct SpriteSlot {
+    x: u8,
+    attributes: u8,
+    pattern_low: u8, // Already flipped horizontally if needed
+    pattern_high: u8,
+    sprite_zero: bool,
 }
 
+pub const DOTS_PER_SCANLINE: u16 = 341;
+pub const SCANLINES_PER_FRAME: u16 = 262;
+pub const VBLANK_SCANLINE: u16 = 241;
+pub const PRE_RENDER_SCANLINE: u16 = 261;
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+pub const FRAMEBUFFER_SIZE: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
+
 /*
 Control Register 1 (PPUCTRL) - 		CPU address: 0x2000
 Control Register 2 (PPUMASK) - 		CPU address: 0x2001
@@ -39,164 +173,1448 @@ impl PPU {
     // }
 
     pub fn new(cartridge: &Cartridge) -> Self {
-        // CHR ROM must have at least 1 bank, there can't be 0 CHR ROM data. In case of testing, we fill zeros.
-        let mut pattern_tables: [u8; 1024 * 8] = [0; 1024 * 8];
-		let mut name_table: [u8; 2048] = [0; 2048];
-
-        //TODO: We need to handle more than 1 CHR ROM bank. For now I just want NES program to work.
-        let first_chr_rom_bank = cartridge.chr_rom.get(0);
-
-        if first_chr_rom_bank.is_some() {
-            // Copy CHR ROM data from cartridge to local scope, and now PPU will own this cloned data.
-            pattern_tables.copy_from_slice(&first_chr_rom_bank.unwrap()[0..1024 * 8]);
-
-			// Init name table
-			//name_table.copy_From_slice(&first_chr_rom_bank.unwrap()[])
-        }
-
+        // The pattern tables (0x0000-0x1FFF) are in the cartridge, the mapper decides which CHR banks the PPU sees.
         //TODO: Init name_table and palette table
 
-        let system_palette: [(u8, u8, u8); 64] = [
-            (0x52, 0x52, 0x52), /* 0x00 */
-            (0x01, 0x1a, 0x51), /* 0x01 */
-            (0x0f, 0x0f, 0x65), /* 0x02 */
-            (0x23, 0x06, 0x63), /* 0x03 */
-            (0x36, 0x03, 0x4b), /* 0x04 */
-            (0x40, 0x04, 0x26), /* 0x05 */
-            (0x3f, 0x09, 0x04), /* 0x06 */
-            (0x32, 0x13, 0x00), /* 0x07 */
-            (0x1f, 0x20, 0x00), /* 0x08 */
-            (0x0b, 0x2a, 0x00), /* 0x09 */
-            (0x00, 0x2f, 0x00), /* 0x0a */
-            (0x00, 0x2e, 0x0a), /* 0x0b */
-            (0x00, 0x26, 0x2d), /* 0x0c */
-            (0x00, 0x00, 0x00), /* 0x0d */
-            (0x00, 0x00, 0x00), /* 0x0e */
-            (0x00, 0x00, 0x00), /* 0x0f */
-            (0xa0, 0xa0, 0xa0), /* 0x10 */
-            (0x1e, 0x4a, 0x9d), /* 0x11 */
-            (0x38, 0x37, 0xbc), /* 0x12 */
-            (0x58, 0x28, 0xb8), /* 0x13 */
-            (0x75, 0x21, 0x94), /* 0x14 */
-            (0x84, 0x23, 0x5c), /* 0x15 */
-            (0x82, 0x2e, 0x24), /* 0x16 */
-            (0x6f, 0x3f, 0x00), /* 0x17 */
-            (0x51, 0x52, 0x00), /* 0x18 */
-            (0x31, 0x63, 0x00), /* 0x19 */
-            (0x1a, 0x6b, 0x05), /* 0x1a */
-            (0x0e, 0x69, 0x2e), /* 0x1b */
-            (0x10, 0x5c, 0x68), /* 0x1c */
-            (0x00, 0x00, 0x00), /* 0x1d */
-            (0x00, 0x00, 0x00), /* 0x1e */
-            (0x00, 0x00, 0x00), /* 0x1f */
-            (0xfe, 0xff, 0xff), /* 0x20 */
-            (0x69, 0x9e, 0xfc), /* 0x21 */
-            (0x89, 0x87, 0xff), /* 0x22 */
-            (0xae, 0x76, 0xff), /* 0x23 */
-            (0xce, 0x6d, 0xf1), /* 0x24 */
-            (0xe0, 0x70, 0xb2), /* 0x25 */
-            (0xde, 0x7c, 0x70), /* 0x26 */
-            (0xc8, 0x91, 0x3e), /* 0x27 */
-            (0xa6, 0xa7, 0x25), /* 0x28 */
-            (0x81, 0xba, 0x28), /* 0x29 */
-            (0x63, 0xc4, 0x46), /* 0x2a */
-            (0x54, 0xc1, 0x7d), /* 0x2b */
-            (0x56, 0xb3, 0xc0), /* 0x2c */
-            (0x3c, 0x3c, 0x3c), /* 0x2d */
-            (0x00, 0x00, 0x00), /* 0x2e */
-            (0x00, 0x00, 0x00), /* 0x2f */
-            (0xfe, 0xff, 0xff), /* 0x30 */
-            (0xbe, 0xd6, 0xfd), /* 0x31 */
-            (0xcc, 0xcc, 0xff), /* 0x32 */
-            (0xdd, 0xc4, 0xff), /* 0x33 */
-            (0xea, 0xc0, 0xf9), /* 0x34 */
-            (0xf2, 0xc1, 0xdf), /* 0x35 */
-            (0xf1, 0xc7, 0xc2), /* 0x36 */
-            (0xe8, 0xd0, 0xaa), /* 0x37 */
-            (0xd9, 0xda, 0x9d), /* 0x38 */
-            (0xc9, 0xe2, 0x9e), /* 0x39 */
-            (0xbc, 0xe6, 0xae), /* 0x3a */
-            (0xb4, 0xe5, 0xc7), /* 0x3b */
-            (0xb5, 0xdf, 0xe4), /* 0x3c */
-            (0xa9, 0xa9, 0xa9), /* 0x3d */
-            (0x00, 0x00, 0x00), /* 0x3e */
-            (0x00, 0x00, 0x00), /* 0x3f */
-        ];
         let palette_table: [u8; 32] = [0; 32];
 
         PPU {
             registers: [0; 8],
-            pattern_tables,
             name_table: [0; 2048],
             palette_table,
             ppu_status: 0,
+            oam_addr: 0,
+            oam: [0; 256],
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+            read_buffer: 0,
+            scanline: 0,
+            dot: 0,
+            frame: 0,
+            nmi_pending: false,
+            warm_up: true,
+            warming_up: true,
+            bg: BackgroundShifters::default(),
+            sprites: [SpriteSlot::default(); 64],
+            sprite_count: 0,
+            secondary_oam: [0xFF; 32],
+            evaluation: SpriteEvaluation::default(),
+            sprite_limit: true,
+            sprite_rotation: false,
+            renderer: Renderer::Dot,
+            framebuffer: [0; FRAMEBUFFER_SIZE],
+            emphasis: [0; SCREEN_HEIGHT],
+            palette_lut: cartridge.vs_system().and_then(|vs| vs.ppu().palette_lut()),
+            rgb_palette: Palette::new(&PaletteSettings { tv_system: cartridge.tv_system(), ..PaletteSettings::default() }),
+            layers: None,
         }
     }
 
-    // pub fn read_register(&mut self, addr: u16) -> u8 {
-    // 	let result = self.registers[addr as usize];
+    /// Advance the PPU by a single dot. The cartridge is where the pattern tables and nametables are.
+    pub fn tick(&mut self, cartridge: &mut Cartridge) {
+        self.dot += 1;
+        // With rendering enabled, the pre-render scanline of odd frames is one dot shorter
+        if self.scanline == PRE_RENDER_SCANLINE && self.dot == DOTS_PER_SCANLINE - 1 && self.frame & 1 == 1 && self.rendering_enabled() {
+            self.dot = DOTS_PER_SCANLINE;
+        }
+        if self.dot == DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline == SCANLINES_PER_FRAME {
+                self.scanline = 0;
+                self.frame += 1;
+            }
+        }
 
-    // 	// clear bit 7
-    // 	let mut cleared_bit_7 = result;
-    // 	bits::set(&mut cleared_bit_7, 7, false);
-    // 	self.registers[addr as usize] = cleared_bit_7;
+        if self.dot == 1 {
+            if self.scanline == VBLANK_SCANLINE {
+                // Vblank starts
+                bits::set(&mut self.ppu_status, 7, true);
+                if self.nmi_enabled() {
+                    self.nmi_pending = true;
+                }
+            } else if self.scanline == PRE_RENDER_SCANLINE {
+                // Vblank ends, sprite 0 hit and sprite overflow are cleared. The PPU is warmed up.
+                self.warming_up = false;
+                bits::set(&mut self.ppu_status, 7, false);
+                bits::set(&mut self.ppu_status, 6, false);
+                bits::set(&mut self.ppu_status, 5, false);
+            }
+        }
 
-    // 	result
-    // }
+        cartridge.ppu_tick(self.scanline, self.dot, self.rendering_enabled());
+        self.render_dot(cartridge);
+    }
 
-    // pub fn write_register(&mut self, addr: u16, value: u8) {
-    // 	self.registers[addr as usize] = value;
-    // }
+    /// Read PPU register (0-7).
+    ///
+    /// When `peek` is true, the read has no side effects (PPUSTATUS doesn't clear vblank, PPUDATA doesn't increment
+    /// the VRAM address or fill the read buffer). Used by debugging tools.
+    ///
+    /// For now, the write-only registers return the last value written to them.
+    pub fn read_register(&mut self, reg: u16, peek: bool, cartridge: &mut Cartridge) -> u8 {
+        match reg {
+            2 => {
+                // PPUSTATUS
+                let result = self.ppu_status;
+                if !peek {
+                    bits::set(&mut self.ppu_status, 7, false);
+                    self.w = false;
+                }
+                result
+            }
+            4 => {
+                // OAMDATA. During rendering, the byte the sprite evaluation or fetch is on.
+                if let Some(value) = self.oam_bus() {
+                    return value;
+                }
+                let value = self.oam[self.oam_addr as usize];
+                // Bits 2-4 of the sprite attributes don't exist, they read back as 0
+                if self.oam_addr & 0b11 == 2 { value & 0xE3 } else { value }
+            }
+            7 => {
+                // PPUDATA
+                let addr = self.v & 0x3FFF;
+                let result = if addr >= 0x3F00 {
+                    // Palette is returned immediately, the buffer is filled with the nametable "under" the palette
+                    if !peek {
+                        self.read_buffer = self.read_vram(addr - 0x1000, PpuFetch::Data, cartridge);
+                    }
+                    self.read_vram(addr, PpuFetch::Data, cartridge)
+                } else {
+                    let result = self.read_buffer;
+                    if !peek {
+                        self.read_buffer = self.read_vram(addr, PpuFetch::Data, cartridge);
+                    }
+                    result
+                };
+                if !peek {
+                    self.increment_v();
+                }
+                result
+            }
+            _ => self.registers[reg as usize],
+        }
+    }
+
+    /// Write PPU register (0-7).
+    ///
+    /// When `poke` is true, only the register is changed (no NMI, no write toggle, no VRAM address increment).
+    /// PPUDATA poke writes the VRAM at the current address.
+    pub fn write_register(&mut self, reg: u16, value: u8, poke: bool, cartridge: &mut Cartridge) {
+        if poke {
+            match reg {
+                4 => self.oam[self.oam_addr as usize] = value,
+                7 => self.write_vram(self.v & 0x3FFF, value, cartridge),
+                _ => self.registers[reg as usize] = value,
+            }
+            return;
+        }
+
+        // The mappers see the write on the CPU bus, also when the PPU ignores it
+        cartridge.ppu_register_write(reg, value);
+        if self.warming_up && matches!(reg, 0 | 1 | 5 | 6) {
+            return;
+        }
+        match reg {
+            0 => {
+                // PPUCTRL
+                // Enabling NMI while already inside vblank raises NMI immediately
+                if !self.nmi_enabled() && bits::get(value, 7) && bits::get(self.ppu_status, 7) {
+                    self.nmi_pending = true;
+                }
+                // Base nametable address goes to t
+                self.t = (self.t & 0xF3FF) | (((value & 0b11) as u16) << 10);
+            }
+            3 => {
+                // OAMADDR
+                self.oam_addr = value;
+            }
+            4 => {
+                // OAMDATA
+                self.write_oam(value);
+            }
+            5 => {
+                // PPUSCROLL
+                if !self.w {
+                    // Coarse X and fine X
+                    self.t = (self.t & !0x001F) | ((value >> 3) as u16);
+                    self.x = value & 0b111;
+                } else {
+                    // Coarse Y and fine Y
+                    self.t = (self.t & 0x8C1F) | (((value & 0b111) as u16) << 12) | (((value & 0xF8) as u16) << 2);
+                }
+                self.w = !self.w;
+            }
+            6 => {
+                // PPUADDR, high byte first
+                if !self.w {
+                    self.t = (self.t & 0x00FF) | (((value & 0x3F) as u16) << 8);
+                } else {
+                    self.t = (self.t & 0xFF00) | value as u16;
+                    self.v = self.t;
+                }
+                self.w = !self.w;
+            }
+            7 => {
+                // PPUDATA
+                self.write_vram(self.v & 0x3FFF, value, cartridge);
+                self.increment_v();
+            }
+            _ => {}
+        }
+        self.registers[reg as usize] = value;
+    }
+
+    /// Write to OAM at OAMADDR, like OAMDATA does. Used by OAM DMA ($4014).
+    pub fn write_oam(&mut self, value: u8) {
+        if self.rendering_oam() {
+            // OAM is busy with the sprites: the write is lost, and OAMADDR moves to the next sprite
+            self.oam_addr = self.oam_addr.wrapping_add(4);
+            return;
+        }
+        self.oam[self.oam_addr as usize] = value;
+        self.oam_addr = self.oam_addr.wrapping_add(1);
+    }
+
+    /// The sprite evaluation and fetches use OAM: the visible and pre-render scanlines, with rendering enabled.
+    fn rendering_oam(&self) -> bool {
+        (self.scanline < SCREEN_HEIGHT as u16 || self.scanline == PRE_RENDER_SCANLINE) && self.rendering_enabled()
+    }
+
+    /// What OAMDATA reads during rendering: the secondary OAM clear ($FF), the byte the evaluation copies, then the
+    /// secondary OAM bytes of the sprite fetches. None outside rendering.
+    fn oam_bus(&self) -> Option<u8> {
+        if !self.rendering_oam() {
+            return None;
+        }
+        let value = match self.dot {
+            1..=64 => 0xFF,
+            65..=256 => self.evaluation.data,
+            257..=320 => {
+                // Y, tile, attributes and X of each sprite, then X again for the rest of its 8 dots
+                let dot = (self.dot - 257) as usize;
+                self.secondary_oam[dot / 8 * 4 + (dot % 8).min(3)]
+            }
+            _ => self.secondary_oam[0],
+        };
+        Some(value)
+    }
+
+    /// PPUDATA access increments the VRAM address by 1 (across) or 32 (down), depending on PPUCTRL bit 2.
+    fn increment_v(&mut self) {
+        let increment = if bits::get(self.registers[0], 2) { 32 } else { 1 };
+        self.v = self.v.wrapping_add(increment) & 0x7FFF;
+    }
+
+    /// Read the PPU address space (0x0000-0x3FFF). Everything below the palette is on the cartridge.
+    fn read_vram(&self, addr: u16, fetch: PpuFetch, cartridge: &mut Cartridge) -> u8 {
+        match addr {
+            0x0000..=0x3EFF => cartridge.ppu_read(addr, fetch, &self.name_table),
+            _ => self.palette_table[Self::palette_index(addr)],
+        }
+    }
+
+    /// Write the PPU address space (0x0000-0x3FFF).
+    fn write_vram(&mut self, addr: u16, value: u8, cartridge: &mut Cartridge) {
+        match addr {
+            0x0000..=0x3EFF => cartridge.ppu_write(addr, value, &mut self.name_table),
+            _ => self.palette_table[Self::palette_index(addr)] = value,
+        }
+    }
+
+    /// Palette is 32 bytes mirrored up to 0x3FFF. The sprite backdrop entries (0x3F10, 0x3F14, 0x3F18, 0x3F1C) are mirrors of
+    /// the background ones (0x3F00, 0x3F04, 0x3F08, 0x3F0C).
+    fn palette_index(addr: u16) -> usize {
+        let index = (addr & 0x1F) as usize;
+        if index >= 0x10 && index & 0b11 == 0 {
+            index - 0x10
+        } else {
+            index
+        }
+    }
+
+    /// PPUCTRL bit 7: generate NMI at the start of vblank.
+    pub fn nmi_enabled(&self) -> bool {
+        bits::get(self.registers[0], 7)
+    }
+
+    /// PPUMASK bit 3 (show background) or bit 4 (show sprites).
+    pub fn rendering_enabled(&self) -> bool {
+        self.registers[1] & 0b0001_1000 != 0
+    }
+
+    /// The rendering work of the current dot: background fetches, scroll updates, sprite evaluation and the pixel output.
+    /// Read here: https://www.nesdev.org/wiki/PPU_rendering
+    fn render_dot(&mut self, cartridge: &mut Cartridge) {
+        let visible = self.scanline < SCREEN_HEIGHT as u16;
+        let pre_render = self.scanline == PRE_RENDER_SCANLINE;
+
+        if self.renderer == Renderer::Scanline {
+            if visible && self.dot == 1 {
+                self.render_scanline(cartridge);
+            }
+            if (visible || pre_render) && self.rendering_enabled() {
+                self.update_scroll_and_sprites(visible, pre_render, cartridge);
+            }
+            return;
+        }
+
+        if (visible || pre_render) && self.rendering_enabled() {
+            let dot = self.dot;
+            if (2..=257).contains(&dot) || (322..=337).contains(&dot) {
+                self.shift_background();
+            }
+            if (1..=256).contains(&dot) || (321..=336).contains(&dot) {
+                match (dot - 1) % 8 {
+                    0 => {
+                        self.load_background_shifters();
+                        self.bg.next_tile = self.read_vram(0x2000 | (self.v & 0x0FFF), PpuFetch::Background, cartridge);
+                    }
+                    2 => {
+                        let addr = 0x23C0 | (self.v & 0x0C00) | ((self.v >> 4) & 0x38) | ((self.v >> 2) & 0x07);
+                        let mut attribute = self.read_vram(addr, PpuFetch::Background, cartridge);
+                        // Each attribute byte covers 4x4 tiles, 2 bits for each 2x2 tiles quadrant
+                        if self.v & 0x40 != 0 {
+                            attribute >>= 4;
+                        }
+                        if self.v & 0x02 != 0 {
+                            attribute >>= 2;
+                        }
+                        self.bg.next_attribute = attribute & 0b11;
+                    }
+                    4 => self.bg.next_pattern_low = self.read_vram(self.background_pattern_addr(), PpuFetch::Background, cartridge),
+                    6 => self.bg.next_pattern_high = self.read_vram(self.background_pattern_addr() + 8, PpuFetch::Background, cartridge),
+                    7 => self.increment_coarse_x(),
+                    _ => {}
+                }
+            }
+            if dot == 257 {
+                self.load_background_shifters();
+            }
+            self.update_scroll_and_sprites(visible, pre_render, cartridge);
+        }
+
+        if visible && (1..=SCREEN_WIDTH as u16).contains(&self.dot) {
+            self.draw_pixel();
+        }
+    }
+
+    /// The scroll updates of v at the end of the scanline and the sprite evaluation, for both renderers.
+    fn update_scroll_and_sprites(&mut self, visible: bool, pre_render: bool, cartridge: &mut Cartridge) {
+        let dot = self.dot;
+        if visible {
+            self.evaluate_sprites_dot();
+        }
+        if pre_render && dot == 1 && self.oam_addr >= 8 {
+            // OAMADDR corruption: when rendering starts with OAMADDR 8 or more, the 8 bytes at OAMADDR & 0xF8 are copied
+            // over the first 8 bytes of OAM (the 2C02G does this)
+            let start = (self.oam_addr & 0xF8) as usize;
+            self.oam.copy_within(start..start + 8, 0);
+        }
+        if (257..=320).contains(&dot) {
+            // The sprite fetches set OAMADDR to 0
+            self.oam_addr = 0;
+        }
+        if dot == 256 {
+            self.increment_y();
+        }
+        if dot == 257 {
+            // Copy horizontal position from t to v
+            self.v = (self.v & !0x041F) | (self.t & 0x041F);
+            if visible {
+                self.fetch_sprites(cartridge);
+            } else {
+                self.sprite_count = 0;
+            }
+        }
+        if pre_render && (280..=304).contains(&dot) {
+            // Copy vertical position from t to v
+            self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+        }
+    }
+
+    /// The scanline renderer: fetch the 33 tiles that the scanline shows (32 and the one that fine X scrolls in) and
+    /// draw the 256 pixels. Like the dot renderer, v moves to the next tile after each fetch, the horizontal position
+    /// is copied back from t at dot 257.
+    fn render_scanline(&mut self, cartridge: &mut Cartridge) {
+        let mut background = [(0u8, 0u8); SCREEN_WIDTH + 8]; // Pixel and palette, from fine X 0
+        if bits::get(self.registers[1], 3) {
+            for tile in 0..33 {
+                self.bg.next_tile = self.read_vram(0x2000 | (self.v & 0x0FFF), PpuFetch::Background, cartridge);
+                let addr = 0x23C0 | (self.v & 0x0C00) | ((self.v >> 4) & 0x38) | ((self.v >> 2) & 0x07);
+                let mut attribute = self.read_vram(addr, PpuFetch::Background, cartridge);
+                if self.v & 0x40 != 0 {
+                    attribute >>= 4;
+                }
+                if self.v & 0x02 != 0 {
+                    attribute >>= 2;
+                }
+                let palette = attribute & 0b11;
+                let pattern_low = self.read_vram(self.background_pattern_addr(), PpuFetch::Background, cartridge);
+                let pattern_high = self.read_vram(self.background_pattern_addr() + 8, PpuFetch::Background, cartridge);
+                for bit in 0..8 {
+                    let pixel = (((pattern_high >> (7 - bit)) & 1) << 1) | ((pattern_low >> (7 - bit)) & 1);
+                    if let Some(slot) = background.get_mut(tile * 8 + bit) {
+                        *slot = (pixel, palette);
+                    }
+                }
+                self.increment_coarse_x();
+            }
+        }
+
+        let y = self.scanline as usize;
+        let show_left = bits::get(self.registers[1], 1);
+        for x in 0..SCREEN_WIDTH {
+            let (bg_pixel, bg_palette) = if x >= 8 || show_left { background[x + self.x as usize] } else { (0, 0) };
+            self.output_pixel(x, y, bg_pixel, bg_palette);
+        }
+    }
+
+    fn background_pattern_addr(&self) -> u16 {
+        let table = if bits::get(self.registers[0], 4) { 0x1000 } else { 0 };
+        let fine_y = (self.v >> 12) & 0b111;
+        table + self.bg.next_tile as u16 * 16 + fine_y
+    }
+
+    fn load_background_shifters(&mut self) {
+        self.bg.pattern_low = (self.bg.pattern_low & 0xFF00) | self.bg.next_pattern_low as u16;
+        self.bg.pattern_high = (self.bg.pattern_high & 0xFF00) | self.bg.next_pattern_high as u16;
+        // The attribute is the same for the whole tile, so we extend the bits to 8 pixels
+        let attribute_low = if self.bg.next_attribute & 0b01 != 0 { 0xFF } else { 0x00 };
+        let attribute_high = if self.bg.next_attribute & 0b10 != 0 { 0xFF } else { 0x00 };
+        self.bg.attribute_low = (self.bg.attribute_low & 0xFF00) | attribute_low;
+        self.bg.attribute_high = (self.bg.attribute_high & 0xFF00) | attribute_high;
+    }
+
+    fn shift_background(&mut self) {
+        self.bg.pattern_low <<= 1;
+        self.bg.pattern_high <<= 1;
+        self.bg.attribute_low <<= 1;
+        self.bg.attribute_high <<= 1;
+    }
+
+    /// Move v to the next tile, wrapping to the horizontally adjacent nametable.
+    fn increment_coarse_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            self.v &= !0x001F;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    /// Move v to the next pixel row, wrapping to the vertically adjacent nametable after 30 tile rows.
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+        } else {
+            self.v &= !0x7000;
+            let mut coarse_y = (self.v & 0x03E0) >> 5;
+            if coarse_y == 29 {
+                coarse_y = 0;
+                self.v ^= 0x0800;
+            } else if coarse_y == 31 {
+                // Out of bounds coarse Y (attribute table) wraps without switching nametable
+                coarse_y = 0;
+            } else {
+                coarse_y += 1;
+            }
+            self.v = (self.v & !0x03E0) | (coarse_y << 5);
+        }
+    }
+
+    /// One dot of the sprite evaluation for the next scanline, like the hardware: dot 64 ends the clear of the
+    /// secondary OAM, then dots 65-256 read an OAM byte on odd dots and copy it to the secondary OAM on even dots. The
+    /// evaluation starts at OAMADDR and moves it, so a game that leaves OAMADDR not zero sees other sprites, and after
+    /// 8 sprites the overflow search has the hardware bug. Read here: https://www.nesdev.org/wiki/PPU_sprite_evaluation
+    fn evaluate_sprites_dot(&mut self) {
+        let dot = self.dot;
+        if dot == 64 {
+            self.secondary_oam = [0xFF; 32];
+            self.evaluation = SpriteEvaluation::default();
+        }
+        if !(65..=256).contains(&dot) {
+            return;
+        }
+        if dot % 2 == 1 {
+            self.evaluation.data = self.oam[self.evaluation_oam_index()];
+            return;
+        }
+
+        let height: i16 = if bits::get(self.registers[0], 5) { 16 } else { 8 };
+        let data = self.evaluation.data;
+        let in_range = (0..height).contains(&(self.scanline as i16 - data as i16));
+        let evaluation = &mut self.evaluation;
+        if evaluation.done {
+            // Failed copies of the Y of the next sprites, until the end of the evaluation
+            self.oam_addr = self.oam_addr.wrapping_add(4);
+            return;
+        }
+        let wrapped; // All 64 sprites were checked
+        if evaluation.byte > 0 {
+            // The tile, attributes and X of a sprite in range. OAMADDR increments with carry into the sprite index.
+            if !evaluation.overflow_search {
+                self.secondary_oam[evaluation.found as usize * 4 + evaluation.byte as usize] = data;
+            }
+            (self.oam_addr, wrapped) = self.oam_addr.overflowing_add(1);
+            evaluation.byte = (evaluation.byte + 1) % 4;
+            if evaluation.byte == 0 {
+                if evaluation.overflow_search {
+                    evaluation.done = true;
+                } else {
+                    evaluation.found += 1;
+                }
+            }
+        } else if !evaluation.overflow_search {
+            self.secondary_oam[evaluation.found as usize * 4] = data;
+            if in_range {
+                evaluation.sprite_zero |= dot == 66;
+                evaluation.byte = 1;
+                (self.oam_addr, wrapped) = self.oam_addr.overflowing_add(1);
+            } else {
+                (self.oam_addr, wrapped) = self.oam_addr.overflowing_add(4);
+            }
+        } else if in_range {
+            bits::set(&mut self.ppu_status, 5, true); // Sprite overflow
+            evaluation.byte = 1;
+            (self.oam_addr, wrapped) = self.oam_addr.overflowing_add(1);
+        } else {
+            // The hardware bug: the byte index increments with the sprite index (without carry), so the next Y checked
+            // is a tile, attributes or X. That makes the overflow flag miss sprites, or set it without 9 sprites.
+            let (sprite, sprite_wrapped) = (self.oam_addr & 0xFC).overflowing_add(4);
+            self.oam_addr = sprite | (self.oam_addr.wrapping_add(1) & 0b11);
+            wrapped = sprite_wrapped;
+        }
+        if evaluation.found == 8 {
+            evaluation.overflow_search = true;
+        }
+        if wrapped {
+            evaluation.done = true;
+        }
+    }
+
+    /// The OAM byte the sprite evaluation reads at OAMADDR. With the sprite rotation, sprites 1-63 are seen in another
+    /// order each frame: a different sprite comes first, so when more than 8 are on a scanline others are dropped each
+    /// frame and their priority changes. Sprite 0 stays first, for the sprite 0 hit.
+    fn evaluation_oam_index(&self) -> usize {
+        let (sprite, byte) = ((self.oam_addr >> 2) as u64, (self.oam_addr & 3) as usize);
+        if !self.sprite_rotation || sprite == 0 {
+            return self.oam_addr as usize;
+        }
+        let rotated = 1 + (sprite - 1 + self.frame) % 63;
+        rotated as usize * 4 + byte
+    }
+
+    /// The pattern fetches of the sprites of the next scanline (dots 257-320, all at once), from the secondary OAM.
+    /// Without the sprite limit all the sprites in range are drawn, but the sprite overflow flag is still set by the
+    /// evaluation as if there was a limit (games use it for timing).
+    fn fetch_sprites(&mut self, cartridge: &mut Cartridge) {
+        self.sprite_count = 0;
+        if !self.sprite_limit {
+            let height: i16 = if bits::get(self.registers[0], 5) { 16 } else { 8 };
+            for i in 0..64 {
+                let sprite: [u8; 4] = self.oam[i * 4..i * 4 + 4].try_into().unwrap();
+                if (0..height).contains(&(self.scanline as i16 - sprite[0] as i16)) {
+                    self.fetch_sprite(sprite, i == 0, cartridge);
+                }
+            }
+            return;
+        }
+        for i in 0..self.evaluation.found as usize {
+            let sprite: [u8; 4] = self.secondary_oam[i * 4..i * 4 + 4].try_into().unwrap();
+            self.fetch_sprite(sprite, i == 0 && self.evaluation.sprite_zero, cartridge);
+        }
+    }
+
+    /// Fetch the pattern of the row of a sprite (Y, tile, attributes, X) on the current scanline.
+    fn fetch_sprite(&mut self, sprite: [u8; 4], sprite_zero: bool, cartridge: &mut Cartridge) {
+        let height: i16 = if bits::get(self.registers[0], 5) { 16 } else { 8 };
+        let [y, tile, attributes, x] = sprite;
+        let mut row = self.scanline as i16 - y as i16;
+        if bits::get(attributes, 7) {
+            // Flip vertically
+            row = height - 1 - row;
+        }
+        let addr = if height == 8 {
+            let table = if bits::get(self.registers[0], 3) { 0x1000 } else { 0 };
+            table + tile as u16 * 16 + row as u16
+        } else {
+            // 8x16 sprites take the pattern table from bit 0 of the tile index
+            let table = (tile as u16 & 1) * 0x1000;
+            let tile = (tile & 0xFE) as u16 + (row / 8) as u16;
+            table + tile * 16 + (row % 8) as u16
+        };
+        let mut pattern_low = self.read_vram(addr, PpuFetch::Sprite, cartridge);
+        let mut pattern_high = self.read_vram(addr + 8, PpuFetch::Sprite, cartridge);
+        if bits::get(attributes, 6) {
+            // Flip horizontally
+            pattern_low = pattern_low.reverse_bits();
+            pattern_high = pattern_high.reverse_bits();
+        }
+
+        self.sprites[self.sprite_count] = SpriteSlot {
+            x,
+            attributes,
+            pattern_low,
+            pattern_high,
+            sprite_zero,
+        };
+        self.sprite_count += 1;
+    }
+
+    /// Output the pixel of the current dot to the framebuffer.
+    fn draw_pixel(&mut self) {
+        let x = (self.dot - 1) as usize;
+        let y = self.scanline as usize;
+        let mask = self.registers[1];
+
+        // Background
+        let mut bg_pixel = 0;
+        let mut bg_palette = 0;
+        if bits::get(mask, 3) && (x >= 8 || bits::get(mask, 1)) {
+            let mux = 0x8000 >> self.x;
+            let bit = |shifter: u16| (shifter & mux != 0) as u8;
+            bg_pixel = (bit(self.bg.pattern_high) << 1) | bit(self.bg.pattern_low);
+            bg_palette = (bit(self.bg.attribute_high) << 1) | bit(self.bg.attribute_low);
+        }
+        self.output_pixel(x, y, bg_pixel, bg_palette);
+    }
+
+    /// Mix the background pixel with the sprites, and output the color to the framebuffer.
+    fn output_pixel(&mut self, x: usize, y: usize, bg_pixel: u8, bg_palette: u8) {
+        let mask = self.registers[1];
+
+        // Sprites, the first sprite with non transparent pixel wins
+        let mut fg_pixel = 0;
+        let mut fg_palette = 0;
+        let mut fg_behind_bg = false;
+        let mut sprite_zero = false;
+        if bits::get(mask, 4) && (x >= 8 || bits::get(mask, 2)) {
+            for sprite in &self.sprites[..self.sprite_count] {
+                let offset = x as i16 - sprite.x as i16;
+                if !(0..8).contains(&offset) {
+                    continue;
+                }
+                let shift = 7 - offset;
+                let pixel = (((sprite.pattern_high >> shift) & 1) << 1) | ((sprite.pattern_low >> shift) & 1);
+                if pixel == 0 {
+                    continue;
+                }
+                fg_pixel = pixel;
+                fg_palette = (sprite.attributes & 0b11) + 4;
+                fg_behind_bg = bits::get(sprite.attributes, 5);
+                sprite_zero = sprite.sprite_zero;
+                break;
+            }
+        }
+
+        if sprite_zero && bg_pixel != 0 && fg_pixel != 0 && x != 255 {
+            bits::set(&mut self.ppu_status, 6, true); // Sprite 0 hit
+        }
 
-    /// Returns the pattern tile at given index (0x00-0xFF) from left/right (parameter) pattern table.
-    fn get_pattern_tile(&self, tile_index: u8, left_table: bool) -> &[u8] {
-        if left_table {
-            // Each pattern tile is 16 bytes in size. We jump by 16 bytes.
-            // The tile index can be 0x0-0xFF, but the actual bytes needed are 0xFF times 16, which fits in u16.
-            let i: u16 = (tile_index as u16 * 16);
-            &self.pattern_tables[i as usize..i as usize + 16]
+        let (pixel, palette) = match (bg_pixel, fg_pixel) {
+            (0, 0) => (0, 0),
+            (0, _) => (fg_pixel, fg_palette),
+            (_, 0) => (bg_pixel, bg_palette),
+            _ if fg_behind_bg => (bg_pixel, bg_palette),
+            _ => (fg_pixel, fg_palette),
+        };
+        // Transparent pixels show the backdrop color
+        self.framebuffer[y * SCREEN_WIDTH + x] = self.output_color(palette, pixel);
+        // Emphasis changes the whole color signal, not the palette index. One value per scanline is enough for games.
+        self.emphasis[y] = mask >> 5;
+
+        if self.layers.is_some() {
+            let background = if bg_pixel == 0 { TRANSPARENT } else { self.output_color(bg_palette, bg_pixel) };
+            let sprites = if fg_pixel == 0 { TRANSPARENT } else { self.output_color(fg_palette, fg_pixel) };
+            if let Some(layers) = &mut self.layers {
+                layers.background[y * SCREEN_WIDTH + x] = background;
+                layers.sprites[y * SCREEN_WIDTH + x] = sprites;
+            }
+        }
+    }
+
+    /// The color of a pixel (0-3) of a palette (0-3 background, 4-7 sprites). Pixel 0 is the backdrop color.
+    fn output_color(&self, palette: u8, pixel: u8) -> u8 {
+        let addr = if pixel != 0 {
+            0x3F00 + palette as u16 * 4 + pixel as u16
+        } else if !self.rendering_enabled() && self.v & 0x3FFF >= 0x3F00 {
+            // Background palette hack: with rendering disabled, the backdrop is the palette entry v points to. Some
+            // demos show more colors this way, and palette updates outside vblank show up as lines.
+            self.v & 0x3FFF
         } else {
-            todo!();
+            0x3F00
+        };
+        let mut color = self.palette_table[Self::palette_index(addr)] & 0x3F;
+        if bits::get(self.registers[1], 0) {
+            // Greyscale: the grey column of the palette, same brightness
+            color &= 0x30;
+        }
+        match self.palette_lut {
+            Some(lut) => lut[color as usize],
+            None => color,
+        }
+    }
+
+    /// Also render the background and the sprites to separate framebuffers (slower).
+    pub fn set_layers_enabled(&mut self, enabled: bool) {
+        self.layers = if enabled { Some(Box::new(Layers::new())) } else { None };
+    }
+
+    /// The background and sprite layers of the picture, when enabled.
+    pub fn layers(&self) -> Option<&Layers> {
+        self.layers.as_deref()
+    }
+
+    /// The hardware draws at most 8 sprites on a scanline, games flicker the sprites to show more. Disabling the limit
+    /// removes the flicker (and shows sprites that games hide on purpose, behind 8 blank sprites).
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.sprite_limit = enabled;
+    }
+
+    pub fn sprite_limit(&self) -> bool {
+        self.sprite_limit
+    }
+
+    /// Reduce the flicker of the sprite limit (kept on) by evaluating the sprites in another order each frame, like some
+    /// emulators do. The dropped sprites change each frame instead of always being the same ones.
+    pub fn set_sprite_rotation(&mut self, enabled: bool) {
+        self.sprite_rotation = enabled;
+    }
+
+    pub fn sprite_rotation(&self) -> bool {
+        self.sprite_rotation
+    }
+
+    /// Change the renderer, also in the middle of a frame (it takes effect on the next scanline).
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer;
+    }
+
+    pub fn renderer(&self) -> Renderer {
+        self.renderer
+    }
+
+    /// Use the palette of a VS System PPU. None for the NES palette.
+    pub fn set_palette_lut(&mut self, palette_lut: Option<&'static [u8; 64]>) {
+        self.palette_lut = palette_lut;
+    }
+
+    /// The 2KB of nametable RAM inside the console (mappers can add more, see `Cartridge::ppu_read`).
+    pub fn name_table(&self) -> &[u8] {
+        &self.name_table
+    }
+
+    pub fn palette_table(&self) -> &[u8] {
+        &self.palette_table
+    }
+
+    pub fn oam(&self) -> &[u8] {
+        &self.oam
+    }
+
+    /// The picture, 256x240 NES color indexes (0x00-0x3F), row by row. See `rgb_palette` for the RGB values.
+    /// During the frame, the scanlines below the beam are still from the previous frame.
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    /// The color emphasis bits of each scanline of the framebuffer (bit 0 red, 1 green, 2 blue), see `Palette::rgb`.
+    pub fn emphasis(&self) -> &[u8] {
+        &self.emphasis
+    }
+
+    /// The RGB colors of the framebuffer. NTSC or PAL by the ROM header, see `PaletteSettings` for the other settings.
+    pub fn rgb_palette(&self) -> &Palette {
+        &self.rgb_palette
+    }
+
+    pub fn set_rgb_palette(&mut self, palette: Palette) {
+        self.rgb_palette = palette;
+    }
+
+    /// After power on and reset, the PPU ignores the writes to PPUCTRL, PPUMASK, PPUSCROLL and PPUADDR until the end of
+    /// vblank, about 29658 CPU cycles after power on. Games wait for two vblanks before using the PPU, some init routines
+    /// depend on the writes being ignored. Read here: https://www.nesdev.org/wiki/PPU_power_up_state
+    ///
+    /// Disabled, the registers work right away (for test programs that don't wait).
+    pub fn set_warm_up(&mut self, enabled: bool) {
+        self.warm_up = enabled;
+        self.warming_up &= enabled;
+    }
+
+    pub fn warm_up(&self) -> bool {
+        self.warm_up
+    }
+
+    /// True while the writes are ignored, see `set_warm_up`.
+    pub fn warming_up(&self) -> bool {
+        self.warming_up
+    }
+
+    /// The reset button clears PPUCTRL, PPUMASK, the scroll and the write toggle. VRAM, OAM and the palette keep their
+    /// values, and the PPU keeps running. The PPU warms up again.
+    pub fn reset(&mut self) {
+        self.warming_up = self.warm_up;
+        self.registers[0] = 0;
+        self.registers[1] = 0;
+        self.t = 0;
+        self.x = 0;
+        self.w = false;
+        self.read_buffer = 0;
+        self.nmi_pending = false;
+    }
+
+    /// Returns true once for each NMI the PPU raised.
+    pub fn take_nmi(&mut self) -> bool {
+        std::mem::take(&mut self.nmi_pending)
+    }
+
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    pub fn dot(&self) -> u16 {
+        self.dot
+    }
+
+    /// Amount of frames completed since power on.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// The registers, the scroll latches and the timing, see `NES::dump_state`.
+    pub fn dump_state(&self) -> PpuState {
+        PpuState {
+            ctrl: self.registers[0],
+            mask: self.registers[1],
+            status: self.ppu_status,
+            oam_addr: self.oam_addr,
+            v: self.v,
+            t: self.t,
+            x: self.x,
+            w: self.w,
+            read_buffer: self.read_buffer,
+            scanline: self.scanline,
+            dot: self.dot,
+            frame: self.frame,
         }
     }
 
+    /// True when the beam is between the vblank scanline and the pre-render scanline.
+    /// Unlike the vblank flag in PPUSTATUS, this is not cleared by reading $2002.
+    pub fn in_vblank(&self) -> bool {
+        self.scanline >= VBLANK_SCANLINE && self.scanline < PRE_RENDER_SCANLINE
+            && !(self.scanline == VBLANK_SCANLINE && self.dot == 0)
+    }
+
+    /// Returns the pattern tile at given index (0x00-0xFF) from left (0x0000) or right (0x1000) pattern table, in the
+    /// CHR banks the mapper currently maps.
+    fn get_pattern_tile(&self, tile_index: u8, left_table: bool, cartridge: &mut Cartridge) -> [u8; 16] {
+        // Each pattern tile is 16 bytes in size. We jump by 16 bytes.
+        let base: u16 = if left_table { 0x0000 } else { 0x1000 } + tile_index as u16 * 16;
+        let mut tile = [0; 16];
+        for (i, byte) in tile.iter_mut().enumerate() {
+            *byte = self.read_vram(base + i as u16, PpuFetch::Data, cartridge);
+        }
+        tile
+    }
+
 	fn get_nametable(&self) {
 		println!("{:?}", &self.name_table[..16]);
-		todo!();
 	}
 
     fn get_palette(&self, index: u8) {
         // Palette starts at 0x3F00 - 0x3F10 (16 bytes)
-        println!("{:?}", &self.pattern_tables[0x3F00..0x3F10]);
+        println!("{:?}", &self.palette_table[0x00..0x10]);
+    }
+}
+
+impl Serialize for SpriteSlot {
+    fn serialize(&mut self, s: &mut Serializer) {
+        s.value(&mut self.x);
+        s.value(&mut self.attributes);
+        s.value(&mut self.pattern_low);
+        s.value(&mut self.pattern_high);
+        s.value(&mut self.sprite_zero);
+    }
+}
+
+/// The settings (renderer, sprite limit, palette, layers) are not saved, they belong to the user and not to the game.
+/// The picture is saved, so a loaded state shows its frame before the next one is drawn.
+impl Serialize for PPU {
+    fn serialize(&mut self, s: &mut Serializer) {
+        s.value(&mut self.registers);
+        s.value(&mut self.name_table);
+        s.value(&mut self.palette_table);
+        s.value(&mut self.ppu_status);
+        s.value(&mut self.oam_addr);
+        s.value(&mut self.oam);
+        s.value(&mut self.v);
+        s.value(&mut self.t);
+        s.value(&mut self.x);
+        s.value(&mut self.w);
+        s.value(&mut self.read_buffer);
+        s.value(&mut self.scanline);
+        s.value(&mut self.dot);
+        s.value(&mut self.frame);
+        s.value(&mut self.nmi_pending);
+        s.value(&mut self.bg);
+        s.value(&mut self.sprites);
+        s.value(&mut self.sprite_count);
+        s.value(&mut self.framebuffer);
+        s.value(&mut self.emphasis);
+        s.value(&mut self.secondary_oam);
+        s.value(&mut self.evaluation);
+        s.value(&mut self.warming_up);
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{cartridge::Cartridge, rom_parser::RomParser};
+    use crate::{cartridge::Cartridge, rom_parser::{RomParser, MirrorType}, mapper::PpuFetch};
 
-    use super::PPU;
+    use super::{bits, Renderer, PPU, SCREEN_WIDTH, TRANSPARENT};
 
-    fn initialize() -> PPU {
+    fn initialize() -> (PPU, Cartridge) {
         let path = "6502asm_programs/nestest/nestest.nes";
         let mut rom_parser = RomParser::new();
-        rom_parser.parse(path);
-        let cartridge: Cartridge = Cartridge::new_with_parser(rom_parser);
-        let ppu = PPU::new(&cartridge);
+        rom_parser.parse(path).unwrap();
+        let cartridge: Cartridge = Cartridge::new_with_parser(rom_parser).unwrap();
+        let ppu = new_ppu(&cartridge);
+        (ppu, cartridge)
+    }
+
+    /// Warmed up, the tests use the registers right away.
+    fn new_ppu(cartridge: &Cartridge) -> PPU {
+        let mut ppu = PPU::new(cartridge);
+        ppu.set_warm_up(false);
         ppu
     }
 
+    /// Set VRAM address through PPUADDR, high byte first.
+    fn set_ppuaddr(ppu: &mut PPU, cartridge: &mut Cartridge, addr: u16) {
+        ppu.write_register(6, (addr >> 8) as u8, false, cartridge);
+        ppu.write_register(6, addr as u8, false, cartridge);
+    }
+
+    #[test]
+    fn test_ppudata_buffered_read() {
+        // Horizontal mirroring, CHR RAM
+        let mut cartridge = Cartridge::new();
+        let mut ppu = new_ppu(&cartridge);
+
+        set_ppuaddr(&mut ppu, &mut cartridge, 0x2108);
+        ppu.write_register(7, 0xAB, false, &mut cartridge);
+        ppu.write_register(7, 0xCD, false, &mut cartridge);
+
+        set_ppuaddr(&mut ppu, &mut cartridge, 0x2108);
+        assert_eq!(ppu.read_register(7, false, &mut cartridge), 0x00); // stale buffer
+        assert_eq!(ppu.read_register(7, false, &mut cartridge), 0xAB);
+        assert_eq!(ppu.read_register(7, true, &mut cartridge), 0xCD); // peek doesn't increment
+        assert_eq!(ppu.read_register(7, false, &mut cartridge), 0xCD);
+
+        // Increment by 32 (PPUCTRL bit 2)
+        ppu.write_register(0, 0b100, false, &mut cartridge);
+        set_ppuaddr(&mut ppu, &mut cartridge, 0x2000);
+        ppu.write_register(7, 0x11, false, &mut cartridge);
+        ppu.write_register(7, 0x22, false, &mut cartridge);
+        assert_eq!(ppu.read_vram(0x2000, PpuFetch::Data, &mut cartridge), 0x11);
+        assert_eq!(ppu.read_vram(0x2020, PpuFetch::Data, &mut cartridge), 0x22);
+    }
+
+    #[test]
+    fn test_palette_read_and_mirrors() {
+        let mut cartridge = Cartridge::new();
+        let mut ppu = new_ppu(&cartridge);
+
+        set_ppuaddr(&mut ppu, &mut cartridge, 0x3F00);
+        ppu.write_register(7, 0x0F, false, &mut cartridge);
+        set_ppuaddr(&mut ppu, &mut cartridge, 0x3F11);
+        ppu.write_register(7, 0x2A, false, &mut cartridge);
+
+        // Palette reads are not buffered
+        set_ppuaddr(&mut ppu, &mut cartridge, 0x3F11);
+        assert_eq!(ppu.read_register(7, false, &mut cartridge), 0x2A);
+
+        // $3F10 is a mirror of $3F00, the palette is mirrored up to $3FFF
+        assert_eq!(ppu.read_vram(0x3F10, PpuFetch::Data, &mut cartridge), 0x0F);
+        assert_eq!(ppu.read_vram(0x3FE0, PpuFetch::Data, &mut cartridge), 0x0F);
+        assert_eq!(ppu.read_vram(0x3F31, PpuFetch::Data, &mut cartridge), 0x2A);
+    }
+
+    #[test]
+    fn test_nametable_mirroring() {
+        // Cartridge::new() is horizontal mirroring
+        let mut cartridge = Cartridge::new();
+        let mut ppu = new_ppu(&cartridge);
+        ppu.write_vram(0x2005, 0x01, &mut cartridge);
+        ppu.write_vram(0x2805, 0x02, &mut cartridge);
+        assert_eq!(ppu.read_vram(0x2405, PpuFetch::Data, &mut cartridge), 0x01);
+        assert_eq!(ppu.read_vram(0x2C05, PpuFetch::Data, &mut cartridge), 0x02);
+        assert_eq!(ppu.read_vram(0x3005, PpuFetch::Data, &mut cartridge), 0x01); // $3000 mirrors $2000
+
+        // The same VRAM with a vertical mirroring cartridge
+        let mut cartridge = Cartridge::from_prg_chr(vec![0; 1024 * 32], vec![], 0, MirrorType::VERTICAL, None).unwrap();
+        assert_eq!(ppu.read_vram(0x2805, PpuFetch::Data, &mut cartridge), 0x01);
+        assert_eq!(ppu.read_vram(0x2405, PpuFetch::Data, &mut cartridge), 0x02);
+    }
+
+    #[test]
+    fn test_ppustatus_read_clears_vblank_and_toggle() {
+        let mut cartridge = Cartridge::new();
+        let mut ppu = new_ppu(&cartridge);
+        while !ppu.in_vblank() {
+            ppu.tick(&mut cartridge);
+        }
+        ppu.write_register(6, 0x21, false, &mut cartridge); // first write, w = 1
+
+        assert_eq!(ppu.read_register(2, true, &mut cartridge) & 0x80, 0x80);
+        assert!(ppu.w);
+        assert_eq!(ppu.read_register(2, false, &mut cartridge) & 0x80, 0x80);
+        assert_eq!(ppu.read_register(2, false, &mut cartridge) & 0x80, 0);
+        assert!(!ppu.w);
+    }
+
+    #[test]
+    fn test_warm_up() {
+        let mut cartridge = Cartridge::new();
+        let mut ppu = PPU::new(&cartridge);
+        ppu.write_register(0, 0x80, false, &mut cartridge);
+        ppu.write_register(6, 0x21, false, &mut cartridge);
+        assert_eq!((ppu.registers[0], ppu.w), (0, false));
+        // OAMADDR works
+        ppu.write_register(3, 0x10, false, &mut cartridge);
+        assert_eq!(ppu.oam_addr, 0x10);
+
+        // Until the pre-render scanline, about 29658 CPU cycles
+        let mut dots = 0;
+        while ppu.warming_up() {
+            ppu.tick(&mut cartridge);
+            dots += 1;
+        }
+        assert_eq!(dots / 3, 29667);
+        ppu.write_register(0, 0x80, false, &mut cartridge);
+        assert_eq!(ppu.registers[0], 0x80);
+
+        // Again after reset, not when it is skipped
+        ppu.reset();
+        assert!(ppu.warming_up());
+        ppu.set_warm_up(false);
+        ppu.reset();
+        assert!(!ppu.warming_up());
+    }
+
+    #[test]
+    fn test_loopy_registers() {
+        // The example of https://www.nesdev.org/wiki/PPU_scrolling#Summary
+        let mut cartridge = Cartridge::new();
+        let mut ppu = new_ppu(&cartridge);
+        ppu.write_register(0, 0x00, false, &mut cartridge);
+        ppu.read_register(2, false, &mut cartridge);
+        ppu.write_register(5, 0x7D, false, &mut cartridge);
+        assert_eq!((ppu.t, ppu.x, ppu.w), (0x000F, 5, true));
+        ppu.write_register(5, 0x5E, false, &mut cartridge);
+        assert_eq!((ppu.t, ppu.w), (0x616F, false));
+        ppu.write_register(6, 0x3D, false, &mut cartridge);
+        assert_eq!((ppu.t, ppu.w), (0x3D6F, true));
+        ppu.write_register(6, 0xF0, false, &mut cartridge);
+        assert_eq!((ppu.t, ppu.v, ppu.x, ppu.w), (0x3DF0, 0x3DF0, 5, false));
+
+        // The toggle is shared: a PPUSCROLL write after half a PPUADDR write is the second (Y) write
+        ppu.write_register(6, 0x24, false, &mut cartridge);
+        ppu.write_register(5, 0xFF, false, &mut cartridge);
+        assert_eq!((ppu.t, ppu.x, ppu.w), (0x77F0, 5, false));
+        // PPUCTRL only changes the nametable bits of t
+        ppu.write_register(0, 0x02, false, &mut cartridge);
+        assert_eq!((ppu.t, ppu.v), (0x7BF0, 0x3DF0));
+
+        // The mid-frame scroll split: $2006 nametable, $2005 Y, $2005 X, $2006 ((Y & $F8) << 2) | (X >> 3)
+        let (nametable, scroll_x, scroll_y) = (1u8, 0x4Bu8, 0x7Eu8);
+        ppu.read_register(2, false, &mut cartridge);
+        ppu.write_register(6, nametable << 2, false, &mut cartridge);
+        ppu.write_register(5, scroll_y, false, &mut cartridge);
+        ppu.write_register(5, scroll_x, false, &mut cartridge);
+        ppu.write_register(6, ((scroll_y & 0xF8) << 2) | (scroll_x >> 3), false, &mut cartridge);
+        // Coarse X 9, coarse Y 15, nametable 1, fine Y 6 (fine Y bit 2 is lost by the first $2006 write and set by the $2005 one)
+        assert_eq!(ppu.v, (6 << 12) | (1 << 10) | (15 << 5) | 9);
+        assert_eq!((ppu.x, ppu.w), (0x4B & 7, false));
+    }
+
+    #[test]
+    fn test_oamdata() {
+        let mut cartridge = Cartridge::new();
+        let mut ppu = new_ppu(&cartridge);
+        ppu.write_register(3, 0xFE, false, &mut cartridge);
+        ppu.write_register(4, 0x10, false, &mut cartridge);
+        ppu.write_register(4, 0x20, false, &mut cartridge);
+        ppu.write_register(4, 0x30, false, &mut cartridge); // OAMADDR wraps around
+        assert_eq!(ppu.oam[0xFE], 0x10);
+        assert_eq!(ppu.oam[0xFF], 0x20);
+        assert_eq!(ppu.oam[0x00], 0x30);
+        assert_eq!(ppu.read_register(4, false, &mut cartridge), 0x00);
+    }
+
+    /// Tile 1 is solid color 1, nametable is all tile 0 (transparent) except the top left tile.
+    fn initialize_rendering() -> (PPU, Cartridge) {
+        let mut cartridge = Cartridge::new();
+        let mut ppu = new_ppu(&cartridge);
+        for i in 0..8 {
+            ppu.write_vram(0x0010 + i, 0xFF, &mut cartridge);
+        }
+        ppu.write_vram(0x2000, 0x01, &mut cartridge);
+        ppu.write_vram(0x3F00, 0x0F, &mut cartridge); // Backdrop
+        ppu.write_vram(0x3F01, 0x16, &mut cartridge); // Background palette 0, color 1
+        ppu.write_vram(0x3F11, 0x2A, &mut cartridge); // Sprite palette 0, color 1
+        (ppu, cartridge)
+    }
+
+    fn run_until(ppu: &mut PPU, cartridge: &mut Cartridge, frame: u64, scanline: u16) {
+        while ppu.frame() < frame || ppu.scanline() < scanline {
+            ppu.tick(cartridge);
+        }
+    }
+
+    #[test]
+    fn test_render_background() {
+        let (mut ppu, mut cartridge) = initialize_rendering();
+        ppu.write_register(1, 0b0000_1010, false, &mut cartridge); // Show background, including the left 8 pixels
+
+        // Frame 0 starts without the pre-render scanline, so we look at frame 1
+        run_until(&mut ppu, &mut cartridge, 2, 0);
+        let framebuffer = ppu.framebuffer();
+        assert_eq!(framebuffer[0..8], [0x16; 8]);
+        assert_eq!(framebuffer[8], 0x0F);
+        assert_eq!(framebuffer[7 * SCREEN_WIDTH + 7], 0x16);
+        assert_eq!(framebuffer[8 * SCREEN_WIDTH], 0x0F);
+
+        // Scroll 4 pixels right and 1 pixel down
+        ppu.write_register(5, 4, false, &mut cartridge);
+        ppu.write_register(5, 1, false, &mut cartridge);
+        // Frame 2 already copied the vertical scroll on the pre-render scanline, so look at frame 3
+        run_until(&mut ppu, &mut cartridge, 4, 0);
+        let framebuffer = ppu.framebuffer();
+        assert_eq!(framebuffer[0..4], [0x16; 4]);
+        assert_eq!(framebuffer[4], 0x0F);
+        assert_eq!(framebuffer[6 * SCREEN_WIDTH], 0x16);
+        assert_eq!(framebuffer[7 * SCREEN_WIDTH], 0x0F);
+    }
+
+    #[test]
+    fn test_render_sprite_and_sprite_zero_hit() {
+        let (mut ppu, mut cartridge) = initialize_rendering();
+        // Sprite 0: Y=0 (drawn from scanline 1), tile 1, X=4
+        ppu.oam[0..4].copy_from_slice(&[0, 1, 0, 4]);
+        ppu.write_register(1, 0b0001_1110, false, &mut cartridge);
+
+        run_until(&mut ppu, &mut cartridge, 1, 10);
+        assert_eq!(ppu.read_register(2, true, &mut cartridge) & 0x40, 0x40);
+        let framebuffer = ppu.framebuffer();
+        assert_eq!(framebuffer[SCREEN_WIDTH + 3], 0x16);
+        assert_eq!(framebuffer[SCREEN_WIDTH + 4..SCREEN_WIDTH + 12], [0x2A; 8]);
+        assert_eq!(framebuffer[SCREEN_WIDTH + 12], 0x0F);
+
+        // Cleared at the pre-render scanline
+        run_until(&mut ppu, &mut cartridge, 2, 0);
+        assert_eq!(ppu.read_register(2, true, &mut cartridge) & 0x40, 0);
+
+        // Behind the background
+        ppu.oam[2] = 0b0010_0000;
+        run_until(&mut ppu, &mut cartridge, 2, 10);
+        assert_eq!(ppu.framebuffer()[SCREEN_WIDTH + 4..SCREEN_WIDTH + 8], [0x16; 4]);
+        assert_eq!(ppu.framebuffer()[SCREEN_WIDTH + 8..SCREEN_WIDTH + 12], [0x2A; 4]);
+    }
+
+    #[test]
+    fn test_sprite_limit() {
+        let (mut ppu, mut cartridge) = initialize_rendering();
+        // 9 sprites on scanline 1, 16 pixels apart
+        ppu.oam.fill(0xFF);
+        for i in 0..9 {
+            ppu.oam[i * 4..i * 4 + 4].copy_from_slice(&[0, 1, 0, 100 + 16 * i as u8]);
+        }
+        ppu.write_register(1, 0b0001_0100, false, &mut cartridge); // Show sprites, including the left 8 pixels
+
+        run_until(&mut ppu, &mut cartridge, 1, 10);
+        assert_eq!(ppu.read_register(2, true, &mut cartridge) & 0x20, 0x20);
+        assert_eq!(ppu.framebuffer()[SCREEN_WIDTH + 100 + 16 * 7], 0x2A);
+        assert_eq!(ppu.framebuffer()[SCREEN_WIDTH + 100 + 16 * 8], 0x0F);
+
+        // Without the limit the 9th sprite is drawn, the overflow flag doesn't change
+        ppu.set_sprite_limit(false);
+        run_until(&mut ppu, &mut cartridge, 2, 10);
+        assert_eq!(ppu.read_register(2, true, &mut cartridge) & 0x20, 0x20);
+        assert_eq!(ppu.framebuffer()[SCREEN_WIDTH + 100 + 16 * 8], 0x2A);
+    }
+
+    #[test]
+    fn test_sprite_rotation() {
+        let (mut ppu, mut cartridge) = initialize_rendering();
+        // Sprite 0 and 9 more sprites on scanline 1, 16 pixels apart
+        ppu.oam.fill(0xFF);
+        for i in 0..10 {
+            ppu.oam[i * 4..i * 4 + 4].copy_from_slice(&[0, 1, 0, 16 * i as u8]);
+        }
+        ppu.write_register(1, 0b0001_0100, false, &mut cartridge);
+        ppu.set_sprite_rotation(true);
+
+        // Each frame other sprites are dropped, sprite 0 is always drawn
+        let mut dropped = vec![];
+        for frame in 1..=3 {
+            run_until(&mut ppu, &mut cartridge, frame, 10);
+            let drawn: Vec<bool> = (0..10).map(|i| ppu.framebuffer()[SCREEN_WIDTH + 16 * i] == 0x2A).collect();
+            assert!(drawn[0]);
+            assert_eq!(drawn.iter().filter(|&&drawn| drawn).count(), 8);
+            assert_eq!(ppu.read_register(2, true, &mut cartridge) & 0x20, 0x20);
+            dropped.push(drawn);
+        }
+        assert!(dropped[0] != dropped[1] && dropped[1] != dropped[2]);
+    }
+
+    #[test]
+    fn test_layers() {
+        let (mut ppu, mut cartridge) = initialize_rendering();
+        ppu.set_layers_enabled(true);
+        // Sprite behind the background, at X=4
+        ppu.oam[0..4].copy_from_slice(&[0, 1, 0b0010_0000, 4]);
+        ppu.write_register(1, 0b0001_1110, false, &mut cartridge);
+
+        run_until(&mut ppu, &mut cartridge, 1, 10);
+        let layers = ppu.layers().unwrap();
+        assert_eq!(ppu.framebuffer()[SCREEN_WIDTH + 4..SCREEN_WIDTH + 10], [0x16, 0x16, 0x16, 0x16, 0x2A, 0x2A]);
+        assert_eq!(layers.background[SCREEN_WIDTH + 4..SCREEN_WIDTH + 10], [0x16, 0x16, 0x16, 0x16, TRANSPARENT, TRANSPARENT]);
+        assert_eq!(layers.sprites[SCREEN_WIDTH + 2..SCREEN_WIDTH + 6], [TRANSPARENT, TRANSPARENT, 0x2A, 0x2A]);
+    }
+
+    #[test]
+    fn test_scanline_renderer() {
+        // The same picture as the dot renderer: scrolled background, attributes, a sprite and sprite 0 hit
+        let [dot, scanline] = [Renderer::Dot, Renderer::Scanline].map(|renderer| {
+            let (mut ppu, mut cartridge) = initialize_rendering();
+            ppu.set_renderer(renderer);
+            for i in 0..40 {
+                ppu.write_vram(0x2000 + i * 3, 0x01, &mut cartridge);
+            }
+            ppu.write_vram(0x23C0, 0b0000_0100, &mut cartridge); // Top right quadrant uses palette 1
+            ppu.write_vram(0x3F05, 0x21, &mut cartridge);
+            ppu.oam[0..4].copy_from_slice(&[4, 1, 0, 6]);
+            ppu.write_register(5, 3, false, &mut cartridge);
+            ppu.write_register(5, 2, false, &mut cartridge);
+            ppu.write_register(1, 0b0001_1110, false, &mut cartridge);
+
+            run_until(&mut ppu, &mut cartridge, 3, 20);
+            (ppu.framebuffer().to_vec(), ppu.read_register(2, true, &mut cartridge))
+        });
+        assert_eq!(dot.1, scanline.1);
+        assert_eq!(scanline.1 & 0x40, 0x40);
+        assert!(dot.0 == scanline.0);
+        assert!(dot.0.contains(&0x21));
+    }
+
+    #[test]
+    fn test_attribute_quadrants() {
+        // Each attribute byte covers 4x4 tiles: bits 0-1 top left 2x2 tiles, 2-3 top right, 4-5 bottom left, 6-7 bottom right
+        let attributes = [
+            (0x23C0, 0b11_10_01_00),
+            (0x23C1, 0b00_01_10_11),
+            (0x23C8, 0b01_00_11_10),
+            (0x23C9, 0b10_11_00_01),
+        ];
+        let colors = [0x16, 0x21, 0x2A, 0x30];
+        for renderer in [Renderer::Dot, Renderer::Scanline] {
+            let (mut ppu, mut cartridge) = initialize_rendering();
+            ppu.set_renderer(renderer);
+            for i in 0..0x3C0 {
+                ppu.write_vram(0x2000 + i, 0x01, &mut cartridge);
+            }
+            for (addr, attribute) in attributes {
+                ppu.write_vram(addr, attribute, &mut cartridge);
+            }
+            for (palette, &color) in colors.iter().enumerate() {
+                ppu.write_vram(0x3F01 + palette as u16 * 4, color, &mut cartridge);
+            }
+            ppu.write_register(1, 0b0000_1010, false, &mut cartridge); // Show background, including the left 8 pixels
+
+            run_until(&mut ppu, &mut cartridge, 2, 0);
+            let framebuffer = ppu.framebuffer();
+            // The 8x8 tiles covered by the 4 attribute bytes
+            for tile_y in 0..8 {
+                for tile_x in 0..8 {
+                    let attribute = attributes[(tile_y / 4) * 2 + tile_x / 4].1;
+                    let quadrant = (tile_y & 2) * 2 + (tile_x & 2);
+                    let color = colors[(attribute >> quadrant) as usize & 0b11];
+                    for y in tile_y * 8..tile_y * 8 + 8 {
+                        for x in tile_x * 8..tile_x * 8 + 8 {
+                            assert_eq!(framebuffer[y * SCREEN_WIDTH + x], color,
+                                "{:?} renderer, tile ({}, {}), pixel ({}, {})", renderer, tile_x, tile_y, x, y);
+                        }
+                    }
+                }
+            }
+            // The attribute bytes that weren't written select palette 0
+            assert_eq!(framebuffer[64 * SCREEN_WIDTH + 64], colors[0]);
+        }
+    }
+
+    #[test]
+    fn test_backdrop_greyscale_and_emphasis() {
+        let (mut ppu, mut cartridge) = initialize_rendering();
+        ppu.write_vram(0x23C0, 0xFF, &mut cartridge); // The top left tiles use palette 3
+        ppu.write_vram(0x3F0D, 0x16, &mut cartridge);
+        // Color 0 of the palettes 1-3 is never shown, the transparent pixels are the backdrop $3F00
+        for addr in [0x3F04, 0x3F08, 0x3F0C] {
+            ppu.write_vram(addr, 0x2A, &mut cartridge);
+        }
+        ppu.write_register(1, 0b0000_1010, false, &mut cartridge);
+        run_until(&mut ppu, &mut cartridge, 2, 0);
+        assert_eq!(ppu.framebuffer()[0], 0x16);
+        assert_eq!(ppu.framebuffer()[8], 0x0F);
+
+        // $3F10 is the backdrop too
+        ppu.write_vram(0x3F10, 0x21, &mut cartridge);
+        run_until(&mut ppu, &mut cartridge, 3, 0);
+        assert_eq!(ppu.framebuffer()[8], 0x21);
+
+        // Greyscale keeps the brightness of the colors
+        ppu.write_register(1, 0b0000_1011, false, &mut cartridge);
+        run_until(&mut ppu, &mut cartridge, 4, 0);
+        assert_eq!(ppu.framebuffer()[0..9], [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x20]);
+        assert_eq!(ppu.emphasis()[0], 0);
+
+        // Red and blue emphasis, the colors stay and the RGB output is darker
+        ppu.write_register(1, 0b1010_1010, false, &mut cartridge);
+        run_until(&mut ppu, &mut cartridge, 5, 0);
+        assert_eq!(ppu.framebuffer()[0], 0x16);
+        assert!(ppu.emphasis().iter().all(|&emphasis| emphasis == 0b101));
+        let palette = ppu.rgb_palette();
+        let (r, g, b) = palette.rgb(0x16, 0);
+        let (red_r, red_g, red_b) = palette.rgb(0x16, 0b001);
+        assert!(red_r <= r && red_g < g && red_b < b);
+        let (blue_r, blue_g, _) = palette.rgb(0x16, 0b101);
+        assert!(blue_r < red_r && blue_g <= red_g);
+        assert_eq!(palette.rgb(0x0F, 0b111), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_background_palette_hack() {
+        for renderer in [Renderer::Dot, Renderer::Scanline] {
+            let (mut ppu, mut cartridge) = initialize_rendering();
+            ppu.set_renderer(renderer);
+            ppu.write_vram(0x3F07, 0x2A, &mut cartridge);
+            // Rendering is disabled, v points to $3F07
+            ppu.write_register(6, 0x3F, false, &mut cartridge);
+            ppu.write_register(6, 0x07, false, &mut cartridge);
+            run_until(&mut ppu, &mut cartridge, 1, 0);
+            assert!(ppu.framebuffer().iter().all(|&color| color == 0x2A));
+
+            // Back to the nametables, the backdrop is $3F00 again
+            ppu.write_register(6, 0x20, false, &mut cartridge);
+            ppu.write_register(6, 0x00, false, &mut cartridge);
+            run_until(&mut ppu, &mut cartridge, 2, 0);
+            assert!(ppu.framebuffer().iter().all(|&color| color == 0x0F));
+
+            // With rendering enabled v is the scroll, the backdrop stays $3F00
+            ppu.write_register(6, 0x3F, false, &mut cartridge);
+            ppu.write_register(6, 0x07, false, &mut cartridge);
+            ppu.write_register(1, 0b0000_1010, false, &mut cartridge);
+            run_until(&mut ppu, &mut cartridge, 3, 0);
+            assert_eq!(ppu.framebuffer()[8], 0x0F);
+        }
+    }
+
+    #[test]
+    fn test_sprite_evaluation() {
+        // 8 sprites drawn on scanlines 16-23 (evaluated on scanlines 15-22), the other bytes of OAM are out of range
+        let overflow = |sprite_8: [u8; 4], sprite_9: [u8; 4]| {
+            let (mut ppu, mut cartridge) = initialize_rendering();
+            ppu.oam = [0xF0; 256];
+            for i in 0..8 {
+                ppu.oam[i * 4] = 15;
+            }
+            ppu.oam[32..36].copy_from_slice(&sprite_8);
+            ppu.oam[36..40].copy_from_slice(&sprite_9);
+            ppu.write_register(1, 0b0001_1000, false, &mut cartridge);
+            run_until(&mut ppu, &mut cartridge, 1, 20);
+            assert_eq!(ppu.secondary_oam, [15, 0xF0, 0xF0, 0xF0].repeat(8)[..]);
+            bits::get(ppu.ppu_status, 5)
+        };
+        assert!(overflow([15, 0xF0, 0xF0, 0xF0], [0xF0; 4]));
+        // After 8 sprites the evaluation checks the tile of sprite 9 as its Y: it misses the 9th sprite...
+        assert!(!overflow([0xF0; 4], [15, 0xF0, 0xF0, 0xF0]));
+        // ... or sees one that isn't there
+        assert!(overflow([0xF0; 4], [0xF0, 15, 0xF0, 0xF0]));
+
+        // OAMADDR written during rendering: the evaluation of the scanline starts at the tile of sprite 0
+        let (mut ppu, mut cartridge) = initialize_rendering();
+        ppu.oam = [0xF0; 256];
+        ppu.oam[0..8].copy_from_slice(&[0xF0, 20, 1, 0x30, 0xF0, 0xF0, 0xF0, 0xF0]);
+        ppu.write_register(1, 0b0001_1000, false, &mut cartridge);
+        run_until(&mut ppu, &mut cartridge, 1, 20);
+        ppu.write_register(3, 1, false, &mut cartridge);
+        run_until(&mut ppu, &mut cartridge, 1, 21);
+        // The Y of the sprites out of range are written to the next slot too
+        assert_eq!(ppu.secondary_oam[0..5], [20, 1, 0x30, 0xF0, 0xF0]);
+        assert_eq!(ppu.evaluation.found, 1);
+        assert_eq!(ppu.oam_addr, 0); // Reset by the sprite fetches
+
+        // OAMADDR 8 or more when rendering starts copies its 8 bytes to the start of OAM
+        run_until(&mut ppu, &mut cartridge, 1, 241);
+        ppu.write_register(3, 0x1B, false, &mut cartridge);
+        ppu.oam[0x18..0x20].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        run_until(&mut ppu, &mut cartridge, 2, 0);
+        assert_eq!(ppu.oam[0..8], [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_oam_data() {
+        let (mut ppu, mut cartridge) = initialize_rendering();
+        let run_until_dot = |ppu: &mut PPU, cartridge: &mut Cartridge, scanline: u16, dot: u16| {
+            run_until(ppu, cartridge, 1, scanline);
+            while ppu.dot() < dot {
+                ppu.tick(cartridge);
+            }
+        };
+        ppu.write_register(3, 0, false, &mut cartridge);
+        for value in [15, 1, 0xFF, 4] {
+            ppu.write_register(4, value, false, &mut cartridge);
+        }
+        // Bits 2-4 of the attributes read back as 0
+        ppu.write_register(3, 1, false, &mut cartridge);
+        assert_eq!(ppu.read_register(4, false, &mut cartridge), 1);
+        ppu.write_register(3, 2, false, &mut cartridge);
+        assert_eq!(ppu.read_register(4, false, &mut cartridge), 0xE3);
+
+        // During rendering OAMDATA reads the secondary OAM clear, then the sprite the evaluation found
+        ppu.write_register(1, 0b0001_1000, false, &mut cartridge);
+        run_until_dot(&mut ppu, &mut cartridge, 20, 30);
+        assert_eq!(ppu.read_register(4, false, &mut cartridge), 0xFF);
+        run_until_dot(&mut ppu, &mut cartridge, 20, 257 + 1);
+        assert_eq!(ppu.read_register(4, false, &mut cartridge), 1); // Tile of the sprite
+        run_until_dot(&mut ppu, &mut cartridge, 20, 257 + 8 + 1);
+        assert_eq!(ppu.read_register(4, false, &mut cartridge), 0xFF); // Tile of the second slot, empty
+
+        // Writes during rendering are lost and move OAMADDR to the next sprite
+        run_until_dot(&mut ppu, &mut cartridge, 20, 330);
+        ppu.write_register(3, 5, false, &mut cartridge);
+        ppu.write_register(4, 0x42, false, &mut cartridge);
+        assert_eq!(ppu.oam[5], 0);
+        assert_eq!(ppu.oam_addr, 9);
+    }
+
     #[test]
     fn test_pattern_table() {
-        let ppu = initialize();
+        let (ppu, mut cartridge) = initialize();
 
         // Test a single tile in background pattern table
         {
-            let first_tile = ppu.get_pattern_tile(0, true);
+            let first_tile = ppu.get_pattern_tile(0, true, &mut cartridge);
             assert!(first_tile.iter().all(|&x| x == 0));
 
-            let tile = ppu.get_pattern_tile(0xF, true);
-            print_tile(tile);
+            let tile = ppu.get_pattern_tile(0xF, true, &mut cartridge);
+            print_tile(&tile);
             // Test the shape of the tile
             for _ in 0..16 {
                 assert!(tile[3] == 0x18);
@@ -205,6 +1623,21 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_pattern_table_banks() {
+        // MMC5 with 4 CHR banks of 8KB, each byte is the bank number + 1 for the left table, + 0x10 for the right table
+        let chr = (0..32 * 1024).map(|i| (i / 0x2000) as u8 + 1 + if i & 0x1000 != 0 { 0x10 } else { 0 }).collect();
+        let mut cartridge = Cartridge::from_prg_chr(vec![0; 1024 * 32], chr, 5, MirrorType::HORIZONTAL, None).unwrap();
+        let ppu = new_ppu(&cartridge);
+        assert_eq!(ppu.get_pattern_tile(0, true, &mut cartridge), [1; 16]);
+        assert_eq!(ppu.get_pattern_tile(0xFF, false, &mut cartridge), [0x11; 16]);
+
+        // Switch to the 8KB CHR bank 3
+        cartridge.cpu_write(0x5127, 3, false);
+        assert_eq!(ppu.get_pattern_tile(0, true, &mut cartridge), [4; 16]);
+        assert_eq!(ppu.get_pattern_tile(0x80, false, &mut cartridge), [0x14; 16]);
+    }
+
     fn print_tile(tile: &[u8]) {
         println!("Lower 8 bytes:");
         for (i, b) in (&tile[0..8]).iter().enumerate() {
@@ -0,0 +1,45 @@
+//! Tracing spans around the emulation boundaries (frame, scanline, instruction, DMA).
+//!
+//! Build with `cargo run --features tracing` and a chrome trace file (`trace-<timestamp>.json`) is written
+//! when the emulator exits. Open it with `chrome://tracing` or https://ui.perfetto.dev to get a flamegraph of where
+//! emulation time goes.
+//!
+//! The level can be changed with the `NES_TRACE` environment variable. Instruction spans are at `trace` level
+//! (there are ~30K of them per frame), everything else is at `debug` level, which is the default:
+//!
+//! `NES_TRACE=trace cargo run --features tracing`
+//!
+//! Without the feature, the `span!` macro expands to nothing, so there is no overhead.
+
+/// Enter a span until the end of the current scope.
+///
+/// `span!(DEBUG, "frame")` or with fields: `span!(TRACE, "instruction", pc = self.registers.PC)`
+#[macro_export]
+macro_rules! span {
+	($level:ident, $name:expr $(, $($fields:tt)*)?) => {
+		#[cfg(feature = "tracing")]
+		let _span = tracing::span!(target: "nes", tracing::Level::$level, $name $(, $($fields)*)?).entered();
+	};
+}
+
+pub use span;
+
+/// Install the chrome trace subscriber. Keep the returned guard alive until exit, dropping it flushes the trace file.
+#[cfg(feature = "tracing")]
+pub fn init() -> tracing_chrome::FlushGuard {
+	use tracing_subscriber::prelude::*;
+	use tracing_subscriber::filter::LevelFilter;
+
+	let level = std::env::var("NES_TRACE")
+		.ok()
+		.and_then(|s| s.parse::<LevelFilter>().ok())
+		.unwrap_or(LevelFilter::DEBUG);
+
+	let (chrome_layer, guard) = tracing_chrome::ChromeLayerBuilder::new()
+		.include_args(true)
+		.build();
+	tracing_subscriber::registry()
+		.with(chrome_layer.with_filter(level))
+		.init();
+	guard
+}
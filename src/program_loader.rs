@@ -9,6 +9,31 @@ pub fn write_rom(rom_memory: &mut [u8;32_768], dump: &str) {
 	}
 }
 
+/// Write the reset vector ($FFFC-$FFFD), so the CPU starts executing the program at the given address.
+pub fn set_reset_vector(rom_memory: &mut [u8;32_768], addr: u16) {
+	rom_memory[0x7FFC] = addr as u8;
+	rom_memory[0x7FFD] = (addr >> 8) as u8;
+}
+
+/// Like `set_reset_vector`, but first wait for the PPU warm-up like games do, for the ROM files of the tests.
+pub fn set_reset_vector_after_warm_up(rom_memory: &mut [u8;32_768], addr: u16) {
+	/*
+	; $FF00
+	vblankwait1:
+		BIT $2002
+		BPL vblankwait1
+	vblankwait2:
+		BIT $2002
+		BPL vblankwait2
+	JMP addr
+	*/
+	let mut routine = [0; 32_768];
+	write_rom(&mut routine, "2c 02 20 10 fb 2c 02 20 10 fb 4c 00 00");
+	routine[11] = addr as u8;
+	routine[12] = (addr >> 8) as u8;
+	rom_memory[0x7F00..0x7F0D].copy_from_slice(&routine[..13]);
+	set_reset_vector(rom_memory, 0xFF00);
+}
 
 // Each function loads a program to memory, and returns amount of assembly lines used.
 
@@ -50,8 +75,7 @@ pub fn load_program_adc(rom: &mut [u8;32_768]) -> u8 {
 	SED
 	LDA #$09
 	CLC
-	ADC #$02 	; A will be 0x11, because decimal bitflag is enabled (i.e. represent the sum in 'decimal' (11) form, not hex (0xB) form)
-				; NOTE: In NES, the decimal mode is not used. Perhaps I should remove this feature?
+	ADC #$02 	; A will be 0x0B too: the 2A03 has no decimal mode, the decimal bitflag is set but ignored
 	
 	CLD
 	LDA #$FF
@@ -72,6 +96,24 @@ pub fn load_program_adc(rom: &mut [u8;32_768]) -> u8 {
 	19
 }
 
+/// The D flag is only a bit on the 2A03: SED/CLD toggle it, PHP/PLP keep it, ADC ignores it.
+pub fn load_program_decimal_flag(rom: &mut [u8;32_768]) -> u8 {
+	/*
+	SED
+	PHP			; Pushes P with B and bit 5 set
+	CLD
+	PLP			; D is set again, B is not
+	LDA #$09
+	CLC
+	ADC #$01	; A will be 0x0A, not 0x10
+	PHP
+	PLA
+	NOP
+	*/
+	write_rom(rom, "F8 08 D8 28 A9 09 18 69 01 08 68 EA");
+	10
+}
+
 pub fn load_program_absolute_store(rom: &mut [u8;32_768]) -> u8 {
 	/*
 	SEI
@@ -332,6 +374,434 @@ pub fn load_program_bcc(rom: &mut [u8;32_768]) -> u8 {
 	8
 }
 
+pub fn load_program_run_helpers(rom: &mut [u8;32_768]) -> u8 {
+	/*
+	LDA #$01
+	STA $0200
+
+	LDX #$05
+	loop:
+		DEX
+		BNE loop
+
+	STA $0300
+
+	end:
+		JMP end
+	*/
+	write_rom(rom, "a9 01 8d 00 02 a2 05 ca d0 fd 8d 00 03 4c 0d 80");
+	7
+}
+
+pub fn load_program_read_controller(rom: &mut [u8;32_768]) -> u8 {
+	/*
+	LDA #$01
+	STA $4016
+	LDA #$00
+	STA $4016 	; Latch the buttons
+
+	LDA $4016 	; A
+	AND #$01
+	STA $0200
+	LDA $4016 	; B
+	AND #$01
+	STA $0201
+
+	end:
+		JMP end
+	*/
+	write_rom(rom, "a9 01 8d 16 40 a9 00 8d 16 40 ad 16 40 29 01 8d 00 02 ad 16 40 29 01 8d 01 02 4c 1a 80");
+	11
+}
+
+pub fn load_program_ppu_status_poll(rom: &mut [u8;32_768]) -> u8 {
+	/*
+	vblankwait:
+		LDA $2002
+		BPL vblankwait
+
+	STA $0200
+
+	end:
+		JMP end
+	*/
+	write_rom(rom, "ad 02 20 10 fb 8d 00 02 4c 08 80");
+	4
+}
+
+pub fn load_program_ppudata(rom: &mut [u8;32_768]) -> u8 {
+	/*
+	LDA #$21
+	STA $2006
+	LDA #$08
+	STA $2006 	; VRAM address = $2108
+
+	LDA #$AB
+	STA $2007 	; $2108 = $AB, VRAM address = $2109
+
+	LDA #$21
+	STA $2006
+	LDA #$08
+	STA $2006 	; VRAM address = $2108
+
+	LDA $2007 	; A = stale read buffer, read buffer = $AB, VRAM address = $2109
+
+	end:
+		JMP end
+	*/
+	write_rom(rom, "a9 21 8d 06 20 a9 08 8d 06 20 a9 ab 8d 07 20 a9 21 8d 06 20 a9 08 8d 06 20 ad 07 20 4c 1c 80");
+	12
+}
+
+/// MMC5 scanline IRQ. The program is in the last 8KB bank, which MMC5 maps to $E000 at power on.
+pub fn load_program_mmc5_irq(rom: &mut [u8;32_768]) -> u8 {
+	/*
+	; $E000
+	LDA #$40
+	STA $4017 	; Inhibit the APU frame IRQ, like games do
+	CLI
+	LDA #$02
+	STA $5203 	; IRQ on scanline 2
+	LDA #$80
+	STA $5204 	; Enable IRQ
+	LDA #$08
+	STA $2001 	; Show background, the scanline counter only runs while rendering
+
+	loop:		; $E015
+		JMP loop
+
+	irq:		; $E018
+		LDA $5204 	; Acknowledge
+		STA $0200
+		RTI
+	*/
+	let mut program = [0; 32_768];
+	write_rom(&mut program, "a9 40 8d 17 40 58 a9 02 8d 03 52 a9 80 8d 04 52 a9 08 8d 01 20 4c 15 e0 ad 04 52 8d 00 02 40");
+	rom[0x6000..0x8000].copy_from_slice(&program[..0x2000]);
+
+	// IRQ vector
+	rom[0x7FFE] = 0x18;
+	rom[0x7FFF] = 0xE0;
+	12
+}
+
+pub fn load_program_mmc5_exram(rom: &mut [u8;32_768]) -> u8 {
+	/*
+	; $E000
+	LDA #$02
+	STA $5104 	; ExRAM as RAM
+	LDA #$42
+	STA $5C10
+	LDA $5C10
+	STA $0200
+	LDA $5800 	; Nothing at $5800, open bus
+	STA $0201
+
+	end:
+		JMP end
+	*/
+	let mut program = [0; 32_768];
+	write_rom(&mut program, "a9 02 8d 04 51 a9 42 8d 10 5c ad 10 5c 8d 00 02 ad 00 58 8d 01 02 4c 16 e0");
+	rom[0x6000..0x8000].copy_from_slice(&program[..0x2000]);
+	9
+}
+
+pub fn load_program_read_prg_ram(rom: &mut [u8;32_768]) -> u8 {
+	/*
+	LDA $6000
+	STA $0200
+	*/
+	write_rom(rom, "ad 00 60 8d 00 02");
+	2
+}
+
+pub fn load_program_frame_irq(rom: &mut [u8;32_768]) -> u8 {
+	/*
+	CLI
+
+	loop:		; $8001
+		JMP loop
+
+	irq:		; $8004
+		LDA $4015 	; Acknowledge the frame IRQ
+		STA $0200
+		RTI
+	*/
+	write_rom(rom, "58 4c 01 80 ad 15 40 8d 00 02 40");
+
+	// IRQ vector
+	rom[0x7FFE] = 0x04;
+	rom[0x7FFF] = 0x80;
+	5
+}
+
+pub fn load_program_nmi_counter(rom: &mut [u8;32_768]) -> u8 {
+	/*
+	LDA #$80
+	STA $2000 	; NMI on vblank
+	LDA #$1E
+	STA $2001 	; Show background and sprites
+
+	loop:		; $800A
+		INC $01
+		JMP loop
+
+	nmi:		; $800F
+		INC $00 	; Frames
+		RTI
+	*/
+	write_rom(rom, "a9 80 8d 00 20 a9 1e 8d 01 20 e6 01 4c 0a 80 e6 00 40");
+
+	// NMI vector
+	rom[0x7FFA] = 0x0F;
+	rom[0x7FFB] = 0x80;
+	8
+}
+
+pub fn load_program_count_a_presses(rom: &mut [u8;32_768]) -> u8 {
+	/*
+	LDA #$80
+	STA $2000 	; NMI on vblank
+
+	loop:		; $8005
+		JMP loop
+
+	nmi:		; $8008
+		LDA #$01
+		STA $4016
+		LDA #$00
+		STA $4016 	; Latch the buttons
+		LDA $4016 	; A
+		AND #$01
+		BEQ skip
+		INC $0200 	; Frames with A pressed
+	skip:
+		RTI
+	*/
+	write_rom(rom, "a9 80 8d 00 20 4c 05 80 a9 01 8d 16 40 a9 00 8d 16 40 ad 16 40 29 01 f0 03 ee 00 02 40");
+
+	// NMI vector
+	rom[0x7FFA] = 0x08;
+	rom[0x7FFB] = 0x80;
+	12
+}
+
+pub fn load_program_kil(rom: &mut [u8;32_768]) -> u8 {
+	/*
+	LDA #$80
+	STA $2000 	; NMI on vblank
+	LDA #$2A
+	STA $0001
+	KIL 		; $800A, jams the CPU
+
+	nmi:		; $800B
+		INC $00 	; Never happens
+		RTI
+	*/
+	write_rom(rom, "a9 80 8d 00 20 a9 2a 8d 01 00 02 e6 00 40");
+
+	// NMI vector
+	rom[0x7FFA] = 0x0B;
+	rom[0x7FFB] = 0x80;
+	5
+}
+
+/// Indexed addresses past $FFFF wrap to the zero page.
+pub fn load_program_absolute_indexed_wrap(rom: &mut [u8;32_768]) -> u8 {
+	/*
+	LDA #$5A
+	STA $01
+	LDX #$02
+	LDY #$FF
+	LDA $FFFF,X 	; $0001
+	STA $FF01,Y 	; $0000
+	*/
+	write_rom(rom, "a9 5a 85 01 a2 02 a0 ff bd ff ff 99 01 ff");
+	6
+}
+
+/// Runs over $FFFF: the PC and an operand wrap to $0000, in RAM.
+pub fn load_program_pc_wrap(rom: &mut [u8;32_768]) -> u8 {
+	/*
+	LDA #$4C
+	STA $00
+	LDA #$FF
+	STA $01
+	STA $02 	; JMP $FFFF at $0000
+	JMP $FFFE
+
+	; $FFFE
+	LDA #$A2 	; The operand is at $FFFF, the next instruction at $0000
+	; $FFFF
+	LDX #$4C 	; The operand is at $0000, the JMP opcode
+	*/
+	write_rom(rom, "a9 4c 85 00 a9 ff 85 01 85 02 4c fe ff");
+	rom[0x7FFE] = 0xA9;
+	rom[0x7FFF] = 0xA2;
+	8
+}
+
+pub fn load_program_scroll(rom: &mut [u8;32_768]) -> u8 {
+	/*
+	LDA #$00
+	STA $2006
+	LDA #$10
+	STA $2006
+	LDA #$FF
+	STA $2007 	; x8, tile 1 is solid color 1
+	LDA #$20
+	STA $2006
+	LDA #$00
+	STA $2006
+	LDA #$01
+	STA $2007 	; Top left tile of nametable 0
+	LDA #$3F
+	STA $2006
+	LDA #$00
+	STA $2006
+	LDA #$0F
+	STA $2007 	; Backdrop
+	LDA #$16
+	STA $2007 	; Background palette 0, color 1
+	LDA #$00
+	STA $2006 	; Half a PPUADDR write: the toggle is on the second write
+	LDA $2002 	; Resets the toggle
+	LDA #$FB
+	STA $2005 	; X scroll 251
+	LDA #$02
+	STA $2005 	; Y scroll 2
+	LDA #$0A
+	STA $2001 	; Show the background, also in the left 8 pixels
+
+	loop:		; $805E
+		JMP loop
+	*/
+	write_rom(rom, "a9 00 8d 06 20 a9 10 8d 06 20 a9 ff 8d 07 20 8d 07 20 8d 07 20 8d 07 20 8d 07 20 8d 07 20 8d 07 20 8d 07 20 a9 20 8d 06 20 a9 00 8d 06 20 a9 01 8d 07 20 a9 3f 8d 06 20 a9 00 8d 06 20 a9 0f 8d 07 20 a9 16 8d 07 20 a9 00 8d 06 20 ad 02 20 a9 fb 8d 05 20 a9 02 8d 05 20 a9 0a 8d 01 20 4c 5e 80");
+	37
+}
+
+/// Play a DMC sample with the VRC6 PRG banks and the sample from RAM: $00 is the 16KB bank at $8000, $01 the 8KB bank at
+/// $C000, $02 and $03 the sample address and length ($4012, $4013). The output starts at 64. Runs at $E8E1.
+pub fn load_program_dmc_banked_sample(rom: &mut [u8;32_768]) -> u8 {
+	/*
+	LDA $00
+	STA $8000
+	LDA $01
+	STA $C000
+	LDA #$0F
+	STA $4010 	; Fastest rate, no IRQ, no loop
+	LDA #$40
+	STA $4011
+	LDA $02
+	STA $4012
+	LDA $03
+	STA $4013
+	LDA #$10
+	STA $4015 	; Start the sample
+
+	loop:		; $E904
+		JMP loop
+	*/
+	write_rom(rom, "a5 00 8d 00 80 a5 01 8d 00 c0 a9 0f 8d 10 40 a9 40 8d 11 40 a5 02 8d 12 40 a5 03 8d 13 40 a9 10 8d 15 40 4c 04 e9");
+	15
+}
+
+pub fn load_program_sbc(rom: &mut [u8;32_768]) -> u8 {
+	/*
+	SEC
+	LDA #$50
+	SBC #$F0	; A = $60, borrow (C = 0)
+
+	SEC
+	LDA #$50
+	SBC #$B0	; A = $A0, overflow (80 - -80)
+
+	CLC			; Borrow in
+	LDA #$05
+	SBC #$01	; A = $03, no borrow (C = 1)
+
+	NOP
+	*/
+	write_rom(rom, "38 a9 50 e9 f0 38 a9 50 e9 b0 18 a9 05 e9 01 ea");
+	10
+}
+
+pub fn load_program_rts(rom: &mut [u8;32_768]) -> u8 {
+	/*
+	JSR sub		; $8000
+	LDA #$01	; $8003
+	NOP
+
+	sub:		; $8006
+		LDX #$02
+		RTS
+	*/
+	write_rom(rom, "20 06 80 a9 01 ea a2 02 60");
+	5
+}
+
+pub fn load_program_rotate(rom: &mut [u8;32_768]) -> u8 {
+	/*
+	SEC
+	LDA #$81
+	ROL A		; A = $03, C = 1
+	ROR A		; A = $81, C = 1
+	CLC
+	ROR A		; A = $40, C = 1
+
+	LDX #$80
+	STX $10
+	ROL $10		; $10 = $01, C = 1
+	LSR $10		; $10 = $00, C = 1
+
+	NOP
+	*/
+	write_rom(rom, "38 a9 81 2a 6a 18 6a a2 80 86 10 26 10 46 10 ea");
+	11
+}
+
+/// BRK jumps to the IRQ vector ($8010), RTI returns after the break mark.
+pub fn load_program_brk(rom: &mut [u8;32_768]) -> u8 {
+	/*
+	LDA #$01
+	BRK
+	.byte $FF	; Break mark
+	LDX #$02	; $8004
+	NOP
+
+	.org $8010
+	irq:
+		LDA #$03
+		RTI
+	*/
+	write_rom(rom, "a9 01 00 ff a2 02 ea 00 00 00 00 00 00 00 00 00 a9 03 40");
+	rom[0x7FFE] = 0x10;
+	rom[0x7FFF] = 0x80;
+	6
+}
+
+pub fn load_program_indirect_indexed(rom: &mut [u8;32_768]) -> u8 {
+	/*
+	LDA #$00
+	STA $FF
+	LDA #$02
+	STA $00			; ($FF) = $0200, the high byte wraps to $00
+
+	LDA #$5A
+	LDY #$10
+	STA ($FF),Y		; $0210 = $5A
+	LDX #$04
+	STA ($FB,X)		; $0200 = $5A
+
+	LDA #$00
+	LDA ($FF),Y		; A = $5A
+
+	NOP
+	*/
+	write_rom(rom, "a9 00 85 ff a9 02 85 00 a9 5a a0 10 91 ff a2 04 81 fb a9 00 b1 ff ea");
+	12
+}
+
 // pub fn load_program_page_crossed(rom: &mut [u8;32_768]) -> u8 {
 // 	// Page cross = 
 // }
@@ -1,89 +1,571 @@
 extern crate sdl2; 
-use sdl2::pixels::Color;
+use sdl2::controller::GameController;
+use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::rect::{Rect};
-use std::time::Duration;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+use std::collections::HashMap;
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::{Duration, Instant};
 use sdl2::rect::Point;
+use log::{error, info, warn};
+
+use crate::audio::{AudioOutput, AudioSettings};
+use crate::apu::{apu::SAMPLE_RATE, sink::AudioSink};
+use crate::config::{Config, CONFIG_PATH};
+use crate::controller::Button;
+use crate::cpu::cpu::CpuHalted;
+use crate::cpu::events::{AccessKind, BusEvent};
+use crate::filter::{FilterChain, Image};
+use crate::input::{Binding, Bindings, InputEvent};
+use crate::nes::NES;
+use crate::ppu::colors::{Palette, PaletteSettings, VideoSetting};
+use crate::ppu::layers::{Layer, Layers};
+use crate::ppu::ppu::{DOTS_PER_SCANLINE, SCANLINES_PER_FRAME, SCREEN_WIDTH, SCREEN_HEIGHT};
+use crate::profiling::span;
+use crate::session::WindowLayout;
+use crate::stats::Stats;
 
 const HORIZONTAL_TILES: u32 = 32;
 const VERTICAL_TILES: u32 = 30;
-const TILE_WIDTH: u32 = 10;
-const TILE_HEIGHT: u32 = 10;
+const WINDOW_TITLE: &str = "NES Emulator - by Shlomi Domnenko";
+// A gamepad axis pushed further than this presses the button bound to that side
+const AXIS_THRESHOLD: i16 = 16_000;
+
+/// What the emulator sends to the frontend: the picture, and the PPU state for the debug overlay.
+pub struct Frame {
+	pub pixels: Vec<u8>,	// NES color indexes, see PPU::framebuffer
+	pub emphasis: Vec<u8>,	// Color emphasis of each scanline, see PPU::emphasis
+	pub palette: Palette,	// See PPU::rgb_palette
+	pub scanline: u16,
+	pub dot: u16,
+	pub events: Vec<BusEvent>,	// PPU/IO register accesses of the last completed frame
+	pub stats: Stats,
+	pub layers: Option<Layers>,	// Background and sprites apart, when the PPU renders them (debugger `layers on`)
+	pub input_latency: Option<Duration>,	// Average input latency, in the input latency diagnostic mode
+	pub halted: Option<CpuHalted>,	// The CPU jammed, the game needs a reset
+	pub samples: Vec<i16>,	// The audio since the last frame, see APU::take_samples
+}
+
+/// What the frontend asks the emulator to do, besides pressing buttons.
+pub enum Command {
+	SaveState(usize),	// Slot
+	LoadState(usize),
+	Reset,
+	SetPalette(PaletteSettings),	// The video settings changed
+	SetSampleRate(u64),	// The dynamic rate control of the audio, see APU::set_sample_rate
+	WindowClosed(WindowLayout),	// Sent last, for the session
+}
+
+impl Frame {
+	pub fn capture(nes: &NES) -> Self {
+		let ppu = nes.cpu.ppu();
+		Frame {
+			pixels: ppu.framebuffer().to_vec(),
+			emphasis: ppu.emphasis().to_vec(),
+			palette: ppu.rgb_palette().clone(),
+			scanline: ppu.scanline(),
+			dot: ppu.dot(),
+			events: nes.cpu.events().last_frame().to_vec(),
+			stats: nes.stats(),
+			layers: ppu.layers().cloned(),
+			input_latency: None,
+			halted: nes.cpu.halted(),
+			samples: vec![],
+		}
+	}
+}
 
-pub fn sdl2_setup() {
+/// Keys:
+/// - F1: beam overlay. Shows the current scanline/dot and where PPUMASK ($2001, yellow), PPUSCROLL ($2005, red) and PPUADDR ($2006, cyan)
+///   were written during the last frame. Useful for debugging raster splits (status bars, parallax).
+/// - F2: tile grid
+/// - F3: event viewer. The whole frame timing (341 dots x 262 scanlines) with the picture inside, and a dot for each PPU/IO register
+///   access of the last frame, colored by register (see `event_color`).
+/// - F4: show the combined picture, the background layer or the sprite layer (needs the debugger `layers on` command).
+///   Transparent pixels are black.
+/// - F5: next filter chain of `filters` (see `filter::presets`). The first one is used on start.
+/// - F6: choose the video setting (brightness, contrast, saturation, hue, gamma), - and + change it. The palette is sent
+///   to `commands`, and the setting saved to the settings file.
+/// - F7, F8: remap the buttons of player 1 or 2. Press a key, gamepad button or push a gamepad stick for each button, Escape
+///   cancels. The bindings are saved to the settings file.
+/// - 0-9: choose the save state slot. F9: save the state to the slot, F10: load it. The states are sent to `commands`.
+/// - F11: mute the audio. Page Down, Page Up: lower or raise the volume. They are saved to the settings file.
+/// - F12: press the reset button, e.g. when the CPU halted (shown in the window title).
+///
+/// The keys and gamepads press the controller buttons of `bindings`, the buttons are sent to `input`.
+///
+/// The samples of the frames are played on the audio device. The sample rate that keeps its buffer at the latency of
+/// `audio_settings` is sent to `commands`.
+///
+/// The window opens with `layout` (the one of the last session) or centered, its layout is sent to `commands` when it
+/// closes.
+pub fn sdl2_setup(frames: Receiver<Frame>, input: Sender<InputEvent>, commands: Sender<Command>, mut filters: Vec<FilterChain>, mut bindings: Bindings, audio_settings: AudioSettings, layout: Option<WindowLayout>) {
 	let sdl_context = sdl2::init().unwrap();
+	let mut audio = AudioOutput::open(&sdl_context, audio_settings)
+		.map_err(|e| warn!("No audio: {}", e))
+		.ok();
     let video_subsystem = sdl_context.video().unwrap();
+	let game_controller_subsystem = sdl_context.game_controller().unwrap();
+	// Gamepads, numbered in the order they were connected. SDL sends the added event for the ones that are connected on start.
+	let mut pads: Vec<Option<GameController>> = vec![];
+	let mut axes: HashMap<(usize, String), i8> = HashMap::new();
+	// The player and the button that is remapped
+	let mut remap: Option<(usize, usize)> = None;
+	let mut state_slot = 0;
  
-    let window = video_subsystem.window("NES Emulator - by Shlomi Domnenko", 800, 800)
-        .position_centered()
-		.resizable()
-        .build()
-        .unwrap();
+	let (width, height) = layout.map_or((800, 800), |layout| (layout.width, layout.height));
+    let mut window = video_subsystem.window(WINDOW_TITLE, width, height);
+	match layout {
+		Some(layout) => window.position(layout.x, layout.y),
+		None => window.position_centered(),
+	};
+	let window = window.resizable().build().unwrap();
  
     let mut canvas = window.into_canvas().build().unwrap();
+	let texture_creator = canvas.texture_creator();
+	let mut texture = texture_creator
+		.create_texture_streaming(PixelFormatEnum::RGB24, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)
+		.unwrap();
+	let mut filter = 0;
  
     canvas.set_draw_color(Color::RGB(0, 255, 255));
     canvas.clear();
     canvas.present();
     let mut event_pump = sdl_context.event_pump().unwrap();
-    let mut i = 0;
 
 	let (mut win_width, mut win_height) = canvas.window_mut().size();
+	let mut frame: Option<Frame> = None;
+	let mut show_beam_overlay = false;
+	let mut show_tile_grid = false;
+	let mut show_event_viewer = false;
+	let mut layer = Layer::Combined;
+	let mut hud_frame = 0;
+	let mut video_setting = VideoSetting::Brightness;
+	let mut next_present = Instant::now();
 
     'running: loop {
-        i = (i + 1) % 255;
+        span!(DEBUG, "present");
 
         for event in event_pump.poll_iter() {
+			match event {
+				Event::Quit {..} => break 'running,
+				Event::ControllerDeviceAdded { which, .. } => match game_controller_subsystem.open(which) {
+					Ok(pad) => {
+						let slot = pads.iter().position(|pad| pad.is_none()).unwrap_or(pads.len());
+						info!("Gamepad {} connected: {}", slot, pad.name());
+						if slot == pads.len() {
+							pads.push(None);
+						}
+						pads[slot] = Some(pad);
+					}
+					Err(e) => warn!("Failed to open gamepad: {}", e),
+				},
+				Event::ControllerDeviceRemoved { which, .. } => {
+					if let Some(slot) = pad_number(&pads, which) {
+						info!("Gamepad {} disconnected", slot);
+						pads[slot] = None;
+					}
+				}
+				_ => {}
+			}
+
+			if let Some((player, index)) = remap {
+				if let Event::KeyDown { keycode: Some(Keycode::Escape), .. } = event {
+					info!("Remapping canceled");
+					remap = None;
+					hud_frame = u64::MAX;
+					continue;
+				}
+				let pressed = binding_events(&event, &pads, &mut axes).into_iter().find(|&(_, pressed)| pressed);
+				if let Some((binding, _)) = pressed {
+					let button = Button::ALL[index];
+					info!("Player {} {}: {}", player + 1, button.name(), binding);
+					bindings.set(player, button, binding);
+					if index + 1 < Button::ALL.len() {
+						remap = Some((player, index + 1));
+						canvas.window_mut().set_title(&remap_prompt(player, index + 1)).unwrap();
+					} else {
+						save_bindings(&bindings);
+						remap = None;
+						hud_frame = u64::MAX;
+					}
+				}
+				continue;
+			}
+
             match event {
-                Event::Quit {..} |
                 Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
                     break 'running
                 },
+				Event::KeyDown { keycode: Some(Keycode::F1), .. } => show_beam_overlay = !show_beam_overlay,
+				Event::KeyDown { keycode: Some(Keycode::F2), .. } => show_tile_grid = !show_tile_grid,
+				Event::KeyDown { keycode: Some(Keycode::F3), .. } => show_event_viewer = !show_event_viewer,
+				Event::KeyDown { keycode: Some(Keycode::F5), .. } if !filters.is_empty() => {
+					filter = (filter + 1) % filters.len();
+					info!("Filter: {}", filters[filter].name());
+				}
+				Event::KeyDown { keycode: Some(Keycode::F6), .. } => {
+					video_setting = video_setting.next();
+					info!("Video setting: {}, - and + change it", video_setting.name());
+				}
+				Event::KeyDown { keycode: Some(key @ (Keycode::Minus | Keycode::KpMinus | Keycode::Equals | Keycode::KpPlus)), .. } => {
+					if let Some(frame) = &mut frame {
+						let mut settings = *frame.palette.settings();
+						settings.adjust(video_setting, if matches!(key, Keycode::Minus | Keycode::KpMinus) { -1 } else { 1 });
+						info!("Video setting: {} = {}", video_setting.name(), settings.get(video_setting));
+						// Shown right away, also when the emulator is paused
+						frame.palette = Palette::new(&settings);
+						let _ = commands.send(Command::SetPalette(settings));
+						save_video_settings(&settings);
+					}
+				}
+				Event::KeyDown { keycode: Some(Keycode::F4), .. } => {
+					layer = layer.next();
+					info!("Showing layer: {:?}", layer);
+				}
+				Event::KeyDown { keycode: Some(key @ (Keycode::F7 | Keycode::F8)), .. } => {
+					let player = if key == Keycode::F7 { 0 } else { 1 };
+					remap = Some((player, 0));
+					canvas.window_mut().set_title(&remap_prompt(player, 0)).unwrap();
+				}
+				Event::KeyDown { keycode: Some(key), .. } if slot_key(key).is_some() => {
+					state_slot = slot_key(key).unwrap();
+					info!("Save state slot {}", state_slot);
+				}
+				// Fails only when the emulator stopped
+				Event::KeyDown { keycode: Some(Keycode::F9), .. } => { let _ = commands.send(Command::SaveState(state_slot)); }
+				Event::KeyDown { keycode: Some(Keycode::F10), .. } => { let _ = commands.send(Command::LoadState(state_slot)); }
+				Event::KeyDown { keycode: Some(Keycode::F12), .. } => { let _ = commands.send(Command::Reset); }
+				Event::KeyDown { keycode: Some(key @ (Keycode::F11 | Keycode::PageDown | Keycode::PageUp)), .. } => {
+					if let Some(audio) = &mut audio {
+						let mut settings = audio.settings().clone();
+						match key {
+							Keycode::F11 => settings.muted = !settings.muted,
+							Keycode::PageDown => settings.adjust_volume(-1),
+							_ => settings.adjust_volume(1),
+						}
+						info!("Volume: {:.0}%{}", settings.volume * 100.0, if settings.muted { " (muted)" } else { "" });
+						save_audio_settings(&settings);
+						audio.set_settings(settings);
+						hud_frame = u64::MAX;
+					}
+				}
 				Event::Window {..} => {
 					(win_width, win_height) = canvas.window_mut().size();
 					//println!("Window size changed");
 				}
-                _ => {}
+                _ => {
+					for (binding, pressed) in binding_events(&event, &pads, &mut axes) {
+						for (player, button) in bindings.buttons(&binding) {
+							// Fails only when the emulator stopped
+							let _ = input.send(InputEvent { player, button, pressed, time: Instant::now() });
+						}
+					}
+				}
             }
         }
 
-		let tile_width: u32 = win_width / TILE_WIDTH;
-		let tile_height: u32 = win_height / TILE_HEIGHT;
-
-		// Loop over tiles
-		for y in 0..VERTICAL_TILES {
-			for x in 0..HORIZONTAL_TILES {
-				let tile_x = x * tile_width;
-				let tile_y = y * tile_height;
-				let rect = Rect::new(tile_x as i32, tile_y as i32, tile_width as u32, tile_height as u32);
-
-				canvas.set_draw_color(Color::RGB(100, 100, 100));
-				canvas.fill_rect(rect).unwrap();
-				canvas.set_draw_color(Color::RGB(230, 230, 230));
-				canvas.draw_rect(rect).unwrap();
-
-				// Loop over pixels per tile
-				canvas.set_draw_color(Color::RGB(0, 200, 0));
-				for pyi in 0..8 {
-					for pxi in 0..8 {
-						// Draw horizontal lines
-						let px: i32 = (tile_x + (tile_width / 8) * pxi) as i32;
-						let p1 = Point::new(px, tile_y as i32);
-						let p2 = Point::new(px, (tile_y + tile_height) as i32);
-						canvas.draw_line(p1, p2).unwrap();
-
-						// Draw vertical lines
-						let py: i32 = (tile_y + (tile_height / 8) * pyi) as i32;
-						let p1 = Point::new(tile_x as i32, py);
-						let p2 = Point::new((tile_x + tile_width) as i32, py);
-						canvas.draw_line(p1, p2).unwrap();
-					}
+		// Only the newest frame is drawn, the audio of all of them is played
+		while let Ok(new_frame) = frames.try_recv() {
+			if let Some(audio) = &mut audio {
+				audio.push_samples(&new_frame.samples);
+				if let Some(rate) = audio.take_rate_change() {
+					let _ = commands.send(Command::SetSampleRate(rate));
+				}
+			}
+			frame = Some(new_frame);
+		}
+
+		canvas.set_draw_color(Color::RGB(0, 0, 0));
+		canvas.clear();
+
+		if let Some(frame) = &frame {
+			// The HUD is in the window title, updated once per emulated frame
+			if remap.is_none() && frame.stats.frames != hud_frame {
+				hud_frame = frame.stats.frames;
+				canvas.window_mut().set_title(&hud_title(frame, audio.as_ref())).unwrap();
+			}
+
+			let pixels = match (&frame.layers, layer) {
+				(Some(layers), Layer::Background) => &layers.background,
+				(Some(layers), Layer::Sprites) => &layers.sprites,
+				_ => &frame.pixels,
+			};
+			let mut image = Image::from_framebuffer(SCREEN_WIDTH, pixels, &frame.emphasis, &frame.palette);
+			if let Some(chain) = filters.get_mut(filter) {
+				image = chain.apply(image);
+			}
+			// Scaling filters change the texture size
+			let query = texture.query();
+			if (query.width as usize, query.height as usize) != (image.width, image.height) {
+				texture = texture_creator
+					.create_texture_streaming(PixelFormatEnum::RGB24, image.width as u32, image.height as u32)
+					.unwrap();
+			}
+			texture.update(None, &image.rgb, image.width * 3).unwrap();
+
+			if show_event_viewer {
+				draw_event_viewer(&mut canvas, &texture, frame, win_width, win_height);
+			} else {
+				canvas.copy(&texture, None, None).unwrap();
+				if show_beam_overlay {
+					draw_beam_overlay(&mut canvas, frame, win_width, win_height);
 				}
 			}
 		}
 
+		if show_tile_grid && !show_event_viewer {
+			draw_tile_grid(&mut canvas, win_width, win_height);
+		}
+
         canvas.present();
-        ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 60));
+		// 60 FPS on average, the time drawing took is not added to the frame. The audio rate control covers the rest.
+		next_present += Duration::new(0, 1_000_000_000u32 / 60);
+		let now = Instant::now();
+		if next_present > now {
+			::std::thread::sleep(next_present - now);
+		} else {
+			next_present = now;
+		}
     }
-}
\ No newline at end of file
+
+	let (width, height) = canvas.window().size();
+	let (x, y) = canvas.window().position();
+	let _ = commands.send(Command::WindowClosed(WindowLayout { width, height, x, y }));
+}
+
+/// The save state slot of a number key.
+fn slot_key(key: Keycode) -> Option<usize> {
+	match key {
+		Keycode::Num0 => Some(0),
+		Keycode::Num1 => Some(1),
+		Keycode::Num2 => Some(2),
+		Keycode::Num3 => Some(3),
+		Keycode::Num4 => Some(4),
+		Keycode::Num5 => Some(5),
+		Keycode::Num6 => Some(6),
+		Keycode::Num7 => Some(7),
+		Keycode::Num8 => Some(8),
+		Keycode::Num9 => Some(9),
+		_ => None,
+	}
+}
+
+/// The number of a connected gamepad, from its SDL joystick id.
+fn pad_number(pads: &[Option<GameController>], which: u32) -> Option<usize> {
+	pads.iter().position(|pad| pad.as_ref().is_some_and(|pad| pad.instance_id() == which))
+}
+
+/// The host inputs that an SDL event presses (true) or releases (false). `axes` is the side each gamepad axis is
+/// pushed to (-1, 0 or 1), so moving a stick from one side to the other releases the first side.
+fn binding_events(event: &Event, pads: &[Option<GameController>], axes: &mut HashMap<(usize, String), i8>) -> Vec<(Binding, bool)> {
+	match *event {
+		Event::KeyDown { keycode: Some(key), repeat: false, .. } => vec![(Binding::Key(key.name()), true)],
+		Event::KeyUp { keycode: Some(key), repeat: false, .. } => vec![(Binding::Key(key.name()), false)],
+		Event::ControllerButtonDown { which, button, .. } | Event::ControllerButtonUp { which, button, .. } => {
+			let pressed = matches!(event, Event::ControllerButtonDown { .. });
+			pad_number(pads, which).map(|pad| vec![(Binding::PadButton { pad, button: button.string() }, pressed)]).unwrap_or_default()
+		}
+		Event::ControllerAxisMotion { which, axis, value, .. } => {
+			let Some(pad) = pad_number(pads, which) else {
+				return vec![];
+			};
+			let side = if value > AXIS_THRESHOLD { 1 } else if value < -AXIS_THRESHOLD { -1 } else { 0 };
+			let previous = axes.insert((pad, axis.string()), side).unwrap_or(0);
+			let binding = |side: i8| Binding::PadAxis { pad, axis: axis.string(), positive: side > 0 };
+			let mut events = vec![];
+			if side != previous {
+				if previous != 0 {
+					events.push((binding(previous), false));
+				}
+				if side != 0 {
+					events.push((binding(side), true));
+				}
+			}
+			events
+		}
+		_ => vec![],
+	}
+}
+
+fn remap_prompt(player: usize, index: usize) -> String {
+	format!("{} | Player {}: press a key or gamepad button for {} (Escape cancels)", WINDOW_TITLE, player + 1, Button::ALL[index].name().to_uppercase())
+}
+
+/// Save the bindings to the settings file, keeping the other settings.
+fn save_bindings(bindings: &Bindings) {
+	let mut config = Config::load(CONFIG_PATH);
+	bindings.write_config(&mut config);
+	match config.save(CONFIG_PATH) {
+		Ok(()) => info!("Saved the input bindings to {}", CONFIG_PATH),
+		Err(e) => error!("Failed to save the settings to {}: {}", CONFIG_PATH, e),
+	}
+}
+
+/// Save the video settings to the settings file, keeping the other settings.
+fn save_video_settings(settings: &PaletteSettings) {
+	let mut config = Config::load(CONFIG_PATH);
+	settings.write_config(&mut config);
+	if let Err(e) = config.save(CONFIG_PATH) {
+		error!("Failed to save the settings to {}: {}", CONFIG_PATH, e);
+	}
+}
+
+/// Save the volume and the mute to the settings file, keeping the other settings.
+fn save_audio_settings(settings: &AudioSettings) {
+	let mut config = Config::load(CONFIG_PATH);
+	settings.write_config(&mut config);
+	if let Err(e) = config.save(CONFIG_PATH) {
+		error!("Failed to save the settings to {}: {}", CONFIG_PATH, e);
+	}
+}
+
+fn hud_title(frame_info: &Frame, audio: Option<&AudioOutput>) -> String {
+	let stats = &frame_info.stats;
+	let frame = &stats.last_frame;
+	let fps = if frame.wall_time.is_zero() { 0.0 } else { 1.0 / frame.wall_time.as_secs_f64() };
+	let mut title = format!("{} | Frame {} | {:.0} FPS ({:.0}%)", WINDOW_TITLE, stats.frames, fps, frame.speed() * 100.0);
+	if stats.subsystem_times {
+		title += &format!(" | CPU: {:.1}ms, PPU: {:.1}ms, APU: {:.1}ms",
+			frame.cpu_time.as_secs_f64() * 1000.0, frame.ppu_time.as_secs_f64() * 1000.0, frame.apu_time.as_secs_f64() * 1000.0);
+	}
+	if let Some(latency) = frame_info.input_latency {
+		title += &format!(" | Input latency: {:.1}ms", latency.as_secs_f64() * 1000.0);
+	}
+	if let Some(audio) = audio {
+		let (stats, settings) = (audio.stats(), audio.settings());
+		let volume = if settings.muted { "muted".to_string() } else { format!("{:.0}%", settings.volume * 100.0) };
+		title += &format!(" | Audio {}: {:.0}ms buffered ({}ms), {} Hz, {} underruns", volume,
+			stats.latency().as_secs_f64() * 1000.0, stats.target as u64 * 1000 / SAMPLE_RATE, stats.rate, stats.underruns);
+	}
+	if let Some(halted) = frame_info.halted {
+		title += &format!(" | {}, F12 resets", halted);
+	}
+	title
+}
+
+/// Rectangle of NES pixel (x, y) in window coordinates.
+fn pixel_rect(x: u16, y: u16, win_width: u32, win_height: u32) -> Rect {
+	let pixel_width = (win_width / SCREEN_WIDTH as u32).max(1);
+	let pixel_height = (win_height / SCREEN_HEIGHT as u32).max(1);
+	let window_x = x as u32 * win_width / SCREEN_WIDTH as u32;
+	let window_y = y as u32 * win_height / SCREEN_HEIGHT as u32;
+	Rect::new(window_x as i32, window_y as i32, pixel_width, pixel_height)
+}
+
+fn draw_beam_overlay(canvas: &mut Canvas<Window>, frame: &Frame, win_width: u32, win_height: u32) {
+	// Visible pixels are drawn at dots 1-256. Outside of the picture (hblank, vblank) the beam is clamped to the edge, and drawn in gray.
+	let visible = frame.scanline < SCREEN_HEIGHT as u16 && (1..=SCREEN_WIDTH as u16).contains(&frame.dot);
+	let x = frame.dot.clamp(1, SCREEN_WIDTH as u16) - 1;
+	let y = frame.scanline.min(SCREEN_HEIGHT as u16 - 1);
+	let beam = pixel_rect(x, y, win_width, win_height);
+
+	canvas.set_draw_color(if visible { Color::RGB(255, 255, 255) } else { Color::RGB(128, 128, 128) });
+	canvas.draw_line(Point::new(0, beam.center().y()), Point::new(win_width as i32, beam.center().y())).unwrap();
+	canvas.fill_rect(Rect::from_center(beam.center(), beam.width() * 3, beam.height() * 3)).unwrap();
+
+	for event in &frame.events {
+		if event.kind != AccessKind::Write || !matches!(event.register(), 0x2001 | 0x2005 | 0x2006) {
+			continue;
+		}
+		if event.scanline >= SCREEN_HEIGHT as u16 {
+			// Writes during vblank don't change the picture mid-frame
+			continue;
+		}
+		let x = event.dot.clamp(1, SCREEN_WIDTH as u16) - 1;
+		let rect = pixel_rect(x, event.scanline, win_width, win_height);
+		canvas.set_draw_color(event_color(event));
+		canvas.fill_rect(Rect::from_center(rect.center(), rect.width() * 2, rect.height() * 2)).unwrap();
+	}
+}
+
+/// Event viewer colors, like Mesen. Reads are darker than writes.
+fn event_color(event: &BusEvent) -> Color {
+	let (r, g, b) = match event.register() {
+		0x2000 => (255, 128, 0),	// PPUCTRL
+		0x2001 => (255, 255, 0),	// PPUMASK
+		0x2002 => (0, 255, 0),		// PPUSTATUS
+		0x2003 | 0x2004 => (255, 0, 255),	// OAMADDR, OAMDATA
+		0x2005 => (255, 0, 0),		// PPUSCROLL
+		0x2006 => (0, 255, 255),	// PPUADDR
+		0x2007 => (64, 64, 255),	// PPUDATA
+		0x4014 => (255, 255, 255),	// OAMDMA
+		_ => (255, 128, 192),		// Controller ($4016)
+	};
+	match event.kind {
+		AccessKind::Write => Color::RGB(r, g, b),
+		AccessKind::Read => Color::RGB(r / 2, g / 2, b / 2),
+	}
+}
+
+fn draw_event_viewer(canvas: &mut Canvas<Window>, texture: &sdl2::render::Texture, frame: &Frame, win_width: u32, win_height: u32) {
+	let dots = DOTS_PER_SCANLINE as u32;
+	let scanlines = SCANLINES_PER_FRAME as u32;
+	let dot_width = (win_width / dots).max(1);
+	let scanline_height = (win_height / scanlines).max(1);
+	let rect = |dot: u16, scanline: u16| Rect::new(
+		(dot as u32 * win_width / dots) as i32,
+		(scanline as u32 * win_height / scanlines) as i32,
+		dot_width,
+		scanline_height,
+	);
+
+	// Hblank and vblank
+	canvas.set_draw_color(Color::RGB(40, 40, 40));
+	canvas.fill_rect(None).unwrap();
+
+	// The picture is drawn at dots 1-256 of scanlines 0-239
+	let top_left = rect(1, 0);
+	let bottom_right = rect(SCREEN_WIDTH as u16 + 1, SCREEN_HEIGHT as u16);
+	let picture = Rect::new(top_left.x(), top_left.y(), (bottom_right.x() - top_left.x()) as u32, (bottom_right.y() - top_left.y()) as u32);
+	canvas.copy(texture, None, picture).unwrap();
+
+	// Beam
+	canvas.set_draw_color(Color::RGB(255, 255, 255));
+	let beam = rect(frame.dot, frame.scanline);
+	canvas.draw_line(Point::new(0, beam.center().y()), Point::new(win_width as i32, beam.center().y())).unwrap();
+
+	for event in &frame.events {
+		let event_rect = rect(event.dot, event.scanline);
+		canvas.set_draw_color(event_color(event));
+		canvas.fill_rect(Rect::from_center(event_rect.center(), event_rect.width() * 2, event_rect.height() * 2)).unwrap();
+	}
+}
+
+fn draw_tile_grid(canvas: &mut Canvas<Window>, win_width: u32, win_height: u32) {
+	let tile_width: u32 = win_width / HORIZONTAL_TILES;
+	let tile_height: u32 = win_height / VERTICAL_TILES;
+
+	// Loop over tiles
+	for y in 0..VERTICAL_TILES {
+		for x in 0..HORIZONTAL_TILES {
+			let tile_x = x * tile_width;
+			let tile_y = y * tile_height;
+			let rect = Rect::new(tile_x as i32, tile_y as i32, tile_width, tile_height);
+
+			// Loop over pixels per tile
+			canvas.set_draw_color(Color::RGB(0, 200, 0));
+			for pyi in 0..8 {
+				for pxi in 0..8 {
+					// Draw horizontal lines
+					let px: i32 = (tile_x + (tile_width / 8) * pxi) as i32;
+					let p1 = Point::new(px, tile_y as i32);
+					let p2 = Point::new(px, (tile_y + tile_height) as i32);
+					canvas.draw_line(p1, p2).unwrap();
+
+					// Draw vertical lines
+					let py: i32 = (tile_y + (tile_height / 8) * pyi) as i32;
+					let p1 = Point::new(tile_x as i32, py);
+					let p2 = Point::new((tile_x + tile_width) as i32, py);
+					canvas.draw_line(p1, p2).unwrap();
+				}
+			}
+
+			canvas.set_draw_color(Color::RGB(230, 230, 230));
+			canvas.draw_rect(rect).unwrap();
+		}
+	}
+}
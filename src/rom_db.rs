@@ -0,0 +1,111 @@
+use log::info;
+
+use crate::{config::Config, cpu::cpu::Scheduler, ppu::ppu::Renderer};
+
+/// How accurate the emulation of a game must be: the faster modes are enough for most games.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Accuracy {
+	pub scheduler: Scheduler,
+	pub renderer: Renderer,
+}
+
+impl Accuracy {
+	/// For games that are not in the database: the PPU is drawn dot by dot, so mid-scanline effects work.
+	pub const DEFAULT: Accuracy = Accuracy { scheduler: Scheduler::Fast, renderer: Renderer::Dot };
+	/// Games without mid-scanline effects.
+	pub const FAST: Accuracy = Accuracy { scheduler: Scheduler::Fast, renderer: Renderer::Scanline };
+	/// Games that time their PPU accesses to the cycle.
+	pub const ACCURATE: Accuracy = Accuracy { scheduler: Scheduler::Accurate, renderer: Renderer::Dot };
+}
+
+/// A known game.
+pub struct Game {
+	pub crc: u32,	// CRC32 of the PRG ROM and the CHR ROM, without the iNES header
+	pub name: &'static str,
+	pub accuracy: Accuracy,
+}
+
+/// The games known to need (or not need) the accurate modes.
+const GAMES: &[Game] = &[
+	Game { crc: 0x158B0388, name: "nestest", accuracy: Accuracy::FAST },
+	Game { crc: 0x3337EC46, name: "Super Mario Bros.", accuracy: Accuracy::FAST },
+	Game { crc: 0x279710DC, name: "Battletoads", accuracy: Accuracy::ACCURATE },
+];
+
+/// The CRC32 that identifies a ROM: of the PRG ROM and then the CHR ROM (the header is not included, headers of the
+/// same game differ).
+pub fn crc32(prg_rom: &[u8], chr_rom: &[u8]) -> u32 {
+	let mut hasher = crc32fast::Hasher::new();
+	hasher.update(prg_rom);
+	hasher.update(chr_rom);
+	hasher.finalize()
+}
+
+pub fn lookup(crc: u32) -> Option<&'static Game> {
+	GAMES.iter().find(|game| game.crc == crc)
+}
+
+/// The accuracy settings for a game. The settings file overrides the database for a game, by its CRC32:
+/// ```text
+/// game.3337EC46.scheduler = accurate
+/// game.3337EC46.renderer = dot
+/// ```
+pub fn accuracy(crc: u32, config: &Config) -> Accuracy {
+	let mut accuracy = match lookup(crc) {
+		Some(game) => {
+			info!("Known game: {}, {} scheduler, {} renderer", game.name, game.accuracy.scheduler.name(), game.accuracy.renderer.name());
+			game.accuracy
+		}
+		None => Accuracy::DEFAULT,
+	};
+	if let Some(scheduler) = Scheduler::parse(&game_setting(crc, config, "scheduler")) {
+		accuracy.scheduler = scheduler;
+	}
+	if let Some(renderer) = Renderer::parse(&game_setting(crc, config, "renderer")) {
+		accuracy.renderer = renderer;
+	}
+	accuracy
+}
+
+/// The extra vblank scanlines of a game (see CPU::set_overclock), 0 unless the settings file sets them:
+/// ```text
+/// game.3337EC46.overclock = 60
+/// ```
+pub fn overclock(crc: u32, config: &Config) -> u16 {
+	game_setting(crc, config, "overclock").parse().unwrap_or(0)
+}
+
+fn game_setting(crc: u32, config: &Config, name: &str) -> String {
+	config.get(&format!("game.{:08X}.{}", crc, name), String::new())
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{config::Config, cpu::cpu::Scheduler, ppu::ppu::Renderer, rom_parser::RomParser};
+	use super::{accuracy, crc32, lookup, overclock, Accuracy};
+
+	#[test]
+	fn test_lookup() {
+		assert_eq!(crc32(b"1234", b"56789"), 0xCBF43926);
+
+		let mut rom_parser = RomParser::new();
+		rom_parser.parse("6502asm_programs/nestest/nestest.nes").unwrap();
+		let crc = crc32(&rom_parser.prg_rom.concat(), &rom_parser.chr_rom.concat());
+		assert_eq!(lookup(crc).map(|game| game.name), Some("nestest"));
+		assert_eq!(accuracy(crc, &Config::parse("")), Accuracy::FAST);
+
+		// The settings file overrides the database, and sets unknown games
+		let config = Config::parse(&format!("game.{:08X}.renderer = dot\ngame.00000001.scheduler = accurate", crc));
+		assert_eq!(accuracy(crc, &config), Accuracy { scheduler: Scheduler::Fast, renderer: Renderer::Dot });
+		assert_eq!(accuracy(1, &config), Accuracy { scheduler: Scheduler::Accurate, renderer: Renderer::Dot });
+		assert_eq!(accuracy(2, &config), Accuracy::DEFAULT);
+	}
+
+	#[test]
+	fn test_overclock() {
+		let config = Config::parse("game.3337EC46.overclock = 60\ngame.279710DC.overclock = lots");
+		assert_eq!(overclock(0x3337EC46, &config), 60);
+		assert_eq!(overclock(0x279710DC, &config), 0);
+		assert_eq!(overclock(0x158B0388, &config), 0);
+	}
+}
@@ -0,0 +1,79 @@
+//! `--info`: what the emulator sees in a ROM file, without running it. For finding out why a ROM doesn't load.
+
+use std::fs;
+
+use crate::{mapper, mapper::fds, rom_db, rom_parser::{RomParser, SizeMismatch}};
+
+/// The header fields, sizes, hashes and mapper support of an iNES ROM or an FDS disk image. Err when the file can't be
+/// read or parsed, with the reason.
+pub fn rom_info(path: &str, size_mismatch: SizeMismatch) -> Result<String, String> {
+	let contents = fs::read(path).map_err(|e| format!("Can't read {}: {}", path, e))?;
+	let mut lines = vec![
+		format!("File: {} ({} bytes)", path, contents.len()),
+		format!("File CRC32: {:08X}", crc32fast::hash(&contents)),
+	];
+	if path.to_lowercase().ends_with(".fds") {
+		let sides = fds::parse_disk(&contents).len();
+		lines.push(format!("Famicom Disk System disk image: {} sides", sides));
+		lines.push(format!("Supported: {}", if sides > 0 { "yes (needs the BIOS disksys.rom)" } else { "no, no disk side found" }));
+		return Ok(lines.join("\n"));
+	}
+
+	let mut rom_parser = RomParser::new();
+	rom_parser.size_mismatch = size_mismatch;
+	rom_parser.parse_contents(&contents).map_err(|e| format!("{} is not a valid iNES ROM: {}", path, e))?;
+
+	let header = &rom_parser.header;
+	let prg_rom = rom_parser.prg_rom.concat();
+	let chr_rom = rom_parser.chr_rom.concat();
+	let supported = mapper::SUPPORTED_MAPPERS.contains(&header.mapper);
+	lines.push(format!("Format: {}", if header.nes2_format { "NES 2.0" } else { "iNES" }));
+	lines.push(format!("Mapper: {} ({})", header.mapper, mapper::mapper_name(header.mapper).unwrap_or("unknown")));
+	lines.push(format!("Supported: {}", if supported { "yes" } else { "no, the emulator doesn't support this mapper" }));
+	lines.push(format!("PRG ROM: {}KB", prg_rom.len() / 1024));
+	lines.push(if chr_rom.is_empty() { "CHR: 8KB RAM".to_string() } else { format!("CHR ROM: {}KB", chr_rom.len() / 1024) });
+	let prg_ram = match header.prg_ram_size {
+		Some(size) => format!("{}KB", size / 1024),
+		None => "the mapper default".to_string(),
+	};
+	lines.push(format!("PRG RAM: {}{}", prg_ram, if header.battery_prg_ram { ", battery backed" } else { "" }));
+	lines.push(format!("Mirroring: {}", if header.four_screen() { "four-screen".to_string() } else { format!("{:?}", header.mirroring).to_lowercase() }));
+	lines.push(format!("TV system: {:?}", header.tv_system()));
+	if header.trainer {
+		lines.push("Trainer: 512 bytes (ignored)".to_string());
+	}
+	if header.vs_unit_system {
+		lines.push("VS System".to_string());
+	}
+	if header.play_choise_10 {
+		lines.push("PlayChoice-10".to_string());
+	}
+	for adjustment in &rom_parser.adjustments {
+		lines.push(format!("Size mismatch: {}", adjustment));
+	}
+	let crc = rom_db::crc32(&prg_rom, &chr_rom);
+	lines.push(format!("PRG+CHR CRC32: {:08X}", crc));
+	if let Some(game) = rom_db::lookup(crc) {
+		lines.push(format!("Known game: {}, {} scheduler, {} renderer", game.name, game.accuracy.scheduler.name(), game.accuracy.renderer.name()));
+	}
+	Ok(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::rom_info;
+	use crate::rom_parser::SizeMismatch;
+
+	#[test]
+	fn test_rom_info() {
+		let info = rom_info("6502asm_programs/nestest/nestest.nes", SizeMismatch::Fix).unwrap();
+		for line in ["Mapper: 0 (NROM)", "Supported: yes", "PRG ROM: 16KB", "CHR ROM: 8KB", "Mirroring: horizontal"] {
+			assert!(info.lines().any(|info_line| info_line == line), "{} not in\n{}", line, info);
+		}
+
+		// Not a ROM
+		let error = rom_info("Cargo.toml", SizeMismatch::Fix).unwrap_err();
+		assert!(error.contains("Incorrect magic bytes"), "{}", error);
+		assert!(rom_info("missing.nes", SizeMismatch::Fix).unwrap_err().starts_with("Can't read missing.nes"));
+	}
+}
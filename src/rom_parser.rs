@@ -1,10 +1,10 @@
-use log::{debug, info};
-use core::panic;
+use log::{debug, info, warn};
 use std::fs;
 
-use crate::common::{PRG_Bank, CHR_Bank};
+use crate::{common::{PRG_Bank, CHR_Bank}, error::RomError, vs_system::VsPpu};
 
 /// Read here about iNES file format: https://www.nesdev.org/wiki/INES#iNES_file_format
+/// NES 2.0 headers are read for the fields we use, read here: https://www.nesdev.org/wiki/NES_2.0
 #[derive(Default, Debug)]
 pub struct Header {
     pub prg_rom_size: u8, // Program ROM size (in 16KB chunks, i.e., 2 means 32KB), also known as amount of banks
@@ -18,12 +18,12 @@ pub struct Header {
     ignore_mirroring_control: bool,
 
     // Flags 7
-    vs_unit_system: bool,
-    play_choise_10: bool,
-    nes2_format: bool,
+    pub vs_unit_system: bool,
+    pub play_choise_10: bool,
+    pub nes2_format: bool,
 
-    // Flags 8
-    prg_ram_size: u8,
+    // Flags 8 (NES 2.0: byte 10). PRG RAM size in bytes, None when the header doesn't say.
+    pub prg_ram_size: Option<usize>,
 
     // Flags 9
     flags9_tv_system: TVSystem,
@@ -32,9 +32,24 @@ pub struct Header {
     flags10_tv_system: TVSystem,
     prg_ram_not_present: bool,
     bus_conflicts: bool,
+
+    // NES 2.0 byte 13
+    pub vs_ppu: Option<VsPpu>,
 }
 
-#[derive(Default, Debug)]
+impl Header {
+    /// Four-screen VRAM on the cartridge instead of the mirroring (flags 6 bit 3).
+    pub fn four_screen(&self) -> bool {
+        self.ignore_mirroring_control
+    }
+
+    /// The TV system of flags 9 (iNES), flags 10 is unofficial.
+    pub fn tv_system(&self) -> &TVSystem {
+        &self.flags9_tv_system
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
 pub enum TVSystem {
     #[default]
     NTSC,
@@ -49,11 +64,42 @@ pub enum MirrorType {
     VERTICAL,
 }
 
+/// What to do when the file size doesn't match the PRG and CHR sizes of the header: some dumps have junk after the ROM,
+/// or miss the CHR ROM.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum SizeMismatch {
+    /// Truncate the extra bytes, pad the missing bytes with zeros, and warn
+    #[default]
+    Fix,
+    /// Refuse to load the ROM
+    Strict,
+}
+
+impl SizeMismatch {
+    /// `fix` or `strict`, the `rom.size_mismatch` setting.
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "fix" => Some(SizeMismatch::Fix),
+            "strict" => Some(SizeMismatch::Strict),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct RomParser {
     pub header: Header,
     pub prg_rom: Vec<PRG_Bank>,
     pub chr_rom: Vec<CHR_Bank>,
+    pub size_mismatch: SizeMismatch,
+    /// How the file was fixed to match the header (`SizeMismatch::Fix`), for the ROM info.
+    pub adjustments: Vec<String>,
+}
+
+impl Default for RomParser {
+    fn default() -> Self {
+        RomParser::new()
+    }
 }
 
 impl RomParser {
@@ -62,20 +108,62 @@ impl RomParser {
             header: Header::default(),
             prg_rom: vec![],
             chr_rom: vec![],
+            size_mismatch: SizeMismatch::default(),
+            adjustments: vec![],
         }
     }
 
-    pub fn parse(&mut self, path: &str) {
+    pub fn parse(&mut self, path: &str) -> Result<(), RomError> {
         info!("Parsing ROM: {}", path);
-        let contents = fs::read(path).expect("Could not read NES ROM");
+        let contents = fs::read(path).map_err(|e| RomError::Read { path: path.to_string(), reason: e.to_string() })?;
+        self.parse_contents(&contents)
+    }
 
-        self.parse_header(&contents);
-        self.parse_prg_rom(&contents);
-        self.parse_chr_rom(&contents);
+    /// Parse the contents of an iNES file.
+    pub fn parse_contents(&mut self, contents: &[u8]) -> Result<(), RomError> {
+        self.parse_header(contents)?;
+        let data = self.rom_data(contents)?;
+        self.parse_prg_rom(&data);
+        self.parse_chr_rom(&data);
+        Ok(())
     }
 
-    fn parse_header(&mut self, contents: &Vec<u8>) {
-        assert_eq!(&contents[0..4], b"NES\x1A", "Incorrect magic bytes");
+    /// The PRG ROM and CHR ROM after the header and the trainer, of the sizes in the header. Extra bytes are truncated and
+    /// missing bytes are padded with zeros, unless the size mismatch is strict.
+    fn rom_data(&mut self, contents: &[u8]) -> Result<Vec<u8>, RomError> {
+        let start = if self.header.trainer { 16 + 512 } else { 16 }.min(contents.len());
+        let mut data = contents[start..].to_vec();
+        let prg_rom_bytes = 1024 * 16 * self.header.prg_rom_size as usize;
+        let expected = prg_rom_bytes + 1024 * 8 * self.header.chr_rom_size as usize;
+        if self.header.play_choise_10 && data.len() > expected {
+            // The PlayChoice-10 INST-ROM (hint screen) and PROM are after the CHR ROM
+            warn!("Ignoring {} bytes of PlayChoice-10 data after the CHR ROM", data.len() - expected);
+            data.truncate(expected);
+        }
+        if data.len() > expected {
+            self.adjust(format!("{} bytes after the ROM were ignored", data.len() - expected))?;
+        } else if data.len() < prg_rom_bytes {
+            self.adjust(format!("The PRG ROM is {} bytes short, padded with zeros", prg_rom_bytes - data.len()))?;
+        } else if data.len() < expected {
+            self.adjust(format!("The CHR ROM is {} bytes short, padded with zeros", expected - data.len()))?;
+        }
+        data.resize(expected, 0);
+        Ok(data)
+    }
+
+    fn adjust(&mut self, adjustment: String) -> Result<(), RomError> {
+        if self.size_mismatch == SizeMismatch::Strict {
+            return Err(RomError::SizeMismatch(adjustment));
+        }
+        warn!("{}", adjustment);
+        self.adjustments.push(adjustment);
+        Ok(())
+    }
+
+    fn parse_header(&mut self, contents: &[u8]) -> Result<(), RomError> {
+        if contents.len() < 16 || &contents[0..4] != b"NES\x1A" {
+            return Err(RomError::InvalidHeader("Incorrect magic bytes".to_string()));
+        }
 
         let flags6 = contents[6];
         let flags7 = contents[7];
@@ -113,18 +201,14 @@ impl RomParser {
 
         // NES 2.0 format
         let nes2_format = (flags7 >> 2) & 0b0000_0011 == 2;
-        assert_ne!(
-            nes2_format, true,
-            "The emulator does not support NES 2.0 format"
-        );
 
         // Mapper number (Upper 4 bits of mapper)
         let msb_mapper = flags7 & 0b1111_0000;
 
         // ==================== FLAGS 8 ====================
         // PRG RAM size
-        // Size of PRG RAM in 8 KB units (Value 0 infers 8 KB for compatibility)
-        let prg_ram_size = flags8;
+        // Size of PRG RAM in 8 KB units (Value 0 infers 8 KB for compatibility, the mapper decides)
+        let mut prg_ram_size = if flags8 == 0 { None } else { Some(flags8 as usize * 8 * 1024) };
 
         // ==================== FLAGS 9 ====================
 
@@ -134,7 +218,9 @@ impl RomParser {
         } else {
             TVSystem::NTSC
         };
-        assert_eq!(flags9 >> 1, 0, "Flags 9 reserve bits are not set to zero");
+        if !nes2_format && flags9 >> 1 != 0 {
+            return Err(RomError::InvalidHeader("Flags 9 reserve bits are not set to zero".to_string()));
+        }
 
         // ==================== FLAGS 10 ====================
 
@@ -146,18 +232,17 @@ impl RomParser {
         };
 
         // PRG RAM (0: present, 1: not present)
-        let prg_ram_not_present = (flags10 >> 4) == 1;
+        let prg_ram_not_present = (flags10 >> 4) & 1 == 1;
+        if prg_ram_not_present && !nes2_format {
+            prg_ram_size = Some(0);
+        }
 
         // Board bus conflicts (0: Board has no bus conflicts; 1: Board has bus conflicts)
-        let bus_conflicts = (flags10 >> 5) == 1;
+        let bus_conflicts = (flags10 >> 5) & 1 == 1;
 
         // ==================== END ====================
         let mapper = msb_mapper | lsb_mapper;
 
-		if mapper != 0 {
-			panic!("The emulator only supports mapper 0. ROM mapper is {}", mapper);
-		}
-
         self.header = Header {
             prg_rom_size: contents[4],
             chr_rom_size: contents[5],
@@ -174,20 +259,56 @@ impl RomParser {
             flags10_tv_system,
             prg_ram_not_present,
             bus_conflicts,
+            vs_ppu: None,
         };
 
-        let padding_bytes = &contents[11..16];
-        if padding_bytes != [0, 0, 0, 0, 0] {
-            panic!("Padding bytes are not zero: {:?}", padding_bytes);
+        if nes2_format {
+            self.parse_nes2_header(contents)?;
+        } else {
+            let padding_bytes = &contents[11..16];
+            if padding_bytes != [0, 0, 0, 0, 0] {
+                return Err(RomError::InvalidHeader(format!("Padding bytes are not zero: {:?}", padding_bytes)));
+            }
         }
         debug!("iNES header: {:#?}", self.header);
+        Ok(())
     }
 
-    fn parse_prg_rom(&mut self, contents: &Vec<u8>) {
+    /// NES 2.0 replaces flags 8-10 and the padding. Only the fields we use are read.
+    fn parse_nes2_header(&mut self, contents: &[u8]) -> Result<(), RomError> {
+        // Byte 8: mapper bits 8-11 (low nibble) and submapper (high nibble)
+        if contents[8] & 0x0F != 0 {
+            return Err(RomError::InvalidHeader("The emulator doesn't support mappers above 255".to_string()));
+        }
+
+        // Byte 9: PRG ROM and CHR ROM size, upper bits
+        if contents[9] != 0 {
+            return Err(RomError::InvalidSize("The emulator doesn't support PRG ROM over 4MB or CHR ROM over 2MB".to_string()));
+        }
+
+        // Byte 10: PRG RAM (low nibble) and PRG NVRAM (high nibble) sizes, 64 << shift bytes (0: none)
+        let size = |shift: u8| if shift == 0 { 0 } else { 64 << shift };
+        self.header.prg_ram_size = Some(size(contents[10] & 0x0F) + size(contents[10] >> 4));
+        self.header.prg_ram_not_present = self.header.prg_ram_size == Some(0);
+        self.header.flags9_tv_system = TVSystem::default();
+        self.header.flags10_tv_system = TVSystem::default();
+        self.header.bus_conflicts = false;
+
+        // Byte 13: VS System PPU type. The RC2C03 and RC2C05 PPUs have the NES palette.
+        if self.header.vs_unit_system {
+            self.header.vs_ppu = match contents[13] & 0x0F {
+                2..=5 => Some(VsPpu::Rp2c04((contents[13] & 0x0F) as usize - 1)),
+                _ => Some(VsPpu::Rp2c03),
+            };
+        }
+        Ok(())
+    }
+
+    fn parse_prg_rom(&mut self, data: &[u8]) {
         let prg_rom_size_bytes: usize = 1024 * 16 * self.header.prg_rom_size as usize;
 
 		// The entire PRG memory in one vector
-        let prg_rom = &contents[16..16 + prg_rom_size_bytes];
+        let prg_rom = &data[..prg_rom_size_bytes];
 
         debug!("PRG ROM size: {}KB", prg_rom.len()/1024);
         //assert_eq!(prg_rom.len(), 1024 * 32, "The emulator, currently, supports PRG ROM of size 32KB.");
@@ -204,19 +325,16 @@ impl RomParser {
 		}
     }
 
-    fn parse_chr_rom(&mut self, contents: &Vec<u8>) {
-        let mut chr_rom_bytes = 1024 * 8 * self.header.chr_rom_size as usize;
+    fn parse_chr_rom(&mut self, data: &[u8]) {
+        let chr_rom_bytes = 1024 * 8 * self.header.chr_rom_size as usize;
         debug!("CHR ROM size: {}KB", chr_rom_bytes/1024);
         if chr_rom_bytes == 0 {
-            //TODO: What to do here? It's empty bytes.
             // "If Y=0, you prepare an empty 8192 bytes of memory, and allow writing into CHR."
             // Quote from my question on reddit: https://www.reddit.com/r/EmuDev/comments/yvaz54/comment/iwdq3s9/?utm_source=share&utm_medium=web2x&context=3
-            info!("CHR ROM size is 0, preparing empty 8192 bytes of memory");
-            chr_rom_bytes = 1024 * 8;
+            info!("CHR ROM size is 0, the cartridge has 8KB of CHR RAM");
         }
         let prg_rom_size_bytes: usize = 1024 * 16 * self.header.prg_rom_size as usize;
-        let chr_rom = &contents[16 + prg_rom_size_bytes..]; // Get the rest of the bytes. The size of the entire file must be exact match to expected size.
-        assert_eq!(chr_rom.len(), chr_rom_bytes);
+        let chr_rom = &data[prg_rom_size_bytes..];
 
 		// Split the CHR memory into banks
 		self.chr_rom = Vec::with_capacity(self.header.chr_rom_size as usize);
@@ -225,3 +343,61 @@ impl RomParser {
 		}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{RomParser, SizeMismatch};
+    use crate::error::RomError;
+
+    /// iNES file of 16KB PRG (each byte 1) and `chr_banks` 8KB CHR (each byte 2), with `extra` bytes more or less.
+    fn ines(chr_banks: u8, extra: isize) -> Vec<u8> {
+        let mut contents = b"NES\x1A\x01".to_vec();
+        contents.extend([chr_banks, 0, 0]);
+        contents.resize(16, 0);
+        contents.extend([1; 1024 * 16]);
+        contents.extend(vec![2; 1024 * 8 * chr_banks as usize]);
+        contents.resize((contents.len() as isize + extra) as usize, 0xEE);
+        contents
+    }
+
+    fn parse(contents: &[u8], size_mismatch: SizeMismatch) -> RomParser {
+        let mut rom_parser = RomParser::new();
+        rom_parser.size_mismatch = size_mismatch;
+        rom_parser.parse_contents(contents).unwrap();
+        rom_parser
+    }
+
+    #[test]
+    fn test_size_mismatch() {
+        let rom_parser = parse(&ines(1, 0), SizeMismatch::Strict);
+        assert!(rom_parser.adjustments.is_empty());
+
+        // Junk after the CHR ROM
+        let rom_parser = parse(&ines(1, 100), SizeMismatch::Fix);
+        assert_eq!(rom_parser.chr_rom.concat(), [2; 1024 * 8]);
+        assert_eq!(rom_parser.adjustments, ["100 bytes after the ROM were ignored"]);
+
+        // Half of the CHR ROM, no CHR ROM, a short PRG ROM
+        let rom_parser = parse(&ines(1, -4096), SizeMismatch::Fix);
+        assert_eq!(rom_parser.chr_rom.concat()[4095..4097], [2, 0]);
+        assert_eq!(rom_parser.adjustments, ["The CHR ROM is 4096 bytes short, padded with zeros"]);
+        let rom_parser = parse(&ines(1, -8192), SizeMismatch::Fix);
+        assert_eq!(rom_parser.chr_rom.concat(), [0; 1024 * 8]);
+        let rom_parser = parse(&ines(0, -16), SizeMismatch::Fix);
+        assert_eq!(rom_parser.prg_rom.concat()[1024 * 16 - 17..], [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(rom_parser.adjustments, ["The PRG ROM is 16 bytes short, padded with zeros"]);
+
+        // No CHR ROM is CHR RAM
+        assert!(parse(&ines(0, 0), SizeMismatch::Strict).chr_rom.is_empty());
+    }
+
+    #[test]
+    fn test_strict_size_mismatch() {
+        let mut rom_parser = RomParser::new();
+        rom_parser.size_mismatch = SizeMismatch::Strict;
+        let err = rom_parser.parse_contents(&ines(1, 1)).unwrap_err();
+        assert_eq!(err, RomError::SizeMismatch("1 bytes after the ROM were ignored".to_string()));
+        assert_eq!(err.to_string(), "The file size doesn't match the header: 1 bytes after the ROM were ignored");
+        assert!(matches!(RomParser::new().parse_contents(b"NES"), Err(RomError::InvalidHeader(_))));
+    }
+}
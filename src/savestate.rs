@@ -0,0 +1,486 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::error::StateError;
+use crate::mapper::SharedRom;
+use crate::ppu::ppu::{FRAMEBUFFER_SIZE, SCREEN_HEIGHT};
+use crate::rom_parser::MirrorType;
+
+/// Save state slots, chosen with the number keys.
+pub const SLOTS: usize = 10;
+
+/// Save state buffer. Devices describe their state once, in `Serialize::serialize`, and the same code saves it (appends
+/// the fields to the buffer) or loads it (reads the fields back, in the same order). Only the state is saved, not the
+/// ROM, so a state is loaded into a device created from the same ROM.
+pub struct Serializer {
+	data: Vec<u8>,
+	position: usize,
+	loading: bool,
+	truncated: bool,	// Loading read past the end, the values past the end were left as they were
+}
+
+impl Serializer {
+	pub fn saving() -> Self {
+		Serializer {
+			data: vec![],
+			position: 0,
+			loading: false,
+			truncated: false,
+		}
+	}
+
+	pub fn loading(data: Vec<u8>) -> Self {
+		Serializer {
+			data,
+			position: 0,
+			loading: true,
+			truncated: false,
+		}
+	}
+
+	/// Save or load a value.
+	pub fn value<T: Serialize>(&mut self, value: &mut T) {
+		value.serialize(self);
+	}
+
+	/// Has the whole state been loaded, and nothing more.
+	pub fn is_done(&self) -> bool {
+		!self.truncated && self.position == self.data.len()
+	}
+
+	pub fn into_bytes(self) -> Vec<u8> {
+		self.data
+	}
+
+	fn bytes(&mut self, bytes: &mut [u8]) {
+		if self.loading {
+			let end = self.position + bytes.len();
+			if end > self.data.len() {
+				self.truncated = true;
+				return;
+			}
+			bytes.copy_from_slice(&self.data[self.position..end]);
+			self.position = end;
+		} else {
+			self.data.extend_from_slice(bytes);
+		}
+	}
+}
+
+/// State that goes into save states.
+pub trait Serialize {
+	fn serialize(&mut self, s: &mut Serializer);
+}
+
+macro_rules! serialize_number {
+	($($t:ty),*) => {
+		$(impl Serialize for $t {
+			fn serialize(&mut self, s: &mut Serializer) {
+				let mut bytes = self.to_le_bytes();
+				s.bytes(&mut bytes);
+				*self = <$t>::from_le_bytes(bytes);
+			}
+		})*
+	};
+}
+
+serialize_number!(u8, u16, u32, u64, i8, i16, i32, i64, f32);
+
+impl Serialize for usize {
+	fn serialize(&mut self, s: &mut Serializer) {
+		let mut value = *self as u64;
+		s.value(&mut value);
+		*self = value as usize;
+	}
+}
+
+impl Serialize for bool {
+	fn serialize(&mut self, s: &mut Serializer) {
+		let mut value = *self as u8;
+		s.value(&mut value);
+		*self = value != 0;
+	}
+}
+
+impl<T: Serialize, const N: usize> Serialize for [T; N] {
+	fn serialize(&mut self, s: &mut Serializer) {
+		for value in self.iter_mut() {
+			s.value(value);
+		}
+	}
+}
+
+/// The length is saved too, loading resizes the vector.
+impl<T: Serialize + Default + Clone> Serialize for Vec<T> {
+	fn serialize(&mut self, s: &mut Serializer) {
+		let mut len = self.len();
+		s.value(&mut len);
+		self.resize(len, T::default());
+		for value in self.iter_mut() {
+			s.value(value);
+		}
+	}
+}
+
+/// Like a `Vec<u8>`, but saving doesn't copy the data a fork shares.
+impl Serialize for SharedRom {
+	fn serialize(&mut self, s: &mut Serializer) {
+		if s.loading {
+			s.value(self.make_mut());
+		} else {
+			let mut len = self.len();
+			s.value(&mut len);
+			s.data.extend_from_slice(self);
+		}
+	}
+}
+
+impl<T: Serialize + Default> Serialize for Option<T> {
+	fn serialize(&mut self, s: &mut Serializer) {
+		let mut is_some = self.is_some();
+		s.value(&mut is_some);
+		if !is_some {
+			*self = None;
+			return;
+		}
+		let value = self.get_or_insert_with(T::default);
+		s.value(value);
+	}
+}
+
+impl Serialize for MirrorType {
+	fn serialize(&mut self, s: &mut Serializer) {
+		let mut vertical = matches!(self, MirrorType::VERTICAL);
+		s.value(&mut vertical);
+		*self = if vertical { MirrorType::VERTICAL } else { MirrorType::HORIZONTAL };
+	}
+}
+
+/// Save state files start with this.
+const MAGIC: [u8; 4] = *b"NESS";
+
+/// Version of the file layout (the header and the component list). The states of the first emulator versions had no
+/// header (version 0), they were the CRC32 and then the components one after the other.
+pub const FORMAT_VERSION: u16 = 1;
+
+/// The parts of the machine state. Each is saved in its own block with its own version, so a change to one device only
+/// needs a migration for that device, and the error says which device doesn't match.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Component {
+	Cpu,			// Registers, RAM, IRQ line
+	Cartridge,		// Mapper and VS System
+	Ppu,
+	Apu,
+	Controllers,
+}
+
+impl Component {
+	/// In the order of the version 0 states.
+	pub const ALL: [Component; 5] = [Component::Cpu, Component::Cartridge, Component::Ppu, Component::Apu, Component::Controllers];
+
+	fn tag(self) -> [u8; 4] {
+		match self {
+			Component::Cpu => *b"CPU ",
+			Component::Cartridge => *b"CART",
+			Component::Ppu => *b"PPU ",
+			Component::Apu => *b"APU ",
+			Component::Controllers => *b"CTRL",
+		}
+	}
+
+	/// The version of the component layout. Change the `Serialize` code of a device only together with its version,
+	/// and add a migration from the previous version to `MIGRATIONS`.
+	pub fn version(self) -> u16 {
+		match self {
+			Component::Ppu => 5,
+			Component::Apu => 7,
+			Component::Cpu => 3,
+			Component::Cartridge | Component::Controllers => 1,
+		}
+	}
+}
+
+/// Converts the data of a component from version `from` to version `from + 1`.
+struct Migration {
+	component: Component,
+	from: u16,
+	migrate: fn(Vec<u8>) -> Vec<u8>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+	// The CPU can be halted (KIL), the states before weren't
+	Migration { component: Component::Cpu, from: 1, migrate: |mut data| { data.push(0); data } },
+	// The RAM is 2KB, the 32KB memory block before had it at its start (after the registers and the cycles, 15 bytes)
+	Migration { component: Component::Cpu, from: 2, migrate: |mut data| { data.drain(15 + 0x800..15 + 0x8000); data } },
+	// The color emphasis of each scanline, after the framebuffer
+	Migration { component: Component::Ppu, from: 1, migrate: |mut data| { data.extend([0; SCREEN_HEIGHT]); data } },
+	// The sprite evaluation runs dot by dot: the secondary OAM (cleared) and the evaluation progress (done)
+	Migration { component: Component::Ppu, from: 2, migrate: |mut data| { data.extend([0xFF; 32]); data.extend([0, 0, 0, 0, 1, 0]); data } },
+	// The warm-up after power on and reset (over)
+	Migration { component: Component::Ppu, from: 3, migrate: |mut data| { data.push(0); data } },
+	// The framebuffer is an array, without the length (8 bytes) before it. After it: the emphasis, the secondary OAM, the
+	// sprite evaluation and the warm-up.
+	Migration { component: Component::Ppu, from: 4, migrate: |mut data| {
+		let end = data.len() - (FRAMEBUFFER_SIZE + SCREEN_HEIGHT + 32 + 6 + 1);
+		data.drain(end - 8..end);
+		data
+	} },
+	// The length counters of the pulse, triangle and noise channels (6 bytes each)
+	Migration { component: Component::Apu, from: 1, migrate: |mut data| { data.extend([0; 4 * 6]); data } },
+	// The timer period and sweep of the pulse channels, after their length counter (the frame counter takes 28 bytes)
+	Migration { component: Component::Apu, from: 2, migrate: |mut data| {
+		for offset in [28 + 6, 28 + 6 + 8 + 6] {
+			data.splice(offset..offset, [0; 8]);
+		}
+		data
+	} },
+	// The delayed frame counter restart of a $4017 write
+	Migration { component: Component::Apu, from: 3, migrate: |mut data| { data.extend([0, 0]); data } },
+	// The DMC (21 bytes, stopped) at the end takes the DMC IRQ flag of the frame counter
+	Migration { component: Component::Apu, from: 4, migrate: |mut data| {
+		let irq = data.remove(27);
+		data.extend([0, irq, 0, 0xAC, 0x01, 0, 0, 0, 0x00, 0xC0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 8, 1]);
+		data
+	} },
+	// The resampler at the end: the phase, the linear sums, the sinc steps and levels (96 bytes, 0 when it starts)
+	Migration { component: Component::Apu, from: 5, migrate: |mut data| { data.extend([0; 96]); data } },
+	// The resampler is integer only (180 bytes): its floating point sums restart, only the phase stays
+	Migration { component: Component::Apu, from: 6, migrate: |mut data| { data.truncate(data.len() - 96 + 8); data.extend([0; 172]); data } },
+];
+
+/// Writes a save state file: the header, then for each component its tag, version, length and data.
+pub struct StateWriter {
+	data: Vec<u8>,
+}
+
+impl StateWriter {
+	/// `crc32` identifies the ROM, the state only loads into the same ROM.
+	pub fn new(crc32: u32) -> Self {
+		let mut data = MAGIC.to_vec();
+		data.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+		data.extend_from_slice(&crc32.to_le_bytes());
+		StateWriter { data }
+	}
+
+	pub fn component(&mut self, component: Component, data: &[u8]) {
+		self.data.extend_from_slice(&component.tag());
+		self.data.extend_from_slice(&component.version().to_le_bytes());
+		self.data.extend_from_slice(&(data.len() as u32).to_le_bytes());
+		self.data.extend_from_slice(data);
+	}
+
+	pub fn into_bytes(self) -> Vec<u8> {
+		self.data
+	}
+}
+
+/// A save state file, with the data of each component migrated to the current version.
+pub struct StateReader {
+	pub crc32: u32,
+	pub components: Vec<(Component, Vec<u8>)>,
+}
+
+impl StateReader {
+	/// Returns None for version 0 states, which have no header.
+	pub fn parse(data: &[u8]) -> Option<Result<StateReader, StateError>> {
+		if !data.starts_with(&MAGIC) {
+			return None;
+		}
+		Some(Self::parse_components(&data[MAGIC.len()..]))
+	}
+
+	fn parse_components(mut data: &[u8]) -> Result<StateReader, StateError> {
+		let format_version = u16::from_le_bytes(take(&mut data, 2).ok_or(StateError::Truncated)?.try_into().unwrap());
+		if format_version > FORMAT_VERSION {
+			return Err(StateError::NewerFormat(format_version));
+		}
+		let crc32 = u32::from_le_bytes(take(&mut data, 4).ok_or(StateError::Truncated)?.try_into().unwrap());
+
+		let mut components = vec![];
+		while !data.is_empty() {
+			let tag: [u8; 4] = take(&mut data, 4).ok_or(StateError::Truncated)?.try_into().unwrap();
+			let version = u16::from_le_bytes(take(&mut data, 2).ok_or(StateError::Truncated)?.try_into().unwrap());
+			let len = u32::from_le_bytes(take(&mut data, 4).ok_or(StateError::Truncated)?.try_into().unwrap());
+			let component_data = take(&mut data, len as usize).ok_or(StateError::Truncated)?.to_vec();
+			let Some(component) = Component::ALL.into_iter().find(|component| component.tag() == tag) else {
+				return Err(StateError::UnknownComponent(String::from_utf8_lossy(&tag).to_string()));
+			};
+			components.push((component, migrate(component, version, component_data)?));
+		}
+
+		if let Some(missing) = Component::ALL.into_iter().find(|&component| components.iter().all(|(c, _)| *c != component)) {
+			return Err(StateError::MissingComponent(missing));
+		}
+		Ok(StateReader { crc32, components })
+	}
+}
+
+/// Split `len` bytes off the start of `data`.
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+	if data.len() < len {
+		return None;
+	}
+	let (bytes, rest) = data.split_at(len);
+	*data = rest;
+	Some(bytes)
+}
+
+/// Bring the data of a component from `version` to the current version.
+fn migrate(component: Component, mut version: u16, mut data: Vec<u8>) -> Result<Vec<u8>, StateError> {
+	if version > component.version() {
+		return Err(StateError::NewerComponent { component, version });
+	}
+	while version < component.version() {
+		let Some(migration) = MIGRATIONS.iter().find(|m| m.component == component && m.from == version) else {
+			return Err(StateError::OldComponent { component, version });
+		};
+		data = (migration.migrate)(data);
+		version += 1;
+	}
+	Ok(data)
+}
+
+/// The file of a save state slot, next to the ROM: game.state0-game.state9 for game.nes.
+pub fn slot_path(rom_path: &str, slot: usize) -> PathBuf {
+	Path::new(rom_path).with_extension(format!("state{}", slot))
+}
+
+/// The file of the autosave, next to the ROM: game.autostate for game.nes.
+pub fn autosave_path(rom_path: &str) -> PathBuf {
+	Path::new(rom_path).with_extension("autostate")
+}
+
+/// When to autosave: every `interval`, never when the interval is 0.
+pub struct Autosave {
+	interval: Duration,
+	last: Instant,
+}
+
+impl Autosave {
+	pub fn new(interval: Duration, now: Instant) -> Self {
+		Autosave { interval, last: now }
+	}
+
+	/// Is it time to save. Returns true once per interval.
+	pub fn due(&mut self, now: Instant) -> bool {
+		if self.interval.is_zero() || now - self.last < self.interval {
+			return false;
+		}
+		self.last = now;
+		true
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{path::Path, time::{Duration, Instant}};
+	use super::{autosave_path, migrate, slot_path, Autosave, Component, Serializer, StateReader, StateWriter};
+	use crate::{ppu::ppu::{FRAMEBUFFER_SIZE, SCREEN_HEIGHT}, rom_parser::MirrorType};
+
+	#[test]
+	fn test_round_trip() {
+		let mut values = (0x1234u16, -5i8, true, vec![1u8, 2, 3], Some(7usize), [0.5f32; 2], MirrorType::VERTICAL);
+		let mut s = Serializer::saving();
+		s.value(&mut values.0);
+		s.value(&mut values.1);
+		s.value(&mut values.2);
+		s.value(&mut values.3);
+		s.value(&mut values.4);
+		s.value(&mut values.5);
+		s.value(&mut values.6);
+		let data = s.into_bytes();
+
+		let mut loaded = (0u16, 0i8, false, vec![], None, [0.0f32; 2], MirrorType::HORIZONTAL);
+		let mut s = Serializer::loading(data);
+		s.value(&mut loaded.0);
+		s.value(&mut loaded.1);
+		s.value(&mut loaded.2);
+		s.value(&mut loaded.3);
+		s.value(&mut loaded.4);
+		s.value(&mut loaded.5);
+		s.value(&mut loaded.6);
+		assert!(s.is_done());
+		assert_eq!((loaded.0, loaded.1, loaded.2, loaded.3, loaded.4, loaded.5), (values.0, values.1, values.2, values.3, values.4, values.5));
+		assert!(matches!(loaded.6, MirrorType::VERTICAL));
+	}
+
+	#[test]
+	fn test_slots_and_autosave() {
+		assert_eq!(slot_path("roms/game.nes", 3), Path::new("roms/game.state3"));
+		assert_eq!(autosave_path("roms/game.nes"), Path::new("roms/game.autostate"));
+
+		let start = Instant::now();
+		let mut autosave = Autosave::new(Duration::from_secs(60), start);
+		assert!(!autosave.due(start + Duration::from_secs(59)));
+		assert!(autosave.due(start + Duration::from_secs(61)));
+		assert!(!autosave.due(start + Duration::from_secs(62)));
+		assert!(autosave.due(start + Duration::from_secs(121)));
+
+		let mut never = Autosave::new(Duration::ZERO, start);
+		assert!(!never.due(start + Duration::from_secs(1000)));
+	}
+
+	#[test]
+	fn test_truncated() {
+		let mut s = Serializer::loading(vec![1, 2, 3]);
+		let mut value = 0x5555u16;
+		s.value(&mut value);
+		assert!(!s.is_done());
+		s.value(&mut value);
+		assert!(!s.is_done());
+		assert_eq!(value, 0x0201);
+	}
+
+	#[test]
+	fn test_state_file() {
+		let mut writer = StateWriter::new(0x12345678);
+		for (i, component) in Component::ALL.into_iter().enumerate() {
+			writer.component(component, &vec![i as u8; i]);
+		}
+		let data = writer.into_bytes();
+
+		let state = StateReader::parse(&data).unwrap().unwrap();
+		assert_eq!(state.crc32, 0x12345678);
+		assert_eq!(state.components.len(), 5);
+		assert_eq!(state.components[4], (Component::Controllers, vec![4; 4]));
+
+		// Version 0 states have no header
+		assert!(StateReader::parse(&[0x78, 0x56, 0x34, 0x12]).is_none());
+
+		let error = |data: &[u8]| StateReader::parse(data).unwrap().err().unwrap().to_string();
+		assert_eq!(error(&data[..data.len() - 1]), "The state file is truncated");
+		// A newer format, a newer CPU component
+		let mut newer = data.clone();
+		newer[4] = 2;
+		assert!(error(&newer).contains("newer emulator (format version 2"));
+		let mut newer = data.clone();
+		newer[14] = 9;
+		assert!(error(&newer).contains("Cpu state was saved by a newer emulator (version 9"));
+		// An older CPU component, without a migration
+		let mut older = data.clone();
+		older[14] = 0;
+		assert!(error(&older).contains("Cpu state is too old (version 0)"));
+		// The PPU is missing: the file header with the CPU (20 bytes) and the cartridge (11 bytes) are kept
+		let mut missing = data[..10 + 10 + 11].to_vec();
+		missing.extend_from_slice(&data[10 + 10 + 11 + 12..]);
+		assert_eq!(error(&missing), "The state has no Ppu component");
+
+		// The CPU of version 1 couldn't halt
+		assert_eq!(migrate(Component::Cpu, 1, vec![7; 15 + 0x8000]).map(|data| data.len()), Ok(15 + 0x800 + 1));
+		// The pulse channels of the APU version 2 had only their length counter
+		let apu = migrate(Component::Apu, 2, vec![7; 28 + 4 * 6]).unwrap();
+		assert_eq!(apu.len(), 27 + 4 * 6 + 2 * 8 + 2 + 21 + 180);
+		assert_eq!(apu[27 + 6..27 + 6 + 8], [0; 8]);
+		assert_eq!(apu[27 + 6 + 8..27 + 6 + 8 + 6], [7; 6]);
+		// Version 5 moved the DMC IRQ flag out of the frame counter (27 bytes since) to the DMC
+		assert_eq!(apu[apu.len() - 180 - 20], 7);
+		// Version 6 added the resampler, which starts at 0, version 7 made it integer only
+		assert_eq!(apu[apu.len() - 180..], [0; 180]);
+		// The PPU of version 1 had no color emphasis, version 5 dropped the length of the framebuffer
+		let mut ppu = vec![1; 3 + 8];
+		ppu.extend([7; FRAMEBUFFER_SIZE]);
+		let ppu = migrate(Component::Ppu, 1, ppu).unwrap();
+		assert_eq!(ppu.len(), 3 + FRAMEBUFFER_SIZE + SCREEN_HEIGHT + 32 + 6 + 1);
+		assert_eq!(ppu[..4], [1, 1, 1, 7]);
+	}
+}
@@ -0,0 +1,273 @@
+//! Gameplay regression scenarios: a ROM, the buttons pressed from frame to frame, and checkpoints with the hash of the
+//! picture or of the whole machine at a frame. The runner plays them headless, in CI (`test_scenarios` runs the
+//! `scenarios` directory), so a change that breaks a game that used to work is caught.
+//!
+//! A scenario is a `.scenario` file, `#` starts a comment:
+//! ```text
+//! rom = ../roms/smb.nes 3337EC46    # Relative to the scenario file, and the CRC32 of the ROM (see --info)
+//! input 60 = start                  # From frame 60 on, until the next input line
+//! input 62 =
+//! input 100 = right b | a           # Player 2 after the `|`
+//! check 300 frame 1A2B3C4D          # CRC32 of the picture at the start of frame 300 (like --frame-hashes)
+//! check 300 state 0                 # CRC32 of the save state
+//! check 300 audio 0                 # CRC32 of the samples since power on
+//! ```
+//! The core is integer only, so the hashes are the same on every platform and in debug and release builds (CI runs the
+//! scenarios in both).
+//! `record` writes the hashes of the checkpoints: check the game plays right first (`--play`, `--headless --screenshot`).
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::{error, info, warn};
+
+use crate::{builder::NesBuilder, controller::{Button, ButtonState}, headless};
+
+pub const EXTENSION: &str = "scenario";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CheckKind {
+	Frame,	// The picture
+	State,	// The whole machine
+	Audio,	// The samples so far
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checkpoint {
+	pub frame: u64,
+	pub kind: CheckKind,
+	pub hash: u32,
+	line: usize,	// Index of the line in the file, to record the hash
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scenario {
+	pub rom: String,
+	pub crc32: u32,
+	pub inputs: Vec<(u64, [ButtonState; 2])>,	// From the frame on, sorted by frame
+	pub checkpoints: Vec<Checkpoint>,			// Sorted by frame
+	lines: Vec<String>,
+}
+
+impl Scenario {
+	pub fn parse(text: &str) -> Result<Self, String> {
+		let mut scenario = Scenario { rom: String::new(), crc32: 0, inputs: vec![], checkpoints: vec![], lines: text.lines().map(str::to_string).collect() };
+		for (i, line) in text.lines().enumerate() {
+			let line = line.split('#').next().unwrap().trim();
+			if line.is_empty() {
+				continue;
+			}
+			let error = |e: &str| format!("Line {}: {}: {}", i + 1, e, line);
+			let (key, value) = line.split_once('=').map_or((line, ""), |(key, value)| (key.trim(), value.trim()));
+			let fields: Vec<&str> = key.split_whitespace().collect();
+			match fields[..] {
+				["rom"] => {
+					let (rom, crc32) = value.rsplit_once(' ').ok_or(error("expected `rom = <file> <CRC32>`"))?;
+					scenario.rom = rom.trim().to_string();
+					scenario.crc32 = u32::from_str_radix(crc32, 16).map_err(|_| error("invalid CRC32"))?;
+				}
+				["input", frame] => {
+					let frame = frame.parse().map_err(|_| error("invalid frame"))?;
+					let mut buttons = [0; 2];
+					for (player, names) in value.split('|').enumerate().take(2) {
+						for name in names.split_whitespace() {
+							let button = Button::ALL.iter().find(|button| button.name() == name).ok_or(error("unknown button"))?;
+							buttons[player] |= button.mask();
+						}
+					}
+					scenario.inputs.push((frame, buttons));
+				}
+				["check", frame, kind, hash] => {
+					let frame = frame.parse().map_err(|_| error("invalid frame"))?;
+					let kind = match kind {
+						"frame" => CheckKind::Frame,
+						"state" => CheckKind::State,
+						"audio" => CheckKind::Audio,
+						_ => return Err(error("expected `frame`, `state` or `audio`")),
+					};
+					let hash = u32::from_str_radix(hash, 16).map_err(|_| error("invalid hash"))?;
+					scenario.checkpoints.push(Checkpoint { frame, kind, hash, line: i });
+				}
+				_ => return Err(error("expected `rom = ...`, `input <frame> = ...` or `check <frame> frame|state|audio <hash>`")),
+			}
+		}
+		if scenario.rom.is_empty() {
+			return Err("No `rom = <file> <CRC32>` line".to_string());
+		}
+		scenario.inputs.sort_by_key(|&(frame, _)| frame);
+		scenario.checkpoints.sort_by_key(|checkpoint| checkpoint.frame);
+		Ok(scenario)
+	}
+
+	/// The buttons of a frame.
+	pub fn buttons(&self, frame: u64) -> [ButtonState; 2] {
+		self.inputs.iter().rev().find(|&&(from, _)| from <= frame).map_or([0; 2], |&(_, buttons)| buttons)
+	}
+
+	/// Play the scenario on the ROM, the hash of each checkpoint in the order of `checkpoints`.
+	pub fn play(&self, rom_path: &Path) -> Result<Vec<u32>, String> {
+		let mut nes = NesBuilder::new().rom_path(rom_path.to_str().ok_or("Invalid ROM path")?).build();
+		if nes.cpu.cartridge().crc32() != self.crc32 {
+			return Err(format!("{} is another ROM (CRC32 {:08X}, expected {:08X})", rom_path.display(), nes.cpu.cartridge().crc32(), self.crc32));
+		}
+		let inputs = self.inputs.clone();
+		nes.set_input_provider(move |frame: u64| inputs.iter().rev().find(|&&(from, _)| from <= frame).map_or([0; 2], |&(_, buttons)| buttons));
+		let audio = Arc::new(Mutex::new(crc32fast::Hasher::new()));
+		let audio_clone = audio.clone();
+		nes.add_audio_sink(Box::new(move |samples: &[i16]| {
+			let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+			audio_clone.lock().unwrap().update(&bytes);
+		}));
+		Ok(self.checkpoints.iter().map(|checkpoint| {
+			nes.run_until(|nes| nes.frame() >= checkpoint.frame);
+			match checkpoint.kind {
+				CheckKind::Frame => headless::framebuffer_hash(&nes),
+				CheckKind::State => crc32fast::hash(&nes.save_state()),
+				CheckKind::Audio => audio.lock().unwrap().clone().finalize(),
+			}
+		}).collect())
+	}
+
+	/// The file with the hashes of the checkpoints replaced.
+	fn with_hashes(&self, hashes: &[u32]) -> String {
+		let mut lines = self.lines.clone();
+		for (checkpoint, hash) in self.checkpoints.iter().zip(hashes) {
+			let line = &lines[checkpoint.line];
+			let (code, comment) = line.find('#').map_or((line.as_str(), ""), |i| line.split_at(i));
+			let fields: Vec<&str> = code.split_whitespace().collect();
+			let code = format!("{} {:08X}", fields[..3].join(" "), hash);
+			lines[checkpoint.line] = if comment.is_empty() { code } else { format!("{} {}", code, comment) };
+		}
+		lines.join("\n") + "\n"
+	}
+}
+
+/// The scenario files of the directory, sorted.
+fn scenario_files(dir: &str) -> Result<Vec<PathBuf>, String> {
+	let mut files: Vec<_> = fs::read_dir(dir).map_err(|e| format!("Can't read {}: {}", dir, e))?
+		.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+		.filter(|path| path.extension().is_some_and(|extension| extension == EXTENSION))
+		.collect();
+	files.sort();
+	Ok(files)
+}
+
+/// The scenario of a file, and the path of its ROM.
+fn load(path: &Path) -> Result<(Scenario, PathBuf), String> {
+	let text = fs::read_to_string(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+	let scenario = Scenario::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+	let rom_path = path.parent().unwrap_or(Path::new(".")).join(&scenario.rom);
+	Ok((scenario, rom_path))
+}
+
+/// Play the scenarios of the directory. Scenarios of missing ROMs are skipped. Returns whether all the checkpoints of
+/// the scenarios that ran have the expected hashes.
+pub fn run(dir: &str) -> Result<bool, String> {
+	let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+	for path in scenario_files(dir)? {
+		let name = path.file_name().unwrap().to_string_lossy().to_string();
+		let (scenario, rom_path) = load(&path)?;
+		if !rom_path.exists() {
+			warn!("{}: the ROM {} is missing, skipped", name, rom_path.display());
+			skipped += 1;
+			continue;
+		}
+		let hashes = match scenario.play(&rom_path) {
+			Ok(hashes) => hashes,
+			Err(e) => {
+				error!("{}: {}", name, e);
+				failed += 1;
+				continue;
+			}
+		};
+		let mismatches: Vec<_> = scenario.checkpoints.iter().zip(&hashes).filter(|(checkpoint, &hash)| checkpoint.hash != hash).collect();
+		for (checkpoint, hash) in &mismatches {
+			error!("{}: frame {} {:?} hash {:08X} instead of {:08X}", name, checkpoint.frame, checkpoint.kind, hash, checkpoint.hash);
+		}
+		if mismatches.is_empty() {
+			passed += 1;
+		} else {
+			failed += 1;
+		}
+	}
+	info!("Scenarios: {} passed, {} failed, {} skipped", passed, failed, skipped);
+	Ok(failed == 0)
+}
+
+/// Play the scenarios of the directory and write the hashes of their checkpoints. Returns the amount of scenarios.
+pub fn record(dir: &str) -> Result<usize, String> {
+	let mut recorded = 0;
+	for path in scenario_files(dir)? {
+		let (scenario, rom_path) = load(&path)?;
+		if !rom_path.exists() {
+			warn!("{}: the ROM {} is missing, not recorded", path.display(), rom_path.display());
+			continue;
+		}
+		let hashes = scenario.play(&rom_path).map_err(|e| format!("{}: {}", path.display(), e))?;
+		fs::write(&path, scenario.with_hashes(&hashes)).map_err(|e| format!("Can't write {}: {}", path.display(), e))?;
+		recorded += 1;
+	}
+	Ok(recorded)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+
+	use super::{record, run, CheckKind, Scenario};
+	use crate::{builder::NesBuilder, controller::Button, program_loader::*};
+
+	#[test]
+	fn test_parse() {
+		let scenario = Scenario::parse("rom = roms/smb.nes 3337EC46\n# Comment\ninput 60 = start\ninput 0 =\ninput 100 = right b | a\ncheck 300 state 0 # Comment\ncheck 200 frame 1A2B3C4D\n").unwrap();
+		assert_eq!((scenario.rom.as_str(), scenario.crc32), ("roms/smb.nes", 0x3337EC46));
+		assert_eq!(scenario.buttons(59), [0, 0]);
+		assert_eq!(scenario.buttons(60), [Button::Start.mask(), 0]);
+		assert_eq!(scenario.buttons(1000), [Button::Right.mask() | Button::B.mask(), Button::A.mask()]);
+		assert_eq!(scenario.checkpoints.iter().map(|checkpoint| (checkpoint.frame, checkpoint.kind, checkpoint.hash)).collect::<Vec<_>>(),
+			[(200, CheckKind::Frame, 0x1A2B3C4D), (300, CheckKind::State, 0)]);
+		assert_eq!(scenario.with_hashes(&[1, 2]).lines().skip(5).collect::<Vec<_>>(), ["check 300 state 00000002 # Comment", "check 200 frame 00000001"]);
+
+		assert!(Scenario::parse("input 1 = start").unwrap_err().contains("No `rom"));
+		assert!(Scenario::parse("rom = a.nes 0\ninput 1 = jump").unwrap_err().starts_with("Line 2: unknown button"));
+		assert!(Scenario::parse("rom = a.nes 0\ncheck 1 sound 0").unwrap_err().contains("`frame`, `state` or `audio`"));
+	}
+
+	#[test]
+	fn test_record_and_run() {
+		// An NROM ROM that counts the frames with A pressed
+		let mut rom_memory: [u8; 1024*32] = [0;1024*32];
+		load_program_count_a_presses(&mut rom_memory);
+		set_reset_vector_after_warm_up(&mut rom_memory, 0x8000);
+		let mut rom = b"NES\x1A\x02\x01\x00\x00".to_vec();
+		rom.resize(16, 0);
+		rom.extend(rom_memory);
+		rom.extend([0; 1024 * 8]);
+
+		let dir = std::env::temp_dir().join(format!("rust-nes-emulator-scenarios-{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		fs::write(dir.join("count.nes"), &rom).unwrap();
+		let crc32 = NesBuilder::new().rom_path(dir.join("count.nes").to_str().unwrap()).build().cpu.cartridge().crc32();
+		fs::write(dir.join("count.scenario"), format!("rom = count.nes {:08X}\ninput 5 = a\ninput 8 =\ncheck 10 state 0\ncheck 10 frame 0\ncheck 10 audio 0\n", crc32)).unwrap();
+		fs::write(dir.join("missing.scenario"), "rom = missing.nes 0\n").unwrap();
+		let dir_path = dir.to_str().unwrap();
+		assert_eq!(run(dir_path), Ok(false));
+		assert_eq!(record(dir_path), Ok(1));
+		assert_eq!(run(dir_path), Ok(true));
+
+		// Other buttons play differently, and another ROM fails
+		let recorded = fs::read_to_string(dir.join("count.scenario")).unwrap();
+		fs::write(dir.join("count.scenario"), recorded.replace("input 5 = a", "input 5 = b")).unwrap();
+		assert_eq!(run(dir_path), Ok(false));
+		fs::write(dir.join("count.scenario"), recorded.replace(&format!("{:08X}", crc32), "00000000")).unwrap();
+		assert_eq!(run(dir_path), Ok(false));
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	/// The scenarios of the repository.
+	#[test]
+	fn test_scenarios() {
+		assert_eq!(run("scenarios"), Ok(true));
+	}
+}
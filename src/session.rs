@@ -0,0 +1,72 @@
+//! Resume where you left off. With the `session = true` setting, exiting saves the machine (a save state next to the
+//! ROM) and the window size and position (per game in the settings file), and the next launch of the ROM starts from
+//! them. `--fresh` starts from power on.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+
+/// Are the sessions saved on exit (the `session` setting, off by default).
+pub fn enabled(config: &Config) -> bool {
+	config.get("session", false)
+}
+
+/// The machine of the session, next to the ROM: game.session for game.nes.
+pub fn session_path(rom_path: &str) -> PathBuf {
+	Path::new(rom_path).with_extension("session")
+}
+
+/// The size and the position of the window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WindowLayout {
+	pub width: u32,
+	pub height: u32,
+	pub x: i32,
+	pub y: i32,
+}
+
+impl WindowLayout {
+	/// The layout of the game with this ROM CRC32, when there is one.
+	pub fn from_config(config: &Config, crc: u32) -> Option<Self> {
+		WindowLayout::parse(&config.get(&format!("game.{:08X}.window", crc), String::new()))
+	}
+
+	pub fn write_config(&self, config: &mut Config, crc: u32) {
+		config.set(&format!("game.{:08X}.window", crc), &self.to_string());
+	}
+
+	/// `800x600+100+-20`: the size, then the position.
+	fn parse(text: &str) -> Option<Self> {
+		let (size, position) = text.split_once('+')?;
+		let (width, height) = size.split_once('x')?;
+		let (x, y) = position.split_once('+')?;
+		Some(WindowLayout { width: width.parse().ok()?, height: height.parse().ok()?, x: x.parse().ok()?, y: y.parse().ok()? })
+	}
+}
+
+impl fmt::Display for WindowLayout {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}x{}+{}+{}", self.width, self.height, self.x, self.y)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{enabled, session_path, WindowLayout};
+	use crate::config::Config;
+
+	#[test]
+	fn test_session() {
+		let mut config = Config::parse("session = true\ngame.00000001.window = 640x480+10+-20\ngame.00000002.window = 640x480");
+		assert!(enabled(&config) && !enabled(&Config::parse("")));
+		let layout = WindowLayout { width: 640, height: 480, x: 10, y: -20 };
+		assert_eq!(WindowLayout::from_config(&config, 1), Some(layout));
+		assert_eq!((WindowLayout::from_config(&config, 2), WindowLayout::from_config(&config, 3)), (None, None));
+
+		let moved = WindowLayout { width: 800, height: 800, x: 0, y: 0 };
+		moved.write_config(&mut config, 2);
+		assert_eq!(WindowLayout::from_config(&config, 2), Some(moved));
+		assert_eq!(session_path("roms/smb.nes").to_str(), Some("roms/smb.session"));
+	}
+}
@@ -0,0 +1,94 @@
+//! The CPU, PPU and APU state as JSON, see `NES::dump_state_json()`. When a game behaves differently than on another
+//! emulator, dump both at the same frame and diff them: the first register that differs is where to look.
+
+use std::fmt::Write;
+
+use crate::cpu::registers::Registers;
+
+/// The state of the machine between two instructions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateDump {
+	pub cpu: CpuState,
+	pub ppu: PpuState,
+	pub apu: ApuState,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CpuState {
+	pub registers: Registers,
+	pub cycles: u64,
+	pub irq: bool,			// The IRQ line is asserted
+	pub halted: bool,		// By a KIL opcode
+}
+
+/// The PPU registers, the internal scroll latches and the timing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PpuState {
+	pub ctrl: u8,
+	pub mask: u8,
+	pub status: u8,
+	pub oam_addr: u8,
+	pub v: u16,
+	pub t: u16,
+	pub x: u8,
+	pub w: bool,
+	pub read_buffer: u8,
+	pub scanline: u16,
+	pub dot: u16,
+	pub frame: u64,
+}
+
+/// The frame counter and the interrupt flags.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ApuState {
+	pub cycles: u64,
+	pub frame_counter_cycles: u64,
+	pub five_step_mode: bool,
+	pub frame_irq_inhibit: bool,
+	pub frame_irq: bool,
+	pub dmc_irq: bool,
+}
+
+/// The values of a chip, see `StateDump::chips`.
+pub type Chip = (&'static str, Vec<(&'static str, String)>);
+
+impl StateDump {
+	/// The name and the values of each chip, always in the same order. The numbers are decimal.
+	pub fn chips(&self) -> [Chip; 3] {
+		let (cpu, ppu, apu) = (&self.cpu, &self.ppu, &self.apu);
+		let r = &cpu.registers;
+		[
+			("cpu", vec![
+				("a", r.A.to_string()), ("x", r.X.to_string()), ("y", r.Y.to_string()), ("s", r.S.to_string()),
+				("p", r.P.flags.to_string()), ("pc", r.PC.to_string()), ("cycles", cpu.cycles.to_string()),
+				("irq", cpu.irq.to_string()), ("halted", cpu.halted.to_string()),
+			]),
+			("ppu", vec![
+				("ctrl", ppu.ctrl.to_string()), ("mask", ppu.mask.to_string()), ("status", ppu.status.to_string()),
+				("oam_addr", ppu.oam_addr.to_string()), ("v", ppu.v.to_string()), ("t", ppu.t.to_string()),
+				("x", ppu.x.to_string()), ("w", ppu.w.to_string()), ("read_buffer", ppu.read_buffer.to_string()),
+				("scanline", ppu.scanline.to_string()), ("dot", ppu.dot.to_string()), ("frame", ppu.frame.to_string()),
+			]),
+			("apu", vec![
+				("cycles", apu.cycles.to_string()), ("frame_counter_cycles", apu.frame_counter_cycles.to_string()),
+				("five_step_mode", apu.five_step_mode.to_string()), ("frame_irq_inhibit", apu.frame_irq_inhibit.to_string()),
+				("frame_irq", apu.frame_irq.to_string()), ("dmc_irq", apu.dmc_irq.to_string()),
+			]),
+		]
+	}
+
+	/// An object for each chip, a field for each value. The numbers are decimal (JSON has no hex), and always in the same
+	/// order, so the dumps diff line by line.
+	pub fn to_json(&self) -> String {
+		let objects = self.chips();
+		let mut json = String::from("{\n");
+		for (i, (name, fields)) in objects.iter().enumerate() {
+			writeln!(json, "  \"{}\": {{", name).unwrap();
+			for (j, (field, value)) in fields.iter().enumerate() {
+				writeln!(json, "    \"{}\": {}{}", field, value, if j + 1 < fields.len() { "," } else { "" }).unwrap();
+			}
+			writeln!(json, "  }}{}", if i + 1 < objects.len() { "," } else { "" }).unwrap();
+		}
+		json + "}\n"
+	}
+}
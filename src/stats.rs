@@ -0,0 +1,160 @@
+use std::time::{Duration, Instant};
+
+use crate::cpu::{cpu::CPU_FREQUENCY, decoder::decode_opcode};
+
+/// Emulation counters since power on, and timings of the last frame. See `NES::stats()`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+	pub cpu_cycles: u64,
+	pub instructions: u64,
+	pub frames: u64,
+	pub apu_samples: u64,
+	pub last_frame: FrameStats,
+	pub subsystem_times: bool,	// The CPU, PPU and APU times of the frame are measured, see `NES::set_subsystem_times`
+}
+
+/// Where the host time of a single frame went. The CPU, PPU and APU times stay zero unless they are measured.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameStats {
+	pub cpu_cycles: u64,
+	pub cpu_time: Duration,		// Executing instructions
+	pub ppu_time: Duration,		// Catching up the PPU
+	pub apu_time: Duration,		// Catching up the APU and the cartridge (expansion audio, IRQ timers)
+	pub wall_time: Duration,	// From the start of the frame to the start of the next one, including the time the emulator was paused
+}
+
+impl FrameStats {
+	/// How long the frame takes on real hardware.
+	pub fn emulated_time(&self) -> Duration {
+		Duration::from_secs_f64(self.cpu_cycles as f64 / CPU_FREQUENCY as f64)
+	}
+
+	/// Emulated time / wall time. 1.0 is full speed.
+	pub fn speed(&self) -> f64 {
+		if self.wall_time.is_zero() {
+			return 0.0;
+		}
+		self.emulated_time().as_secs_f64() / self.wall_time.as_secs_f64()
+	}
+}
+
+/// How many times each opcode was executed since power on. Shows which instructions and addressing modes games
+/// really use, e.g. which illegal opcodes are worth implementing first. See `NES::opcode_stats()`.
+#[derive(Clone)]
+pub struct OpcodeStats {
+	counts: [u64; 256],
+}
+
+impl Default for OpcodeStats {
+	fn default() -> Self {
+		OpcodeStats::new()
+	}
+}
+
+impl OpcodeStats {
+	pub fn new() -> Self {
+		OpcodeStats { counts: [0; 256] }
+	}
+
+	pub fn count(&self, opcode: u8) -> u64 {
+		self.counts[opcode as usize]
+	}
+
+	/// The opcodes executed at least once and how many times, the most executed first.
+	pub fn executed(&self) -> Vec<(u8, u64)> {
+		let mut executed: Vec<_> = (0..=255).map(|opcode| (opcode, self.count(opcode))).filter(|&(_, count)| count > 0).collect();
+		executed.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+		executed
+	}
+
+	/// A line for each executed opcode, the most executed first: the opcode, its instruction and addressing mode, the
+	/// executions and their share of all the executions.
+	pub fn table(&self) -> String {
+		let total: u64 = self.counts.iter().sum();
+		let mut table = format!("{:<6} {:<20} {:>12} {:>7}\n", "Opcode", "Instruction", "Executions", "Share");
+		for (opcode, count) in self.executed() {
+			let instruction = match decode_opcode(opcode) {
+				Some((instr, addrmode, ..)) => format!("{:?} {:?}", instr, addrmode),
+				None => "illegal, as NOP".to_string(),
+			};
+			table += &format!("${:02X}    {:<20} {:>12} {:>6.2}%\n", opcode, instruction, count, count as f64 * 100.0 / total as f64);
+		}
+		table
+	}
+}
+
+/// Collects the stats while the CPU runs.
+#[derive(Clone)]
+pub struct StatsCollector {
+	instructions: u64,
+	opcodes: OpcodeStats,
+	current_frame: FrameStats,
+	last_frame: FrameStats,
+	frame_start: Instant,
+	frame_start_cycles: u64,
+	subsystem_times: bool,
+}
+
+impl Default for StatsCollector {
+	fn default() -> Self {
+		StatsCollector::new()
+	}
+}
+
+impl StatsCollector {
+	pub fn new() -> Self {
+		StatsCollector {
+			instructions: 0,
+			opcodes: OpcodeStats::new(),
+			current_frame: FrameStats::default(),
+			last_frame: FrameStats::default(),
+			frame_start: Instant::now(),
+			frame_start_cycles: 0,
+			subsystem_times: false,
+		}
+	}
+
+	/// Measure the host time of the CPU, the PPU and the APU apart. It costs clock reads on every instruction, so it is
+	/// off by default; the wall time of the frames is always measured.
+	pub fn set_subsystem_times(&mut self, enabled: bool) {
+		self.subsystem_times = enabled;
+	}
+
+	pub fn subsystem_times(&self) -> bool {
+		self.subsystem_times
+	}
+
+	pub fn add_instruction(&mut self, cpu_time: Duration, ppu_time: Duration, apu_time: Duration) {
+		self.instructions += 1;
+		self.current_frame.cpu_time += cpu_time;
+		self.current_frame.ppu_time += ppu_time;
+		self.current_frame.apu_time += apu_time;
+	}
+
+	pub fn add_opcode(&mut self, opcode: u8) {
+		self.opcodes.counts[opcode as usize] += 1;
+	}
+
+	/// Called when the PPU completes a frame, with the CPU cycles since power on.
+	pub fn end_frame(&mut self, cycles: u64) {
+		let now = Instant::now();
+		// Loading a save state can take the cycles back
+		self.current_frame.cpu_cycles = cycles.saturating_sub(self.frame_start_cycles);
+		self.current_frame.wall_time = now - self.frame_start;
+		self.last_frame = std::mem::take(&mut self.current_frame);
+		self.frame_start = now;
+		self.frame_start_cycles = cycles;
+	}
+
+	pub fn instructions(&self) -> u64 {
+		self.instructions
+	}
+
+	pub fn last_frame(&self) -> FrameStats {
+		self.last_frame
+	}
+
+	pub fn opcodes(&self) -> &OpcodeStats {
+		&self.opcodes
+	}
+}
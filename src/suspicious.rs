@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use log::{error, warn};
+
+/// How the emulator treats what a game does that is legal on the hardware but usually a bug.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EmulationMode {
+	Permissive,	// Emulate the hardware without a word, commercial games do these things and work
+	Strict,		// Log an error and stop in the debugger, for homebrew developers
+}
+
+impl EmulationMode {
+	/// `permissive` or `strict`, for the command line and the debugger.
+	pub fn parse(name: &str) -> Option<EmulationMode> {
+		match name {
+			"permissive" => Some(EmulationMode::Permissive),
+			"strict" => Some(EmulationMode::Strict),
+			_ => None,
+		}
+	}
+
+	pub fn name(self) -> &'static str {
+		match self {
+			EmulationMode::Permissive => "permissive",
+			EmulationMode::Strict => "strict",
+		}
+	}
+}
+
+/// Something suspicious the program did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Suspicious {
+	RomWrite(u16),			// A write to ROM without mapper registers, it does nothing
+	WriteOnlyRead(u16),		// A read of a write-only register, it reads open bus or a latch
+	StackOverflow,			// A push with S at $00, S wraps to $FF
+	StackUnderflow,			// A pull with S at $FF, S wraps to $00
+}
+
+impl fmt::Display for Suspicious {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Suspicious::RomWrite(addr) => write!(f, "write to ROM at ${:04X}", addr),
+			Suspicious::WriteOnlyRead(addr) => write!(f, "read of the write-only register ${:04X}", addr),
+			Suspicious::StackOverflow => write!(f, "stack overflow, push with S = $00"),
+			Suspicious::StackUnderflow => write!(f, "stack underflow, pull with S = $FF"),
+		}
+	}
+}
+
+/// Counts the suspicious accesses in strict mode. Permissive mode doesn't look for them at all.
+#[derive(Clone)]
+pub struct SuspiciousLog {
+	mode: EmulationMode,
+	counts: BTreeMap<Suspicious, u64>,
+	break_at: Option<(u16, Suspicious)>,	// The first of its kind the debugger didn't stop at yet, with the PC
+}
+
+impl Default for SuspiciousLog {
+	fn default() -> Self {
+		SuspiciousLog::new()
+	}
+}
+
+impl SuspiciousLog {
+	pub fn new() -> Self {
+		SuspiciousLog {
+			mode: EmulationMode::Permissive,
+			counts: BTreeMap::new(),
+			break_at: None,
+		}
+	}
+
+	pub fn set_mode(&mut self, mode: EmulationMode) {
+		self.mode = mode;
+	}
+
+	pub fn mode(&self) -> EmulationMode {
+		self.mode
+	}
+
+	pub fn strict(&self) -> bool {
+		self.mode == EmulationMode::Strict
+	}
+
+	/// The instruction at `pc` did it. The first time of each is an error and a break.
+	pub fn report(&mut self, pc: u16, suspicious: Suspicious) {
+		if !self.strict() {
+			return;
+		}
+		let count = self.counts.entry(suspicious).or_insert(0);
+		*count += 1;
+		if *count == 1 {
+			error!("Strict mode: {} by the instruction at ${:04X}", suspicious, pc);
+			self.break_at = Some((pc, suspicious));
+		}
+	}
+
+	/// The first suspicious access since the last call, for the debugger to stop at.
+	pub fn take_break(&mut self) -> Option<(u16, Suspicious)> {
+		self.break_at.take()
+	}
+
+	pub fn total(&self) -> u64 {
+		self.counts.values().sum()
+	}
+
+	/// Log the suspicious accesses so far, e.g. on exit.
+	pub fn log_summary(&self) {
+		if self.counts.is_empty() {
+			return;
+		}
+		warn!("Strict mode found suspicious accesses:");
+		for (suspicious, count) in &self.counts {
+			warn!("  {}: {} times", suspicious, count);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{EmulationMode, Suspicious, SuspiciousLog};
+
+	#[test]
+	fn test_strict_only() {
+		let mut log = SuspiciousLog::new();
+		log.report(0x8000, Suspicious::StackOverflow);
+		assert_eq!((log.total(), log.take_break()), (0, None));
+
+		log.set_mode(EmulationMode::Strict);
+		log.report(0x8000, Suspicious::RomWrite(0x8000));
+		log.report(0x8003, Suspicious::RomWrite(0x8000));
+		assert_eq!((log.total(), log.take_break()), (2, Some((0x8000, Suspicious::RomWrite(0x8000)))));
+		assert_eq!(log.take_break(), None);
+	}
+}
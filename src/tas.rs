@@ -0,0 +1,167 @@
+use crate::{controller::Button, movie::Movie, nes::NES};
+
+/// A save state is kept every so many frames, seeking replays from the nearest one.
+const STATE_INTERVAL: usize = 30;
+
+/// The backend of a TAS input editor (a piano roll): the emulation stops between frames, the buttons of any frame can
+/// be edited, and the frames run one at a time.
+///
+/// The input is a `Movie`, frame 0 is where the editor was opened. Editing a frame that already ran doesn't change the
+/// emulation until `seek` goes back to it: seeking loads the nearest save state before the frame and runs the frames in
+/// between with the edited input.
+pub struct TasEditor {
+	movie: Movie,
+	start_frame: u64,				// `NES::frame` of the movie frame 0
+	states: Vec<(usize, Vec<u8>)>,	// Save states at the start of a movie frame, by frame
+}
+
+impl TasEditor {
+	/// Start editing from the current state.
+	pub fn new(nes: &mut NES) -> Self {
+		TasEditor {
+			movie: Movie::record(nes),
+			start_frame: nes.frame(),
+			states: vec![(0, nes.save_state())],
+		}
+	}
+
+	/// The movie frame that runs next.
+	pub fn frame(&self, nes: &NES) -> usize {
+		nes.frame().saturating_sub(self.start_frame) as usize
+	}
+
+	/// The buttons of a player at a frame, bit per `Button`.
+	pub fn buttons(&self, frame: usize, player: usize) -> u8 {
+		self.movie.input(frame)[player]
+	}
+
+	pub fn is_pressed(&self, frame: usize, player: usize, button: Button) -> bool {
+		self.buttons(frame, player) & button.mask() != 0
+	}
+
+	pub fn set_buttons(&mut self, frame: usize, player: usize, buttons: u8) {
+		let mut input = self.movie.input(frame);
+		input[player] = buttons;
+		self.movie.set_input(frame, input);
+		self.invalidate(frame);
+	}
+
+	/// Press or release one button of a frame, e.g. of the next frame before `advance`.
+	pub fn set_button(&mut self, frame: usize, player: usize, button: Button, pressed: bool) {
+		let buttons = self.buttons(frame, player);
+		let buttons = if pressed { buttons | button.mask() } else { buttons & !button.mask() };
+		self.set_buttons(frame, player, buttons);
+	}
+
+	/// Insert a frame without buttons before `frame`, the input of the next frames moves one frame later.
+	pub fn insert_frame(&mut self, frame: usize) {
+		self.movie.insert_input(frame);
+		self.invalidate(frame);
+	}
+
+	/// Remove the input of a frame, the input of the next frames moves one frame earlier.
+	pub fn remove_frame(&mut self, frame: usize) {
+		self.movie.remove_input(frame);
+		self.invalidate(frame);
+	}
+
+	/// The states after an edited frame saw the old input.
+	fn invalidate(&mut self, frame: usize) {
+		self.states.retain(|&(state_frame, _)| state_frame <= frame);
+	}
+
+	/// Run the next frame with its input.
+	pub fn advance(&mut self, nes: &mut NES) {
+		let frame = self.frame(nes);
+		if frame.is_multiple_of(STATE_INTERVAL) && self.states.last().is_none_or(|&(last, _)| last < frame) {
+			self.states.push((frame, nes.save_state()));
+		}
+		for (player, buttons) in self.movie.input(frame).into_iter().enumerate() {
+			nes.cpu.controller_mut(player).set_buttons(buttons);
+		}
+		nes.run_frame();
+	}
+
+	/// Go to the start of a frame, before or after the current one.
+	pub fn seek(&mut self, nes: &mut NES, frame: usize) {
+		let (_, state) = self.states.iter().rev().find(|&&(state_frame, _)| state_frame <= frame).unwrap();
+		nes.load_state(state.clone()).expect("The states of the editor are of this NES");
+		while self.frame(nes) < frame {
+			self.advance(nes);
+		}
+	}
+
+	/// The input, to save it as a movie.
+	pub fn movie(&self) -> &Movie {
+		&self.movie
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::TasEditor;
+	use crate::{controller::Button, nes::NES, program_loader::*};
+
+	/// Counts the frames with A pressed in $0200.
+	fn initialize() -> NES {
+		let mut rom_memory: [u8; 1024*32] = [0;1024*32];
+		load_program_count_a_presses(&mut rom_memory);
+		set_reset_vector(&mut rom_memory, 0x8000);
+		NES::new_custom_prg_rom(rom_memory)
+	}
+
+	#[test]
+	fn test_frame_advance() {
+		let mut nes = initialize();
+		let mut editor = TasEditor::new(&mut nes);
+
+		// Press A on the next frame only
+		editor.set_button(0, 0, Button::A, true);
+		editor.advance(&mut nes);
+		editor.advance(&mut nes);
+		assert_eq!(editor.frame(&nes), 2);
+		assert_eq!(nes.peek(0x0200), 1);
+		assert!(editor.is_pressed(0, 0, Button::A));
+		assert!(!editor.is_pressed(1, 0, Button::A));
+
+		// Input buffered for future frames
+		for frame in 2..100 {
+			editor.set_buttons(frame, 0, Button::A.mask());
+		}
+		editor.set_button(50, 0, Button::A, false);
+		while editor.frame(&nes) < 100 {
+			editor.advance(&mut nes);
+		}
+		assert_eq!(nes.peek(0x0200), 98);
+	}
+
+	#[test]
+	fn test_edit_past_frames() {
+		let mut nes = initialize();
+		let mut editor = TasEditor::new(&mut nes);
+		for frame in 0..80 {
+			editor.set_button(frame, 0, Button::A, true);
+		}
+		while editor.frame(&nes) < 80 {
+			editor.advance(&mut nes);
+		}
+		assert_eq!(nes.peek(0x0200), 80);
+
+		// Release A at frames 40 and 70, and remove frame 10: the emulation changes once seeking replays them
+		editor.set_button(40, 0, Button::A, false);
+		editor.set_button(70, 0, Button::A, false);
+		editor.remove_frame(10);
+		assert_eq!(nes.peek(0x0200), 80);
+		editor.seek(&mut nes, 80);
+		assert_eq!(editor.frame(&nes), 80);
+		// 79 frames with input left, 2 of them without A
+		assert_eq!(nes.peek(0x0200), 77);
+
+		// Back in time
+		editor.seek(&mut nes, 5);
+		assert_eq!((editor.frame(&nes), nes.peek(0x0200)), (5, 5));
+		editor.insert_frame(0);
+		editor.seek(&mut nes, 5);
+		assert_eq!(nes.peek(0x0200), 4);
+	}
+}
@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use log::warn;
+
+use crate::cpu::decoder::decode_opcode;
+
+/// Something a game used that the emulator doesn't emulate yet. The emulation goes on without it (the instruction does
+/// nothing), which is often enough to see how far the game gets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum UnimplementedFeature {
+	Opcode(u8),			// The decoder doesn't know the opcode (illegal opcodes), it runs as a 1 byte NOP
+	AddressingMode(u8),	// The CPU doesn't fetch the operand of the opcode in its addressing mode
+	RegisterRead(u16),	// A read of $4020-$5FFF that nothing answers (open bus), a register of a missing chip
+}
+
+impl fmt::Display for UnimplementedFeature {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match *self {
+			UnimplementedFeature::Opcode(opcode) => write!(f, "illegal opcode ${:02X}", opcode),
+			UnimplementedFeature::AddressingMode(opcode) => {
+				let (instr, addrmode, ..) = decode_opcode(opcode).expect("Reported for a decoded opcode");
+				write!(f, "addressing mode {:?} of {:?} (opcode ${:02X})", addrmode, instr, opcode)
+			}
+			UnimplementedFeature::RegisterRead(addr) => write!(f, "register read ${:04X}", addr),
+		}
+	}
+}
+
+impl UnimplementedFeature {
+	/// The kind of `Needs::KINDS`, and the opcode or the address.
+	fn kind(self) -> (&'static str, u16) {
+		match self {
+			UnimplementedFeature::Opcode(opcode) => ("opcodes", opcode as u16),
+			UnimplementedFeature::AddressingMode(opcode) => ("addressing_modes", opcode as u16),
+			UnimplementedFeature::RegisterRead(addr) => ("register_reads", addr),
+		}
+	}
+}
+
+/// What to do when a game uses an unimplemented feature.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnimplementedPolicy {
+	Warn,	// Log the first use of each feature and go on
+	Quiet,	// Only count them, for the summary
+	Panic,	// Stop the emulator (the crash dump has the trace and the state), for debugging the emulator
+}
+
+impl UnimplementedPolicy {
+	/// `warn`, `quiet` or `panic`, for the command line.
+	pub fn parse(name: &str) -> Option<UnimplementedPolicy> {
+		match name {
+			"warn" => Some(UnimplementedPolicy::Warn),
+			"quiet" => Some(UnimplementedPolicy::Quiet),
+			"panic" => Some(UnimplementedPolicy::Panic),
+			_ => None,
+		}
+	}
+}
+
+/// Counts the uses of each unimplemented feature, so the summary tells exactly what a game that doesn't work needed.
+#[derive(Clone)]
+pub struct UnimplementedLog {
+	policy: UnimplementedPolicy,
+	counts: BTreeMap<UnimplementedFeature, u64>,
+}
+
+impl Default for UnimplementedLog {
+	fn default() -> Self {
+		UnimplementedLog::new()
+	}
+}
+
+impl UnimplementedLog {
+	pub fn new() -> Self {
+		UnimplementedLog {
+			policy: UnimplementedPolicy::Warn,
+			counts: BTreeMap::new(),
+		}
+	}
+
+	pub fn set_policy(&mut self, policy: UnimplementedPolicy) {
+		self.policy = policy;
+	}
+
+	pub fn report(&mut self, feature: UnimplementedFeature) {
+		if self.policy == UnimplementedPolicy::Panic {
+			panic!("Unimplemented {}", feature);
+		}
+		let count = self.counts.entry(feature).or_insert(0);
+		*count += 1;
+		if *count == 1 && self.policy == UnimplementedPolicy::Warn {
+			warn!("Unimplemented {}, ignored", feature);
+		}
+	}
+
+	/// The features used so far and how many times, in order.
+	pub fn counts(&self) -> impl Iterator<Item = (UnimplementedFeature, u64)> + '_ {
+		self.counts.iter().map(|(&feature, &count)| (feature, count))
+	}
+
+	/// What the game needed so far, for a report.
+	pub fn needs(&self) -> Needs {
+		Needs { mapper: None, features: self.counts().collect() }
+	}
+
+	/// Log the features used so far, e.g. on exit.
+	pub fn log_summary(&self) {
+		if self.counts.is_empty() {
+			return;
+		}
+		warn!("The game used features the emulator doesn't implement yet:");
+		for (feature, count) in self.counts() {
+			warn!("  {}: {} times", feature, count);
+		}
+	}
+}
+
+/// What a game needs that the emulator doesn't have, aggregated for an issue: "mapper 4, opcodes $0B/$2B, $5205 reads".
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Needs {
+	pub mapper: Option<u8>,		// Not supported, the game didn't run
+	pub features: Vec<(UnimplementedFeature, u64)>,
+}
+
+impl Needs {
+	/// The kinds of features, in the order of `UnimplementedFeature`: the keys of the JSON report.
+	pub const KINDS: [&'static str; 3] = ["opcodes", "addressing_modes", "register_reads"];
+
+	pub fn is_empty(&self) -> bool {
+		self.mapper.is_none() && self.features.is_empty()
+	}
+
+	/// The opcodes (the addresses for the register reads) of a kind of `KINDS`, with how many times they were used.
+	pub fn of_kind<'a>(&'a self, kind: &'a str) -> impl Iterator<Item = (u16, u64)> + 'a {
+		self.features.iter().filter_map(move |&(feature, count)| {
+			let (feature_kind, value) = feature.kind();
+			(feature_kind == kind).then_some((value, count))
+		})
+	}
+}
+
+impl fmt::Display for Needs {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let mut parts: Vec<String> = self.mapper.iter().map(|mapper| format!("mapper {}", mapper)).collect();
+		for kind in Needs::KINDS {
+			let values: Vec<String> = self.of_kind(kind).map(|(value, _)| match kind {
+				"register_reads" => format!("${:04X}", value),
+				_ => format!("${:02X}", value),
+			}).collect();
+			if values.is_empty() {
+				continue;
+			}
+			parts.push(match kind {
+				"register_reads" => format!("{} reads", values.join("/")),
+				_ => format!("{} {}", kind.replace('_', " "), values.join("/")),
+			});
+		}
+		write!(f, "{}", if parts.is_empty() { "nothing".to_string() } else { parts.join(", ") })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Needs, UnimplementedFeature, UnimplementedLog, UnimplementedPolicy};
+
+	#[test]
+	fn test_counts() {
+		let mut log = UnimplementedLog::new();
+		log.report(UnimplementedFeature::Opcode(0x0B));
+		log.report(UnimplementedFeature::Opcode(0x03));
+		log.report(UnimplementedFeature::Opcode(0x0B));
+		assert_eq!(log.counts().collect::<Vec<_>>(), vec![
+			(UnimplementedFeature::Opcode(0x03), 1),
+			(UnimplementedFeature::Opcode(0x0B), 2),
+		]);
+		assert_eq!(UnimplementedFeature::Opcode(0x0B).to_string(), "illegal opcode $0B");
+		assert_eq!(UnimplementedFeature::AddressingMode(0x91).to_string(), "addressing mode INDIRECTY of STA (opcode $91)");
+
+		log.report(UnimplementedFeature::RegisterRead(0x5205));
+		log.report(UnimplementedFeature::Opcode(0x2B));
+		assert_eq!(log.needs().to_string(), "opcodes $03/$0B/$2B, $5205 reads");
+		assert_eq!(Needs { mapper: Some(4), features: vec![] }.to_string(), "mapper 4");
+
+		log.set_policy(UnimplementedPolicy::Panic);
+		assert!(std::panic::catch_unwind(move || log.report(UnimplementedFeature::Opcode(0x03))).is_err());
+	}
+}
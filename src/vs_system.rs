@@ -0,0 +1,189 @@
+use log::info;
+use crate::savestate::{Serialize, Serializer};
+
+/// The VS System arcade cabinet: coin slots, DIP switches and a service button on the controller ports, and a PPU with
+/// a different palette. Read here: https://www.nesdev.org/wiki/VS_System
+///
+/// Dual system games (two CPUs and two screens) are not supported.
+#[derive(Clone)]
+pub struct VsSystem {
+	ppu: VsPpu,
+	dip_switches: u8,
+	coins: [u8; 2],		// Frames left until the coin switch is released
+	service: bool,
+}
+
+/// The VS System PPUs. The RP2C03 has the NES palette, the RP2C04 revisions have the same colors in a scrambled order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VsPpu {
+	Rp2c03,
+	Rp2c04(usize),	// Revision 1-4 (RP2C04-0001 to RP2C04-0004)
+}
+
+/// The coin switch is held for a few frames, like a falling coin. Games ignore shorter pulses.
+const COIN_FRAMES: u8 = 4;
+
+/// NES palette index of each RP2C04 color index.
+const RP2C04_PALETTES: [[u8; 64]; 4] = [
+	[
+		0x35, 0x23, 0x16, 0x22, 0x1C, 0x09, 0x1D, 0x15, 0x20, 0x00, 0x27, 0x05, 0x04, 0x28, 0x08, 0x20,
+		0x21, 0x3E, 0x1F, 0x29, 0x3C, 0x32, 0x36, 0x12, 0x3F, 0x2B, 0x2E, 0x1E, 0x3D, 0x2D, 0x24, 0x01,
+		0x0E, 0x31, 0x33, 0x2A, 0x2C, 0x0C, 0x1B, 0x14, 0x2E, 0x07, 0x34, 0x06, 0x13, 0x02, 0x26, 0x2E,
+		0x2E, 0x19, 0x10, 0x0A, 0x39, 0x03, 0x37, 0x17, 0x0F, 0x11, 0x0B, 0x0D, 0x38, 0x25, 0x18, 0x3A,
+	],
+	[
+		0x2E, 0x27, 0x18, 0x39, 0x3A, 0x25, 0x1C, 0x31, 0x16, 0x13, 0x38, 0x34, 0x20, 0x23, 0x3C, 0x0B,
+		0x0F, 0x21, 0x06, 0x3D, 0x1B, 0x29, 0x1E, 0x22, 0x1D, 0x24, 0x0E, 0x2B, 0x32, 0x08, 0x2E, 0x03,
+		0x04, 0x36, 0x26, 0x33, 0x11, 0x1F, 0x10, 0x02, 0x14, 0x3F, 0x00, 0x09, 0x12, 0x2E, 0x28, 0x20,
+		0x3E, 0x0D, 0x2A, 0x17, 0x0C, 0x01, 0x15, 0x19, 0x2E, 0x2C, 0x07, 0x37, 0x35, 0x05, 0x0A, 0x2F,
+	],
+	[
+		0x14, 0x25, 0x3A, 0x10, 0x0B, 0x20, 0x31, 0x09, 0x01, 0x2E, 0x36, 0x08, 0x15, 0x3D, 0x3E, 0x3C,
+		0x22, 0x1C, 0x05, 0x12, 0x19, 0x18, 0x17, 0x1B, 0x00, 0x03, 0x2E, 0x02, 0x16, 0x06, 0x34, 0x35,
+		0x23, 0x0F, 0x0E, 0x37, 0x0D, 0x27, 0x26, 0x20, 0x29, 0x04, 0x21, 0x24, 0x11, 0x2D, 0x2E, 0x1F,
+		0x2C, 0x1E, 0x39, 0x33, 0x07, 0x2A, 0x28, 0x1D, 0x0A, 0x2E, 0x32, 0x38, 0x13, 0x2B, 0x3F, 0x0C,
+	],
+	[
+		0x18, 0x03, 0x1C, 0x28, 0x2E, 0x35, 0x01, 0x17, 0x10, 0x1F, 0x2A, 0x0E, 0x36, 0x37, 0x0B, 0x39,
+		0x25, 0x1E, 0x12, 0x34, 0x2E, 0x1D, 0x06, 0x26, 0x3E, 0x1B, 0x22, 0x19, 0x04, 0x2E, 0x3A, 0x21,
+		0x05, 0x0A, 0x07, 0x02, 0x13, 0x14, 0x00, 0x15, 0x0C, 0x3D, 0x11, 0x0F, 0x0D, 0x38, 0x2D, 0x24,
+		0x33, 0x20, 0x08, 0x16, 0x3F, 0x2B, 0x20, 0x3C, 0x2E, 0x27, 0x23, 0x31, 0x29, 0x32, 0x2C, 0x09,
+	],
+];
+
+impl VsPpu {
+	/// Parse "2c03" or the RP2C04 revision "0001"-"0004".
+	pub fn parse(name: &str) -> Option<Self> {
+		match name.to_lowercase().trim_start_matches("rp").trim_start_matches("2c04-") {
+			"2c03" => Some(VsPpu::Rp2c03),
+			"0001" => Some(VsPpu::Rp2c04(1)),
+			"0002" => Some(VsPpu::Rp2c04(2)),
+			"0003" => Some(VsPpu::Rp2c04(3)),
+			"0004" => Some(VsPpu::Rp2c04(4)),
+			_ => None,
+		}
+	}
+
+	/// Maps the palette RAM values to the NES palette. None for the NES palette.
+	pub fn palette_lut(&self) -> Option<&'static [u8; 64]> {
+		match self {
+			VsPpu::Rp2c03 => None,
+			VsPpu::Rp2c04(revision) => Some(&RP2C04_PALETTES[revision - 1]),
+		}
+	}
+}
+
+impl VsSystem {
+	pub fn new(ppu: VsPpu) -> Self {
+		VsSystem {
+			ppu,
+			dip_switches: 0,
+			coins: [0; 2],
+			service: false,
+		}
+	}
+
+	/// The VS System bits of the controller ports. $4016: service button (bit 2), DIP switches 1-2 (bits 3-4),
+	/// coins (bits 5-6). $4017: DIP switches 3-8 (bits 2-7). Bits 0-1 are the controllers.
+	pub fn read(&self, addr: u16) -> u8 {
+		if addr == 0x4016 {
+			((self.service as u8) << 2)
+				| ((self.dip_switches & 0b11) << 3)
+				| (((self.coins[0] > 0) as u8) << 5)
+				| (((self.coins[1] > 0) as u8) << 6)
+		} else {
+			self.dip_switches & 0b1111_1100
+		}
+	}
+
+	pub fn ppu(&self) -> VsPpu {
+		self.ppu
+	}
+
+	pub fn set_ppu(&mut self, ppu: VsPpu) {
+		self.ppu = ppu;
+	}
+
+	pub fn dip_switches(&self) -> u8 {
+		self.dip_switches
+	}
+
+	/// DIP switch 1 is bit 0, DIP switch 8 is bit 7. The meaning of each switch depends on the game (difficulty, lives,
+	/// coins per credit...).
+	pub fn set_dip_switches(&mut self, dip_switches: u8) {
+		self.dip_switches = dip_switches;
+	}
+
+	/// Insert a coin to coin slot 0 or 1.
+	pub fn insert_coin(&mut self, slot: usize) {
+		info!("VS System: coin inserted to slot {}", slot);
+		self.coins[slot] = COIN_FRAMES;
+	}
+
+	pub fn set_service_button(&mut self, pressed: bool) {
+		self.service = pressed;
+	}
+
+	/// Called when the PPU completes a frame.
+	pub fn end_frame(&mut self) {
+		for coin in self.coins.iter_mut() {
+			*coin = coin.saturating_sub(1);
+		}
+	}
+}
+
+/// The DIP switches and the coins. The PPU is not saved, it comes from the ROM (or the user).
+impl Serialize for VsSystem {
+	fn serialize(&mut self, s: &mut Serializer) {
+		s.value(&mut self.dip_switches);
+		s.value(&mut self.coins);
+		s.value(&mut self.service);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{VsPpu, VsSystem, COIN_FRAMES};
+
+	#[test]
+	fn test_controller_port_bits() {
+		let mut vs = VsSystem::new(VsPpu::Rp2c03);
+		assert_eq!((vs.read(0x4016), vs.read(0x4017)), (0, 0));
+
+		vs.set_dip_switches(0b1010_0110);
+		assert_eq!(vs.read(0x4016), 0b10 << 3);
+		assert_eq!(vs.read(0x4017), 0b1010_0100);
+
+		vs.set_service_button(true);
+		assert_eq!(vs.read(0x4016) & 0b100, 0b100);
+	}
+
+	#[test]
+	fn test_coin() {
+		let mut vs = VsSystem::new(VsPpu::Rp2c03);
+		vs.insert_coin(1);
+		for _ in 0..COIN_FRAMES {
+			assert_eq!(vs.read(0x4016) & 0b0110_0000, 0b0100_0000);
+			vs.end_frame();
+		}
+		assert_eq!(vs.read(0x4016), 0);
+	}
+
+	#[test]
+	fn test_palette() {
+		assert_eq!(VsPpu::parse("2C03"), Some(VsPpu::Rp2c03));
+		assert_eq!(VsPpu::parse("RP2C04-0003"), Some(VsPpu::Rp2c04(3)));
+		assert_eq!(VsPpu::parse("0005"), None);
+		assert!(VsPpu::Rp2c03.palette_lut().is_none());
+
+		// Each RP2C04 has almost all of the NES colors
+		for revision in 1..=4 {
+			let lut = VsPpu::Rp2c04(revision).palette_lut().unwrap();
+			let mut colors = lut.to_vec();
+			colors.sort();
+			colors.dedup();
+			assert!(colors.len() >= 60);
+		}
+		assert_eq!(VsPpu::Rp2c04(1).palette_lut().unwrap()[0], 0x35);
+	}
+}